@@ -33,6 +33,7 @@ use wprs::buffer_pointer::BufferPointer;
 use wprs::filtering;
 use wprs::sharding_compression::CompressedShard;
 use wprs::sharding_compression::CompressedShards;
+use wprs::sharding_compression::Compression;
 use wprs::sharding_compression::ShardingCompressor;
 use wprs::sharding_compression::ShardingDecompressor;
 
@@ -116,7 +117,7 @@ fn compress_png(c: &mut Criterion, path: &Path) {
     );
 }
 
-fn filter_compress_png(c: &mut Criterion, path: &Path) {
+fn filter_compress_png(c: &mut Criterion, path: &Path, compression: Compression) {
     let mut data = read_png(path);
     let _orig_data = data.clone();
     let data_ptr = &data.as_ptr();
@@ -125,15 +126,18 @@ fn filter_compress_png(c: &mut Criterion, path: &Path) {
     let uncompressed_size = data.len();
 
     let n_compressors = NonZeroUsize::new(16).unwrap();
-    let mut compressor = ShardingCompressor::new(n_compressors, 1).unwrap();
+    let mut compressor = ShardingCompressor::with_compression(n_compressors, compression).unwrap();
 
     let mut compressed_shards = CompressedShards::default();
 
-    c.bench_function(&format!("filter and compress: {}", path.display()), |b| {
-        b.iter(|| {
-            compressed_shards = filtering::filter_and_compress(buf_ptr, &mut compressor);
-        })
-    });
+    c.bench_function(
+        &format!("filter and compress ({compression:?}): {}", path.display()),
+        |b| {
+            b.iter(|| {
+                compressed_shards = filtering::filter_and_compress(buf_ptr, &mut compressor);
+            })
+        },
+    );
 
     let compressed_size = compressed_shards.size();
 
@@ -148,7 +152,10 @@ fn filter_compress_png(c: &mut Criterion, path: &Path) {
         .transpose_into_fallible();
 
     c.bench_function(
-        &format!("unfilter and decompress: {}", path.display()),
+        &format!(
+            "unfilter and decompress ({compression:?}): {}",
+            path.display()
+        ),
         |b| {
             b.iter_batched(
                 || compressed_shards.clone(),
@@ -190,7 +197,11 @@ fn compression_benchmark(c: &mut Criterion) {
     for file in files {
         compress_png(c, &file);
         println!("");
-        filter_compress_png(c, &file);
+        // None is what local use needs, compare it to the default.
+        for compression in [Compression::None, Compression::Zstd { level: 1 }] {
+            filter_compress_png(c, &file, compression);
+            println!("");
+        }
         println!(
             "--------------------------------------------------------------------------------"
         );
@@ -31,6 +31,7 @@ use serde::Serialize;
 use tracing::Level;
 use tracing::metadata::ParseLevelError;
 
+use crate::clipboard_limit::ClipboardLimit;
 use crate::prelude::*;
 
 pub trait Config: Debug + Default + Serialize {
@@ -165,6 +166,25 @@ pub fn default_control_socket_path(prefix: &str) -> PathBuf {
     Path::join(&socket_dir(), format!("{prefix}-ctrl.sock"))
 }
 
+pub fn default_dpi() -> impl Parser<Option<u32>> {
+    bpaf::long("default-dpi")
+        .argument::<u32>("DPI")
+        .help("DPI to compute an output's physical size from when the compositor reports an implausible one (e.g., 0x0). X11 apps use the physical size to compute their DPI.")
+        .optional()
+}
+
+pub fn clipboard_limit() -> impl Parser<Option<ClipboardLimit>> {
+    bpaf::long("clipboard-limit")
+        .argument::<String>("(max_bytes: N, mime_type_max_bytes: {MIME_TYPE: N, ...}, oversized: Refuse|Truncate)")
+        .help("Maximum size of forwarded clipboard, primary selection and drag-and-drop payloads, per transfer of a MIME type. mime_type_max_bytes overrides max_bytes for specific MIME types, 0 means unlimited. Oversized payloads are either refused, in which case pasting yields nothing, or truncated to the limit.")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+pub fn default_snapshot_file(prefix: &str) -> PathBuf {
+    Path::join(&socket_dir(), format!("{prefix}-snapshot.json"))
+}
+
 pub fn control_socket() -> impl Parser<Option<PathBuf>> {
     bpaf::long("control-socket")
         .argument::<PathBuf>("PATH")
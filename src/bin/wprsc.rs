@@ -14,6 +14,7 @@
 
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use bpaf::Parser;
 use optional_struct::optional_struct;
@@ -32,10 +33,19 @@ use wprs::args::OptionalConfig;
 use wprs::args::SerializableLevel;
 use wprs::client::ClientOptions;
 use wprs::client::WprsClientState;
+use wprs::client::input_arbitration::InputArbitration;
+use wprs::client::placeholder::SurfacePlaceholder;
+use wprs::client::pointer_prediction::PointerPrediction;
+use wprs::client::primary_selection::PrimarySelectionFallback;
+use wprs::client::selection_clear::SelectionClearBehavior;
+use wprs::clipboard_limit::ClipboardLimit;
 use wprs::control_server;
 use wprs::prelude::*;
+use wprs::reconnect::Reconnect;
 use wprs::serialization;
+use wprs::serialization::ClientHello;
 use wprs::serialization::Serializer;
+use wprs::sharding_compression::CompressionChoice;
 use wprs::utils;
 
 #[optional_struct]
@@ -56,6 +66,15 @@ pub struct WprscConfig {
     pub file_log_level: SerializableLevel,
     pub log_priv_data: bool,
     pub title_prefix: String,
+    pub placeholder: SurfacePlaceholder,
+    pub primary_selection_fallback: PrimarySelectionFallback,
+    pub selection_clear_behavior: SelectionClearBehavior,
+    pub pointer_prediction: PointerPrediction,
+    pub input_arbitration: InputArbitration,
+    pub disconnect_grace_period_secs: u32,
+    pub clipboard_limit: ClipboardLimit,
+    pub reconnect: Reconnect,
+    pub buffer_compression: CompressionChoice,
 }
 
 impl Default for WprscConfig {
@@ -70,6 +89,15 @@ impl Default for WprscConfig {
             file_log_level: SerializableLevel(Level::TRACE),
             log_priv_data: false,
             title_prefix: String::new(),
+            placeholder: SurfacePlaceholder::Disabled,
+            primary_selection_fallback: PrimarySelectionFallback::Disabled,
+            selection_clear_behavior: SelectionClearBehavior::Clear,
+            pointer_prediction: PointerPrediction::Disabled,
+            input_arbitration: InputArbitration::Independent,
+            disconnect_grace_period_secs: 0,
+            clipboard_limit: ClipboardLimit::default(),
+            reconnect: Reconnect::Disabled,
+            buffer_compression: CompressionChoice::Auto,
         }
     }
 }
@@ -80,6 +108,69 @@ impl Config for WprscConfig {
     }
 }
 
+fn placeholder() -> impl Parser<Option<SurfacePlaceholder>> {
+    bpaf::long("placeholder")
+        .help("What to show in a window until its first frame arrives. Disabled shows nothing (the window appears once its first frame arrives), Color(0xAARRGGBB) fills the window with a solid color, Loading(background: 0xAARRGGBB, foreground: 0xAARRGGBB) additionally draws a bar across the middle of the window.")
+        .argument::<String>("Disabled|Color(COLOR)|Loading(background: COLOR, foreground: COLOR)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn primary_selection_fallback() -> impl Parser<Option<PrimarySelectionFallback>> {
+    bpaf::long("primary-selection-fallback")
+        .help("What to do with the primary selection (middle-click paste) if the local compositor doesn't support it. Disabled doesn't sync it, Clipboard syncs remote primary selections to the local clipboard and offers the local clipboard as the remote primary selection.")
+        .argument::<String>("Disabled|Clipboard")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn selection_clear_behavior() -> impl Parser<Option<SelectionClearBehavior>> {
+    bpaf::long("selection-clear-behavior")
+        .help("What to do with the local selection when the remote selection it was synced from is cleared, e.g. because the X11 app owning it exited. Clear unsets the local selection, Ignore keeps it even though pasting from it yields nothing.")
+        .argument::<String>("Clear|Ignore")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn pointer_prediction() -> impl Parser<Option<PointerPrediction>> {
+    bpaf::long("pointer-prediction")
+        .help("Extrapolate forwarded pointer motion from the pointer's recent velocity, so that remote apps react to where the pointer will be once their response arrives. Useful on high-latency links. lookahead_ms should be about the round-trip time, max_distance bounds how far ahead of the real position the prediction can get. Button presses are always sent with the real position.")
+        .argument::<String>("Disabled|Enabled(lookahead_ms: MS, max_distance: PIXELS)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn input_arbitration() -> impl Parser<Option<InputArbitration>> {
+    bpaf::long("input-arbitration")
+        .help("How to handle pointer and touch input used at the same time. Independent forwards both, PointerPriority cancels touches when a button is pressed and ignores touches while a button is held, TouchPriority ignores pointer input while touching.")
+        .argument::<String>("Independent|PointerPriority|TouchPriority")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn disconnect_grace_period_secs() -> impl Parser<Option<u32>> {
+    bpaf::long("disconnect-grace-period-secs")
+        .help("Seconds to keep the windows of a remote app which disconnected, frozen on their last frame, before destroying them. Avoids windows flickering away when an app reconnects quickly. 0 destroys them immediately.")
+        .argument::<u32>("SECS")
+        .optional()
+}
+
+fn reconnect() -> impl Parser<Option<Reconnect>> {
+    bpaf::long("reconnect")
+        .help("What to do when the connection to wprsd drops. Disabled exits, e.g. so that a supervisor restarts wprsc. Enabled retries connecting up to max_retries times with exponential backoff between initial_backoff_ms and max_backoff_ms, then exits. On reconnection, all windows are recreated from wprsd's state.")
+        .argument::<String>("Disabled|Enabled(initial_backoff_ms: MS, max_backoff_ms: MS, max_retries: N)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn buffer_compression() -> impl Parser<Option<CompressionChoice>> {
+    bpaf::long("buffer-compression")
+        .help("How wprsd should compress buffer contents before sending them. A Fixed --buffer-compression of wprsd takes precedence. Auto leaves the choice to wprsd, which picks None when wprsc connects to its socket directly and Zstd(level: 1) when the socket is forwarded, e.g. by ssh.")
+        .argument::<String>("Auto|Fixed(None)|Fixed(Zstd(level: N))")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
 impl OptionalConfig<WprscConfig> for OptionalWprscConfig {
     fn parse_args() -> Self {
         let print_default_config_and_exit = args::print_default_config_and_exit();
@@ -91,6 +182,15 @@ impl OptionalConfig<WprscConfig> for OptionalWprscConfig {
         let file_log_level = args::file_log_level();
         let log_priv_data = args::log_priv_data();
         let title_prefix = args::title_prefix();
+        let placeholder = placeholder();
+        let primary_selection_fallback = primary_selection_fallback();
+        let selection_clear_behavior = selection_clear_behavior();
+        let pointer_prediction = pointer_prediction();
+        let input_arbitration = input_arbitration();
+        let disconnect_grace_period_secs = disconnect_grace_period_secs();
+        let clipboard_limit = args::clipboard_limit();
+        let reconnect = reconnect();
+        let buffer_compression = buffer_compression();
         bpaf::construct!(Self {
             print_default_config_and_exit,
             config_file,
@@ -101,6 +201,15 @@ impl OptionalConfig<WprscConfig> for OptionalWprscConfig {
             file_log_level,
             log_priv_data,
             title_prefix,
+            placeholder,
+            primary_selection_fallback,
+            selection_clear_behavior,
+            pointer_prediction,
+            input_arbitration,
+            disconnect_grace_period_secs,
+            clipboard_limit,
+            reconnect,
+            buffer_compression,
         })
         .to_options()
         .run()
@@ -137,23 +246,35 @@ fn main() -> Result<()> {
     let (globals, event_queue) = registry_queue_init(&conn)?;
 
     fs::create_dir_all(config.socket.parent().location(loc!())?).location(loc!())?;
-    let mut serializer = Serializer::new_client(&config.socket).with_context(loc!(), || {
+    let mut serializer = Serializer::new_client(
+        &config.socket,
+        Some(serialization::Event::WprsClientConnect(ClientHello {
+            buffer_compression: config.buffer_compression,
+        })),
+        config.reconnect,
+    )
+    .with_context(loc!(), || {
         format!(
             "Serializer unable to connect to socket {:?}.",
             &config.socket
         )
     })?;
     let reader = serializer.reader().location(loc!())?;
-    let writer = serializer.writer();
-    writer.send(serialization::SendType::Object(
-        serialization::Event::WprsClientConnect,
-    ));
 
     let options = ClientOptions {
         title_prefix: config.title_prefix,
+        placeholder: config.placeholder,
+        primary_selection_fallback: config.primary_selection_fallback,
+        selection_clear_behavior: config.selection_clear_behavior,
+        pointer_prediction: config.pointer_prediction,
+        input_arbitration: config.input_arbitration,
+        disconnect_grace_period: Duration::from_secs(config.disconnect_grace_period_secs.into()),
+        clipboard_limit: config.clipboard_limit,
     };
+    let mut event_loop = EventLoop::try_new()?;
     let mut state = WprsClientState::new(
         event_queue.handle(),
+        event_loop.handle(),
         globals,
         conn.clone(),
         serializer,
@@ -161,8 +282,6 @@ fn main() -> Result<()> {
     )
     .location(loc!())?;
 
-    let mut event_loop = EventLoop::try_new()?;
-
     event_loop.handle().insert_source(
         reader,
         |event, _metadata, state: &mut WprsClientState| {
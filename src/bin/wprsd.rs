@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 use std::process::Command;
@@ -35,10 +36,20 @@ use wprs::args;
 use wprs::args::Config;
 use wprs::args::OptionalConfig;
 use wprs::args::SerializableLevel;
+use wprs::clipboard_limit::ClipboardLimit;
+use wprs::control_server;
+use wprs::output_dpi;
 use wprs::prelude::*;
 use wprs::serialization::Serializer;
+use wprs::serialization::wayland::Subpixel;
 use wprs::server::WprsServerState;
+use wprs::server::buffer_tiles::BufferTiles;
+use wprs::server::commit_timing::CommitTimings;
+use wprs::server::input_priority::InputLoop;
+use wprs::server::input_priority::InputPriority;
 use wprs::server::smithay_handlers::ClientState;
+use wprs::server::subpixel::SubpixelOverrides;
+use wprs::sharding_compression::CompressionChoice;
 use wprs::utils;
 
 #[optional_struct]
@@ -52,6 +63,7 @@ pub struct WprsdConfig {
     config_file: PathBuf,
     wayland_display: String,
     socket: PathBuf,
+    control_socket: PathBuf,
     framerate: u32,
     // Optional fields don't get wrapped unless we specify it ourselves
     #[optional_wrap]
@@ -64,6 +76,15 @@ pub struct WprsdConfig {
     xwayland_xdg_shell_wayland_debug: bool,
     xwayland_xdg_shell_args: Vec<String>,
     kde_server_side_decorations: bool,
+    progressive_buffer_threshold: usize,
+    buffer_tile_size: u32,
+    commit_timing: bool,
+    default_dpi: u32,
+    output_debounce_ms: u32,
+    subpixel_overrides: BTreeMap<String, Subpixel>,
+    clipboard_limit: ClipboardLimit,
+    input_priority: InputPriority,
+    buffer_compression: CompressionChoice,
 }
 
 impl Default for WprsdConfig {
@@ -73,6 +94,7 @@ impl Default for WprsdConfig {
             config_file: args::default_config_file("wprsd"),
             wayland_display: "wprs-0".to_string(),
             socket: args::default_socket_path(),
+            control_socket: args::default_control_socket_path("wprsd"),
             framerate: 60,
             log_file: None,
             stderr_log_level: SerializableLevel(Level::INFO),
@@ -83,6 +105,15 @@ impl Default for WprsdConfig {
             xwayland_xdg_shell_wayland_debug: false,
             xwayland_xdg_shell_args: Vec::new(),
             kde_server_side_decorations: false,
+            progressive_buffer_threshold: 0,
+            buffer_tile_size: 256,
+            commit_timing: false,
+            default_dpi: output_dpi::DEFAULT_DPI,
+            output_debounce_ms: 100,
+            subpixel_overrides: BTreeMap::new(),
+            clipboard_limit: ClipboardLimit::default(),
+            input_priority: InputPriority::default(),
+            buffer_compression: CompressionChoice::Auto,
         }
     }
 }
@@ -127,12 +158,65 @@ fn kde_server_side_decorations() -> impl Parser<Option<bool>> {
         .optional()
 }
 
+fn progressive_buffer_threshold() -> impl Parser<Option<usize>> {
+    bpaf::long("progressive-buffer-threshold")
+        .argument::<usize>("BYTES")
+        .help("Send the first frame of buffers of at least this many bytes progressively, as tiles spread over several event loop iterations, with tiles inside the window geometry sent first. This keeps the initial transmission of very large windows from stalling everything else. 0 disables progressive transmission.")
+        .optional()
+}
+
+fn buffer_tile_size() -> impl Parser<Option<u32>> {
+    bpaf::long("buffer-tile-size")
+        .argument::<u32>("PIXELS")
+        .help("Maximum width and height of the tiles used for progressive buffer transmission.")
+        .optional()
+}
+
+fn commit_timing() -> impl Parser<Option<bool>> {
+    bpaf::long("commit-timing")
+        .argument::<bool>("BOOL")
+        .help("Record how long each stage of handling a commit (reading the buffer, building the messages, handing them to the transport) takes, per surface. The timings can be queried with the commit_timings command on the control socket.")
+        .optional()
+}
+
+fn output_debounce_ms() -> impl Parser<Option<u32>> {
+    bpaf::long("output-debounce-ms")
+        .argument::<u32>("MS")
+        .help("Apply changes to outputs (e.g., mode changes) only once no new change has arrived for this many milliseconds, so that a burst of changes reaches X11 and Wayland apps as a single update. 0 applies every change immediately.")
+        .optional()
+}
+
+fn subpixel_overrides() -> impl Parser<Option<BTreeMap<String, Subpixel>>> {
+    bpaf::long("subpixel-overrides")
+        .help("Per-output subpixel orders, as a map from the name of the output on the wprsc side to one of Unknown, None, HorizontalRgb, HorizontalBgr, VerticalRgb or VerticalBgr, e.g. {\"DP-1\": HorizontalRgb}. Other outputs advertise the order reported by wprsc's compositor. Apps, including X11 apps, use it for subpixel antialiasing of text.")
+        .argument::<String>("{OUTPUT_NAME: SUBPIXEL, ...}")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn input_priority() -> impl Parser<Option<InputPriority>> {
+    bpaf::long("input-priority")
+        .help("When to handle input (and other events) from wprsc: Fifo handles it in turn with requests from Wayland apps, InputFirst handles it before each buffer commit, so that input isn't delayed by apps committing many buffers at once.")
+        .argument::<String>("Fifo|InputFirst")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn buffer_compression() -> impl Parser<Option<CompressionChoice>> {
+    bpaf::long("buffer-compression")
+        .help("How buffer contents are compressed before being sent to wprsc. Fixed overrides the --buffer-compression of wprsc. Auto uses the one of wprsc, or, if that is Auto too, None when wprsc connects to the socket directly, i.e. runs on the same machine, and Zstd(level: 1) when the socket is forwarded, e.g. by ssh. None skips compression, which saves CPU time when wprsc runs on the same machine but multiplies the bandwidth used over a network.")
+        .argument::<String>("Auto|Fixed(None)|Fixed(Zstd(level: N))")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
 impl OptionalConfig<WprsdConfig> for OptionalWprsdConfig {
     fn parse_args() -> Self {
         let print_default_config_and_exit = args::print_default_config_and_exit();
         let config_file = args::config_file();
         let wayland_display = args::wayland_display();
         let socket = args::socket();
+        let control_socket = args::control_socket();
         let framerate = args::framerate();
         let log_file = args::log_file();
         let stderr_log_level = args::stderr_log_level();
@@ -143,11 +227,21 @@ impl OptionalConfig<WprsdConfig> for OptionalWprsdConfig {
         let xwayland_xdg_shell_wayland_debug = xwayland_xdg_shell_wayland_debug();
         let xwayland_xdg_shell_args = xwayland_xdg_shell_args();
         let kde_server_side_decorations = kde_server_side_decorations();
+        let progressive_buffer_threshold = progressive_buffer_threshold();
+        let buffer_tile_size = buffer_tile_size();
+        let commit_timing = commit_timing();
+        let default_dpi = args::default_dpi();
+        let output_debounce_ms = output_debounce_ms();
+        let subpixel_overrides = subpixel_overrides();
+        let clipboard_limit = args::clipboard_limit();
+        let input_priority = input_priority();
+        let buffer_compression = buffer_compression();
         bpaf::construct!(Self {
             print_default_config_and_exit,
             config_file,
             wayland_display,
             socket,
+            control_socket,
             framerate,
             log_file,
             stderr_log_level,
@@ -158,6 +252,15 @@ impl OptionalConfig<WprsdConfig> for OptionalWprsdConfig {
             xwayland_xdg_shell_wayland_debug,
             xwayland_xdg_shell_args,
             kde_server_side_decorations,
+            progressive_buffer_threshold,
+            buffer_tile_size,
+            commit_timing,
+            default_dpi,
+            output_debounce_ms,
+            subpixel_overrides,
+            clipboard_limit,
+            input_priority,
+            buffer_compression,
         })
         .to_options()
         .run()
@@ -199,6 +302,7 @@ fn init_wayland_listener(
                 Mode::Level,
             ),
             move |_, _, state| {
+                state.dispatch_input();
                 display.dispatch_clients(state).unwrap();
                 Ok(PostAction::Continue)
             },
@@ -253,6 +357,31 @@ pub fn main() -> Result<()> {
     let display: Display<WprsServerState> = Display::new().location(loc!())?;
 
     let frame_interval = Duration::from_secs_f64(1.0 / (config.framerate as f64));
+    let commit_timings = config.commit_timing.then(CommitTimings::new);
+
+    let input_loop = match config.input_priority {
+        InputPriority::Fifo => None,
+        InputPriority::InputFirst => Some(InputLoop::new().location(loc!())?),
+    };
+    let reader_handle = input_loop
+        .as_ref()
+        .map_or_else(|| event_loop.handle(), InputLoop::handle);
+    if let Some(input_loop) = &input_loop {
+        event_loop
+            .handle()
+            .insert_source(
+                Generic::new(
+                    input_loop.fd().location(loc!())?,
+                    Interest::READ,
+                    Mode::Level,
+                ),
+                |_, _, state: &mut WprsServerState| {
+                    state.dispatch_input();
+                    Ok(PostAction::Continue)
+                },
+            )
+            .location(loc!())?;
+    }
 
     let mut state = WprsServerState::new(
         display.handle(),
@@ -261,8 +390,30 @@ pub fn main() -> Result<()> {
         config.enable_xwayland,
         frame_interval,
         config.kde_server_side_decorations,
+        BufferTiles::new(config.progressive_buffer_threshold, config.buffer_tile_size),
+        commit_timings.clone(),
+        config.default_dpi,
+        Duration::from_millis(config.output_debounce_ms.into()),
+        SubpixelOverrides::new(config.subpixel_overrides),
+        config.clipboard_limit,
+        input_loop,
+        config.buffer_compression,
     );
 
+    control_server::start(config.control_socket, move |input: &str| {
+        Ok(match input {
+            "commit_timings" => commit_timings
+                .as_ref()
+                .ok_or(anyhow!("commit timing is disabled, see --commit-timing"))?
+                .to_json()
+                .location(loc!())?,
+            _ => {
+                bail!("Unknown command: {input:?}")
+            },
+        })
+    })
+    .location(loc!())?;
+
     init_wayland_listener(&config.wayland_display, display, &mut state, &event_loop)
         .location(loc!())?;
 
@@ -281,9 +432,9 @@ pub fn main() -> Result<()> {
         .add_keyboard(Default::default(), 200, 200)
         .location(loc!())?;
     let _pointer = state.seat.add_pointer();
+    let _touch = state.seat.add_touch();
 
-    event_loop
-        .handle()
+    reader_handle
         .insert_source(reader, |event, _metadata, state| {
             match event {
                 Event::Msg(msg) => state.handle_event(msg),
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
 use std::ffi::OsString;
+use std::fs;
 use std::path::PathBuf;
 
 use bpaf::Parser;
@@ -38,11 +40,45 @@ use wprs::args;
 use wprs::args::Config;
 use wprs::args::OptionalConfig;
 use wprs::args::SerializableLevel;
+use wprs::control_server;
+use wprs::dmabuf::DmabufBehavior;
+use wprs::output_dpi;
 use wprs::prelude::*;
 use wprs::utils;
+use wprs::xwayland_xdg_shell::ShellOptions;
 use wprs::xwayland_xdg_shell::WprsState;
+use wprs::xwayland_xdg_shell::compositor::CompositorOptions;
 use wprs::xwayland_xdg_shell::compositor::DecorationBehavior;
+use wprs::xwayland_xdg_shell::compositor::MaximizedFrame;
+use wprs::xwayland_xdg_shell::compositor::TilingMode;
 use wprs::xwayland_xdg_shell::compositor::XwaylandOptions;
+use wprs::xwayland_xdg_shell::configure_timeout::ConfigureTimeout;
+use wprs::xwayland_xdg_shell::csd::CsdDetection;
+use wprs::xwayland_xdg_shell::cursor::CursorThemes;
+use wprs::xwayland_xdg_shell::decoration_rules::DecorationRules;
+use wprs::xwayland_xdg_shell::early_buffer::EarlyBufferBehavior;
+use wprs::xwayland_xdg_shell::focus_loss::FocusLossBehavior;
+use wprs::xwayland_xdg_shell::frame_buttons::FrameButtons;
+use wprs::xwayland_xdg_shell::frame_limit;
+use wprs::xwayland_xdg_shell::frame_pacing::FramePacing;
+use wprs::xwayland_xdg_shell::fullscreen::FullscreenMonitorBehavior;
+use wprs::xwayland_xdg_shell::input_region::EmptyInputRegionBehavior;
+use wprs::xwayland_xdg_shell::mode_change::ModeChangeBehavior;
+use wprs::xwayland_xdg_shell::no_output::NoOutputBehavior;
+use wprs::xwayland_xdg_shell::opacity::OpacityInterpolation;
+use wprs::xwayland_xdg_shell::pending_parents::ParentRaceBehavior;
+use wprs::xwayland_xdg_shell::pointer_constraints::PointerLockEscape;
+use wprs::xwayland_xdg_shell::pointer_leave::PointerLeaveBehavior;
+use wprs::xwayland_xdg_shell::popup_grab::PopupGrabBehavior;
+use wprs::xwayland_xdg_shell::scale_override::ScaleOverrides;
+use wprs::xwayland_xdg_shell::scale_override::UpscaleFilter;
+use wprs::xwayland_xdg_shell::selection_limit::SelectionRateLimit;
+use wprs::xwayland_xdg_shell::surface_limit::SurfaceLimit;
+use wprs::xwayland_xdg_shell::sync_request::SyncRequestBehavior;
+use wprs::xwayland_xdg_shell::title;
+use wprs::xwayland_xdg_shell::title::TitleSource;
+use wprs::xwayland_xdg_shell::window_layer::WindowLayerBehavior;
+use wprs::xwayland_xdg_shell::wmname;
 
 #[optional_struct]
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
@@ -54,6 +90,7 @@ pub struct XwaylandXdgShellConfig {
     #[serde(skip_serializing)]
     config_file: PathBuf,
     wayland_display: String,
+    control_socket: PathBuf,
     display: u32,
     // Optional fields don't get wrapped unless we specify it ourselves
     #[optional_wrap]
@@ -63,6 +100,45 @@ pub struct XwaylandXdgShellConfig {
     log_priv_data: bool,
     xwayland_wayland_debug: bool,
     decoration_behavior: DecorationBehavior,
+    decoration_rules: Vec<(String, DecorationBehavior)>,
+    tiling_mode: TilingMode,
+    maximized_frame: MaximizedFrame,
+    csd_detection: CsdDetection,
+    parent_race_behavior: ParentRaceBehavior,
+    early_buffer_behavior: EarlyBufferBehavior,
+    skip_unchanged_commits: bool,
+    popup_grab_behavior: PopupGrabBehavior,
+    window_layer_behavior: WindowLayerBehavior,
+    fullscreen_monitor_behavior: FullscreenMonitorBehavior,
+    title_source: TitleSource,
+    title_template: String,
+    wm_name: String,
+    configure_timeout: ConfigureTimeout,
+    max_frames_in_flight: u32,
+    frame_pacing: FramePacing,
+    mode_change_behavior: ModeChangeBehavior,
+    scale_overrides: BTreeMap<String, u32>,
+    upscale_filter: UpscaleFilter,
+    focus_loss_behavior: FocusLossBehavior,
+    selection_rate_limit: SelectionRateLimit,
+    forward_primary_selection: bool,
+    opacity_interpolation: OpacityInterpolation,
+    sync_request_behavior: SyncRequestBehavior,
+    no_output_behavior: NoOutputBehavior,
+    empty_input_region_behavior: EmptyInputRegionBehavior,
+    pointer_leave_behavior: PointerLeaveBehavior,
+    pointer_lock_escape: PointerLockEscape,
+    frame_buttons: FrameButtons,
+    default_dpi: u32,
+    dmabuf_behavior: DmabufBehavior,
+    idle_timeout_secs: u32,
+    #[optional_wrap]
+    cursor_theme: Option<String>,
+    #[optional_wrap]
+    cursor_size: Option<u32>,
+    cursor_theme_overrides: BTreeMap<String, String>,
+    snapshot_file: PathBuf,
+    surface_limit: SurfaceLimit,
 }
 
 impl Default for XwaylandXdgShellConfig {
@@ -71,6 +147,7 @@ impl Default for XwaylandXdgShellConfig {
             print_default_config_and_exit: false,
             config_file: args::default_config_file("xwayland-xdg-shell"),
             wayland_display: "xwayland-xdg-shell-0".to_string(),
+            control_socket: args::default_control_socket_path("xwayland-xdg-shell"),
             display: 100,
             log_file: None,
             stderr_log_level: SerializableLevel(Level::INFO),
@@ -78,6 +155,46 @@ impl Default for XwaylandXdgShellConfig {
             log_priv_data: false,
             xwayland_wayland_debug: false,
             decoration_behavior: DecorationBehavior::Auto,
+            decoration_rules: Vec::new(),
+            tiling_mode: TilingMode::Detect,
+            maximized_frame: MaximizedFrame::Shown,
+            csd_detection: CsdDetection::GtkFrameExtents,
+            parent_race_behavior: ParentRaceBehavior::Queue,
+            early_buffer_behavior: EarlyBufferBehavior::Retain,
+            skip_unchanged_commits: true,
+            popup_grab_behavior: PopupGrabBehavior::Dismiss,
+            window_layer_behavior: WindowLayerBehavior::LayerShell,
+            fullscreen_monitor_behavior: FullscreenMonitorBehavior::Honor,
+            title_source: TitleSource::NetWmName,
+            title_template: title::DEFAULT_TITLE_TEMPLATE.to_string(),
+            wm_name: wmname::DEFAULT_WMNAME.to_string(),
+            configure_timeout: ConfigureTimeout::Enabled { timeout_ms: 2000 },
+            max_frames_in_flight: frame_limit::DEFAULT_MAX_FRAMES_IN_FLIGHT,
+            frame_pacing: FramePacing::Refresh,
+            mode_change_behavior: ModeChangeBehavior::Hold { timeout_ms: 500 },
+            scale_overrides: BTreeMap::new(),
+            upscale_filter: UpscaleFilter::Nearest,
+            focus_loss_behavior: FocusLossBehavior::MostRecentlyUsed,
+            selection_rate_limit: SelectionRateLimit::Limited { max_per_sec: 10 },
+            forward_primary_selection: true,
+            opacity_interpolation: OpacityInterpolation::Linear { max_ms: 100 },
+            sync_request_behavior: SyncRequestBehavior::Enabled { timeout_ms: 500 },
+            no_output_behavior: NoOutputBehavior::Wait,
+            empty_input_region_behavior: EmptyInputRegionBehavior::ClickThrough,
+            pointer_leave_behavior: PointerLeaveBehavior::Leave,
+            pointer_lock_escape: PointerLockEscape::default(),
+            frame_buttons: FrameButtons::default(),
+            default_dpi: output_dpi::DEFAULT_DPI,
+            dmabuf_behavior: DmabufBehavior::Disabled,
+            // Matches the X server's default screensaver timeout.
+            idle_timeout_secs: 600,
+            cursor_theme: None,
+            cursor_size: None,
+            cursor_theme_overrides: BTreeMap::new(),
+            snapshot_file: args::default_snapshot_file("xwayland-xdg-shell"),
+            surface_limit: SurfaceLimit::Limited {
+                max_per_client: 4096,
+            },
         }
     }
 }
@@ -105,11 +222,293 @@ fn decoration_behavior() -> impl Parser<Option<DecorationBehavior>> {
         .optional()
 }
 
+fn decoration_rules() -> impl Parser<Option<Vec<(String, DecorationBehavior)>>> {
+    bpaf::long("decoration-rules")
+        .help("Per-application overrides of --decoration-behavior, as a list of rules matching the WM_CLASS class or instance of a window against a glob pattern (* and ? are supported), e.g. [(\"jetbrains-*\", AlwaysDisabled)]. The first matching rule wins, windows no rule matches use --decoration-behavior.")
+        .argument::<String>("[(PATTERN, Auto|AlwaysEnabled|AlwaysDisabled), ...]")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn tiling_mode() -> impl Parser<Option<TilingMode>> {
+    bpaf::long("tiling-mode")
+        .help("Whether the local compositor tiles windows. With --decoration-behavior Auto, tiled windows get no decorations from us and we ask the local compositor not to decorate them either, though it may still choose to. Detect treats windows as tiled when the local compositor reports them as tiled, Tiling and Floating override detection.")
+        .argument::<String>("Detect|Tiling|Floating")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn maximized_frame() -> impl Parser<Option<MaximizedFrame>> {
+    bpaf::long("maximized-frame")
+        .help("Whether the frame we draw around windows is kept when they're maximized. Hidden drops it, so that the contents of maximized windows fill the whole maximized area. The frame of windows decorated by the local compositor isn't affected.")
+        .argument::<String>("Shown|Hidden")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn csd_detection() -> impl Parser<Option<CsdDetection>> {
+    bpaf::long("csd-detection")
+        .help("How to detect X11 apps which draw their own decorations. With --decoration-behavior Auto, such windows get no decorations from us and we ask the local compositor not to decorate them either. GtkFrameExtents detects both apps advertising _GTK_FRAME_EXTENTS, whose shadow is then kept out of the window geometry, and apps asking not to be decorated with _MOTIF_WM_HINTS. MotifHints only detects the latter.")
+        .argument::<String>("GtkFrameExtents|MotifHints")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn parent_race_behavior() -> impl Parser<Option<ParentRaceBehavior>> {
+    bpaf::long("parent-race-behavior")
+        .help("What to do with a child window which is committed before its parent has been mapped. Queue holds the child until the parent is mapped, Orphan maps the child immediately without a parent.")
+        .argument::<String>("Queue|Orphan")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn early_buffer_behavior() -> impl Parser<Option<EarlyBufferBehavior>> {
+    bpaf::long("early-buffer-behavior")
+        .help("What to do with a buffer which is committed before its window has been mapped. Retain displays the buffer as soon as the window is mapped, Discard waits for the window's next commit.")
+        .argument::<String>("Retain|Discard")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn skip_unchanged_commits() -> impl Parser<Option<bool>> {
+    bpaf::long("skip-unchanged-commits")
+        .help("Whether to skip sending buffers which are identical to the previously committed buffer. Disabling this can be useful for debugging.")
+        .argument::<bool>("BOOL")
+        .optional()
+}
+
+fn popup_grab_behavior() -> impl Parser<Option<PopupGrabBehavior>> {
+    bpaf::long("popup-grab-behavior")
+        .help("What to do when the local compositor dismisses an X11 menu (e.g., because the user clicked outside of it). Dismiss unmaps the X11 menu, Ignore leaves it to the X11 app.")
+        .argument::<String>("Dismiss|Ignore")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn window_layer_behavior() -> impl Parser<Option<WindowLayerBehavior>> {
+    bpaf::long("window-layer-behavior")
+        .help("How to map X11 desktop windows (e.g., wallpapers) and docks (e.g., panels). LayerShell maps desktops to the background layer and docks to the top layer if the local compositor supports wlr-layer-shell, Toplevel maps them as regular windows.")
+        .argument::<String>("LayerShell|Toplevel")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn fullscreen_monitor_behavior() -> impl Parser<Option<FullscreenMonitorBehavior>> {
+    bpaf::long("fullscreen-monitor-behavior")
+        .help("What to do when an X11 app asks to go fullscreen on a specific monitor (_NET_WM_FULLSCREEN_MONITORS). Honor goes fullscreen on that monitor, falling back to the window's current output if it isn't available, Ignore lets the local compositor pick the output.")
+        .argument::<String>("Honor|Ignore")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn title_source() -> impl Parser<Option<TitleSource>> {
+    bpaf::long("title-source")
+        .help("The X11 property window titles are read from. NetWmName uses _NET_WM_NAME, falling back to WM_NAME for windows which don't set it, WmName always uses WM_NAME, Class uses the class from WM_CLASS.")
+        .argument::<String>("NetWmName|WmName|Class")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn title_template() -> impl Parser<Option<String>> {
+    bpaf::long("title-template")
+        .help("Template for window titles. {title} is replaced by the title read from --title-source, {class} and {instance} by the class and instance name from WM_CLASS. Placeholders for properties a window doesn't set are dropped along with the text separating them from their neighboring placeholders.")
+        .argument::<String>("TEMPLATE")
+        .optional()
+}
+
+fn wm_name() -> impl Parser<Option<String>> {
+    bpaf::long("wm-name")
+        .help("The window manager name advertised to X11 apps with _NET_WM_NAME on the root window. The default, LG3D, makes Java AWT/Swing apps lay out their windows correctly, but some apps change their behavior based on it.")
+        .argument::<String>("NAME")
+        .optional()
+}
+
+fn configure_timeout() -> impl Parser<Option<ConfigureTimeout>> {
+    bpaf::long("configure-timeout")
+        .help("What to do if the local compositor doesn't send the initial configure of a window, which keeps the window from being shown. Enabled commits the window again after timeout_ms and then with increasing delays until it's configured, Disabled waits indefinitely.")
+        .argument::<String>("Disabled|Enabled(timeout_ms: MS)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn max_frames_in_flight() -> impl Parser<Option<u32>> {
+    bpaf::long("max-frames-in-flight")
+        .help("Maximum number of frames per window which were sent to the local compositor but not displayed yet. Once it's reached, frames which an app renders in the meantime are dropped and only the latest one is sent. 0 is unlimited.")
+        .argument::<u32>("N")
+        .optional()
+}
+
+fn frame_pacing() -> impl Parser<Option<FramePacing>> {
+    bpaf::long("frame-pacing")
+        .help("When to send the frame callbacks of surfaces which aren't displayed by the local compositor, like cursors and surfaces without an X11 window. Refresh sends them at most once per refresh interval of the surface's outputs, so that apps render at the display rate, Immediate sends them as soon as the surface is committed.")
+        .argument::<String>("Immediate|Refresh")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn mode_change_behavior() -> impl Parser<Option<ModeChangeBehavior>> {
+    bpaf::long("mode-change-behavior")
+        .help("What to do with frames of maximized and fullscreen windows which still have the old size after the mode of their output changed. Hold keeps showing the previous frame until the app has resized or timeout_ms has passed, Present shows them as they are.")
+        .argument::<String>("Present|Hold(timeout_ms: MS)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn scale_overrides() -> impl Parser<Option<BTreeMap<String, u32>>> {
+    bpaf::long("scale-overrides")
+        .help("Per-application scales for apps which don't support HiDPI, as a map from WM_CLASS to an integer scale, e.g. {\"XTerm\": 2}. The app's windows are upscaled by that factor with --upscale-filter.")
+        .argument::<String>("{WM_CLASS: SCALE, ...}")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn upscale_filter() -> impl Parser<Option<UpscaleFilter>> {
+    bpaf::long("upscale-filter")
+        .help("How windows of apps in --scale-overrides are upscaled. Nearest keeps them crisp but blocky, Linear smooths them.")
+        .argument::<String>("Nearest|Linear")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn focus_loss_behavior() -> impl Parser<Option<FocusLossBehavior>> {
+    bpaf::long("focus-loss-behavior")
+        .help("Where the keyboard focus goes when the focused window is closed. MostRecentlyUsed focuses the window which was focused before it, NextInStack the window below it, None leaves the keyboard unfocused until the local compositor focuses another window.")
+        .argument::<String>("MostRecentlyUsed|NextInStack|None")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn selection_rate_limit() -> impl Parser<Option<SelectionRateLimit>> {
+    bpaf::long("selection-rate-limit")
+        .help("How often X11 apps may change the clipboard or primary selection. Changes beyond max_per_sec are coalesced and only the latest is forwarded once the rate drops, so apps grabbing the selection in a loop don't flood the local compositor.")
+        .argument::<String>("Unlimited|Limited(max_per_sec: N)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn forward_primary_selection() -> impl Parser<Option<bool>> {
+    bpaf::long("forward-primary-selection")
+        .help("Whether to forward the primary selection, which X11 apps set whenever text is selected. When disabled, only explicit copies to the clipboard are forwarded.")
+        .argument::<bool>("BOOL")
+        .optional()
+}
+
+fn opacity_interpolation() -> impl Parser<Option<OpacityInterpolation>> {
+    bpaf::long("opacity-interpolation")
+        .help("How to forward window opacity changes (e.g., fade animations), which are forwarded at most once per frame. Linear fades between the opacities set by the app, taking at most max_ms to reach a new opacity; Disabled forwards the latest opacity as is.")
+        .argument::<String>("Disabled|Linear(max_ms: N)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn sync_request_behavior() -> impl Parser<Option<SyncRequestBehavior>> {
+    bpaf::long("sync-request-behavior")
+        .help("Whether to hold the frames of X11 apps supporting _NET_WM_SYNC_REQUEST while they redraw after a resize, so that half-drawn frames don't flicker. Apps which don't finish drawing within timeout_ms have their frames shown anyway.")
+        .argument::<String>("Disabled|Enabled(timeout_ms: N)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn no_output_behavior() -> impl Parser<Option<NoOutputBehavior>> {
+    bpaf::long("no-output-behavior")
+        .help("What to do with X11 windows shown before the local compositor has announced any output. Wait holds them until the first output appears, AssumeDefaults shows them right away and moves them into place once it does.")
+        .argument::<String>("Wait|AssumeDefaults")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn empty_input_region_behavior() -> impl Parser<Option<EmptyInputRegionBehavior>> {
+    bpaf::long("empty-input-region-behavior")
+        .help("What to do with X11 windows whose input region is empty. ClickThrough lets input pass through them to the windows beneath, as on X11. Interactive makes them take input on their whole surface, which can help with apps which set an empty input shape by mistake.")
+        .argument::<String>("ClickThrough|Interactive")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn pointer_leave_behavior() -> impl Parser<Option<PointerLeaveBehavior>> {
+    bpaf::long("pointer-leave-behavior")
+        .help("What an X11 window sees when the pointer leaves it for something other than an X11 window, e.g. the desktop. Leave sends it a leave, Retain keeps the pointer in it at the position it left it, which some games need to keep scrolling while the pointer is at the edge of their window.")
+        .argument::<String>("Leave|Retain")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn pointer_lock_escape() -> impl Parser<Option<PointerLockEscape>> {
+    bpaf::long("pointer-lock-escape")
+        .help("Keys which, held together, release the pointer from an X11 window which locked or confined it, until the window loses the keyboard focus. Keys are evdev keycodes (see linux/input-event-codes.h), the default is Keys([29, 42, 1]), i.e. Left Ctrl + Left Shift + Escape. Disabled leaves only moving the keyboard focus to another window to release the pointer.")
+        .argument::<String>("Keys([KEYCODE, ...])|Disabled")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn frame_buttons() -> impl Parser<Option<FrameButtons>> {
+    bpaf::long("frame-buttons")
+        .help("What the close, maximize and minimize buttons of the window frame drawn around X11 windows do. Close sends WM_DELETE_WINDOW, ToggleMaximize maximizes or unmaximizes the window, Minimize minimizes it and Ignore does nothing.")
+        .argument::<String>("(close: ACTION, maximize: ACTION, minimize: ACTION)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn dmabuf_behavior() -> impl Parser<Option<DmabufBehavior>> {
+    bpaf::long("dmabuf-behavior")
+        .help("Whether to let X11 apps render with the GPU. Readback offers linux-dmabuf for the GPU with the given render node (e.g. Readback(render_node: \"/dev/dri/renderD128\")), buffers are read back when committed, which requires them to be linear. Disabled makes apps render in software.")
+        .argument::<String>("Disabled|Readback(render_node: PATH)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn idle_timeout_secs() -> impl Parser<Option<u32>> {
+    bpaf::long("idle-timeout-secs")
+        .help("Seconds of local inactivity after which the X screensaver is activated. 0 disables idle forwarding.")
+        .argument::<u32>("SECS")
+        .optional()
+}
+
+fn cursor_theme() -> impl Parser<Option<Option<String>>> {
+    bpaf::long("cursor-theme")
+        .help("Cursor theme to use for named cursors. Defaults to the XCURSOR_THEME environment variable.")
+        .argument::<String>("NAME")
+        .optional()
+        .map(|s| s.map(Some))
+}
+
+fn cursor_size() -> impl Parser<Option<Option<u32>>> {
+    bpaf::long("cursor-size")
+        .help("Base size of cursors loaded from the cursor theme, which is multiplied by the scale of the output the cursor is on. Defaults to the XCURSOR_SIZE environment variable, or 24.")
+        .argument::<u32>("SIZE")
+        .optional()
+        .map(|s| s.map(Some))
+}
+
+fn cursor_theme_overrides() -> impl Parser<Option<BTreeMap<String, String>>> {
+    bpaf::long("cursor-theme-overrides")
+        .help("Per-application cursor themes, as a map from WM_CLASS to cursor theme name, e.g. {\"Gimp\": \"Adwaita\"}. Applications without an override use --cursor-theme.")
+        .argument::<String>("{WM_CLASS: THEME, ...}")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
+fn snapshot_file() -> impl Parser<Option<PathBuf>> {
+    bpaf::long("snapshot-file")
+        .help("Where to write a JSON snapshot of the compositor state when receiving SIGUSR1. Useful for attaching to bug reports, contains no window contents, and window titles only with --log-priv-data.")
+        .argument::<PathBuf>("PATH")
+        .optional()
+}
+
+fn surface_limit() -> impl Parser<Option<SurfaceLimit>> {
+    bpaf::long("surface-limit")
+        .help("How many surfaces each Wayland client may have. Surfaces beyond the limit are never mapped, which protects against apps leaking windows. Note that all X11 apps share xwayland's client. The current counts can be queried with the surface_counts command on the control socket.")
+        .argument::<String>("Unlimited|Limited(max_per_client: N)")
+        .parse(|s| ron::from_str(&s))
+        .optional()
+}
+
 impl OptionalConfig<XwaylandXdgShellConfig> for OptionalXwaylandXdgShellConfig {
     fn parse_args() -> Self {
         let print_default_config_and_exit = args::print_default_config_and_exit();
         let config_file = args::config_file();
         let wayland_display = args::wayland_display();
+        let control_socket = args::control_socket();
         let display = display();
         let log_file = args::log_file();
         let stderr_log_level = args::stderr_log_level();
@@ -117,10 +516,48 @@ impl OptionalConfig<XwaylandXdgShellConfig> for OptionalXwaylandXdgShellConfig {
         let log_priv_data = args::log_priv_data();
         let xwayland_wayland_debug = xwayland_wayland_debug();
         let decoration_behavior = decoration_behavior();
+        let decoration_rules = decoration_rules();
+        let tiling_mode = tiling_mode();
+        let maximized_frame = maximized_frame();
+        let csd_detection = csd_detection();
+        let parent_race_behavior = parent_race_behavior();
+        let early_buffer_behavior = early_buffer_behavior();
+        let skip_unchanged_commits = skip_unchanged_commits();
+        let popup_grab_behavior = popup_grab_behavior();
+        let window_layer_behavior = window_layer_behavior();
+        let fullscreen_monitor_behavior = fullscreen_monitor_behavior();
+        let title_source = title_source();
+        let title_template = title_template();
+        let wm_name = wm_name();
+        let configure_timeout = configure_timeout();
+        let max_frames_in_flight = max_frames_in_flight();
+        let frame_pacing = frame_pacing();
+        let mode_change_behavior = mode_change_behavior();
+        let scale_overrides = scale_overrides();
+        let upscale_filter = upscale_filter();
+        let focus_loss_behavior = focus_loss_behavior();
+        let selection_rate_limit = selection_rate_limit();
+        let forward_primary_selection = forward_primary_selection();
+        let opacity_interpolation = opacity_interpolation();
+        let sync_request_behavior = sync_request_behavior();
+        let no_output_behavior = no_output_behavior();
+        let empty_input_region_behavior = empty_input_region_behavior();
+        let pointer_leave_behavior = pointer_leave_behavior();
+        let pointer_lock_escape = pointer_lock_escape();
+        let frame_buttons = frame_buttons();
+        let default_dpi = args::default_dpi();
+        let dmabuf_behavior = dmabuf_behavior();
+        let idle_timeout_secs = idle_timeout_secs();
+        let cursor_theme = cursor_theme();
+        let cursor_size = cursor_size();
+        let cursor_theme_overrides = cursor_theme_overrides();
+        let snapshot_file = snapshot_file();
+        let surface_limit = surface_limit();
         bpaf::construct!(Self {
             print_default_config_and_exit,
             config_file,
             wayland_display,
+            control_socket,
             display,
             log_file,
             stderr_log_level,
@@ -128,6 +565,43 @@ impl OptionalConfig<XwaylandXdgShellConfig> for OptionalXwaylandXdgShellConfig {
             log_priv_data,
             xwayland_wayland_debug,
             decoration_behavior,
+            decoration_rules,
+            tiling_mode,
+            maximized_frame,
+            csd_detection,
+            parent_race_behavior,
+            early_buffer_behavior,
+            skip_unchanged_commits,
+            popup_grab_behavior,
+            window_layer_behavior,
+            fullscreen_monitor_behavior,
+            title_source,
+            title_template,
+            wm_name,
+            configure_timeout,
+            max_frames_in_flight,
+            frame_pacing,
+            mode_change_behavior,
+            scale_overrides,
+            upscale_filter,
+            focus_loss_behavior,
+            selection_rate_limit,
+            forward_primary_selection,
+            opacity_interpolation,
+            sync_request_behavior,
+            no_output_behavior,
+            empty_input_region_behavior,
+            pointer_leave_behavior,
+            pointer_lock_escape,
+            frame_buttons,
+            default_dpi,
+            dmabuf_behavior,
+            idle_timeout_secs,
+            cursor_theme,
+            cursor_size,
+            cursor_theme_overrides,
+            snapshot_file,
+            surface_limit,
         })
         .to_options()
         .run()
@@ -201,17 +675,73 @@ pub fn main() -> Result<()> {
         display: Some(config.display),
     };
 
+    let options = ShellOptions {
+        compositor: CompositorOptions {
+            decoration_behavior: config.decoration_behavior,
+            decoration_rules: DecorationRules::new(config.decoration_rules),
+            tiling_mode: config.tiling_mode,
+            maximized_frame: config.maximized_frame,
+            csd_detection: config.csd_detection,
+            parent_race_behavior: config.parent_race_behavior,
+            early_buffer_behavior: config.early_buffer_behavior,
+            skip_unchanged_commits: config.skip_unchanged_commits,
+            popup_grab_behavior: config.popup_grab_behavior,
+            window_layer_behavior: config.window_layer_behavior,
+            fullscreen_monitor_behavior: config.fullscreen_monitor_behavior,
+            title_source: config.title_source,
+            title_template: config.title_template,
+            wm_name: config.wm_name,
+            configure_timeout: config.configure_timeout,
+            max_frames_in_flight: config.max_frames_in_flight,
+            frame_pacing: config.frame_pacing,
+            mode_change_behavior: config.mode_change_behavior,
+            scale_overrides: ScaleOverrides::new(config.scale_overrides, config.upscale_filter),
+            focus_loss_behavior: config.focus_loss_behavior,
+            selection_rate_limit: config.selection_rate_limit,
+            forward_primary_selection: config.forward_primary_selection,
+            opacity_interpolation: config.opacity_interpolation,
+            sync_request_behavior: config.sync_request_behavior,
+            no_output_behavior: config.no_output_behavior,
+            empty_input_region_behavior: config.empty_input_region_behavior,
+            pointer_leave_behavior: config.pointer_leave_behavior,
+            pointer_lock_escape: config.pointer_lock_escape,
+            default_dpi: config.default_dpi,
+            dmabuf_behavior: config.dmabuf_behavior,
+        },
+        frame_buttons: config.frame_buttons,
+        idle_timeout_ms: config.idle_timeout_secs.saturating_mul(1000),
+        cursor_themes: CursorThemes::new(
+            config.cursor_theme,
+            config.cursor_size,
+            config.cursor_theme_overrides,
+        ),
+        surface_limit: config.surface_limit,
+    };
+
     let mut state = WprsState::new(
         display.handle(),
         &globals,
         event_queue.handle(),
         conn.clone(),
         event_loop.handle(),
-        config.decoration_behavior,
+        options,
         xwayland_options,
     )
     .location(loc!())?;
 
+    let surface_counts = state.surface_counts.clone();
+    let session_windows = state.session_windows.clone();
+    control_server::start(config.control_socket, move |input: &str| {
+        Ok(match input {
+            "surface_counts" => surface_counts.to_json().location(loc!())?,
+            "session_windows" => session_windows.to_json().location(loc!())?,
+            _ => {
+                bail!("Unknown command: {input:?}")
+            },
+        })
+    })
+    .location(loc!())?;
+
     init_wayland_listener(
         &config.wayland_display,
         display,
@@ -220,13 +750,6 @@ pub fn main() -> Result<()> {
     )
     .location(loc!())?;
 
-    let seat = &mut state.compositor_state.seat;
-    // TODO: do this in WprsState::new;
-    let _keyboard = seat
-        .add_keyboard(Default::default(), 200, 200)
-        .location(loc!())?;
-    let _pointer = seat.add_pointer();
-
     WaylandSource::new(conn, event_queue)
         .insert(event_loop.handle())
         .location(loc!())?;
@@ -243,6 +766,19 @@ pub fn main() -> Result<()> {
         )
         .location(loc!())?;
 
+    let snapshot_file = config.snapshot_file;
+    event_loop
+        .handle()
+        .insert_source(
+            Signals::new(&[Signal::SIGUSR1]).location(loc!())?,
+            move |_event, _metadata, state| {
+                let snapshot = log_and_return!(state.snapshot().to_json());
+                log_and_return!(fs::write(&snapshot_file, snapshot));
+                info!("wrote state snapshot to {snapshot_file:?}");
+            },
+        )
+        .location(loc!())?;
+
     event_loop
         .run(None, &mut state, move |state| {
             state.dh.flush_clients().unwrap();
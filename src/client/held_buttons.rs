@@ -0,0 +1,112 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Tracking of held pointer buttons. wl_pointer.enter doesn't say which buttons
+/// are held, but the pointer can enter a surface with a button held, e.g. when
+/// a menu is mapped under the pointer while the button which opened it is
+/// still down. The held buttons are sent along with enter events so that wprsd
+/// can press them on the newly entered surface.
+use std::collections::BTreeSet;
+
+use smithay_client_toolkit::seat::pointer::PointerEventKind;
+
+#[derive(Debug, Default)]
+pub struct HeldButtons(BTreeSet<u32>);
+
+impl HeldButtons {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, kind: &PointerEventKind) {
+        match kind {
+            PointerEventKind::Press { button, .. } => {
+                self.0.insert(*button);
+            },
+            PointerEventKind::Release { button, .. } => {
+                self.0.remove(button);
+            },
+            _ => {},
+        }
+    }
+
+    /// Forgets all held buttons. Needed when the local compositor takes over
+    /// the pointer (e.g., for an interactive move), as we then won't see the
+    /// releases.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        self.0.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay_client_toolkit::seat::pointer::BTN_LEFT;
+    use smithay_client_toolkit::seat::pointer::BTN_RIGHT;
+
+    use super::*;
+
+    fn press(button: u32) -> PointerEventKind {
+        PointerEventKind::Press {
+            time: 0,
+            button,
+            serial: 0,
+        }
+    }
+
+    fn release(button: u32) -> PointerEventKind {
+        PointerEventKind::Release {
+            time: 0,
+            button,
+            serial: 0,
+        }
+    }
+
+    #[test]
+    fn enter_with_button_held() {
+        let mut held = HeldButtons::new();
+        // Press on one surface, then leave it for another surface (e.g., a
+        // menu mapped under the pointer) without releasing.
+        held.update(&PointerEventKind::Enter { serial: 0 });
+        held.update(&press(BTN_LEFT));
+        held.update(&PointerEventKind::Leave { serial: 1 });
+        assert_eq!(held.to_vec(), vec![BTN_LEFT]);
+
+        held.update(&release(BTN_LEFT));
+        assert!(held.to_vec().is_empty());
+    }
+
+    #[test]
+    fn multiple_buttons_held() {
+        let mut held = HeldButtons::new();
+        held.update(&press(BTN_RIGHT));
+        held.update(&press(BTN_LEFT));
+        held.update(&PointerEventKind::Motion { time: 0 });
+        assert_eq!(held.to_vec(), vec![BTN_LEFT, BTN_RIGHT]);
+
+        held.update(&release(BTN_RIGHT));
+        assert_eq!(held.to_vec(), vec![BTN_LEFT]);
+    }
+
+    #[test]
+    fn clear_forgets_buttons() {
+        let mut held = HeldButtons::new();
+        held.update(&press(BTN_LEFT));
+        held.clear();
+        assert!(held.to_vec().is_empty());
+    }
+}
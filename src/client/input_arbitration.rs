@@ -0,0 +1,200 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Arbitration between pointer and touch input used at the same time, e.g. on
+/// a convertible with a touchpad and a touchscreen. Many apps (and most X11
+/// apps, which see touch as emulated pointer input) get confused by a touch
+/// sequence and a pointer button press overlapping, so one of them can be
+/// given priority: input from the other device is dropped while it's active.
+use std::collections::HashSet;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay_client_toolkit::seat::pointer::PointerEventKind;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum InputArbitration {
+    /// Forward both, as the local compositor sends them.
+    #[default]
+    Independent,
+    /// A button press cancels active touches, and touches going down while a
+    /// button is held are dropped.
+    PointerPriority,
+    /// Pointer input other than enter and leave is dropped while touches are
+    /// active.
+    TouchPriority,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Decision {
+    Forward,
+    Drop,
+    /// Cancel the active touch sequence, then forward the event.
+    CancelTouchesAndForward,
+}
+
+impl Decision {
+    fn from_forward(forward: bool) -> Self {
+        if forward { Self::Forward } else { Self::Drop }
+    }
+}
+
+/// Tracks the buttons and touch points which were forwarded, so that only the
+/// releases, ups and motions belonging to them are.
+#[derive(Debug, Default)]
+pub struct InputArbiter {
+    policy: InputArbitration,
+    buttons: HashSet<u32>,
+    touches: HashSet<i32>,
+}
+
+impl InputArbiter {
+    pub fn new(policy: InputArbitration) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn pointer(&mut self, kind: &PointerEventKind) -> Decision {
+        match kind {
+            PointerEventKind::Press { button, .. } => {
+                let decision = match self.policy {
+                    InputArbitration::PointerPriority if !self.touches.is_empty() => {
+                        self.touches.clear();
+                        Decision::CancelTouchesAndForward
+                    },
+                    InputArbitration::TouchPriority if !self.touches.is_empty() => Decision::Drop,
+                    _ => Decision::Forward,
+                };
+                if decision != Decision::Drop {
+                    self.buttons.insert(*button);
+                }
+                decision
+            },
+            PointerEventKind::Release { button, .. } => Decision::from_forward(
+                self.buttons.remove(button) || self.policy == InputArbitration::Independent,
+            ),
+            PointerEventKind::Motion { .. } | PointerEventKind::Axis { .. } => {
+                Decision::from_forward(!self.touch_has_priority())
+            },
+            PointerEventKind::Leave { .. } => {
+                // wprsd releases the buttons held when the pointer leaves.
+                self.buttons.clear();
+                Decision::Forward
+            },
+            PointerEventKind::Enter { .. } => Decision::Forward,
+        }
+    }
+
+    pub fn touch_down(&mut self, id: i32) -> Decision {
+        if self.policy == InputArbitration::PointerPriority && !self.buttons.is_empty() {
+            return Decision::Drop;
+        }
+        self.touches.insert(id);
+        Decision::Forward
+    }
+
+    pub fn touch_up(&mut self, id: i32) -> Decision {
+        Decision::from_forward(self.touches.remove(&id))
+    }
+
+    pub fn touch_motion(&self, id: i32) -> Decision {
+        Decision::from_forward(self.touches.contains(&id))
+    }
+
+    pub fn touch_cancel(&mut self) {
+        self.touches.clear();
+    }
+
+    fn touch_has_priority(&self) -> bool {
+        self.policy == InputArbitration::TouchPriority && !self.touches.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay_client_toolkit::seat::pointer::BTN_LEFT;
+
+    use super::*;
+
+    fn press() -> PointerEventKind {
+        PointerEventKind::Press {
+            time: 0,
+            button: BTN_LEFT,
+            serial: 0,
+        }
+    }
+
+    fn release() -> PointerEventKind {
+        PointerEventKind::Release {
+            time: 0,
+            button: BTN_LEFT,
+            serial: 0,
+        }
+    }
+
+    const MOTION: PointerEventKind = PointerEventKind::Motion { time: 0 };
+
+    #[test]
+    fn independent_forwards_interleaved_input() {
+        let mut arbiter = InputArbiter::new(InputArbitration::Independent);
+        assert_eq!(arbiter.touch_down(0), Decision::Forward);
+        assert_eq!(arbiter.pointer(&press()), Decision::Forward);
+        assert_eq!(arbiter.touch_motion(0), Decision::Forward);
+        assert_eq!(arbiter.pointer(&MOTION), Decision::Forward);
+        assert_eq!(arbiter.touch_up(0), Decision::Forward);
+        assert_eq!(arbiter.pointer(&release()), Decision::Forward);
+    }
+
+    #[test]
+    fn pointer_priority_cancels_touches() {
+        let mut arbiter = InputArbiter::new(InputArbitration::PointerPriority);
+        assert_eq!(arbiter.touch_down(0), Decision::Forward);
+        assert_eq!(arbiter.touch_motion(0), Decision::Forward);
+        assert_eq!(arbiter.pointer(&press()), Decision::CancelTouchesAndForward);
+        // The cancelled touch and new touches are dropped until the button is
+        // released.
+        assert_eq!(arbiter.touch_motion(0), Decision::Drop);
+        assert_eq!(arbiter.touch_down(1), Decision::Drop);
+        assert_eq!(arbiter.pointer(&MOTION), Decision::Forward);
+        assert_eq!(arbiter.touch_up(0), Decision::Drop);
+        assert_eq!(arbiter.touch_up(1), Decision::Drop);
+        assert_eq!(arbiter.pointer(&release()), Decision::Forward);
+        assert_eq!(arbiter.touch_down(2), Decision::Forward);
+    }
+
+    #[test]
+    fn touch_priority_drops_pointer_input() {
+        let mut arbiter = InputArbiter::new(InputArbitration::TouchPriority);
+        assert_eq!(arbiter.pointer(&MOTION), Decision::Forward);
+        assert_eq!(arbiter.touch_down(0), Decision::Forward);
+        assert_eq!(arbiter.pointer(&press()), Decision::Drop);
+        assert_eq!(arbiter.pointer(&MOTION), Decision::Drop);
+        assert_eq!(arbiter.touch_motion(0), Decision::Forward);
+        assert_eq!(arbiter.touch_up(0), Decision::Forward);
+        // The release of the dropped press is dropped as well.
+        assert_eq!(arbiter.pointer(&release()), Decision::Drop);
+        assert_eq!(arbiter.pointer(&MOTION), Decision::Forward);
+    }
+
+    #[test]
+    fn touch_cancel_forgets_touches() {
+        let mut arbiter = InputArbiter::new(InputArbitration::TouchPriority);
+        arbiter.touch_down(0);
+        arbiter.touch_cancel();
+        assert_eq!(arbiter.touch_motion(0), Decision::Drop);
+        assert_eq!(arbiter.pointer(&press()), Decision::Forward);
+    }
+}
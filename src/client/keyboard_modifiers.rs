@@ -0,0 +1,110 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Tracking of the keyboard modifiers and group (layout index). The local
+/// compositor only sends wl_keyboard.modifiers when they change, so wprsd
+/// can miss a layout switch which happened while none of our surfaces had
+/// focus. The last modifiers are resent after every enter event so that the
+/// active layout is always correct on the remote side.
+use crate::serialization::wayland::KeyboardEvent;
+use crate::serialization::wayland::ModifierState;
+
+#[derive(Debug, Default)]
+pub struct KeyboardModifiers(Option<(ModifierState, u32)>);
+
+impl KeyboardModifiers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records new modifiers and returns the event to forward.
+    pub fn update(&mut self, modifier_state: ModifierState, layout_index: u32) -> KeyboardEvent {
+        self.0 = Some((modifier_state, layout_index));
+        KeyboardEvent::Modifiers {
+            modifier_state,
+            layout_index,
+        }
+    }
+
+    /// Returns the event to forward after an enter, if any modifiers were
+    /// received yet.
+    pub fn on_enter(&self) -> Option<KeyboardEvent> {
+        self.0
+            .map(|(modifier_state, layout_index)| KeyboardEvent::Modifiers {
+                modifier_state,
+                layout_index,
+            })
+    }
+
+    /// Forgets the modifiers, e.g. when the keymap changes, as the group may
+    /// not be valid for the new keymap.
+    pub fn clear(&mut self) {
+        self.0 = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_MODIFIERS: ModifierState = ModifierState {
+        ctrl: false,
+        alt: false,
+        shift: false,
+        caps_lock: false,
+        logo: false,
+        num_lock: false,
+    };
+
+    fn layout_index(event: Option<KeyboardEvent>) -> Option<u32> {
+        match event {
+            Some(KeyboardEvent::Modifiers { layout_index, .. }) => Some(layout_index),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn layout_switch_propagates() {
+        let mut modifiers = KeyboardModifiers::new();
+        assert_eq!(modifiers.on_enter(), None);
+
+        assert_eq!(
+            layout_index(Some(modifiers.update(NO_MODIFIERS, 0))),
+            Some(0)
+        );
+        // Switch to the second layout, e.g. with a group toggle while focus is
+        // elsewhere.
+        assert_eq!(
+            layout_index(Some(modifiers.update(NO_MODIFIERS, 1))),
+            Some(1)
+        );
+        assert_eq!(layout_index(modifiers.on_enter()), Some(1));
+
+        let caps_lock = ModifierState {
+            caps_lock: true,
+            ..NO_MODIFIERS
+        };
+        modifiers.update(caps_lock, 1);
+        assert_eq!(
+            modifiers.on_enter(),
+            Some(KeyboardEvent::Modifiers {
+                modifier_state: caps_lock,
+                layout_index: 1,
+            })
+        );
+
+        modifiers.clear();
+        assert_eq!(modifiers.on_enter(), None);
+    }
+}
@@ -16,14 +16,21 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::OnceLock;
+use std::time::Duration;
 
 use bimap::BiMap;
 use enum_as_inner::EnumAsInner;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_manager_v3::ZwpTextInputManagerV3;
 use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewport::WpViewport;
 use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewporter::WpViewporter;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::client::xdg_toplevel_drag_manager_v1::XdgToplevelDragManagerV1;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::client::xdg_toplevel_drag_v1::XdgToplevelDragV1;
+use smithay_client_toolkit::activation::ActivationState;
 use smithay_client_toolkit::compositor::CompositorState;
 use smithay_client_toolkit::compositor::Surface;
 use smithay_client_toolkit::data_device_manager::DataDeviceManagerState;
+use smithay_client_toolkit::data_device_manager::ReadPipe;
 use smithay_client_toolkit::data_device_manager::WritePipe;
 use smithay_client_toolkit::data_device_manager::data_offer::DragOffer;
 use smithay_client_toolkit::data_device_manager::data_offer::SelectionOffer;
@@ -46,6 +53,8 @@ use smithay_client_toolkit::registry::RegistryState;
 use smithay_client_toolkit::registry::SimpleGlobal;
 use smithay_client_toolkit::seat::SeatState;
 use smithay_client_toolkit::seat::pointer::ThemedPointer;
+use smithay_client_toolkit::seat::pointer_constraints::PointerConstraintsState;
+use smithay_client_toolkit::seat::relative_pointer::RelativePointerState;
 use smithay_client_toolkit::shell::WaylandSurface;
 use smithay_client_toolkit::shell::xdg::XdgShell;
 use smithay_client_toolkit::shell::xdg::XdgSurface;
@@ -53,8 +62,23 @@ use smithay_client_toolkit::shm::Shm;
 use smithay_client_toolkit::shm::slot::Buffer as SlotBuffer;
 use smithay_client_toolkit::shm::slot::SlotPool;
 
+use crate::client::held_buttons::HeldButtons;
+use crate::client::input_arbitration::InputArbiter;
+use crate::client::input_arbitration::InputArbitration;
+use crate::client::keyboard_modifiers::KeyboardModifiers;
+use crate::client::pending_role::PendingRole;
+use crate::client::placeholder::SurfacePlaceholder;
+use crate::client::pointer_constraints::RemoteConstraints;
+use crate::client::pointer_prediction::PointerPrediction;
+use crate::client::pointer_prediction::PointerPredictor;
+use crate::client::primary_selection::PrimarySelectionFallback;
+use crate::client::selection_clear::SelectionClearBehavior;
+use crate::client::text_input::LocalTextInput;
+use crate::client_utils::CursorSurface;
 use crate::client_utils::SeatObject;
+use crate::clipboard_limit::ClipboardLimit;
 use crate::constants;
+use crate::data_targets::DataTargets;
 use crate::filtering;
 use crate::prelude::*;
 use crate::serialization::Capabilities;
@@ -69,16 +93,31 @@ use crate::serialization::wayland::Buffer;
 use crate::serialization::wayland::BufferAssignment;
 use crate::serialization::wayland::BufferData;
 use crate::serialization::wayland::BufferMetadata;
+use crate::serialization::wayland::BufferTile;
+use crate::serialization::wayland::ClientSurface;
+use crate::serialization::wayland::DataSource;
+use crate::serialization::wayland::PartialBuffer;
 use crate::serialization::wayland::Region;
 use crate::serialization::wayland::SubsurfacePosition;
+use crate::serialization::wayland::SurfaceState;
 use crate::serialization::wayland::UncompressedBufferData;
 use crate::serialization::wayland::ViewportState;
 use crate::serialization::wayland::WlSurfaceId;
 use crate::vec4u8::Vec4u8s;
 
+mod held_buttons;
+pub mod input_arbitration;
+mod keyboard_modifiers;
+mod pending_role;
+pub mod placeholder;
+pub mod pointer_constraints;
+pub mod pointer_prediction;
+pub mod primary_selection;
+pub mod selection_clear;
 pub mod server_handlers;
 pub mod smithay_handlers;
 mod subsurface;
+pub mod text_input;
 mod xdg_shell;
 
 use smithay_handlers::SubCompositorData;
@@ -104,10 +143,37 @@ impl ObjectBimapExt for ObjectBimap {
 
 pub struct ClientOptions {
     pub title_prefix: String,
+    pub placeholder: SurfacePlaceholder,
+    pub primary_selection_fallback: PrimarySelectionFallback,
+    pub selection_clear_behavior: SelectionClearBehavior,
+    pub pointer_prediction: PointerPrediction,
+    pub input_arbitration: InputArbitration,
+    /// How long to keep the windows of a disconnected remote client before
+    /// destroying them.
+    pub disconnect_grace_period: Duration,
+    pub clipboard_limit: ClipboardLimit,
+}
+
+#[derive(Debug, Clone)]
+pub enum DataOffer {
+    Selection(SelectionOffer),
+    Primary(PrimarySelectionOffer),
+    DnD(DragOffer),
+}
+
+impl DataOffer {
+    fn receive(&self, mime_type: String) -> Option<ReadPipe> {
+        match self {
+            Self::Selection(offer) => offer.receive(mime_type).ok(),
+            Self::Primary(offer) => offer.receive(mime_type).ok(),
+            Self::DnD(offer) => offer.receive(mime_type).ok(),
+        }
+    }
 }
 
 pub struct WprsClientState {
     qh: QueueHandle<WprsClientState>,
+    loop_handle: LoopHandle<'static, WprsClientState>,
     conn: Connection,
     pub capabilities: Arc<OnceLock<Capabilities>>,
 
@@ -119,6 +185,11 @@ pub struct WprsClientState {
     shm_state: Shm,
     xdg_shell_state: XdgShell,
     wp_viewporter: Option<SimpleGlobal<WpViewporter, 1>>,
+    toplevel_drag_manager: Option<SimpleGlobal<XdgToplevelDragManagerV1, 1>>,
+    text_input_manager: Option<SimpleGlobal<ZwpTextInputManagerV3, 1>>,
+    activation_state: Option<ActivationState>,
+    pointer_constraints_state: PointerConstraintsState,
+    relative_pointer_state: RelativePointerState,
 
     data_device_manager_state: DataDeviceManagerState,
     primary_selection_manager_state: Option<PrimarySelectionManagerState>,
@@ -127,15 +198,21 @@ pub struct WprsClientState {
 
     seat_objects: Vec<SeatObject<ThemedPointer>>,
     selection_source: Option<CopyPasteSource>,
-    selection_offer: Option<SelectionOffer>,
-    selection_pipe: Option<WritePipe>,
+    /// The remote selection which selection_source holds, see
+    /// PrimarySelectionFallback.
+    selection_source_target: DataSource,
     dnd_source: Option<DragSource>,
-    dnd_offer: Option<DragOffer>,
-    dnd_pipe: Option<WritePipe>,
+    /// Set while dnd_source is a drag which a remote toplevel can attach to.
+    toplevel_drag: Option<XdgToplevelDragV1>,
     dnd_accept_counter: u32,
     primary_selection_source: Option<PrimarySelectionSource>,
-    primary_selection_pipe: Option<WritePipe>,
-    primary_selection_offer: Option<PrimarySelectionOffer>,
+    primary_selection_fallback: PrimarySelectionFallback,
+    selection_clear_behavior: SelectionClearBehavior,
+    /// Offers from the local compositor, keyed by transfer target.
+    data_offers: DataTargets<DataOffer>,
+    /// Pipes to write transferred data to, keyed by transfer target.
+    data_pipes: DataTargets<WritePipe>,
+    clipboard_limit: ClipboardLimit,
 
     serializer: Serializer<Event, Request>,
     remote_display: RemoteDisplay,
@@ -143,18 +220,44 @@ pub struct WprsClientState {
     pub object_bimap: ObjectBimap,
 
     last_enter_serial: u32,
+    cursor_surface: CursorSurface<ClientSurface>,
     last_implicit_grab_serial: Option<u32>,
     last_mouse_down_serial: Option<u32>,
+    held_buttons: HeldButtons,
+    pointer_predictor: PointerPredictor,
+    input_arbiter: InputArbiter,
+    /// The surfaces forwarded touch points went down on.
+    touch_surfaces: HashMap<i32, WlSurfaceId>,
+    keyboard_modifiers: KeyboardModifiers,
+    /// The last keymap received from the local compositor, resent to wprsd
+    /// after reconnecting.
+    keymap: Option<String>,
     current_focus: Option<WlSurface>,
+    /// Created along with the keyboard if the local compositor supports
+    /// text-input-v3.
+    text_input: Option<LocalTextInput>,
+    pointer_constraints: RemoteConstraints,
 
     title_prefix: String,
+    placeholder: SurfacePlaceholder,
+
+    disconnect_grace_period: Duration,
 
     buffer_cache: Option<UncompressedBufferData>,
+    /// BatchedCommits waiting for the Commit which closes their batch, along
+    /// with their buffer contents.
+    pending_commits: Vec<(
+        ClientId,
+        WlSurfaceId,
+        SurfaceState,
+        Option<UncompressedBufferData>,
+    )>,
 }
 
 impl WprsClientState {
     pub fn new(
         qh: QueueHandle<Self>,
+        loop_handle: LoopHandle<'static, Self>,
         globals: GlobalList,
         conn: Connection,
         serializer: Serializer<Event, Request>,
@@ -169,6 +272,7 @@ impl WprsClientState {
 
         Ok(Self {
             qh: qh.clone(),
+            loop_handle,
             conn,
             capabilities: Arc::new(OnceLock::new()),
             registry_state: RegistryState::new(&globals),
@@ -186,6 +290,29 @@ impl WprsClientState {
                 .context(loc!(), "wp_viewporter is not available")
                 .warn(loc!())
                 .ok(),
+            toplevel_drag_manager: SimpleGlobal::<XdgToplevelDragManagerV1, 1>::bind(&globals, &qh)
+                .context(
+                    loc!(),
+                    "xdg_toplevel_drag_manager_v1 is not available, windows attached to drags won't follow the pointer",
+                )
+                .warn(loc!())
+                .ok(),
+            text_input_manager: SimpleGlobal::<ZwpTextInputManagerV3, 1>::bind(&globals, &qh)
+                .context(
+                    loc!(),
+                    "zwp_text_input_manager_v3 is not available, text from input methods won't be forwarded",
+                )
+                .warn(loc!())
+                .ok(),
+            activation_state: ActivationState::bind(&globals, &qh)
+                .context(
+                    loc!(),
+                    "xdg_activation_v1 is not available, remote windows can't be activated",
+                )
+                .warn(loc!())
+                .ok(),
+            pointer_constraints_state: PointerConstraintsState::bind(&globals, &qh),
+            relative_pointer_state: RelativePointerState::bind(&globals, &qh),
             data_device_manager_state: DataDeviceManagerState::bind(&globals, &qh)
                 .context(loc!(), "data device manager is not available")?,
             primary_selection_manager_state: PrimarySelectionManagerState::bind(&globals, &qh)
@@ -197,34 +324,70 @@ impl WprsClientState {
 
             seat_objects: Vec::new(),
             selection_source: None,
-            selection_offer: None,
-            selection_pipe: None,
+            selection_source_target: DataSource::Selection,
             dnd_source: None,
-            dnd_offer: None,
-            dnd_pipe: None,
+            toplevel_drag: None,
             dnd_accept_counter: 0,
             primary_selection_source: None,
-            primary_selection_offer: None,
-            primary_selection_pipe: None,
+            primary_selection_fallback: options.primary_selection_fallback,
+            selection_clear_behavior: options.selection_clear_behavior,
+            data_offers: DataTargets::new(),
+            data_pipes: DataTargets::new(),
+            clipboard_limit: options.clipboard_limit,
 
             serializer,
             remote_display: RemoteDisplay::new(),
             object_bimap: BiMap::new(),
 
             last_enter_serial: 0,
+            cursor_surface: CursorSurface::default(),
             last_implicit_grab_serial: None,
             last_mouse_down_serial: None,
+            held_buttons: HeldButtons::new(),
+            pointer_predictor: PointerPredictor::new(options.pointer_prediction),
+            input_arbiter: InputArbiter::new(options.input_arbitration),
+            touch_surfaces: HashMap::new(),
+            keyboard_modifiers: KeyboardModifiers::new(),
+            keymap: None,
             current_focus: None,
+            text_input: None,
+            pointer_constraints: RemoteConstraints::new(),
             title_prefix: options.title_prefix,
+            placeholder: options.placeholder,
+            disconnect_grace_period: options.disconnect_grace_period,
             buffer_cache: None,
+            pending_commits: Vec::new(),
         })
     }
+
+    fn primary_selection_supported(&self) -> bool {
+        self.primary_selection_manager_state.is_some()
+    }
+
+    /// Destroys the toplevel drag object, which must only be done once the
+    /// drag has ended.
+    fn end_toplevel_drag(&mut self) {
+        if let Some(toplevel_drag) = self.toplevel_drag.take() {
+            toplevel_drag.destroy();
+        }
+    }
+
+    fn dnd_offer(&self) -> Option<&DragOffer> {
+        match self.data_offers.get(DataSource::DnD) {
+            Some(DataOffer::DnD(offer)) => Some(offer),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct RemoteBuffer {
     pub metadata: BufferMetadata,
+    /// Empty once the tiles cover the whole buffer, see compact_tiles.
     pub data: Vec4u8s,
+    /// Tiles and partial updates received since data was last replaced, drawn
+    /// on top of data. See BufferTile and PartialBuffer.
+    pub tiles: Vec<(Rectangle<i32>, Vec<u8>)>,
     pub active_buffer: SlotBuffer,
     pub dirty: bool,
 }
@@ -246,6 +409,7 @@ impl RemoteBuffer {
         Ok(Self {
             metadata: buffer_msg.metadata,
             data,
+            tiles: Vec::new(),
             active_buffer,
             dirty: true,
         })
@@ -253,9 +417,123 @@ impl RemoteBuffer {
 
     fn update_data(&mut self, buffer: Buffer) {
         self.data = buffer.data.into_uncompressed().unwrap().0;
+        self.tiles.clear();
         self.dirty = true;
     }
 
+    #[instrument(skip(self, tile_data, pool), level = "debug")]
+    fn apply_tile(
+        &mut self,
+        tile: BufferTile,
+        tile_data: Vec4u8s,
+        pool: &mut SlotPool,
+    ) -> Result<()> {
+        let rect = tile.rect;
+        if tile.metadata != self.metadata {
+            // The buffer was replaced before all of its tiles arrived.
+            debug!("dropping tile for stale buffer {:?}", tile.metadata);
+            return Ok(());
+        }
+        if !self.fits(&rect) || tile_data.len() != (rect.size.w * rect.size.h) as usize {
+            bail!("tile {rect:?} does not fit buffer {:?}", self.metadata);
+        }
+
+        let mut pixels = vec![0; tile_data.len() * 4];
+        filtering::unfilter(&tile_data, &mut pixels);
+        self.draw_rect(rect, pixels, pool).location(loc!())
+    }
+
+    #[instrument(skip(self, data, pool), level = "debug")]
+    fn apply_partial(
+        &mut self,
+        partial: PartialBuffer,
+        data: Vec4u8s,
+        pool: &mut SlotPool,
+    ) -> Result<()> {
+        if partial.metadata != self.metadata {
+            bail!(
+                "partial buffer {:?} does not match buffer {:?}",
+                partial.metadata,
+                self.metadata
+            );
+        }
+        let mut pixels = vec![0; data.len() * 4];
+        filtering::unfilter(&data, &mut pixels);
+
+        let mut rest = pixels.as_slice();
+        for rect in partial.rects {
+            let len = (rect.size.w * rect.size.h) as usize * 4;
+            if !self.fits(&rect) || len > rest.len() {
+                bail!("rect {rect:?} does not fit buffer {:?}", self.metadata);
+            }
+            let (rect_pixels, tail) = rest.split_at(len);
+            self.draw_rect(rect, rect_pixels.to_vec(), pool)
+                .location(loc!())?;
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    fn fits(&self, rect: &Rectangle<i32>) -> bool {
+        rect.loc.x >= 0
+            && rect.loc.y >= 0
+            && rect.size.w > 0
+            && rect.size.h > 0
+            && rect.loc.x + rect.size.w <= self.metadata.width
+            && rect.loc.y + rect.size.h <= self.metadata.height
+    }
+
+    /// Draws `pixels`, tightly packed, at `rect` and keeps them in tiles.
+    fn draw_rect(
+        &mut self,
+        rect: Rectangle<i32>,
+        pixels: Vec<u8>,
+        pool: &mut SlotPool,
+    ) -> Result<()> {
+        match pool.canvas(&self.active_buffer) {
+            Some(canvas) => {
+                blit_tile(canvas, self.metadata.stride, &rect, &pixels);
+                self.tiles.push((rect, pixels));
+            },
+            None => {
+                // write_data has to fill a new buffer from scratch, including
+                // this tile.
+                self.tiles.push((rect, pixels));
+                self.write_data(pool).location(loc!())?;
+            },
+        }
+        self.dirty = true;
+        self.compact_tiles();
+        Ok(())
+    }
+
+    /// Folds data and the tiles into a single tile once the tiles take more
+    /// memory than the buffer itself, e.g. after many partial updates.
+    fn compact_tiles(&mut self) {
+        let tiles_len: usize = self.tiles.iter().map(|(_, pixels)| pixels.len()).sum();
+        if tiles_len <= self.metadata.len() {
+            return;
+        }
+        let mut canvas = vec![0; self.metadata.len()];
+        if !self.data.is_empty() {
+            filtering::unfilter(&self.data, &mut canvas);
+        }
+        for (rect, pixels) in &self.tiles {
+            blit_tile(&mut canvas, self.metadata.stride, rect, pixels);
+        }
+        let row_len = self.metadata.width as usize * 4;
+        let pixels = canvas
+            .chunks_exact(self.metadata.stride as usize)
+            .flat_map(|row| &row[..row_len])
+            .copied()
+            .collect();
+        self.tiles = vec![(
+            Rectangle::new(0, 0, self.metadata.width, self.metadata.height),
+            pixels,
+        )];
+        self.data = Vec4u8s::new();
+    }
+
     #[instrument(skip_all, level = "debug")]
     fn write_data(&mut self, pool: &mut SlotPool) -> Result<()> {
         let canvas = match pool.canvas(&self.active_buffer) {
@@ -277,11 +555,24 @@ impl RemoteBuffer {
                 pool.canvas(&self.active_buffer).location(loc!())?
             },
         };
-        filtering::unfilter(&self.data, canvas);
+        if !self.data.is_empty() {
+            filtering::unfilter(&self.data, canvas);
+        }
+        for (rect, pixels) in &self.tiles {
+            blit_tile(canvas, self.metadata.stride, rect, pixels);
+        }
         Ok(())
     }
 }
 
+fn blit_tile(canvas: &mut [u8], stride: i32, rect: &Rectangle<i32>, pixels: &[u8]) {
+    let row_len = rect.size.w as usize * 4;
+    for (y, row) in (rect.loc.y..).zip(pixels.chunks_exact(row_len)) {
+        let start = (y * stride) as usize + rect.loc.x as usize * 4;
+        canvas[start..start + row_len].copy_from_slice(row);
+    }
+}
+
 #[derive(Debug, EnumAsInner)]
 pub enum Role {
     Cursor(RemoteCursor),
@@ -334,9 +625,12 @@ pub struct RemoteSurface {
     pub client: ClientId,
     pub id: WlSurfaceId,
     pub buffer: Option<RemoteBuffer>,
+    /// Shown until the first buffer arrives, see SurfacePlaceholder.
+    pub placeholder: Option<SlotBuffer>,
     // None when the surface is owned by a role object (e.g., a Window).
     pub local_surface: Option<Surface>,
     pub role: Option<Role>,
+    pub pending_role: PendingRole,
     pub opaque_region: Option<Region>,
     pub input_region: Option<Region>,
     pub z_ordered_children: Vec<SubsurfacePosition>,
@@ -365,8 +659,10 @@ impl RemoteSurface {
             client: client_id,
             id,
             buffer: None,
+            placeholder: None,
             local_surface,
             role: None,
+            pending_role: PendingRole::default(),
             opaque_region: None,
             input_region: None,
             z_ordered_children: vec![SubsurfacePosition {
@@ -450,6 +746,7 @@ impl RemoteSurface {
             },
             // First commit for surface with a buffer.
             None => {
+                self.placeholder = None;
                 self.buffer = Some(RemoteBuffer::new(new_buffer, pool).location(loc!())?);
                 self.buffer.as_mut().unwrap() // we just set this to Some
             },
@@ -461,13 +758,60 @@ impl RemoteSurface {
         Ok(())
     }
 
+    #[instrument(skip(self, tile_data, pool), level = "debug")]
+    pub fn apply_buffer_tile(
+        &mut self,
+        tile: BufferTile,
+        tile_data: UncompressedBufferData,
+        pool: &mut SlotPool,
+    ) -> Result<()> {
+        let Some(buffer) = &mut self.buffer else {
+            debug!("dropping tile for surface without a buffer");
+            return Ok(());
+        };
+        let rect = tile.rect;
+        buffer
+            .apply_tile(tile, tile_data.0, pool)
+            .location(loc!())?;
+        self.frame_damage.get_or_insert_with(Vec::new).push(rect);
+        Ok(())
+    }
+
     #[instrument(skip(self), level = "debug")]
     fn clear_buffer(&mut self) {
         let wl_surface = self.wl_surface().clone();
         self.buffer = None;
+        self.placeholder = None;
         wl_surface.attach(None, 0, 0);
     }
 
+    /// Attaches a placeholder if the surface doesn't have a buffer yet.
+    #[instrument(skip(self, pool), level = "debug")]
+    pub fn draw_placeholder(
+        &mut self,
+        placeholder: SurfacePlaceholder,
+        (width, height): (i32, i32),
+        pool: &mut SlotPool,
+    ) -> Result<()> {
+        if self.buffer.is_some() {
+            return Ok(());
+        }
+        let Some(buffer) = placeholder
+            .create_buffer(width, height, pool)
+            .location(loc!())?
+        else {
+            return Ok(());
+        };
+        let wl_surface = self.wl_surface().clone();
+        // The placeholder is in surface coordinates, the real buffer's scale
+        // is set again on the next commit.
+        wl_surface.set_buffer_scale(1);
+        buffer.attach_to(&wl_surface).location(loc!())?;
+        wl_surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+        self.placeholder = Some(buffer);
+        Ok(())
+    }
+
     #[instrument(skip(self, pool), level = "debug")]
     pub fn apply_buffer(
         &mut self,
@@ -499,6 +843,17 @@ impl RemoteSurface {
 
                 self.set_buffer(new_buffer, pool).location(loc!())?;
             },
+            Some(BufferAssignment::Partial(partial)) => {
+                let data = buffer_cache
+                    .take()
+                    .context(loc!(), "received partial buffer commit without data")?;
+                let Some(buffer) = &mut self.buffer else {
+                    bail!("received partial buffer for a surface without a buffer");
+                };
+                buffer
+                    .apply_partial(partial, data.0, pool)
+                    .location(loc!())?;
+            },
             Some(BufferAssignment::Removed) => {
                 self.clear_buffer();
             },
@@ -598,11 +953,14 @@ impl RemoteSurface {
 
         // skip if the viewport state hasn't changed
         if self.current_viewport_state != Some(viewport_state) {
-            if let Some(src) = viewport_state.src {
-                viewport.set_source(src.loc.x, src.loc.y, src.size.w, src.size.h);
+            // -1 unsets the source or destination.
+            match viewport_state.src {
+                Some(src) => viewport.set_source(src.loc.x, src.loc.y, src.size.w, src.size.h),
+                None => viewport.set_source(-1.0, -1.0, -1.0, -1.0),
             }
-            if let Some(dst) = viewport_state.dst {
-                viewport.set_destination(dst.w, dst.h);
+            match viewport_state.dst {
+                Some(dst) => viewport.set_destination(dst.w, dst.h),
+                None => viewport.set_destination(-1, -1),
             }
             self.current_viewport_state = Some(viewport_state);
         }
@@ -718,10 +1076,10 @@ pub struct RemoteCursor {
 }
 
 impl RemoteCursor {
-    pub fn set_role(client_id: ClientId, surface: &mut RemoteSurface) {
+    pub fn set_role(client_id: ClientId, hotspot: Point<i32>, surface: &mut RemoteSurface) {
         let remote_cursor = Self {
             client: client_id,
-            hotspot: Point { x: 0, y: 0 }, // TODO
+            hotspot,
         };
         surface.role = Some(Role::Cursor(remote_cursor));
     }
@@ -761,15 +1119,28 @@ impl RemoteClient {
 #[derive(Debug)]
 pub struct RemoteDisplay {
     pub clients: HashMap<ClientId, RemoteClient>,
+    /// Clients which disconnected but whose windows are kept until their
+    /// disconnect grace period ends.
+    pub departed_clients: HashSet<ClientId>,
 }
 
 impl RemoteDisplay {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            departed_clients: HashSet::new(),
         }
     }
 
+    pub fn is_connected(&self, id: &ClientId) -> bool {
+        self.clients.contains_key(id) && !self.departed_clients.contains(id)
+    }
+
+    pub fn remove_client(&mut self, id: &ClientId) {
+        self.departed_clients.remove(id);
+        self.clients.remove(id);
+    }
+
     pub fn client(&mut self, id: &ClientId) -> &mut RemoteClient {
         self.clients.entry(*id).or_insert(RemoteClient::new(*id))
     }
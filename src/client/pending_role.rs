@@ -0,0 +1,89 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Hiding of surfaces which are committed with a buffer before they get a role.
+/// How the local compositor displays a roleless surface is undefined, and it
+/// has no position yet, so the buffer is kept but not attached until a role is
+/// assigned. It's then drawn as part of the commit which assigns the role, or,
+/// for cursors, when the surface becomes the cursor.
+use std::mem;
+
+use crate::prelude::*;
+
+#[derive(Debug, Default)]
+pub struct PendingRole {
+    hidden: bool,
+}
+
+impl PendingRole {
+    /// Whether the surface's buffer is being held back until it gets a role.
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Updates the state for a commit of a surface which does or doesn't have
+    /// a role (after applying the commit) and a buffer. Returns whether the
+    /// buffer may be drawn.
+    pub fn commit(&mut self, has_role: bool, has_buffer: bool) -> bool {
+        if has_role {
+            self.role_assigned();
+        } else if has_buffer && !self.hidden {
+            debug!("hiding surface with a buffer but no role");
+            self.hidden = true;
+        }
+        !self.hidden
+    }
+
+    /// Shows the surface after it was assigned a role outside of a commit
+    /// (e.g., by becoming the cursor). Returns whether it was hidden, in which
+    /// case its buffer needs to be drawn.
+    pub fn role_assigned(&mut self) -> bool {
+        if self.hidden {
+            debug!("role assigned, showing surface");
+        }
+        mem::take(&mut self.hidden)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_before_role_is_hidden_until_role() {
+        let mut pending_role = PendingRole::default();
+        assert!(!pending_role.commit(false, true));
+        assert!(pending_role.hidden());
+        // Further roleless commits keep it hidden.
+        assert!(!pending_role.commit(false, true));
+        assert!(pending_role.commit(true, true));
+        assert!(!pending_role.hidden());
+    }
+
+    #[test]
+    fn role_assigned_outside_of_commit_shows_surface() {
+        let mut pending_role = PendingRole::default();
+        assert!(!pending_role.commit(false, true));
+        assert!(pending_role.role_assigned());
+        assert!(!pending_role.hidden());
+        assert!(!pending_role.role_assigned());
+    }
+
+    #[test]
+    fn roleless_surface_without_buffer_is_not_hidden() {
+        let mut pending_role = PendingRole::default();
+        assert!(pending_role.commit(false, false));
+        assert!(!pending_role.hidden());
+    }
+}
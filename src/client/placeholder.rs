@@ -0,0 +1,121 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Placeholder contents for toplevels whose first buffer hasn't arrived yet.
+/// Large buffers can take a while to be transmitted, and without a buffer the
+/// window isn't mapped at all, so the app looks like it didn't start.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+use smithay_client_toolkit::shm::slot::Buffer as SlotBuffer;
+use smithay_client_toolkit::shm::slot::SlotPool;
+
+use crate::prelude::*;
+
+const PIXEL_BYTES: i32 = 4;
+
+/// Used when the local compositor leaves the window size up to us.
+pub const DEFAULT_PLACEHOLDER_SIZE: (i32, i32) = (512, 256);
+
+/// Colors are ARGB, e.g. 0xff303030.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum SurfacePlaceholder {
+    #[default]
+    Disabled,
+    Color(u32),
+    /// A background color with a bar across the middle of the window.
+    Loading {
+        background: u32,
+        foreground: u32,
+    },
+}
+
+impl SurfacePlaceholder {
+    /// Returns the color of the pixel at (`x`, `y`) in a `width`x`height`
+    /// placeholder.
+    fn pixel(&self, x: i32, y: i32, width: i32, height: i32) -> u32 {
+        match *self {
+            Self::Disabled => 0,
+            Self::Color(color) => color,
+            Self::Loading {
+                background,
+                foreground,
+            } => {
+                let bar_width = width / 3;
+                let bar_height = (height / 32).max(2);
+                let bar_x = (width - bar_width) / 2;
+                let bar_y = (height - bar_height) / 2;
+                if (bar_x..bar_x + bar_width).contains(&x)
+                    && (bar_y..bar_y + bar_height).contains(&y)
+                {
+                    foreground
+                } else {
+                    background
+                }
+            },
+        }
+    }
+
+    fn fill(&self, canvas: &mut [u8], width: i32, height: i32) {
+        for (i, pixel) in canvas.chunks_exact_mut(PIXEL_BYTES as usize).enumerate() {
+            let (x, y) = (i as i32 % width, i as i32 / width);
+            pixel.copy_from_slice(&self.pixel(x, y, width, height).to_le_bytes());
+        }
+    }
+
+    /// Returns None if placeholders are disabled.
+    pub fn create_buffer(
+        &self,
+        width: i32,
+        height: i32,
+        pool: &mut SlotPool,
+    ) -> Result<Option<SlotBuffer>> {
+        if *self == Self::Disabled {
+            return Ok(None);
+        }
+        let (buffer, canvas) = pool
+            .create_buffer(width, height, width * PIXEL_BYTES, wl_shm::Format::Argb8888)
+            .location(loc!())?;
+        self.fill(canvas, width, height);
+        Ok(Some(buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_fills_canvas() {
+        let mut canvas = vec![0; 2 * 2 * 4];
+        SurfacePlaceholder::Color(0xff102030).fill(&mut canvas, 2, 2);
+        assert_eq!(canvas, [0x30, 0x20, 0x10, 0xff].repeat(4));
+    }
+
+    #[test]
+    fn loading_bar_is_centered() {
+        let placeholder = SurfacePlaceholder::Loading {
+            background: 0,
+            foreground: 1,
+        };
+        let (width, height) = (30, 64);
+        let bar: Vec<_> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| placeholder.pixel(x, y, width, height) == 1)
+            .collect();
+        assert_eq!(bar.len(), 10 * 2);
+        assert_eq!(bar.first(), Some(&(10, 31)));
+        assert_eq!(bar.last(), Some(&(19, 32)));
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Pointer locks and confinements of remote apps (e.g., games using the mouse
+/// as a camera control), see server::pointer_constraints for the remote side.
+/// wprsd forwards the constraints of remote surfaces, which are mirrored by a
+/// constraint of the local pointer while the corresponding local surface has
+/// the keyboard focus. The local compositor's (de)activations of the local
+/// constraint are forwarded back, and so is the relative motion of the local
+/// pointer, which is all the app gets while the pointer is locked.
+///
+/// The local constraint is released when the surface loses the keyboard
+/// focus, so that a remote app can't trap the pointer: the remote constraint
+/// is deactivated, and persistent ones are constrained locally again once the
+/// surface has the keyboard focus again.
+///
+/// Cursor position hints are forwarded to the local lock, so that the local
+/// compositor puts the cursor where the app drew it when the lock is released.
+use std::collections::HashMap;
+
+use smithay_client_toolkit::compositor::Region as SctkRegion;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_pointer::WlPointer;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_confined_pointer_v1::ZwpConfinedPointerV1;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_locked_pointer_v1::ZwpLockedPointerV1;
+use smithay_client_toolkit::reexports::protocols::wp::relative_pointer::zv1::client::zwp_relative_pointer_v1::ZwpRelativePointerV1;
+use smithay_client_toolkit::seat::pointer_constraints::PointerConstraintsHandler;
+use smithay_client_toolkit::seat::relative_pointer::RelativeMotionEvent;
+use smithay_client_toolkit::seat::relative_pointer::RelativePointerHandler;
+
+use crate::client::ObjectBimapExt;
+use crate::client::WprsClientState;
+use crate::client_utils::LocalPointerConstraint;
+use crate::prelude::*;
+use crate::serialization::ClientId;
+use crate::serialization::Event;
+use crate::serialization::SendType;
+use crate::serialization::geometry::Point;
+use crate::serialization::wayland::PointerConstraintEvent;
+use crate::serialization::wayland::PointerConstraintKind;
+use crate::serialization::wayland::PointerConstraintRequest;
+use crate::serialization::wayland::Region;
+use crate::serialization::wayland::RelativeMotion;
+use crate::serialization::wayland::WlSurfaceId;
+
+/// A remote app's constraint.
+#[derive(Debug)]
+pub struct RemoteConstraint {
+    kind: PointerConstraintKind,
+    region: Option<Region>,
+    position_hint: Option<Point<f64>>,
+    /// Exists while the surface has the keyboard focus.
+    local: Option<LocalPointerConstraint>,
+}
+
+pub type RemoteConstraints = HashMap<(ClientId, WlSurfaceId), RemoteConstraint>;
+
+impl WprsClientState {
+    pub(crate) fn handle_pointer_constraint(
+        &mut self,
+        request: PointerConstraintRequest,
+    ) -> Result<()> {
+        match request {
+            PointerConstraintRequest::Constrain {
+                client,
+                surface,
+                kind,
+                region,
+            } => {
+                // Replaces the constraint of a surface which was destroyed and
+                // recreated between two checks of wprsd.
+                self.pointer_constraints.insert(
+                    (client, surface),
+                    RemoteConstraint {
+                        kind,
+                        region,
+                        position_hint: None,
+                        local: None,
+                    },
+                );
+            },
+            PointerConstraintRequest::CursorPositionHint {
+                client,
+                surface,
+                position,
+            } => {
+                let Some(constraint) = self.pointer_constraints.get_mut(&(client, surface)) else {
+                    debug!("ignoring cursor position hint of unknown constraint");
+                    return Ok(());
+                };
+                constraint.position_hint = Some(position);
+                if let Some(local) = &constraint.local {
+                    local.set_cursor_position_hint(position.into());
+                }
+            },
+            PointerConstraintRequest::Release { client, surface } => {
+                self.pointer_constraints.remove(&(client, surface));
+            },
+        }
+        self.sync_pointer_constraints();
+        Ok(())
+    }
+
+    /// Drops the constraints of `client`'s `surface`, which was destroyed.
+    pub(crate) fn forget_pointer_constraint(&mut self, client: ClientId, surface: WlSurfaceId) {
+        self.pointer_constraints.remove(&(client, surface));
+    }
+
+    /// Drops the constraints of `client`, which disconnected.
+    pub(crate) fn forget_pointer_constraints(&mut self, client: ClientId) {
+        self.pointer_constraints
+            .retain(|(constraint_client, _), _| *constraint_client != client);
+    }
+
+    /// Constrains the local pointer for the remote constraint of the surface
+    /// with the keyboard focus, and releases the local constraints of other
+    /// surfaces.
+    pub(crate) fn sync_pointer_constraints(&mut self) {
+        let focus = self
+            .current_focus
+            .as_ref()
+            .and_then(|focus| Some((focus, self.object_bimap.get_wl_surface_id(&focus.id())?)));
+        let pointer = self
+            .seat_objects
+            .iter()
+            .find_map(|seat_obj| seat_obj.pointer.as_ref())
+            .map(|pointer| pointer.pointer().clone());
+
+        for (key, constraint) in &mut self.pointer_constraints {
+            match (&focus, &constraint.local) {
+                (Some((surface, focus_key)), None) if focus_key == key => {
+                    let Some(pointer) = &pointer else {
+                        continue;
+                    };
+                    let Ok(region) = constraint
+                        .region
+                        .as_ref()
+                        .map(|region| region.create_compositor_region(&self.compositor_state))
+                        .transpose()
+                        .warn(loc!())
+                    else {
+                        continue;
+                    };
+                    let Ok(local) = LocalPointerConstraint::new(
+                        &self.pointer_constraints_state,
+                        &self.relative_pointer_state,
+                        surface,
+                        pointer,
+                        constraint.kind,
+                        region.as_ref().map(SctkRegion::wl_region),
+                        &self.qh,
+                    )
+                    .context(
+                        loc!(),
+                        "failed to constrain the local pointer, is zwp_pointer_constraints_v1 available?",
+                    )
+                    .warn(loc!()) else {
+                        continue;
+                    };
+                    if let Some(position_hint) = constraint.position_hint {
+                        local.set_cursor_position_hint(position_hint.into());
+                    }
+                    debug!("constraining the local pointer for {key:?}");
+                    constraint.local = Some(local);
+                },
+                (_, Some(_)) if focus.as_ref().map(|(_, focus_key)| focus_key) != Some(key) => {
+                    debug!("releasing the local pointer of {key:?}, which lost the keyboard focus");
+                    if constraint.local.take().is_some_and(|local| local.active) {
+                        self.serializer
+                            .writer()
+                            .send(SendType::Object(Event::PointerConstraint(
+                                PointerConstraintEvent::Deactivated(key.1),
+                            )));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Records that the local compositor (de)activated the local constraint
+    /// `constraint` and forwards that.
+    fn set_pointer_constraint_active(&mut self, constraint: &impl Proxy, active: bool) {
+        let Some(((_, surface_id), remote)) =
+            self.pointer_constraints.iter_mut().find(|(_, remote)| {
+                remote
+                    .local
+                    .as_ref()
+                    .is_some_and(|local| local.is(&constraint.id()))
+            })
+        else {
+            return;
+        };
+        remote.local.as_mut().unwrap().active = active;
+        let event = if active {
+            PointerConstraintEvent::Activated(*surface_id)
+        } else {
+            PointerConstraintEvent::Deactivated(*surface_id)
+        };
+        self.serializer
+            .writer()
+            .send(SendType::Object(Event::PointerConstraint(event)));
+    }
+}
+
+impl PointerConstraintsHandler for WprsClientState {
+    fn confined(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        confined_pointer: &ZwpConfinedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(confined_pointer, true);
+    }
+
+    fn unconfined(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        confined_pointer: &ZwpConfinedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(confined_pointer, false);
+    }
+
+    fn locked(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        locked_pointer: &ZwpLockedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(locked_pointer, true);
+    }
+
+    fn unlocked(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        locked_pointer: &ZwpLockedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(locked_pointer, false);
+    }
+}
+
+impl RelativePointerHandler for WprsClientState {
+    fn relative_pointer_motion(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        relative_pointer: &ZwpRelativePointerV1,
+        _pointer: &WlPointer,
+        event: RelativeMotionEvent,
+    ) {
+        let Some((_, surface_id)) = self
+            .pointer_constraints
+            .iter()
+            .find(|(_, remote)| {
+                remote
+                    .local
+                    .as_ref()
+                    .is_some_and(|local| local.has_relative_pointer(relative_pointer))
+            })
+            .map(|(key, _)| *key)
+        else {
+            return;
+        };
+        self.serializer
+            .writer()
+            .send(SendType::Object(Event::RelativeMotion(RelativeMotion {
+                surface_id,
+                delta: event.delta.into(),
+                delta_unaccel: event.delta_unaccel.into(),
+                utime: event.utime,
+            })));
+    }
+}
+
+smithay_client_toolkit::delegate_pointer_constraints!(WprsClientState);
+smithay_client_toolkit::delegate_relative_pointer!(WprsClientState);
@@ -0,0 +1,187 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Pointer motion prediction for high-latency links. The local cursor is drawn
+/// by the local compositor, but what the remote app draws in response to the
+/// pointer (hover effects, dragged objects, etc.) arrives a round trip later
+/// and trails behind it. With prediction enabled, forwarded motion events are
+/// extrapolated from the pointer's recent velocity so that the remote app
+/// renders where the pointer will be by the time the frame arrives.
+///
+/// Only motion events are predicted, button and axis events are sent with the
+/// real position so that clicks land where the user clicked.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+/// Motion events further apart than this are treated as the pointer having
+/// stopped in between.
+const MAX_SAMPLE_INTERVAL_MS: u32 = 100;
+
+/// Weight of the newest sample in the velocity estimate.
+const VELOCITY_SMOOTHING: f64 = 0.5;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum PointerPrediction {
+    #[default]
+    Disabled,
+    Enabled {
+        /// How far ahead to predict, usually about the round-trip time.
+        lookahead_ms: u32,
+        /// The furthest a predicted position may be from the real position,
+        /// in surface-local coordinates.
+        max_distance: u32,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct PointerPredictor {
+    prediction: PointerPrediction,
+    last_sample: Option<(u32, (f64, f64))>,
+    velocity: Option<(f64, f64)>,
+}
+
+impl PointerPredictor {
+    pub fn new(prediction: PointerPrediction) -> Self {
+        Self {
+            prediction,
+            ..Self::default()
+        }
+    }
+
+    /// Forgets the pointer's history, e.g. when it enters a different surface,
+    /// whose coordinates aren't comparable.
+    pub fn reset(&mut self) {
+        self.last_sample = None;
+        self.velocity = None;
+    }
+
+    /// Records a motion to `position` at `time_ms` and returns the position to
+    /// forward.
+    pub fn motion(&mut self, time_ms: u32, position: (f64, f64)) -> (f64, f64) {
+        let PointerPrediction::Enabled {
+            lookahead_ms,
+            max_distance,
+        } = self.prediction
+        else {
+            return position;
+        };
+
+        if let Some((last_time_ms, last_position)) = self.last_sample {
+            let dt_ms = time_ms.wrapping_sub(last_time_ms);
+            if dt_ms > MAX_SAMPLE_INTERVAL_MS {
+                self.velocity = None;
+            } else if dt_ms > 0 {
+                let sample = (
+                    (position.0 - last_position.0) / f64::from(dt_ms),
+                    (position.1 - last_position.1) / f64::from(dt_ms),
+                );
+                self.velocity = match self.velocity {
+                    // Don't carry the old velocity over a change of direction,
+                    // that's where predictions overshoot.
+                    Some(velocity) if dot(velocity, sample) < 0.0 => None,
+                    Some(velocity) => Some((
+                        lerp(velocity.0, sample.0, VELOCITY_SMOOTHING),
+                        lerp(velocity.1, sample.1, VELOCITY_SMOOTHING),
+                    )),
+                    None => Some(sample),
+                };
+            }
+        }
+        self.last_sample = Some((time_ms, position));
+
+        let Some(velocity) = self.velocity else {
+            return position;
+        };
+        let mut offset = (
+            velocity.0 * f64::from(lookahead_ms),
+            velocity.1 * f64::from(lookahead_ms),
+        );
+        let distance = dot(offset, offset).sqrt();
+        let max_distance = f64::from(max_distance);
+        if distance > max_distance {
+            let scale = max_distance / distance;
+            offset = (offset.0 * scale, offset.1 * scale);
+        }
+        (position.0 + offset.0, position.1 + offset.1)
+    }
+}
+
+fn dot(a: (f64, f64), b: (f64, f64)) -> f64 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn predictor() -> PointerPredictor {
+        PointerPredictor::new(PointerPrediction::Enabled {
+            lookahead_ms: 50,
+            max_distance: 40,
+        })
+    }
+
+    #[test]
+    fn disabled_passes_through() {
+        let mut predictor = PointerPredictor::new(PointerPrediction::Disabled);
+        assert_eq!(predictor.motion(0, (0.0, 0.0)), (0.0, 0.0));
+        assert_eq!(predictor.motion(10, (10.0, 0.0)), (10.0, 0.0));
+    }
+
+    #[test]
+    fn predicts_along_velocity() {
+        let mut predictor = predictor();
+        assert_eq!(predictor.motion(0, (0.0, 0.0)), (0.0, 0.0));
+        // 0.1 px/ms for 50ms.
+        assert_eq!(predictor.motion(10, (1.0, 0.0)), (6.0, 0.0));
+        assert_eq!(predictor.motion(20, (2.0, 0.0)), (7.0, 0.0));
+    }
+
+    #[test]
+    fn prediction_is_bounded() {
+        let mut predictor = predictor();
+        predictor.motion(0, (0.0, 0.0));
+        // 10 px/ms would predict 500px ahead.
+        assert_eq!(predictor.motion(10, (0.0, 100.0)), (0.0, 140.0));
+    }
+
+    #[test]
+    fn no_overshoot_on_direction_change() {
+        let mut predictor = predictor();
+        predictor.motion(0, (0.0, 0.0));
+        predictor.motion(10, (10.0, 0.0));
+        assert_eq!(predictor.motion(20, (5.0, 0.0)), (5.0, 0.0));
+    }
+
+    #[test]
+    fn pause_resets_velocity() {
+        let mut predictor = predictor();
+        predictor.motion(0, (0.0, 0.0));
+        predictor.motion(10, (10.0, 0.0));
+        assert_eq!(predictor.motion(500, (11.0, 0.0)), (11.0, 0.0));
+    }
+
+    #[test]
+    fn reset_forgets_history() {
+        let mut predictor = predictor();
+        predictor.motion(0, (0.0, 0.0));
+        predictor.motion(10, (10.0, 0.0));
+        predictor.reset();
+        assert_eq!(predictor.motion(20, (100.0, 0.0)), (100.0, 0.0));
+    }
+}
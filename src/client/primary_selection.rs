@@ -0,0 +1,140 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of the primary selection when the local compositor doesn't support
+/// zwp_primary_selection_device_manager_v1. wprsd always offers the primary
+/// selection to remote apps, so without a fallback selecting text in a remote
+/// app silently does nothing locally.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use crate::serialization::wayland::DataSource;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum PrimarySelectionFallback {
+    /// Don't sync the primary selection.
+    #[default]
+    Disabled,
+    /// Sync the remote primary selection through the local clipboard: remote
+    /// primary selections are copied to the local clipboard and the local
+    /// clipboard is also offered as the remote primary selection.
+    Clipboard,
+}
+
+impl PrimarySelectionFallback {
+    /// The local selection which the remote selection `source` is synced to,
+    /// if any.
+    pub fn local_target(self, source: DataSource, primary_supported: bool) -> Option<DataSource> {
+        match source {
+            DataSource::Primary if !primary_supported => match self {
+                Self::Disabled => None,
+                Self::Clipboard => Some(DataSource::Selection),
+            },
+            source => Some(source),
+        }
+    }
+
+    /// The local selection which the remote selection `source`, which was set
+    /// offering `mime_types`, is synced to, if any. An empty primary selection
+    /// (nothing is highlighted) isn't synced through the clipboard, as that
+    /// would replace the clipboard contents with nothing to paste.
+    pub fn set_target(
+        self,
+        source: DataSource,
+        mime_types: &[String],
+        primary_supported: bool,
+    ) -> Option<DataSource> {
+        self.local_target(source, primary_supported)
+            .filter(|target| source == *target || !mime_types.is_empty())
+    }
+
+    /// The remote selections which the local selection `source` is synced to.
+    pub fn remote_targets(self, source: DataSource, primary_supported: bool) -> Vec<DataSource> {
+        match source {
+            DataSource::Selection if !primary_supported && self == Self::Clipboard => {
+                vec![DataSource::Selection, DataSource::Primary]
+            },
+            source => vec![source],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primary_supported() {
+        for fallback in [
+            PrimarySelectionFallback::Disabled,
+            PrimarySelectionFallback::Clipboard,
+        ] {
+            assert_eq!(
+                fallback.local_target(DataSource::Primary, true),
+                Some(DataSource::Primary)
+            );
+            assert_eq!(
+                fallback.remote_targets(DataSource::Selection, true),
+                vec![DataSource::Selection]
+            );
+        }
+    }
+
+    #[test]
+    fn primary_unsupported_disabled() {
+        let fallback = PrimarySelectionFallback::Disabled;
+        assert_eq!(fallback.local_target(DataSource::Primary, false), None);
+        assert_eq!(
+            fallback.local_target(DataSource::Selection, false),
+            Some(DataSource::Selection)
+        );
+        assert_eq!(
+            fallback.remote_targets(DataSource::Selection, false),
+            vec![DataSource::Selection]
+        );
+    }
+
+    #[test]
+    fn primary_unsupported_clipboard() {
+        let fallback = PrimarySelectionFallback::Clipboard;
+        assert_eq!(
+            fallback.local_target(DataSource::Primary, false),
+            Some(DataSource::Selection)
+        );
+        assert_eq!(
+            fallback.remote_targets(DataSource::Selection, false),
+            vec![DataSource::Selection, DataSource::Primary]
+        );
+        assert_eq!(
+            fallback.remote_targets(DataSource::DnD, false),
+            vec![DataSource::DnD]
+        );
+    }
+
+    #[test]
+    fn empty_primary_keeps_clipboard() {
+        let fallback = PrimarySelectionFallback::Clipboard;
+        let mime_types = ["text/plain".to_string()];
+        assert_eq!(
+            fallback.set_target(DataSource::Primary, &mime_types, false),
+            Some(DataSource::Selection)
+        );
+        assert_eq!(fallback.set_target(DataSource::Primary, &[], false), None);
+        // An empty primary selection still replaces a local primary selection.
+        assert_eq!(
+            fallback.set_target(DataSource::Primary, &[], true),
+            Some(DataSource::Primary)
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of remote selections being cleared, e.g. when an X11 app
+/// relinquishes the selection or exits. The local selection which was synced
+/// from the cleared remote selection can't be pasted from anymore, since the
+/// remote side has nothing to serve.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use crate::serialization::wayland::DataSource;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum SelectionClearBehavior {
+    /// Unset the local selection, so that pastes don't wait for stale data.
+    #[default]
+    Clear,
+    /// Keep the local selection, pastes from it yield nothing.
+    Ignore,
+}
+
+impl SelectionClearBehavior {
+    /// Whether the local selection, which holds the remote selection `held`
+    /// (if any), should be unset when the remote selection `cleared` is
+    /// cleared.
+    pub fn clears(self, cleared: DataSource, held: Option<DataSource>) -> bool {
+        self == Self::Clear && held == Some(cleared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_selection_is_kept() {
+        let behavior = SelectionClearBehavior::Clear;
+        // Clearing the remote primary selection doesn't affect the local
+        // clipboard, unless it holds the primary selection through
+        // PrimarySelectionFallback::Clipboard.
+        assert!(!behavior.clears(DataSource::Primary, Some(DataSource::Selection)));
+        assert!(behavior.clears(DataSource::Primary, Some(DataSource::Primary)));
+        assert!(!behavior.clears(DataSource::Selection, None));
+    }
+
+    #[test]
+    fn ignore_keeps_selection() {
+        assert!(
+            !SelectionClearBehavior::Ignore
+                .clears(DataSource::Selection, Some(DataSource::Selection))
+        );
+    }
+}
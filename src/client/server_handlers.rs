@@ -14,19 +14,26 @@
 
 /// Handlers for events from the wprs server.
 use std::fs::File;
-use std::io::Read;
 use std::io::Write;
+use std::mem;
 use std::os::fd::OwnedFd;
 use std::thread;
 
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay_client_toolkit::activation::RequestData;
+use smithay_client_toolkit::reexports::csd_frame::CursorIcon;
 use smithay_client_toolkit::shell::WaylandSurface;
 
 use crate::client::RemoteCursor;
+use crate::client::RemoteDisplay;
 use crate::client::RemoteSurface;
 use crate::client::RemoteXdgPopup;
 use crate::client::RemoteXdgToplevel;
 use crate::client::Role;
 use crate::client::WprsClientState;
+use crate::client::primary_selection::PrimarySelectionFallback;
+use crate::client::smithay_handlers::ActivationRequest;
 use crate::client::subsurface;
 use crate::client::subsurface::RemoteSubSurface;
 use crate::fallible_entry::FallibleEntryExt;
@@ -39,6 +46,7 @@ use crate::serialization::Request;
 use crate::serialization::SendType;
 use crate::serialization::tuple::Tuple2;
 use crate::serialization::wayland;
+use crate::serialization::wayland::BufferTile;
 use crate::serialization::wayland::ClientSurface;
 use crate::serialization::wayland::CursorImage;
 use crate::serialization::wayland::CursorImageStatus;
@@ -48,6 +56,7 @@ use crate::serialization::wayland::DataRequest;
 use crate::serialization::wayland::DataSource;
 use crate::serialization::wayland::DataSourceRequest;
 use crate::serialization::wayland::DataToTransfer;
+use crate::serialization::wayland::KeyboardEvent;
 use crate::serialization::wayland::SurfaceRequest;
 use crate::serialization::wayland::SurfaceRequestPayload;
 use crate::serialization::wayland::SurfaceState;
@@ -134,7 +143,30 @@ impl WprsClientState {
         subsurface::reorder_subsurfaces(surface_id, &surface_state, surfaces).location(loc!())?;
 
         match &surface_state.role {
-            Some(wayland::Role::Cursor(_)) => {},
+            Some(wayland::Role::Cursor(hotspot)) => {
+                let remote_surface = surfaces.get_mut(&surface_id).location(loc!())?;
+                if let Some(Role::Cursor(cursor)) = &mut remote_surface.role
+                    && cursor.hotspot != *hotspot
+                {
+                    cursor.hotspot = *hotspot;
+                    // The hotspot of animated cursors can move between frames.
+                    let is_cursor = self.cursor_surface.is(&ClientSurface {
+                        client: client_id,
+                        surface: surface_id,
+                    });
+                    if is_cursor
+                        && let Some(themed_pointer) =
+                            self.seat_objects.last().location(loc!())?.pointer.as_ref()
+                    {
+                        themed_pointer.pointer().set_cursor(
+                            self.last_enter_serial,
+                            Some(remote_surface.wl_surface()),
+                            hotspot.x,
+                            hotspot.y,
+                        );
+                    }
+                }
+            },
             Some(wayland::Role::SubSurface(_)) => RemoteSubSurface::apply(
                 client.id,
                 surface_state,
@@ -170,10 +202,16 @@ impl WprsClientState {
             None => {},
         }
 
+        let remote_surface = surfaces.get_mut(&surface_id).location(loc!())?;
+        let has_role = remote_surface.role.is_some();
+        let has_buffer = remote_surface.buffer.is_some();
+        remote_surface.pending_role.commit(has_role, has_buffer);
+
         if frame_callback_completed {
             subsurface::commit_sync_children(surface_id, surfaces).location(loc!())?;
             let remote_surface = surfaces.get_mut(&surface_id).location(loc!())?;
             match &remote_surface.role {
+                _ if remote_surface.pending_role.hidden() => {},
                 Some(Role::SubSurface(subsurface)) if subsurface.sync => {},
                 Some(Role::XdgToplevel(toplevel)) if !toplevel.configured => {
                     toplevel.commit();
@@ -189,12 +227,52 @@ impl WprsClientState {
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
+    fn handle_buffer_tile(
+        &mut self,
+        client_id: ClientId,
+        surface_id: WlSurfaceId,
+        tile: BufferTile,
+    ) -> Result<()> {
+        let tile_data = self.buffer_cache.take().location(loc!())?;
+        let client = self.remote_display.client(&client_id);
+        let Ok(remote_surface) = client.surface(&surface_id) else {
+            // The surface was destroyed before all of its tiles arrived.
+            return Ok(());
+        };
+        remote_surface
+            .apply_buffer_tile(tile, tile_data, &mut self.pool)
+            .location(loc!())?;
+
+        // Otherwise the tile will be drawn when the pending frame callback
+        // completes.
+        if remote_surface.frame_callback_completed {
+            match &remote_surface.role {
+                _ if remote_surface.pending_role.hidden() => {},
+                Some(Role::SubSurface(subsurface)) if subsurface.sync => {},
+                Some(Role::XdgToplevel(toplevel)) if !toplevel.configured => {},
+                Some(Role::XdgPopup(popup)) if !popup.configured => {},
+                _ => remote_surface
+                    .draw_buffer_send_frame(&self.qh)
+                    .location(loc!())?,
+            }
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self), level = "debug")]
     fn handle_surface_destroy(
         &mut self,
         client_id: ClientId,
         surface_id: WlSurfaceId,
     ) -> Result<()> {
+        if self.cursor_surface.destroyed(&ClientSurface {
+            client: client_id,
+            surface: surface_id,
+        }) {
+            self.reset_cursor().location(loc!())?;
+        }
+
         let client = self.remote_display.client(&client_id);
         if let Some(surface) = client.surfaces.remove(&surface_id)
             && let Ok(Role::SubSurface(subsurface)) = surface.get_role() &&
@@ -211,7 +289,7 @@ impl WprsClientState {
     #[instrument(skip(self), level = "debug")]
     fn handle_surface(&mut self, request: SurfaceRequest) -> Result<()> {
         if (matches!(request.payload, SurfaceRequestPayload::Destroyed)
-            && !self.remote_display.clients.contains_key(&request.client))
+            && !self.remote_display.is_connected(&request.client))
         {
             // Client already disconnected, nothing to do.
             return Ok(());
@@ -220,10 +298,34 @@ impl WprsClientState {
         let surface_id = request.surface;
         match request.payload {
             SurfaceRequestPayload::Commit(surface_state) => {
+                // The buffer of this commit, if any, arrived after those of the
+                // batched commits.
+                let buffer = self.buffer_cache.take();
+                for (client_id, surface_id, surface_state, buffer) in
+                    mem::take(&mut self.pending_commits)
+                {
+                    self.buffer_cache = buffer;
+                    self.handle_commit(client_id, surface_id, surface_state)
+                        .location(loc!())?;
+                }
+                self.buffer_cache = buffer;
                 self.handle_commit(request.client, surface_id, surface_state)
                     .location(loc!())?;
             },
+            SurfaceRequestPayload::BatchedCommit(surface_state) => {
+                self.pending_commits.push((
+                    request.client,
+                    surface_id,
+                    surface_state,
+                    self.buffer_cache.take(),
+                ));
+            },
+            SurfaceRequestPayload::BufferTile(tile) => {
+                self.handle_buffer_tile(request.client, surface_id, tile)
+                    .location(loc!())?;
+            },
             SurfaceRequestPayload::Destroyed => {
+                self.forget_pointer_constraint(request.client, surface_id);
                 self.handle_surface_destroy(request.client, surface_id)
                     .location(loc!())?;
             },
@@ -234,7 +336,7 @@ impl WprsClientState {
     #[instrument(skip(self), level = "debug")]
     fn handle_toplevel(&mut self, request: ToplevelRequest) -> Result<()> {
         if (matches!(request.payload, ToplevelRequestPayload::Destroyed)
-            && !self.remote_display.clients.contains_key(&request.client))
+            && !self.remote_display.is_connected(&request.client))
         {
             // Client already disconnected, nothing to do.
             return Ok(());
@@ -273,13 +375,35 @@ impl WprsClientState {
                 ToplevelRequestPayload::SetMinimized => {
                     toplevel.local_window.set_minimized();
                 },
+                ToplevelRequestPayload::Activate => {
+                    let Some(activation_state) = &self.activation_state else {
+                        return Ok(());
+                    };
+                    // The token has to come from the local compositor, which
+                    // only hands out valid ones for recent input.
+                    activation_state.request_token_with_data(
+                        &self.qh,
+                        ActivationRequest {
+                            request: RequestData {
+                                app_id: None,
+                                seat_and_serial: self.seat_objects.first().map(|seat_obj| {
+                                    (seat_obj.seat.clone(), self.last_enter_serial)
+                                }),
+                                surface: self.current_focus.clone(),
+                            },
+                            target: toplevel.local_window.wl_surface().clone(),
+                        },
+                    );
+                },
                 ToplevelRequestPayload::Move(xdg_shell::Move { serial }) => {
+                    self.held_buttons.clear();
                     toplevel
                         .local_window
                         .xdg_toplevel()
                         ._move(&self.seat_state.seats().next().location(loc!())?, serial);
                 },
                 ToplevelRequestPayload::Resize(xdg_shell::Resize { serial, edge }) => {
+                    self.held_buttons.clear();
                     toplevel.local_window.xdg_toplevel().resize(
                         &self.seat_state.seats().next().location(loc!())?,
                         serial,
@@ -297,7 +421,7 @@ impl WprsClientState {
     #[instrument(skip(self), level = "debug")]
     fn handle_popup(&mut self, request: PopupRequest) -> Result<()> {
         if (matches!(request.payload, PopupRequestPayload::Destroyed)
-            && !self.remote_display.clients.contains_key(&request.client))
+            && !self.remote_display.is_connected(&request.client))
         {
             // Client already disconnected, nothing to do.
             return Ok(());
@@ -317,6 +441,18 @@ impl WprsClientState {
         Ok(())
     }
 
+    /// Shows the default cursor, e.g. once the cursor surface is gone.
+    fn reset_cursor(&self) -> Result<()> {
+        // TODO: support multiple seats
+        let Some(themed_pointer) = self.seat_objects.last().location(loc!())?.pointer.as_ref()
+        else {
+            return Ok(());
+        };
+        themed_pointer
+            .set_cursor(&self.conn, CursorIcon::Default)
+            .location(loc!())
+    }
+
     #[instrument(skip(self), level = "debug")]
     fn handle_cursor_image(&mut self, cursor_image: CursorImage) -> Result<()> {
         // TODO: support multiple seats
@@ -335,6 +471,7 @@ impl WprsClientState {
                             .with_context(loc!(), || format!("Unknown cursor name {name:?}."))?,
                     )
                     .location(loc!())?;
+                self.cursor_surface.set(None);
             },
             CursorImageStatus::Surface {
                 client_surface: ClientSurface { client, surface },
@@ -358,16 +495,28 @@ impl WprsClientState {
                         )
                     })
                     .location(loc!())?;
-                RemoteCursor::set_role(client.id, remote_surface);
+                RemoteCursor::set_role(client.id, hotspot, remote_surface);
                 themed_pointer.pointer().set_cursor(
                     self.last_enter_serial,
                     Some(remote_surface.wl_surface()),
                     hotspot.x,
                     hotspot.y,
                 );
+                // The cursor's buffer may have been committed before it
+                // became the cursor.
+                if remote_surface.pending_role.role_assigned() {
+                    remote_surface
+                        .draw_buffer_send_frame(&self.qh)
+                        .location(loc!())?;
+                }
+                self.cursor_surface.set(Some(ClientSurface {
+                    client: client.id,
+                    surface,
+                }));
             },
             CursorImageStatus::Hidden => {
                 themed_pointer.hide_cursor().location(loc!())?;
+                self.cursor_surface.set(None);
             },
         }
         Ok(())
@@ -414,6 +563,17 @@ impl WprsClientState {
                             .map_err(|_| anyhow!("invalid dnd actions"))
                             .location(loc!())?,
                     );
+                    // A drag which a toplevel will be attached to looks just
+                    // like a normal one until it's attached, so always make
+                    // this possible if the local compositor supports it.
+                    if let Some(toplevel_drag) = self.toplevel_drag.take() {
+                        toplevel_drag.destroy();
+                    }
+                    self.toplevel_drag = self
+                        .toplevel_drag_manager
+                        .as_ref()
+                        .and_then(|manager| manager.get().ok())
+                        .map(|manager| manager.get_xdg_toplevel_drag(source.inner(), &self.qh, ()));
                     source.start_drag(
                         &seat_obj.data_device,
                         self.current_focus.as_ref().location(loc!())?,
@@ -423,27 +583,58 @@ impl WprsClientState {
                     self.dnd_source = Some(source);
                 }
             },
+            DataRequest::SourceRequest(DataSourceRequest::AttachToplevel(
+                Tuple2(client, surface),
+                offset,
+            )) => {
+                let Some(toplevel_drag) = &self.toplevel_drag else {
+                    debug!("local compositor doesn't support toplevel drags, dragging normally");
+                    return Ok(());
+                };
+                let client = self.remote_display.client(&client);
+                let Ok(RemoteSurface {
+                    role: Some(Role::XdgToplevel(toplevel)),
+                    ..
+                }) = client.surface(&surface)
+                else {
+                    warn!("attach of unknown toplevel {surface:?}");
+                    return Ok(());
+                };
+                toplevel_drag.attach(toplevel.local_window.xdg_toplevel(), offset.x, offset.y);
+            },
             DataRequest::SourceRequest(DataSourceRequest::SetSelection(
                 source,
                 mut source_metadata,
             )) => {
-                match source {
-                    DataSource::Selection => {
+                let target = self.primary_selection_fallback.set_target(
+                    source,
+                    &source_metadata.mime_types,
+                    self.primary_selection_supported(),
+                );
+                match target {
+                    Some(DataSource::Selection) => {
+                        // A primary selection synced through the clipboard is
+                        // set by a click, there is no implicit grab serial.
+                        let serial = if source == DataSource::Primary {
+                            self.last_mouse_down_serial
+                        } else {
+                            self.last_implicit_grab_serial.take()
+                        };
                         // TODO: support multiple seats
-                        if let (Some(seat_obj), Some(serial)) = (
-                            self.seat_objects.iter().last(),
-                            self.last_implicit_grab_serial.take(),
-                        ) {
+                        if let (Some(seat_obj), Some(serial)) =
+                            (self.seat_objects.iter().last(), serial)
+                        {
                             source_metadata.mime_types.push("_wprs_marker".to_string());
                             let mime_types = source_metadata.mime_types.iter().map(String::as_str);
-                            let source = self
+                            let copy_paste_source = self
                                 .data_device_manager_state
                                 .create_copy_paste_source(&self.qh, mime_types);
-                            source.set_selection(&seat_obj.data_device, serial);
-                            self.selection_source = Some(source);
+                            copy_paste_source.set_selection(&seat_obj.data_device, serial);
+                            self.selection_source = Some(copy_paste_source);
+                            self.selection_source_target = source;
                         }
                     },
-                    DataSource::Primary => {
+                    Some(DataSource::Primary) => {
                         if let (Some(seat_obj), Some(serial)) = (
                             self.seat_objects.iter().last(),
                             self.last_mouse_down_serial.take(),
@@ -462,13 +653,33 @@ impl WprsClientState {
                             self.primary_selection_source = Some(source);
                         }
                     },
-                    DataSource::DnD => {},
+                    Some(DataSource::DnD) | None => {},
+                }
+            },
+            DataRequest::SourceRequest(DataSourceRequest::ClearSelection(source)) => {
+                // Dropping a source destroys it, which unsets the local
+                // selection if it's still ours.
+                let held = self
+                    .selection_source
+                    .as_ref()
+                    .map(|_| self.selection_source_target);
+                if self.selection_clear_behavior.clears(source, held) {
+                    debug!("remote {source:?} was cleared, unsetting local selection");
+                    self.selection_source = None;
+                }
+                let held = self
+                    .primary_selection_source
+                    .as_ref()
+                    .map(|_| DataSource::Primary);
+                if self.selection_clear_behavior.clears(source, held) {
+                    debug!("remote {source:?} was cleared, unsetting local primary selection");
+                    self.primary_selection_source = None;
                 }
             },
             DataRequest::DestinationRequest(DataDestinationRequest::DnDAcceptMimeType(
                 mime_type,
             )) => {
-                if let Some(dnd_offer) = &self.dnd_offer {
+                if let Some(dnd_offer) = self.dnd_offer() {
                     dnd_offer.accept_mime_type(self.dnd_accept_counter, mime_type);
                     self.dnd_accept_counter += 1;
                 }
@@ -477,34 +688,14 @@ impl WprsClientState {
                 source,
                 mime_type,
             )) => {
-                let read_pipe = match source {
-                    DataSource::Primary => {
-                        let cur_offer = self
-                            .primary_selection_offer
-                            .clone()
-                            .ok_or(anyhow!("primary_selection_offer was empty"))?;
-
-                        cur_offer.receive(mime_type.clone()).ok()
-                    },
-                    DataSource::Selection => {
-                        let cur_offer = self
-                            .selection_offer
-                            .clone()
-                            .ok_or(anyhow!("selection_offer was empty"))?;
-
-                        cur_offer.receive(mime_type.clone()).ok()
-                    },
-                    DataSource::DnD => {
-                        let cur_offer = self
-                            .dnd_offer
-                            .clone()
-                            .ok_or(anyhow!("dnd_offer was empty"))?;
-
-                        cur_offer.receive(mime_type.clone()).ok()
-                    },
-                };
-                if let Some(mut read_pipe) = read_pipe {
+                let read_pipe = self
+                    .data_offers
+                    .get(source)
+                    .ok_or(anyhow!("no {source:?} offer"))?
+                    .receive(mime_type.clone());
+                if let Some(read_pipe) = read_pipe {
                     debug!("spawning receive thread for mime {mime_type}");
+                    let limit = self.clipboard_limit.for_mime_type(&mime_type);
                     let writer = self.serializer.writer().clone().into_inner();
                     // The data source application will write to the other end
                     // of read_pipe at its convenience and then close the file
@@ -513,9 +704,8 @@ impl WprsClientState {
                     // completed. The thread will then terminate.
                     thread::spawn(move || -> Result<()> {
                         debug!("in receive thread for mime {mime_type}");
-                        let mut buf = Vec::new();
-                        let bytes_read = read_pipe.read_to_end(&mut buf).location(loc!())?;
-                        debug!("read selection ({bytes_read:?} bytes): {buf:?}");
+                        let buf = limit.read_to_end(read_pipe, &mime_type).location(loc!())?;
+                        debug!("read selection ({} bytes): {buf:?}", buf.len());
                         writer.send(SendType::Object(Event::Data(DataEvent::TransferData(
                             source,
                             DataToTransfer(buf),
@@ -531,7 +721,7 @@ impl WprsClientState {
             DataRequest::DestinationRequest(DataDestinationRequest::DnDSetDestinationActions(
                 action,
             )) => {
-                if let Some(dnd_offer) = &self.dnd_offer {
+                if let Some(dnd_offer) = self.dnd_offer() {
                     let action = action
                         .try_into()
                         .map_err(|_| anyhow!("invalid dnd action"))
@@ -540,16 +730,12 @@ impl WprsClientState {
                 }
             },
             DataRequest::DestinationRequest(DataDestinationRequest::DnDFinish) => {
-                if let Some(dnd_offer) = &self.dnd_offer {
+                if let Some(dnd_offer) = self.dnd_offer() {
                     dnd_offer.finish();
                 }
             },
             DataRequest::TransferData(source, data) => {
-                let write_pipe = match source {
-                    DataSource::Primary => self.primary_selection_pipe.take().location(loc!())?,
-                    DataSource::Selection => self.selection_pipe.take().location(loc!())?, // TODO
-                    DataSource::DnD => self.dnd_pipe.take().location(loc!())?,             // TODO
-                };
+                let write_pipe = self.data_pipes.take(source).location(loc!())?;
                 let fd = OwnedFd::from(write_pipe);
                 let mut f = File::from(fd);
                 // If data is large, the write may block if the reader (the
@@ -566,12 +752,76 @@ impl WprsClientState {
 
     #[instrument(skip(self), level = "debug")]
     fn handle_client_disconnected(&mut self, client: ClientId) -> Result<()> {
-        self.remote_display.clients.remove(&client);
+        self.forget_pointer_constraints(client);
+        if self.disconnect_grace_period.is_zero() {
+            self.remote_display.remove_client(&client);
+            return Ok(());
+        }
+
+        // Keep the client's windows around, frozen on their last frame, so
+        // that they don't flicker away on a short-lived disconnect. A
+        // reconnecting client is a new client as far as we can tell, so its
+        // new windows appear alongside these until the grace period ends.
+        debug!(
+            "keeping the windows of disconnected client {client:?} for {:?}",
+            self.disconnect_grace_period
+        );
+        self.remote_display.departed_clients.insert(client);
+        self.loop_handle
+            .insert_source(
+                Timer::from_duration(self.disconnect_grace_period),
+                move |_, _, state| {
+                    state.remote_display.remove_client(&client);
+                    TimeoutAction::Drop
+                },
+            )
+            .map_err(|e| anyhow!("failed to insert disconnect grace period timer: {e}"))
+            .location(loc!())?;
         Ok(())
     }
 
     #[instrument(skip(self), level = "debug")]
     fn handle_capabilities(&mut self, caps: Capabilities) -> Result<()> {
+        info!(
+            "wprsd compresses buffers with {:?}",
+            caps.buffer_compression
+        );
+        // wprsd sends its capabilities first on every connection, so getting
+        // them again means we reconnected. Surfaces may have been destroyed
+        // while we were disconnected and wprsd is about to resend all the live
+        // ones, so start over from that.
+        if let Some(old_caps) = self.capabilities.get() {
+            info!("reconnected to wprsd, recreating all windows");
+            self.remote_display = RemoteDisplay::new();
+            self.buffer_cache = None;
+            self.cursor_surface.set(None);
+            self.reset_cursor().log_and_ignore(loc!());
+            // wprsd may have restarted with its default keymap.
+            if let Some(keymap) = &self.keymap {
+                self.serializer
+                    .writer()
+                    .send(SendType::Object(Event::KeyboardEvent(
+                        KeyboardEvent::Keymap(keymap.clone()),
+                    )));
+            }
+            if *old_caps != caps {
+                warn!(
+                    "wprsd's capabilities changed from {old_caps:?} to {caps:?}, restart wprsc to use them"
+                );
+            }
+            return Ok(());
+        }
+
+        if !self.primary_selection_supported() {
+            match self.primary_selection_fallback {
+                PrimarySelectionFallback::Disabled => warn!(
+                    "The local compositor doesn't support the primary selection, the primary selection (middle-click paste) won't be synced. Use --primary-selection-fallback Clipboard to sync it through the clipboard instead."
+                ),
+                PrimarySelectionFallback::Clipboard => info!(
+                    "The local compositor doesn't support the primary selection, syncing the remote primary selection through the clipboard."
+                ),
+            }
+        }
         self.capabilities
             .set(caps)
             .map_err(|_| anyhow!("attempted to set capabilities more than once"))
@@ -598,6 +848,10 @@ impl WprsClientState {
                 self.handle_client_disconnected(client)
             },
             RecvType::Object(Request::Capabilities(caps)) => self.handle_capabilities(caps),
+            RecvType::Object(Request::TextInput(text_input)) => self.handle_text_input(text_input),
+            RecvType::Object(Request::PointerConstraint(request)) => {
+                self.handle_pointer_constraint(request)
+            },
             RecvType::RawBuffer(buffer) => self.handle_buffer(buffer),
         }
         .log_and_ignore(loc!())
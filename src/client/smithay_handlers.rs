@@ -15,9 +15,16 @@
 /// Handlers for events from smithay client toolkit.
 use smithay::reexports::wayland_protocols::wp::primary_selection::zv1::client::zwp_primary_selection_device_v1::ZwpPrimarySelectionDeviceV1;
 use smithay::reexports::wayland_protocols::wp::primary_selection::zv1::client::zwp_primary_selection_source_v1::ZwpPrimarySelectionSourceV1;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_manager_v3::ZwpTextInputManagerV3;
 use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewport;
 use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewport::WpViewport;
 use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewporter::WpViewporter;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::client::xdg_toplevel_drag_manager_v1::XdgToplevelDragManagerV1;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::client::xdg_toplevel_drag_v1;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::client::xdg_toplevel_drag_v1::XdgToplevelDragV1;
+use smithay_client_toolkit::activation::ActivationHandler;
+use smithay_client_toolkit::activation::RequestData;
+use smithay_client_toolkit::activation::RequestDataExt;
 use smithay_client_toolkit::compositor::CompositorHandler;
 use smithay_client_toolkit::compositor::SurfaceData;
 use smithay_client_toolkit::data_device_manager::data_device::DataDeviceHandler;
@@ -43,6 +50,7 @@ use smithay_client_toolkit::reexports::client::protocol::wl_subcompositor::WlSub
 use smithay_client_toolkit::reexports::client::protocol::wl_subsurface::Event as WlSubsurfaceEvent;
 use smithay_client_toolkit::reexports::client::protocol::wl_subsurface::WlSubsurface;
 use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::reexports::client::protocol::wl_touch::WlTouch;
 use smithay_client_toolkit::reexports::client::Connection;
 use smithay_client_toolkit::reexports::client::Dispatch;
 use smithay_client_toolkit::reexports::client::Proxy;
@@ -62,6 +70,7 @@ use smithay_client_toolkit::seat::pointer::PointerEvent;
 use smithay_client_toolkit::seat::pointer::PointerEventKind;
 use smithay_client_toolkit::seat::pointer::PointerHandler;
 use smithay_client_toolkit::seat::pointer::ThemeSpec;
+use smithay_client_toolkit::seat::touch::TouchHandler;
 use smithay_client_toolkit::seat::Capability;
 use smithay_client_toolkit::seat::SeatHandler;
 use smithay_client_toolkit::seat::SeatState;
@@ -75,12 +84,18 @@ use smithay_client_toolkit::shm::ShmHandler;
 use tracing::Span;
 
 use crate::args;
-use crate::client::subsurface;
+use crate::client::DataOffer;
 use crate::client::ObjectBimapExt;
 use crate::client::Role;
 use crate::client::SeatObject;
 use crate::client::WprsClientState;
+use crate::client::input_arbitration::Decision;
+use crate::client::placeholder::DEFAULT_PLACEHOLDER_SIZE;
+use crate::client::subsurface;
+use crate::client::text_input::LocalTextInput;
 use crate::prelude::*;
+use crate::serialization::Event;
+use crate::serialization::SendType;
 use crate::serialization::wayland;
 use crate::serialization::wayland::DataDestinationEvent;
 use crate::serialization::wayland::DataEvent;
@@ -95,13 +110,12 @@ use crate::serialization::wayland::OutputEvent;
 use crate::serialization::wayland::SourceMetadata;
 use crate::serialization::wayland::SurfaceEvent;
 use crate::serialization::wayland::SurfaceEventPayload::OutputsChanged;
+use crate::serialization::wayland::TouchEvent;
 use crate::serialization::xdg_shell::PopupConfigure;
 use crate::serialization::xdg_shell::PopupEvent;
 use crate::serialization::xdg_shell::ToplevelClose;
 use crate::serialization::xdg_shell::ToplevelConfigure;
 use crate::serialization::xdg_shell::ToplevelEvent;
-use crate::serialization::Event;
-use crate::serialization::SendType;
 
 impl WprsClientState {
     fn send_surface_outputs(&self, surface: &WlSurface) {
@@ -244,11 +258,18 @@ impl OutputHandler for WprsClientState {
 impl WindowHandler for WprsClientState {
     #[instrument(skip_all, level = "debug")]
     fn request_close(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, window: &Window) {
-        let (_, surface_id) = self
+        let (client_id, surface_id) = self
             .object_bimap
             .get_wl_surface_id(&window.wl_surface().id())
             .expect("Object corresponding to client object id {key} not found.");
 
+        if self.remote_display.departed_clients.contains(&client_id) {
+            // Nobody is left to handle the close, so end the disconnect grace
+            // period of the window's client early instead.
+            self.remote_display.remove_client(&client_id);
+            return;
+        }
+
         self.serializer
             .writer()
             .send(SendType::Object(Event::Toplevel(ToplevelEvent::Close(
@@ -281,6 +302,13 @@ impl WindowHandler for WprsClientState {
 
         if !toplevel.configured {
             toplevel.configured = true;
+            let size = match configure.new_size {
+                (Some(width), Some(height)) => (width.get() as i32, height.get() as i32),
+                _ => DEFAULT_PLACEHOLDER_SIZE,
+            };
+            surface
+                .draw_placeholder(self.placeholder, size, &mut self.pool)
+                .log_and_ignore(loc!());
             surface.draw_buffer_send_frame(qh).log_and_ignore(loc!());
         }
 
@@ -358,6 +386,7 @@ impl SeatHandler for WprsClientState {
                 seat: seat.clone(),
                 keyboard: None,
                 pointer: None,
+                touch: None,
                 data_device,
                 primary_selection_device,
             });
@@ -371,6 +400,16 @@ impl SeatHandler for WprsClientState {
                 .get_keyboard(qh, &seat, None)
                 .expect("Failed to create keyboard");
             seat_obj.keyboard.replace(keyboard);
+
+            // TODO: support multiple seats
+            if self.text_input.is_none()
+                && let Some(manager) = self
+                    .text_input_manager
+                    .as_ref()
+                    .and_then(|manager| manager.get().ok())
+            {
+                self.text_input = Some(LocalTextInput::new(manager, &seat, qh));
+            }
         }
 
         if capability == Capability::Pointer && seat_obj.pointer.is_none() {
@@ -387,6 +426,15 @@ impl SeatHandler for WprsClientState {
                 .expect("Failed to create pointer");
             seat_obj.pointer.replace(themed_pointer);
         }
+
+        if capability == Capability::Touch && seat_obj.touch.is_none() {
+            debug!("set touch capability");
+            let touch = self
+                .seat_state
+                .get_touch(qh, &seat)
+                .expect("Failed to create touch");
+            seat_obj.touch.replace(touch);
+        }
     }
 
     fn remove_capability(
@@ -406,6 +454,11 @@ impl SeatHandler for WprsClientState {
                 Capability::Pointer => {
                     seat_obj.pointer.take();
                 },
+                Capability::Touch => {
+                    if let Some(t) = seat_obj.touch.take() {
+                        t.release()
+                    }
+                },
                 _ => {},
             }
         }
@@ -427,6 +480,7 @@ impl KeyboardHandler for WprsClientState {
         keysyms: &[Keysym],
     ) {
         self.current_focus = Some(surface.clone());
+        self.sync_pointer_constraints();
         let Some((_, surface_id)) = self.object_bimap.get_wl_surface_id(&surface.id()) else {
             // TODO: unwrap is wrong, we can enter before surface exists.
             // Currently we're just returning in that case, but should we create
@@ -444,6 +498,11 @@ impl KeyboardHandler for WprsClientState {
                     keysyms: keysyms.iter().map(|k| k.raw()).collect(),
                 },
             )));
+        if let Some(modifiers) = self.keyboard_modifiers.on_enter() {
+            self.serializer
+                .writer()
+                .send(SendType::Object(Event::KeyboardEvent(modifiers)));
+        }
     }
 
     #[instrument(skip(self, _conn, _qh, _keyboard), level = "debug")]
@@ -456,6 +515,7 @@ impl KeyboardHandler for WprsClientState {
         serial: u32,
     ) {
         self.current_focus = None;
+        self.sync_pointer_constraints();
         self.serializer
             .writer()
             .send(SendType::Object(Event::KeyboardEvent(
@@ -571,10 +631,13 @@ impl KeyboardHandler for WprsClientState {
         _keyboard: &WlKeyboard,
         keymap: Keymap<'_>,
     ) {
+        self.keyboard_modifiers.clear();
+        let keymap = keymap.as_string();
+        self.keymap = Some(keymap.clone());
         self.serializer
             .writer()
             .send(SendType::Object(Event::KeyboardEvent(
-                KeyboardEvent::Keymap(keymap.as_string()),
+                KeyboardEvent::Keymap(keymap),
             )));
     }
 
@@ -592,14 +655,10 @@ impl KeyboardHandler for WprsClientState {
         _raw_modifiers: RawModifiers,
         variant: u32,
     ) {
+        let modifiers = self.keyboard_modifiers.update(modifiers.into(), variant);
         self.serializer
             .writer()
-            .send(SendType::Object(Event::KeyboardEvent(
-                KeyboardEvent::Modifiers {
-                    modifier_state: modifiers.into(),
-                    layout_index: variant,
-                },
-            )));
+            .send(SendType::Object(Event::KeyboardEvent(modifiers)));
     }
 }
 
@@ -633,24 +692,208 @@ impl PointerHandler for WprsClientState {
             }
         }
 
+        let mut cancel_touches = false;
+        let events: Vec<&PointerEvent> = events
+            .iter()
+            .filter(|event| match self.input_arbiter.pointer(&event.kind) {
+                Decision::Forward => true,
+                Decision::Drop => false,
+                Decision::CancelTouchesAndForward => {
+                    cancel_touches = true;
+                    true
+                },
+            })
+            .collect();
+        if cancel_touches {
+            self.touch_surfaces.clear();
+            self.serializer
+                .writer()
+                .send(SendType::Object(Event::Touch(TouchEvent::Cancel)));
+        }
+        if events.is_empty() {
+            return;
+        }
+
         self.serializer
             .writer()
             .send(SendType::Object(Event::PointerFrame(
                 events
-                    .iter()
+                    .into_iter()
                     .map(|event| {
                         let (_, surface_id) = self
                             .object_bimap
                             .get_wl_surface_id(&event.surface.id())
                             .expect("Object corresponding to client object id {key} not found.");
 
-                        wayland::PointerEvent::from_smithay(&surface_id, event)
+                        let mut pointer_event =
+                            wayland::PointerEvent::from_smithay(&surface_id, event);
+                        match event.kind {
+                            PointerEventKind::Enter { .. } => self.pointer_predictor.reset(),
+                            PointerEventKind::Motion { time } => {
+                                pointer_event.position =
+                                    self.pointer_predictor.motion(time, event.position).into();
+                            },
+                            _ => {},
+                        }
+                        if let wayland::PointerEventKind::Enter { buttons, .. } =
+                            &mut pointer_event.kind
+                        {
+                            *buttons = self.held_buttons.to_vec();
+                        }
+                        self.held_buttons.update(&event.kind);
+                        pointer_event
                     })
                     .collect(),
             )));
     }
 }
 
+impl TouchHandler for WprsClientState {
+    #[instrument(skip(self, _conn, _qh, _touch), level = "debug")]
+    fn down(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        serial: u32,
+        time: u32,
+        surface: WlSurface,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        let Some((_, surface_id)) = self.object_bimap.get_wl_surface_id(&surface.id()) else {
+            // window was distroyed already
+            return;
+        };
+        if self.input_arbiter.touch_down(id) == Decision::Drop {
+            return;
+        }
+        self.touch_surfaces.insert(id, surface_id);
+
+        self.serializer
+            .writer()
+            .send(SendType::Object(Event::Touch(TouchEvent::Down {
+                surface_id,
+                id,
+                position: position.into(),
+                serial,
+            })));
+    }
+
+    #[instrument(skip(self, _conn, _qh, _touch), level = "debug")]
+    fn up(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        serial: u32,
+        time: u32,
+        id: i32,
+    ) {
+        self.touch_surfaces.remove(&id);
+        if self.input_arbiter.touch_up(id) == Decision::Drop {
+            return;
+        }
+
+        self.serializer
+            .writer()
+            .send(SendType::Object(Event::Touch(TouchEvent::Up {
+                id,
+                serial,
+            })));
+    }
+
+    #[instrument(skip(self, _conn, _qh, _touch), level = "debug")]
+    fn motion(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        time: u32,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        if self.input_arbiter.touch_motion(id) == Decision::Drop {
+            return;
+        }
+        let Some(surface_id) = self.touch_surfaces.get(&id) else {
+            return;
+        };
+
+        self.serializer
+            .writer()
+            .send(SendType::Object(Event::Touch(TouchEvent::Motion {
+                surface_id: *surface_id,
+                id,
+                position: position.into(),
+            })));
+    }
+
+    fn shape(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _id: i32,
+        _major: f64,
+        _minor: f64,
+    ) {
+    }
+
+    fn orientation(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _id: i32,
+        _orientation: f64,
+    ) {
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    fn cancel(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _touch: &WlTouch) {
+        self.input_arbiter.touch_cancel();
+        self.touch_surfaces.clear();
+
+        self.serializer
+            .writer()
+            .send(SendType::Object(Event::Touch(TouchEvent::Cancel)));
+    }
+}
+
+/// A token request for activating `target`, made on behalf of the focused
+/// surface.
+#[derive(Debug)]
+pub struct ActivationRequest {
+    pub request: RequestData,
+    pub target: WlSurface,
+}
+
+impl RequestDataExt for ActivationRequest {
+    fn app_id(&self) -> Option<&str> {
+        self.request.app_id()
+    }
+
+    fn seat_and_serial(&self) -> Option<(&WlSeat, u32)> {
+        self.request.seat_and_serial()
+    }
+
+    fn surface(&self) -> Option<&WlSurface> {
+        self.request.surface()
+    }
+}
+
+impl ActivationHandler for WprsClientState {
+    type RequestData = ActivationRequest;
+
+    #[instrument(skip(self, token), level = "debug")]
+    fn new_token(&mut self, token: String, data: &ActivationRequest) {
+        if let Some(activation_state) = &self.activation_state {
+            activation_state.activate::<Self>(&data.target, token);
+        }
+    }
+}
+
 impl ShmHandler for WprsClientState {
     fn shm_state(&mut self) -> &mut Shm {
         &mut self.shm_state
@@ -684,7 +927,8 @@ impl DataDeviceHandler for WprsClientState {
         if mime_types.contains(&"_wprs_marker".to_string()) {
             return;
         }
-        self.dnd_offer = Some(drag_offer.clone());
+        self.data_offers
+            .set(DataSource::DnD, DataOffer::DnD(drag_offer.clone()));
         let (_, surface_id) = self
             .object_bimap
             .get_wl_surface_id(&drag_offer.surface.id())
@@ -759,15 +1003,19 @@ impl DataDeviceHandler for WprsClientState {
         if mime_types.contains(&"_wprs_marker".to_string()) {
             return;
         }
-        self.selection_offer = Some(offer);
-        self.serializer
-            .writer()
-            .send(SendType::Object(Event::Data(DataEvent::DestinationEvent(
-                DataDestinationEvent::SelectionSet(
-                    DataSource::Selection,
-                    SourceMetadata::from_mime_types(mime_types),
-                ),
-            ))));
+        for target in self
+            .primary_selection_fallback
+            .remote_targets(DataSource::Selection, self.primary_selection_supported())
+        {
+            self.data_offers
+                .set(target, DataOffer::Selection(offer.clone()));
+            self.serializer.writer().send(SendType::Object(Event::Data(
+                DataEvent::DestinationEvent(DataDestinationEvent::SelectionSet(
+                    target,
+                    SourceMetadata::from_mime_types(mime_types.clone()),
+                )),
+            )));
+        }
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -840,16 +1088,17 @@ impl DataSourceHandler for WprsClientState {
     ) {
         match (source, &self.selection_source, &self.dnd_source) {
             (source, Some(selection_source), _) if source == selection_source.inner() => {
-                self.selection_pipe = Some(write_pipe);
+                self.data_pipes
+                    .set(self.selection_source_target, write_pipe);
                 self.serializer.writer().send(SendType::Object(Event::Data(
                     DataEvent::SourceEvent(DataSourceEvent::MimeTypeSendRequestedByDestination(
-                        DataSource::Selection,
+                        self.selection_source_target,
                         mime,
                     )),
                 )));
             },
             (source, _, Some(dnd_source)) if source == dnd_source.inner() => {
-                self.dnd_pipe = Some(write_pipe);
+                self.data_pipes.set(DataSource::DnD, write_pipe);
                 self.serializer.writer().send(SendType::Object(Event::Data(
                     DataEvent::SourceEvent(DataSourceEvent::MimeTypeSendRequestedByDestination(
                         DataSource::DnD,
@@ -867,12 +1116,14 @@ impl DataSourceHandler for WprsClientState {
     fn cancelled(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, source: &WlDataSource) {
         match (source, &self.selection_source, &self.dnd_source) {
             (source, Some(selection_source), _) if source == selection_source.inner() => {
-                self.selection_pipe = None;
+                self.selection_source = None;
+                self.data_pipes.take(self.selection_source_target);
                 // self.serializer.writer().send(SendType::Object(Event::Data(DataSourceEvent::SelectionCancelled));
             },
             (source, _, Some(dnd_source)) if source == dnd_source.inner() => {
+                self.end_toplevel_drag();
                 self.dnd_source = None;
-                self.dnd_pipe = None;
+                self.data_pipes.take(DataSource::DnD);
                 self.serializer.writer().send(SendType::Object(Event::Data(
                     DataEvent::SourceEvent(DataSourceEvent::DnDCancelled),
                 )));
@@ -886,6 +1137,8 @@ impl DataSourceHandler for WprsClientState {
 
     #[instrument(skip_all, level = "debug")]
     fn dnd_dropped(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _source: &WlDataSource) {
+        // The attached toplevel stays where it was dropped.
+        self.end_toplevel_drag();
         self.serializer
             .writer()
             .send(SendType::Object(Event::Data(DataEvent::SourceEvent(
@@ -900,8 +1153,9 @@ impl DataSourceHandler for WprsClientState {
         _qh: &QueueHandle<Self>,
         _source: &WlDataSource,
     ) {
+        self.end_toplevel_drag();
         self.dnd_source = None;
-        self.dnd_pipe = None;
+        self.data_pipes.take(DataSource::DnD);
         self.serializer
             .writer()
             .send(SendType::Object(Event::Data(DataEvent::SourceEvent(
@@ -950,7 +1204,8 @@ impl PrimarySelectionDeviceHandler for WprsClientState {
         if mime_types.contains(&"_wprs_marker".to_string()) {
             return;
         }
-        self.primary_selection_offer = Some(offer);
+        self.data_offers
+            .set(DataSource::Primary, DataOffer::Primary(offer));
         self.serializer
             .writer()
             .send(SendType::Object(Event::Data(DataEvent::DestinationEvent(
@@ -974,7 +1229,7 @@ impl PrimarySelectionSourceHandler for WprsClientState {
     ) {
         match &self.primary_selection_source {
             Some(primary_selection_source) if source == primary_selection_source.inner() => {
-                self.primary_selection_pipe = Some(write_pipe);
+                self.data_pipes.set(DataSource::Primary, write_pipe);
                 self.serializer.writer().send(SendType::Object(Event::Data(
                     DataEvent::SourceEvent(DataSourceEvent::MimeTypeSendRequestedByDestination(
                         DataSource::Primary,
@@ -995,7 +1250,7 @@ impl PrimarySelectionSourceHandler for WprsClientState {
         _qh: &QueueHandle<Self>,
         _source: &ZwpPrimarySelectionSourceV1,
     ) {
-        self.primary_selection_pipe = None;
+        self.data_pipes.take(DataSource::Primary);
     }
 }
 
@@ -1006,6 +1261,21 @@ impl AsMut<SimpleGlobal<WpViewporter, 1>> for WprsClientState {
     }
 }
 
+impl AsMut<SimpleGlobal<ZwpTextInputManagerV3, 1>> for WprsClientState {
+    fn as_mut(&mut self) -> &mut SimpleGlobal<ZwpTextInputManagerV3, 1> {
+        // This should never panic since self.text_input_manager is none then we will never get any events for it.
+        &mut *self.text_input_manager.as_mut().unwrap()
+    }
+}
+
+impl AsMut<SimpleGlobal<XdgToplevelDragManagerV1, 1>> for WprsClientState {
+    fn as_mut(&mut self) -> &mut SimpleGlobal<XdgToplevelDragManagerV1, 1> {
+        // This should never panic since self.toplevel_drag_manager is none then we will never get any events for it.
+        &mut *self.toplevel_drag_manager.as_mut().unwrap()
+    }
+}
+
+smithay_client_toolkit::delegate_activation!(WprsClientState, ActivationRequest);
 smithay_client_toolkit::delegate_compositor!(WprsClientState);
 smithay_client_toolkit::delegate_data_device!(WprsClientState);
 smithay_client_toolkit::delegate_keyboard!(WprsClientState);
@@ -1015,11 +1285,14 @@ smithay_client_toolkit::delegate_registry!(WprsClientState);
 smithay_client_toolkit::delegate_seat!(WprsClientState);
 smithay_client_toolkit::delegate_shm!(WprsClientState);
 smithay_client_toolkit::delegate_subcompositor!(WprsClientState);
+smithay_client_toolkit::delegate_touch!(WprsClientState);
 smithay_client_toolkit::delegate_xdg_popup!(WprsClientState);
 smithay_client_toolkit::delegate_xdg_shell!(WprsClientState);
 smithay_client_toolkit::delegate_xdg_window!(WprsClientState);
 smithay_client_toolkit::delegate_primary_selection!(WprsClientState);
 smithay_client_toolkit::delegate_simple!(WprsClientState, WpViewporter, 1);
+smithay_client_toolkit::delegate_simple!(WprsClientState, XdgToplevelDragManagerV1, 1);
+smithay_client_toolkit::delegate_simple!(WprsClientState, ZwpTextInputManagerV3, 1);
 
 impl ProvidesRegistryState for WprsClientState {
     fn registry(&mut self) -> &mut RegistryState {
@@ -1058,6 +1331,19 @@ impl Dispatch<WlSubsurface, SubSurfaceData> for WprsClientState {
     }
 }
 
+impl Dispatch<XdgToplevelDragV1, ()> for WprsClientState {
+    fn event(
+        _state: &mut Self,
+        _toplevel_drag: &XdgToplevelDragV1,
+        _event: xdg_toplevel_drag_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        unreachable!("There are no xdg_toplevel_drag_v1 events")
+    }
+}
+
 impl Dispatch<WpViewport, ()> for WprsClientState {
     fn event(
         _state: &mut Self,
@@ -0,0 +1,275 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Forwarding of text from the local input method (e.g., an IME composing CJK
+/// text) to remote apps over text-input-v3. wprsd forwards the state of the
+/// focused remote app's text input, and while the corresponding local surface
+/// has the text input focus, a local text input with the same state is
+/// enabled. The preedit string, commit string and surrounding text deletion
+/// which the local input method sends are collected until their done event and
+/// then forwarded to wprsd together, see server::text_input.
+///
+/// text-input-v3 has no preedit styling: apps underline the preedit string
+/// themselves and highlight the part between the cursor offsets, which are
+/// forwarded as-is.
+///
+/// X11 apps aren't covered: Xwayland doesn't support text-input-v3, X11 input
+/// methods (XIM) connect to the X server directly.
+use std::mem;
+
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_manager_v3::ZwpTextInputManagerV3;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_v3;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_v3::ContentHint;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_v3::ContentPurpose;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::client::zwp_text_input_v3::ZwpTextInputV3;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+
+use crate::client::ObjectBimapExt;
+use crate::client::WprsClientState;
+use crate::prelude::*;
+use crate::serialization::Event;
+use crate::serialization::SendType;
+use crate::serialization::wayland::DeleteSurroundingText;
+use crate::serialization::wayland::Preedit;
+use crate::serialization::wayland::TextInputChange;
+use crate::serialization::wayland::TextInputState;
+
+/// The events of the local input method since the last done event.
+#[derive(Debug, Default)]
+pub struct PendingTextInputChange(TextInputChange);
+
+impl PendingTextInputChange {
+    pub fn preedit_string(&mut self, text: Option<String>, cursor_begin: i32, cursor_end: i32) {
+        self.0.preedit = text.map(|text| Preedit {
+            text,
+            cursor_begin,
+            cursor_end,
+        });
+    }
+
+    pub fn commit_string(&mut self, text: Option<String>) {
+        self.0.commit = text;
+    }
+
+    pub fn delete_surrounding_text(&mut self, before_length: u32, after_length: u32) {
+        self.0.delete_surrounding_text = Some(DeleteSurroundingText {
+            before_length,
+            after_length,
+        });
+    }
+
+    /// Takes the change to apply on a done event. Anything not sent since the
+    /// last one is reset, in particular the preedit string is cleared.
+    pub fn done(&mut self) -> TextInputChange {
+        mem::take(&mut self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalTextInput {
+    text_input: ZwpTextInputV3,
+    /// The local surface with the text input focus.
+    focus: Option<WlSurface>,
+    /// The latest state of the remote text input.
+    remote: Option<TextInputState>,
+    enabled: bool,
+    pending: PendingTextInputChange,
+}
+
+impl LocalTextInput {
+    pub fn new(
+        manager: &ZwpTextInputManagerV3,
+        seat: &WlSeat,
+        qh: &QueueHandle<WprsClientState>,
+    ) -> Self {
+        Self {
+            text_input: manager.get_text_input(seat, qh, ()),
+            focus: None,
+            remote: None,
+            enabled: false,
+            pending: PendingTextInputChange::default(),
+        }
+    }
+}
+
+impl WprsClientState {
+    pub(crate) fn handle_text_input(&mut self, remote: TextInputState) -> Result<()> {
+        let Some(text_input) = &mut self.text_input else {
+            debug!("local compositor doesn't support text input, ignoring {remote:?}");
+            return Ok(());
+        };
+        text_input.remote = Some(remote);
+        self.sync_text_input();
+        Ok(())
+    }
+
+    /// Enables the local text input with the remote state while the remote
+    /// surface has the text input focus, and disables it otherwise.
+    fn sync_text_input(&mut self) {
+        let Some(text_input) = &mut self.text_input else {
+            return;
+        };
+        let focus = text_input
+            .focus
+            .as_ref()
+            .and_then(|focus| self.object_bimap.get_wl_surface_id(&focus.id()));
+        let remote = text_input
+            .remote
+            .as_ref()
+            .filter(|remote| remote.enabled && focus == Some((remote.client, remote.surface)));
+
+        let local = &text_input.text_input;
+        match remote {
+            Some(remote) => {
+                if !text_input.enabled {
+                    local.enable();
+                }
+                if let Some(surrounding_text) = &remote.surrounding_text {
+                    local.set_surrounding_text(
+                        surrounding_text.text.clone(),
+                        surrounding_text.cursor,
+                        surrounding_text.anchor,
+                    );
+                }
+                local.set_content_type(
+                    ContentHint::from_bits_truncate(remote.content_hint),
+                    ContentPurpose::try_from(remote.content_purpose)
+                        .unwrap_or(ContentPurpose::Normal),
+                );
+                if let Some(rect) = remote.cursor_rectangle {
+                    local.set_cursor_rectangle(rect.loc.x, rect.loc.y, rect.size.w, rect.size.h);
+                }
+                local.commit();
+                text_input.enabled = true;
+            },
+            None if text_input.enabled => {
+                local.disable();
+                local.commit();
+                text_input.enabled = false;
+            },
+            None => {},
+        }
+    }
+}
+
+impl Dispatch<ZwpTextInputV3, ()> for WprsClientState {
+    fn event(
+        state: &mut Self,
+        _text_input: &ZwpTextInputV3,
+        event: zwp_text_input_v3::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let Some(text_input) = &mut state.text_input else {
+            return;
+        };
+        match event {
+            zwp_text_input_v3::Event::Enter { surface } => {
+                text_input.focus = Some(surface);
+                state.sync_text_input();
+            },
+            zwp_text_input_v3::Event::Leave { .. } => {
+                text_input.focus = None;
+                // Disabling after leave is required, the text input isn't
+                // enabled again until the next enter.
+                state.sync_text_input();
+            },
+            zwp_text_input_v3::Event::PreeditString {
+                text,
+                cursor_begin,
+                cursor_end,
+            } => text_input
+                .pending
+                .preedit_string(text, cursor_begin, cursor_end),
+            zwp_text_input_v3::Event::CommitString { text } => {
+                text_input.pending.commit_string(text);
+            },
+            zwp_text_input_v3::Event::DeleteSurroundingText {
+                before_length,
+                after_length,
+            } => text_input
+                .pending
+                .delete_surrounding_text(before_length, after_length),
+            zwp_text_input_v3::Event::Done { .. } => {
+                let change = text_input.pending.done();
+                state
+                    .serializer
+                    .writer()
+                    .send(SendType::Object(Event::TextInput(change)));
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composing_multi_character_string() {
+        let mut pending = PendingTextInputChange::default();
+        // Typing "nihao" with a pinyin IME; cursor offsets are in bytes.
+        pending.preedit_string(Some("ni".to_string()), 2, 2);
+        assert_eq!(
+            pending.done(),
+            TextInputChange {
+                preedit: Some(Preedit {
+                    text: "ni".to_string(),
+                    cursor_begin: 2,
+                    cursor_end: 2,
+                }),
+                ..TextInputChange::default()
+            }
+        );
+
+        pending.preedit_string(Some("你hao".to_string()), 3, 6);
+        assert_eq!(
+            pending.done().preedit,
+            Some(Preedit {
+                text: "你hao".to_string(),
+                cursor_begin: 3,
+                cursor_end: 6,
+            })
+        );
+
+        // Choosing the candidate commits the string and clears the preedit.
+        pending.preedit_string(None, 0, 0);
+        pending.commit_string(Some("你好".to_string()));
+        assert_eq!(
+            pending.done(),
+            TextInputChange {
+                preedit: None,
+                commit: Some("你好".to_string()),
+                delete_surrounding_text: None,
+            }
+        );
+    }
+
+    #[test]
+    fn done_resets_pending_change() {
+        let mut pending = PendingTextInputChange::default();
+        pending.preedit_string(Some("ka".to_string()), 2, 2);
+        pending.delete_surrounding_text(3, 0);
+        assert!(pending.done().delete_surrounding_text.is_some());
+        // An empty done clears the preedit string.
+        assert_eq!(pending.done(), TextInputChange::default());
+    }
+}
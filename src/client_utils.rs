@@ -14,14 +14,191 @@
 
 use smithay_client_toolkit::data_device_manager::data_device::DataDevice;
 use smithay_client_toolkit::primary_selection::device::PrimarySelectionDevice;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::backend::ObjectId;
 use smithay_client_toolkit::reexports::client::protocol::wl_keyboard::WlKeyboard;
+use smithay_client_toolkit::reexports::client::protocol::wl_pointer::WlPointer;
+use smithay_client_toolkit::reexports::client::protocol::wl_region::WlRegion;
 use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::reexports::client::protocol::wl_touch::WlTouch;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_confined_pointer_v1::ZwpConfinedPointerV1;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_locked_pointer_v1::ZwpLockedPointerV1;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_pointer_constraints_v1::Lifetime;
+use smithay_client_toolkit::reexports::protocols::wp::relative_pointer::zv1::client::zwp_relative_pointer_v1::ZwpRelativePointerV1;
+use smithay_client_toolkit::seat::pointer_constraints::PointerConstraintData;
+use smithay_client_toolkit::seat::pointer_constraints::PointerConstraintsState;
+use smithay_client_toolkit::seat::relative_pointer::RelativePointerData;
+use smithay_client_toolkit::seat::relative_pointer::RelativePointerState;
+
+use crate::prelude::*;
+use crate::serialization::wayland::PointerConstraintKind;
 
 #[derive(Debug)]
 pub(crate) struct SeatObject<P> {
     pub(crate) seat: WlSeat,
     pub(crate) keyboard: Option<WlKeyboard>,
     pub(crate) pointer: Option<P>,
+    pub(crate) touch: Option<WlTouch>,
     pub(crate) data_device: DataDevice,
     pub(crate) primary_selection_device: Option<PrimarySelectionDevice>,
 }
+
+/// The surface currently used as a seat's cursor. The cursor has to be reset
+/// when that surface is destroyed, as the local compositor may otherwise keep
+/// showing its last image.
+#[derive(Debug)]
+pub(crate) struct CursorSurface<S>(Option<S>);
+
+impl<S> Default for CursorSurface<S> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<S: PartialEq> CursorSurface<S> {
+    pub(crate) fn set(&mut self, surface: Option<S>) {
+        self.0 = surface;
+    }
+
+    pub(crate) fn is(&self, surface: &S) -> bool {
+        self.0.as_ref() == Some(surface)
+    }
+
+    /// Records that `surface` was destroyed. Returns whether it was the
+    /// cursor surface, in which case the cursor should be reset.
+    pub(crate) fn destroyed(&mut self, surface: &S) -> bool {
+        let was_cursor = self.is(surface);
+        if was_cursor {
+            self.0 = None;
+        }
+        was_cursor
+    }
+}
+
+#[derive(Debug)]
+enum LocalConstraintObject {
+    Locked(ZwpLockedPointerV1),
+    Confined(ZwpConfinedPointerV1),
+}
+
+/// A constraint of the local pointer mirroring a remote app's, along with a
+/// relative pointer receiving the motion of the local pointer while the
+/// constraint exists. Both are destroyed when this is dropped, which releases
+/// the local pointer.
+///
+/// The local constraint is persistent, whatever the lifetime of the remote
+/// one: the remote side deactivates its constraint when the local one is, and
+/// oneshot constraints are destroyed (and released here) then.
+#[derive(Debug)]
+pub(crate) struct LocalPointerConstraint {
+    constraint: LocalConstraintObject,
+    relative_pointer: Option<ZwpRelativePointerV1>,
+    /// Whether the local compositor activated the constraint.
+    pub(crate) active: bool,
+}
+
+impl LocalPointerConstraint {
+    pub(crate) fn new<D>(
+        constraints: &PointerConstraintsState,
+        relative_pointers: &RelativePointerState,
+        surface: &WlSurface,
+        pointer: &WlPointer,
+        kind: PointerConstraintKind,
+        region: Option<&WlRegion>,
+        qh: &QueueHandle<D>,
+    ) -> Result<Self>
+    where
+        D: Dispatch<ZwpLockedPointerV1, PointerConstraintData>
+            + Dispatch<ZwpConfinedPointerV1, PointerConstraintData>
+            + Dispatch<ZwpRelativePointerV1, RelativePointerData>
+            + 'static,
+    {
+        let constraint = match kind {
+            PointerConstraintKind::Lock => LocalConstraintObject::Locked(
+                constraints
+                    .lock_pointer(surface, pointer, region, Lifetime::Persistent, qh)
+                    .location(loc!())?,
+            ),
+            PointerConstraintKind::Confine => LocalConstraintObject::Confined(
+                constraints
+                    .confine_pointer(surface, pointer, region, Lifetime::Persistent, qh)
+                    .location(loc!())?,
+            ),
+        };
+        let relative_pointer = relative_pointers
+            .get_relative_pointer(pointer, qh)
+            .context(
+                loc!(),
+                "zwp_relative_pointer_manager_v1 is not available, relative motion won't be forwarded",
+            )
+            .warn(loc!())
+            .ok();
+        Ok(Self {
+            constraint,
+            relative_pointer,
+            active: false,
+        })
+    }
+
+    /// Whether `id` is the local constraint object.
+    pub(crate) fn is(&self, id: &ObjectId) -> bool {
+        match &self.constraint {
+            LocalConstraintObject::Locked(locked) => &locked.id() == id,
+            LocalConstraintObject::Confined(confined) => &confined.id() == id,
+        }
+    }
+
+    pub(crate) fn has_relative_pointer(&self, relative_pointer: &ZwpRelativePointerV1) -> bool {
+        self.relative_pointer.as_ref() == Some(relative_pointer)
+    }
+
+    /// Sets where the cursor is put when the lock is released, which takes
+    /// effect on the next commit of the surface. Ignored for confinements.
+    pub(crate) fn set_cursor_position_hint(&self, (x, y): (f64, f64)) {
+        if let LocalConstraintObject::Locked(locked) = &self.constraint {
+            locked.set_cursor_position_hint(x, y);
+        }
+    }
+}
+
+impl Drop for LocalPointerConstraint {
+    fn drop(&mut self) {
+        match &self.constraint {
+            LocalConstraintObject::Locked(locked) => locked.destroy(),
+            LocalConstraintObject::Confined(confined) => confined.destroy(),
+        }
+        if let Some(relative_pointer) = &self.relative_pointer {
+            relative_pointer.destroy();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destroying_cursor_surface_resets_cursor() {
+        let mut cursor = CursorSurface::default();
+        cursor.set(Some(1));
+        assert!(!cursor.destroyed(&2));
+        assert!(cursor.is(&1));
+        assert!(cursor.destroyed(&1));
+        assert!(!cursor.is(&1));
+        // Only reset once.
+        assert!(!cursor.destroyed(&1));
+    }
+
+    #[test]
+    fn replaced_cursor_surface_does_not_reset_cursor() {
+        let mut cursor = CursorSurface::default();
+        cursor.set(Some(1));
+        cursor.set(Some(2));
+        assert!(!cursor.destroyed(&1));
+        cursor.set(None);
+        assert!(!cursor.destroyed(&2));
+    }
+}
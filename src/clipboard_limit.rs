@@ -0,0 +1,186 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Limits on the size of selection (clipboard, primary selection and
+/// drag-and-drop) payloads forwarded between wprsd and wprsc. Payloads are
+/// read into memory in full before being sent, so a huge paste (e.g., a
+/// multi-hundred-MB image) could otherwise exhaust memory or saturate the
+/// link. The limit applies to each transfer, which is of a single MIME type.
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use crate::prelude::*;
+
+/// What to do with payloads over the limit.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum OversizedPayload {
+    /// Forward nothing, pasting yields no data.
+    #[default]
+    Refuse,
+    /// Forward the first max_bytes bytes.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ClipboardLimit {
+    /// Maximum payload size in bytes, 0 means unlimited.
+    pub max_bytes: u64,
+    /// MIME type -> maximum payload size in bytes, overriding max_bytes.
+    pub mime_type_max_bytes: BTreeMap<String, u64>,
+    pub oversized: OversizedPayload,
+}
+
+impl Default for ClipboardLimit {
+    fn default() -> Self {
+        Self {
+            max_bytes: 256 * 1024 * 1024,
+            mime_type_max_bytes: BTreeMap::new(),
+            oversized: OversizedPayload::Refuse,
+        }
+    }
+}
+
+impl ClipboardLimit {
+    /// The limit for a transfer of `mime_type`.
+    pub fn for_mime_type(&self, mime_type: &str) -> PayloadLimit {
+        PayloadLimit {
+            max_bytes: self
+                .mime_type_max_bytes
+                .get(mime_type)
+                .copied()
+                .unwrap_or(self.max_bytes),
+            oversized: self.oversized,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PayloadLimit {
+    max_bytes: u64,
+    oversized: OversizedPayload,
+}
+
+impl PayloadLimit {
+    /// Reads a payload of `mime_type` from `reader` until EOF. Oversized
+    /// payloads are still read to the end, so that the source app isn't left
+    /// blocked writing to the pipe, but only up to the limit is kept.
+    pub fn read_to_end<R: Read>(self, mut reader: R, mime_type: &str) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        if self.max_bytes == 0 {
+            reader.read_to_end(&mut buf).location(loc!())?;
+            return Ok(buf);
+        }
+
+        (&mut reader)
+            .take(self.max_bytes)
+            .read_to_end(&mut buf)
+            .location(loc!())?;
+        let excess = io::copy(&mut reader, &mut io::sink()).location(loc!())?;
+        if excess > 0 {
+            let size = self.max_bytes + excess;
+            match self.oversized {
+                OversizedPayload::Refuse => {
+                    warn!(
+                        "refusing {size} byte {mime_type} selection payload, the limit is {} bytes",
+                        self.max_bytes
+                    );
+                    buf.clear();
+                },
+                OversizedPayload::Truncate => {
+                    warn!(
+                        "truncating {size} byte {mime_type} selection payload to {} bytes",
+                        self.max_bytes
+                    );
+                },
+            }
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(max_bytes: u64, oversized: OversizedPayload) -> ClipboardLimit {
+        ClipboardLimit {
+            max_bytes,
+            mime_type_max_bytes: BTreeMap::from([("image/png".to_string(), 8)]),
+            oversized,
+        }
+    }
+
+    #[test]
+    fn payload_within_limit_is_forwarded() {
+        let limit = limit(4, OversizedPayload::Refuse).for_mime_type("text/plain");
+        assert_eq!(
+            limit.read_to_end(&b"abcd"[..], "text/plain").unwrap(),
+            b"abcd"
+        );
+    }
+
+    #[test]
+    fn oversized_payload_is_refused_or_truncated() {
+        let refuse = limit(4, OversizedPayload::Refuse).for_mime_type("text/plain");
+        assert!(
+            refuse
+                .read_to_end(&b"abcde"[..], "text/plain")
+                .unwrap()
+                .is_empty()
+        );
+        let truncate = limit(4, OversizedPayload::Truncate).for_mime_type("text/plain");
+        assert_eq!(
+            truncate.read_to_end(&b"abcde"[..], "text/plain").unwrap(),
+            b"abcd"
+        );
+    }
+
+    #[test]
+    fn limit_is_per_mime_type() {
+        let limit = limit(4, OversizedPayload::Refuse);
+        let payload = [0; 8];
+        assert_eq!(
+            limit
+                .for_mime_type("image/png")
+                .read_to_end(&payload[..], "image/png")
+                .unwrap()
+                .len(),
+            8
+        );
+        assert!(
+            limit
+                .for_mime_type("text/plain")
+                .read_to_end(&payload[..], "text/plain")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let limit = limit(0, OversizedPayload::Refuse).for_mime_type("text/plain");
+        assert_eq!(
+            limit
+                .read_to_end(&[0; 1024][..], "text/plain")
+                .unwrap()
+                .len(),
+            1024
+        );
+    }
+}
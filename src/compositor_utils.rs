@@ -14,30 +14,106 @@
 
 use std::collections::HashSet;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
+use smithay::backend::allocator::Buffer;
+use smithay::input::SeatHandler;
+use smithay::input::keyboard::KeyboardHandle;
+use smithay::input::keyboard::Keycode;
+use smithay::input::pointer::PointerHandle;
 use smithay::output::Mode;
 use smithay::output::Output;
-use smithay::output::Scale;
 use smithay::reexports::wayland_server::Resource;
 use smithay::reexports::wayland_server::protocol::wl_buffer::WlBuffer;
 use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::Buffer as BufferCoords;
+use smithay::utils::Logical;
+use smithay::utils::Point;
+use smithay::utils::Transform;
 use smithay::utils::user_data::UserDataMap;
+use smithay::wayland::compositor;
+use smithay::wayland::compositor::Damage;
+use smithay::wayland::compositor::RegionAttributes;
 use smithay::wayland::compositor::SurfaceAttributes;
+use smithay::wayland::dmabuf;
+use smithay::wayland::pointer_constraints::PointerConstraint;
+use smithay::wayland::pointer_constraints::with_pointer_constraint;
 use smithay::wayland::shm;
-use smithay::wayland::shm::BufferAccessError;
 use smithay::wayland::shm::BufferData;
 
 use crate::buffer_pointer::BufferPointer;
+use crate::output_scale;
 use crate::prelude::*;
+use crate::serialization;
+use crate::serialization::geometry::Rectangle;
+use crate::serialization::geometry::Size;
 use crate::serialization::wayland::OutputInfo;
+use crate::serialization::wayland::PointerConstraintKind;
 
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Calls `f` with `data`, laid out as `spec`, with its rows tightly packed.
+/// Buffers may pad their rows (e.g. dmabufs with aligned strides), in which
+/// case the rows are copied without the padding first, so that the padding is
+/// never sent.
+fn with_tight_rows<F, T>(data: BufferPointer<u8>, spec: BufferData, f: F) -> Result<T>
+where
+    F: FnOnce(BufferPointer<u8>, BufferData) -> T,
+{
+    let row_len = spec.width as usize * BYTES_PER_PIXEL;
+    let stride = spec.stride as usize;
+    if stride == row_len {
+        return Ok(f(data, spec));
+    }
+    if stride < row_len {
+        bail!(
+            "stride {stride} is too small for a buffer of width {}",
+            spec.width
+        );
+    }
+
+    let mut tight = vec![0; row_len * spec.height as usize];
+    for (y, row) in tight.chunks_exact_mut(row_len).enumerate() {
+        data.split_at(y * stride)
+            .1
+            .split_at(row_len)
+            .0
+            .copy_to_nonoverlapping(row);
+    }
+    let ptr = tight.as_ptr();
+    // SAFETY: ptr comes from tight, which outlives the BufferPointer.
+    let data = unsafe { BufferPointer::new(&ptr, tight.len()) };
+    Ok(f(
+        data,
+        BufferData {
+            stride: row_len as i32,
+            ..spec
+        },
+    ))
+}
+
+/// Calls `f` with the contents of `buffer` and their layout. The rows of the
+/// contents are tightly packed, whatever the stride of the buffer, see
+/// with_tight_rows.
+///
+/// Only call this while handling the commit which attached `buffer`: the
+/// client may write to the buffer again once it is released, which happens
+/// when the next buffer is committed, so reading it later can give a torn
+/// frame. dmabuf buffers are read back, see dmabuf.
+///
 /// # Panics
 /// If smithay has a bug and with_buffer_contents gives us an invalid pointer.
-pub fn with_buffer_contents<F, T>(buffer: &WlBuffer, f: F) -> Result<T, BufferAccessError>
+pub fn with_buffer_contents<F, T>(buffer: &WlBuffer, f: F) -> Result<T>
 where
     F: FnOnce(BufferPointer<u8>, BufferData) -> T,
 {
+    if let Ok(dmabuf) = dmabuf::get_dmabuf(buffer) {
+        return crate::dmabuf::with_contents(dmabuf, |data, spec| with_tight_rows(data, spec, f))
+            .location(loc!())?
+            .location(loc!());
+    }
     shm::with_buffer_contents(buffer, |ptr, len, spec| {
         assert!(!ptr.is_null());
         let start = spec.offset as usize;
@@ -52,9 +128,11 @@ where
         unsafe {
             let ptr = ptr.add(start);
             let buf = BufferPointer::new(&ptr, buffer_len);
-            f(buf, spec)
+            with_tight_rows(buf, spec, f)
         }
     })
+    .location(loc!())?
+    .location(loc!())
 }
 
 // Based on https://github.com/Smithay/smithay/blob/b1c682742ac7b9fa08736476df3e651489709ac2/src/desktop/wayland/utils.rs.
@@ -100,28 +178,184 @@ pub fn send_frames(
     Ok(())
 }
 
-pub fn update_output(local_output: &mut Output, output: OutputInfo) {
-    let current_mode = local_output.current_mode().unwrap_or(Mode {
-        size: (0, 0).into(),
-        refresh: 0,
+/// The size of `buffer` in buffer coordinates, if it's an shm or dmabuf
+/// buffer.
+pub fn buffer_size(buffer: &WlBuffer) -> Option<Size<i32>> {
+    if let Ok(dmabuf) = dmabuf::get_dmabuf(buffer) {
+        return Some(dmabuf.size().into());
+    }
+    shm::with_buffer_contents(buffer, |_, _, spec| (spec.width, spec.height).into()).ok()
+}
+
+/// `damage` in buffer coordinates, for a buffer of `buffer_size`, if known,
+/// attached with `buffer_scale` and `transform`.
+pub fn damage_to_buffer(
+    damage: &Damage,
+    buffer_scale: i32,
+    transform: Transform,
+    buffer_size: Option<Size<i32>>,
+) -> Rectangle<i32> {
+    match damage {
+        Damage::Buffer(rect) => (*rect).into(),
+        Damage::Surface(rect) => {
+            // The transform flips or rotates the damage within the surface.
+            let surface_size = buffer_size.map_or(rect.size, |size| {
+                smithay::utils::Size::<i32, BufferCoords>::from((size.w, size.h))
+                    .to_logical(buffer_scale, transform)
+            });
+            rect.to_buffer(buffer_scale, transform, &surface_size)
+                .into()
+        },
+    }
+}
+
+/// The largest scale no larger than `scale` which evenly divides both
+/// dimensions of `buffer_size`.
+pub fn largest_valid_buffer_scale(buffer_size: Size<i32>, scale: i32) -> i32 {
+    (1..=scale)
+        .rev()
+        .find(|scale| buffer_size.w % scale == 0 && buffer_size.h % scale == 0)
+        .unwrap_or(1)
+}
+
+/// The text of a keymap forwarded from the local compositor. Keymaps in
+/// wl_keyboard.keymap fds are NUL-terminated, and xkbcommon panics on NULs
+/// when compiling a keymap from a string, so the keymap ends at the first one.
+fn forwarded_keymap_text(keymap: &str) -> &str {
+    keymap.split('\0').next().unwrap_or_default()
+}
+
+/// Loads a keymap forwarded from the local compositor into `keyboard`. smithay
+/// recompiles it and sends it to clients in XKB v1 format, in a sealed memfd
+/// (a plain file for wl_keyboard older than v7) whose size includes the
+/// terminating NUL.
+pub fn set_forwarded_keymap<D: SeatHandler + 'static>(
+    keyboard: &KeyboardHandle<D>,
+    data: &mut D,
+    keymap: &str,
+) -> Result<()> {
+    keyboard
+        .set_keymap_from_string(data, forwarded_keymap_text(keymap).to_owned())
+        .location(loc!())
+}
+
+/// Offset between evdev keycodes (see linux/input-event-codes.h) and XKB
+/// keycodes. X11 reserves keycodes below 8, so XKB keymaps, including the ones
+/// compositors send on wl_keyboard, name evdev key n as keycode n + 8.
+pub const EVDEV_KEYCODE_OFFSET: u32 = 8;
+
+/// The XKB keycode for `evdev_keycode`, as wl_keyboard.key and .enter carry
+/// it. Keys are always forwarded as evdev keycodes (never as keysyms), which
+/// are translated through the forwarded keymap only when delivered to the
+/// seat's keyboard, so that clients (and Xwayland, which maps them through
+/// its own copy of the keymap) see the characters of the local layout.
+/// smithay's KeyboardHandle::input takes XKB keycodes, see
+/// https://github.com/Smithay/smithay/pull/1536.
+pub fn xkb_keycode(evdev_keycode: u32) -> Keycode {
+    (evdev_keycode + EVDEV_KEYCODE_OFFSET).into()
+}
+
+/// The keys in `pressed_keys` which aren't among `held_keycodes`, in keycode
+/// order. When the keyboard focus moves to a surface, e.g. after the focused
+/// surface was destroyed without a wl_keyboard.leave, keys which were released
+/// in the meantime must be released before the keys the new focus sees held
+/// are pressed, or they'd stay pressed (e.g., Alt after Alt-Tab).
+pub fn keys_to_release(pressed_keys: &HashSet<u32>, held_keycodes: &[u32]) -> Vec<u32> {
+    let mut keys: Vec<u32> = pressed_keys
+        .iter()
+        .filter(|keycode| !held_keycodes.contains(keycode))
+        .copied()
+        .collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// The buffer scale to suggest to a surface on outputs with `scales`: the
+/// largest one, so that the surface is sharp on all of them.
+pub fn preferred_buffer_scale(scales: impl IntoIterator<Item = i32>) -> i32 {
+    scales.into_iter().max().unwrap_or(1)
+}
+
+/// Sends wl_surface.preferred_buffer_scale to `surface` if it changed since it
+/// was last sent. Does nothing for wl_surfaces older than v6.
+pub fn send_preferred_buffer_scale(surface: &WlSurface, scale: i32) {
+    compositor::with_states(surface, |surface_data| {
+        compositor::send_surface_state(surface, surface_data, scale, Transform::Normal);
     });
-    let received_mode = Mode {
-        size: output.mode.dimensions.into(),
-        refresh: output.mode.refresh_rate,
-    };
-    if current_mode != received_mode {
-        local_output.delete_mode(current_mode);
+}
+
+#[derive(Debug, Default)]
+struct InvalidBufferScaleWarned(AtomicBool);
+
+/// Committing a buffer whose size isn't a multiple of the buffer scale is an
+/// invalid_size protocol error, but older clients do it and would be killed
+/// for it. Instead, the scale is clamped to one which divides the buffer size
+/// so that the surface we send is consistent. Warns once per surface.
+pub fn clamp_buffer_scale(
+    surface: &WlSurface,
+    data_map: &UserDataMap,
+    buffer_size: Size<i32>,
+    scale: i32,
+) -> i32 {
+    let valid_scale = largest_valid_buffer_scale(buffer_size, scale);
+    if valid_scale != scale {
+        data_map.insert_if_missing_threadsafe(InvalidBufferScaleWarned::default);
+        let warned = data_map
+            .get::<InvalidBufferScaleWarned>()
+            .is_some_and(|warned| warned.0.swap(true, Ordering::Relaxed));
+        if !warned {
+            warn!(
+                "surface {:?} committed a {}x{} buffer with scale {scale}, which doesn't divide it, using scale {valid_scale} instead",
+                surface.id(),
+                buffer_size.w,
+                buffer_size.h,
+            );
+        }
+    }
+    valid_scale
+}
+
+fn smithay_mode(mode: &serialization::wayland::Mode) -> Mode {
+    Mode {
+        size: mode.dimensions.into(),
+        refresh: mode.refresh_rate,
+    }
+}
+
+/// Applies `output` to `local_output`, advertising all of its modes so that
+/// e.g. apps offering a list of resolutions see them. Modes the output no
+/// longer has are removed, but wl_output can't retract modes from clients
+/// which already bound it, so they only disappear for new clients.
+pub fn update_output(local_output: &mut Output, output: OutputInfo) {
+    let received_mode = smithay_mode(&output.mode);
+    let modes: Vec<Mode> = output
+        .modes
+        .iter()
+        .map(smithay_mode)
+        .chain([received_mode])
+        .collect();
+    for mode in local_output.modes() {
+        if !modes.contains(&mode) {
+            local_output.delete_mode(mode);
+        }
+    }
+    for mode in &modes {
+        local_output.add_mode(*mode);
     }
 
     local_output.change_current_state(
         Some(received_mode),
         Some(output.transform.into()),
-        Some(Scale::Integer(output.scale_factor)),
+        Some(output_scale::smithay_scale(output.scale_120)),
         Some(output.location.into()),
     );
 
-    if output.mode.preferred {
-        local_output.set_preferred(received_mode);
+    if let Some(preferred) = [&output.mode]
+        .into_iter()
+        .chain(&output.modes)
+        .find(|mode| mode.preferred)
+    {
+        local_output.set_preferred(smithay_mode(preferred));
     }
 }
 
@@ -151,3 +385,394 @@ pub fn update_surface_outputs<'a, F>(
         }
     }
 }
+
+/// The kind and region of the constraint of `pointer` on `surface`, if there
+/// is one.
+pub fn pointer_constraint<D: SeatHandler + 'static>(
+    surface: &WlSurface,
+    pointer: &PointerHandle<D>,
+) -> Option<(PointerConstraintKind, Option<RegionAttributes>)> {
+    with_pointer_constraint(surface, pointer, |constraint| {
+        let constraint = constraint?;
+        let kind = match *constraint {
+            PointerConstraint::Locked(_) => PointerConstraintKind::Lock,
+            PointerConstraint::Confined(_) => PointerConstraintKind::Confine,
+        };
+        Some((kind, constraint.region().cloned()))
+    })
+}
+
+/// Whether `hint`, a cursor position hint of a locked pointer, is in the
+/// lock's `region`. Apps (e.g., Xwayland emulating XWarpPointer) set hints to
+/// warp the cursor, which only makes sense within the region the pointer is
+/// locked to. Locks without a region cover the whole surface.
+pub fn position_hint_in_region(
+    region: Option<&RegionAttributes>,
+    hint: Point<f64, Logical>,
+) -> bool {
+    region.is_none_or(|region| region.contains(hint.to_i32_floor()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+
+    use nix::fcntl::FcntlArg;
+    use nix::fcntl::SealFlag;
+    use nix::fcntl::fcntl;
+    use smithay::input::keyboard::KeymapFile;
+    use smithay::input::keyboard::xkb;
+    use smithay::output::PhysicalProperties;
+    use smithay::reexports::wayland_server::protocol::wl_shm;
+    use smithay::wayland::compositor::RectangleKind;
+
+    use super::*;
+
+    /// A `width`x`height` buffer with `stride`, whose pixels' bytes are their
+    /// index and whose padding is 0xff.
+    fn padded_buffer(width: usize, height: usize, stride: usize) -> Vec<u8> {
+        let mut data = vec![0xff; stride * height];
+        for y in 0..height {
+            for x in 0..width {
+                let start = y * stride + x * BYTES_PER_PIXEL;
+                data[start..start + BYTES_PER_PIXEL].fill((y * width + x) as u8);
+            }
+        }
+        data
+    }
+
+    fn spec(width: i32, height: i32, stride: i32) -> BufferData {
+        BufferData {
+            offset: 0,
+            width,
+            height,
+            stride,
+            format: wl_shm::Format::Argb8888,
+        }
+    }
+
+    fn tight_rows(data: &[u8], spec: BufferData) -> Result<(Vec<u8>, BufferData)> {
+        let ptr = data.as_ptr();
+        // SAFETY: ptr comes from data, which outlives the BufferPointer.
+        let data = unsafe { BufferPointer::new(&ptr, data.len()) };
+        with_tight_rows(data, spec, |data, spec| {
+            let mut tight = vec![0; data.len()];
+            data.copy_to_nonoverlapping(&mut tight);
+            (tight, spec)
+        })
+    }
+
+    #[test]
+    fn padded_rows_are_packed() {
+        // The padding of the second row isn't a multiple of the pixel size.
+        for stride in [16, 14] {
+            let data = padded_buffer(3, 2, stride);
+            let (tight, tight_spec) = tight_rows(&data, spec(3, 2, stride as i32)).unwrap();
+            assert_eq!(tight_spec.stride, 12);
+            assert_eq!(tight, padded_buffer(3, 2, 12));
+
+            // The buffer can be reconstructed at its original stride.
+            let mut reconstructed = vec![0xff; data.len()];
+            for (y, row) in tight.chunks_exact(tight_spec.stride as usize).enumerate() {
+                reconstructed[y * stride..y * stride + row.len()].copy_from_slice(row);
+            }
+            assert_eq!(reconstructed, data);
+        }
+    }
+
+    #[test]
+    fn tight_rows_are_unchanged() {
+        let data = padded_buffer(3, 2, 12);
+        let (tight, tight_spec) = tight_rows(&data, spec(3, 2, 12)).unwrap();
+        assert_eq!(tight_spec.stride, 12);
+        assert_eq!(tight, data);
+    }
+
+    #[test]
+    fn short_stride_is_rejected() {
+        assert!(tight_rows(&[0; 16], spec(3, 2, 8)).is_err());
+    }
+
+    /// A keymap as wprsc forwards it, including the terminating NUL it has
+    /// when read straight from a wl_keyboard.keymap fd.
+    fn forwarded_keymap(layout: &str) -> String {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            layout,
+            "",
+            None,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .unwrap();
+        keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1) + "\0"
+    }
+
+    /// The character typed by pressing the key with `evdev_keycode` alone,
+    /// with the keymap forwarded for `layout`.
+    fn typed_char(layout: &str, evdev_keycode: u32) -> String {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_string(
+            &context,
+            forwarded_keymap_text(&forwarded_keymap(layout)).to_owned(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .unwrap();
+        xkb::State::new(&keymap).key_get_utf8(xkb_keycode(evdev_keycode))
+    }
+
+    // see linux/input-event-codes.h
+    const KEY_Q: u32 = 16;
+    const KEY_Y: u32 = 21;
+    const KEY_Z: u32 = 44;
+
+    #[test]
+    fn evdev_keycodes_map_to_local_layout() {
+        assert_eq!(typed_char("us", KEY_Y), "y");
+        assert_eq!(typed_char("us", KEY_Z), "z");
+        assert_eq!(typed_char("de", KEY_Y), "z");
+        assert_eq!(typed_char("de", KEY_Z), "y");
+        assert_eq!(typed_char("fr", KEY_Q), "a");
+    }
+
+    #[test]
+    fn forwarded_keymap_is_sent_in_sealed_fd() {
+        let forwarded = forwarded_keymap("us");
+        let text = forwarded_keymap_text(&forwarded);
+        assert!(!text.contains('\0'));
+
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_string(
+            &context,
+            text.to_owned(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .expect("forwarded keymap failed to compile");
+
+        KeymapFile::new(&keymap)
+            .with_fd(true, |fd, size| {
+                let seals = SealFlag::from_bits_truncate(
+                    fcntl(fd, FcntlArg::F_GET_SEALS).expect("keymap fd is not a memfd"),
+                );
+                assert!(seals.contains(
+                    SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_WRITE
+                ));
+
+                let file = File::from(fd.try_clone_to_owned().unwrap());
+                let mut contents = vec![0; size];
+                file.read_exact_at(&mut contents, 0).unwrap();
+                assert_eq!(file.metadata().unwrap().len(), size as u64);
+                // The size includes the terminating NUL.
+                assert_eq!(contents.pop(), Some(0));
+                assert_eq!(
+                    String::from_utf8(contents).unwrap(),
+                    keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1)
+                );
+            })
+            .unwrap();
+    }
+
+    fn surface_damage(x: i32, y: i32, w: i32, h: i32) -> Damage {
+        Damage::Surface(smithay::utils::Rectangle::new((x, y).into(), (w, h).into()))
+    }
+
+    #[test]
+    fn surface_damage_is_scaled() {
+        assert_eq!(
+            damage_to_buffer(
+                &surface_damage(10, 20, 30, 40),
+                2,
+                Transform::Normal,
+                Some((200, 200).into())
+            ),
+            Rectangle::new(20, 40, 60, 80)
+        );
+        let buffer_damage = Damage::Buffer(smithay::utils::Rectangle::new(
+            (10, 20).into(),
+            (30, 40).into(),
+        ));
+        assert_eq!(
+            damage_to_buffer(&buffer_damage, 2, Transform::Normal, None),
+            Rectangle::new(10, 20, 30, 40)
+        );
+    }
+
+    #[test]
+    fn surface_damage_is_transformed_within_surface() {
+        // A 200x100 buffer rotated by 90 degrees at scale 2 is a 50x100
+        // surface, whose rightmost column is the bottom rows of the buffer.
+        let buffer_size = Some((200, 100).into());
+        assert_eq!(
+            damage_to_buffer(
+                &surface_damage(0, 0, 50, 100),
+                2,
+                Transform::_90,
+                buffer_size
+            ),
+            Rectangle::new(0, 0, 200, 100)
+        );
+        assert_eq!(
+            damage_to_buffer(
+                &surface_damage(40, 0, 10, 100),
+                2,
+                Transform::_90,
+                buffer_size
+            ),
+            Rectangle::new(0, 80, 200, 20)
+        );
+    }
+
+    #[test]
+    fn valid_scale_is_kept() {
+        assert_eq!(largest_valid_buffer_scale((640, 480).into(), 2), 2);
+        assert_eq!(largest_valid_buffer_scale((641, 481).into(), 1), 1);
+    }
+
+    #[test]
+    fn odd_buffer_at_scale_2_is_clamped() {
+        assert_eq!(largest_valid_buffer_scale((641, 480).into(), 2), 1);
+        assert_eq!(largest_valid_buffer_scale((640, 481).into(), 2), 1);
+    }
+
+    #[test]
+    fn clamps_to_largest_divisor() {
+        // 6 isn't a multiple of 4, but it is of 3.
+        assert_eq!(largest_valid_buffer_scale((6, 6).into(), 4), 3);
+    }
+
+    #[test]
+    fn preferred_scale_is_largest_output_scale() {
+        assert_eq!(preferred_buffer_scale([1, 2]), 2);
+        assert_eq!(preferred_buffer_scale([]), 1);
+    }
+
+    #[test]
+    fn nonpositive_scale_becomes_1() {
+        assert_eq!(largest_valid_buffer_scale((640, 480).into(), 0), 1);
+    }
+
+    #[test]
+    fn warp_within_locked_region() {
+        let region = RegionAttributes {
+            rects: vec![
+                (
+                    RectangleKind::Add,
+                    smithay::utils::Rectangle::new((0, 0).into(), (800, 600).into()),
+                ),
+                (
+                    RectangleKind::Subtract,
+                    smithay::utils::Rectangle::new((0, 0).into(), (800, 40).into()),
+                ),
+            ],
+        };
+        assert!(position_hint_in_region(
+            Some(&region),
+            (400.5, 300.5).into()
+        ));
+        // In the subtracted title bar.
+        assert!(!position_hint_in_region(
+            Some(&region),
+            (400.0, 20.0).into()
+        ));
+        assert!(!position_hint_in_region(
+            Some(&region),
+            (900.0, 300.0).into()
+        ));
+        // Locks without a region cover the whole surface.
+        assert!(position_hint_in_region(None, (900.0, 300.0).into()));
+    }
+
+    fn mode(w: i32, h: i32, current: bool, preferred: bool) -> serialization::wayland::Mode {
+        serialization::wayland::Mode {
+            dimensions: (w, h).into(),
+            refresh_rate: 60000,
+            current,
+            preferred,
+        }
+    }
+
+    fn output_info(modes: Vec<serialization::wayland::Mode>) -> OutputInfo {
+        OutputInfo {
+            id: 1,
+            model: "model".to_string(),
+            make: "make".to_string(),
+            location: (0, 0).into(),
+            physical_size: (600, 340).into(),
+            subpixel: serialization::wayland::Subpixel::Unknown,
+            transform: serialization::wayland::Transform::Normal,
+            scale_factor: 1,
+            scale_120: 120,
+            mode: *modes.iter().find(|mode| mode.current).unwrap(),
+            modes,
+            name: None,
+            description: None,
+        }
+    }
+
+    fn sizes(modes: Vec<Mode>) -> Vec<(i32, i32)> {
+        modes
+            .iter()
+            .map(|mode| (mode.size.w, mode.size.h))
+            .collect()
+    }
+
+    #[test]
+    fn all_output_modes_are_advertised() {
+        let mut output = Output::new(
+            "output".to_string(),
+            PhysicalProperties {
+                size: (600, 340).into(),
+                subpixel: smithay::output::Subpixel::Unknown,
+                make: "make".to_string(),
+                model: "model".to_string(),
+            },
+        );
+        update_output(
+            &mut output,
+            output_info(vec![
+                mode(2560, 1440, false, true),
+                mode(1920, 1080, true, false),
+                mode(1280, 720, false, false),
+            ]),
+        );
+        assert_eq!(
+            sizes(output.modes()),
+            [(2560, 1440), (1920, 1080), (1280, 720)]
+        );
+        assert_eq!(output.current_mode().unwrap().size, (1920, 1080).into());
+        assert_eq!(output.preferred_mode().unwrap().size, (2560, 1440).into());
+
+        // The mode list changes on reconfigure.
+        update_output(
+            &mut output,
+            output_info(vec![
+                mode(2560, 1440, true, true),
+                mode(1920, 1080, false, false),
+                mode(800, 600, false, false),
+            ]),
+        );
+        assert_eq!(
+            sizes(output.modes()),
+            [(2560, 1440), (1920, 1080), (800, 600)]
+        );
+        assert_eq!(output.current_mode().unwrap().size, (2560, 1440).into());
+        assert_eq!(output.preferred_mode().unwrap().size, (2560, 1440).into());
+    }
+
+    #[test]
+    fn keys_held_on_focus_out_are_released() {
+        // KEY_LEFTALT and KEY_TAB are held when the focus moves away, e.g. on
+        // Alt-Tab.
+        let pressed_keys = HashSet::from([56, 15]);
+        assert_eq!(keys_to_release(&pressed_keys, &[]), [15, 56]);
+        // Only the keys which are no longer held are released when the focus
+        // moves to a surface.
+        assert_eq!(keys_to_release(&pressed_keys, &[56]), [15]);
+        assert!(keys_to_release(&pressed_keys, &[15, 56, 29]).is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Data transfer state (offers, pipes) keyed by transfer target. The
+/// clipboard, the primary selection, and drag-and-drop each have their own
+/// slot, so e.g. a drag can be in progress while the clipboard is set and
+/// neither clobbers the other's state.
+use crate::serialization::wayland::DataSource;
+
+#[derive(Debug)]
+pub struct DataTargets<T> {
+    selection: Option<T>,
+    primary: Option<T>,
+    dnd: Option<T>,
+}
+
+impl<T> Default for DataTargets<T> {
+    fn default() -> Self {
+        Self {
+            selection: None,
+            primary: None,
+            dnd: None,
+        }
+    }
+}
+
+impl<T> DataTargets<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slot(&self, target: DataSource) -> &Option<T> {
+        match target {
+            DataSource::Selection => &self.selection,
+            DataSource::Primary => &self.primary,
+            DataSource::DnD => &self.dnd,
+        }
+    }
+
+    fn slot_mut(&mut self, target: DataSource) -> &mut Option<T> {
+        match target {
+            DataSource::Selection => &mut self.selection,
+            DataSource::Primary => &mut self.primary,
+            DataSource::DnD => &mut self.dnd,
+        }
+    }
+
+    pub fn get(&self, target: DataSource) -> Option<&T> {
+        self.slot(target).as_ref()
+    }
+
+    /// Returns the previous value for `target`, if any.
+    pub fn set(&mut self, target: DataSource, value: T) -> Option<T> {
+        self.slot_mut(target).replace(value)
+    }
+
+    pub fn take(&mut self, target: DataSource) -> Option<T> {
+        self.slot_mut(target).take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection_and_concurrent_drag() {
+        let mut offers = DataTargets::new();
+        offers.set(DataSource::Selection, "clipboard offer");
+
+        // A drag starts while the clipboard offer is active.
+        assert_eq!(offers.set(DataSource::DnD, "drag offer"), None);
+        assert_eq!(offers.get(DataSource::Selection), Some(&"clipboard offer"));
+        assert_eq!(offers.get(DataSource::DnD), Some(&"drag offer"));
+
+        // The drop consumes the drag offer only.
+        assert_eq!(offers.take(DataSource::DnD), Some("drag offer"));
+        assert_eq!(offers.get(DataSource::DnD), None);
+        assert_eq!(offers.get(DataSource::Selection), Some(&"clipboard offer"));
+    }
+
+    #[test]
+    fn set_replaces_only_its_target() {
+        let mut pipes = DataTargets::new();
+        pipes.set(DataSource::Selection, 1);
+        pipes.set(DataSource::Primary, 2);
+        pipes.set(DataSource::DnD, 3);
+
+        assert_eq!(pipes.set(DataSource::Primary, 4), Some(2));
+        assert_eq!(pipes.take(DataSource::Selection), Some(1));
+        assert_eq!(pipes.take(DataSource::Selection), None);
+        assert_eq!(pipes.get(DataSource::Primary), Some(&4));
+        assert_eq!(pipes.get(DataSource::DnD), Some(&3));
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Import of dmabuf buffers, which Xwayland allocates for hardware-accelerated
+/// apps when it renders with glamor. Buffer contents always cross the
+/// transport as pixel data (the transport may be a network connection, which
+/// can't carry fds), so dmabufs are read back into memory when committed,
+/// like shm buffers. Only linear single-plane 8-bit RGB buffers are accepted,
+/// which can be read back by mapping them, without a renderer of our own.
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::backend::allocator::Buffer;
+use smithay::backend::allocator::Format;
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::allocator::Modifier;
+use smithay::backend::allocator::dmabuf::Dmabuf;
+use smithay::backend::allocator::dmabuf::DmabufMappingMode;
+use smithay::backend::allocator::dmabuf::DmabufSyncFlags;
+use smithay::reexports::wayland_server::protocol::wl_shm;
+use smithay::wayland::dmabuf::DmabufFeedback;
+use smithay::wayland::dmabuf::DmabufFeedbackBuilder;
+use smithay::wayland::shm::BufferData;
+
+use crate::buffer_pointer::BufferPointer;
+use crate::prelude::*;
+
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub enum DmabufBehavior {
+    /// Don't offer linux-dmabuf, apps render into shm buffers (in software).
+    #[default]
+    Disabled,
+    /// Offer linux-dmabuf for the GPU with render node `render_node` (e.g.
+    /// /dev/dri/renderD128) and read buffers back when they're committed.
+    Readback { render_node: PathBuf },
+}
+
+const FORMATS: [(Fourcc, wl_shm::Format); 2] = [
+    (Fourcc::Argb8888, wl_shm::Format::Argb8888),
+    (Fourcc::Xrgb8888, wl_shm::Format::Xrgb8888),
+];
+
+const BYTES_PER_PIXEL: u32 = 4;
+
+fn shm_format(format: Format) -> Option<wl_shm::Format> {
+    if format.modifier != Modifier::Linear {
+        return None;
+    }
+    FORMATS
+        .iter()
+        .find(|(fourcc, _)| *fourcc == format.code)
+        .map(|(_, shm_format)| *shm_format)
+}
+
+/// Whether `dmabuf` can be read back, see with_contents.
+pub fn is_readable(dmabuf: &Dmabuf) -> bool {
+    let size = dmabuf.size();
+    dmabuf.num_planes() == 1
+        && shm_format(dmabuf.format()).is_some()
+        && size.w > 0
+        && size.h > 0
+        && dmabuf
+            .strides()
+            .next()
+            .is_some_and(|stride| stride >= size.w as u32 * BYTES_PER_PIXEL)
+}
+
+/// The feedback to create the linux-dmabuf global with according to
+/// `behavior`, if enabled.
+pub fn default_feedback(behavior: &DmabufBehavior) -> Result<Option<DmabufFeedback>> {
+    let DmabufBehavior::Readback { render_node } = behavior else {
+        return Ok(None);
+    };
+    let feedback = DmabufFeedbackBuilder::new(
+        main_device(render_node).location(loc!())?,
+        FORMATS.iter().map(|(code, _)| Format {
+            code: *code,
+            modifier: Modifier::Linear,
+        }),
+    )
+    .build()
+    .location(loc!())?;
+    info!("offering dmabuf import for {render_node:?}");
+    Ok(Some(feedback))
+}
+
+fn main_device(render_node: &Path) -> Result<u64> {
+    Ok(nix::sys::stat::stat(render_node)
+        .with_context(loc!(), || format!("render node {render_node:?} not found"))?
+        .st_rdev)
+}
+
+/// Reads back the contents of `dmabuf`, which must be readable (see
+/// is_readable). As with shm buffers, only call this while handling the commit
+/// which attached the buffer.
+///
+/// # Panics
+/// If mapping the buffer gives us an invalid pointer.
+pub fn with_contents<F, T>(dmabuf: &Dmabuf, f: F) -> Result<T>
+where
+    F: FnOnce(BufferPointer<u8>, BufferData) -> T,
+{
+    let format = shm_format(dmabuf.format()).with_context(loc!(), || {
+        format!("unsupported format {:?}", dmabuf.format())
+    })?;
+    let size = dmabuf.size();
+    let stride = dmabuf.strides().next().location(loc!())?;
+    let buffer_len = (stride as usize) * (size.h as usize);
+
+    dmabuf
+        .sync_plane(0, DmabufSyncFlags::START | DmabufSyncFlags::READ)
+        .location(loc!())?;
+    let mapping = dmabuf.map_plane(0, DmabufMappingMode::READ);
+    let result = mapping.location(loc!()).and_then(|mapping| {
+        if buffer_len > mapping.length() {
+            bail!("buffer_len = {buffer_len}, len = {}", mapping.length());
+        }
+        let ptr = mapping.ptr().cast_const().cast::<u8>();
+        assert!(!ptr.is_null());
+        let spec = BufferData {
+            offset: 0,
+            width: size.w,
+            height: size.h,
+            stride: stride as i32,
+            format,
+        };
+        // SAFETY: the mapping is valid until it's dropped at the end of this
+        // closure and we check that the buffer fits within it.
+        let buf = unsafe { BufferPointer::new(&ptr, buffer_len) };
+        Ok(f(buf, spec))
+    });
+    dmabuf
+        .sync_plane(0, DmabufSyncFlags::END | DmabufSyncFlags::READ)
+        .location(loc!())?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_linear_rgb_formats_are_readable() {
+        let format = |code, modifier| Format { code, modifier };
+        assert_eq!(
+            shm_format(format(Fourcc::Argb8888, Modifier::Linear)),
+            Some(wl_shm::Format::Argb8888)
+        );
+        assert_eq!(
+            shm_format(format(Fourcc::Xrgb8888, Modifier::Linear)),
+            Some(wl_shm::Format::Xrgb8888)
+        );
+        assert_eq!(
+            shm_format(format(Fourcc::Xrgb8888, Modifier::I915_x_tiled)),
+            None
+        );
+        assert_eq!(shm_format(format(Fourcc::Nv12, Modifier::Linear)), None);
+    }
+}
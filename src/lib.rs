@@ -18,13 +18,19 @@ pub mod buffer_pointer;
 pub mod channel_utils;
 pub mod client;
 pub mod client_utils;
+pub mod clipboard_limit;
 pub mod compositor_utils;
 pub mod constants;
 pub mod control_server;
+pub mod data_targets;
+pub mod dmabuf;
 pub mod error_utils;
 pub mod fallible_entry;
 pub mod filtering;
+pub mod output_dpi;
+pub mod output_scale;
 pub mod prelude;
+pub mod reconnect;
 pub mod serialization;
 pub mod server;
 pub mod sharding_compression;
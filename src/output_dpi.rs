@@ -0,0 +1,105 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Validation of output physical sizes. X11 apps compute their DPI from the
+/// output's physical size, but compositors report 0x0 for outputs without
+/// EDID data (e.g., virtual outputs) and some monitors report only their aspect
+/// ratio (e.g., 16x9 cm), resulting in tiny or huge fonts.
+use crate::prelude::*;
+use crate::serialization::geometry::Size;
+use crate::serialization::wayland::OutputInfo;
+
+pub const DEFAULT_DPI: u32 = 96;
+
+/// Larger than any real display, about 10m wide.
+const MAX_PHYSICAL_SIZE_MM: i32 = 10_000;
+const MIN_PLAUSIBLE_DPI: f64 = 30.0;
+const MAX_PLAUSIBLE_DPI: f64 = 1000.0;
+
+const MM_PER_INCH: f64 = 25.4;
+
+fn dpi(pixels: i32, mm: i32) -> f64 {
+    f64::from(pixels) * MM_PER_INCH / f64::from(mm)
+}
+
+/// Whether `physical_size` (in mm) is plausible for an output with a mode of
+/// `mode_size` pixels.
+pub fn is_plausible(physical_size: Size<i32>, mode_size: Size<i32>) -> bool {
+    let plausible_mm = |mm| (1..=MAX_PHYSICAL_SIZE_MM).contains(&mm);
+    let plausible_dpi =
+        |pixels, mm| (MIN_PLAUSIBLE_DPI..=MAX_PLAUSIBLE_DPI).contains(&dpi(pixels, mm));
+    plausible_mm(physical_size.w)
+        && plausible_mm(physical_size.h)
+        && plausible_dpi(mode_size.w, physical_size.w)
+        && plausible_dpi(mode_size.h, physical_size.h)
+}
+
+/// The physical size (in mm) of an output with a mode of `mode_size` pixels at
+/// `dpi`.
+pub fn physical_size_from_dpi(mode_size: Size<i32>, dpi: u32) -> Size<i32> {
+    let mm = |pixels| (f64::from(pixels) * MM_PER_INCH / f64::from(dpi.max(1))).round() as i32;
+    (mm(mode_size.w), mm(mode_size.h)).into()
+}
+
+/// The physical size to advertise for `output`, falling back to its size at
+/// `default_dpi` if the reported size is implausible.
+pub fn physical_size(output: &OutputInfo, default_dpi: u32) -> Size<i32> {
+    let mode_size = output.mode.dimensions;
+    if is_plausible(output.physical_size, mode_size) {
+        return output.physical_size;
+    }
+    let fallback = physical_size_from_dpi(mode_size, default_dpi);
+    info!(
+        "output {} reported an implausible physical size of {}x{}mm for a {}x{} mode, using {}x{}mm ({default_dpi} DPI) instead",
+        output.id,
+        output.physical_size.w,
+        output.physical_size.h,
+        mode_size.w,
+        mode_size.h,
+        fallback.w,
+        fallback.h,
+    );
+    fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODE_1080P: Size<i32> = Size { w: 1920, h: 1080 };
+
+    #[test]
+    fn real_monitor_is_plausible() {
+        // A 24" 1080p monitor.
+        assert!(is_plausible((531, 299).into(), MODE_1080P));
+    }
+
+    #[test]
+    fn bogus_sizes_are_implausible() {
+        assert!(!is_plausible((0, 0).into(), MODE_1080P));
+        assert!(!is_plausible((-1, 299).into(), MODE_1080P));
+        // Aspect ratio only, in cm.
+        assert!(!is_plausible((16, 9).into(), MODE_1080P));
+        assert!(!is_plausible((100_000, 60_000).into(), MODE_1080P));
+    }
+
+    #[test]
+    fn fallback_matches_dpi() {
+        assert_eq!(physical_size_from_dpi(MODE_1080P, 96), (508, 286).into());
+        assert!(is_plausible(
+            physical_size_from_dpi(MODE_1080P, DEFAULT_DPI),
+            MODE_1080P
+        ));
+    }
+}
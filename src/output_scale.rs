@@ -0,0 +1,101 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Fractional output scales. wl_output only carries an integer scale, so the
+/// scale of an output scaled by e.g. 1.5 is derived from the ratio of its mode
+/// to its logical size (from xdg-output) and carried in 120ths, like
+/// wp_fractional_scale_v1 does. Only the outputs' scales are fractional:
+/// buffers keep integer buffer scales, which are forwarded as committed, and
+/// the local compositor scales them to the output.
+use smithay::output::Scale;
+
+use crate::serialization::geometry::Size;
+
+pub const SCALE_DENOMINATOR: u32 = 120;
+
+/// The scale, in 120ths, of an output with a mode of `mode_size` pixels and a
+/// logical size of `logical_size`, or `scale_factor` if its logical size is
+/// unknown.
+pub fn scale_120(mode_size: Size<i32>, logical_size: Option<Size<i32>>, scale_factor: i32) -> u32 {
+    let integer_scale = scale_factor.max(1) as u32 * SCALE_DENOMINATOR;
+    let Some(logical_size) = logical_size.filter(|size| size.w > 0 && size.h > 0) else {
+        return integer_scale;
+    };
+    // Compare the longer sides, the logical size is transformed but the mode
+    // isn't.
+    let mode = mode_size.w.max(mode_size.h);
+    let logical = logical_size.w.max(logical_size.h);
+    match (f64::from(mode) * f64::from(SCALE_DENOMINATOR) / f64::from(logical)).round() as u32 {
+        0 => integer_scale,
+        scale => scale,
+    }
+}
+
+/// The scale to advertise for an output with a scale of `scale_120`.
+/// smithay rounds fractional scales up for wl_output.scale.
+pub fn smithay_scale(scale_120: u32) -> Scale {
+    if scale_120 % SCALE_DENOMINATOR == 0 {
+        Scale::Integer((scale_120 / SCALE_DENOMINATOR) as i32)
+    } else {
+        Scale::Fractional(f64::from(scale_120) / f64::from(SCALE_DENOMINATOR))
+    }
+}
+
+/// The font DPI for X11 apps on an output with a scale of `scale_120`, given
+/// the DPI at scale 1.
+pub fn xft_dpi(base_dpi: u32, scale_120: u32) -> u32 {
+    (base_dpi * scale_120 + SCALE_DENOMINATOR / 2) / SCALE_DENOMINATOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_from_logical_size() {
+        let mode = (2880, 1800).into();
+        assert_eq!(scale_120(mode, Some((1920, 1200).into()), 2), 180);
+        assert_eq!(scale_120(mode, Some((1440, 900).into()), 2), 240);
+        // Rotated.
+        assert_eq!(scale_120(mode, Some((1200, 1920).into()), 2), 180);
+        assert_eq!(
+            scale_120((2560, 1600).into(), Some((2048, 1280).into()), 2),
+            150
+        );
+    }
+
+    #[test]
+    fn integer_scale_without_logical_size() {
+        let mode = (2880, 1800).into();
+        assert_eq!(scale_120(mode, None, 2), 240);
+        assert_eq!(scale_120(mode, Some((0, 0).into()), 1), 120);
+        assert_eq!(scale_120(mode, None, 0), 120);
+    }
+
+    #[test]
+    fn fractional_scales_are_advertised_as_fractional() {
+        assert!(matches!(smithay_scale(120), Scale::Integer(1)));
+        assert!(matches!(smithay_scale(240), Scale::Integer(2)));
+        assert!(matches!(smithay_scale(180), Scale::Fractional(1.5)));
+        assert_eq!(smithay_scale(180).integer_scale(), 2);
+    }
+
+    #[test]
+    fn dpi_follows_scale() {
+        assert_eq!(xft_dpi(96, 120), 96);
+        assert_eq!(xft_dpi(96, 180), 144);
+        assert_eq!(xft_dpi(96, 150), 120);
+        assert_eq!(xft_dpi(96, 240), 192);
+    }
+}
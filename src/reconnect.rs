@@ -0,0 +1,98 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Reconnection of wprsc to wprsd when the connection drops, e.g. because an
+/// ssh tunnel forwarding the socket restarted. wprsd keeps the state of its
+/// surfaces across connections and resends all of it when wprsc connects, so
+/// wprsc drops its windows on reconnection and recreates them from the
+/// resynced state.
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Reconnect {
+    /// Exit when the connection drops.
+    Disabled,
+    /// Retry connecting up to max_retries times, waiting initial_backoff_ms
+    /// before the first attempt and twice as long before each following one,
+    /// up to max_backoff_ms.
+    Enabled {
+        initial_backoff_ms: u64,
+        max_backoff_ms: u64,
+        max_retries: u32,
+    },
+}
+
+impl Reconnect {
+    /// The time to wait before reconnection attempt number `attempt`
+    /// (starting from 0), or None if no more attempts should be made.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        let Self::Enabled {
+            initial_backoff_ms,
+            max_backoff_ms,
+            max_retries,
+        } = *self
+        else {
+            return None;
+        };
+        if attempt >= max_retries {
+            return None;
+        }
+        let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+        Some(Duration::from_millis(
+            initial_backoff_ms
+                .saturating_mul(factor)
+                .min(max_backoff_ms),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let reconnect = Reconnect::Enabled {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            max_retries: 6,
+        };
+        let backoffs: Vec<_> = (0..)
+            .map_while(|attempt| reconnect.backoff(attempt))
+            .map(|backoff| backoff.as_millis())
+            .collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let reconnect = Reconnect::Enabled {
+            initial_backoff_ms: 100,
+            max_backoff_ms: u64::MAX,
+            max_retries: u32::MAX,
+        };
+        assert_eq!(
+            reconnect.backoff(200),
+            Some(Duration::from_millis(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn disabled_never_retries() {
+        assert_eq!(Reconnect::Disabled.backoff(0), None);
+    }
+}
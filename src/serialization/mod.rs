@@ -15,6 +15,7 @@
 use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use std::fmt::Debug;
+use std::fs;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::io::BufWriter;
@@ -26,6 +27,7 @@ use std::os::fd::AsFd;
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process;
 use std::str;
 use std::sync::Arc;
@@ -40,6 +42,7 @@ use crossbeam_channel::Receiver;
 use crossbeam_channel::RecvTimeoutError;
 use crossbeam_channel::Sender;
 use nix::sys::socket;
+use nix::sys::socket::sockopt::PeerCredentials;
 use nix::sys::socket::sockopt::RcvBuf;
 use nix::sys::socket::sockopt::SndBuf;
 use num_enum::IntoPrimitive;
@@ -65,7 +68,10 @@ use crate::arc_slice::ArcSlice;
 use crate::channel_utils::DiscardingSender;
 use crate::channel_utils::InfallibleSender;
 use crate::prelude::*;
+use crate::reconnect::Reconnect;
 use crate::sharding_compression::CompressedShards;
+use crate::sharding_compression::Compression;
+use crate::sharding_compression::CompressionChoice;
 use crate::sharding_compression::ShardingCompressor;
 use crate::sharding_compression::ShardingDecompressor;
 use crate::utils;
@@ -110,6 +116,15 @@ pub enum ObjectId {
 #[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize, serde_derive::Serialize)]
 pub struct Capabilities {
     pub xwayland: bool,
+    /// The compression of the buffers wprsd sends, see
+    /// sharding_compression::negotiate.
+    pub buffer_compression: Compression,
+}
+
+/// What wprsc asks for when it connects.
+#[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct ClientHello {
+    pub buffer_compression: CompressionChoice,
 }
 
 // TODO: https://github.com/rust-lang/rfcs/pull/2593 - simplify all the enums.
@@ -123,18 +138,24 @@ pub enum Request {
     Data(wayland::DataRequest),
     ClientDisconnected(ClientId),
     Capabilities(Capabilities),
+    TextInput(wayland::TextInputState),
+    PointerConstraint(wayland::PointerConstraintRequest),
 }
 
 #[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
 pub enum Event {
-    WprsClientConnect,
+    WprsClientConnect(ClientHello),
     Output(wayland::OutputEvent),
     PointerFrame(Vec<wayland::PointerEvent>),
+    Touch(wayland::TouchEvent),
     KeyboardEvent(wayland::KeyboardEvent),
     Toplevel(xdg_shell::ToplevelEvent),
     Popup(xdg_shell::PopupEvent),
     Data(wayland::DataEvent),
     Surface(wayland::SurfaceEvent),
+    TextInput(wayland::TextInputChange),
+    PointerConstraint(wayland::PointerConstraintEvent),
+    RelativeMotion(wayland::RelativeMotion),
 }
 
 // TODO: test that object ids with same value from different clients hash
@@ -474,11 +495,23 @@ where
     Ok((read_thread, write_thread))
 }
 
+/// Whether the process at the other end of `stream` is wprsc, i.e., whether
+/// wprsc connected to the socket directly, from the same machine, rather than
+/// through a forwarder such as ssh.
+fn peer_is_wprsc(stream: &UnixStream) -> bool {
+    let Ok(credentials) = socket::getsockopt(stream, PeerCredentials) else {
+        return false;
+    };
+    fs::read_to_string(format!("/proc/{}/comm", credentials.pid()))
+        .is_ok_and(|comm| comm.trim_end() == "wprsc")
+}
+
 fn accept_loop<ST, RT>(
     listener: UnixListener,
     read_channel_tx: channel::SyncSender<RecvType<RT>>,
     write_channel_rx: Receiver<SendType<ST>>,
     other_end_connected: Arc<AtomicBool>,
+    other_end_local: Arc<AtomicBool>,
 ) where
     ST: Serializable,
     ST::Archived: Deserialize<ST, HighDeserializer<RancorError>>
@@ -492,6 +525,7 @@ fn accept_loop<ST, RT>(
             debug!("waiting for client connection");
             let (stream, _) = listener.accept().unwrap();
             info!("wprs client connected");
+            other_end_local.store(peer_is_wprsc(&stream), Ordering::Release);
             let (read_thread, write_thread) = spawn_rw_loops(
                 scope,
                 stream.try_clone().unwrap(),
@@ -517,14 +551,37 @@ fn accept_loop<ST, RT>(
     });
 }
 
+/// Connects to `sock_path`, retrying with the backoff of `reconnect`. Returns
+/// None once the retries are exhausted.
+fn reconnect_with_backoff(sock_path: &Path, reconnect: Reconnect) -> Option<UnixStream> {
+    let mut attempt = 0;
+    while let Some(backoff) = reconnect.backoff(attempt) {
+        thread::sleep(backoff);
+        attempt += 1;
+        match UnixStream::connect(sock_path) {
+            Ok(stream) => {
+                info!("reconnected to {sock_path:?} after {attempt} attempt(s)");
+                enlarge_socket_buffer(&stream);
+                return Some(stream);
+            },
+            Err(e) => warn!("reconnection attempt {attempt} to {sock_path:?} failed: {e}"),
+        }
+    }
+    None
+}
+
 fn client_loop<ST, RT>(
+    sock_path: PathBuf,
     stream: UnixStream,
+    hello: Option<ST>,
+    reconnect: Reconnect,
     read_channel_tx: channel::SyncSender<RecvType<RT>>,
+    write_channel_tx: Sender<SendType<ST>>,
     write_channel_rx: Receiver<SendType<ST>>,
     other_end_connected: Arc<AtomicBool>,
 ) -> Result<()>
 where
-    ST: Serializable,
+    ST: Serializable + Clone,
     ST::Archived: Deserialize<ST, HighDeserializer<RancorError>>
         + for<'a> bytecheck::CheckBytes<HighValidator<'a, RancorError>>,
     RT: Serializable,
@@ -532,21 +589,50 @@ where
         + for<'a> bytecheck::CheckBytes<HighValidator<'a, RancorError>>,
 {
     thread::scope(|scope| {
-        let (read_thread, _) = spawn_rw_loops(
-            scope,
-            stream,
-            read_channel_tx,
-            write_channel_rx,
-            other_end_connected,
-        )
-        .location(loc!())?;
+        let mut stream = stream;
+        loop {
+            let (read_thread, write_thread) = spawn_rw_loops(
+                scope,
+                stream.try_clone().location(loc!())?,
+                read_channel_tx.clone(),
+                write_channel_rx.clone(),
+                other_end_connected.clone(),
+            )
+            .location(loc!())?;
 
-        // TODO: consider actually look at the error and not printing the reason
-        // if was actually just a disconnection and not some other error.
-        let result = utils::join_unwrap(read_thread);
-        debug!("read thread joined: {:?}", result);
-        eprintln!("server disconnected: {result:?}");
-        process::exit(1);
+            // TODO: consider actually look at the error and not printing the reason
+            // if was actually just a disconnection and not some other error.
+            let result = utils::join_unwrap(read_thread);
+            debug!("read thread joined: {:?}", result);
+            other_end_connected.store(false, Ordering::Relaxed);
+            // The read thread may have terminated because of bad data rather
+            // than a disconnection, so make sure the write thread stops too.
+            _ = stream.shutdown(Shutdown::Both);
+            let write_thread_result = utils::join_unwrap(write_thread);
+            debug!("write thread joined: {write_thread_result:?}");
+
+            if reconnect == Reconnect::Disabled {
+                eprintln!("server disconnected: {result:?}");
+                process::exit(1);
+            }
+            warn!("server disconnected: {result:?}, reconnecting");
+            stream = reconnect_with_backoff(&sock_path, reconnect).unwrap_or_else(|| {
+                error!("giving up on reconnecting to {sock_path:?}, exiting");
+                eprintln!("server disconnected and reconnecting failed: {result:?}");
+                process::exit(1);
+            });
+
+            // Messages queued for the previous connection are stale.
+            while write_channel_rx.try_recv().is_ok() {}
+            if let Some(hello) = &hello {
+                write_channel_tx
+                    .send(SendType::Object(hello.clone()))
+                    // The error type is not Send + Sync, which anyhow requires.
+                    .map_err(|e| anyhow!("{e}"))
+                    .location(loc!())?;
+            }
+            other_end_connected.store(true, Ordering::Relaxed);
+        }
     })
 }
 
@@ -566,6 +652,7 @@ where
     read_handle: Option<Channel<RecvType<RT>>>,
     write_handle: DiscardingSender<Sender<SendType<ST>>>,
     other_end_connected: Arc<AtomicBool>,
+    other_end_local: Arc<AtomicBool>,
 }
 
 impl<ST, RT> Serializer<ST, RT>
@@ -586,10 +673,20 @@ where
         let (writer_tx, writer_rx): (Sender<SendType<ST>>, Receiver<SendType<ST>>) =
             crossbeam_channel::unbounded();
         let other_end_connected = Arc::new(AtomicBool::new(false));
+        let other_end_local = Arc::new(AtomicBool::new(false));
 
         {
             let other_end_connected = other_end_connected.clone();
-            thread::spawn(move || accept_loop(listener, reader_tx, writer_rx, other_end_connected));
+            let other_end_local = other_end_local.clone();
+            thread::spawn(move || {
+                accept_loop(
+                    listener,
+                    reader_tx,
+                    writer_rx,
+                    other_end_connected,
+                    other_end_local,
+                )
+            });
         }
 
         let writer_tx = DiscardingSender {
@@ -601,11 +698,22 @@ where
             read_handle: Some(reader_rx),
             write_handle: writer_tx,
             other_end_connected,
+            other_end_local,
         })
     }
 
-    pub fn new_client<P: AsRef<Path>>(sock_path: P) -> Result<Self> {
-        let stream = UnixStream::connect(sock_path).location(loc!())?;
+    /// Connects to the server at `sock_path`. `hello` is sent first on every
+    /// connection, including reconnections made according to `reconnect`.
+    pub fn new_client<P: AsRef<Path>>(
+        sock_path: P,
+        hello: Option<ST>,
+        reconnect: Reconnect,
+    ) -> Result<Self>
+    where
+        ST: Clone,
+    {
+        let sock_path = sock_path.as_ref().to_path_buf();
+        let stream = UnixStream::connect(&sock_path).location(loc!())?;
         enlarge_socket_buffer(&stream);
 
         let (reader_tx, reader_rx): (channel::SyncSender<RecvType<RT>>, Channel<RecvType<RT>>) =
@@ -616,7 +724,26 @@ where
 
         {
             let other_end_connected = other_end_connected.clone();
-            thread::spawn(move || client_loop(stream, reader_tx, writer_rx, other_end_connected));
+            if let Some(hello) = &hello {
+                writer_tx
+                    .send(SendType::Object(hello.clone()))
+                    // The error type is not Send + Sync, which anyhow requires.
+                    .map_err(|e| anyhow!("{e}"))
+                    .location(loc!())?;
+            }
+            let writer_tx = writer_tx.clone();
+            thread::spawn(move || {
+                client_loop(
+                    sock_path,
+                    stream,
+                    hello,
+                    reconnect,
+                    reader_tx,
+                    writer_tx,
+                    writer_rx,
+                    other_end_connected,
+                )
+            });
         }
 
         let writer_tx = DiscardingSender {
@@ -628,6 +755,8 @@ where
             read_handle: Some(reader_rx),
             write_handle: writer_tx,
             other_end_connected,
+            // Only wprsd checks where the other end is.
+            other_end_local: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -652,4 +781,10 @@ where
     pub fn set_other_end_connected(&mut self, state: bool) {
         self.other_end_connected.store(state, Ordering::Relaxed);
     }
+
+    /// Whether the connected wprsc is on the same machine, as far as wprsd can
+    /// tell. Always false for wprsc.
+    pub fn other_end_local(&self) -> bool {
+        self.other_end_local.load(Ordering::Acquire)
+    }
 }
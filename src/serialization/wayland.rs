@@ -32,6 +32,7 @@ use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
 use smithay::utils::Transform as SmithayTransform;
 use smithay::wayland::compositor::RectangleKind as SmithayRectangleKind;
 use smithay::wayland::compositor::RegionAttributes;
+use smithay::wayland::selection::SelectionTarget;
 use smithay::wayland::selection::data_device::SourceMetadata as SmithaySourceMetadata;
 use smithay::wayland::shm::BufferData as SmithayBufferData;
 use smithay::wayland::viewporter::ViewportCachedState;
@@ -54,6 +55,7 @@ use super::tuple::Tuple2;
 use crate::args;
 use crate::buffer_pointer::BufferPointer;
 use crate::filtering;
+use crate::output_scale;
 use crate::prelude::*;
 use crate::serialization;
 use crate::serialization::ClientId;
@@ -251,11 +253,35 @@ impl Buffer {
     }
 }
 
+/// A tile of a buffer which is being sent progressively. The tile's pixels are
+/// sent in the preceding raw buffer message, tightly packed.
+#[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct BufferTile {
+    /// Metadata of the whole buffer the tile belongs to.
+    pub metadata: BufferMetadata,
+    /// Location of the tile in the buffer, in buffer pixels.
+    pub rect: Rectangle<i32>,
+}
+
+/// The damaged parts of a buffer, sent instead of the whole buffer when a
+/// commit only changes them. The pixels of the rects are sent in the preceding
+/// raw buffer message, each rect tightly packed, one after the other.
+#[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct PartialBuffer {
+    /// Metadata of the whole buffer, which is the same as that of the
+    /// previous commit.
+    pub metadata: BufferMetadata,
+    /// The damaged rects, in buffer pixels.
+    pub rects: Vec<Rectangle<i32>>,
+}
+
 // TODO: consider splitting SurfaceState, this only really makes sense for the
 // surface state we're sending, not the one we're storing.
 #[derive(Debug, Clone, Eq, PartialEq, EnumAsInner, Archive, Deserialize, Serialize)]
 pub enum BufferAssignment {
     New(Buffer),
+    /// Only sent, never stored.
+    Partial(PartialBuffer),
     Removed,
 }
 
@@ -301,6 +327,7 @@ impl From<SctkRepeatInfo> for RepeatInfo {
 #[derive(Copy, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
 pub struct KeyInner {
     pub serial: u32,
+    /// evdev keycode, see compositor_utils::xkb_keycode.
     pub raw_code: u32,
     pub state: KeyState,
 }
@@ -350,6 +377,7 @@ pub enum KeyboardEvent {
     Enter {
         serial: u32,
         surface_id: WlSurfaceId,
+        /// evdev keycodes of the pressed keys.
         keycodes: Vec<u32>,
         keysyms: Vec<u32>,
     },
@@ -413,10 +441,12 @@ impl From<AxisSource> for SmithayAxisSource {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Archive, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
 pub enum PointerEventKind {
     Enter {
         serial: u32,
+        /// Buttons already held when the pointer entered the surface.
+        buttons: Vec<u32>,
     },
     Leave {
         serial: u32,
@@ -440,7 +470,10 @@ pub enum PointerEventKind {
 impl From<SctkPointerEventKind> for PointerEventKind {
     fn from(event: SctkPointerEventKind) -> Self {
         match event {
-            SctkPointerEventKind::Enter { serial } => Self::Enter { serial },
+            SctkPointerEventKind::Enter { serial } => Self::Enter {
+                serial,
+                buttons: Vec::new(),
+            },
             SctkPointerEventKind::Leave { serial } => Self::Leave { serial },
             SctkPointerEventKind::Motion { time: _ } => Self::Motion,
             SctkPointerEventKind::Press {
@@ -467,7 +500,7 @@ impl From<SctkPointerEventKind> for PointerEventKind {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Archive, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
 pub struct PointerEvent {
     pub surface_id: WlSurfaceId,
     pub position: Point<f64>,
@@ -484,6 +517,27 @@ impl PointerEvent {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
+pub enum TouchEvent {
+    Down {
+        surface_id: WlSurfaceId,
+        id: i32,
+        position: Point<f64>,
+        serial: u32,
+    },
+    Up {
+        id: i32,
+        serial: u32,
+    },
+    Motion {
+        /// The surface the touch point went down on.
+        surface_id: WlSurfaceId,
+        id: i32,
+        position: Point<f64>,
+    },
+    Cancel,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
 pub struct SubSurfaceState {
     pub parent: WlSurfaceId,
@@ -688,6 +742,8 @@ pub struct SurfaceState {
     pub damage: Option<Vec<Rectangle<i32>>>,
     // server-side only
     pub output_ids: Vec<u32>,
+    /// In commits, only set if the viewport changed since the previous commit
+    /// of the surface, the viewport persists otherwise.
     pub viewport_state: Option<ViewportState>,
 
     // Interfaces
@@ -727,7 +783,7 @@ impl SurfaceState {
             Some(BufferAssignment::New(buffer)) => {
                 buffer.update(metadata, data, compressor).location(loc!())?;
             },
-            Some(BufferAssignment::Removed) | None => {
+            Some(BufferAssignment::Partial(_) | BufferAssignment::Removed) | None => {
                 self.buffer = Some(BufferAssignment::New(
                     Buffer::new(metadata, data, compressor).location(loc!())?,
                 ));
@@ -796,7 +852,18 @@ impl SurfaceState {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+)]
 pub enum Subpixel {
     Unknown,
     None,
@@ -862,13 +929,25 @@ pub struct OutputInfo {
     pub subpixel: Subpixel,
     pub transform: Transform,
     pub scale_factor: i32,
+    /// The possibly fractional scale in 120ths, see output_scale.
+    pub scale_120: u32,
+    /// The current mode.
     pub mode: Mode,
+    /// All modes the output advertises, including the current one.
+    pub modes: Vec<Mode>,
     pub name: Option<String>,
     pub description: Option<String>,
 }
 
 impl From<SctkOutputInfo> for OutputInfo {
     fn from(output: SctkOutputInfo) -> Self {
+        let mode: Mode = output
+            .modes
+            .iter()
+            .filter(|mode| mode.current)
+            .next_back()
+            .unwrap()
+            .into();
         Self {
             id: output.id,
             model: output.model.clone(),
@@ -878,13 +957,13 @@ impl From<SctkOutputInfo> for OutputInfo {
             subpixel: output.subpixel.into(),
             transform: output.transform.into(),
             scale_factor: output.scale_factor,
-            mode: output
-                .modes
-                .iter()
-                .filter(|mode| mode.current)
-                .next_back()
-                .unwrap()
-                .into(),
+            scale_120: output_scale::scale_120(
+                mode.dimensions,
+                output.logical_size.map(Into::into),
+                output.scale_factor,
+            ),
+            mode,
+            modes: output.modes.iter().map(Into::into).collect(),
             name: output.name.clone(),
             description: output.description.clone(),
         }
@@ -894,6 +973,11 @@ impl From<SctkOutputInfo> for OutputInfo {
 #[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
 pub enum SurfaceRequestPayload {
     Commit(SurfaceState),
+    /// A commit which is part of the same frame as the next Commit, see
+    /// server::commit_batch.
+    BatchedCommit(SurfaceState),
+    /// Part of the contents of the surface's current buffer, see BufferTile.
+    BufferTile(BufferTile),
     Destroyed,
 }
 
@@ -952,6 +1036,15 @@ pub enum DataSource {
     Primary,
 }
 
+impl From<SelectionTarget> for DataSource {
+    fn from(target: SelectionTarget) -> Self {
+        match target {
+            SelectionTarget::Clipboard => Self::Selection,
+            SelectionTarget::Primary => Self::Primary,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
 pub struct DragEnter {
     pub serial: u32,
@@ -988,6 +1081,12 @@ pub enum DataSourceRequest {
     // wl_data_device requests
     StartDrag(SourceMetadata, Option<Tuple2<ClientId, WlSurfaceId>>),
     SetSelection(DataSource, SourceMetadata),
+    /// The selection was unset, e.g. because its X11 owner relinquished it.
+    ClearSelection(DataSource),
+
+    // xdg_toplevel_drag_v1 requests
+    /// Move a toplevel with the ongoing drag, offset from the cursor hotspot.
+    AttachToplevel(Tuple2<ClientId, WlSurfaceId>, Point<i32>),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
@@ -1112,3 +1211,101 @@ impl From<&ViewportCachedState> for ViewportState {
         }
     }
 }
+
+#[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct SurroundingText {
+    pub text: String,
+    /// Byte offsets into text.
+    pub cursor: i32,
+    pub anchor: i32,
+}
+
+/// The committed state of a remote app's text input, see client::text_input.
+#[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct TextInputState {
+    pub client: ClientId,
+    pub surface: WlSurfaceId,
+    pub enabled: bool,
+    pub surrounding_text: Option<SurroundingText>,
+    /// zwp_text_input_v3.content_hint bits.
+    pub content_hint: u32,
+    /// zwp_text_input_v3.content_purpose value.
+    pub content_purpose: u32,
+    pub cursor_rectangle: Option<Rectangle<i32>>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct Preedit {
+    pub text: String,
+    /// Byte offsets into text of the part of the preedit string the input
+    /// method is working on, which apps usually highlight. -1 for both hides
+    /// the cursor.
+    pub cursor_begin: i32,
+    pub cursor_end: i32,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct DeleteSurroundingText {
+    /// Bytes to delete before and after the cursor.
+    pub before_length: u32,
+    pub after_length: u32,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub enum PointerConstraintKind {
+    Lock,
+    Confine,
+}
+
+/// A remote app's pointer lock or confinement, see
+/// client::pointer_constraints.
+#[derive(Debug, Clone, PartialEq, Archive, Deserialize, Serialize)]
+pub enum PointerConstraintRequest {
+    /// The app wants the pointer constrained while it is over `surface`.
+    Constrain {
+        client: ClientId,
+        surface: WlSurfaceId,
+        kind: PointerConstraintKind,
+        /// The region to constrain the pointer to, None for the whole surface.
+        region: Option<Region>,
+    },
+    /// Where the app draws the cursor while the pointer is locked, in surface
+    /// coordinates. The cursor is put there when the lock is released.
+    CursorPositionHint {
+        client: ClientId,
+        surface: WlSurfaceId,
+        position: Point<f64>,
+    },
+    /// The app destroyed the constraint.
+    Release {
+        client: ClientId,
+        surface: WlSurfaceId,
+    },
+}
+
+/// The local compositor (de)activated the constraint of a surface.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub enum PointerConstraintEvent {
+    Activated(WlSurfaceId),
+    Deactivated(WlSurfaceId),
+}
+
+/// Motion of the local pointer while it is constrained on `surface_id`.
+#[derive(Debug, Copy, Clone, PartialEq, Archive, Deserialize, Serialize)]
+pub struct RelativeMotion {
+    pub surface_id: WlSurfaceId,
+    pub delta: Point<f64>,
+    pub delta_unaccel: Point<f64>,
+    /// Timestamp in microseconds.
+    pub utime: u64,
+}
+
+/// The changes to a remote app's text sent by the local input method between
+/// two zwp_text_input_v3.done events.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Archive, Deserialize, Serialize)]
+pub struct TextInputChange {
+    /// Replaces the current preedit string, None clears it.
+    pub preedit: Option<Preedit>,
+    pub commit: Option<String>,
+    pub delete_surrounding_text: Option<DeleteSurroundingText>,
+}
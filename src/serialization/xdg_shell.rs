@@ -373,6 +373,9 @@ pub enum ToplevelRequestPayload {
     // "There is no way to know if the surface is currently minimized, nor is
     // there any way to unset minimization on this surface."
     SetMinimized,
+    /// The client activated the toplevel with an xdg-activation token, e.g.
+    /// the startup ID of an X11 app whose window was mapped.
+    Activate,
 
     Move(Move),
     Resize(Resize),
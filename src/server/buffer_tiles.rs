@@ -0,0 +1,280 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Progressive transmission of large buffers. The first frame of a large
+/// surface is sent as a blank buffer of the right size, followed by the actual
+/// contents split into tiles, one tile per event loop iteration. This keeps a
+/// single huge buffer from monopolizing the connection and delaying everything
+/// queued behind it.
+use std::collections::VecDeque;
+
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+
+use crate::buffer_pointer::BufferPointer;
+use crate::filtering;
+use crate::prelude::*;
+use crate::serialization::Request;
+use crate::serialization::SendType;
+use crate::serialization::geometry::Rectangle;
+use crate::serialization::wayland::BufferMetadata;
+use crate::serialization::wayland::BufferTile;
+use crate::serialization::wayland::SurfaceRequest;
+use crate::serialization::wayland::SurfaceRequestPayload;
+use crate::server::WprsServerState;
+use crate::sharding_compression::CompressedShards;
+use crate::sharding_compression::ShardingCompressor;
+
+const PIXEL_BYTES: usize = 4;
+
+/// Splits a `width`x`height` buffer into tiles of at most
+/// `tile_size`x`tile_size` pixels. Tiles overlapping `visible` come first,
+/// otherwise tiles are ordered top to bottom, left to right.
+pub fn tile_rects(
+    width: i32,
+    height: i32,
+    tile_size: i32,
+    visible: Option<Rectangle<i32>>,
+) -> Vec<Rectangle<i32>> {
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(tile_size as usize) {
+        for x in (0..width).step_by(tile_size as usize) {
+            tiles.push(Rectangle::new(
+                x,
+                y,
+                tile_size.min(width - x),
+                tile_size.min(height - y),
+            ));
+        }
+    }
+
+    if let Some(visible) = visible {
+        // sort_by_key is stable, so the row-major order is kept within each
+        // group.
+        tiles.sort_by_key(|tile| !overlaps(tile, &visible));
+    }
+    tiles
+}
+
+fn overlaps(a: &Rectangle<i32>, b: &Rectangle<i32>) -> bool {
+    a.loc.x < b.loc.x + b.size.w
+        && b.loc.x < a.loc.x + a.size.w
+        && a.loc.y < b.loc.y + b.size.h
+        && b.loc.y < a.loc.y + a.size.h
+}
+
+#[derive(Debug)]
+struct PendingBuffer {
+    surface: WlSurface,
+    metadata: BufferMetadata,
+    data: Vec<u8>,
+    tiles: VecDeque<Rectangle<i32>>,
+}
+
+/// Copies the pixels of `tile` out of `data`, tightly packed.
+fn tile_data(data: &[u8], stride: i32, tile: &Rectangle<i32>) -> Vec<u8> {
+    let row_len = tile.size.w as usize * PIXEL_BYTES;
+    let mut tile_data = Vec::with_capacity(row_len * tile.size.h as usize);
+    for y in tile.loc.y..(tile.loc.y + tile.size.h) {
+        let start = (y * stride) as usize + tile.loc.x as usize * PIXEL_BYTES;
+        tile_data.extend_from_slice(&data[start..start + row_len]);
+    }
+    tile_data
+}
+
+#[derive(Debug)]
+pub struct BufferTiles {
+    /// Buffers of at least this many bytes are sent progressively, 0 disables
+    /// progressive transmission.
+    threshold: usize,
+    tile_size: i32,
+    pending: VecDeque<PendingBuffer>,
+    scheduled: bool,
+}
+
+impl BufferTiles {
+    pub fn new(threshold: usize, tile_size: u32) -> Self {
+        Self {
+            threshold,
+            tile_size: tile_size.clamp(1, i32::MAX as u32) as i32,
+            pending: VecDeque::new(),
+            scheduled: false,
+        }
+    }
+
+    /// Only the first frame of a surface, or the first frame after a resize,
+    /// is split: later frames have the previous contents to fall back on.
+    pub fn should_split(
+        &self,
+        metadata: &BufferMetadata,
+        prev_metadata: Option<&BufferMetadata>,
+    ) -> bool {
+        self.threshold > 0 && metadata.len() >= self.threshold && prev_metadata != Some(metadata)
+    }
+
+    /// Queues the contents of `data` to be sent as tiles. `visible` is the part
+    /// of the buffer to send first, in buffer coordinates.
+    pub fn push(
+        &mut self,
+        surface: &WlSurface,
+        metadata: BufferMetadata,
+        data: BufferPointer<u8>,
+        visible: Option<Rectangle<i32>>,
+    ) {
+        self.cancel(surface);
+        let mut buf = vec![0; data.len()];
+        data.copy_to_nonoverlapping(&mut buf);
+        self.pending.push_back(PendingBuffer {
+            surface: surface.clone(),
+            metadata,
+            data: buf,
+            tiles: tile_rects(metadata.width, metadata.height, self.tile_size, visible).into(),
+        });
+    }
+
+    /// Whether tiles are still queued for `surface`.
+    pub fn is_pending(&self, surface: &WlSurface) -> bool {
+        self.pending
+            .iter()
+            .any(|pending| &pending.surface == surface)
+    }
+
+    /// Drops any tiles still queued for `surface`, e.g. because a newer buffer
+    /// was committed.
+    pub fn cancel(&mut self, surface: &WlSurface) {
+        self.pending.retain(|pending| &pending.surface != surface);
+    }
+
+    /// Pops the next tile to send, along with its pixels.
+    fn pop(&mut self) -> Option<(WlSurface, BufferTile, Vec<u8>)> {
+        loop {
+            let pending = self.pending.front_mut()?;
+            if !pending.surface.is_alive() {
+                self.pending.pop_front();
+                continue;
+            }
+            let Some(tile) = pending.tiles.pop_front() else {
+                self.pending.pop_front();
+                continue;
+            };
+            let tile_data = tile_data(&pending.data, pending.metadata.stride, &tile);
+            let tile = BufferTile {
+                metadata: pending.metadata,
+                rect: tile,
+            };
+            return Some((pending.surface.clone(), tile, tile_data));
+        }
+    }
+}
+
+/// A blank buffer of `len` bytes, sent in place of the real contents.
+pub fn placeholder(len: usize, compressor: &mut ShardingCompressor) -> CompressedShards {
+    let data = vec![0u8; len];
+    let ptr = data.as_ptr();
+    // SAFETY: ptr comes from data, which outlives the BufferPointer.
+    filtering::filter_and_compress(unsafe { BufferPointer::new(&ptr, data.len()) }, compressor)
+}
+
+impl WprsServerState {
+    /// Sends queued tiles, one per event loop iteration, until the queue is
+    /// empty.
+    pub fn schedule_buffer_tiles(&mut self) {
+        if self.buffer_tiles.scheduled {
+            return;
+        }
+        self.buffer_tiles.scheduled = true;
+        self.lh.insert_idle(|state| {
+            state.buffer_tiles.scheduled = false;
+            if let Ok(true) = state.send_next_buffer_tile().warn(loc!()) {
+                state.schedule_buffer_tiles();
+            }
+        });
+    }
+
+    /// Returns whether there may be more tiles to send.
+    #[instrument(skip(self), level = "debug")]
+    fn send_next_buffer_tile(&mut self) -> Result<bool> {
+        let Some((surface, tile, tile_data)) = self.buffer_tiles.pop() else {
+            return Ok(false);
+        };
+
+        let ptr = tile_data.as_ptr();
+        // SAFETY: ptr comes from tile_data, which outlives the BufferPointer.
+        let data = unsafe { BufferPointer::new(&ptr, tile_data.len()) };
+        let compressed = filtering::filter_and_compress(data, &mut self.compressor);
+
+        let writer = self.serializer.writer();
+        writer.send(SendType::RawBuffer(compressed.into()));
+        writer.send(SendType::Object(Request::Surface(
+            SurfaceRequest::new(&surface, SurfaceRequestPayload::BufferTile(tile))
+                .location(loc!())?,
+        )));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_buffer() {
+        let tiles = tile_rects(10, 5, 4, None);
+        assert_eq!(
+            tiles,
+            vec![
+                Rectangle::new(0, 0, 4, 4),
+                Rectangle::new(4, 0, 4, 4),
+                Rectangle::new(8, 0, 2, 4),
+                Rectangle::new(0, 4, 4, 1),
+                Rectangle::new(4, 4, 4, 1),
+                Rectangle::new(8, 4, 2, 1),
+            ]
+        );
+        let area: i32 = tiles.iter().map(|t| t.size.w * t.size.h).sum();
+        assert_eq!(area, 50);
+    }
+
+    #[test]
+    fn visible_tiles_first() {
+        let tiles = tile_rects(8, 8, 4, Some(Rectangle::new(5, 5, 2, 2)));
+        assert_eq!(
+            tiles,
+            vec![
+                Rectangle::new(4, 4, 4, 4),
+                Rectangle::new(0, 0, 4, 4),
+                Rectangle::new(4, 0, 4, 4),
+                Rectangle::new(0, 4, 4, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn visible_edges_are_exclusive() {
+        let tiles = tile_rects(8, 4, 4, Some(Rectangle::new(0, 0, 4, 4)));
+        assert_eq!(tiles[0], Rectangle::new(0, 0, 4, 4));
+        assert_eq!(tiles[1], Rectangle::new(4, 0, 4, 4));
+        assert!(!overlaps(&tiles[1], &Rectangle::new(0, 0, 4, 4)));
+    }
+
+    #[test]
+    fn tile_data_is_packed() {
+        // 3x2 pixels with one pixel of padding per row.
+        let data: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            tile_data(&data, 16, &Rectangle::new(1, 0, 2, 2)),
+            vec![4, 5, 6, 7, 8, 9, 10, 11, 20, 21, 22, 23, 24, 25, 26, 27]
+        );
+    }
+}
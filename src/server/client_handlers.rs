@@ -16,7 +16,6 @@
 use std::collections::HashSet;
 use std::collections::hash_map::Entry;
 use std::fs::File;
-use std::io::Read;
 use std::io::Write;
 use std::os::fd::AsFd;
 use std::thread;
@@ -35,11 +34,16 @@ use smithay::input::pointer::AxisFrame;
 use smithay::input::pointer::ButtonEvent;
 use smithay::input::pointer::Focus;
 use smithay::input::pointer::MotionEvent;
+use smithay::input::touch::DownEvent;
+use smithay::input::touch::MotionEvent as TouchMotionEvent;
+use smithay::input::touch::UpEvent;
 use smithay::output::Output;
 use smithay::output::PhysicalProperties;
 use smithay::reexports::wayland_server::Client;
 use smithay::reexports::wayland_server::backend::ObjectId;
 use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::Logical;
+use smithay::utils::Point;
 use smithay::utils::Rectangle;
 use smithay::utils::SERIAL_COUNTER;
 use smithay::utils::Serial;
@@ -50,8 +54,10 @@ use smithay::wayland::selection::primary_selection;
 
 use crate::args;
 use crate::compositor_utils;
+use crate::output_dpi;
 use crate::prelude::*;
 use crate::serialization::Capabilities;
+use crate::serialization::ClientHello;
 use crate::serialization::Event;
 use crate::serialization::RecvType;
 use crate::serialization::Request;
@@ -73,14 +79,18 @@ use crate::serialization::wayland::SurfaceEvent;
 use crate::serialization::wayland::SurfaceEventPayload;
 use crate::serialization::wayland::SurfaceRequest;
 use crate::serialization::wayland::SurfaceRequestPayload;
+use crate::serialization::wayland::TouchEvent;
 use crate::serialization::wayland::WlSurfaceId;
 use crate::serialization::xdg_shell::PopupConfigure;
 use crate::serialization::xdg_shell::PopupEvent;
 use crate::serialization::xdg_shell::ToplevelConfigure;
 use crate::serialization::xdg_shell::ToplevelEvent;
 use crate::server::LockedSurfaceState;
+use crate::server::N_COMPRESSORS;
 use crate::server::WprsServerState;
 use crate::server::smithay_handlers::DndGrab;
+use crate::sharding_compression;
+use crate::sharding_compression::ShardingCompressor;
 
 enum UnknownSurfaceErr {
     ObjectId(WlSurfaceId),
@@ -139,20 +149,40 @@ impl WprsServerState {
             };
 
             let time = self.start_time.elapsed().as_millis() as u32;
+            let origin = self.surface_origin(&surface);
+            let location = origin + Point::<f64, Logical>::from(event.position);
 
             match event.kind {
-                PointerEventKind::Enter { serial } => {
+                PointerEventKind::Enter { serial, buttons } => {
                     debug!("pointer entered at {:?}", event.position);
                     let serial = self.serial_map.insert(serial);
                     pointer.motion(
                         self,
-                        Some((surface, (0 as f64, 0 as f64).into())),
+                        Some((surface, origin)),
                         &MotionEvent {
-                            location: event.position.into(),
+                            location,
                             serial,
                             time,
                         },
                     );
+
+                    // Like keys on keyboard enter, buttons held while entering
+                    // are pressed on the surface.
+                    for button in buttons {
+                        if !self.pressed_buttons.insert(button) {
+                            continue;
+                        }
+                        debug!("pressing held button {:x}", button);
+                        pointer.button(
+                            self,
+                            &ButtonEvent {
+                                time,
+                                button,
+                                serial: SERIAL_COUNTER.next_serial(),
+                                state: ButtonState::Pressed,
+                            },
+                        );
+                    }
                 },
                 PointerEventKind::Leave { serial } => {
                     debug!("pointer left");
@@ -181,7 +211,7 @@ impl WprsServerState {
                         self,
                         None,
                         &MotionEvent {
-                            location: event.position.into(),
+                            location,
                             serial,
                             time,
                         },
@@ -191,9 +221,9 @@ impl WprsServerState {
                     debug!("pointer moved to {:?}", event.position);
                     pointer.motion(
                         self,
-                        Some((surface, (0 as f64, 0 as f64).into())),
+                        Some((surface, origin)),
                         &MotionEvent {
-                            location: event.position.into(),
+                            location,
                             serial: 0.into(), // unused
                             time,
                         },
@@ -259,6 +289,115 @@ impl WprsServerState {
         Ok(())
     }
 
+    fn touch_surface(&self, surface_id: &WlSurfaceId) -> Result<WlSurface> {
+        self.object_client_surface_from_id(surface_id)
+            .map(|(_, _, surface)| surface)
+            .map_err(|err| match err {
+                UnknownSurfaceErr::ObjectId(surface_id) => {
+                    anyhow!("Ignoring touch event for unknown object {:?}", surface_id)
+                },
+                UnknownSurfaceErr::Client(object_id) => {
+                    anyhow!("Ignoring touch event for unknown client {:?}", object_id)
+                },
+                UnknownSurfaceErr::Surface(client) => {
+                    anyhow!("Ignoring touch event for unknown surface {:?}", client)
+                },
+            })
+    }
+
+    pub(crate) fn pointer_constraint_surface(&self, surface_id: &WlSurfaceId) -> Result<WlSurface> {
+        self.object_client_surface_from_id(surface_id)
+            .map(|(_, _, surface)| surface)
+            .map_err(|err| match err {
+                UnknownSurfaceErr::ObjectId(surface_id) => anyhow!(
+                    "Ignoring pointer constraint event for unknown object {:?}",
+                    surface_id
+                ),
+                UnknownSurfaceErr::Client(object_id) => anyhow!(
+                    "Ignoring pointer constraint event for unknown client {:?}",
+                    object_id
+                ),
+                UnknownSurfaceErr::Surface(client) => anyhow!(
+                    "Ignoring pointer constraint event for unknown surface {:?}",
+                    client
+                ),
+            })
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    fn handle_touch(&mut self, event: TouchEvent) -> Result<()> {
+        let touch = self.seat.get_touch().location(loc!())?;
+        let time = self.start_time.elapsed().as_millis() as u32;
+
+        match event {
+            TouchEvent::Down {
+                surface_id,
+                id,
+                position,
+                serial,
+            } => {
+                debug!("touch {id} down at {:?}", position);
+                let Ok(surface) = self.touch_surface(&surface_id).warn(loc!()) else {
+                    return Ok(());
+                };
+                let origin = self.surface_origin(&surface);
+                let location = origin + Point::<f64, Logical>::from(position);
+                let serial = self.serial_map.insert(serial);
+                touch.down(
+                    self,
+                    Some((surface, origin)),
+                    &DownEvent {
+                        slot: Some(id as u32).into(),
+                        location,
+                        serial,
+                        time,
+                    },
+                );
+            },
+            TouchEvent::Up { id, serial } => {
+                debug!("touch {id} up");
+                let serial = self.serial_map.insert(serial);
+                touch.up(
+                    self,
+                    &UpEvent {
+                        slot: Some(id as u32).into(),
+                        serial,
+                        time,
+                    },
+                );
+            },
+            TouchEvent::Motion {
+                surface_id,
+                id,
+                position,
+            } => {
+                debug!("touch {id} moved to {:?}", position);
+                let Ok(surface) = self.touch_surface(&surface_id).warn(loc!()) else {
+                    return Ok(());
+                };
+                let origin = self.surface_origin(&surface);
+                let location = origin + Point::<f64, Logical>::from(position);
+                touch.motion(
+                    self,
+                    Some((surface, origin)),
+                    &TouchMotionEvent {
+                        slot: Some(id as u32).into(),
+                        location,
+                        time,
+                    },
+                );
+            },
+            TouchEvent::Cancel => {
+                debug!("touch cancelled");
+                touch.cancel(self);
+                return Ok(());
+            },
+        }
+        touch.frame(self);
+
+        Ok(())
+    }
+
     #[instrument(
         skip(self, keycode, state),
         fields(keycode = "<redacted>", state = "<redacted>"),
@@ -267,6 +406,12 @@ impl WprsServerState {
     fn set_key_state(&mut self, keycode: u32, state: KeyState, serial: Serial) -> Result<()> {
         let keyboard = self.seat.get_keyboard().location(loc!())?;
 
+        // Clients already saw the keys which were held when the keymap
+        // changed released, see release_pressed_keys.
+        if state != KeyState::Pressed && !self.pressed_keys.contains(&keycode) {
+            return Ok(());
+        }
+
         if args::get_log_priv_data() {
             debug!("sending key input: code {keycode:?}, state {state:?}");
         }
@@ -282,9 +427,7 @@ impl WprsServerState {
             FilterResult::Forward
         }
 
-        // our keycode is getting offset by 8 for reasons
-        // see https://github.com/Smithay/smithay/pull/1536
-        let x11_keycode = (keycode + 8).into();
+        let x11_keycode = compositor_utils::xkb_keycode(keycode);
         let time = self.start_time.elapsed().as_millis() as u32;
         match state {
             KeyState::Pressed => {
@@ -334,6 +477,31 @@ impl WprsServerState {
         Ok(())
     }
 
+    /// Releases all held keys, e.g. before the keymap changes so that clients
+    /// see the release of the same keysyms they saw pressed rather than of
+    /// whatever the new keymap maps the keys to. The keys are forgotten, so
+    /// their eventual releases from wprsc are dropped.
+    fn release_pressed_keys(&mut self) -> Result<()> {
+        for keycode in self.pressed_keys.clone() {
+            self.set_key_state(keycode, KeyState::Released, SERIAL_COUNTER.next_serial())
+                .location(loc!())?;
+        }
+        Ok(())
+    }
+
+    /// Releases all held keys if `surface`, which is being destroyed, has the
+    /// keyboard focus. wprsc may never send a leave for it, and the keys would
+    /// otherwise stay pressed for the next focus.
+    pub(crate) fn release_keys_of_destroyed_surface(&mut self, surface: &WlSurface) -> Result<()> {
+        let keyboard = self.seat.get_keyboard().location(loc!())?;
+        if keyboard.current_focus().as_ref() != Some(surface) {
+            return Ok(());
+        }
+        self.release_pressed_keys().location(loc!())?;
+        keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+        Ok(())
+    }
+
     #[instrument(skip_all, level = "debug")]
     fn handle_keyboard_event(&mut self, event: KeyboardEvent) -> Result<()> {
         let keyboard = self.seat.get_keyboard().location(loc!())?;
@@ -352,6 +520,14 @@ impl WprsServerState {
                     /* KEY_LEFTSHIFT */ 42, /* KEY_RIGHTSHIFT */ 54,
                 ]);
 
+                // The leave for the previous focus may have been lost, e.g.
+                // if it was destroyed, so keys released since must be released
+                // here.
+                for keycode in compositor_utils::keys_to_release(&self.pressed_keys, &keycodes) {
+                    self.set_key_state(keycode, KeyState::Released, SERIAL_COUNTER.next_serial())
+                        .location(loc!())?;
+                }
+
                 // We simulate keycodes before focusing since that is what a normal wayland application would see.
                 // Process modifier keys first so that they apply to other held keys.
                 let mut delayed_keycodes = Vec::new();
@@ -401,10 +577,7 @@ impl WprsServerState {
                 data_device::set_data_device_focus(&self.dh, &self.seat, None);
                 primary_selection::set_primary_focus(&self.dh, &self.seat, None);
 
-                for keycode in self.pressed_keys.clone() {
-                    self.set_key_state(keycode, KeyState::Released, SERIAL_COUNTER.next_serial())
-                        .location(loc!())?;
-                }
+                self.release_pressed_keys().location(loc!())?;
             },
             KeyboardEvent::Key(KeyInner {
                 serial,
@@ -425,9 +598,10 @@ impl WprsServerState {
                 },
                 RepeatInfo::Disable => {},
             },
-            KeyboardEvent::Keymap(keymap) => keyboard
-                .set_keymap_from_string(self, keymap)
-                .location(loc!())?,
+            KeyboardEvent::Keymap(keymap) => {
+                self.release_pressed_keys().location(loc!())?;
+                compositor_utils::set_forwarded_keymap(&keyboard, self, &keymap).location(loc!())?
+            },
             KeyboardEvent::Modifiers {
                 modifier_state,
                 layout_index,
@@ -570,7 +744,7 @@ impl WprsServerState {
     }
 
     #[instrument(skip_all, level = "debug")]
-    fn handle_output(&mut self, output_event: OutputEvent) -> Result<()> {
+    pub(crate) fn handle_output(&mut self, output_event: OutputEvent) -> Result<()> {
         match output_event {
             OutputEvent::New(output) => {
                 let (local_output, _) = self.outputs.entry(output.id).or_insert_with_key(|id| {
@@ -581,8 +755,8 @@ impl WprsServerState {
                             output.name.clone().unwrap_or("None".to_string())
                         ),
                         PhysicalProperties {
-                            size: output.physical_size.into(),
-                            subpixel: output.subpixel.into(),
+                            size: output_dpi::physical_size(&output, self.default_dpi).into(),
+                            subpixel: self.subpixel_overrides.subpixel(&output).into(),
                             make: output.make.clone(),
                             model: output.model.clone(),
                         },
@@ -615,14 +789,28 @@ impl WprsServerState {
     }
 
     #[instrument(skip_all, level = "debug")]
-    fn handle_connect(&mut self) -> Result<()> {
+    fn handle_connect(&mut self, hello: ClientHello) -> Result<()> {
         // TODO: sync client outputs
         self.serializer.set_other_end_connected(true);
+        self.pointer_constraint_state.clear();
+
+        let buffer_compression = sharding_compression::negotiate(
+            self.buffer_compression,
+            hello.buffer_compression,
+            self.serializer.other_end_local(),
+        );
+        info!("compressing buffers with {buffer_compression:?}");
+        if buffer_compression != self.compressor.compression() {
+            self.compressor =
+                ShardingCompressor::with_compression(N_COMPRESSORS, buffer_compression)
+                    .location(loc!())?;
+        }
 
         self.serializer
             .writer()
             .send(SendType::Object(Request::Capabilities(Capabilities {
                 xwayland: self.xwayland_enabled,
+                buffer_compression,
             })));
 
         self.for_each_surface(|_, surface_data| {
@@ -672,10 +860,12 @@ impl WprsServerState {
                 mime,
             )) => {
                 let (recv_fd, send_fd) = unistd::pipe2(OFlag::O_CLOEXEC).location(loc!())?; // TODO: handle error
-                let mut f = File::from(recv_fd);
+                let f = File::from(recv_fd);
+                let limit = self.clipboard_limit.for_mime_type(&mime);
 
                 {
                     let writer = self.serializer.writer().into_inner();
+                    let mime = mime.clone();
                     // The data source application will write to the other end
                     // of read_pipe at its convenience and then close the file
                     // descriptor, so spawn off a thread to perform that read
@@ -683,9 +873,11 @@ impl WprsServerState {
                     // completed. The thread will then terminate
                     thread::spawn(move || {
                         debug!("in receive read thread");
-                        let mut buf = Vec::new();
-                        let bytes_read = f.read_to_end(&mut buf);
-                        debug!("read selection ({bytes_read:?} bytes): {buf:?}");
+                        let buf = limit.read_to_end(f, &mime).unwrap_or_else(|e| {
+                            warn!("failed to read {mime} selection: {e:?}");
+                            Vec::new()
+                        });
+                        debug!("read selection ({} bytes): {buf:?}", buf.len());
                         writer.send(SendType::Object(Request::Data(DataRequest::TransferData(
                             source,
                             DataToTransfer(buf),
@@ -890,11 +1082,7 @@ impl WprsServerState {
                 };
             },
             DataEvent::TransferData(source, data) => {
-                let fd = match source {
-                    DataSource::Selection => self.selection_pipe.take().location(loc!())?,
-                    DataSource::Primary => self.primary_selection_pipe.take().location(loc!())?,
-                    DataSource::DnD => self.dnd_pipe.take().location(loc!())?,
-                };
+                let fd = self.data_pipes.take(source).location(loc!())?;
                 let mut f = File::from(fd);
                 // If data is large, the write may block if the reader (the
                 // application requesting the data) isn't reading it quickly
@@ -966,16 +1154,26 @@ impl WprsServerState {
     #[instrument(skip(self), level = "debug")]
     pub fn handle_event(&mut self, event: RecvType<Event>) {
         match event {
-            RecvType::Object(Event::WprsClientConnect) => self.handle_connect(),
+            RecvType::Object(Event::WprsClientConnect(hello)) => self.handle_connect(hello),
             RecvType::Object(Event::Toplevel(toplevel)) => self.handle_toplevel(toplevel),
             RecvType::Object(Event::Popup(popup)) => self.handle_popup(popup),
             RecvType::Object(Event::KeyboardEvent(event)) => self.handle_keyboard_event(event),
             RecvType::Object(Event::PointerFrame(events)) => self.handle_pointer_frame(events),
-            RecvType::Object(Event::Output(output_event)) => self.handle_output(output_event),
+            RecvType::Object(Event::Touch(event)) => self.handle_touch(event),
+            RecvType::Object(Event::Output(output_event)) => {
+                self.debounce_output_event(output_event)
+            },
             RecvType::Object(Event::Data(data_event)) => self.handle_data_event(data_event),
             RecvType::Object(Event::Surface(surface_event)) => {
+                // Surfaces may have entered outputs with held changes.
+                self.apply_output_changes();
                 self.handle_surface_event(surface_event)
             },
+            RecvType::Object(Event::TextInput(change)) => self.handle_text_input_change(change),
+            RecvType::Object(Event::PointerConstraint(event)) => {
+                self.handle_pointer_constraint_event(event)
+            },
+            RecvType::Object(Event::RelativeMotion(motion)) => self.handle_relative_motion(motion),
             RecvType::RawBuffer(_) => unreachable!(),
         }
         .log_and_ignore(loc!());
@@ -0,0 +1,150 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Atomic commits of subsurface trees. Synchronized subsurfaces only apply
+/// their state when their parent commits, so a parent commit and the commits
+/// of its synced children make up one frame. They're sent back to back, with
+/// every commit but the last one sent as a BatchedCommit, which wprsc holds
+/// until the closing Commit arrives and then applies together with it. This
+/// way, the local compositor never displays a partially-updated tree.
+use std::sync::Arc;
+
+use crate::serialization::Request;
+use crate::serialization::SendType;
+use crate::serialization::wayland::SurfaceRequest;
+use crate::serialization::wayland::SurfaceRequestPayload;
+use crate::sharding_compression::CompressedShards;
+
+#[derive(Debug, Default)]
+pub struct CommitBatch {
+    /// Commits in the order they were processed, children before parents,
+    /// along with their buffer contents if they attached a new buffer.
+    commits: Vec<(Option<Arc<CompressedShards>>, SurfaceRequest)>,
+}
+
+impl CommitBatch {
+    pub fn push(&mut self, raw_buffer: Option<Arc<CompressedShards>>, commit: SurfaceRequest) {
+        self.commits.push((raw_buffer, commit));
+    }
+
+    /// The messages to send for the commits pushed since the last call.
+    pub fn finish(&mut self) -> Vec<SendType<Request>> {
+        let len = self.commits.len();
+        self.commits
+            .drain(..)
+            .enumerate()
+            .flat_map(|(i, (raw_buffer, mut commit))| {
+                if i + 1 < len
+                    && let SurfaceRequestPayload::Commit(surface_state) = commit.payload
+                {
+                    commit.payload = SurfaceRequestPayload::BatchedCommit(surface_state);
+                }
+                // Each buffer must directly precede the commit it belongs to.
+                raw_buffer
+                    .map(SendType::RawBuffer)
+                    .into_iter()
+                    .chain([SendType::Object(Request::Surface(commit))])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::serialization::ClientId;
+    use crate::serialization::wayland::SurfaceState;
+    use crate::serialization::wayland::WlSurfaceId;
+    use crate::server::buffer_tiles;
+    use crate::sharding_compression::ShardingCompressor;
+
+    fn commit(id: u64) -> SurfaceRequest {
+        SurfaceRequest {
+            client: ClientId(1),
+            surface: WlSurfaceId(id),
+            payload: SurfaceRequestPayload::Commit(SurfaceState {
+                client: ClientId(1),
+                id: WlSurfaceId(id),
+                buffer: None,
+                role: None,
+                buffer_scale: 1,
+                buffer_transform: None,
+                opaque_region: None,
+                input_region: None,
+                z_ordered_children: Vec::new(),
+                damage: None,
+                output_ids: Vec::new(),
+                viewport_state: None,
+                xdg_surface_state: None,
+            }),
+        }
+    }
+
+    fn raw_buffer() -> Arc<CompressedShards> {
+        let mut compressor = ShardingCompressor::new(NonZeroUsize::new(1).unwrap(), 1).unwrap();
+        Arc::new(buffer_tiles::placeholder(16, &mut compressor))
+    }
+
+    #[test]
+    fn single_commit_is_not_batched() {
+        let mut batch = CommitBatch::default();
+        batch.push(None, commit(1));
+        let messages = batch.finish();
+        assert!(matches!(
+            messages.as_slice(),
+            [SendType::Object(Request::Surface(SurfaceRequest {
+                payload: SurfaceRequestPayload::Commit(_),
+                ..
+            }))]
+        ));
+        assert!(batch.finish().is_empty());
+    }
+
+    #[test]
+    fn parent_and_synced_children_are_batched() {
+        let mut batch = CommitBatch::default();
+        // Children first, as in commit_sync_children.
+        batch.push(Some(raw_buffer()), commit(2));
+        batch.push(Some(raw_buffer()), commit(3));
+        batch.push(Some(raw_buffer()), commit(1));
+
+        let messages = batch.finish();
+        let [
+            SendType::RawBuffer(_),
+            SendType::Object(Request::Surface(first_child)),
+            SendType::RawBuffer(_),
+            SendType::Object(Request::Surface(second_child)),
+            SendType::RawBuffer(_),
+            SendType::Object(Request::Surface(parent)),
+        ] = messages.as_slice()
+        else {
+            panic!("unexpected messages: {messages:?}");
+        };
+        assert_eq!(first_child.surface, WlSurfaceId(2));
+        assert!(matches!(
+            first_child.payload,
+            SurfaceRequestPayload::BatchedCommit(_)
+        ));
+        assert_eq!(second_child.surface, WlSurfaceId(3));
+        assert!(matches!(
+            second_child.payload,
+            SurfaceRequestPayload::BatchedCommit(_)
+        ));
+        // The parent's commit closes the batch.
+        assert_eq!(parent.surface, WlSurfaceId(1));
+        assert!(matches!(parent.payload, SurfaceRequestPayload::Commit(_)));
+    }
+}
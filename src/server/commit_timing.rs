@@ -0,0 +1,250 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Per-surface commit timing diagnostics, for finding out whether commit
+/// latency is spent reading the buffer, building the messages, or handing them
+/// off to the transport. Timings are exposed via the control socket.
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde_derive::Serialize;
+
+use crate::prelude::*;
+use crate::serialization::wayland::WlSurfaceId;
+
+/// Times the stages of a single commit.
+#[derive(Debug)]
+pub struct CommitTimer {
+    received: Instant,
+    buffer_read: Option<Instant>,
+    serialized: Option<Instant>,
+}
+
+impl CommitTimer {
+    pub fn start() -> Self {
+        Self {
+            received: Instant::now(),
+            buffer_read: None,
+            serialized: None,
+        }
+    }
+
+    /// The buffer contents were copied out of the client's buffer and
+    /// compressed.
+    pub fn buffer_read(&mut self) {
+        self.buffer_read = Some(Instant::now());
+    }
+
+    /// The messages to send were built.
+    pub fn serialized(&mut self) {
+        self.serialized = Some(Instant::now());
+    }
+
+    /// The messages were handed off to the serializer's writer thread.
+    pub fn sent(self) -> CommitTiming {
+        let sent = Instant::now();
+        let buffer_read = self.buffer_read.unwrap_or(self.received);
+        let serialized = self.serialized.unwrap_or(buffer_read);
+        CommitTiming {
+            buffer_read: self.buffer_read.map(|t| t - self.received),
+            serialized: serialized - buffer_read,
+            sent: sent - serialized,
+        }
+    }
+}
+
+/// How long each stage of a commit took, measured from the end of the
+/// previous stage.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitTiming {
+    /// None for commits without a new buffer.
+    pub buffer_read: Option<Duration>,
+    pub serialized: Duration,
+    pub sent: Duration,
+}
+
+impl CommitTiming {
+    fn total(&self) -> Duration {
+        self.buffer_read.unwrap_or_default() + self.serialized + self.sent
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+struct StageMicros {
+    buffer_read: u64,
+    serialized: u64,
+    sent: u64,
+    total: u64,
+}
+
+impl From<CommitTiming> for StageMicros {
+    fn from(timing: CommitTiming) -> Self {
+        Self {
+            buffer_read: timing.buffer_read.unwrap_or_default().as_micros() as u64,
+            serialized: timing.serialized.as_micros() as u64,
+            sent: timing.sent.as_micros() as u64,
+            total: timing.total().as_micros() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SurfaceTimings {
+    commits: u32,
+    buffer_commits: u32,
+    last: CommitTiming,
+    sum_buffer_read: Duration,
+    sum_serialized: Duration,
+    sum_sent: Duration,
+    sum_total: Duration,
+    max_total: Duration,
+}
+
+impl SurfaceTimings {
+    fn record(&mut self, timing: CommitTiming) {
+        self.commits += 1;
+        if let Some(buffer_read) = timing.buffer_read {
+            self.buffer_commits += 1;
+            self.sum_buffer_read += buffer_read;
+        }
+        self.sum_serialized += timing.serialized;
+        self.sum_sent += timing.sent;
+        self.sum_total += timing.total();
+        self.max_total = self.max_total.max(timing.total());
+        self.last = timing;
+    }
+
+    fn summary(&self) -> SurfaceSummary {
+        let commits = self.commits.max(1);
+        SurfaceSummary {
+            commits: self.commits,
+            buffer_commits: self.buffer_commits,
+            last_us: self.last.into(),
+            mean_us: StageMicros {
+                // Only averaged over commits which had a buffer.
+                buffer_read: (self.sum_buffer_read / self.buffer_commits.max(1)).as_micros() as u64,
+                serialized: (self.sum_serialized / commits).as_micros() as u64,
+                sent: (self.sum_sent / commits).as_micros() as u64,
+                total: (self.sum_total / commits).as_micros() as u64,
+            },
+            max_total_us: self.max_total.as_micros() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SurfaceSummary {
+    commits: u32,
+    buffer_commits: u32,
+    last_us: StageMicros,
+    mean_us: StageMicros,
+    max_total_us: u64,
+}
+
+/// Commit timings of all live surfaces. Cloning shares the underlying
+/// timings, so the control server can read what the event loop records.
+#[derive(Debug, Default, Clone)]
+pub struct CommitTimings(Arc<Mutex<HashMap<WlSurfaceId, SurfaceTimings>>>);
+
+impl CommitTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, surface: WlSurfaceId, timing: CommitTiming) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(surface)
+            .or_default()
+            .record(timing);
+    }
+
+    pub fn remove(&self, surface: &WlSurfaceId) {
+        self.0.lock().unwrap().remove(surface);
+    }
+
+    /// A JSON object mapping surface ids to their timings, in microseconds.
+    pub fn to_json(&self) -> Result<String> {
+        let summaries: BTreeMap<u64, SurfaceSummary> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(surface, timings)| (surface.0, timings.summary()))
+            .collect();
+        serde_json::to_string(&summaries).location(loc!())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(buffer_read: Option<u64>, serialized: u64, sent: u64) -> CommitTiming {
+        CommitTiming {
+            buffer_read: buffer_read.map(Duration::from_micros),
+            serialized: Duration::from_micros(serialized),
+            sent: Duration::from_micros(sent),
+        }
+    }
+
+    #[test]
+    fn timer_stages_add_up() {
+        let mut timer = CommitTimer::start();
+        timer.buffer_read();
+        timer.serialized();
+        let received = timer.received;
+        let timing = timer.sent();
+        assert!(timing.buffer_read.is_some());
+        assert!(timing.total() <= received.elapsed());
+    }
+
+    #[test]
+    fn commit_without_buffer() {
+        let mut timer = CommitTimer::start();
+        timer.serialized();
+        assert_eq!(timer.sent().buffer_read, None);
+    }
+
+    #[test]
+    fn summary() {
+        let timings = CommitTimings::new();
+        let surface = WlSurfaceId(7);
+        timings.record(surface, timing(Some(100), 10, 2));
+        timings.record(surface, timing(None, 20, 4));
+
+        let summary = timings.0.lock().unwrap()[&surface].summary();
+        assert_eq!(summary.commits, 2);
+        assert_eq!(summary.buffer_commits, 1);
+        assert_eq!(
+            summary.mean_us,
+            StageMicros {
+                buffer_read: 100,
+                serialized: 15,
+                sent: 3,
+                total: 68,
+            }
+        );
+        assert_eq!(summary.last_us.total, 24);
+        assert_eq!(summary.max_total_us, 112);
+
+        timings.remove(&surface);
+        assert_eq!(timings.to_json().unwrap(), "{}");
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Prioritization of events from wprsc (input, but also configures, output
+/// changes, etc.) over buffer serialization. The main loop handles events from
+/// wprsc only once it has finished dispatching the requests of Wayland clients,
+/// so when apps commit many buffers at once, input waits for all of them to be
+/// serialized. With InputFirst, events from wprsc get their own, nested event
+/// loop instead, which is dispatched both as a source of the main loop and
+/// before each commit. Events from wprsc keep their relative order.
+use std::os::fd::AsFd;
+use std::os::fd::OwnedFd;
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::EventLoop;
+use smithay::reexports::calloop::LoopHandle;
+
+use crate::prelude::*;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum InputPriority {
+    /// Handle events from wprsc in turn with the requests of Wayland clients.
+    #[default]
+    Fifo,
+    /// Handle events from wprsc before each commit.
+    InputFirst,
+}
+
+/// The nested event loop events from wprsc are inserted into with InputFirst.
+pub struct InputLoop<D: 'static> {
+    event_loop: EventLoop<'static, D>,
+}
+
+impl<D: 'static> InputLoop<D> {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            event_loop: EventLoop::try_new().location(loc!())?,
+        })
+    }
+
+    pub fn handle(&self) -> LoopHandle<'static, D> {
+        self.event_loop.handle()
+    }
+
+    /// An fd which is readable when the loop has pending events, for inserting
+    /// it into the main loop.
+    pub fn fd(&self) -> Result<OwnedFd> {
+        self.event_loop
+            .as_fd()
+            .try_clone_to_owned()
+            .location(loc!())
+    }
+}
+
+/// Dispatches the pending events of the loop returned by `input_loop`, if any.
+/// The loop is taken out of `state` while it's being dispatched, so calls from
+/// within its own callbacks do nothing.
+pub fn dispatch<D: 'static>(
+    state: &mut D,
+    input_loop: impl Fn(&mut D) -> &mut Option<InputLoop<D>>,
+) {
+    let Some(mut taken) = input_loop(state).take() else {
+        return;
+    };
+    taken
+        .event_loop
+        .dispatch(Some(Duration::ZERO), state)
+        .log_and_ignore(loc!());
+    *input_loop(state) = Some(taken);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use smithay::reexports::calloop::Interest;
+    use smithay::reexports::calloop::Mode;
+    use smithay::reexports::calloop::PostAction;
+    use smithay::reexports::calloop::channel;
+    use smithay::reexports::calloop::generic::Generic;
+    use smithay::reexports::calloop::ping;
+
+    use super::*;
+
+    const COMMITS: u32 = 20;
+    const COMMIT_DURATION: Duration = Duration::from_millis(5);
+
+    #[derive(Default)]
+    struct State {
+        input_loop: Option<InputLoop<State>>,
+        latency: Option<Duration>,
+    }
+
+    fn dispatch_input(state: &mut State) {
+        dispatch(state, |state| &mut state.input_loop);
+    }
+
+    /// The time between input arriving and it being handled while the main
+    /// loop is busy serializing COMMITS buffers.
+    fn input_latency(priority: InputPriority) -> Duration {
+        let mut event_loop: EventLoop<State> = EventLoop::try_new().unwrap();
+        let mut state = State::default();
+        let (input_tx, input_rx) = channel::channel::<Instant>();
+        let input_callback = |event: channel::Event<Instant>, _: &mut (), state: &mut State| {
+            if let channel::Event::Msg(sent) = event {
+                state.latency = Some(sent.elapsed());
+            }
+        };
+
+        match priority {
+            InputPriority::Fifo => {
+                event_loop
+                    .handle()
+                    .insert_source(input_rx, input_callback)
+                    .unwrap();
+            },
+            InputPriority::InputFirst => {
+                let input_loop = InputLoop::new().unwrap();
+                input_loop
+                    .handle()
+                    .insert_source(input_rx, input_callback)
+                    .unwrap();
+                event_loop
+                    .handle()
+                    .insert_source(
+                        Generic::new(input_loop.fd().unwrap(), Interest::READ, Mode::Level),
+                        |_, _, state| {
+                            dispatch_input(state);
+                            Ok(PostAction::Continue)
+                        },
+                    )
+                    .unwrap();
+                state.input_loop = Some(input_loop);
+            },
+        }
+
+        // Synthetic buffer load: the input arrives while a batch of commits is
+        // being handled.
+        let (load_ping, load_source) = ping::make_ping().unwrap();
+        event_loop
+            .handle()
+            .insert_source(load_source, move |_, _, state| {
+                input_tx.send(Instant::now()).unwrap();
+                for _ in 0..COMMITS {
+                    if state.input_loop.is_some() {
+                        dispatch_input(state);
+                    }
+                    thread::sleep(COMMIT_DURATION);
+                }
+            })
+            .unwrap();
+        load_ping.ping();
+
+        while state.latency.is_none() {
+            event_loop
+                .dispatch(Some(Duration::from_secs(1)), &mut state)
+                .unwrap();
+        }
+        state.latency.unwrap()
+    }
+
+    #[test]
+    fn fifo_input_waits_for_commits() {
+        assert!(input_latency(InputPriority::Fifo) >= COMMIT_DURATION * COMMITS);
+    }
+
+    #[test]
+    fn prioritized_input_does_not_wait_for_commits() {
+        // Generous, the input should be handled before the second commit.
+        assert!(input_latency(InputPriority::InputFirst) < COMMIT_DURATION * COMMITS / 2);
+    }
+
+    #[test]
+    fn dispatch_is_not_reentrant() {
+        let mut state = State {
+            input_loop: Some(InputLoop::new().unwrap()),
+            latency: None,
+        };
+        let (ping, source) = ping::make_ping().unwrap();
+        state
+            .input_loop
+            .as_ref()
+            .unwrap()
+            .handle()
+            .insert_source(source, |_, _, state: &mut State| {
+                assert!(state.input_loop.is_none());
+                dispatch_input(state);
+                state.latency = Some(Duration::ZERO);
+            })
+            .unwrap();
+        ping.ping();
+        dispatch_input(&mut state);
+        assert_eq!(state.latency, Some(Duration::ZERO));
+        assert!(state.input_loop.is_some());
+    }
+}
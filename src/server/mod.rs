@@ -42,7 +42,10 @@ use smithay::wayland::shell::xdg::decoration::XdgDecorationState;
 use smithay::wayland::shm::ShmState;
 use smithay::reexports::wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration_manager::Mode as KdeDecorationMode;
 use smithay::wayland::viewporter::ViewporterState;
+use smithay::wayland::xdg_activation::XdgActivationState;
 
+use crate::clipboard_limit::ClipboardLimit;
+use crate::data_targets::DataTargets;
 use crate::prelude::*;
 use crate::serialization::wayland::SurfaceRequest;
 use crate::serialization::wayland::SurfaceRequestPayload;
@@ -52,11 +55,37 @@ use crate::serialization::Event;
 use crate::serialization::Request;
 use crate::serialization::SendType;
 use crate::serialization::Serializer;
+use crate::server::buffer_tiles::BufferTiles;
+use crate::server::commit_batch::CommitBatch;
+use crate::server::commit_timing::CommitTimings;
+use crate::server::input_priority::InputLoop;
+use crate::server::output_debounce::OutputDebouncer;
+use crate::server::pointer_constraints::PointerConstraintState;
+use crate::server::subpixel::SubpixelOverrides;
+use crate::server::text_input::TextInputManagerState;
+use crate::server::toplevel_drag::ToplevelDragState;
+use crate::sharding_compression;
+use crate::sharding_compression::CompressionChoice;
 use crate::sharding_compression::ShardingCompressor;
 use crate::utils::SerialMap;
 
+pub mod buffer_tiles;
 pub mod client_handlers;
+pub mod commit_batch;
+pub mod commit_timing;
+pub mod input_priority;
+pub mod output_debounce;
+pub mod output_layout;
+pub mod partial_buffers;
+pub mod pointer_constraints;
 pub mod smithay_handlers;
+pub mod subpixel;
+pub mod text_input;
+pub mod toplevel_drag;
+pub mod viewport;
+
+// TODO: try tuning this based on the number of cpus the machine has.
+const N_COMPRESSORS: NonZeroUsize = NonZeroUsize::new(16).unwrap();
 
 struct LockedSurfaceState(Mutex<SurfaceState>);
 
@@ -79,7 +108,14 @@ fn surface_destruction_callback(state: &mut WprsServerState, surface: &WlSurface
         })));
 
         state.object_map.remove(&surface_state.id);
+        state.pointer_constraint_state.remove(&surface_state.id);
+        if let Some(commit_timings) = &state.commit_timings {
+            commit_timings.remove(&surface_state.id);
+        }
     });
+    state
+        .release_keys_of_destroyed_surface(surface)
+        .log_and_ignore(loc!());
 }
 
 pub struct WprsServerState {
@@ -100,11 +136,29 @@ pub struct WprsServerState {
     pub data_device_state: DataDeviceState,
     pub primary_selection_state: PrimarySelectionState,
     pub viewporter_state: ViewporterState,
+    pub toplevel_drag_state: ToplevelDragState,
+    pub text_input_state: TextInputManagerState,
+    pub pointer_constraint_state: PointerConstraintState,
+    pub xdg_activation_state: XdgActivationState,
 
     pub seat: Seat<Self>,
 
     pub serializer: Serializer<Request, Event>,
     pub compressor: ShardingCompressor,
+    /// wprsd's side of the buffer compression negotiation, see
+    /// sharding_compression::negotiate.
+    buffer_compression: CompressionChoice,
+    pub buffer_tiles: BufferTiles,
+    pub commit_batch: CommitBatch,
+    /// None unless commit timing diagnostics are enabled.
+    pub commit_timings: Option<CommitTimings>,
+    /// Used for outputs with an implausible physical size.
+    pub default_dpi: u32,
+    pub output_debouncer: OutputDebouncer,
+    pub subpixel_overrides: SubpixelOverrides,
+    /// Events from wprsc, if they're prioritized over commits, see
+    /// input_priority.
+    input_loop: Option<InputLoop<Self>>,
     /// Reverse map from WlSurfaceId, which is the hash of ObjectId, back to its
     /// source ObjectId. We can't put this in SurfaceState because is
     /// serializable, while this only has meaning locally. We need this for
@@ -119,10 +173,10 @@ pub struct WprsServerState {
     pressed_keys: HashSet<u32>,
     pressed_buttons: HashSet<u32>,
 
-    selection_pipe: Option<OwnedFd>,
     dnd_source: Option<WlDataSource>,
-    dnd_pipe: Option<OwnedFd>,
-    primary_selection_pipe: Option<OwnedFd>,
+    /// Pipes to write transferred data to, keyed by transfer target.
+    data_pipes: DataTargets<OwnedFd>,
+    clipboard_limit: ClipboardLimit,
 }
 
 impl WprsServerState {
@@ -133,6 +187,14 @@ impl WprsServerState {
         xwayland_enabled: bool,
         frame_interval: Duration,
         kde_server_side_decorations: bool,
+        buffer_tiles: BufferTiles,
+        commit_timings: Option<CommitTimings>,
+        default_dpi: u32,
+        output_debounce_interval: Duration,
+        subpixel_overrides: SubpixelOverrides,
+        clipboard_limit: ClipboardLimit,
+        input_loop: Option<InputLoop<Self>>,
+        buffer_compression: CompressionChoice,
     ) -> Self {
         let mut seat_state = SeatState::new();
         let seat = seat_state.new_wl_seat(&dh, "wprs");
@@ -157,22 +219,44 @@ impl WprsServerState {
             data_device_state: DataDeviceState::new::<Self>(&dh),
             primary_selection_state: PrimarySelectionState::new::<Self>(&dh),
             viewporter_state: ViewporterState::new::<Self>(&dh),
+            toplevel_drag_state: ToplevelDragState::new(&dh),
+            text_input_state: TextInputManagerState::new(&dh),
+            pointer_constraint_state: PointerConstraintState::new(&dh),
+            xdg_activation_state: XdgActivationState::new::<Self>(&dh),
             seat,
             serializer,
-            // TODO: try tuning this based on the number of cpus the machine has.
-            compressor: ShardingCompressor::new(NonZeroUsize::new(16).unwrap(), 1).unwrap(),
+            // Replaced by the compression negotiated with wprsc once it
+            // connects.
+            compressor: ShardingCompressor::with_compression(
+                N_COMPRESSORS,
+                sharding_compression::negotiate(buffer_compression, CompressionChoice::Auto, false),
+            )
+            .unwrap(),
+            buffer_compression,
+            buffer_tiles,
+            commit_batch: CommitBatch::default(),
+            commit_timings,
+            default_dpi,
+            output_debouncer: OutputDebouncer::new(output_debounce_interval),
+            subpixel_overrides,
+            input_loop,
             object_map: HashMap::new(),
             outputs: HashMap::new(),
             serial_map: SerialMap::new(),
             pressed_keys: HashSet::new(),
             pressed_buttons: HashSet::new(),
-            selection_pipe: None,
             dnd_source: None,
-            dnd_pipe: None,
-            primary_selection_pipe: None,
+            data_pipes: DataTargets::new(),
+            clipboard_limit,
         }
     }
 
+    /// Handles pending events from wprsc now if they're prioritized over
+    /// commits.
+    pub fn dispatch_input(&mut self) {
+        input_priority::dispatch(self, |state| &mut state.input_loop);
+    }
+
     #[instrument(skip(self), level = "debug")]
     pub fn insert_surface(&mut self, surface: &WlSurface) -> Result<()> {
         self.object_map
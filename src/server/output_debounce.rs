@@ -0,0 +1,316 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Debouncing of output changes. Some local compositors send bursts of output
+/// changes (e.g., while probing display modes), and every change is visible to
+/// X11 apps as RandR events. Changes are held until no new one has arrived for
+/// the debounce interval and are then applied at once, coalesced to one change
+/// per output which leads to its final state. Changes are never held for longer
+/// than a few intervals, so that a steady stream of changes can't keep the
+/// outputs from being updated.
+use std::time::Duration;
+use std::time::Instant;
+
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+
+use crate::prelude::*;
+use crate::serialization::wayland::OutputEvent;
+use crate::serialization::wayland::OutputInfo;
+use crate::server::WprsServerState;
+
+/// Changes are applied at the latest this many intervals after the first one.
+const MAX_DELAY_FACTOR: u32 = 10;
+
+/// The coalesced changes to one output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PendingChange {
+    New(OutputInfo),
+    Update(OutputInfo),
+    Destroy(OutputInfo),
+    /// The output was destroyed and then created again with the same id.
+    Replace(OutputInfo),
+}
+
+impl PendingChange {
+    /// The coalesced change for `self` followed by `event`, or None if they
+    /// cancel out.
+    fn then(self, event: OutputEvent) -> Option<Self> {
+        Some(match (self, event) {
+            (Self::New(_), OutputEvent::Destroy(_)) => return None,
+            (Self::New(_), OutputEvent::New(output) | OutputEvent::Update(output)) => {
+                Self::New(output)
+            },
+            (Self::Update(_), OutputEvent::New(output) | OutputEvent::Update(output)) => {
+                Self::Update(output)
+            },
+            (Self::Destroy(_), OutputEvent::New(output)) => Self::Replace(output),
+            // The output doesn't exist, updates to it are ignored anyway.
+            (Self::Destroy(output), OutputEvent::Update(_)) => Self::Destroy(output),
+            (Self::Replace(_), OutputEvent::New(output) | OutputEvent::Update(output)) => {
+                Self::Replace(output)
+            },
+            (_, OutputEvent::Destroy(output)) => Self::Destroy(output),
+        })
+    }
+
+    fn into_events(self) -> Vec<OutputEvent> {
+        match self {
+            Self::New(output) => vec![OutputEvent::New(output)],
+            Self::Update(output) => vec![OutputEvent::Update(output)],
+            Self::Destroy(output) => vec![OutputEvent::Destroy(output)],
+            Self::Replace(output) => vec![
+                OutputEvent::Destroy(output.clone()),
+                OutputEvent::New(output),
+            ],
+        }
+    }
+}
+
+impl From<OutputEvent> for PendingChange {
+    fn from(event: OutputEvent) -> Self {
+        match event {
+            OutputEvent::New(output) => Self::New(output),
+            OutputEvent::Update(output) => Self::Update(output),
+            OutputEvent::Destroy(output) => Self::Destroy(output),
+        }
+    }
+}
+
+fn output_id(event: &OutputEvent) -> u32 {
+    match event {
+        OutputEvent::New(output) | OutputEvent::Update(output) | OutputEvent::Destroy(output) => {
+            output.id
+        },
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputDebouncer {
+    interval: Duration,
+    /// In the order the outputs were first changed.
+    pending: Vec<(u32, PendingChange)>,
+    first_change: Option<Instant>,
+    last_change: Option<Instant>,
+    /// Whether a timer to apply the held changes is scheduled.
+    pub scheduled: bool,
+}
+
+impl OutputDebouncer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            pending: Vec::new(),
+            first_change: None,
+            last_change: None,
+            scheduled: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// Whether changes are being held.
+    pub fn pending(&self) -> bool {
+        self.first_change.is_some()
+    }
+
+    /// Holds `event`, which arrived at `now`.
+    pub fn push(&mut self, event: OutputEvent, now: Instant) {
+        self.first_change.get_or_insert(now);
+        self.last_change = Some(now);
+
+        let id = output_id(&event);
+        match self
+            .pending
+            .iter()
+            .position(|(pending_id, _)| *pending_id == id)
+        {
+            Some(i) => {
+                let (_, change) = self.pending.remove(i);
+                if let Some(change) = change.then(event) {
+                    self.pending.insert(i, (id, change));
+                }
+            },
+            None => self.pending.push((id, event.into())),
+        }
+    }
+
+    /// When the held changes should be applied, None if there are none.
+    pub fn deadline(&self) -> Option<Instant> {
+        let quiet = self.last_change? + self.interval;
+        let max = self.first_change? + self.interval * MAX_DELAY_FACTOR;
+        Some(quiet.min(max))
+    }
+
+    /// Takes the held changes, coalesced.
+    pub fn take(&mut self) -> Vec<OutputEvent> {
+        self.first_change = None;
+        self.last_change = None;
+        self.pending
+            .drain(..)
+            .flat_map(|(_, change)| change.into_events())
+            .collect()
+    }
+}
+
+impl WprsServerState {
+    /// Handles `output_event` once the outputs have settled, see the module
+    /// documentation.
+    pub fn debounce_output_event(&mut self, output_event: OutputEvent) -> Result<()> {
+        if !self.output_debouncer.enabled() {
+            return self.handle_output(output_event).location(loc!());
+        }
+        self.output_debouncer.push(output_event, Instant::now());
+        if self.output_debouncer.scheduled {
+            return Ok(());
+        }
+
+        let deadline = self.output_debouncer.deadline().location(loc!())?;
+        self.lh
+            .insert_source(Timer::from_deadline(deadline), |_, _, state| {
+                match state.output_debouncer.deadline() {
+                    Some(deadline) if deadline > Instant::now() => {
+                        TimeoutAction::ToInstant(deadline)
+                    },
+                    _ => {
+                        state.output_debouncer.scheduled = false;
+                        state.apply_output_changes();
+                        TimeoutAction::Drop
+                    },
+                }
+            })
+            .map_err(|e| anyhow!("failed to insert output debounce timer: {e}"))
+            .location(loc!())?;
+        self.output_debouncer.scheduled = true;
+        Ok(())
+    }
+
+    /// Applies the held output changes immediately.
+    pub fn apply_output_changes(&mut self) {
+        for output_event in self.output_debouncer.take() {
+            self.handle_output(output_event).log_and_ignore(loc!());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::wayland::Mode;
+    use crate::serialization::wayland::Subpixel;
+    use crate::serialization::wayland::Transform;
+
+    fn output(id: u32, width: i32) -> OutputInfo {
+        OutputInfo {
+            id,
+            model: "model".to_string(),
+            make: "make".to_string(),
+            location: (0, 0).into(),
+            physical_size: (600, 340).into(),
+            subpixel: Subpixel::Unknown,
+            transform: Transform::Normal,
+            scale_factor: 1,
+            scale_120: 120,
+            mode: Mode {
+                dimensions: (width, 1080).into(),
+                refresh_rate: 60000,
+                current: true,
+                preferred: true,
+            },
+            modes: Vec::new(),
+            name: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn burst_of_updates_applies_final_state() {
+        let mut debouncer = OutputDebouncer::new(Duration::from_millis(100));
+        let start = Instant::now();
+        for (i, width) in [640, 800, 1024, 1920].into_iter().enumerate() {
+            debouncer.push(
+                OutputEvent::Update(output(1, width)),
+                start + Duration::from_millis(10 * i as u64),
+            );
+        }
+        assert_eq!(
+            debouncer.deadline(),
+            Some(start + Duration::from_millis(130))
+        );
+        assert_eq!(debouncer.take(), [OutputEvent::Update(output(1, 1920))]);
+        assert!(!debouncer.pending());
+        assert_eq!(debouncer.deadline(), None);
+    }
+
+    #[test]
+    fn outputs_are_coalesced_separately() {
+        let mut debouncer = OutputDebouncer::new(Duration::from_millis(100));
+        let now = Instant::now();
+        debouncer.push(OutputEvent::New(output(1, 640)), now);
+        debouncer.push(OutputEvent::Update(output(2, 640)), now);
+        debouncer.push(OutputEvent::Update(output(1, 1920)), now);
+        assert_eq!(
+            debouncer.take(),
+            [
+                OutputEvent::New(output(1, 1920)),
+                OutputEvent::Update(output(2, 640))
+            ]
+        );
+    }
+
+    #[test]
+    fn transient_output_is_dropped() {
+        let mut debouncer = OutputDebouncer::new(Duration::from_millis(100));
+        let now = Instant::now();
+        debouncer.push(OutputEvent::New(output(1, 640)), now);
+        debouncer.push(OutputEvent::Update(output(1, 800)), now);
+        debouncer.push(OutputEvent::Destroy(output(1, 800)), now);
+        assert!(debouncer.take().is_empty());
+    }
+
+    #[test]
+    fn recreated_output_is_replaced() {
+        let mut debouncer = OutputDebouncer::new(Duration::from_millis(100));
+        let now = Instant::now();
+        debouncer.push(OutputEvent::Update(output(1, 640)), now);
+        debouncer.push(OutputEvent::Destroy(output(1, 640)), now);
+        debouncer.push(OutputEvent::New(output(1, 1920)), now);
+        assert_eq!(
+            debouncer.take(),
+            [
+                OutputEvent::Destroy(output(1, 1920)),
+                OutputEvent::New(output(1, 1920))
+            ]
+        );
+    }
+
+    #[test]
+    fn steady_stream_is_applied_after_max_delay() {
+        let interval = Duration::from_millis(100);
+        let mut debouncer = OutputDebouncer::new(interval);
+        let start = Instant::now();
+        for i in 0..20 {
+            debouncer.push(
+                OutputEvent::Update(output(1, 640 + i)),
+                start + Duration::from_millis(50 * i as u64),
+            );
+        }
+        assert_eq!(
+            debouncer.deadline(),
+            Some(start + interval * MAX_DELAY_FACTOR)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Mapping of pointer positions into the global coordinate space, in which the
+/// outputs are laid out at their logical positions. wprsc only knows where the
+/// pointer is within a surface and which outputs the surface is on, so each
+/// surface is anchored at the logical position of its top-left output. A
+/// surface spanning two outputs then maps pointer positions past the output
+/// boundary onto the second output, and a surface on a single output maps them
+/// onto that output.
+use smithay::output::Output;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::Logical;
+use smithay::utils::Point;
+use smithay::utils::Rectangle;
+use smithay::wayland::compositor;
+
+use crate::server::LockedSurfaceState;
+use crate::server::WprsServerState;
+
+/// The area `output` covers in the global coordinate space, or None if it has
+/// no mode yet.
+pub(crate) fn logical_geometry(output: &Output) -> Option<Rectangle<i32, Logical>> {
+    let mode = output.current_mode()?;
+    let size = output
+        .current_transform()
+        .transform_size(mode.size)
+        .to_f64()
+        .to_logical(output.current_scale().fractional_scale())
+        .to_i32_round();
+    Some(Rectangle::new(output.current_location(), size))
+}
+
+/// Where a surface on the outputs with `geometries` is anchored: the position
+/// of the leftmost, then topmost, output. Surfaces on no known output are
+/// anchored at the origin.
+fn surface_origin<I>(geometries: I) -> Point<i32, Logical>
+where
+    I: IntoIterator<Item = Rectangle<i32, Logical>>,
+{
+    geometries
+        .into_iter()
+        .map(|geometry| geometry.loc)
+        .min_by_key(|loc| (loc.x, loc.y))
+        .unwrap_or_default()
+}
+
+impl WprsServerState {
+    /// The position of `surface` in the global coordinate space.
+    pub(crate) fn surface_origin(&self, surface: &WlSurface) -> Point<f64, Logical> {
+        let output_ids = compositor::with_states(surface, |surface_data| {
+            surface_data
+                .data_map
+                .get::<LockedSurfaceState>()
+                .map(|state| state.0.lock().unwrap().output_ids.clone())
+                .unwrap_or_default()
+        });
+        surface_origin(
+            output_ids
+                .iter()
+                .filter_map(|id| self.outputs.get(id))
+                .filter_map(|(output, _)| logical_geometry(output)),
+        )
+        .to_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay::output::Mode;
+    use smithay::output::PhysicalProperties;
+    use smithay::output::Scale;
+    use smithay::output::Subpixel;
+    use smithay::utils::Transform;
+
+    use super::*;
+
+    fn output(location: (i32, i32), size: (i32, i32), scale: i32, transform: Transform) -> Output {
+        let output = Output::new(
+            "test".to_string(),
+            PhysicalProperties {
+                size: (0, 0).into(),
+                subpixel: Subpixel::Unknown,
+                make: String::new(),
+                model: String::new(),
+            },
+        );
+        output.change_current_state(
+            Some(Mode {
+                size: size.into(),
+                refresh: 60_000,
+            }),
+            Some(transform),
+            Some(Scale::Integer(scale)),
+            Some(location.into()),
+        );
+        output
+    }
+
+    /// The output containing `point`, if any.
+    fn output_at<I>(outputs: I, point: Point<f64, Logical>) -> Option<u32>
+    where
+        I: IntoIterator<Item = (u32, Rectangle<i32, Logical>)>,
+    {
+        outputs
+            .into_iter()
+            .find(|(_, geometry)| geometry.to_f64().contains(point))
+            .map(|(id, _)| id)
+    }
+
+    /// A 1080p output on the left and a HiDPI 1440p output on its right.
+    fn side_by_side() -> Vec<(u32, Rectangle<i32, Logical>)> {
+        let left = output((0, 0), (1920, 1080), 1, Transform::Normal);
+        let right = output((1920, 0), (2560, 1440), 2, Transform::Normal);
+        vec![
+            (1, logical_geometry(&left).unwrap()),
+            (2, logical_geometry(&right).unwrap()),
+        ]
+    }
+
+    #[test]
+    fn geometry_is_logical() {
+        assert_eq!(
+            side_by_side()[1].1,
+            Rectangle::new((1920, 0).into(), (1280, 720).into())
+        );
+        let rotated = output((0, 0), (1920, 1080), 1, Transform::_90);
+        assert_eq!(
+            logical_geometry(&rotated),
+            Some(Rectangle::new((0, 0).into(), (1080, 1920).into()))
+        );
+    }
+
+    #[test]
+    fn pointer_crosses_output_boundary() {
+        let outputs = side_by_side();
+        // A window stretched across both outputs.
+        let origin = surface_origin(outputs.iter().map(|(_, geometry)| *geometry)).to_f64();
+        assert_eq!(origin, (0.0, 0.0).into());
+        let at = |x: f64, y: f64| output_at(outputs.clone(), origin + Point::from((x, y)));
+        assert_eq!(at(1919.5, 500.0), Some(1));
+        assert_eq!(at(1920.0, 500.0), Some(2));
+        assert_eq!(at(3199.0, 700.0), Some(2));
+        // Below the shorter right output.
+        assert_eq!(at(2000.0, 900.0), None);
+
+        // A window on the right output only.
+        let origin = surface_origin([outputs[1].1]).to_f64();
+        assert_eq!(origin, (1920.0, 0.0).into());
+        assert_eq!(
+            output_at(outputs.clone(), origin + Point::from((5.0, 5.0))),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn surface_without_outputs_is_at_origin() {
+        assert_eq!(surface_origin([]), Point::from((0, 0)));
+    }
+}
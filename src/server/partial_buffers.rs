@@ -0,0 +1,164 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Partial buffer updates. Mostly static surfaces like terminals and editors
+/// only damage small parts of their buffers in most commits, so when a buffer
+/// keeps the size and format of the previous one, only its damaged rects are
+/// sent and wprsc draws them onto its copy of the previous buffer. The first
+/// buffer of a surface, the first after a resize and buffers with much of
+/// their area damaged are sent whole.
+use crate::buffer_pointer::BufferPointer;
+use crate::serialization::geometry::Rectangle;
+
+const PIXEL_BYTES: usize = 4;
+
+/// Beyond this many damaged rects, their bounding box is sent instead.
+const MAX_RECTS: usize = 32;
+
+fn area(rect: &Rectangle<i32>) -> i64 {
+    i64::from(rect.size.w) * i64::from(rect.size.h)
+}
+
+fn intersection(a: &Rectangle<i32>, b: &Rectangle<i32>) -> Option<Rectangle<i32>> {
+    let x = a.loc.x.max(b.loc.x);
+    let y = a.loc.y.max(b.loc.y);
+    let right = a.loc.x.saturating_add(a.size.w).min(b.loc.x + b.size.w);
+    let bottom = a.loc.y.saturating_add(a.size.h).min(b.loc.y + b.size.h);
+    (x < right && y < bottom).then(|| Rectangle::new(x, y, right - x, bottom - y))
+}
+
+fn bounding_box(rects: &[Rectangle<i32>]) -> Option<Rectangle<i32>> {
+    let x = rects.iter().map(|rect| rect.loc.x).min()?;
+    let y = rects.iter().map(|rect| rect.loc.y).min()?;
+    let right = rects.iter().map(|rect| rect.loc.x + rect.size.w).max()?;
+    let bottom = rects.iter().map(|rect| rect.loc.y + rect.size.h).max()?;
+    Some(Rectangle::new(x, y, right - x, bottom - y))
+}
+
+/// The rects to send of a `width`x`height` buffer with `damage` (in buffer
+/// coordinates), or None if the whole buffer should be sent.
+pub fn partial_rects(
+    damage: &[Rectangle<i32>],
+    width: i32,
+    height: i32,
+) -> Option<Vec<Rectangle<i32>>> {
+    let buffer = Rectangle::new(0, 0, width, height);
+    let mut rects: Vec<Rectangle<i32>> = damage
+        .iter()
+        .filter_map(|rect| intersection(rect, &buffer))
+        .collect();
+    if rects.len() > MAX_RECTS {
+        rects = bounding_box(&rects).into_iter().collect();
+    }
+    // Overlapping rects are counted twice, which only errs towards sending
+    // the whole buffer.
+    let damaged_area: i64 = rects.iter().map(area).sum();
+    (2 * damaged_area < area(&buffer)).then_some(rects)
+}
+
+/// The pixels of `rects` of `data`, see PartialBuffer.
+///
+/// # Panics
+/// If a rect doesn't fit in `data`.
+pub fn pack(data: BufferPointer<u8>, stride: i32, rects: &[Rectangle<i32>]) -> Vec<u8> {
+    let mut packed = vec![0; rects.iter().map(area).sum::<i64>() as usize * PIXEL_BYTES];
+    let mut rest = packed.as_mut_slice();
+    for rect in rects {
+        let row_len = rect.size.w as usize * PIXEL_BYTES;
+        for y in rect.loc.y..(rect.loc.y + rect.size.h) {
+            let start = (y * stride) as usize + rect.loc.x as usize * PIXEL_BYTES;
+            let (row, tail) = rest.split_at_mut(row_len);
+            data.split_at(start)
+                .1
+                .split_at(row_len)
+                .0
+                .copy_to_nonoverlapping(row);
+            rest = tail;
+        }
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_damage_is_sent_partially() {
+        let damage = [Rectangle::new(0, 0, 10, 10), Rectangle::new(50, 50, 5, 5)];
+        assert_eq!(partial_rects(&damage, 100, 100), Some(damage.to_vec()));
+    }
+
+    #[test]
+    fn no_damage_sends_nothing() {
+        assert_eq!(partial_rects(&[], 100, 100), Some(Vec::new()));
+    }
+
+    #[test]
+    fn full_damage_sends_whole_buffer() {
+        assert_eq!(
+            partial_rects(&[Rectangle::new(0, 0, i32::MAX, i32::MAX)], 100, 100),
+            None
+        );
+        assert_eq!(
+            partial_rects(&[Rectangle::new(0, 0, 100, 50)], 100, 100),
+            None
+        );
+    }
+
+    #[test]
+    fn damage_is_clipped_to_buffer() {
+        assert_eq!(
+            partial_rects(
+                &[
+                    Rectangle::new(90, 90, 20, 20),
+                    Rectangle::new(-5, 0, 10, 10),
+                    Rectangle::new(200, 0, 10, 10)
+                ],
+                100,
+                100
+            ),
+            Some(vec![
+                Rectangle::new(90, 90, 10, 10),
+                Rectangle::new(0, 0, 5, 10)
+            ])
+        );
+    }
+
+    #[test]
+    fn many_rects_are_merged() {
+        let damage: Vec<_> = (0..40).map(|i| Rectangle::new(i, i, 1, 1)).collect();
+        assert_eq!(
+            partial_rects(&damage, 100, 100),
+            Some(vec![Rectangle::new(0, 0, 40, 40)])
+        );
+    }
+
+    #[test]
+    fn rects_are_packed_in_order() {
+        // A 4x2 buffer with a stride of 5 pixels, each pixel's bytes are its
+        // index.
+        let stride = 5 * 4;
+        let data: Vec<u8> = (0..10u8).flat_map(|i| [i; 4]).collect();
+        let ptr = data.as_ptr();
+        let packed = pack(
+            // SAFETY: ptr comes from data, which outlives the BufferPointer.
+            unsafe { BufferPointer::new(&ptr, data.len()) },
+            stride,
+            &[Rectangle::new(1, 0, 2, 2), Rectangle::new(3, 1, 1, 1)],
+        );
+        let pixels: Vec<u8> = packed.chunks_exact(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(pixels, [1, 2, 6, 7, 8]);
+    }
+}
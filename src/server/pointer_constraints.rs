@@ -0,0 +1,193 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Pointer locks and confinements (pointer-constraints-unstable-v1) and
+/// relative pointer motion (relative-pointer-unstable-v1), which games use to
+/// turn the mouse into a camera control. Constraints are forwarded to wprsc,
+/// which constrains the local pointer, and are only activated once the local
+/// compositor activated the local constraint. While the local pointer is
+/// constrained, wprsc forwards its relative motion. See
+/// client::pointer_constraints for the local side.
+///
+/// smithay doesn't tell when apps destroy their constraints, so forwarded
+/// constraints are checked on commits and relative motion, and released once
+/// they're gone.
+use std::collections::HashSet;
+
+use smithay::input::pointer::PointerHandle;
+use smithay::input::pointer::RelativeMotionEvent;
+use smithay::reexports::wayland_server::DisplayHandle;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::Logical;
+use smithay::utils::Point;
+use smithay::wayland::pointer_constraints::PointerConstraintsHandler;
+use smithay::wayland::pointer_constraints::PointerConstraintsState;
+use smithay::wayland::pointer_constraints::with_pointer_constraint;
+use smithay::wayland::relative_pointer::RelativePointerManagerState;
+
+use crate::compositor_utils;
+use crate::prelude::*;
+use crate::serialization;
+use crate::serialization::Request;
+use crate::serialization::SendType;
+use crate::serialization::wayland::PointerConstraintEvent;
+use crate::serialization::wayland::PointerConstraintRequest;
+use crate::serialization::wayland::RelativeMotion;
+use crate::serialization::wayland::WlSurfaceId;
+use crate::server::WprsServerState;
+
+#[derive(Debug)]
+pub struct PointerConstraintState {
+    _constraints: PointerConstraintsState,
+    _relative_pointer_manager: RelativePointerManagerState,
+    /// The surfaces whose constraint was forwarded to wprsc.
+    forwarded: HashSet<WlSurfaceId>,
+}
+
+impl PointerConstraintState {
+    pub fn new(dh: &DisplayHandle) -> Self {
+        Self {
+            _constraints: PointerConstraintsState::new::<WprsServerState>(dh),
+            _relative_pointer_manager: RelativePointerManagerState::new::<WprsServerState>(dh),
+            forwarded: HashSet::new(),
+        }
+    }
+
+    /// Forgets the forwarded constraints, e.g. for a new wprsc which doesn't
+    /// have them.
+    pub(crate) fn clear(&mut self) {
+        self.forwarded.clear();
+    }
+
+    pub(crate) fn remove(&mut self, surface_id: &WlSurfaceId) {
+        self.forwarded.remove(surface_id);
+    }
+}
+
+impl WprsServerState {
+    /// Forwards the constraint of `surface` if it wasn't yet, or releases the
+    /// forwarded one if the app destroyed it.
+    pub(crate) fn sync_pointer_constraint(&mut self, surface: &WlSurface) {
+        let Some(pointer) = self.seat.get_pointer() else {
+            return;
+        };
+        let Some(client) = surface.client() else {
+            return;
+        };
+        let client = serialization::ClientId::new(&client);
+        let surface_id = WlSurfaceId::new(surface);
+        let forwarded = &mut self.pointer_constraint_state.forwarded;
+        let request = match compositor_utils::pointer_constraint(surface, &pointer) {
+            Some((kind, region)) if forwarded.insert(surface_id) => {
+                debug!("forwarding {kind:?} of surface {surface_id:?}");
+                PointerConstraintRequest::Constrain {
+                    client,
+                    surface: surface_id,
+                    kind,
+                    region: region.as_ref().map(Into::into),
+                }
+            },
+            None if forwarded.remove(&surface_id) => {
+                debug!("releasing pointer constraint of surface {surface_id:?}");
+                PointerConstraintRequest::Release {
+                    client,
+                    surface: surface_id,
+                }
+            },
+            _ => return,
+        };
+        self.serializer
+            .writer()
+            .send(SendType::Object(Request::PointerConstraint(request)));
+    }
+
+    pub(crate) fn handle_pointer_constraint_event(
+        &mut self,
+        event: PointerConstraintEvent,
+    ) -> Result<()> {
+        let pointer = self.seat.get_pointer().location(loc!())?;
+        let (surface_id, activate) = match event {
+            PointerConstraintEvent::Activated(surface_id) => (surface_id, true),
+            PointerConstraintEvent::Deactivated(surface_id) => (surface_id, false),
+        };
+        let surface = self
+            .pointer_constraint_surface(&surface_id)
+            .location(loc!())?;
+        with_pointer_constraint(&surface, &pointer, |constraint| match constraint {
+            Some(constraint) if activate && !constraint.is_active() => constraint.activate(),
+            Some(constraint) if !activate && constraint.is_active() => constraint.deactivate(),
+            Some(_) => {},
+            None => debug!("ignoring {event:?}, the constraint was destroyed"),
+        });
+        self.sync_pointer_constraint(&surface);
+        Ok(())
+    }
+
+    pub(crate) fn handle_relative_motion(&mut self, motion: RelativeMotion) -> Result<()> {
+        let pointer = self.seat.get_pointer().location(loc!())?;
+        let surface = self
+            .pointer_constraint_surface(&motion.surface_id)
+            .location(loc!())?;
+        let origin = self.surface_origin(&surface);
+        pointer.relative_motion(
+            self,
+            Some((surface.clone(), origin)),
+            &RelativeMotionEvent {
+                delta: motion.delta.into(),
+                delta_unaccel: motion.delta_unaccel.into(),
+                utime: motion.utime,
+            },
+        );
+        pointer.frame(self);
+        self.sync_pointer_constraint(&surface);
+        Ok(())
+    }
+}
+
+impl PointerConstraintsHandler for WprsServerState {
+    fn new_constraint(&mut self, surface: &WlSurface, _pointer: &PointerHandle<Self>) {
+        self.sync_pointer_constraint(surface);
+    }
+
+    fn cursor_position_hint(
+        &mut self,
+        surface: &WlSurface,
+        pointer: &PointerHandle<Self>,
+        location: Point<f64, Logical>,
+    ) {
+        let Some((_, region)) = compositor_utils::pointer_constraint(surface, pointer) else {
+            return;
+        };
+        if !compositor_utils::position_hint_in_region(region.as_ref(), location) {
+            debug!("ignoring cursor position hint {location:?} outside of the locked region");
+            return;
+        }
+        let Some(client) = surface.client() else {
+            return;
+        };
+        self.serializer
+            .writer()
+            .send(SendType::Object(Request::PointerConstraint(
+                PointerConstraintRequest::CursorPositionHint {
+                    client: serialization::ClientId::new(&client),
+                    surface: WlSurfaceId::new(surface),
+                    position: location.into(),
+                },
+            )));
+    }
+}
+
+smithay::delegate_pointer_constraints!(WprsServerState);
+smithay::delegate_relative_pointer!(WprsServerState);
@@ -15,6 +15,7 @@
 /// Handlers for events from Smithay.
 use std::mem;
 use std::os::fd::OwnedFd;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crossbeam_channel::Sender;
@@ -67,7 +68,6 @@ use smithay::wayland::compositor::BufferAssignment as SmithayBufferAssignment;
 use smithay::wayland::compositor::CompositorClientState;
 use smithay::wayland::compositor::CompositorHandler;
 use smithay::wayland::compositor::CompositorState;
-use smithay::wayland::compositor::Damage;
 use smithay::wayland::compositor::SubsurfaceCachedState;
 use smithay::wayland::compositor::SurfaceAttributes;
 use smithay::wayland::compositor::SurfaceData;
@@ -96,13 +96,21 @@ use smithay::wayland::shell::xdg::decoration::XdgDecorationHandler;
 use smithay::wayland::shm::ShmHandler;
 use smithay::wayland::shm::ShmState;
 use smithay::wayland::viewporter::ViewportCachedState;
+use smithay::wayland::xdg_activation::XdgActivationHandler;
+use smithay::wayland::xdg_activation::XdgActivationState;
+use smithay::wayland::xdg_activation::XdgActivationToken;
+use smithay::wayland::xdg_activation::XdgActivationTokenData;
 
+use crate::buffer_pointer::BufferPointer;
 use crate::channel_utils::DiscardingSender;
 use crate::compositor_utils;
+use crate::filtering;
 use crate::prelude::*;
 use crate::serialization;
+use crate::serialization::geometry::Rectangle;
 use crate::serialization::tuple::Tuple2;
 use crate::serialization::wayland::BufferAssignment;
+use crate::serialization::wayland::BufferMetadata;
 use crate::serialization::wayland::ClientSurface;
 use crate::serialization::wayland::CursorImage;
 use crate::serialization::wayland::CursorImageStatus;
@@ -110,6 +118,7 @@ use crate::serialization::wayland::DataDestinationRequest;
 use crate::serialization::wayland::DataRequest;
 use crate::serialization::wayland::DataSource;
 use crate::serialization::wayland::DataSourceRequest;
+use crate::serialization::wayland::PartialBuffer;
 use crate::serialization::wayland::Role;
 use crate::serialization::wayland::SourceMetadata;
 use crate::serialization::wayland::SubSurfaceState;
@@ -134,6 +143,10 @@ use crate::serialization::Request;
 use crate::serialization::SendType;
 use crate::server::LockedSurfaceState;
 use crate::server::WprsServerState;
+use crate::server::buffer_tiles;
+use crate::server::commit_timing::CommitTimer;
+use crate::server::partial_buffers;
+use crate::server::viewport;
 
 impl BufferHandler for WprsServerState {
     #[instrument(skip(self), level = "debug")]
@@ -433,6 +446,15 @@ impl SelectionHandler for WprsServerState {
                         SourceMetadata::from_mime_types(source.mime_types()),
                     ),
                 ))));
+        } else {
+            self.serializer
+                .writer()
+                .send(SendType::Object(Request::Data(DataRequest::SourceRequest(
+                    DataSourceRequest::ClearSelection(match ty {
+                        SelectionTarget::Clipboard => DataSource::Selection,
+                        SelectionTarget::Primary => DataSource::Primary,
+                    }),
+                ))));
         }
     }
 
@@ -446,17 +468,10 @@ impl SelectionHandler for WprsServerState {
         _user_data: &Self::SelectionUserData,
     ) {
         let data_source = match ty {
-            SelectionTarget::Clipboard => {
-                self.selection_pipe = Some(fd);
-
-                DataSource::Selection
-            },
-            SelectionTarget::Primary => {
-                self.primary_selection_pipe = Some(fd);
-
-                DataSource::Primary
-            },
+            SelectionTarget::Clipboard => DataSource::Selection,
+            SelectionTarget::Primary => DataSource::Primary,
         };
+        self.data_pipes.set(data_source, fd);
 
         self.serializer
             .writer()
@@ -508,6 +523,7 @@ impl ClientDndGrabHandler for WprsServerState {
                     ))));
             })
             .log_and_ignore(loc!());
+            self.send_toplevel_drag_attachment(source);
         }
     }
 
@@ -546,7 +562,7 @@ impl ServerDndGrabHandler for WprsServerState {
 
     #[instrument(skip(self, _seat), level = "debug")]
     fn send(&mut self, mime_type: String, fd: OwnedFd, _seat: Seat<Self>) {
-        self.dnd_pipe = Some(fd);
+        self.data_pipes.set(DataSource::DnD, fd);
         self.serializer
             .writer()
             .send(SendType::Object(Request::Data(
@@ -578,10 +594,17 @@ impl CompositorHandler for WprsServerState {
 
     #[instrument(skip(self), level = "debug")]
     fn commit(&mut self, surface: &WlSurface) {
+        self.dispatch_input();
         // Send over the updated buffers from the children first so that the
         // client already has them when the parent is comitted.
         let children_dirty = commit_sync_children(self, surface, &commit).unwrap();
         commit(surface, self, children_dirty, false).log_and_ignore(loc!());
+        self.sync_pointer_constraint(surface);
+
+        let writer = self.serializer.writer();
+        for message in self.commit_batch.finish() {
+            writer.send(message);
+        }
     }
 }
 
@@ -730,6 +753,21 @@ pub fn set_xdg_toplevel_attributes(
     Ok(())
 }
 
+/// The part of the buffer inside the window geometry, which excludes
+/// client-side decoration shadows and the like, if the surface has one.
+fn visible_buffer_rect(
+    surface_state: &SurfaceState,
+) -> Option<serialization::geometry::Rectangle<i32>> {
+    let window_geometry = surface_state.xdg_surface_state.as_ref()?.window_geometry?;
+    let scale = surface_state.buffer_scale;
+    Some(serialization::geometry::Rectangle::new(
+        window_geometry.loc.x * scale,
+        window_geometry.loc.y * scale,
+        window_geometry.size.w * scale,
+        window_geometry.size.h * scale,
+    ))
+}
+
 #[allow(clippy::iter_with_drain)]
 #[instrument(skip(state), level = "debug")]
 pub fn commit_impl(
@@ -745,6 +783,7 @@ pub fn commit_impl(
     // two back-to-back commits in a better way.
     skip_buffer: bool,
 ) -> Result<bool> {
+    let mut timer = state.commit_timings.is_some().then(CommitTimer::start);
     let surface_state = &mut surface_data
         .data_map
         .get::<LockedSurfaceState>()
@@ -821,11 +860,41 @@ pub fn commit_impl(
 
     set_regions(surface_attributes, surface_state);
     set_transformation(surface_attributes, surface_state);
+    let buffer_size = match &surface_attributes.buffer {
+        Some(SmithayBufferAssignment::NewBuffer(buffer)) => compositor_utils::buffer_size(buffer),
+        Some(SmithayBufferAssignment::Removed) => None,
+        None => surface_state
+            .buffer
+            .as_ref()
+            .and_then(BufferAssignment::as_new)
+            .map(|buffer| (buffer.metadata.width, buffer.metadata.height).into()),
+    };
+    if let Some(buffer_size) = buffer_size {
+        surface_state.buffer_scale = compositor_utils::clamp_buffer_scale(
+            surface,
+            &surface_data.data_map,
+            buffer_size,
+            surface_state.buffer_scale,
+        );
+    }
     set_viewport_state(viewport_state, surface_state);
     set_xdg_surface_attributes(surface_data, surface_state);
 
     match &mut surface_state.role {
-        Some(Role::Cursor(_)) => {},
+        Some(Role::Cursor(hotspot)) => {
+            // Animated cursors move the hotspot along with their frames by
+            // offsetting the buffer, which smithay leaves to the compositor.
+            if let Some(buffer_delta) = surface_attributes.buffer_delta.take() {
+                let mut cursor_image_attributes = surface_data
+                    .data_map
+                    .get::<CursorImageSurfaceData>()
+                    .location(loc!())?
+                    .lock()
+                    .unwrap();
+                cursor_image_attributes.hotspot -= buffer_delta;
+                *hotspot = cursor_image_attributes.hotspot.into();
+            }
+        },
         Some(Role::SubSurface(subsurface_state)) => {
             subsurface_state.sync = sync;
             subsurface_state.location = surface_data
@@ -842,28 +911,110 @@ pub fn commit_impl(
         None => {},
     }
 
+    // Damage accumulates over commits which aren't sent, it's only cleared
+    // below.
+    let damage: Vec<Rectangle<i32>> = surface_attributes
+        .damage
+        .iter()
+        .map(|damage| {
+            compositor_utils::damage_to_buffer(
+                damage,
+                surface_state.buffer_scale,
+                surface_state
+                    .buffer_transform
+                    .unwrap_or(Transform::Normal)
+                    .into(),
+                buffer_size,
+            )
+        })
+        .collect();
+    // TODO: map surface damage through the viewport instead.
+    let viewported = surface_state
+        .viewport_state
+        .is_some_and(|viewport| viewport.src.is_some() || viewport.dst.is_some());
+
     // This needs to be a clone_without_buffer, the extra copy of the buffer
     // data arc will cause a deadlock otherwise.
     let mut surface_state_to_send = surface_state.clone_without_buffer();
 
+    let mut raw_buffer_to_send = None;
     // TODO: make a function and dedupe with compositor.rs.
     debug!("buffer assignment: {:?}", &surface_attributes.buffer);
     match &surface_attributes.buffer {
         Some(SmithayBufferAssignment::NewBuffer(buffer)) if !skip_buffer => {
-            compositor_utils::with_buffer_contents(buffer, |data, spec| {
-                surface_state.set_buffer(&spec, data, &mut state.compressor)
-            })
+            // wprsc doesn't have the whole previous buffer while its tiles are
+            // still being sent.
+            let partial_allowed = !viewported && !state.buffer_tiles.is_pending(surface);
+            // Any tiles still queued are for an older buffer.
+            state.buffer_tiles.cancel(surface);
+            let prev_metadata = surface_state
+                .buffer
+                .as_ref()
+                .and_then(BufferAssignment::as_new)
+                .map(|buffer| buffer.metadata);
+            let visible = visible_buffer_rect(surface_state);
+
+            let mut partial = None;
+            let tiled = compositor_utils::with_buffer_contents(
+                buffer,
+                |data, spec| -> Result<Option<BufferMetadata>> {
+                    surface_state
+                        .set_buffer(&spec, data, &mut state.compressor)
+                        .location(loc!())?;
+                    let metadata = BufferMetadata::from_buffer_data(&spec).location(loc!())?;
+                    if partial_allowed
+                        && prev_metadata == Some(metadata)
+                        && let Some(rects) =
+                            partial_buffers::partial_rects(&damage, metadata.width, metadata.height)
+                    {
+                        let pixels = partial_buffers::pack(data, metadata.stride, &rects);
+                        partial = Some((PartialBuffer { metadata, rects }, pixels));
+                        return Ok(None);
+                    }
+                    if !state
+                        .buffer_tiles
+                        .should_split(&metadata, prev_metadata.as_ref())
+                    {
+                        return Ok(None);
+                    }
+                    state.buffer_tiles.push(surface, metadata, data, visible);
+                    Ok(Some(metadata))
+                },
+            )
             .location(loc!())?
             .location(loc!())?;
+            if let Some(timer) = &mut timer {
+                timer.buffer_read();
+            }
 
-            let raw_buffer_to_send = surface_state_to_send
+            let mut raw_buffer = surface_state_to_send
                 .update_with_external_buffer(&surface_state.buffer)
                 .location(loc!())?;
-
-            state
-                .serializer
-                .writer()
-                .send(SendType::RawBuffer(raw_buffer_to_send));
+            if let Some(metadata) = tiled {
+                raw_buffer = Arc::new(buffer_tiles::placeholder(
+                    metadata.len(),
+                    &mut state.compressor,
+                ));
+                state.schedule_buffer_tiles();
+            }
+            match partial {
+                // Nothing was damaged, wprsc keeps the previous contents.
+                Some((partial, _)) if partial.rects.is_empty() => {
+                    surface_state_to_send.buffer = None;
+                },
+                Some((partial, pixels)) => {
+                    let ptr = pixels.as_ptr();
+                    // SAFETY: ptr comes from pixels, which outlives the
+                    // BufferPointer.
+                    let data = unsafe { BufferPointer::new(&ptr, pixels.len()) };
+                    raw_buffer_to_send = Some(Arc::new(filtering::filter_and_compress(
+                        data,
+                        &mut state.compressor,
+                    )));
+                    surface_state_to_send.buffer = Some(BufferAssignment::Partial(partial));
+                },
+                None => raw_buffer_to_send = Some(raw_buffer),
+            }
         },
         Some(SmithayBufferAssignment::Removed) => {
             surface_state.buffer = None;
@@ -879,33 +1030,25 @@ pub fn commit_impl(
         },
     }
 
-    let damage = mem::take(&mut surface_attributes.damage)
-        .iter()
-        .map(|damage| match damage {
-            Damage::Buffer(rect) => *rect,
-            Damage::Surface(rect) => rect.to_buffer(
-                surface_state.buffer_scale,
-                surface_state
-                    .buffer_transform
-                    .unwrap_or(Transform::Normal)
-                    .into(),
-                &rect.size,
-            ),
-        })
-        .map(Into::into)
-        .collect();
+    surface_attributes.damage.clear();
     surface_state_to_send.damage = Some(damage);
+    surface_state_to_send.viewport_state =
+        viewport::viewport_to_send(surface_data, surface_state_to_send.viewport_state);
+    let commit = SurfaceRequest::new(
+        surface,
+        SurfaceRequestPayload::Commit(surface_state_to_send),
+    )
+    .location(loc!())?;
+    if let Some(timer) = &mut timer {
+        timer.serialized();
+    }
 
-    state
-        .serializer
-        .writer()
-        .send(SendType::Object(Request::Surface(
-            SurfaceRequest::new(
-                surface,
-                SurfaceRequestPayload::Commit(surface_state_to_send),
-            )
-            .location(loc!())?,
-        )));
+    // Sent along with the rest of the surface tree by CompositorHandler::commit.
+    state.commit_batch.push(raw_buffer_to_send, commit);
+
+    if let (Some(timer), Some(commit_timings)) = (timer, &state.commit_timings) {
+        commit_timings.record(WlSurfaceId::new(surface), timer.sent());
+    }
     Ok(true)
 }
 
@@ -924,6 +1067,10 @@ impl SeatHandler for WprsServerState {
         &mut self.seat_state
     }
 
+    fn focus_changed(&mut self, _seat: &Seat<Self>, focused: Option<&WlSurface>) {
+        self.text_input_state.set_focus(focused);
+    }
+
     #[instrument(skip(self, _seat), level = "debug")]
     fn cursor_image(&mut self, _seat: &Seat<Self>, image: SmithayCursorImageStatus) {
         // TODO: move to a fn on serialization::CursorImaveStatus
@@ -1233,6 +1380,36 @@ impl ClientData for ClientState {
 
 impl OutputHandler for WprsServerState {}
 
+impl XdgActivationHandler for WprsServerState {
+    fn activation_state(&mut self) -> &mut XdgActivationState {
+        &mut self.xdg_activation_state
+    }
+
+    #[instrument(skip(self, _token_data), level = "debug")]
+    fn request_activation(
+        &mut self,
+        token: XdgActivationToken,
+        _token_data: XdgActivationTokenData,
+        surface: WlSurface,
+    ) {
+        // Tokens are handed to launched apps (as XDG_ACTIVATION_TOKEN or, for
+        // X11 apps, DESKTOP_STARTUP_ID) and used once their window maps.
+        self.xdg_activation_state.remove_token(&token);
+
+        let Some(toplevel) = self
+            .xdg_shell_state
+            .toplevel_surfaces()
+            .iter()
+            .find(|toplevel| toplevel.wl_surface() == &surface)
+            .cloned()
+        else {
+            debug!("ignoring activation of a surface which isn't a toplevel");
+            return;
+        };
+        self.send_toplevel_request(&toplevel, ToplevelRequestPayload::Activate);
+    }
+}
+
 smithay::delegate_compositor!(WprsServerState);
 smithay::delegate_xdg_shell!(WprsServerState);
 smithay::delegate_xdg_decoration!(WprsServerState);
@@ -1243,3 +1420,4 @@ smithay::delegate_data_device!(WprsServerState);
 smithay::delegate_output!(WprsServerState);
 smithay::delegate_primary_selection!(WprsServerState);
 smithay::delegate_viewporter!(WprsServerState);
+smithay::delegate_xdg_activation!(WprsServerState);
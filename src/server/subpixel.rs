@@ -0,0 +1,102 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Subpixel orders of our outputs. The order the local compositor reports is
+/// advertised by default, so that apps antialias text for the actual layout of
+/// the display; X11 apps see it through the outputs xwayland-xdg-shell
+/// mirrors, which Xwayland exposes as the RandR subpixel order. Some
+/// compositors report Unknown or a wrong order, so it can be overridden per
+/// output.
+use std::collections::BTreeMap;
+
+use crate::serialization::wayland::OutputInfo;
+use crate::serialization::wayland::Subpixel;
+
+#[derive(Debug, Clone, Default)]
+pub struct SubpixelOverrides {
+    /// Local output name -> subpixel order.
+    overrides: BTreeMap<String, Subpixel>,
+}
+
+impl SubpixelOverrides {
+    pub fn new(overrides: BTreeMap<String, Subpixel>) -> Self {
+        Self { overrides }
+    }
+
+    /// The subpixel order to advertise for `output`.
+    pub fn subpixel(&self, output: &OutputInfo) -> Subpixel {
+        output
+            .name
+            .as_ref()
+            .and_then(|name| self.overrides.get(name))
+            .copied()
+            .unwrap_or(output.subpixel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::wayland::Mode;
+    use crate::serialization::wayland::Transform;
+
+    fn output(name: Option<&str>, subpixel: Subpixel) -> OutputInfo {
+        OutputInfo {
+            id: 1,
+            model: "model".to_string(),
+            make: "make".to_string(),
+            location: (0, 0).into(),
+            physical_size: (600, 340).into(),
+            subpixel,
+            transform: Transform::Normal,
+            scale_factor: 1,
+            scale_120: 120,
+            mode: Mode {
+                dimensions: (1920, 1080).into(),
+                refresh_rate: 60000,
+                current: true,
+                preferred: true,
+            },
+            modes: Vec::new(),
+            name: name.map(str::to_string),
+            description: None,
+        }
+    }
+
+    #[test]
+    fn defaults_to_reported_subpixel() {
+        let overrides = SubpixelOverrides::default();
+        assert_eq!(
+            overrides.subpixel(&output(Some("DP-1"), Subpixel::HorizontalBgr)),
+            Subpixel::HorizontalBgr
+        );
+    }
+
+    #[test]
+    fn overrides_by_output_name() {
+        let overrides = SubpixelOverrides::new(ron::from_str("{\"DP-1\": HorizontalRgb}").unwrap());
+        assert_eq!(
+            overrides.subpixel(&output(Some("DP-1"), Subpixel::Unknown)),
+            Subpixel::HorizontalRgb
+        );
+        assert_eq!(
+            overrides.subpixel(&output(Some("DP-2"), Subpixel::Unknown)),
+            Subpixel::Unknown
+        );
+        assert_eq!(
+            overrides.subpixel(&output(None, Subpixel::VerticalRgb)),
+            Subpixel::VerticalRgb
+        );
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Support for text-input-v3, which apps use to receive text from an input
+/// method (e.g., preedit and commit strings while composing CJK text).
+///
+/// smithay's implementation needs an input method running in wprsd, while the
+/// input method runs locally, so text inputs are implemented here: the state
+/// of the focused app's text input is forwarded to wprsc on every commit, and
+/// the text which the local input method sends is delivered to the app's
+/// enabled text input. See client::text_input for the local side.
+use std::collections::HashMap;
+use std::mem;
+
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::server::zwp_text_input_manager_v3;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::server::zwp_text_input_manager_v3::ZwpTextInputManagerV3;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::server::zwp_text_input_v3;
+use smithay::reexports::wayland_protocols::wp::text_input::zv3::server::zwp_text_input_v3::ZwpTextInputV3;
+use smithay::reexports::wayland_server::Client;
+use smithay::reexports::wayland_server::DataInit;
+use smithay::reexports::wayland_server::Dispatch;
+use smithay::reexports::wayland_server::DisplayHandle;
+use smithay::reexports::wayland_server::GlobalDispatch;
+use smithay::reexports::wayland_server::New;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::backend;
+use smithay::reexports::wayland_server::backend::GlobalId;
+use smithay::reexports::wayland_server::backend::ObjectId;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+
+use crate::prelude::*;
+use crate::serialization;
+use crate::serialization::Request;
+use crate::serialization::SendType;
+use crate::serialization::geometry::Rectangle;
+use crate::serialization::wayland::SurroundingText;
+use crate::serialization::wayland::TextInputChange;
+use crate::serialization::wayland::TextInputState;
+use crate::serialization::wayland::WlSurfaceId;
+use crate::server::WprsServerState;
+
+/// State set since the last commit.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+struct PendingState {
+    enable: Option<bool>,
+    surrounding_text: Option<SurroundingText>,
+    content_type: Option<(u32, u32)>,
+    cursor_rectangle: Option<Rectangle<i32>>,
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+struct CurrentState {
+    enabled: bool,
+    surrounding_text: Option<SurroundingText>,
+    content_hint: u32,
+    content_purpose: u32,
+    cursor_rectangle: Option<Rectangle<i32>>,
+}
+
+impl CurrentState {
+    /// Applies `pending` as zwp_text_input_v3.commit does.
+    fn apply(&mut self, pending: PendingState) {
+        if let Some(enable) = pending.enable {
+            // Enabling resets the state to its initial values.
+            *self = Self {
+                enabled: enable,
+                ..Self::default()
+            };
+        }
+        if let Some(surrounding_text) = pending.surrounding_text {
+            self.surrounding_text = Some(surrounding_text);
+        }
+        if let Some((content_hint, content_purpose)) = pending.content_type {
+            self.content_hint = content_hint;
+            self.content_purpose = content_purpose;
+        }
+        if let Some(cursor_rectangle) = pending.cursor_rectangle {
+            self.cursor_rectangle = Some(cursor_rectangle);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Instance {
+    text_input: ZwpTextInputV3,
+    /// The number of commits, which done events must carry.
+    serial: u32,
+    pending: PendingState,
+    current: CurrentState,
+}
+
+#[derive(Debug)]
+pub struct TextInputManagerState {
+    _global: GlobalId,
+    instances: HashMap<ObjectId, Instance>,
+    focus: Option<WlSurface>,
+}
+
+impl TextInputManagerState {
+    pub fn new(dh: &DisplayHandle) -> Self {
+        Self {
+            _global: dh.create_global::<WprsServerState, ZwpTextInputManagerV3, _>(1, ()),
+            instances: HashMap::new(),
+            focus: None,
+        }
+    }
+
+    /// The text inputs of the client of `surface`.
+    fn instances_of<'a>(&'a self, surface: &'a WlSurface) -> impl Iterator<Item = &'a Instance> {
+        self.instances
+            .values()
+            .filter(move |instance| instance.text_input.id().same_client_as(&surface.id()))
+    }
+
+    /// Moves the text input focus along with the keyboard focus.
+    pub(crate) fn set_focus(&mut self, surface: Option<&WlSurface>) {
+        if self.focus.as_ref() == surface {
+            return;
+        }
+        if let Some(old_focus) = self.focus.take().filter(Resource::is_alive) {
+            for instance in self.instances_of(&old_focus) {
+                instance.text_input.leave(&old_focus);
+            }
+        }
+        if let Some(surface) = surface {
+            for instance in self.instances_of(surface) {
+                instance.text_input.enter(surface);
+            }
+        }
+        self.focus = surface.cloned();
+    }
+}
+
+impl WprsServerState {
+    /// Forwards the state `current` of `text_input` if its client has the
+    /// focus.
+    fn send_text_input_state(&self, text_input: &ZwpTextInputV3, current: CurrentState) {
+        let Some(focus) = &self.text_input_state.focus else {
+            return;
+        };
+        if !text_input.id().same_client_as(&focus.id()) {
+            return;
+        }
+        let Some(client) = focus.client() else {
+            return;
+        };
+        self.serializer
+            .writer()
+            .send(SendType::Object(Request::TextInput(TextInputState {
+                client: serialization::ClientId::new(&client),
+                surface: WlSurfaceId::new(focus),
+                enabled: current.enabled,
+                surrounding_text: current.surrounding_text,
+                content_hint: current.content_hint,
+                content_purpose: current.content_purpose,
+                cursor_rectangle: current.cursor_rectangle,
+            })));
+    }
+
+    /// Delivers text from the local input method to the focused app.
+    pub(crate) fn handle_text_input_change(&mut self, change: TextInputChange) -> Result<()> {
+        let text_input_state = &self.text_input_state;
+        let Some(focus) = &text_input_state.focus else {
+            debug!("dropping text input change without focus");
+            return Ok(());
+        };
+        let Some(instance) = text_input_state
+            .instances_of(focus)
+            .find(|instance| instance.current.enabled)
+        else {
+            debug!("dropping text input change without enabled text input");
+            return Ok(());
+        };
+
+        let text_input = &instance.text_input;
+        if let Some(preedit) = change.preedit {
+            text_input.preedit_string(Some(preedit.text), preedit.cursor_begin, preedit.cursor_end);
+        }
+        if let Some(delete) = change.delete_surrounding_text {
+            text_input.delete_surrounding_text(delete.before_length, delete.after_length);
+        }
+        if let Some(commit) = change.commit {
+            text_input.commit_string(Some(commit));
+        }
+        text_input.done(instance.serial);
+        Ok(())
+    }
+}
+
+impl GlobalDispatch<ZwpTextInputManagerV3, ()> for WprsServerState {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwpTextInputManagerV3>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwpTextInputManagerV3, ()> for WprsServerState {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _manager: &ZwpTextInputManagerV3,
+        request: zwp_text_input_manager_v3::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            zwp_text_input_manager_v3::Request::GetTextInput { id, seat: _ } => {
+                let text_input = data_init.init(id, ());
+                let text_input_state = &mut state.text_input_state;
+                if let Some(focus) = &text_input_state.focus
+                    && text_input.id().same_client_as(&focus.id())
+                {
+                    text_input.enter(focus);
+                }
+                text_input_state.instances.insert(
+                    text_input.id(),
+                    Instance {
+                        text_input,
+                        serial: 0,
+                        pending: PendingState::default(),
+                        current: CurrentState::default(),
+                    },
+                );
+            },
+            zwp_text_input_manager_v3::Request::Destroy => {},
+            _ => {},
+        }
+    }
+}
+
+impl Dispatch<ZwpTextInputV3, ()> for WprsServerState {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        text_input: &ZwpTextInputV3,
+        request: zwp_text_input_v3::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let Some(instance) = state.text_input_state.instances.get_mut(&text_input.id()) else {
+            warn!("request for unknown text input {:?}", text_input.id());
+            return;
+        };
+        let pending = &mut instance.pending;
+        match request {
+            zwp_text_input_v3::Request::Enable => pending.enable = Some(true),
+            zwp_text_input_v3::Request::Disable => pending.enable = Some(false),
+            zwp_text_input_v3::Request::SetSurroundingText {
+                text,
+                cursor,
+                anchor,
+            } => {
+                pending.surrounding_text = Some(SurroundingText {
+                    text,
+                    cursor,
+                    anchor,
+                });
+            },
+            zwp_text_input_v3::Request::SetContentType { hint, purpose } => {
+                pending.content_type = Some((
+                    hint.into_result().map_or(0, |hint| hint.bits()),
+                    purpose.into_result().map_or(0, u32::from),
+                ));
+            },
+            zwp_text_input_v3::Request::SetCursorRectangle {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                pending.cursor_rectangle = Some(Rectangle::new(x, y, width, height));
+            },
+            // Only input methods use the cause.
+            zwp_text_input_v3::Request::SetTextChangeCause { .. } => {},
+            zwp_text_input_v3::Request::Commit => {
+                instance.serial = instance.serial.wrapping_add(1);
+                let pending = mem::take(&mut instance.pending);
+                instance.current.apply(pending);
+                let current = instance.current.clone();
+                state.send_text_input_state(text_input, current);
+            },
+            zwp_text_input_v3::Request::Destroy => {},
+            _ => {},
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: backend::ClientId,
+        text_input: &ZwpTextInputV3,
+        _data: &(),
+    ) {
+        if let Some(instance) = state.text_input_state.instances.remove(&text_input.id())
+            && instance.current.enabled
+        {
+            // Let wprsc disable the local text input.
+            state.send_text_input_state(text_input, CurrentState::default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enable_resets_state() {
+        let mut current = CurrentState::default();
+        current.apply(PendingState {
+            enable: Some(true),
+            content_type: Some((1, 2)),
+            cursor_rectangle: Some(Rectangle::new(1, 2, 3, 4)),
+            ..PendingState::default()
+        });
+        assert_eq!(
+            current,
+            CurrentState {
+                enabled: true,
+                surrounding_text: None,
+                content_hint: 1,
+                content_purpose: 2,
+                cursor_rectangle: Some(Rectangle::new(1, 2, 3, 4)),
+            }
+        );
+
+        // State persists across commits until it's changed.
+        current.apply(PendingState {
+            surrounding_text: Some(SurroundingText {
+                text: "你".to_string(),
+                cursor: 3,
+                anchor: 3,
+            }),
+            ..PendingState::default()
+        });
+        assert_eq!(current.cursor_rectangle, Some(Rectangle::new(1, 2, 3, 4)));
+
+        current.apply(PendingState {
+            enable: Some(true),
+            ..PendingState::default()
+        });
+        assert_eq!(
+            current,
+            CurrentState {
+                enabled: true,
+                ..CurrentState::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Support for xdg-toplevel-drag-v1, which apps with detachable tabs use to
+/// move a window along with a drag: a toplevel created for the dragged tab is
+/// attached to the drag and follows the pointer until it's dropped.
+///
+/// wprsd doesn't position windows itself, so attachments are only recorded
+/// here and forwarded to wprsc once the drag has started. wprsc attaches the
+/// corresponding local toplevel to its local drag if the local compositor
+/// supports xdg-toplevel-drag-v1 and otherwise performs a normal drag, in
+/// which case the detached window is mapped wherever the local compositor
+/// places new windows.
+use std::collections::HashMap;
+
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::server::xdg_toplevel_drag_manager_v1;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::server::xdg_toplevel_drag_manager_v1::XdgToplevelDragManagerV1;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::server::xdg_toplevel_drag_v1;
+use smithay::reexports::wayland_protocols::xdg::toplevel_drag::v1::server::xdg_toplevel_drag_v1::XdgToplevelDragV1;
+use smithay::reexports::wayland_server::Client;
+use smithay::reexports::wayland_server::DataInit;
+use smithay::reexports::wayland_server::Dispatch;
+use smithay::reexports::wayland_server::DisplayHandle;
+use smithay::reexports::wayland_server::GlobalDispatch;
+use smithay::reexports::wayland_server::New;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::backend;
+use smithay::reexports::wayland_server::backend::GlobalId;
+use smithay::reexports::wayland_server::backend::ObjectId;
+use smithay::reexports::wayland_server::protocol::wl_data_source::WlDataSource;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+
+use crate::prelude::*;
+use crate::serialization;
+use crate::serialization::Request;
+use crate::serialization::SendType;
+use crate::serialization::geometry::Point;
+use crate::serialization::tuple::Tuple2;
+use crate::serialization::wayland::DataRequest;
+use crate::serialization::wayland::DataSourceRequest;
+use crate::serialization::wayland::WlSurfaceId;
+use crate::server::WprsServerState;
+
+#[derive(Debug)]
+pub struct ToplevelDragState {
+    _global: GlobalId,
+    /// Attached toplevels and their offsets from the cursor, keyed by the data
+    /// source of the drag they're attached to.
+    attachments: HashMap<ObjectId, (WlSurface, Point<i32>)>,
+}
+
+impl ToplevelDragState {
+    pub fn new(dh: &DisplayHandle) -> Self {
+        Self {
+            _global: dh.create_global::<WprsServerState, XdgToplevelDragManagerV1, _>(1, ()),
+            attachments: HashMap::new(),
+        }
+    }
+}
+
+impl WprsServerState {
+    /// Forwards the toplevel attached to the drag of `source`, if any.
+    pub(crate) fn send_toplevel_drag_attachment(&self, source: &WlDataSource) {
+        let Some((surface, offset)) = self.toplevel_drag_state.attachments.get(&source.id()) else {
+            return;
+        };
+        let Some(client) = surface.client() else {
+            return;
+        };
+        debug!("attaching {:?} to drag of {:?}", surface.id(), source.id());
+        self.serializer
+            .writer()
+            .send(SendType::Object(Request::Data(DataRequest::SourceRequest(
+                DataSourceRequest::AttachToplevel(
+                    Tuple2(
+                        serialization::ClientId::new(&client),
+                        WlSurfaceId::new(surface),
+                    ),
+                    *offset,
+                ),
+            ))));
+    }
+}
+
+impl GlobalDispatch<XdgToplevelDragManagerV1, ()> for WprsServerState {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<XdgToplevelDragManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<XdgToplevelDragManagerV1, ()> for WprsServerState {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        manager: &XdgToplevelDragManagerV1,
+        request: xdg_toplevel_drag_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            xdg_toplevel_drag_manager_v1::Request::GetXdgToplevelDrag { id, data_source } => {
+                if state.dnd_source.as_ref() == Some(&data_source) {
+                    manager.post_error(
+                        xdg_toplevel_drag_manager_v1::Error::InvalidSource,
+                        "data source was already used to start a drag",
+                    );
+                    return;
+                }
+                data_init.init(id, data_source);
+            },
+            xdg_toplevel_drag_manager_v1::Request::Destroy => {},
+            _ => {},
+        }
+    }
+}
+
+impl Dispatch<XdgToplevelDragV1, WlDataSource> for WprsServerState {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _drag: &XdgToplevelDragV1,
+        request: xdg_toplevel_drag_v1::Request,
+        source: &WlDataSource,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            xdg_toplevel_drag_v1::Request::Attach {
+                toplevel,
+                x_offset,
+                y_offset,
+            } => {
+                let Some(surface) = state
+                    .xdg_shell_state
+                    .toplevel_surfaces()
+                    .iter()
+                    .find(|toplevel_surface| toplevel_surface.xdg_toplevel() == &toplevel)
+                    .map(|toplevel_surface| toplevel_surface.wl_surface().clone())
+                else {
+                    warn!("attach of unknown toplevel {:?}", toplevel.id());
+                    return;
+                };
+                state
+                    .toplevel_drag_state
+                    .attachments
+                    .insert(source.id(), (surface, (x_offset, y_offset).into()));
+                // Otherwise, the attachment is sent once the drag starts.
+                if state.dnd_source.as_ref() == Some(source) {
+                    state.send_toplevel_drag_attachment(source);
+                }
+            },
+            xdg_toplevel_drag_v1::Request::Destroy => {},
+            _ => {},
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: backend::ClientId,
+        _drag: &XdgToplevelDragV1,
+        source: &WlDataSource,
+    ) {
+        state.toplevel_drag_state.attachments.remove(&source.id());
+    }
+}
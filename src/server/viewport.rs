@@ -0,0 +1,90 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Forwarding of wp_viewport state. Video players scale every decoded frame
+/// with a viewport which rarely changes, so commits only carry the viewport
+/// when it differs from the one last sent for the surface. On the wprsc
+/// side, the viewport of the remote surface persists until it's changed, so it
+/// still applies to every committed buffer.
+use std::sync::Mutex;
+
+use smithay::wayland::compositor::SurfaceData;
+
+use crate::serialization::wayland::ViewportState;
+
+/// The viewport state last sent for a surface, kept in its data map.
+#[derive(Debug, Default)]
+struct SentViewport(Option<ViewportState>);
+
+impl SentViewport {
+    /// `current` if it differs from the viewport sent last, which is then
+    /// updated to it.
+    fn update(&mut self, current: Option<ViewportState>) -> Option<ViewportState> {
+        if self.0 == current {
+            return None;
+        }
+        self.0 = current;
+        current
+    }
+}
+
+/// The viewport state to send in a commit of the surface with `surface_data`
+/// if its viewport is `current`, None if it's unchanged. Only call this for
+/// commits which are actually sent.
+pub fn viewport_to_send(
+    surface_data: &SurfaceData,
+    current: Option<ViewportState>,
+) -> Option<ViewportState> {
+    surface_data
+        .data_map
+        .insert_if_missing_threadsafe(|| Mutex::new(SentViewport::default()));
+    surface_data
+        .data_map
+        .get::<Mutex<SentViewport>>()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .update(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn viewport(w: i32, h: i32) -> Option<ViewportState> {
+        Some(ViewportState {
+            src: None,
+            dst: Some((w, h).into()),
+        })
+    }
+
+    #[test]
+    fn unchanged_viewport_is_not_resent() {
+        let mut sent = SentViewport::default();
+        assert_eq!(sent.update(viewport(1920, 1080)), viewport(1920, 1080));
+        // A second of 60fps video.
+        for _ in 0..60 {
+            assert_eq!(sent.update(viewport(1920, 1080)), None);
+        }
+    }
+
+    #[test]
+    fn changed_viewport_is_resent() {
+        let mut sent = SentViewport::default();
+        assert_eq!(sent.update(viewport(1920, 1080)), viewport(1920, 1080));
+        assert_eq!(sent.update(viewport(1280, 720)), viewport(1280, 720));
+        assert_eq!(sent.update(viewport(1280, 720)), None);
+        assert_eq!(sent.update(viewport(1920, 1080)), viewport(1920, 1080));
+    }
+}
@@ -44,6 +44,65 @@ use crate::serialization::framing::Framed;
 // TODO: benchmark this and pick a value based on that.
 pub const MIN_SIZE_TO_COMPRESS: usize = 4096;
 
+/// How the shards are compressed. Each shard records whether it was
+/// compressed, so the decompressing side handles either without having to be
+/// told.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+)]
+pub enum Compression {
+    /// Send the data as is. Saves the CPU time spent compressing when the data
+    /// doesn't leave the machine.
+    None,
+    /// zstd with the given compression level.
+    Zstd { level: i32 },
+}
+
+/// The buffer compression which wprsd or wprsc asks for, see negotiate.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+)]
+pub enum CompressionChoice {
+    /// Leave the choice to the other side.
+    Auto,
+    Fixed(Compression),
+}
+
+/// The compression of the buffers wprsd sends to a newly connected wprsc:
+/// wprsd's fixed choice if it has one, else wprsc's. If neither has one, None
+/// when wprsc is on the same machine, where compressing only costs CPU time,
+/// and zstd level 1 otherwise.
+pub fn negotiate(
+    server: CompressionChoice,
+    client: CompressionChoice,
+    client_is_local: bool,
+) -> Compression {
+    match (server, client) {
+        (CompressionChoice::Fixed(compression), _)
+        | (CompressionChoice::Auto, CompressionChoice::Fixed(compression)) => compression,
+        (CompressionChoice::Auto, CompressionChoice::Auto) if client_is_local => Compression::None,
+        (CompressionChoice::Auto, CompressionChoice::Auto) => Compression::Zstd { level: 1 },
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Archive, Deserialize, Serialize)]
 pub struct CompressedShard {
     pub idx: usize,
@@ -221,12 +280,18 @@ impl Framed for CompressedShards {
 }
 
 fn spawn_compressor(
-    compression_level: i32,
+    compression: Compression,
     input_rx: Receiver<(usize, Box<dyn AsRef<[u8]> + Send + Sync + 'static>)>,
     output_tx: Sender<CompressedShard>,
 ) -> Result<()> {
-    let mut compressor = Compressor::new(compression_level).location(loc!())?;
-    compressor.long_distance_matching(true).location(loc!())?;
+    let mut compressor = match compression {
+        Compression::None => None,
+        Compression::Zstd { level } => {
+            let mut compressor = Compressor::new(level).location(loc!())?;
+            compressor.long_distance_matching(true).location(loc!())?;
+            Some(compressor)
+        },
+    };
     thread::spawn(move || {
         // The iterator (and, consequently, the thread) will terminate when all
         // the input senders (which are all in the ShardingCompressor) are
@@ -239,11 +304,11 @@ fn spawn_compressor(
             //
             // This will allocate as much space as it needs, so compression
             // should never panic.
-            let compression = input.len() > MIN_SIZE_TO_COMPRESS;
-            let data = if compression {
-                compressor.compress(input).unwrap()
-            } else {
-                input.to_vec()
+            let (compression, data) = match &mut compressor {
+                Some(compressor) if input.len() > MIN_SIZE_TO_COMPRESS => {
+                    (true, compressor.compress(input).unwrap())
+                },
+                _ => (false, input.to_vec()),
             };
 
             // This will be an error when the ShardingDecompressor is dropped,
@@ -261,19 +326,29 @@ fn spawn_compressor(
 }
 
 pub struct ShardingCompressor {
+    compression: Compression,
     compressor_input: Sender<(usize, Box<dyn AsRef<[u8]> + Send + Sync + 'static>)>,
     compressor_output: Receiver<CompressedShard>,
 }
 
 impl ShardingCompressor {
     pub fn new(n_compressors: NonZeroUsize, compression_level: i32) -> Result<Self> {
+        Self::with_compression(
+            n_compressors,
+            Compression::Zstd {
+                level: compression_level,
+            },
+        )
+    }
+
+    pub fn with_compression(n_compressors: NonZeroUsize, compression: Compression) -> Result<Self> {
         // These channels will have at most n_shards items in them, but we only
         // know n_shards when compress is called, not now.
         let (compressor_input_tx, compressor_input_rx) = crossbeam_channel::unbounded();
         let (compressor_output_tx, compressor_output_rx) = crossbeam_channel::unbounded();
         for _ in 0..n_compressors.get() {
             spawn_compressor(
-                compression_level,
+                compression,
                 compressor_input_rx.clone(),
                 compressor_output_tx.clone(),
             )
@@ -281,11 +356,16 @@ impl ShardingCompressor {
         }
 
         Ok(Self {
+            compression,
             compressor_input: compressor_input_tx,
             compressor_output: compressor_output_rx,
         })
     }
 
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
     #[instrument(skip_all, level = "debug")]
     pub fn compress(&mut self, n_shards: NonZeroUsize, data: ArcSlice<u8>) -> CompressedShards {
         let n_shards = n_shards.get();
@@ -539,3 +619,76 @@ impl ShardingDecompressor {
         Ok(vec)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: Compression) -> CompressedShards {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i / 256) as u8).collect();
+        let mut compressor =
+            ShardingCompressor::with_compression(NonZeroUsize::new(2).unwrap(), compression)
+                .unwrap();
+        let shards =
+            compressor.compress(NonZeroUsize::new(4).unwrap(), ArcSlice::new(data.clone()));
+
+        let mut decompressor = ShardingDecompressor::new(NonZeroUsize::new(2).unwrap()).unwrap();
+        let decompressed = decompressor
+            .decompress_to_owned(
+                &shards.indices(),
+                shards.uncompressed_size(),
+                shards
+                    .shards
+                    .clone()
+                    .into_iter()
+                    .map(Ok::<_, anyhow::Error>)
+                    .transpose_into_fallible(),
+            )
+            .unwrap();
+        assert_eq!(decompressed, data);
+        shards
+    }
+
+    #[test]
+    fn zstd_shards_are_compressed() {
+        let shards = round_trip(Compression::Zstd { level: 1 });
+        assert!(shards.shards.iter().all(|shard| shard.compression));
+        assert!(shards.size() < shards.uncompressed_size());
+    }
+
+    #[test]
+    fn uncompressed_shards_round_trip() {
+        let shards = round_trip(Compression::None);
+        assert!(shards.shards.iter().all(|shard| !shard.compression));
+        assert_eq!(shards.size(), shards.uncompressed_size());
+    }
+
+    #[test]
+    fn negotiate_prefers_server_then_client_then_locality() {
+        let zstd = Compression::Zstd { level: 3 };
+        assert_eq!(
+            negotiate(
+                CompressionChoice::Fixed(zstd),
+                CompressionChoice::Fixed(Compression::None),
+                true
+            ),
+            zstd
+        );
+        assert_eq!(
+            negotiate(
+                CompressionChoice::Auto,
+                CompressionChoice::Fixed(zstd),
+                true
+            ),
+            zstd
+        );
+        assert_eq!(
+            negotiate(CompressionChoice::Auto, CompressionChoice::Auto, true),
+            Compression::None
+        );
+        assert_eq!(
+            negotiate(CompressionChoice::Auto, CompressionChoice::Auto, false),
+            Compression::Zstd { level: 1 }
+        );
+    }
+}
@@ -18,6 +18,7 @@ use std::arch::x86_64::_mm_storeu_si128;
 use std::arch::x86_64::_mm256_storeu_si256;
 use std::backtrace::Backtrace;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fs;
 use std::fs::File;
 use std::io;
@@ -91,41 +92,62 @@ pub fn join_unwrap<T>(handle: ScopedJoinHandle<T>) -> T {
     }
 }
 
+/// Maps the serials we send to those of the events they were sent for. Only
+/// the most recent `capacity` serials are kept, as serials are only looked up
+/// shortly after they're sent, e.g. for the grab of a popup opened on a click.
 #[derive(Debug)]
 pub struct SerialMap {
     map: HashMap<u32, u32>,
-    last_serial: u32,
+    /// Server serials in the order they were inserted, which may include
+    /// serials that were already removed from map.
+    order: VecDeque<u32>,
+    capacity: usize,
 }
 
 impl SerialMap {
+    pub const DEFAULT_CAPACITY: usize = 1000;
+
     pub fn new() -> Self {
-        Self {
-            map: HashMap::with_capacity(2000),
-            last_serial: 0,
-        }
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
     }
 
-    const PRUNE_THRESHOLD: usize = 2000;
-    const PRUNE_AGE: u32 = 1000;
-
-    #[instrument(skip(self), level = "debug")]
-    fn prune(&mut self) {
-        if self.map.len() > Self::PRUNE_THRESHOLD {
-            self.map
-                .retain(|&k, _| k > self.last_serial.saturating_sub(Self::PRUNE_AGE));
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "SerialMap capacity must be positive");
+        Self {
+            map: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
         }
     }
 
     pub fn insert(&mut self, client_serial: u32) -> Serial {
-        self.last_serial = SERIAL_COUNTER.next_serial().into();
-        _ = self.map.insert(self.last_serial, client_serial).is_none();
-        self.prune();
-        self.last_serial.into()
+        let server_serial = SERIAL_COUNTER.next_serial();
+        self.insert_serial(server_serial.into(), client_serial);
+        server_serial
     }
 
+    fn insert_serial(&mut self, server_serial: u32, client_serial: u32) {
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.map.remove(&oldest);
+        }
+        self.order.push_back(server_serial);
+        self.map.insert(server_serial, client_serial);
+    }
+
+    /// The client serial `server_serial` was sent for, None if it was evicted.
     pub fn remove(&mut self, server_serial: Serial) -> Option<u32> {
         self.map.remove(&server_serial.into())
     }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
 }
 
 impl Default for SerialMap {
@@ -256,3 +278,40 @@ pub struct AssertN3<const N1: usize, const N2: usize, const N3: usize>;
 impl<const N1: usize, const N2: usize, const N3: usize> AssertN3<N1, N2, N3> {
     pub const N1_X_N2_EQ_N3: () = assert!(N1.checked_mul(N2).unwrap() == N3);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_map_evicts_oldest_serials() {
+        let mut serial_map = SerialMap::with_capacity(100);
+        let serials: Vec<(Serial, u32)> = (0..1000)
+            .map(|client_serial| (serial_map.insert(client_serial), client_serial))
+            .collect();
+        assert_eq!(serial_map.len(), 100);
+
+        // Old serials are gone.
+        for (server_serial, _) in &serials[..900] {
+            assert_eq!(serial_map.remove(*server_serial), None);
+        }
+        // Recent ones still resolve.
+        for (server_serial, client_serial) in &serials[900..] {
+            assert_eq!(serial_map.remove(*server_serial), Some(*client_serial));
+        }
+        assert!(serial_map.is_empty());
+    }
+
+    #[test]
+    fn removed_serials_still_count_towards_capacity() {
+        let mut serial_map = SerialMap::with_capacity(2);
+        serial_map.insert_serial(1, 10);
+        serial_map.insert_serial(2, 20);
+        assert_eq!(serial_map.remove(1.into()), Some(10));
+        serial_map.insert_serial(3, 30);
+        serial_map.insert_serial(4, 40);
+        assert_eq!(serial_map.remove(2.into()), None);
+        assert_eq!(serial_map.remove(3.into()), Some(30));
+        assert_eq!(serial_map.remove(4.into()), Some(40));
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// _NET_WM_ALLOWED_ACTIONS, which the WM sets to tell apps and pagers which
+/// actions a window supports, e.g. whether to offer maximizing it. They're
+/// derived from the window's hints: fixed-size windows (WM_NORMAL_HINTS with
+/// equal minimum and maximum sizes) can't be resized, maximized or made
+/// fullscreen, only normal windows can be minimized, and menus, tooltips and
+/// the like allow no actions at all. The property is updated whenever the
+/// hints change. Override-redirect windows aren't managed, so they don't get
+/// it.
+use smithay::utils::Logical;
+use smithay::utils::Size;
+use smithay::xwayland::X11Surface;
+use smithay::xwayland::xwm::WmWindowType;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::PropMode;
+use x11rb::wrapper::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+x11rb::atom_manager! {
+    pub Atoms: AtomsCookie {
+        _NET_SUPPORTED,
+        _NET_WM_ALLOWED_ACTIONS,
+        _NET_WM_ACTION_MOVE,
+        _NET_WM_ACTION_RESIZE,
+        _NET_WM_ACTION_MINIMIZE,
+        _NET_WM_ACTION_MAXIMIZE_HORZ,
+        _NET_WM_ACTION_MAXIMIZE_VERT,
+        _NET_WM_ACTION_FULLSCREEN,
+        _NET_WM_ACTION_CLOSE,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Action {
+    Move,
+    Resize,
+    Minimize,
+    MaximizeHorz,
+    MaximizeVert,
+    Fullscreen,
+    Close,
+}
+
+impl Action {
+    fn atom(self, atoms: &Atoms) -> Atom {
+        match self {
+            Self::Move => atoms._NET_WM_ACTION_MOVE,
+            Self::Resize => atoms._NET_WM_ACTION_RESIZE,
+            Self::Minimize => atoms._NET_WM_ACTION_MINIMIZE,
+            Self::MaximizeHorz => atoms._NET_WM_ACTION_MAXIMIZE_HORZ,
+            Self::MaximizeVert => atoms._NET_WM_ACTION_MAXIMIZE_VERT,
+            Self::Fullscreen => atoms._NET_WM_ACTION_FULLSCREEN,
+            Self::Close => atoms._NET_WM_ACTION_CLOSE,
+        }
+    }
+}
+
+fn is_fixed_size(min: Option<Size<i32, Logical>>, max: Option<Size<i32, Logical>>) -> bool {
+    matches!((min, max), (Some(min), Some(max)) if min == max && min.w > 0 && min.h > 0)
+}
+
+/// The actions allowed on a window of `window_type`.
+fn allowed_actions(fixed_size: bool, window_type: Option<WmWindowType>) -> Vec<Action> {
+    let (minimize, maximize, fullscreen) = match window_type {
+        None | Some(WmWindowType::Normal) => (true, true, true),
+        Some(WmWindowType::Dialog) => (false, true, false),
+        Some(WmWindowType::Utility | WmWindowType::Toolbar) => (false, false, false),
+        Some(WmWindowType::Splash) => return vec![Action::Close],
+        Some(
+            WmWindowType::DropdownMenu
+            | WmWindowType::Menu
+            | WmWindowType::Notification
+            | WmWindowType::PopupMenu
+            | WmWindowType::Tooltip,
+        ) => return Vec::new(),
+    };
+
+    let mut actions = vec![Action::Move];
+    if !fixed_size {
+        actions.push(Action::Resize);
+    }
+    if minimize {
+        actions.push(Action::Minimize);
+    }
+    if maximize && !fixed_size {
+        actions.extend([Action::MaximizeHorz, Action::MaximizeVert]);
+    }
+    if fullscreen && !fixed_size {
+        actions.push(Action::Fullscreen);
+    }
+    actions.push(Action::Close);
+    actions
+}
+
+/// Sets _NET_WM_ALLOWED_ACTIONS on the shared X11 connection, see
+/// x11_connection.
+#[derive(Debug)]
+pub(crate) struct AllowedActionsWriter {
+    atoms: Atoms,
+}
+
+impl AllowedActionsWriter {
+    /// Adds _NET_WM_ALLOWED_ACTIONS to the _NET_SUPPORTED set by the X11 WM,
+    /// so it must be started after that.
+    pub(crate) fn start(conn: &X11Connection) -> Result<Self> {
+        let atoms = Atoms::new(&**conn)
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        conn.change_property32(
+            PropMode::APPEND,
+            conn.screen().root,
+            atoms._NET_SUPPORTED,
+            AtomEnum::ATOM,
+            &[atoms._NET_WM_ALLOWED_ACTIONS],
+        )
+        .location(loc!())?
+        .check()
+        .location(loc!())?;
+        Ok(Self { atoms })
+    }
+
+    fn set(&self, conn: &X11Connection, window: u32, actions: &[Action]) -> Result<()> {
+        let atoms: Vec<Atom> = actions
+            .iter()
+            .map(|action| action.atom(&self.atoms))
+            .collect();
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            self.atoms._NET_WM_ALLOWED_ACTIONS,
+            AtomEnum::ATOM,
+            &atoms,
+        )
+        .location(loc!())?
+        .check()
+        .location(loc!())
+    }
+}
+
+impl WprsState {
+    /// Sets the _NET_WM_ALLOWED_ACTIONS of `window` from its current hints.
+    pub(crate) fn update_allowed_actions(&self, window: &X11Surface) {
+        if window.is_override_redirect() {
+            return;
+        }
+        let (Some(writer), Some(conn)) = (
+            &self.compositor_state.allowed_actions_writer,
+            &self.compositor_state.x11_conn,
+        ) else {
+            return;
+        };
+        let actions = allowed_actions(
+            is_fixed_size(window.min_size(), window.max_size()),
+            window.window_type(),
+        );
+        debug!("allowed actions of {}: {actions:?}", window.window_id());
+        // The window may already have been destroyed.
+        writer
+            .set(conn, window.window_id(), &actions)
+            .warn(loc!())
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_size_windows_cannot_be_resized() {
+        assert!(is_fixed_size(
+            Some((300, 200).into()),
+            Some((300, 200).into())
+        ));
+        assert!(!is_fixed_size(
+            Some((300, 200).into()),
+            Some((600, 400).into())
+        ));
+        assert!(!is_fixed_size(Some((300, 200).into()), None));
+        assert!(!is_fixed_size(Some((0, 0).into()), Some((0, 0).into())));
+
+        assert_eq!(
+            allowed_actions(true, None),
+            vec![Action::Move, Action::Minimize, Action::Close]
+        );
+    }
+
+    #[test]
+    fn normal_windows_allow_everything() {
+        assert_eq!(
+            allowed_actions(false, Some(WmWindowType::Normal)),
+            vec![
+                Action::Move,
+                Action::Resize,
+                Action::Minimize,
+                Action::MaximizeHorz,
+                Action::MaximizeVert,
+                Action::Fullscreen,
+                Action::Close
+            ]
+        );
+    }
+
+    #[test]
+    fn actions_depend_on_window_type() {
+        assert_eq!(
+            allowed_actions(false, Some(WmWindowType::Dialog)),
+            vec![
+                Action::Move,
+                Action::Resize,
+                Action::MaximizeHorz,
+                Action::MaximizeVert,
+                Action::Close
+            ]
+        );
+        assert_eq!(
+            allowed_actions(false, Some(WmWindowType::Utility)),
+            vec![Action::Move, Action::Resize, Action::Close]
+        );
+        assert_eq!(
+            allowed_actions(false, Some(WmWindowType::Splash)),
+            vec![Action::Close]
+        );
+        assert!(allowed_actions(false, Some(WmWindowType::Tooltip)).is_empty());
+    }
+}
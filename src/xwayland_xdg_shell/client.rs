@@ -12,22 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::num::NonZeroU32;
+use std::os::fd::AsFd;
 use std::sync::Arc;
 
 use enum_as_inner::EnumAsInner;
-use smithay::backend::input::Axis;
-use smithay::backend::input::AxisSource;
 use smithay::backend::input::ButtonState;
 use smithay::input::keyboard::Layout;
 use smithay::input::keyboard::XkbContext;
-use smithay::input::pointer::AxisFrame;
 use smithay::input::pointer::ButtonEvent;
 use smithay::input::pointer::MotionEvent;
 use smithay::input::pointer::PointerTarget;
 use smithay::reexports::wayland_protocols::wp::primary_selection::zv1::client::zwp_primary_selection_device_v1::ZwpPrimarySelectionDeviceV1;
 use smithay::reexports::wayland_protocols::wp::primary_selection::zv1::client::zwp_primary_selection_source_v1::ZwpPrimarySelectionSourceV1;
+use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewporter::WpViewporter;
 use smithay::reexports::wayland_server::backend::ObjectId;
 use smithay::reexports::wayland_server::Resource;
 use smithay::utils::Rectangle;
@@ -39,6 +38,9 @@ use smithay::wayland::selection::SelectionTarget;
 use smithay::wayland::selection::primary_selection;
 use smithay::wayland::shm::BufferData;
 use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::activation::ActivationHandler;
+use smithay_client_toolkit::activation::ActivationState;
+use smithay_client_toolkit::activation::RequestData;
 use smithay_client_toolkit::compositor::CompositorHandler;
 use smithay_client_toolkit::compositor::CompositorState;
 use smithay_client_toolkit::compositor::Surface;
@@ -49,6 +51,7 @@ use smithay_client_toolkit::data_device_manager::data_offer::SelectionOffer;
 use smithay_client_toolkit::data_device_manager::data_source::CopyPasteSource;
 use smithay_client_toolkit::data_device_manager::data_source::DataSourceHandler;
 use smithay_client_toolkit::data_device_manager::DataDeviceManagerState;
+use smithay_client_toolkit::data_device_manager::ReadPipe;
 use smithay_client_toolkit::data_device_manager::WritePipe;
 use smithay_client_toolkit::output::OutputHandler;
 use smithay_client_toolkit::output::OutputState;
@@ -57,6 +60,7 @@ use smithay_client_toolkit::primary_selection::PrimarySelectionManagerState;
 use smithay_client_toolkit::primary_selection::device::PrimarySelectionDeviceHandler;
 use smithay_client_toolkit::primary_selection::offer::PrimarySelectionOffer;
 use smithay_client_toolkit::primary_selection::selection::PrimarySelectionSourceHandler;
+use smithay_client_toolkit::reexports::client::backend::ObjectId as SctkObjectId;
 use smithay_client_toolkit::reexports::client::globals::GlobalList;
 use smithay_client_toolkit::reexports::client::protocol::wl_data_device::WlDataDevice;
 use smithay_client_toolkit::reexports::client::protocol::wl_data_device_manager::DndAction;
@@ -64,7 +68,6 @@ use smithay_client_toolkit::reexports::client::protocol::wl_data_source::WlDataS
 use smithay_client_toolkit::reexports::client::protocol::wl_keyboard::WlKeyboard;
 use smithay_client_toolkit::reexports::client::protocol::wl_output::Transform;
 use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
-use smithay_client_toolkit::reexports::client::protocol::wl_pointer::AxisSource as WlPointerAxisSource;
 use smithay_client_toolkit::reexports::client::protocol::wl_pointer::WlPointer;
 use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
 use smithay_client_toolkit::reexports::client::protocol::wl_subcompositor::Event as WlSubcompositorEvent;
@@ -79,11 +82,16 @@ use smithay_client_toolkit::reexports::client::QueueHandle;
 use smithay_client_toolkit::reexports::csd_frame::CursorIcon;
 use smithay_client_toolkit::reexports::csd_frame::DecorationsFrame;
 use smithay_client_toolkit::reexports::csd_frame::WindowManagerCapabilities;
+use smithay_client_toolkit::reexports::protocols::ext::idle_notify::v1::client::ext_idle_notification_v1::ExtIdleNotificationV1;
+use smithay_client_toolkit::reexports::protocols::ext::idle_notify::v1::client::ext_idle_notifier_v1::ExtIdleNotifierV1;
+use smithay_client_toolkit::reexports::protocols::wp::alpha_modifier::v1::client::wp_alpha_modifier_v1::WpAlphaModifierV1;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_manager_v2::ZwpTabletManagerV2;
 use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_positioner::Anchor;
 use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_positioner::Gravity;
 use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_surface::XdgSurface as SctkXdgSurface;
 use smithay_client_toolkit::registry::ProvidesRegistryState;
 use smithay_client_toolkit::registry::RegistryState;
+use smithay_client_toolkit::registry::SimpleGlobal;
 use smithay_client_toolkit::registry_handlers;
 use smithay_client_toolkit::seat::keyboard::KeyEvent;
 use smithay_client_toolkit::seat::keyboard::KeyboardHandler;
@@ -95,11 +103,13 @@ use smithay_client_toolkit::seat::keyboard::RepeatInfo;
 use smithay_client_toolkit::seat::pointer::PointerEvent;
 use smithay_client_toolkit::seat::pointer::PointerEventKind;
 use smithay_client_toolkit::seat::pointer::PointerHandler;
-use smithay_client_toolkit::seat::pointer::ThemeSpec;
 use smithay_client_toolkit::seat::pointer::ThemedPointer;
+use smithay_client_toolkit::seat::pointer_constraints::PointerConstraintsState;
+use smithay_client_toolkit::seat::relative_pointer::RelativePointerState;
 use smithay_client_toolkit::seat::Capability;
 use smithay_client_toolkit::seat::SeatHandler;
 use smithay_client_toolkit::seat::SeatState;
+use smithay_client_toolkit::shell::wlr_layer::LayerShell;
 use smithay_client_toolkit::shell::xdg::fallback_frame::FallbackFrame;
 use smithay_client_toolkit::shell::xdg::popup::Popup;
 use smithay_client_toolkit::shell::xdg::popup::PopupConfigure;
@@ -107,7 +117,6 @@ use smithay_client_toolkit::shell::xdg::popup::PopupHandler;
 use smithay_client_toolkit::shell::xdg::window::DecorationMode;
 use smithay_client_toolkit::shell::xdg::window::Window;
 use smithay_client_toolkit::shell::xdg::window::WindowConfigure;
-use smithay_client_toolkit::shell::xdg::window::WindowDecorations;
 use smithay_client_toolkit::shell::xdg::window::WindowHandler;
 use smithay_client_toolkit::shell::xdg::XdgPositioner;
 use smithay_client_toolkit::shell::xdg::XdgShell;
@@ -119,26 +128,106 @@ use smithay_client_toolkit::shm::Shm;
 use smithay_client_toolkit::shm::ShmHandler;
 use smithay_client_toolkit::subcompositor::SubcompositorState;
 use tracing::Span;
+use xxhash_rust::xxh3::Xxh3;
 
 use crate::args;
 use crate::buffer_pointer::BufferPointer;
 use crate::client_utils::SeatObject;
+use crate::compositor_utils;
+use crate::data_targets::DataTargets;
 use crate::prelude::*;
 use crate::serialization;
 use crate::serialization::geometry::Point;
 use crate::serialization::wayland::BufferMetadata;
+use crate::serialization::wayland::DataSource;
 use crate::serialization::wayland::KeyState;
 use crate::xwayland_xdg_shell::compositor::DecorationBehavior;
+use crate::xwayland_xdg_shell::compositor::MaximizedFrame;
+use crate::xwayland_xdg_shell::compositor::TilingMode;
 use crate::xwayland_xdg_shell::compositor::X11Parent;
 use crate::xwayland_xdg_shell::compositor::X11ParentForPopup;
 use crate::xwayland_xdg_shell::compositor::X11ParentForSubsurface;
+use crate::xwayland_xdg_shell::csd::FrameExtents;
+use crate::xwayland_xdg_shell::cursor::CursorThemes;
 use crate::xwayland_xdg_shell::decoration::handle_window_frame_pointer_event;
+use crate::xwayland_xdg_shell::drag;
+use crate::xwayland_xdg_shell::drag::ClientDrag;
+use crate::xwayland_xdg_shell::frame_buttons::FrameButtons;
+use crate::xwayland_xdg_shell::pointer_constraints::EscapedConstraint;
+use crate::xwayland_xdg_shell::pointer_constraints::MirroredConstraint;
+use crate::xwayland_xdg_shell::popup_grab::PopupGrabBehavior;
+use crate::xwayland_xdg_shell::scale_override::ScaleOverride;
+use crate::xwayland_xdg_shell::window_layer::XWaylandLayerSurface;
+use crate::xwayland_xdg_shell::scroll;
+use crate::xwayland_xdg_shell::seat::WprsSeat;
+use crate::xwayland_xdg_shell::seat::keyboard_seat;
+use crate::xwayland_xdg_shell::seat::pointer_seat;
+use crate::xwayland_xdg_shell::sync_request;
+use crate::xwayland_xdg_shell::tablet::LocalTablets;
+use crate::xwayland_xdg_shell::xdnd;
 use crate::xwayland_xdg_shell::xsurface_from_client_surface;
 use crate::xwayland_xdg_shell::WprsState;
 use crate::xwayland_xdg_shell::XWaylandSurface;
 
 const DEFAULT_WINDOW_SIZE: (i32, i32) = (512, 256);
 
+// see linux/input-event-codes.h for keycodes
+pub(crate) const MODIFIER_KEYCODES: [u32; 8] = [
+    /* KEY_LEFTCTRL */ 29, /* KEY_RIGHTCTRL */ 97, /* KEY_LEFTALT */ 56,
+    /* KEY_RIGHTALT */ 100, /* KEY_LEFTMETA	*/ 125, /* KEY_RIGHTMETA */ 126,
+    /* KEY_LEFTSHIFT */ 42, /* KEY_RIGHTSHIFT */ 54,
+];
+
+/// Snaps a size requested by the compositor down to the resize increments in
+/// the X11 window's WM_NORMAL_HINTS, so that e.g. terminals are resized in
+/// whole character cells. Maximized and fullscreen windows must fill the size
+/// they are given, so are left alone.
+fn apply_resize_increments(
+    x11_surface: &X11Surface,
+    configure: Option<&WindowConfigure>,
+    (width, height): (i32, i32),
+) -> (i32, i32) {
+    if configure.is_none_or(|configure| configure.is_maximized() || configure.is_fullscreen()) {
+        return (width, height);
+    }
+    let Some(hints) = x11_surface.size_hints() else {
+        return (width, height);
+    };
+    let Some((inc_w, inc_h)) = hints.size_increment else {
+        return (width, height);
+    };
+    // ICCCM: if the base size isn't provided, the minimum size is used in its
+    // place.
+    let (base_w, base_h) = hints.base_size.or(hints.min_size).unwrap_or((0, 0));
+
+    let snap = |size: i32, base: i32, inc: i32| {
+        if inc <= 1 || size <= base {
+            size
+        } else {
+            base + (size - base) / inc * inc
+        }
+    };
+    (
+        snap(width, base_w, inc_w).max(1),
+        snap(height, base_h, inc_h).max(1),
+    )
+}
+
+#[derive(Debug)]
+pub(crate) enum LocalSelectionOffer {
+    Clipboard(SelectionOffer),
+    Primary(PrimarySelectionOffer),
+}
+
+impl LocalSelectionOffer {
+    pub(crate) fn receive(&self, mime_type: String) -> Option<ReadPipe> {
+        match self {
+            Self::Clipboard(offer) => offer.receive(mime_type).ok(),
+            Self::Primary(offer) => offer.receive(mime_type).ok(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WprsClientState {
     pub qh: QueueHandle<WprsState>,
@@ -151,27 +240,70 @@ pub struct WprsClientState {
     pub subcompositor_state: Arc<SubcompositorState>,
     pub shm_state: Shm,
     pub xdg_shell_state: XdgShell,
+    pub(crate) layer_shell: Option<LayerShell>,
 
     pub(crate) data_device_manager_state: DataDeviceManagerState,
     pub(crate) primary_selection_manager_state: Option<PrimarySelectionManagerState>,
+    pub(crate) idle_notifier: Option<SimpleGlobal<ExtIdleNotifierV1, 1>>,
+    pub(crate) alpha_modifier: Option<SimpleGlobal<WpAlphaModifierV1, 1>>,
+    pub(crate) wp_viewporter: Option<SimpleGlobal<WpViewporter, 1>>,
+    pub(crate) tablet_manager: Option<SimpleGlobal<ZwpTabletManagerV2, 1>>,
+    /// Used to complete the startup notification of X11 apps.
+    pub(crate) activation_state: Option<ActivationState>,
+    pub(crate) pointer_constraints_state: PointerConstraintsState,
+    pub(crate) relative_pointer_state: RelativePointerState,
 
     pub exit: bool,
     pub pool: Option<SlotPool>,
 
     pub last_enter_serial: u32,
     pub(crate) last_implicit_grab_serial: u32,
+    /// The serial of the last button press on an X11 window, used for the
+    /// popup grabs of the menus it opens, see popup_grab.
+    pub(crate) last_button_press_serial: Option<u32>,
     pub(crate) last_focused_window: Option<X11Parent>,
+    /// Popups holding an xdg_popup grab, bottommost first.
+    pub(crate) popup_grab_stack: Vec<ObjectId>,
 
     pub(crate) seat_objects: Vec<SeatObject<ThemedPointer>>,
+    /// Local seat -> the name of the compositor seat mirroring it, see seat.
+    pub(crate) seat_names: HashMap<SctkObjectId, String>,
     pub(crate) cursor_icon: Option<CursorIcon>,
-    pub(crate) selection_offer: Option<SelectionOffer>,
+    pub(crate) cursor_themes: CursorThemes,
+    pub(crate) frame_buttons: FrameButtons,
+    /// WM_CLASS of the window the pointer last entered.
+    pub(crate) pointer_window_class: Option<String>,
+    /// Integer scale of the outputs of the window the pointer last entered,
+    /// see cursor.
+    pub(crate) pointer_window_scale: i32,
+    /// Local selection offers, served to X11 apps reading the corresponding
+    /// X11 selection.
+    pub(crate) selection_offers: DataTargets<LocalSelectionOffer>,
+    /// Local sources for selections owned by X11 apps.
     pub(crate) selection_source: Option<CopyPasteSource>,
-    pub(crate) primary_selection_offer: Option<PrimarySelectionOffer>,
     pub(crate) primary_selection_source: Option<PrimarySelectionSource>,
+    /// The drag started by a client which the local compositor is running.
+    pub(crate) client_drag: Option<ClientDrag>,
+    /// See pointer_constraints.
+    pub(crate) pointer_constraints: Vec<MirroredConstraint>,
+    pub(crate) escaped_pointer_constraints: Vec<EscapedConstraint>,
+    /// See tablet.
+    pub(crate) tablets: LocalTablets,
+
+    pub(crate) idle_timeout_ms: u32,
+    pub(crate) idle_notification: Option<ExtIdleNotificationV1>,
+    pub(crate) idle: bool,
 }
 
 impl WprsClientState {
-    pub fn new(globals: &GlobalList, qh: QueueHandle<WprsState>, conn: Connection) -> Result<Self> {
+    pub fn new(
+        globals: &GlobalList,
+        qh: QueueHandle<WprsState>,
+        conn: Connection,
+        frame_buttons: FrameButtons,
+        idle_timeout_ms: u32,
+        cursor_themes: CursorThemes,
+    ) -> Result<Self> {
         let shm_state = Shm::bind(globals, &qh).context(loc!(), "wl_shm is not available")?;
         let pool =
             Some(SlotPool::new(3840 * 2160, &shm_state).context(loc!(), "failed to create pool")?);
@@ -193,26 +325,66 @@ impl WprsClientState {
             shm_state,
             xdg_shell_state: XdgShell::bind(globals, &qh)
                 .context(loc!(), "xdg shell is not available")?,
+            layer_shell: LayerShell::bind(globals, &qh)
+                .context(loc!(), "wlr layer shell is not available")
+                .warn(loc!())
+                .ok(),
             data_device_manager_state: DataDeviceManagerState::bind(globals, &qh)
                 .context(loc!(), "data device manager is not available")?,
             primary_selection_manager_state: PrimarySelectionManagerState::bind(globals, &qh)
                 .context(loc!(), "primary selection manager is not available")
                 .warn(loc!())
                 .ok(),
+            idle_notifier: SimpleGlobal::<ExtIdleNotifierV1, 1>::bind(globals, &qh)
+                .context(loc!(), "ext_idle_notifier_v1 is not available")
+                .warn(loc!())
+                .ok(),
+            alpha_modifier: SimpleGlobal::<WpAlphaModifierV1, 1>::bind(globals, &qh)
+                .context(loc!(), "wp_alpha_modifier_v1 is not available")
+                .warn(loc!())
+                .ok(),
+            wp_viewporter: SimpleGlobal::<WpViewporter, 1>::bind(globals, &qh)
+                .context(loc!(), "wp_viewporter is not available")
+                .warn(loc!())
+                .ok(),
+            tablet_manager: SimpleGlobal::<ZwpTabletManagerV2, 1>::bind(globals, &qh)
+                .context(loc!(), "zwp_tablet_manager_v2 is not available")
+                .warn(loc!())
+                .ok(),
+            activation_state: ActivationState::bind(globals, &qh)
+                .context(loc!(), "xdg_activation_v1 is not available")
+                .warn(loc!())
+                .ok(),
+            pointer_constraints_state: PointerConstraintsState::bind(globals, &qh),
+            relative_pointer_state: RelativePointerState::bind(globals, &qh),
 
             exit: false,
             pool,
 
             last_enter_serial: 0,
             last_implicit_grab_serial: 0,
+            last_button_press_serial: None,
             last_focused_window: None,
+            popup_grab_stack: Vec::new(),
 
             seat_objects: Vec::new(),
+            seat_names: HashMap::new(),
             cursor_icon: None,
-            selection_offer: None,
+            cursor_themes,
+            frame_buttons,
+            pointer_window_class: None,
+            pointer_window_scale: 1,
+            selection_offers: DataTargets::new(),
             selection_source: None,
-            primary_selection_offer: None,
             primary_selection_source: None,
+            client_drag: None,
+            pointer_constraints: Vec::new(),
+            escaped_pointer_constraints: Vec::new(),
+            tablets: LocalTablets::default(),
+
+            idle_timeout_ms,
+            idle_notification: None,
+            idle: false,
         })
     }
 }
@@ -240,16 +412,30 @@ impl CompositorHandler for WprsState {
         self.sync_surface_outputs(surface);
     }
 
-    #[instrument(skip(self, _conn, _qh), level = "debug")]
+    #[instrument(skip(self, _conn, qh), level = "debug")]
     fn frame(
         &mut self,
         _conn: &Connection,
-        _qh: &QueueHandle<Self>,
+        qh: &QueueHandle<Self>,
         surface: &WlSurface,
         time: u32,
     ) {
         if let Some(compositor_surface_id) = self.surface_bimap.get_by_right(&surface.id()) {
             let xwayland_surface = self.surfaces.get_mut(compositor_surface_id).unwrap();
+            xwayland_surface.frames_in_flight.displayed();
+            // Send the frame held back by the frame limit, if any. Stale frames
+            // held after a mode change and frames held for a sync request stay
+            // held.
+            if xwayland_surface.ready()
+                && xwayland_surface.pending_resize.is_none()
+                && xwayland_surface
+                    .x11_surface
+                    .as_ref()
+                    .and_then(sync_request::pending_sync)
+                    .is_none()
+            {
+                xwayland_surface.commit_buffer(qh);
+            }
             if let Some(Role::SubSurface(subsurface)) = &mut xwayland_surface.role {
                 subsurface.pending_frame_callback = false;
             }
@@ -307,13 +493,32 @@ impl OutputHandler for WprsState {
     #[instrument(skip(self, _conn, _qh), level = "debug")]
     fn new_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, output: WlOutput) {
         let output_info = self.output_state().info(&output).unwrap();
+        let had_output = self.compositor_state.x11_screen_offset.is_some();
         self.compositor_state.new_output(output_info.into());
+        if !had_output {
+            self.place_windows_awaiting_output();
+        }
     }
 
     #[instrument(skip(self, _conn, _qh), level = "debug")]
     fn update_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, output: WlOutput) {
         let output_info = self.output_state().info(&output).unwrap();
-        self.compositor_state.update_output(output_info.into());
+        let output_id = output_info.id;
+        if self.compositor_state.update_output(output_info.into()) {
+            self.start_mode_change(output_id);
+        }
+
+        // The output's scale may have changed, so update the preferred buffer
+        // scale of the surfaces on it.
+        let surfaces_on_output: Vec<_> = self
+            .surfaces
+            .values()
+            .filter(|xwayland_surface| xwayland_surface.output_ids.contains(&output_id))
+            .map(|xwayland_surface| xwayland_surface.wl_surface().clone())
+            .collect();
+        for surface in surfaces_on_output {
+            self.sync_surface_outputs(&surface);
+        }
     }
 
     #[instrument(skip(self, _conn, _qh), level = "debug")]
@@ -367,6 +572,11 @@ impl WindowHandler for WprsState {
         x11_surface
             .set_fullscreen(configure.is_fullscreen())
             .log_and_ignore(loc!());
+        // The local compositor restored the window if it was minimized, see
+        // window_state.
+        if configure.is_activated() && x11_surface.is_minimized() {
+            x11_surface.set_suspended(false).log_and_ignore(loc!());
+        }
 
         xdg_toplevel
             .apply_decoration(
@@ -379,6 +589,15 @@ impl WindowHandler for WprsState {
             )
             .log_and_ignore(loc!());
 
+        if let Some(serial) = sync_request::pending_sync(x11_surface) {
+            sync_request::arm_timeout(
+                &self.event_loop_handle,
+                self.compositor_state.sync_request_behavior,
+                compositor_surface_id.clone(),
+                serial,
+            );
+        }
+
         // The code below commits the buffer we received but couldn't attach
         // because we hadn't received our initial commit. In the normal
         // configure case, the above code will have sent some X11 configure
@@ -410,12 +629,13 @@ impl PopupHandler for WprsState {
         let xwayland_surface = self.surfaces.get_mut(compositor_surface_id).unwrap();
 
         let x11_surface = log_and_return!(xwayland_surface.get_x11_surface());
+        let scale = xwayland_surface.scale();
         let geo = if x11_surface.is_override_redirect() {
             None
         } else {
             Some(Rectangle::new(
-                configure.position.into(),
-                (configure.width, configure.height).into(),
+                (configure.position.0 / scale, configure.position.1 / scale).into(),
+                (configure.width / scale, configure.height / scale).into(),
             ))
         };
 
@@ -450,8 +670,17 @@ impl PopupHandler for WprsState {
         xwayland_surface.commit_buffer(&self.client_state.qh);
     }
 
-    fn done(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _popup: &Popup) {
-        // TODO?
+    #[instrument(skip(self, _conn, _qh), level = "debug")]
+    fn done(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, popup: &Popup) {
+        if self.compositor_state.popup_grab_behavior == PopupGrabBehavior::Ignore {
+            return;
+        }
+        let Some(compositor_surface_id) = self.surface_bimap.get_by_right(&popup.wl_surface().id())
+        else {
+            warn!("Received popup_done for already-destroyed popup {popup:?}.");
+            return;
+        };
+        self.dismiss_popup(&compositor_surface_id.clone());
     }
 }
 
@@ -469,6 +698,10 @@ impl SeatHandler for WprsState {
         seat: WlSeat,
         capability: Capability,
     ) {
+        self.init_idle_notification(&seat);
+        log_and_return!(self.add_compositor_seat(&seat));
+        self.add_tablet_seat(&seat, qh);
+
         let seat_obj = if let Some(seat_obj) = self
             .client_state
             .seat_objects
@@ -491,6 +724,7 @@ impl SeatHandler for WprsState {
                 seat: seat.clone(),
                 keyboard: None,
                 pointer: None,
+                touch: None,
                 data_device,
                 primary_selection_device,
             });
@@ -517,11 +751,22 @@ impl SeatHandler for WprsState {
                     &seat,
                     self.client_state.shm_state.wl_shm(),
                     self.client_state.compositor_state.create_surface(qh),
-                    ThemeSpec::default(),
+                    self.client_state.cursor_themes.theme_spec(),
                 )
                 .expect("Failed to create pointer");
             seat_obj.pointer.replace(themed_pointer);
         }
+
+        if capability == Capability::Touch && seat_obj.touch.is_none() {
+            match self.client_state.seat_state.get_touch(qh, &seat) {
+                Ok(touch) => {
+                    seat_obj.touch.replace(touch);
+                    self.set_compositor_touch(&seat, true)
+                        .log_and_ignore(loc!());
+                },
+                Err(err) => warn!("failed to create touch: {err}"),
+            }
+        }
     }
 
     fn remove_capability(
@@ -548,12 +793,24 @@ impl SeatHandler for WprsState {
                         p.pointer().release()
                     }
                 },
+                Capability::Touch => {
+                    if let Some(t) = seat_obj.touch.take() {
+                        t.release();
+                        self.set_compositor_touch(&seat, false)
+                            .log_and_ignore(loc!());
+                    }
+                },
                 _ => {},
             }
         }
     }
 
-    fn remove_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: WlSeat) {}
+    fn remove_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, seat: WlSeat) {
+        // The compositor seat is kept, to be reused if a local seat with the
+        // same name appears.
+        self.remove_tablet_seat(&seat);
+        self.client_state.seat_names.remove(&seat.id());
+    }
 }
 
 impl KeyboardHandler for WprsState {
@@ -561,32 +818,40 @@ impl KeyboardHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         surface: &WlSurface,
         serial: u32,
         raw: &[u32],
         _keysyms: &[Keysym],
     ) {
-        // see linux/input-event-codes.h for keycodes
-        let modifier_keycodes = HashSet::from([
-            /* KEY_LEFTCTRL */ 29, /* KEY_RIGHTCTRL */ 97, /* KEY_LEFTALT */ 56,
-            /* KEY_RIGHTALT */ 100, /* KEY_LEFTMETA	*/ 125, /* KEY_RIGHTMETA */ 126,
-            /* KEY_LEFTSHIFT */ 42, /* KEY_RIGHTSHIFT */ 54,
-        ]);
-
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
         let keyboard = log_and_return!(
             self.compositor_state
-                .seat
-                .get_keyboard()
-                .ok_or("seat has no keyboard")
+                .seat(&seat_name)
+                .and_then(WprsSeat::keyboard)
         );
 
+        // The leave for the previous focus may have been lost, e.g. if it was
+        // destroyed, so keys released since must be released here.
+        let pressed_keys = &log_and_return!(self.compositor_state.seat(&seat_name)).pressed_keys;
+        for keycode in compositor_utils::keys_to_release(pressed_keys, raw) {
+            log_and_return!(self.set_key_state(
+                &seat_name,
+                keycode,
+                KeyState::Released,
+                SERIAL_COUNTER.next_serial(),
+            ));
+        }
+
         // We simulate keycodes before focusing since that is what a normal wayland application would see.
         // Process modifier keys first so that they apply to other held keys.
         let mut delayed_keycodes = Vec::new();
         for keycode in raw {
-            if modifier_keycodes.contains(keycode) {
+            if MODIFIER_KEYCODES.contains(keycode) {
                 log_and_return!(self.set_key_state(
+                    &seat_name,
                     *keycode,
                     KeyState::Pressed,
                     SERIAL_COUNTER.next_serial(),
@@ -597,6 +862,7 @@ impl KeyboardHandler for WprsState {
         }
         for keycode in delayed_keycodes {
             log_and_return!(self.set_key_state(
+                &seat_name,
                 *keycode,
                 KeyState::Pressed,
                 SERIAL_COUNTER.next_serial()
@@ -611,25 +877,28 @@ impl KeyboardHandler for WprsState {
         let x11_surface = log_and_return!(xwayland_surface.get_x11_surface()).clone();
         let client = x11_surface.wl_surface().unwrap().client();
         x11_surface.set_activated(true).unwrap();
-        let serial = self.compositor_state.serial_map.insert(serial);
+        if x11_surface.is_minimized() {
+            // Minimized with the frame's minimize button.
+            x11_surface.set_suspended(false).log_and_ignore(loc!());
+        }
+        self.compositor_state.focus_history.focus(&x11_surface);
+        self.install_window_colormap(&x11_surface);
+        let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+            .serial_map
+            .insert(serial);
         keyboard.set_focus(self, Some(x11_surface), serial);
-        data_device::set_data_device_focus(
-            &self.compositor_state.dh,
-            &self.compositor_state.seat,
-            client.clone(),
-        );
-        primary_selection::set_primary_focus(
-            &self.compositor_state.dh,
-            &self.compositor_state.seat,
-            client,
-        );
+        self.invalidate_auto_repeat_controls();
+        self.sync_pointer_constraints();
+        let seat = log_and_return!(self.compositor_state.seat(&seat_name));
+        data_device::set_data_device_focus(&self.compositor_state.dh, &seat.seat, client.clone());
+        primary_selection::set_primary_focus(&self.compositor_state.dh, &seat.seat, client);
     }
 
     fn leave(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         surface: &WlSurface,
         serial: u32,
     ) {
@@ -641,34 +910,30 @@ impl KeyboardHandler for WprsState {
         };
         let x11_surface = log_and_return!(xwayland_surface.get_x11_surface()).clone();
         x11_surface.set_activated(false).unwrap();
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
         let keyboard = log_and_return!(
             self.compositor_state
-                .seat
-                .get_keyboard()
-                .ok_or("seat has no keyboard")
+                .seat(&seat_name)
+                .and_then(WprsSeat::keyboard)
         );
 
-        let serial = self.compositor_state.serial_map.insert(serial);
+        let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+            .serial_map
+            .insert(serial);
         keyboard.set_focus(self, None, serial);
-        data_device::set_data_device_focus(
-            &self.compositor_state.dh,
-            &self.compositor_state.seat,
-            None,
-        );
-        primary_selection::set_primary_focus(
-            &self.compositor_state.dh,
-            &self.compositor_state.seat,
-            None,
-        );
+        self.sync_pointer_constraints();
+        let seat = log_and_return!(self.compositor_state.seat(&seat_name));
+        data_device::set_data_device_focus(&self.compositor_state.dh, &seat.seat, None);
+        primary_selection::set_primary_focus(&self.compositor_state.dh, &seat.seat, None);
 
-        for keycode in self.compositor_state.pressed_keys.clone() {
-            log_and_return!(self.set_key_state(keycode, KeyState::Released, serial));
-        }
+        log_and_return!(self.release_pressed_keys(&seat_name, serial));
     }
 
     // INTENTIONALLY NOT LOGGING KEY EVENTS
     #[instrument(
-        skip(self, _conn, _qh, _keyboard, event),
+        skip(self, _conn, _qh, wl_keyboard, event),
         fields(event),
         level = "debug"
     )]
@@ -676,7 +941,7 @@ impl KeyboardHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         serial: u32,
         event: KeyEvent,
     ) {
@@ -684,13 +949,18 @@ impl KeyboardHandler for WprsState {
             Span::current().record("event", field::debug(&event));
         }
         self.client_state.last_implicit_grab_serial = serial;
-        let serial = self.compositor_state.serial_map.insert(serial);
-        log_and_return!(self.set_key_state(event.raw_code, KeyState::Pressed, serial));
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
+        let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+            .serial_map
+            .insert(serial);
+        log_and_return!(self.set_key_state(&seat_name, event.raw_code, KeyState::Pressed, serial));
     }
 
     // INTENTIONALLY NOT LOGGING KEY EVENTS
     #[instrument(
-        skip(self, _conn, _qh, _keyboard, event),
+        skip(self, _conn, _qh, wl_keyboard, event),
         fields(event),
         level = "debug"
     )]
@@ -698,7 +968,7 @@ impl KeyboardHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         serial: u32,
         event: KeyEvent,
     ) {
@@ -706,13 +976,18 @@ impl KeyboardHandler for WprsState {
             Span::current().record("event", field::debug(&event));
         }
         self.client_state.last_implicit_grab_serial = serial;
-        let serial = self.compositor_state.serial_map.insert(serial);
-        log_and_return!(self.set_key_state(event.raw_code, KeyState::Repeated, serial));
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
+        let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+            .serial_map
+            .insert(serial);
+        log_and_return!(self.set_key_state(&seat_name, event.raw_code, KeyState::Repeated, serial));
     }
 
     // INTENTIONALLY NOT LOGGING KEY EVENTS
     #[instrument(
-        skip(self, _conn, _qh, _keyboard, event),
+        skip(self, _conn, _qh, wl_keyboard, event),
         fields(event = "<redacted>"),
         level = "debug"
     )]
@@ -720,30 +995,37 @@ impl KeyboardHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         serial: u32,
         event: KeyEvent,
     ) {
         if args::get_log_priv_data() {
             Span::current().record("event", field::debug(&event));
         }
-        let serial = self.compositor_state.serial_map.insert(serial);
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
+        let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+            .serial_map
+            .insert(serial);
 
-        log_and_return!(self.set_key_state(event.raw_code, KeyState::Released, serial));
+        log_and_return!(self.set_key_state(&seat_name, event.raw_code, KeyState::Released, serial));
     }
 
     fn update_repeat_info(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         info: RepeatInfo,
     ) {
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
         let keyboard = log_and_return!(
             self.compositor_state
-                .seat
-                .get_keyboard()
-                .ok_or("seat has no keyboard")
+                .seat(&seat_name)
+                .and_then(WprsSeat::keyboard)
         );
         let (rate, delay) = match info {
             RepeatInfo::Repeat { rate, delay } => (rate.get(), delay),
@@ -756,33 +1038,42 @@ impl KeyboardHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         keymap: Keymap<'_>,
     ) {
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
         let keyboard = log_and_return!(
             self.compositor_state
-                .seat
-                .get_keyboard()
-                .ok_or("seat has no keyboard")
+                .seat(&seat_name)
+                .and_then(WprsSeat::keyboard)
         );
-        log_and_return!(keyboard.set_keymap_from_string(self, keymap.as_string()));
+        log_and_return!(self.release_pressed_keys(&seat_name, SERIAL_COUNTER.next_serial()));
+        log_and_return!(compositor_utils::set_forwarded_keymap(
+            &keyboard,
+            self,
+            &keymap.as_string()
+        ));
     }
 
     fn update_modifiers(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _keyboard: &WlKeyboard,
+        wl_keyboard: &WlKeyboard,
         _serial: u32,
         modifiers: Modifiers,
         _raw_modifiers: RawModifiers,
         variant: u32,
     ) {
+        let seat_name = log_and_return!(
+            keyboard_seat(wl_keyboard).and_then(|seat| self.compositor_seat_name(seat))
+        );
         let keyboard = log_and_return!(
             self.compositor_state
-                .seat
-                .get_keyboard()
-                .ok_or("seat has no keyboard")
+                .seat(&seat_name)
+                .and_then(WprsSeat::keyboard)
         );
         keyboard.with_xkb_state(self, |mut context: XkbContext| {
             context.set_layout(Layout(variant));
@@ -804,11 +1095,13 @@ impl KeyboardHandler for WprsState {
         ] {
             if new_modifier != current_modifier {
                 log_and_return!(self.set_key_state(
+                    &seat_name,
                     keycode,
                     KeyState::Pressed,
                     SERIAL_COUNTER.next_serial(),
                 ));
                 log_and_return!(self.set_key_state(
+                    &seat_name,
                     keycode,
                     KeyState::Released,
                     SERIAL_COUNTER.next_serial(),
@@ -827,10 +1120,29 @@ impl PointerHandler for WprsState {
         pointer: &WlPointer,
         events: &[PointerEvent],
     ) {
-        let compositor_seat = self.compositor_state.seat.clone();
-        let compositor_pointer = compositor_seat.get_pointer().unwrap();
+        self.reset_idle();
+        let seat_name =
+            log_and_return!(pointer_seat(pointer).and_then(|seat| self.compositor_seat_name(seat)));
+        let compositor_seat = log_and_return!(self.compositor_state.seat(&seat_name))
+            .seat
+            .clone();
+        let compositor_pointer = log_and_return!(compositor_seat.get_pointer().location(loc!()));
+        // The local compositor has the pointer until a move or resize ends,
+        // see move_resize.
+        if events
+            .iter()
+            .any(|event| !matches!(event.kind, PointerEventKind::Leave { .. }))
+        {
+            self.end_move_resize(&seat_name);
+        }
 
         for event in events {
+            // Before looking up the surface, which may have been destroyed,
+            // see pointer_leave.
+            if let PointerEventKind::Leave { serial } = event.kind {
+                self.handle_pointer_leave(&seat_name, serial)
+                    .log_and_ignore(loc!());
+            }
             let Some(xwayland_surface) = xsurface_from_client_surface(
                 &self.surface_bimap,
                 &mut self.surfaces,
@@ -841,10 +1153,15 @@ impl PointerHandler for WprsState {
                 return;
             };
             let x11_surface = log_and_return!(xwayland_surface.get_x11_surface()).clone();
+            let scale = f64::from(xwayland_surface.scale());
 
             match event.kind {
                 PointerEventKind::Enter { serial } => {
                     self.client_state.last_enter_serial = serial;
+                    self.client_state.pointer_window_class = Some(x11_surface.class());
+                    self.client_state.pointer_window_scale = self
+                        .compositor_state
+                        .preferred_buffer_scale(&xwayland_surface.output_ids);
                     // TODO: allow this to be a popup?
                     if let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role {
                         let parent_id = self
@@ -875,35 +1192,28 @@ impl PointerHandler for WprsState {
                         .unwrap()
                         .raise_window(&x11_surface)
                         .unwrap();
-                    let serial = self.compositor_state.serial_map.insert(serial);
+                    self.compositor_state.focus_history.raise(&x11_surface);
+                    let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+                        .serial_map
+                        .insert(serial);
                     compositor_pointer.motion(
                         self,
                         Some((x11_surface, (0 as f64, 0 as f64).into())),
                         &MotionEvent {
-                            location: event.position.into(),
-                            serial,
-                            time: 0, // unused
-                        },
-                    );
-                },
-                PointerEventKind::Leave { serial } => {
-                    let serial = self.compositor_state.serial_map.insert(serial);
-                    compositor_pointer.motion(
-                        self,
-                        None,
-                        &MotionEvent {
-                            location: event.position.into(),
+                            location: (event.position.0 / scale, event.position.1 / scale).into(),
                             serial,
                             time: 0, // unused
                         },
                     );
                 },
+                // Handled above.
+                PointerEventKind::Leave { .. } => {},
                 PointerEventKind::Motion { time } => {
                     compositor_pointer.motion(
                         self,
                         Some((x11_surface, (0 as f64, 0 as f64).into())),
                         &MotionEvent {
-                            location: event.position.into(),
+                            location: (event.position.0 / scale, event.position.1 / scale).into(),
                             serial: 0.into(), // unused
                             time,
                         },
@@ -914,7 +1224,10 @@ impl PointerHandler for WprsState {
                     button,
                     serial,
                 } => {
-                    let serial = self.compositor_state.serial_map.insert(serial);
+                    self.client_state.last_button_press_serial = Some(serial);
+                    let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+                        .serial_map
+                        .insert(serial);
                     compositor_pointer.button(
                         self,
                         &ButtonEvent {
@@ -930,7 +1243,9 @@ impl PointerHandler for WprsState {
                     button,
                     serial,
                 } => {
-                    let serial = self.compositor_state.serial_map.insert(serial);
+                    let serial = log_and_return!(self.compositor_state.seat_mut(&seat_name))
+                        .serial_map
+                        .insert(serial);
                     compositor_pointer.button(
                         self,
                         &ButtonEvent {
@@ -949,18 +1264,7 @@ impl PointerHandler for WprsState {
                 } => x11_surface.axis(
                     &compositor_seat,
                     self,
-                    AxisFrame::new(time)
-                        .source(match source.unwrap() {
-                            WlPointerAxisSource::Wheel => AxisSource::Wheel,
-                            WlPointerAxisSource::Finger => AxisSource::Finger,
-                            WlPointerAxisSource::Continuous => AxisSource::Continuous,
-                            WlPointerAxisSource::WheelTilt => AxisSource::WheelTilt,
-                            _ => unreachable!("got unknown AxisSource {:?}", source),
-                        })
-                        .value(Axis::Horizontal, horizontal.absolute)
-                        .value(Axis::Vertical, vertical.absolute)
-                        .v120(Axis::Horizontal, horizontal.discrete * 120)
-                        .v120(Axis::Vertical, vertical.discrete * 120),
+                    scroll::axis_frame(time, &horizontal, &vertical, source),
                 ),
             }
         }
@@ -968,6 +1272,14 @@ impl PointerHandler for WprsState {
     }
 }
 
+impl ActivationHandler for WprsState {
+    type RequestData = RequestData;
+
+    // We only activate with the tokens X11 apps were launched with, and never
+    // request our own.
+    fn new_token(&mut self, _token: String, _data: &RequestData) {}
+}
+
 impl ShmHandler for WprsState {
     fn shm_state(&mut self) -> &mut Shm {
         &mut self.client_state.shm_state
@@ -999,30 +1311,85 @@ impl XWaylandBuffer {
         })
     }
 
+    fn canvas<'a>(&mut self, pool: &'a mut SlotPool) -> Result<&'a mut [u8]> {
+        if pool.canvas(&self.active_buffer).is_none() {
+            // This should be rare, but if the compositor has not
+            // released the previous_button_state buffer, we need
+            // double-buffering.
+            debug!("previous buffer wasn't released, creating new buffer");
+            self.active_buffer = pool
+                .create_buffer(
+                    self.metadata.width,
+                    self.metadata.height,
+                    self.metadata.stride,
+                    self.metadata.format.into(),
+                )
+                .location(loc!())?
+                .0;
+        }
+        pool.canvas(&self.active_buffer).location(loc!())
+    }
+
     #[instrument(skip_all, level = "debug")]
     pub fn write_data(&mut self, data: BufferPointer<u8>, pool: &mut SlotPool) -> Result<()> {
-        let canvas = match pool.canvas(&self.active_buffer) {
-            Some(canvas) => canvas,
-            None => {
-                // This should be rare, but if the compositor has not
-                // released the previous_button_state buffer, we need
-                // double-buffering.
-                debug!("previous buffer wasn't released, creating new buffer");
-                self.active_buffer = pool
-                    .create_buffer(
-                        self.metadata.width,
-                        self.metadata.height,
-                        self.metadata.stride,
-                        self.metadata.format.into(),
-                    )
-                    .location(loc!())?
-                    .0;
-                pool.canvas(&self.active_buffer).unwrap()
-            },
-        };
+        let canvas = self.canvas(pool).location(loc!())?;
         data.copy_to_nonoverlapping(canvas);
         Ok(())
     }
+
+    /// Writes `data`, with layout `metadata`, upscaled by `scale_override`.
+    #[instrument(skip(self, data, pool), level = "debug")]
+    pub fn write_upscaled_data(
+        &mut self,
+        data: BufferPointer<u8>,
+        metadata: &BufferMetadata,
+        scale_override: ScaleOverride,
+        pool: &mut SlotPool,
+    ) -> Result<()> {
+        let mut src = vec![0; data.len()];
+        data.copy_to_nonoverlapping(&mut src);
+        let canvas = self.canvas(pool).location(loc!())?;
+        scale_override.upscale(&src, metadata, canvas);
+        Ok(())
+    }
+
+    /// Hashes the buffer metadata and the damaged parts of the buffer
+    /// contents, so that commits which don't change anything can be detected.
+    #[instrument(skip_all, level = "debug")]
+    pub fn content_hash(
+        &self,
+        damage: &[serialization::geometry::Rectangle<i32>],
+        pool: &mut SlotPool,
+    ) -> Option<u64> {
+        let canvas = pool.canvas(&self.active_buffer)?;
+        let metadata = &self.metadata;
+        let bytes_per_pixel = 4;
+
+        let mut hasher = Xxh3::new();
+        hasher.update(&metadata.width.to_ne_bytes());
+        hasher.update(&metadata.height.to_ne_bytes());
+        hasher.update(&metadata.stride.to_ne_bytes());
+        hasher.update(&[metadata.format as u8]);
+        for rect in damage {
+            let (x, y, w, h) = (rect.loc.x, rect.loc.y, rect.size.w, rect.size.h);
+            for n in [x, y, w, h] {
+                hasher.update(&n.to_ne_bytes());
+            }
+
+            // Damage may extend past the buffer (e.g., i32::MAX).
+            let x_start = x.clamp(0, metadata.width);
+            let x_end = x.saturating_add(w).clamp(0, metadata.width);
+            let y_start = y.clamp(0, metadata.height);
+            let y_end = y.saturating_add(h).clamp(0, metadata.height);
+            for y in y_start..y_end {
+                let row = (y * metadata.stride) as usize;
+                let start = row + (x_start * bytes_per_pixel) as usize;
+                let end = row + (x_end * bytes_per_pixel) as usize;
+                hasher.update(&canvas[start..end]);
+            }
+        }
+        Some(hasher.digest())
+    }
 }
 
 impl XWaylandSurface {
@@ -1033,6 +1400,10 @@ impl XWaylandSurface {
         Ok(())
     }
 
+    /// Copies `data`, laid out as `metadata`, into the local buffer of the
+    /// surface, creating a new one if the size or format changed. The pool
+    /// grows to fit buffers larger than it, see SlotPool::create_buffer, and
+    /// the local compositor remaps it when it's resized.
     #[instrument(skip(data, pool), level = "debug")]
     pub fn update_buffer(
         &mut self,
@@ -1040,8 +1411,12 @@ impl XWaylandSurface {
         data: BufferPointer<u8>,
         pool: &mut SlotPool,
     ) -> Result<()> {
-        let metadata =
+        let src_metadata =
             serialization::wayland::BufferMetadata::from_buffer_data(metadata).location(loc!())?;
+        let metadata = match &self.scale_override {
+            Some(scale_override) => scale_override.scale_metadata(&src_metadata),
+            None => src_metadata,
+        };
         let buffer = match &mut self.buffer {
             // Surface was previously committed.
             Some(buffer) => {
@@ -1070,7 +1445,12 @@ impl XWaylandSurface {
             },
         };
 
-        buffer.write_data(data, pool).location(loc!())?;
+        match self.scale_override {
+            Some(scale_override) => buffer
+                .write_upscaled_data(data, &src_metadata, scale_override, pool)
+                .location(loc!())?,
+            None => buffer.write_data(data, pool).location(loc!())?,
+        }
 
         Ok(())
     }
@@ -1079,8 +1459,9 @@ impl XWaylandSurface {
         self.wl_surface().commit();
     }
 
-    pub fn frame(&self, qh: &QueueHandle<WprsState>) {
+    pub fn frame(&mut self, qh: &QueueHandle<WprsState>) {
         self.wl_surface().frame(qh, self.wl_surface().clone());
+        self.frames_in_flight.sent();
     }
 
     pub fn get_role(&self) -> Result<&Role> {
@@ -1107,9 +1488,11 @@ impl XWaylandSurface {
 #[derive(Debug, EnumAsInner)]
 pub enum Role {
     Cursor,
+    DragIcon,
     XdgToplevel(XWaylandXdgToplevel),
     XdgPopup(XWaylandXdgPopup),
     SubSurface(XWaylandSubSurface),
+    LayerSurface(XWaylandLayerSurface),
 }
 
 #[derive(Debug)]
@@ -1119,7 +1502,17 @@ pub struct XWaylandXdgToplevel {
     pub frame_offset: Point<i32>,
     pub configured: bool,
     pub decoration_behavior: DecorationBehavior,
+    pub tiling_mode: TilingMode,
+    /// Set by the caller of set_role, like frame_extents.
+    pub maximized_frame: MaximizedFrame,
+    /// Whether we've asked the local compositor not to decorate the window.
+    /// This is only done once to avoid configure loops.
+    pub requested_no_decorations: bool,
+    /// Set for windows client-side decorated by GTK, see csd.
+    pub frame_extents: Option<FrameExtents>,
     pub x11_offset: Point<i32>,
+    /// See scale_override.
+    pub scale: i32,
 }
 
 impl XWaylandXdgToplevel {
@@ -1143,6 +1536,7 @@ impl XWaylandXdgToplevel {
 
         // configure.new_size has outer_dimensions, we want width and height to
         // be inner dimensions.
+        let scale = self.scale as u32;
         let (width, height) = match (configure, buffer_metadata) {
             (
                 Some(WindowConfigure {
@@ -1150,10 +1544,16 @@ impl XWaylandXdgToplevel {
                     ..
                 }),
                 _,
-            ) => window_frame.subtract_borders(*width, *height),
+            ) => {
+                let (width, height) = window_frame.subtract_borders(*width, *height);
+                (
+                    width.and_then(|width| NonZeroU32::new(width.get() / scale)),
+                    height.and_then(|height| NonZeroU32::new(height.get() / scale)),
+                )
+            },
             (_, Some(buffer_metadata)) => (
-                NonZeroU32::new(buffer_metadata.width as u32),
-                NonZeroU32::new(buffer_metadata.height as u32),
+                NonZeroU32::new(buffer_metadata.width as u32 / scale),
+                NonZeroU32::new(buffer_metadata.height as u32 / scale),
             ),
             _ => {
                 warn!(
@@ -1168,21 +1568,36 @@ impl XWaylandXdgToplevel {
         let width = width.unwrap_or(NonZeroU32::new(1).unwrap());
         let height = height.unwrap_or(NonZeroU32::new(1).unwrap());
 
-        window_frame.resize(width, height);
-
-        // Everything after this wants u32s or i32s.
-        let width = width.get();
-        let height = height.get();
+        let (width, height) = apply_resize_increments(
+            x11_surface,
+            configure,
+            (width.get() as i32, height.get() as i32),
+        );
+        let width = NonZeroU32::new(width as u32).location(loc!())?;
+        let height = NonZeroU32::new(height as u32).location(loc!())?;
 
+        sync_request::request_sync(
+            x11_surface,
+            (width.get() as i32, height.get() as i32).into(),
+        );
         // X11's ConfigureNotify wants the outer coordinates but the inner
         // dimensions. And don't worry about border_width. /sigh
         x11_surface
             .configure(Rectangle::new(
                 (-self.x11_offset.x, -self.x11_offset.y).into(),
-                (width as i32, height as i32).into(),
+                (width.get() as i32, height.get() as i32).into(),
             ))
             .location(loc!())?;
 
+        // The frame is drawn around the local surface.
+        let width = width.saturating_mul(NonZeroU32::new(scale).location(loc!())?);
+        let height = height.saturating_mul(NonZeroU32::new(scale).location(loc!())?);
+        window_frame.resize(width, height);
+
+        // Everything after this wants u32s or i32s.
+        let width = width.get();
+        let height = height.get();
+
         // Top-left corner of frame relative to the inner surface, so x and y
         // will always be negative. -x and -y are thus the coordinates of the
         // inner top-left corner (assuming the outer coordinates are 0).
@@ -1201,7 +1616,7 @@ impl XWaylandXdgToplevel {
             .xdg_surface()
             .set_window_geometry(x, y, outer_w as i32, outer_h as i32);
 
-        Ok((width as i32, height as i32))
+        Ok((width as i32 / self.scale, height as i32 / self.scale))
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -1215,7 +1630,9 @@ impl XWaylandXdgToplevel {
         let window_frame = &mut self.window_frame;
         window_frame.set_hidden(true);
         self.frame_offset = (0, 0).into();
+        let extents = self.shadow_extents(configure);
 
+        let scale = self.scale;
         let (width, height) = match (configure, buffer_metadata) {
             (
                 Some(WindowConfigure {
@@ -1223,8 +1640,18 @@ impl XWaylandXdgToplevel {
                     ..
                 }),
                 _,
-            ) => (width.get() as i32, height.get() as i32),
-            (_, Some(buffer_metadata)) => (buffer_metadata.width, buffer_metadata.height),
+            ) => apply_resize_increments(
+                x11_surface,
+                configure,
+                extents.grow((
+                    (width.get() as i32 / scale).max(1),
+                    (height.get() as i32 / scale).max(1),
+                )),
+            ),
+            (_, Some(buffer_metadata)) => (
+                buffer_metadata.width / scale,
+                buffer_metadata.height / scale,
+            ),
             _ => {
                 warn!(
                     "Unable to get size from either configure or buffer_metadata, using default size: {:?}",
@@ -1234,6 +1661,7 @@ impl XWaylandXdgToplevel {
             },
         };
 
+        sync_request::request_sync(x11_surface, (width, height).into());
         x11_surface
             .configure(Rectangle::new(
                 (-self.x11_offset.x, -self.x11_offset.y).into(),
@@ -1241,13 +1669,66 @@ impl XWaylandXdgToplevel {
             ))
             .location(loc!())?;
 
-        self.local_window
-            .xdg_surface()
-            .set_window_geometry(0, 0, width, height);
+        let visible = extents.visible((width, height)).upscale(scale);
+        self.local_window.xdg_surface().set_window_geometry(
+            visible.loc.x,
+            visible.loc.y,
+            visible.size.w,
+            visible.size.h,
+        );
 
         Ok((width, height))
     }
 
+    /// The extents of the shadow drawn around the window by the app, which
+    /// is dropped when the window is maximized, fullscreen or tiled.
+    fn shadow_extents(&self, configure: Option<&WindowConfigure>) -> FrameExtents {
+        let Some(extents) = self.frame_extents else {
+            return FrameExtents::default();
+        };
+        match configure {
+            Some(configure)
+                if configure.is_maximized()
+                    || configure.is_fullscreen()
+                    || self.is_tiled(Some(configure)) =>
+            {
+                FrameExtents::default()
+            },
+            _ => extents,
+        }
+    }
+
+    /// Whether the app draws its own decorations.
+    fn client_decorated(&self, x11_surface: &X11Surface) -> bool {
+        x11_surface.is_decorated() || self.frame_extents.is_some()
+    }
+
+    fn is_tiled(&self, configure: Option<&WindowConfigure>) -> bool {
+        match self.tiling_mode {
+            TilingMode::Detect => configure.is_some_and(WindowConfigure::is_tiled),
+            TilingMode::Tiling => true,
+            TilingMode::Floating => false,
+        }
+    }
+
+    /// Draws our frame around the window, unless it's maximized and the
+    /// frame of maximized windows is hidden, see MaximizedFrame.
+    fn draw_decorations(
+        &mut self,
+        x11_surface: &X11Surface,
+        configure: Option<&WindowConfigure>,
+        buffer_metadata: Option<&BufferMetadata>,
+    ) -> Result<(i32, i32)> {
+        if self
+            .maximized_frame
+            .hides_frame(configure.is_some_and(WindowConfigure::is_maximized))
+        {
+            self.disable_decoration(x11_surface, configure, buffer_metadata)
+        } else {
+            self.enable_decorations(x11_surface, configure, buffer_metadata)
+        }
+    }
+
     pub fn apply_decoration(
         &mut self,
         x11_surface: &X11Surface,
@@ -1255,30 +1736,38 @@ impl XWaylandXdgToplevel {
         buffer_metadata: Option<&BufferMetadata>,
     ) -> Result<(i32, i32)> {
         match self.decoration_behavior {
+            DecorationBehavior::Auto if self.is_tiled(configure) => {
+                // Client-side decorations which we then don't draw, so that
+                // the only decorations are the tiler's borders. This is a
+                // request: the compositor may still pick server-side
+                // decorations.
+                if !self.requested_no_decorations {
+                    self.local_window
+                        .request_decoration_mode(Some(DecorationMode::Client));
+                    self.requested_no_decorations = true;
+                }
+                self.disable_decoration(x11_surface, configure, buffer_metadata)
+            },
+            DecorationBehavior::Auto if self.client_decorated(x11_surface) => {
+                // The app has drawn its own decorations, so it needs neither
+                // ours nor the local compositor's.
+                if !self.requested_no_decorations {
+                    self.local_window
+                        .request_decoration_mode(Some(DecorationMode::Client));
+                    self.requested_no_decorations = true;
+                }
+                self.disable_decoration(x11_surface, configure, buffer_metadata)
+            },
             DecorationBehavior::Auto => {
                 if let Some(configure) = configure {
                     match configure.decoration_mode {
                         DecorationMode::Server => {
-                            // wayland compositor has drawn decorations so it doesn't need ours
+                            // wayland compositor has drawn decorations so it doesn't need ours,
+                            // this also zeroes frame_offset, which popups are positioned with
                             self.disable_decoration(x11_surface, Some(configure), buffer_metadata)
                         },
                         DecorationMode::Client => {
-                            // x11 app has drawn it's own decorations so it doesn't need ours
-                            // TODO: if the x11_surface is decorated, but there are server-side decorations then we
-                            // should request client-side decorations from the compositor
-                            if x11_surface.is_decorated() {
-                                self.disable_decoration(
-                                    x11_surface,
-                                    Some(configure),
-                                    buffer_metadata,
-                                )
-                            } else {
-                                self.enable_decorations(
-                                    x11_surface,
-                                    Some(configure),
-                                    buffer_metadata,
-                                )
-                            }
+                            self.draw_decorations(x11_surface, Some(configure), buffer_metadata)
                         },
                     }
                 } else {
@@ -1286,7 +1775,7 @@ impl XWaylandXdgToplevel {
                 }
             },
             DecorationBehavior::AlwaysEnabled => {
-                self.enable_decorations(x11_surface, configure, buffer_metadata)
+                self.draw_decorations(x11_surface, configure, buffer_metadata)
             },
             DecorationBehavior::AlwaysDisabled => {
                 self.disable_decoration(x11_surface, configure, buffer_metadata)
@@ -1302,24 +1791,29 @@ impl XWaylandXdgToplevel {
         subcompositor_state: Arc<SubcompositorState>,
         qh: &QueueHandle<WprsState>,
         decoration_behavior: DecorationBehavior,
+        tiling_mode: TilingMode,
     ) -> Result<()> {
         let local_surface = surface.local_surface.take().location(loc!())?;
-        let local_window =
-            xdg_shell_state.create_window(local_surface, WindowDecorations::ServerDefault, qh);
+        let local_window = xdg_shell_state.create_window(
+            local_surface,
+            decoration_behavior.window_decorations(),
+            qh,
+        );
 
+        // The title is set by the caller, see title.
         let x11_surface = surface.get_x11_surface().location(loc!())?;
-        local_window.set_title(x11_surface.title());
 
+        let scale = surface.scale();
         if let Some(max_size) = x11_surface.max_size() {
+            let max_size = max_size.upscale(scale);
             local_window.set_max_size(Some((max_size.w as u32, max_size.h as u32)));
         }
 
         if let Some(min_size) = x11_surface.min_size() {
+            let min_size = min_size.upscale(scale);
             local_window.set_min_size(Some((min_size.w as u32, min_size.h as u32)));
         }
 
-        // TODO: decorations
-
         local_window.commit();
 
         let window_frame =
@@ -1333,7 +1827,12 @@ impl XWaylandXdgToplevel {
             frame_offset: (0, 0).into(),
             configured: false,
             decoration_behavior,
+            tiling_mode,
+            maximized_frame: MaximizedFrame::default(),
+            requested_no_decorations: false,
+            frame_extents: None,
             x11_offset,
+            scale,
         };
         surface.role = Some(Role::XdgToplevel(new_toplevel));
         Ok(())
@@ -1380,6 +1879,8 @@ pub struct XWaylandSubSurface {
     pub move_pointer_location: (f64, f64),
     pub pending_frame_callback: bool,
     pub buffer_attached: bool,
+    /// See scale_override.
+    pub scale: i32,
 }
 
 impl XWaylandSubSurface {
@@ -1440,6 +1941,7 @@ impl XWaylandSubSurface {
             move_pointer_location: (0 as f64, 0 as f64),
             pending_frame_callback: false,
             buffer_attached: false,
+            scale: surface.scale(),
         };
         surface.role = Some(Role::SubSurface(new_subsurface));
 
@@ -1456,9 +1958,10 @@ impl XWaylandSubSurface {
         if !self.pending_frame_callback {
             let local_wl_surface = self.wl_surface();
 
-            self.local_subsurface
-                .subsurface
-                .set_position(x + self.offset.x, y + self.offset.y);
+            self.local_subsurface.subsurface.set_position(
+                (x + self.offset.x) * self.scale,
+                (y + self.offset.y) * self.scale,
+            );
             local_wl_surface.frame(qh, local_wl_surface.clone());
             self.parent_surface.commit();
 
@@ -1498,10 +2001,11 @@ impl XWaylandXdgPopup {
         // TODO: move into function
         let positioner = XdgPositioner::new(xdg_shell_state).unwrap();
         let geometry = x11_surface.geometry();
-        positioner.set_size(geometry.size.w, geometry.size.h);
+        let scale = surface.scale();
+        positioner.set_size(geometry.size.w * scale, geometry.size.h * scale);
         positioner.set_anchor_rect(
-            geometry.loc.x + parent.x11_offset.x,
-            geometry.loc.y + parent.x11_offset.y,
+            (geometry.loc.x + parent.x11_offset.x) * scale,
+            (geometry.loc.y + parent.x11_offset.y) * scale,
             1,
             1,
         );
@@ -1587,49 +2091,32 @@ impl DataDeviceHandler for WprsState {
         _y: f64,
         _wl_surface: &WlSurface,
     ) {
-        let data_device = &self
-            .client_state
-            .seat_objects
-            .iter()
-            .find(|seat| seat.data_device.inner() == data_device)
-            .unwrap()
-            .data_device
-            .data();
-        let drag_offer = data_device.drag_offer().unwrap();
+        let Some(drag_offer) = self.drag_offer(data_device) else {
+            return;
+        };
         debug!(
             "data offer entered x: {:.2} y: {:.2}",
             drag_offer.x, drag_offer.y
         );
-
-        // TODO: revisit xwayland drag-and-drop.
-        // // accept the first mime type we support
-        // if let Some(m) = data_device
-        //     .drag_mime_types()
-        //     .iter()
-        //     .find(|m| SUPPORTED_MIME_TYPES.contains(&m.as_str()))
-        // {
-        //     drag_offer.accept_mime_type(0, Some(m.clone()));
-        // }
-
-        // // accept the action now just in case
-        // drag_offer.set_actions(DndAction::Copy, DndAction::Copy);
+        self.xdnd_enter(drag_offer);
     }
 
     fn leave(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _data_device: &WlDataDevice) {
         debug!("data offer left");
+        self.xdnd_leave();
     }
 
     fn motion(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _data_device: &WlDataDevice,
+        data_device: &WlDataDevice,
         _x: f64,
         _y: f64,
     ) {
-        // TODO: revisit xwayland drag-and-drop.
-        // let DragOffer { x, y, time, .. } = data_device.drag_offer().unwrap();
-        // dbg!((time, x, y));
+        if let Some(drag_offer) = self.drag_offer(data_device) {
+            self.xdnd_motion(&drag_offer);
+        }
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -1648,13 +2135,19 @@ impl DataDeviceHandler for WprsState {
             .data_device
             .data();
         let Some(offer) = data_device.selection_offer() else {
+            self.clear_local_selection(SelectionTarget::Clipboard);
             return;
         };
         let mime_types = offer.with_mime_types(<[String]>::to_vec);
         if mime_types.contains(&"_xwayland_xdg_shell_marker".to_string()) {
             return;
         }
-        self.client_state.selection_offer = Some(offer);
+        self.client_state
+            .selection_offers
+            .set(DataSource::Selection, LocalSelectionOffer::Clipboard(offer));
+        self.compositor_state
+            .client_selections
+            .take(DataSource::Selection);
         if let Some(xwm) = &mut self.compositor_state.xwm {
             xwm.new_selection(SelectionTarget::Clipboard, Some(mime_types))
                 .log_and_ignore(loc!());
@@ -1672,49 +2165,7 @@ impl DataDeviceHandler for WprsState {
         _qh: &QueueHandle<Self>,
         _data_device: &WlDataDevice,
     ) {
-        // TODO: revisit xwayland drag-and-drop.
-        // if let Some(offer) = data_device.drag_offer() {
-        //     println!("Dropped: {offer:?}");
-        //     self.dnd_offers.push((offer, String::new(), None));
-        //     let cur_offer = self.dnd_offers.last_mut().unwrap();
-        //     let mime_type = match data_device
-        //         .drag_mime_types()
-        //         .iter()
-        //         .find(|m| SUPPORTED_MIME_TYPES.contains(&m.as_str()))
-        //         .cloned()
-        //     {
-        //         Some(mime) => mime,
-        //         None => return,
-        //     };
-        //     dbg!(&mime_type);
-        //     self.accept_counter += 1;
-        //     cur_offer.0.accept_mime_type(self.accept_counter, Some(mime_type.clone()));
-        //     cur_offer.0.set_actions(DndAction::Copy, DndAction::Copy);
-        //     if let Ok(read_pipe) = cur_offer.0.receive(mime_type.clone()) {
-        //         let offer_clone = cur_offer.0.clone();
-        //         match self.loop_handle.insert_source(read_pipe, move |_, f, state| {
-        //             let (offer, mut contents, token) = state
-        //                 .dnd_offers
-        //                 .iter()
-        //                 .position(|o| &o.0.inner() == &offer_clone.inner())
-        //                 .map(|p| state.dnd_offers.remove(p))
-        //                 .unwrap();
-
-        //             f.read_to_string(&mut contents).unwrap();
-        //             println!("TEXT FROM drop: {contents}");
-        //             state.loop_handle.remove(token.unwrap());
-
-        //             offer.finish();
-        //         }) {
-        //             Ok(token) => {
-        //                 cur_offer.2.replace(token);
-        //             }
-        //             Err(err) => {
-        //                 eprintln!("{:?}", err);
-        //             }
-        //         }
-        //     }
-        // }
+        self.xdnd_drop();
     }
 }
 
@@ -1723,12 +2174,14 @@ impl DataOfferHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _offer: &mut DragOffer,
+        offer: &mut DragOffer,
         actions: DndAction,
     ) {
         debug!("Source actions: {actions:?}");
-        // TODO: revisit xwayland drag-and-drop.
-        // offer.set_actions(DndAction::Copy, DndAction::Copy);
+        // Accept whatever X11 clients can do, rather than always copying, so
+        // that e.g. move-only sources still work.
+        let preferred = xdnd::choose_action(actions, xdnd::x11_actions(), DndAction::empty());
+        offer.set_actions(xdnd::x11_actions(), preferred);
     }
 
     fn selected_action(
@@ -1738,8 +2191,10 @@ impl DataOfferHandler for WprsState {
         _offer: &mut DragOffer,
         actions: DndAction,
     ) {
-        debug!("Selected action: {actions:?}");
-        // TODO ?
+        debug!(
+            "Selected action: {actions:?} ({:?})",
+            xdnd::to_xdnd_action(actions)
+        );
     }
 }
 
@@ -1748,10 +2203,13 @@ impl DataSourceHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _source: &WlDataSource,
+        source: &WlDataSource,
         mime: Option<String>,
     ) {
         debug!("Source mime type: {mime:?} was accepted");
+        if let Some(drag_source) = self.client_drag_source(source) {
+            drag_source.target(mime);
+        }
     }
 
     #[instrument(skip(self, _conn, _qh), level = "debug")]
@@ -1763,7 +2221,20 @@ impl DataSourceHandler for WprsState {
         mime: String,
         write_pipe: WritePipe,
     ) {
-        // TODO: handle multiple sources
+        if let Some(drag_source) = self.client_drag_source(source) {
+            // The client writes to the local drop target directly.
+            drag_source.send(mime, write_pipe.as_fd());
+            return;
+        }
+        if self
+            .client_state
+            .selection_source
+            .as_ref()
+            .is_none_or(|selection_source| selection_source.inner() != source)
+        {
+            warn!("send request for a stale clipboard source, ignoring");
+            return;
+        }
         if let Some(xwm) = &mut self.compositor_state.xwm {
             xwm.send_selection(
                 SelectionTarget::Clipboard,
@@ -1777,22 +2248,36 @@ impl DataSourceHandler for WprsState {
 
     #[instrument(skip(self, _conn, _qh), level = "debug")]
     fn cancelled(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, source: &WlDataSource) {
-        // TODO: revisit xwayland drag-and-drop.
+        // The local drop target rejected the drag, or it was dropped over no
+        // surface.
+        if self.client_drag_source(source).is_some() {
+            self.end_client_drag();
+            return;
+        }
+        // Another local client took the clipboard, its offer reaches X11
+        // through the selection event.
+        if self
+            .client_state
+            .selection_source
+            .as_ref()
+            .is_some_and(|selection_source| selection_source.inner() == source)
+        {
+            self.client_state.selection_source = None;
+        }
     }
 
     #[instrument(skip_all, level = "debug")]
-    fn dnd_dropped(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _source: &WlDataSource) {
-        // TODO: revisit xwayland drag-and-drop.
+    fn dnd_dropped(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, source: &WlDataSource) {
+        if let Some(drag_source) = self.client_drag_source(source) {
+            drag::forward_drop_performed(drag_source);
+        }
     }
 
     #[instrument(skip_all, level = "debug")]
-    fn dnd_finished(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _source: &WlDataSource,
-    ) {
-        // TODO: revisit xwayland drag-and-drop.
+    fn dnd_finished(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, source: &WlDataSource) {
+        if let Some(drag_source) = self.client_drag_source(source).cloned() {
+            self.client_drag_finished(&drag_source);
+        }
     }
 
     #[instrument(skip_all, level = "debug")]
@@ -1800,10 +2285,12 @@ impl DataSourceHandler for WprsState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _source: &WlDataSource,
-        _action: DndAction,
+        source: &WlDataSource,
+        action: DndAction,
     ) {
-        // TODO: revisit xwayland drag-and-drop.
+        if let Some(drag_source) = self.client_drag_source(source) {
+            drag::forward_action(drag_source, action);
+        }
     }
 }
 
@@ -1827,13 +2314,19 @@ impl PrimarySelectionDeviceHandler for WprsState {
             .unwrap()
             .data();
         let Some(offer) = primary_selection_device.selection_offer() else {
+            self.clear_local_selection(SelectionTarget::Primary);
             return;
         };
         let mime_types = offer.with_mime_types(<[String]>::to_vec);
         if mime_types.contains(&"_xwayland_xdg_shell_marker".to_string()) {
             return;
         }
-        self.client_state.primary_selection_offer = Some(offer);
+        self.client_state
+            .selection_offers
+            .set(DataSource::Primary, LocalSelectionOffer::Primary(offer));
+        self.compositor_state
+            .client_selections
+            .take(DataSource::Primary);
         if let Some(xwm) = &mut self.compositor_state.xwm {
             xwm.new_selection(SelectionTarget::Primary, Some(mime_types))
                 .log_and_ignore(loc!());
@@ -1851,6 +2344,15 @@ impl PrimarySelectionSourceHandler for WprsState {
         mime: String,
         write_pipe: WritePipe,
     ) {
+        if self
+            .client_state
+            .primary_selection_source
+            .as_ref()
+            .is_none_or(|selection_source| selection_source.inner() != source)
+        {
+            warn!("send request for a stale primary selection source, ignoring");
+            return;
+        }
         if let Some(xwm) = &mut self.compositor_state.xwm {
             xwm.send_selection(
                 SelectionTarget::Primary,
@@ -1869,9 +2371,18 @@ impl PrimarySelectionSourceHandler for WprsState {
         _qh: &QueueHandle<Self>,
         source: &ZwpPrimarySelectionSourceV1,
     ) {
+        if self
+            .client_state
+            .primary_selection_source
+            .as_ref()
+            .is_some_and(|selection_source| selection_source.inner() == source)
+        {
+            self.client_state.primary_selection_source = None;
+        }
     }
 }
 
+smithay_client_toolkit::delegate_activation!(WprsState);
 smithay_client_toolkit::delegate_compositor!(WprsState);
 smithay_client_toolkit::delegate_data_device!(WprsState);
 smithay_client_toolkit::delegate_keyboard!(WprsState);
@@ -1881,6 +2392,7 @@ smithay_client_toolkit::delegate_registry!(WprsState);
 smithay_client_toolkit::delegate_seat!(WprsState);
 smithay_client_toolkit::delegate_shm!(WprsState);
 smithay_client_toolkit::delegate_subcompositor!(WprsState);
+smithay_client_toolkit::delegate_touch!(WprsState);
 smithay_client_toolkit::delegate_xdg_popup!(WprsState);
 smithay_client_toolkit::delegate_xdg_shell!(WprsState);
 smithay_client_toolkit::delegate_xdg_window!(WprsState);
@@ -1922,3 +2434,170 @@ impl Dispatch<WlSubsurface, SubSurfaceData> for WprsState {
         dbg!("SUBSURFACE DISPATCH");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::sync::mpsc::TryRecvError;
+    use std::thread;
+    use std::time::Duration;
+
+    use smithay::reexports::wayland_server::Display;
+    use smithay::reexports::wayland_server::backend::ClientData;
+    use smithay::reexports::wayland_server::protocol::wl_buffer::WlBuffer as ServerWlBuffer;
+    use smithay::reexports::wayland_server::protocol::wl_shm;
+    use smithay::wayland::buffer::BufferHandler;
+    use smithay::wayland::shm::ShmHandler as ServerShmHandler;
+    use smithay::wayland::shm::ShmState;
+    use smithay_client_toolkit::reexports::client::globals::GlobalListContents;
+    use smithay_client_toolkit::reexports::client::globals::registry_queue_init;
+    use smithay_client_toolkit::reexports::client::protocol::wl_registry::WlRegistry;
+
+    use super::*;
+    use crate::compositor_utils;
+
+    struct Server {
+        shm_state: ShmState,
+    }
+
+    impl BufferHandler for Server {
+        fn buffer_destroyed(&mut self, _buffer: &ServerWlBuffer) {}
+    }
+
+    impl ServerShmHandler for Server {
+        fn shm_state(&self) -> &ShmState {
+            &self.shm_state
+        }
+    }
+
+    smithay::delegate_shm!(Server);
+
+    struct TestClientData;
+
+    impl ClientData for TestClientData {}
+
+    /// A request for the contents of the buffer with a protocol id.
+    type ContentsRequest = (u32, mpsc::Sender<Vec<u8>>);
+
+    /// Runs a compositor with only wl_shm on `stream` until the returned
+    /// sender is dropped. It answers requests for the contents of buffers as
+    /// they would be sent.
+    fn spawn_server(stream: UnixStream) -> (mpsc::Sender<ContentsRequest>, thread::JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel::<ContentsRequest>();
+        let handle = thread::spawn(move || {
+            let mut display: Display<Server> = Display::new().unwrap();
+            let dh = display.handle();
+            let mut state = Server {
+                shm_state: ShmState::new::<Server>(&dh, Vec::new()),
+            };
+            let client = display
+                .handle()
+                .insert_client(stream, Arc::new(TestClientData))
+                .unwrap();
+            loop {
+                display.dispatch_clients(&mut state).unwrap();
+                display.flush_clients().unwrap();
+                match receiver.try_recv() {
+                    Ok((protocol_id, contents)) => {
+                        let buffer = client
+                            .object_from_protocol_id::<ServerWlBuffer>(&dh, protocol_id)
+                            .unwrap();
+                        let data = compositor_utils::with_buffer_contents(&buffer, |data, _| {
+                            let mut copy = vec![0; data.len()];
+                            data.copy_to_nonoverlapping(&mut copy);
+                            copy
+                        })
+                        .unwrap();
+                        contents.send(data).unwrap();
+                    },
+                    Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(1)),
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+        });
+        (sender, handle)
+    }
+
+    struct TestClient {
+        shm: Shm,
+    }
+
+    impl ShmHandler for TestClient {
+        fn shm_state(&mut self) -> &mut Shm {
+            &mut self.shm
+        }
+    }
+
+    impl Dispatch<WlRegistry, GlobalListContents> for TestClient {
+        fn event(
+            _state: &mut Self,
+            _registry: &WlRegistry,
+            _event: <WlRegistry as Proxy>::Event,
+            _data: &GlobalListContents,
+            _conn: &Connection,
+            _qh: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    smithay_client_toolkit::delegate_shm!(TestClient);
+
+    fn pattern(width: i32, height: i32) -> Vec<u8> {
+        (0..width * height * 4).map(|i| (i + width) as u8).collect()
+    }
+
+    #[test]
+    fn pool_grows_to_fit_larger_buffers() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let (server, server_thread) = spawn_server(server_stream);
+
+        let conn = Connection::from_socket(client_stream).unwrap();
+        let (globals, event_queue) = registry_queue_init::<TestClient>(&conn).unwrap();
+        let client = TestClient {
+            shm: Shm::bind(&globals, &event_queue.handle()).unwrap(),
+        };
+        // Much smaller than any of the buffers, so that each one grows the
+        // pool.
+        let mut pool = SlotPool::new(64, &client.shm).unwrap();
+        let mut surface = XWaylandSurface::default();
+
+        let mut pool_len = pool.len();
+        for (width, height) in [(8, 8), (64, 32), (300, 200), (1024, 768)] {
+            let data = pattern(width, height);
+            let ptr = data.as_ptr();
+            // SAFETY: ptr comes from data, which outlives the BufferPointer.
+            let data_ptr = unsafe { BufferPointer::new(&ptr, data.len()) };
+            let metadata = BufferData {
+                offset: 0,
+                width,
+                height,
+                stride: width * 4,
+                format: wl_shm::Format::Argb8888,
+            };
+            surface
+                .update_buffer(&metadata, data_ptr, &mut pool)
+                .unwrap();
+            assert!(pool.len() > pool_len);
+            pool_len = pool.len();
+
+            // The compositor sees the contents of the new buffer through its
+            // mapping of the resized pool.
+            conn.roundtrip().unwrap();
+            let protocol_id = surface
+                .buffer
+                .as_ref()
+                .unwrap()
+                .active_buffer
+                .wl_buffer()
+                .id()
+                .protocol_id();
+            let (contents_sender, contents) = mpsc::channel();
+            server.send((protocol_id, contents_sender)).unwrap();
+            assert!(contents.recv().unwrap() == data);
+        }
+
+        drop(server);
+        server_thread.join().unwrap();
+    }
+}
@@ -19,12 +19,12 @@ use std::ffi::OsStr;
 use std::mem;
 use std::os::fd::OwnedFd;
 use std::process::Stdio;
-use std::time::Duration;
 use std::time::Instant;
 
 use calloop::RegistrationToken;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use smithay::backend::allocator::dmabuf::Dmabuf;
 use smithay::backend::renderer::utils::on_commit_buffer_handler;
 use smithay::input::Seat;
 use smithay::input::SeatHandler;
@@ -34,6 +34,8 @@ use smithay::input::pointer::CursorImageSurfaceData;
 use smithay::output::Output;
 use smithay::output::PhysicalProperties;
 use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
 use smithay::reexports::wayland_server::Client;
 use smithay::reexports::wayland_server::DisplayHandle;
 use smithay::reexports::wayland_server::Resource;
@@ -50,18 +52,25 @@ use smithay::wayland::compositor::CompositorState;
 use smithay::wayland::compositor::Damage;
 use smithay::wayland::compositor::SurfaceAttributes;
 use smithay::wayland::compositor::SurfaceData;
+use smithay::wayland::dmabuf::DmabufGlobal;
+use smithay::wayland::dmabuf::DmabufHandler;
+use smithay::wayland::dmabuf::DmabufState;
+use smithay::wayland::dmabuf::ImportNotifier;
 use smithay::wayland::output::OutputHandler;
+use smithay::wayland::pointer_constraints::PointerConstraintsState;
+use smithay::wayland::relative_pointer::RelativePointerManagerState;
 use smithay::wayland::selection::SelectionHandler;
 use smithay::wayland::selection::SelectionSource;
 use smithay::wayland::selection::SelectionTarget;
-use smithay::wayland::selection::data_device::ClientDndGrabHandler;
 use smithay::wayland::selection::data_device::DataDeviceHandler;
 use smithay::wayland::selection::data_device::DataDeviceState;
-use smithay::wayland::selection::data_device::ServerDndGrabHandler;
 use smithay::wayland::selection::primary_selection::PrimarySelectionHandler;
 use smithay::wayland::selection::primary_selection::PrimarySelectionState;
 use smithay::wayland::shm::ShmHandler;
 use smithay::wayland::shm::ShmState;
+use smithay::wayland::tablet_manager::TabletManagerState;
+use smithay::wayland::viewporter::ViewportCachedState;
+use smithay::wayland::viewporter::ViewporterState;
 use smithay::wayland::xwayland_shell::XWaylandShellHandler;
 use smithay::wayland::xwayland_shell::XWaylandShellState;
 use smithay::xwayland::X11Surface;
@@ -75,17 +84,67 @@ use smithay_client_toolkit::reexports::csd_frame::DecorationsFrame;
 use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_surface;
 use smithay_client_toolkit::shell::WaylandSurface;
 use smithay_client_toolkit::shell::xdg::XdgSurface;
+use smithay_client_toolkit::shell::xdg::window::WindowDecorations;
 
 use crate::compositor_utils;
+use crate::data_targets::DataTargets;
+use crate::dmabuf;
+use crate::dmabuf::DmabufBehavior;
 use crate::fallible_entry::FallibleEntryExt;
+use crate::output_dpi;
 use crate::prelude::*;
 use crate::serialization::geometry::Point;
+use crate::serialization::geometry::Rectangle;
 use crate::serialization::wayland::OutputInfo;
-use crate::utils::SerialMap;
+use crate::serialization::wayland::ViewportState;
 use crate::xwayland_xdg_shell::WprsState;
 use crate::xwayland_xdg_shell::XWaylandSurface;
+use crate::xwayland_xdg_shell::allowed_actions::AllowedActionsWriter;
 use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::configure_timeout;
+use crate::xwayland_xdg_shell::configure_timeout::ConfigureTimeout;
+use crate::xwayland_xdg_shell::csd::CsdDetection;
+use crate::xwayland_xdg_shell::decoration_rules::DecorationRules;
+use crate::xwayland_xdg_shell::early_buffer::EarlyBufferBehavior;
+use crate::xwayland_xdg_shell::focus_loss::FocusHistory;
+use crate::xwayland_xdg_shell::focus_loss::FocusLossBehavior;
+use crate::xwayland_xdg_shell::frame_pacing::FramePacing;
+use crate::xwayland_xdg_shell::fullscreen::FullscreenMonitorBehavior;
+use crate::xwayland_xdg_shell::input_region::EmptyInputRegionBehavior;
+use crate::xwayland_xdg_shell::key_repeat::AutoRepeatControls;
+use crate::xwayland_xdg_shell::mode_change::ModeChangeBehavior;
+use crate::xwayland_xdg_shell::move_resize::MoveResize;
+use crate::xwayland_xdg_shell::move_resize::MoveResizeWatcher;
+use crate::xwayland_xdg_shell::no_output::NoOutputBehavior;
+use crate::xwayland_xdg_shell::opacity::OpacityInterpolation;
+use crate::xwayland_xdg_shell::opacity::OpacityWatcher;
+use crate::xwayland_xdg_shell::opacity::WindowOpacities;
+use crate::xwayland_xdg_shell::pending_parents;
+use crate::xwayland_xdg_shell::pending_parents::ParentRaceBehavior;
+use crate::xwayland_xdg_shell::pending_parents::PendingParents;
+use crate::xwayland_xdg_shell::pending_parents::Retry;
+use crate::xwayland_xdg_shell::pointer_constraints::PointerLockEscape;
+use crate::xwayland_xdg_shell::pointer_leave::PointerLeaveBehavior;
+use crate::xwayland_xdg_shell::popup_grab;
+use crate::xwayland_xdg_shell::popup_grab::PopupGrabBehavior;
+use crate::xwayland_xdg_shell::scale_override::ScaleOverrides;
+use crate::xwayland_xdg_shell::seat;
+use crate::xwayland_xdg_shell::seat::WprsSeat;
+use crate::xwayland_xdg_shell::selection_limit::SelectionLimiter;
+use crate::xwayland_xdg_shell::selection_limit::SelectionRateLimit;
+use crate::xwayland_xdg_shell::startup;
+use crate::xwayland_xdg_shell::sync_request;
+use crate::xwayland_xdg_shell::sync_request::SyncRequestBehavior;
+use crate::xwayland_xdg_shell::sync_request::SyncWatcher;
+#[cfg(test)]
+use crate::xwayland_xdg_shell::testing;
+use crate::xwayland_xdg_shell::title::TitleSource;
+use crate::xwayland_xdg_shell::viewport;
+use crate::xwayland_xdg_shell::window_layer::WindowLayerBehavior;
+use crate::xwayland_xdg_shell::window_state::RequestedState;
 use crate::xwayland_xdg_shell::wmname;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+use crate::xwayland_xdg_shell::xdnd::XdndSource;
 
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
 pub enum DecorationBehavior {
@@ -95,6 +154,50 @@ pub enum DecorationBehavior {
     AlwaysDisabled,
 }
 
+impl DecorationBehavior {
+    /// The decorations local toplevels are created with. With Auto, the local
+    /// compositor is asked to draw its own decorations, which match the rest
+    /// of the desktop, and we only draw our frame if it declines or doesn't
+    /// support zxdg_decoration_manager_v1.
+    pub fn window_decorations(self) -> WindowDecorations {
+        match self {
+            Self::Auto => WindowDecorations::RequestServer,
+            Self::AlwaysEnabled | Self::AlwaysDisabled => WindowDecorations::ServerDefault,
+        }
+    }
+}
+
+/// Whether the local compositor tiles windows. Tiling compositors draw their
+/// own borders, so with DecorationBehavior::Auto we ask them not to decorate
+/// tiled windows and don't draw a frame ourselves either.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum TilingMode {
+    /// Treat windows as tiled when the local compositor reports them as tiled
+    /// on all four edges.
+    #[default]
+    Detect,
+    Tiling,
+    Floating,
+}
+
+/// Whether the frame we draw around windows (see DecorationBehavior) is kept
+/// when they're maximized.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum MaximizedFrame {
+    #[default]
+    Shown,
+    /// Hide the frame of maximized windows, so that their contents fill the
+    /// whole maximized area.
+    Hidden,
+}
+
+impl MaximizedFrame {
+    /// Whether the frame of a window should be hidden.
+    pub fn hides_frame(self, maximized: bool) -> bool {
+        self == Self::Hidden && maximized
+    }
+}
+
 pub struct XwaylandOptions<K, V, I>
 where
     I: IntoIterator<Item = (K, V)>,
@@ -105,110 +208,338 @@ where
     pub env: I,
 }
 
+/// The behaviors of the compositor, see the flags of xwayland-xdg-shell.
+pub struct CompositorOptions {
+    /// Used for windows no decoration rule matches.
+    pub decoration_behavior: DecorationBehavior,
+    pub decoration_rules: DecorationRules,
+    pub tiling_mode: TilingMode,
+    pub maximized_frame: MaximizedFrame,
+    pub csd_detection: CsdDetection,
+    pub parent_race_behavior: ParentRaceBehavior,
+    pub early_buffer_behavior: EarlyBufferBehavior,
+    pub skip_unchanged_commits: bool,
+    pub popup_grab_behavior: PopupGrabBehavior,
+    pub window_layer_behavior: WindowLayerBehavior,
+    pub fullscreen_monitor_behavior: FullscreenMonitorBehavior,
+    pub title_source: TitleSource,
+    pub title_template: String,
+    /// Set as _NET_WM_NAME once xwayland is ready, see wmname.
+    pub wm_name: String,
+    pub configure_timeout: ConfigureTimeout,
+    /// 0 means unlimited, see frame_limit.
+    pub max_frames_in_flight: u32,
+    pub frame_pacing: FramePacing,
+    pub mode_change_behavior: ModeChangeBehavior,
+    pub scale_overrides: ScaleOverrides,
+    pub focus_loss_behavior: FocusLossBehavior,
+    pub selection_rate_limit: SelectionRateLimit,
+    pub forward_primary_selection: bool,
+    pub opacity_interpolation: OpacityInterpolation,
+    pub sync_request_behavior: SyncRequestBehavior,
+    pub no_output_behavior: NoOutputBehavior,
+    pub empty_input_region_behavior: EmptyInputRegionBehavior,
+    pub pointer_leave_behavior: PointerLeaveBehavior,
+    pub pointer_lock_escape: PointerLockEscape,
+    /// Used for outputs with an implausible physical size, and as Xft.dpi at
+    /// scale 1.
+    pub default_dpi: u32,
+    pub dmabuf_behavior: DmabufBehavior,
+}
+
 #[derive(Debug)]
 pub struct WprsCompositorState {
     pub dh: DisplayHandle,
     pub compositor_state: CompositorState,
     pub start_time: Instant,
     pub shm_state: ShmState,
+    pub dmabuf_state: DmabufState,
+    /// Set if dmabuf import is enabled, see dmabuf.
+    pub dmabuf_global: Option<DmabufGlobal>,
     pub seat_state: SeatState<WprsState>,
     pub data_device_state: DataDeviceState,
     pub xwayland_shell_state: XWaylandShellState,
     pub primary_selection_state: PrimarySelectionState,
+    pub pointer_constraints_state: PointerConstraintsState,
+    pub relative_pointer_manager_state: RelativePointerManagerState,
+    pub viewporter_state: ViewporterState,
+    pub tablet_manager_state: TabletManagerState,
+    /// Used for windows no decoration rule matches.
     pub decoration_behavior: DecorationBehavior,
-
-    pub seat: Seat<WprsState>,
+    pub decoration_rules: DecorationRules,
+    pub tiling_mode: TilingMode,
+    pub maximized_frame: MaximizedFrame,
+    pub csd_detection: CsdDetection,
+    pub parent_race_behavior: ParentRaceBehavior,
+    pub early_buffer_behavior: EarlyBufferBehavior,
+    pub skip_unchanged_commits: bool,
+    pub popup_grab_behavior: PopupGrabBehavior,
+    pub window_layer_behavior: WindowLayerBehavior,
+    pub fullscreen_monitor_behavior: FullscreenMonitorBehavior,
+    pub title_source: TitleSource,
+    pub title_template: String,
+    /// Set as _NET_WM_NAME once xwayland is ready, see wmname.
+    pub wm_name: String,
+    pub configure_timeout: ConfigureTimeout,
+    /// 0 means unlimited, see frame_limit.
+    pub max_frames_in_flight: u32,
+    pub frame_pacing: FramePacing,
+    pub mode_change_behavior: ModeChangeBehavior,
+    pub scale_overrides: ScaleOverrides,
+    pub focus_loss_behavior: FocusLossBehavior,
+    pub(crate) selection_limiter: SelectionLimiter,
+    /// Whether X11 primary selections (selected text) are forwarded.
+    pub forward_primary_selection: bool,
+    /// None until xwayland is ready, see opacity.
+    pub(crate) opacity_watcher: Option<OpacityWatcher>,
+    /// None until xwayland is ready, see allowed_actions.
+    pub(crate) allowed_actions_writer: Option<AllowedActionsWriter>,
+    /// None until queried, see key_repeat.
+    pub(crate) auto_repeat_controls: Option<AutoRepeatControls>,
+    pub(crate) window_opacities: WindowOpacities,
+    pub sync_request_behavior: SyncRequestBehavior,
+    /// None until xwayland is ready or if sync requests are disabled, see
+    /// sync_request.
+    pub(crate) sync_watcher: Option<SyncWatcher>,
+    /// None until xwayland is ready, see move_resize.
+    pub(crate) move_resize_watcher: Option<MoveResizeWatcher>,
+    pub(crate) move_resize: Option<MoveResize>,
+    pub no_output_behavior: NoOutputBehavior,
+    pub empty_input_region_behavior: EmptyInputRegionBehavior,
+    pub pointer_leave_behavior: PointerLeaveBehavior,
+    pub pointer_lock_escape: PointerLockEscape,
+    /// Surfaces whose commits are held until the first output appears.
+    pub(crate) surfaces_awaiting_output: Vec<WlSurface>,
+    /// X11 window -> the sub-window whose colormap it uses, see visual.
+    pub(crate) colormap_windows: HashMap<u32, u32>,
+    pub(crate) requested_window_states: HashMap<u32, RequestedState>,
+    /// Used for outputs with an implausible physical size, and as Xft.dpi at
+    /// scale 1.
+    pub default_dpi: u32,
+    /// The Xft.dpi last set, see xresources.
+    pub(crate) xft_dpi: Option<u32>,
+
+    /// Seat name -> seat, see seat.
+    pub seats: HashMap<String, WprsSeat>,
 
     pub outputs: HashMap<u32, (Output, GlobalId)>,
-    pub(crate) serial_map: SerialMap,
-    pub(crate) pressed_keys: HashSet<u32>,
+    pub(crate) focus_history: FocusHistory<X11Surface>,
 
     pub xwm: Option<X11Wm>,
+    /// Seats whose selection is owned by a Wayland client connected directly
+    /// to us rather than through xwayland, and which is offered to X11.
+    pub(crate) client_selections: DataTargets<Seat<WprsState>>,
 
     pub x11_screen_offset: Option<Point<i32>>,
+    /// The X display number xwayland is running on, once it's ready.
+    pub x11_display: Option<u32>,
+    /// None until xwayland is ready, see x11_connection.
+    pub(crate) x11_conn: Option<X11Connection>,
+    /// None until xwayland is ready, see xdnd.
+    pub(crate) xdnd_source: Option<XdndSource>,
 
     /// unpaired x11 surfaces
     pub x11_surfaces: Vec<X11Surface>,
+    /// surfaces whose x11 parent hasn't been assigned a role yet
+    pub(crate) pending_parents: PendingParents<WlSurface>,
 }
 
-impl WprsCompositorState {
-    /// # Panics
-    /// On failure launching xwayland.
-    pub fn new<K, V, I>(
-        dh: DisplayHandle,
-        event_loop_handle: &LoopHandle<'static, WprsState>,
-        decoration_behavior: DecorationBehavior,
-        xwayland_options: XwaylandOptions<K, V, I>,
-        registration_tokens: &mut Vec<RegistrationToken>,
-    ) -> Self
-    where
-        I: IntoIterator<Item = (K, V)>,
-        K: AsRef<OsStr>,
-        V: AsRef<OsStr>,
-    {
-        let mut seat_state = SeatState::new();
-        let seat = seat_state.new_wl_seat(&dh, "wprs");
-
-        let (xwayland, client) = XWayland::spawn(
-            &dh,
-            xwayland_options.display,
-            xwayland_options.env,
-            false,
-            Stdio::inherit(),
-            Stdio::inherit(),
-            |_| {},
-        )
-        .expect("failed to start xwayland.");
-
-        let ret = event_loop_handle.insert_source(xwayland, move |event, _, data| match event {
-            XWaylandEvent::Ready {
-                x11_socket,
-                display_number,
-            } => {
-                let wm =
-                    X11Wm::start_wm(data.event_loop_handle.clone(), x11_socket, client.clone())
-                        .expect("Failed to attach X11 Window Manager.");
-
-                // Oh Java...
-                wmname::set_wmname(Some(&format!(":{display_number}")), "LG3D")
-                    .expect("Failed to set WM name.");
-
-                data.compositor_state.xwm = Some(wm);
-            },
-            XWaylandEvent::Error => {
-                let _ = data.compositor_state.xwm.take();
-            },
-        });
+/// Launches xwayland, whose window manager is started once it's ready.
+///
+/// # Panics
+/// On failure launching xwayland.
+pub(crate) fn start_xwayland<K, V, I>(
+    dh: &DisplayHandle,
+    event_loop_handle: &LoopHandle<'static, WprsState>,
+    xwayland_options: XwaylandOptions<K, V, I>,
+    registration_tokens: &mut Vec<RegistrationToken>,
+) where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let (xwayland, client) = XWayland::spawn(
+        dh,
+        xwayland_options.display,
+        xwayland_options.env,
+        false,
+        Stdio::inherit(),
+        Stdio::inherit(),
+        |_| {},
+    )
+    .expect("failed to start xwayland.");
+
+    let ret = event_loop_handle.insert_source(xwayland, move |event, _, data| match event {
+        XWaylandEvent::Ready {
+            x11_socket,
+            display_number,
+        } => {
+            let wm = X11Wm::start_wm(data.event_loop_handle.clone(), x11_socket, client.clone())
+                .expect("Failed to attach X11 Window Manager.");
+
+            wmname::set_wmname(
+                Some(&format!(":{display_number}")),
+                &data.compositor_state.wm_name,
+            )
+            .expect("Failed to set WM name.");
+
+            data.compositor_state.xwm = Some(wm);
+            data.compositor_state.x11_display = Some(display_number);
+            data.compositor_state.x11_conn = X11Connection::start(display_number).warn(loc!()).ok();
+            data.compositor_state.allowed_actions_writer = data
+                .compositor_state
+                .x11_conn
+                .as_ref()
+                .and_then(|conn| AllowedActionsWriter::start(conn).warn(loc!()).ok());
+            data.compositor_state.xdnd_source =
+                XdndSource::start(display_number, &data.event_loop_handle)
+                    .warn(loc!())
+                    .ok();
+            data.compositor_state.sync_xft_dpi();
+            data.compositor_state.opacity_watcher =
+                OpacityWatcher::start(display_number, &data.event_loop_handle)
+                    .warn(loc!())
+                    .ok();
+            if data.compositor_state.sync_request_behavior != SyncRequestBehavior::Disabled {
+                data.compositor_state.sync_watcher =
+                    SyncWatcher::start(display_number, &data.event_loop_handle)
+                        .warn(loc!())
+                        .ok();
+            }
+            data.compositor_state.move_resize_watcher =
+                MoveResizeWatcher::start(display_number, &data.event_loop_handle)
+                    .warn(loc!())
+                    .ok();
+        },
+        XWaylandEvent::Error => {
+            let _ = data.compositor_state.xwm.take();
+        },
+    });
 
-        match ret {
-            Ok(token) => {
-                registration_tokens.push(token);
-            },
-            Err(e) => {
-                error!(
-                    "Failed to insert the XWaylandSource into the event loop: {}",
-                    e
-                );
-            },
-        }
+    match ret {
+        Ok(token) => {
+            registration_tokens.push(token);
+        },
+        Err(e) => {
+            error!(
+                "Failed to insert the XWaylandSource into the event loop: {}",
+                e
+            );
+        },
+    }
+}
+
+impl WprsCompositorState {
+    /// The state of a compositor which xwayland hasn't connected to yet, see
+    /// start_xwayland.
+    pub fn new(dh: DisplayHandle, options: CompositorOptions) -> Self {
+        let CompositorOptions {
+            decoration_behavior,
+            decoration_rules,
+            tiling_mode,
+            maximized_frame,
+            csd_detection,
+            parent_race_behavior,
+            early_buffer_behavior,
+            skip_unchanged_commits,
+            popup_grab_behavior,
+            window_layer_behavior,
+            fullscreen_monitor_behavior,
+            title_source,
+            title_template,
+            wm_name,
+            configure_timeout,
+            max_frames_in_flight,
+            frame_pacing,
+            mode_change_behavior,
+            scale_overrides,
+            focus_loss_behavior,
+            selection_rate_limit,
+            forward_primary_selection,
+            opacity_interpolation,
+            sync_request_behavior,
+            no_output_behavior,
+            empty_input_region_behavior,
+            pointer_leave_behavior,
+            pointer_lock_escape,
+            default_dpi,
+            dmabuf_behavior,
+        } = options;
+        let seat_state = SeatState::new();
+        let mut dmabuf_state = DmabufState::new();
+        let dmabuf_global = dmabuf::default_feedback(&dmabuf_behavior)
+            .warn(loc!())
+            .ok()
+            .flatten()
+            .map(|feedback| {
+                dmabuf_state.create_global_with_default_feedback::<WprsState>(&dh, &feedback)
+            });
 
         Self {
             dh: dh.clone(),
-            compositor_state: CompositorState::new::<WprsState>(&dh),
+            compositor_state: CompositorState::new_v6::<WprsState>(&dh),
             start_time: Instant::now(),
             shm_state: ShmState::new::<WprsState>(&dh, Vec::new()),
+            dmabuf_state,
+            dmabuf_global,
             seat_state,
             xwayland_shell_state: XWaylandShellState::new::<WprsState>(&dh),
             data_device_state: DataDeviceState::new::<WprsState>(&dh),
             primary_selection_state: PrimarySelectionState::new::<WprsState>(&dh),
+            pointer_constraints_state: PointerConstraintsState::new::<WprsState>(&dh),
+            relative_pointer_manager_state: RelativePointerManagerState::new::<WprsState>(&dh),
+            viewporter_state: ViewporterState::new::<WprsState>(&dh),
+            tablet_manager_state: TabletManagerState::new::<WprsState>(&dh),
             decoration_behavior,
-            seat,
+            decoration_rules,
+            tiling_mode,
+            maximized_frame,
+            csd_detection,
+            parent_race_behavior,
+            early_buffer_behavior,
+            skip_unchanged_commits,
+            popup_grab_behavior,
+            window_layer_behavior,
+            fullscreen_monitor_behavior,
+            title_source,
+            title_template,
+            wm_name,
+            configure_timeout,
+            max_frames_in_flight,
+            frame_pacing,
+            mode_change_behavior,
+            scale_overrides,
+            focus_loss_behavior,
+            selection_limiter: SelectionLimiter::new(selection_rate_limit),
+            forward_primary_selection,
+            opacity_watcher: None,
+            allowed_actions_writer: None,
+            auto_repeat_controls: None,
+            window_opacities: WindowOpacities::new(opacity_interpolation),
+            sync_request_behavior,
+            sync_watcher: None,
+            move_resize_watcher: None,
+            move_resize: None,
+            no_output_behavior,
+            empty_input_region_behavior,
+            pointer_leave_behavior,
+            pointer_lock_escape,
+            surfaces_awaiting_output: Vec::new(),
+            colormap_windows: HashMap::new(),
+            requested_window_states: HashMap::new(),
+            default_dpi,
+            xft_dpi: None,
+            seats: HashMap::new(),
             outputs: HashMap::new(),
-            serial_map: SerialMap::new(),
-            pressed_keys: HashSet::new(),
+            focus_history: FocusHistory::new(),
             xwm: None,
+            client_selections: DataTargets::new(),
             x11_screen_offset: None,
+            x11_display: None,
+            x11_conn: None,
+            xdnd_source: None,
             x11_surfaces: Vec::new(),
+            pending_parents: PendingParents::new(),
         }
     }
 
@@ -222,7 +553,7 @@ impl WprsCompositorState {
                     output.name.clone().unwrap_or("None".to_string())
                 ),
                 PhysicalProperties {
-                    size: output.physical_size.into(),
+                    size: output_dpi::physical_size(&output, self.default_dpi).into(),
                     subpixel: output.subpixel.into(),
                     make: output.make.clone(),
                     model: output.model.clone(),
@@ -243,19 +574,24 @@ impl WprsCompositorState {
         let mut expanded_output = output.clone();
         expanded_output.mode.dimensions =
             (output.mode.dimensions.w * 3, output.mode.dimensions.h * 3).into();
+        // Xwayland only reads the current mode, it emulates its own list of
+        // modes for RandR.
+        expanded_output.modes.clear();
         self.x11_screen_offset =
             Some((-output.mode.dimensions.w, -output.mode.dimensions.h).into());
 
         compositor_utils::update_output(local_output, expanded_output);
+        self.sync_xft_dpi();
     }
 
+    /// Returns whether the size of the output's mode changed.
     #[instrument(skip(self), level = "debug")]
-    pub(crate) fn update_output(&mut self, output: OutputInfo) {
+    pub(crate) fn update_output(&mut self, output: OutputInfo) -> bool {
         let (local_output, _) = match self.outputs.entry(output.id) {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(_) => {
                 warn!("update to unknown display {:?}", output.id);
-                return;
+                return false;
             },
         };
 
@@ -265,10 +601,29 @@ impl WprsCompositorState {
             expanded_output.mode.dimensions.h * 3,
         )
             .into();
+        expanded_output.modes.clear();
         self.x11_screen_offset =
             Some((-output.mode.dimensions.w, -output.mode.dimensions.h).into());
 
+        let old_size = local_output.current_mode().map(|mode| mode.size);
+        let new_size = expanded_output.mode.dimensions;
         compositor_utils::update_output(local_output, expanded_output);
+        self.sync_xft_dpi();
+        old_size.is_some_and(|size| (size.w, size.h) != (new_size.w, new_size.h))
+    }
+
+    /// The buffer scale Xwayland should render a surface on the outputs
+    /// `output_ids` at. Fractional output scales are rounded up, Xwayland
+    /// only renders at integer scales, so this is sent as
+    /// wl_surface.preferred_buffer_scale rather than through
+    /// fractional-scale-v1.
+    pub(crate) fn preferred_buffer_scale(&self, output_ids: &HashSet<u32>) -> i32 {
+        compositor_utils::preferred_buffer_scale(
+            output_ids
+                .iter()
+                .filter_map(|id| self.outputs.get(id))
+                .map(|(output, _)| output.current_scale().integer_scale()),
+        )
     }
 
     #[instrument(skip(self), level = "debug")]
@@ -276,6 +631,7 @@ impl WprsCompositorState {
         if let Some((_, (_, global_id))) = self.outputs.remove_entry(&output.id) {
             self.dh.remove_global::<WprsState>(global_id);
         }
+        self.sync_xft_dpi();
     }
 }
 
@@ -284,33 +640,54 @@ impl BufferHandler for WprsState {
     fn buffer_destroyed(&mut self, buffer: &WlBuffer) {}
 }
 
+impl DmabufHandler for WprsState {
+    fn dmabuf_state(&mut self) -> &mut DmabufState {
+        &mut self.compositor_state.dmabuf_state
+    }
+
+    #[instrument(skip(self, _global, notifier), level = "debug")]
+    fn dmabuf_imported(
+        &mut self,
+        _global: &DmabufGlobal,
+        dmabuf: Dmabuf,
+        notifier: ImportNotifier,
+    ) {
+        if dmabuf::is_readable(&dmabuf) {
+            notifier.successful::<Self>().log_and_ignore(loc!());
+        } else {
+            debug!("rejecting dmabuf {dmabuf:?}, it can't be read back");
+            notifier.failed();
+        }
+    }
+}
+
 impl SelectionHandler for WprsState {
     type SelectionUserData = ();
 
-    // We need to implement this trait for copying to clients, but all our
-    // clients are xwayland clients and so the methods below should never be
-    // called.
+    // X11 selections are handled by the xwm, so these are only called for
+    // Wayland clients connected directly to us rather than through xwayland.
 
-    #[instrument(skip(self, _seat), level = "debug")]
+    #[instrument(skip(self, seat), level = "debug")]
     fn new_selection(
         &mut self,
         ty: SelectionTarget,
         source: Option<SelectionSource>,
-        _seat: Seat<Self>,
+        seat: Seat<Self>,
     ) {
-        error!("new_selection called");
+        self.set_client_selection(ty, source.map(|source| source.mime_types()), seat);
     }
 
-    #[instrument(skip(self, _fd, _seat, _user_data), level = "debug")]
+    // Called for selections set by us, which mirror the local selection.
+    #[instrument(skip(self, fd, _seat, _user_data), level = "debug")]
     fn send_selection(
         &mut self,
         ty: SelectionTarget,
         mime_type: String,
-        _fd: OwnedFd,
+        fd: OwnedFd,
         _seat: Seat<Self>,
         _user_data: &Self::SelectionUserData,
     ) {
-        error!("new_selection called");
+        self.send_local_selection(ty, mime_type, fd);
     }
 }
 
@@ -328,9 +705,6 @@ impl PrimarySelectionHandler for WprsState {
     }
 }
 
-impl ClientDndGrabHandler for WprsState {}
-impl ServerDndGrabHandler for WprsState {}
-
 impl XWaylandShellHandler for WprsState {
     fn xwayland_shell_state(&mut self) -> &mut XWaylandShellState {
         &mut self.compositor_state.xwayland_shell_state
@@ -345,9 +719,19 @@ impl XWaylandShellHandler for WprsState {
     }
 }
 
-fn execute_or_defer_commit(state: &mut WprsState, surface: WlSurface) -> Result<()> {
+pub(crate) fn execute_or_defer_commit(state: &mut WprsState, surface: WlSurface) -> Result<()> {
     commit(&surface, state).location(loc!())?;
 
+    // the commit will be replayed once the parent is assigned a role.
+    if state
+        .compositor_state
+        .pending_parents
+        .contains_child(&surface)
+    {
+        debug!("commit waiting on parent");
+        return Ok(());
+    }
+
     let xwayland_surface = state.surfaces.get(&surface.id());
 
     // we may not have matched an X11 surface to the wayland surface yet.
@@ -361,12 +745,39 @@ fn execute_or_defer_commit(state: &mut WprsState, surface: WlSurface) -> Result<
     Ok(())
 }
 
+/// Checks on `child` while it's waiting on its parent and maps it without the
+/// parent if it waits too long, see pending_parents.
+fn watch_pending_child(event_loop_handle: &LoopHandle<'static, WprsState>, child: WlSurface) {
+    event_loop_handle
+        .insert_source(
+            Timer::from_duration(pending_parents::RETRY_INTERVAL),
+            move |_, _, state| match state.compositor_state.pending_parents.retry(&child) {
+                Retry::NotWaiting => TimeoutAction::Drop,
+                Retry::Waiting => TimeoutAction::ToDuration(pending_parents::RETRY_INTERVAL),
+                Retry::GaveUp => {
+                    warn!(
+                        "the parent of {:?} still has no role, mapping it without one",
+                        child.id()
+                    );
+                    execute_or_defer_commit(state, child.clone()).log_and_ignore(loc!());
+                    TimeoutAction::Drop
+                },
+            },
+        )
+        .map_err(|e| anyhow!("failed to insert pending parent timer: {e}"))
+        .log_and_ignore(loc!());
+}
+
 impl CompositorHandler for WprsState {
     fn compositor_state(&mut self) -> &mut CompositorState {
         &mut self.compositor_state.compositor_state
     }
 
     fn client_compositor_state<'a>(&self, client: &'a Client) -> &'a CompositorClientState {
+        #[cfg(test)]
+        if let Some(client_data) = client.get_data::<testing::FakeXwaylandData>() {
+            return &client_data.compositor_state;
+        }
         &client
             .get_data::<XWaylandClientData>()
             .unwrap()
@@ -376,6 +787,7 @@ impl CompositorHandler for WprsState {
     #[instrument(skip(self), level = "debug")]
     fn commit(&mut self, surface: &WlSurface) {
         execute_or_defer_commit(self, surface.clone()).log_and_ignore(loc!());
+        self.sync_pointer_constraints();
     }
 }
 
@@ -387,6 +799,21 @@ pub fn commit(surface: &WlSurface, state: &mut WprsState) -> Result<()> {
     })
     .location(loc!())?;
     on_commit_buffer_handler::<WprsState>(surface);
+
+    if state
+        .surfaces
+        .get(&surface.id())
+        .is_some_and(|xwayland_surface| xwayland_surface.role.is_some())
+    {
+        for child in state
+            .compositor_state
+            .pending_parents
+            .take_children(surface)
+        {
+            debug!("replaying commit for child {:?}", child.id());
+            execute_or_defer_commit(state, child).log_and_ignore(loc!());
+        }
+    }
     Ok(())
 }
 
@@ -411,82 +838,284 @@ pub(crate) struct X11Parent {
     pub(crate) for_subsurface: X11ParentForSubsurface,
 }
 
+/// The wl_surface xwayland associated with `x11_surface`, if any yet.
+fn paired_wl_surface(x11_surface: &X11Surface) -> Option<WlSurface> {
+    #[cfg(test)]
+    if let Some(window) = testing::FakeWindow::of(x11_surface) {
+        return Some(window.wl_surface.clone());
+    }
+    x11_surface.wl_surface()
+}
+
+/// The WM_TRANSIENT_FOR of `x11_surface`.
+fn transient_for(x11_surface: &X11Surface) -> Option<u32> {
+    #[cfg(test)]
+    if let Some(window) = testing::FakeWindow::of(x11_surface) {
+        return window.transient_for;
+    }
+    x11_surface.is_transient_for()
+}
+
+/// Returns the wl_surface of `x11_surface`'s parent if the parent exists but
+/// hasn't been assigned a role yet. This happens when a child is committed
+/// before its parent.
+fn find_pending_x11_parent(state: &WprsState, x11_surface: &X11Surface) -> Option<WlSurface> {
+    let parent_id = transient_for(x11_surface)?;
+    let committed = state
+        .surfaces
+        .values()
+        .filter(|xwls| xwls.role.is_none())
+        .filter_map(|xwls| xwls.x11_surface.as_ref());
+    // The parent may not have been committed at all yet. X11 clients draw
+    // independently of each other, so this is likely when it belongs to
+    // another client than the child.
+    let uncommitted = state.compositor_state.x11_surfaces.iter();
+    committed
+        .chain(uncommitted)
+        .find(|s| s.window_id() == parent_id)
+        .and_then(paired_wl_surface)
+}
+
+/// Follows WM_TRANSIENT_FOR from `window`, as looked up by `transient_for`,
+/// and returns the first window which is reached twice, if any.
+fn transient_for_cycle(window: u32, transient_for: impl Fn(u32) -> Option<u32>) -> Option<u32> {
+    let mut visited = HashSet::from([window]);
+    let mut next = transient_for(window);
+    while let Some(window) = next {
+        if !visited.insert(window) {
+            return Some(window);
+        }
+        next = transient_for(window);
+    }
+    None
+}
+
+/// The X11 window with id `window`, whether its surface was committed or not.
+fn x11_surface_by_window_id(state: &WprsState, window: u32) -> Option<&X11Surface> {
+    state
+        .surfaces
+        .values()
+        .filter_map(|xwls| xwls.x11_surface.as_ref())
+        .chain(state.compositor_state.x11_surfaces.iter())
+        .find(|s| s.window_id() == window)
+}
+
+/// Returns the parent of `x11_surface`, or None if it has no parent or the
+/// parent doesn't exist (anymore). Errors if its WM_TRANSIENT_FOR chain is a
+/// cycle.
 pub(crate) fn find_x11_parent(
     state: &WprsState,
     x11_surface: Option<X11Surface>,
+) -> Result<Option<X11Parent>> {
+    let Some(x11_surface) = x11_surface else {
+        return Ok(None);
+    };
+    let Some(parent_id) = transient_for(&x11_surface) else {
+        return Ok(None);
+    };
+    // x11_surface itself may not be tracked while its commit is handled.
+    if let Some(window) = transient_for_cycle(x11_surface.window_id(), |window| {
+        if window == x11_surface.window_id() {
+            return Some(parent_id);
+        }
+        transient_for(x11_surface_by_window_id(state, window)?)
+    }) {
+        bail!(
+            "the WM_TRANSIENT_FOR chain of window {} has a cycle through window {window}",
+            x11_surface.window_id()
+        );
+    }
+    let Some((parent_id, parent)) = state.surfaces.iter().find(|(_, xwls)| {
+        xwls.x11_surface
+            .as_ref()
+            .is_some_and(|s| s.window_id() == parent_id)
+    }) else {
+        error!("parent_id {parent_id:?} not found");
+        return Ok(None);
+    };
+    Ok(x11_parent_from_surface(parent_id, parent))
+}
+
+/// Builds the X11Parent for using `parent` as the parent of another surface.
+pub(crate) fn x11_parent_from_surface(
+    parent_id: &ObjectId,
+    parent: &XWaylandSurface,
 ) -> Option<X11Parent> {
-    if let Some(x11_surface) = &x11_surface {
-        if let Some(parent_id) = x11_surface.is_transient_for() {
-            let Some((parent_id, parent)) = state.surfaces.iter().find(|(_, xwls)| {
-                xwls.x11_surface
-                    .as_ref()
-                    .is_some_and(|s| s.window_id() == parent_id)
-            }) else {
-                error!("parent_id {parent_id:?} not found");
-                return None;
-            };
-
-            let Ok(parent_x11_surface) = parent.get_x11_surface() else {
-                error!("parent {parent:?} has no attached x11 surface");
-                return None;
-            };
-            let parent_geo = parent_x11_surface.geometry();
-
-            match &parent.role {
-                Some(Role::XdgToplevel(toplevel)) => Some(X11Parent {
-                    surface_id: parent_id.clone(),
-                    for_popup: Some(X11ParentForPopup {
-                        surface_id: parent_id.clone(),
-                        xdg_surface: toplevel.xdg_surface().clone(),
-                        x11_offset: (
-                            -parent_geo.loc.x + toplevel.frame_offset.x,
-                            -parent_geo.loc.y + toplevel.frame_offset.y,
-                        )
-                            .into(),
-                        wl_offset: (
-                            -parent_geo.loc.x + toplevel.frame_offset.x - toplevel.x11_offset.x,
-                            -parent_geo.loc.y + toplevel.frame_offset.y - toplevel.x11_offset.y,
-                        )
-                            .into(),
-                    }),
-                    for_subsurface: X11ParentForSubsurface {
-                        surface: toplevel.wl_surface().clone(),
-                        x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
-                    },
-                }),
-                Some(Role::XdgPopup(popup)) => Some(X11Parent {
-                    surface_id: parent_id.clone(),
-                    for_popup: Some(X11ParentForPopup {
-                        surface_id: parent_id.clone(),
-                        xdg_surface: popup.xdg_surface().clone(),
-                        x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
-                        wl_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
-                    }),
-                    for_subsurface: X11ParentForSubsurface {
-                        surface: popup.wl_surface().clone(),
-                        x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
-                    },
-                }),
-                Some(Role::SubSurface(subsurface)) => Some(X11Parent {
-                    surface_id: parent_id.clone(),
-                    for_popup: None, // subsurface cannot be parent to popup
-                    for_subsurface: X11ParentForSubsurface {
-                        surface: subsurface.wl_surface().clone(),
-                        x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
-                    },
-                }),
-                Some(Role::Cursor) => unreachable!("Cursors cannot have child surfaces."),
-                // TODO: fix this
-                None => unreachable!(
-                    "Parent doesn't yet have a role assigned. This is a race condition."
-                ),
-            }
-        } else {
+    let Ok(parent_x11_surface) = parent.get_x11_surface() else {
+        error!("parent {parent:?} has no attached x11 surface");
+        return None;
+    };
+    let parent_geo = parent_x11_surface.geometry();
+
+    match &parent.role {
+        Some(Role::XdgToplevel(toplevel)) => Some(X11Parent {
+            surface_id: parent_id.clone(),
+            for_popup: Some(X11ParentForPopup {
+                surface_id: parent_id.clone(),
+                xdg_surface: toplevel.xdg_surface().clone(),
+                x11_offset: (
+                    -parent_geo.loc.x + toplevel.frame_offset.x,
+                    -parent_geo.loc.y + toplevel.frame_offset.y,
+                )
+                    .into(),
+                wl_offset: (
+                    -parent_geo.loc.x + toplevel.frame_offset.x - toplevel.x11_offset.x,
+                    -parent_geo.loc.y + toplevel.frame_offset.y - toplevel.x11_offset.y,
+                )
+                    .into(),
+            }),
+            for_subsurface: X11ParentForSubsurface {
+                surface: toplevel.wl_surface().clone(),
+                x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
+            },
+        }),
+        Some(Role::XdgPopup(popup)) => Some(X11Parent {
+            surface_id: parent_id.clone(),
+            for_popup: Some(X11ParentForPopup {
+                surface_id: parent_id.clone(),
+                xdg_surface: popup.xdg_surface().clone(),
+                x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
+                wl_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
+            }),
+            for_subsurface: X11ParentForSubsurface {
+                surface: popup.wl_surface().clone(),
+                x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
+            },
+        }),
+        Some(Role::SubSurface(subsurface)) => Some(X11Parent {
+            surface_id: parent_id.clone(),
+            for_popup: None, // subsurface cannot be parent to popup
+            for_subsurface: X11ParentForSubsurface {
+                surface: subsurface.wl_surface().clone(),
+                x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
+            },
+        }),
+        Some(Role::LayerSurface(layer_surface)) => Some(X11Parent {
+            surface_id: parent_id.clone(),
+            // Layer surface popups are created through zwlr_layer_surface_v1
+            // rather than with an xdg_surface parent.
+            for_popup: None,
+            for_subsurface: X11ParentForSubsurface {
+                surface: layer_surface.wl_surface().clone(),
+                x11_offset: (-parent_geo.loc.x, -parent_geo.loc.y).into(),
+            },
+        }),
+        Some(Role::Cursor | Role::DragIcon) => {
+            unreachable!("Cursors and drag icons cannot have child surfaces.")
+        },
+        None => {
+            warn!(
+                "parent {parent_id:?} doesn't yet have a role assigned, mapping child without a parent"
+            );
             None
+        },
+    }
+}
+
+/// Re-evaluates the parent of `x11_surface` after its WM_TRANSIENT_FOR
+/// changed. Toplevels are reparented in place, other roles are tied to their
+/// parent's surface and have to be remapped. A cleared WM_TRANSIENT_FOR makes
+/// the surface parentless, which turns subsurfaces and popups into toplevels.
+#[instrument(skip(state), level = "debug")]
+pub(crate) fn update_x11_parent(state: &mut WprsState, x11_surface: &X11Surface) -> Result<()> {
+    let Some(surface) = x11_surface.wl_surface() else {
+        return Ok(());
+    };
+    // Surfaces without a role look up their parent when they get one.
+    let Some(xwayland_surface) = state
+        .surfaces
+        .get(&surface.id())
+        .filter(|xwls| xwls.role.is_some())
+    else {
+        return Ok(());
+    };
+
+    let old_parent_id = xwayland_surface
+        .parent
+        .as_ref()
+        .map(|parent| parent.surface_id.clone());
+    let new_parent = find_x11_parent(state, Some(x11_surface.clone())).location(loc!())?;
+    let new_parent_id = new_parent.as_ref().map(|parent| parent.surface_id.clone());
+    if old_parent_id == new_parent_id {
+        return Ok(());
+    }
+    if new_parent_id
+        .as_ref()
+        .is_some_and(|id| state.is_descendant(id, &surface.id()))
+    {
+        warn!("ignoring WM_TRANSIENT_FOR cycle for {:?}", surface.id());
+        return Ok(());
+    }
+    debug!(
+        "reparenting {:?} from {old_parent_id:?} to {new_parent_id:?}",
+        surface.id()
+    );
+
+    if !matches!(xwayland_surface.role, Some(Role::XdgToplevel(_))) {
+        return remap_x11_surface(state, &surface).location(loc!());
+    }
+
+    if let Some(old_parent_id) = &old_parent_id
+        && let Some(old_parent) = state.surfaces.get_mut(old_parent_id)
+    {
+        old_parent.children.remove(&surface.id());
+    }
+    // xdg_toplevel.set_parent only accepts toplevels.
+    let new_parent_window = new_parent_id.as_ref().and_then(|id| {
+        let new_parent = state.surfaces.get_mut(id)?;
+        new_parent.children.insert(surface.id());
+        match &new_parent.role {
+            Some(Role::XdgToplevel(toplevel)) => Some(toplevel.local_window.clone()),
+            _ => None,
         }
-    } else {
-        None
+    });
+
+    let xwayland_surface = state.surfaces.get_mut(&surface.id()).location(loc!())?;
+    xwayland_surface.parent = new_parent;
+    if let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role {
+        toplevel.local_window.set_parent(new_parent_window.as_ref());
     }
+    Ok(())
+}
+
+/// Destroys the local surfaces of `surface` and its descendants and maps them
+/// again from their current X11 state. The last buffer of each surface is kept
+/// so that they don't have to wait for the X11 client to redraw.
+fn remap_x11_surface(state: &mut WprsState, surface: &WlSurface) -> Result<()> {
+    let mut remapped = Vec::new();
+    let mut stack = vec![surface.id()];
+    while let Some(surface_id) = stack.pop() {
+        let Some(xwayland_surface) = state.surfaces.get_mut(&surface_id) else {
+            continue;
+        };
+        stack.extend(xwayland_surface.children.iter().cloned());
+        if let Some(x11_surface) = &xwayland_surface.x11_surface
+            && let Some(wl_surface) = x11_surface.wl_surface()
+        {
+            remapped.push((
+                wl_surface,
+                x11_surface.clone(),
+                xwayland_surface.buffer.take(),
+            ));
+        }
+    }
+
+    state.remove_surface(&surface.id());
+
+    // Parents come before their children, so they get their roles first.
+    for (wl_surface, x11_surface, buffer) in remapped {
+        state.admit_surface(&wl_surface).location(loc!())?;
+        state.compositor_state.x11_surfaces.push(x11_surface);
+        state.surfaces.insert(
+            wl_surface.id(),
+            XWaylandSurface {
+                buffer,
+                ..Default::default()
+            },
+        );
+        execute_or_defer_commit(state, wl_surface).log_and_ignore(loc!());
+    }
+    Ok(())
 }
 
 #[instrument(skip(state), level = "debug")]
@@ -502,11 +1131,53 @@ pub fn commit_inner(
         .compositor_state
         .x11_surfaces
         .iter()
-        .position(|x11s| x11s.wl_surface().map(|s| s == *surface).unwrap_or(false))
+        .position(|x11s| paired_wl_surface(x11s).is_some_and(|s| s == *surface))
         .map(|pos| state.compositor_state.x11_surfaces.swap_remove(pos));
     debug!("matched x11 surface: {x11_surface:?}");
 
-    let parent = find_x11_parent(state, x11_surface.clone());
+    if state.compositor_state.parent_race_behavior == ParentRaceBehavior::Queue
+        && !state.compositor_state.pending_parents.is_abandoned(surface)
+        && let Some(x11_surface) = x11_surface.as_ref()
+        && let Some(parent) = find_pending_x11_parent(state, x11_surface)
+    {
+        debug!(
+            "parent {:?} has no role yet, queueing child {:?}",
+            parent.id(),
+            surface.id()
+        );
+        state
+            .compositor_state
+            .x11_surfaces
+            .push(x11_surface.clone());
+        if state
+            .compositor_state
+            .pending_parents
+            .push(parent, surface.clone())
+        {
+            watch_pending_child(&state.event_loop_handle, surface.clone());
+        }
+
+        // The buffer is only guaranteed to hold what was committed until the
+        // next buffer is committed, after which the app may reuse it. Read it
+        // now, as the replayed commit may come later than that, and doesn't
+        // see the buffer at all as on_commit_buffer_handler takes it. This is
+        // regardless of early_buffer_behavior: the surface is already matched
+        // to its X11 window, it would have gotten its role with this commit if
+        // the parent had one.
+        if let Some(BufferAssignment::NewBuffer(buffer)) = &surface_attributes.buffer {
+            state.admit_surface(surface).location(loc!())?;
+            let xwayland_surface = state.surfaces.entry(surface.id()).or_default();
+            let pool = state.client_state.pool.as_mut().location(loc!())?;
+            compositor_utils::with_buffer_contents(buffer, |data, spec| {
+                xwayland_surface.update_buffer(&spec, data, pool)
+            })
+            .location(loc!())?
+            .location(loc!())?;
+        }
+        return Ok(());
+    }
+
+    let parent = find_x11_parent(state, x11_surface.clone()).location(loc!())?;
 
     if let (Some(parent), Some(_)) = (&parent, &x11_surface) {
         debug!(
@@ -514,16 +1185,57 @@ pub fn commit_inner(
             surface.id(),
             &parent.surface_id
         );
-        // We can still get cycles in the case of bugs in find_x11_parent, but
-        // this is a start.
-        assert!(
-            surface.id() != parent.surface_id,
-            "tried to register a surface as a child of itself"
-        );
-        let parent_xwayland_surface = state.surfaces.get_mut(&parent.surface_id).unwrap();
+        let parent_xwayland_surface = state
+            .surfaces
+            .get_mut(&parent.surface_id)
+            .location(loc!())?;
         parent_xwayland_surface.children.insert(surface.id());
     }
 
+    // X11 menus take an xdg_popup grab so that we find out when they should be
+    // dismissed. Grabbing popups must be stacked on the topmost grabbing popup.
+    let grab_menu = state.compositor_state.popup_grab_behavior == PopupGrabBehavior::Dismiss
+        && parent.is_none()
+        && x11_surface.as_ref().is_some_and(popup_grab::is_menu);
+    let fallback_parent = if grab_menu {
+        state
+            .popup_grab_parent()
+            .or_else(|| state.client_state.last_focused_window.clone())
+    } else {
+        state.client_state.last_focused_window.clone()
+    };
+
+    // Only looked up for surfaces which are about to get a role, as they're
+    // round trips to the X server.
+    let needs_role = parent.is_none()
+        && state
+            .surfaces
+            .get(&surface.id())
+            .is_none_or(|xwls| xwls.role.is_none());
+    let layer_placement = match &x11_surface {
+        Some(x11_surface) if needs_role => state.layer_placement(x11_surface),
+        _ => None,
+    };
+    let frame_extents = match &x11_surface {
+        Some(x11_surface) if needs_role && layer_placement.is_none() => {
+            state.gtk_frame_extents(x11_surface)
+        },
+        _ => None,
+    };
+    let pending_window_state = match &x11_surface {
+        Some(x11_surface) if needs_role && layer_placement.is_none() => {
+            state.take_pending_window_state(x11_surface)
+        },
+        _ => None,
+    };
+    let session_properties = match &x11_surface {
+        Some(x11_surface) if needs_role && layer_placement.is_none() => {
+            state.session_properties(x11_surface)
+        },
+        _ => None,
+    };
+
+    state.admit_surface(surface).location(loc!())?;
     let xwayland_surface = state.surfaces.entry(surface.id()).or_default();
 
     if let Some(x11_surface) = x11_surface {
@@ -539,46 +1251,228 @@ pub fn commit_inner(
                 .location(loc!())?;
         }
 
-        if let Some(x11_offset) = state.compositor_state.x11_screen_offset {
+        if let Some(x11_offset) = state
+            .compositor_state
+            .no_output_behavior
+            .x11_offset(state.compositor_state.x11_screen_offset)
+        {
+            let had_role = xwayland_surface.role.is_some();
+            if !had_role && layer_placement.is_none() {
+                xwayland_surface.scale_override = state
+                    .compositor_state
+                    .scale_overrides
+                    .get(&x11_surface.class());
+            }
+            // Only used when the role is assigned.
+            let decoration_behavior = state.compositor_state.decoration_rules.behavior(
+                &x11_surface.class(),
+                &x11_surface.instance(),
+                state.compositor_state.decoration_behavior,
+            );
             xwayland_surface
                 .update_x11_surface(
                     x11_surface,
                     x11_offset,
                     parent,
-                    &state.client_state.last_focused_window,
-                    &state.client_state.xdg_shell_state,
-                    &state.client_state.shm_state,
-                    state.client_state.subcompositor_state.clone(),
-                    &state.client_state.qh,
-                    state.compositor_state.decoration_behavior,
+                    &fallback_parent,
+                    layer_placement,
+                    &state.client_state,
+                    decoration_behavior,
+                    state.compositor_state.tiling_mode,
                 )
                 .location(loc!())?;
+
+            if !had_role
+                && let Some(Role::XdgToplevel(toplevel)) = &mut xwayland_surface.role
+                && let Some(x11_surface) = &xwayland_surface.x11_surface
+            {
+                // Before the first configure, which applies the decorations.
+                toplevel.frame_extents = frame_extents;
+                toplevel.maximized_frame = state.compositor_state.maximized_frame;
+                toplevel
+                    .local_window
+                    .set_title(state.compositor_state.window_title(x11_surface));
+                toplevel
+                    .local_window
+                    .set_app_id(state.compositor_state.window_app_id(x11_surface));
+                if let Some(pending_window_state) = &pending_window_state {
+                    pending_window_state.apply(&toplevel.local_window);
+                }
+                if let Some(session_properties) = session_properties {
+                    state.session_windows.record(
+                        surface.id(),
+                        x11_surface,
+                        session_properties.clone(),
+                    );
+                    xwayland_surface.session = Some(session_properties);
+                }
+                startup::complete(
+                    state.client_state.activation_state.as_ref(),
+                    x11_surface,
+                    toplevel.wl_surface(),
+                );
+                // Applied with the commit of the first buffer.
+                state.compositor_state.window_opacities.apply(
+                    x11_surface.window_id(),
+                    toplevel.wl_surface(),
+                    state.client_state.alpha_modifier.as_ref(),
+                    &state.client_state.qh,
+                );
+            }
+
+            if !had_role && xwayland_surface.needs_configure() {
+                configure_timeout::watch_initial_configure(
+                    &state.event_loop_handle,
+                    state.compositor_state.configure_timeout,
+                    surface.id(),
+                );
+            }
+
+            // A buffer retained from before the role was assigned was never
+            // displayed, and its damage may only cover part of it.
+            if !had_role && xwayland_surface.role.is_some() && xwayland_surface.buffer.is_some() {
+                xwayland_surface.damage = Some(vec![Rectangle::new(0, 0, i32::MAX, i32::MAX)]);
+            }
+
+            // TODO: support multiple seats
+            if grab_menu
+                && let Some(Role::XdgPopup(popup)) = &xwayland_surface.role
+                && let Some(seat_obj) = state.client_state.seat_objects.last()
+                && let Some(serial) = state.client_state.last_button_press_serial
+            {
+                debug!("taking popup grab for {:?}", surface.id());
+                popup.local_popup.xdg_popup().grab(&seat_obj.seat, serial);
+                state.client_state.popup_grab_stack.push(surface.id());
+            }
+        } else {
+            // Replayed once the first output appears, see no_output.
+            debug!("no output yet, holding {:?}", surface.id());
+            state.compositor_state.x11_surfaces.push(x11_surface);
+            let awaiting_output = &mut state.compositor_state.surfaces_awaiting_output;
+            if !awaiting_output.contains(surface) {
+                awaiting_output.push(surface.clone());
+            }
         }
     }
 
+    let buffer_size = match &surface_attributes.buffer {
+        Some(BufferAssignment::NewBuffer(buffer)) => compositor_utils::buffer_size(buffer),
+        Some(BufferAssignment::Removed) => None,
+        None => xwayland_surface
+            .buffer
+            .as_ref()
+            .map(|buffer| (buffer.metadata.width, buffer.metadata.height).into()),
+    };
+    let buffer_scale = buffer_size.map_or(surface_attributes.buffer_scale, |buffer_size| {
+        compositor_utils::clamp_buffer_scale(
+            surface,
+            &surface_data.data_map,
+            buffer_size,
+            surface_attributes.buffer_scale,
+        )
+    });
+    let viewport_state = ViewportState::from(
+        &*surface_data
+            .cached_state
+            .get::<ViewportCachedState>()
+            .current(),
+    );
+    let viewported = viewport::is_viewported(&viewport_state);
+    let damage: &mut Vec<_> = &mut mem::take(&mut surface_attributes.damage)
+        .iter()
+        .map(|damage| match damage {
+            // TODO: map surface damage through the viewport instead.
+            Damage::Surface(_) if viewported => Rectangle::new(0, 0, i32::MAX, i32::MAX),
+            damage => compositor_utils::damage_to_buffer(
+                damage,
+                buffer_scale,
+                surface_attributes.buffer_transform.into(),
+                buffer_size,
+            ),
+        })
+        .map(|damage| match xwayland_surface.scale_override {
+            Some(scale_override) => scale_override.scale_damage(&damage),
+            None => damage,
+        })
+        .collect();
+
     debug!("buffer assignment: {:?}", &surface_attributes.buffer);
 
+    // Whether the client re-committed a buffer identical to the one we last
+    // sent. If so, the previously attached buffer is left in place and only
+    // the frame callback is forwarded.
+    let mut unchanged = false;
     match &surface_attributes.buffer {
+        Some(BufferAssignment::NewBuffer(_))
+            if !state
+                .compositor_state
+                .early_buffer_behavior
+                .keep_buffer(xwayland_surface.role.is_some()) =>
+        {
+            debug!("discarding buffer committed before role assignment");
+            damage.clear();
+        },
         Some(BufferAssignment::NewBuffer(buffer)) => {
+            let pool = state.client_state.pool.as_mut().location(loc!())?;
             compositor_utils::with_buffer_contents(buffer, |data, spec| {
-                xwayland_surface.update_buffer(
-                    &spec,
-                    data,
-                    state.client_state.pool.as_mut().location(loc!())?,
-                )
+                xwayland_surface.update_buffer(&spec, data, pool)
             })
             .location(loc!())?
             .location(loc!())?;
 
-            xwayland_surface.buffer_attached = false;
+            if state.compositor_state.skip_unchanged_commits
+                && let Some(buffer) = &xwayland_surface.buffer
+            {
+                let hash = buffer.content_hash(damage, pool);
+                unchanged = xwayland_surface.ready()
+                    && xwayland_surface.buffer_attached
+                    && hash.is_some()
+                    && hash == xwayland_surface.last_commit_hash;
+                xwayland_surface.last_commit_hash = hash;
+            }
+
+            if unchanged {
+                debug!("buffer contents unchanged, skipping");
+                damage.clear();
+            } else {
+                xwayland_surface.buffer_attached = false;
+            }
         },
         Some(BufferAssignment::Removed) => {
             xwayland_surface.buffer = None;
+            xwayland_surface.last_commit_hash = None;
             xwayland_surface.wl_surface().attach(None, 0, 0);
         },
         None => {},
     }
 
+    // Surfaces get a local surface once they're matched with an X11 window,
+    // the viewport and input region are forwarded with the first commit after
+    // that.
+    if xwayland_surface.role.is_some() || xwayland_surface.local_surface.is_some() {
+        let local_wl_surface = xwayland_surface.wl_surface().clone();
+        let scale = xwayland_surface
+            .scale_override
+            .map_or(1, |scale_override| scale_override.scale);
+        xwayland_surface.viewport.update(
+            viewport_state,
+            scale,
+            &local_wl_surface,
+            state.client_state.wp_viewporter.as_ref(),
+            &state.client_state.qh,
+        );
+        xwayland_surface
+            .input_region
+            .update(
+                surface_attributes.input_region.as_ref(),
+                state.compositor_state.empty_input_region_behavior,
+                scale,
+                &local_wl_surface,
+                &state.client_state.compositor_state,
+            )
+            .location(loc!())?;
+    }
+
     if let Some(Role::XdgToplevel(toplevel)) = &mut xwayland_surface.role
         && toplevel.configured
         && toplevel.window_frame.is_dirty()
@@ -593,51 +1487,64 @@ pub fn commit_inner(
         decorated_subsurface.draw();
     }
 
-    let damage = &mut mem::take(&mut surface_attributes.damage)
-        .iter()
-        .map(|damage| match damage {
-            Damage::Buffer(rect) => *rect,
-            Damage::Surface(rect) => rect.to_buffer(
-                surface_attributes.buffer_scale,
-                surface_attributes.buffer_transform.into(),
-                &rect.size,
-            ),
-        })
-        .map(Into::into)
-        .collect();
-
     if let Some(surface_damage) = &mut xwayland_surface.damage {
         surface_damage.append(damage);
     } else {
         xwayland_surface.damage = Some(damage.clone());
     }
 
+    let hold_stale_frame = xwayland_surface.ready()
+        && xwayland_surface.hold_stale_frame(state.compositor_state.mode_change_behavior);
+    let hold_unsynced_frame = xwayland_surface.ready()
+        && xwayland_surface
+            .x11_surface
+            .as_ref()
+            .and_then(sync_request::pending_sync)
+            .is_some();
     if xwayland_surface.ready() {
-        if let Some(Role::SubSurface(subsurface)) = &mut xwayland_surface.role {
-            if !subsurface.pending_frame_callback {
+        if hold_stale_frame {
+            // Sent once the app resizes or the hold times out, see mode_change.
+            debug!("frame has the size from before an output mode change, holding frame");
+        } else if hold_unsynced_frame {
+            // Sent once the app updates its sync counter or the hold times
+            // out, see sync_request.
+            debug!("app hasn't finished drawing at its new size, holding frame");
+        } else if !xwayland_surface
+            .frames_in_flight
+            .can_send(state.compositor_state.max_frames_in_flight)
+        {
+            // Sent once a frame callback arrives, see frame_limit.
+            debug!("too many frames in flight, holding frame");
+        } else {
+            if let Some(Role::SubSurface(subsurface)) = &mut xwayland_surface.role {
+                if !subsurface.pending_frame_callback {
+                    xwayland_surface.frame(&state.client_state.qh);
+                }
+            } else {
                 xwayland_surface.frame(&state.client_state.qh);
             }
-        } else {
-            xwayland_surface.frame(&state.client_state.qh);
-        }
 
-        xwayland_surface.try_draw_buffer();
+            xwayland_surface.try_draw_buffer();
+        }
     }
 
     if xwayland_surface.ready() || xwayland_surface.needs_configure() {
         xwayland_surface.commit();
     }
 
-    if xwayland_surface.x11_surface.is_none() || matches!(xwayland_surface.role, Some(Role::Cursor))
+    // No local frame callback was requested for a held frame, so let the app
+    // render its next frame, see frame_pacing.
+    if xwayland_surface.x11_surface.is_none()
+        || matches!(xwayland_surface.role, Some(Role::Cursor | Role::DragIcon))
+        || hold_stale_frame
+        || hold_unsynced_frame
     {
-        compositor_utils::send_frames(
-            surface,
-            &surface_data.data_map,
-            surface_attributes,
-            state.compositor_state.start_time.elapsed(),
-            Duration::ZERO,
-        )
-        .location(loc!())?;
+        let frame_interval = state
+            .compositor_state
+            .frame_interval(&xwayland_surface.output_ids);
+        state
+            .send_paced_frames(surface, surface_data, surface_attributes, frame_interval)
+            .location(loc!())?;
     }
     Ok(())
 }
@@ -657,18 +1564,18 @@ impl SeatHandler for WprsState {
         &mut self.compositor_state.seat_state
     }
 
-    #[instrument(skip(self, _seat), level = "debug")]
-    fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {
-        // TODO: support multiple seats
-        let themed_pointer = self
-            .client_state
-            .seat_objects
-            .last()
-            .unwrap()
-            .pointer
-            .as_ref()
-            .unwrap();
-        let pointer = themed_pointer.pointer();
+    #[instrument(skip(self, seat), level = "debug")]
+    fn cursor_image(&mut self, seat: &Seat<Self>, image: CursorImageStatus) {
+        let themed_pointer = log_and_return!(seat::themed_pointer(&self.client_state, seat));
+        let pointer = themed_pointer.pointer().clone();
+
+        let cursor_surface = match &image {
+            CursorImageStatus::Surface(surface) => Some(surface.id()),
+            _ => None,
+        };
+        log_and_return!(self.compositor_state.seat_mut(seat.name()))
+            .cursor_surface
+            .set(cursor_surface);
 
         // TODO: move to a fn on serialization::CursorImaveStatus
         match image {
@@ -686,6 +1593,7 @@ impl SeatHandler for WprsState {
                         .hotspot
                 });
 
+                log_and_return!(self.admit_surface(&surface));
                 let xwayland_surface = log_and_return!(
                     self.surfaces.entry(surface.id()).or_insert_with_result(|| {
                         XWaylandSurface::new(
@@ -709,9 +1617,14 @@ impl SeatHandler for WprsState {
                 );
             },
             CursorImageStatus::Named(name) => {
-                themed_pointer
-                    .set_cursor(&self.client_state.conn, name)
-                    .log_and_ignore(loc!());
+                if !log_and_return!(self.set_override_cursor(&pointer, name)) {
+                    // Re-borrow, set_override_cursor needs all of self.
+                    let themed_pointer =
+                        log_and_return!(seat::themed_pointer(&self.client_state, seat));
+                    themed_pointer
+                        .set_cursor(&self.client_state.conn, name)
+                        .log_and_ignore(loc!());
+                }
             },
         }
     }
@@ -721,8 +1634,68 @@ impl OutputHandler for WprsState {}
 
 smithay::delegate_compositor!(WprsState);
 smithay::delegate_shm!(WprsState);
+smithay::delegate_dmabuf!(WprsState);
 smithay::delegate_seat!(WprsState);
 smithay::delegate_data_device!(WprsState);
 smithay::delegate_output!(WprsState);
 smithay::delegate_primary_selection!(WprsState);
 smithay::delegate_xwayland_shell!(WprsState);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_decorations_prefer_server_side() {
+        assert_eq!(
+            DecorationBehavior::Auto.window_decorations(),
+            WindowDecorations::RequestServer
+        );
+        assert_eq!(
+            DecorationBehavior::AlwaysEnabled.window_decorations(),
+            WindowDecorations::ServerDefault
+        );
+    }
+
+    #[test]
+    fn transient_for_chain_without_cycle() {
+        let transient_for = HashMap::from([(3, 2), (2, 1)]);
+        assert_eq!(
+            transient_for_cycle(3, |window| transient_for.get(&window).copied()),
+            None
+        );
+        // The parent isn't known.
+        let transient_for = HashMap::from([(4, 5)]);
+        assert_eq!(
+            transient_for_cycle(4, |window| transient_for.get(&window).copied()),
+            None
+        );
+    }
+
+    #[test]
+    fn transient_for_cycles_are_detected() {
+        assert_eq!(transient_for_cycle(1, |_| Some(1)), Some(1));
+
+        let transient_for = HashMap::from([(1, 2), (2, 1)]);
+        assert_eq!(
+            transient_for_cycle(1, |window| transient_for.get(&window).copied()),
+            Some(1)
+        );
+
+        // The cycle doesn't include the window itself.
+        let transient_for = HashMap::from([(4, 3), (3, 2), (2, 3)]);
+        assert_eq!(
+            transient_for_cycle(4, |window| transient_for.get(&window).copied()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn frame_of_maximized_windows() {
+        assert!(!MaximizedFrame::Shown.hides_frame(true));
+        assert!(!MaximizedFrame::Shown.hides_frame(false));
+        assert!(MaximizedFrame::Hidden.hides_frame(true));
+        // The frame comes back when the window is unmaximized.
+        assert!(!MaximizedFrame::Hidden.hides_frame(false));
+    }
+}
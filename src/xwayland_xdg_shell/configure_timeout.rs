@@ -0,0 +1,110 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Recovery from missing initial configures. Local compositors send the
+/// initial configure of a toplevel, popup, or layer surface in response to its
+/// initial commit, and the surface stays unconfigured (and its window
+/// invisible) until it arrives. Attaching a buffer before acking a configure is
+/// a protocol error, so the window can't be shown without one. Instead, if the
+/// configure doesn't arrive in time, the initial commit is repeated, with
+/// increasing delays, until the local compositor configures the surface.
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay::reexports::wayland_server::backend::ObjectId;
+use smithay_client_toolkit::shell::WaylandSurface;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+
+/// The delay between retries stops growing at this multiple of the timeout.
+const MAX_BACKOFF_FACTOR: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ConfigureTimeout {
+    /// Wait for the initial configure indefinitely.
+    Disabled,
+    Enabled {
+        /// How long to wait for the initial configure before committing the
+        /// surface again.
+        timeout_ms: u32,
+    },
+}
+
+/// The delay before retry number `retry` (starting at 0) of the initial commit.
+fn retry_delay(timeout: Duration, retry: u32) -> Duration {
+    timeout * 2u32.saturating_pow(retry).min(MAX_BACKOFF_FACTOR)
+}
+
+/// Repeats the initial commit of the local surface for `surface_id` until it's
+/// configured, see the module documentation.
+pub(crate) fn watch_initial_configure(
+    event_loop_handle: &LoopHandle<'static, WprsState>,
+    configure_timeout: ConfigureTimeout,
+    surface_id: ObjectId,
+) {
+    let ConfigureTimeout::Enabled { timeout_ms } = configure_timeout else {
+        return;
+    };
+    let timeout = Duration::from_millis(timeout_ms.into());
+
+    let mut retry = 0;
+    event_loop_handle
+        .insert_source(
+            Timer::from_duration(retry_delay(timeout, retry)),
+            move |_, _, state| {
+                let Some(xwayland_surface) = state.surfaces.get(&surface_id) else {
+                    return TimeoutAction::Drop;
+                };
+                if !xwayland_surface.needs_configure() {
+                    return TimeoutAction::Drop;
+                }
+                warn!(
+                    "the local compositor didn't configure {surface_id:?} in time, committing it again (retry {retry})"
+                );
+                xwayland_surface.wl_surface().commit();
+                retry += 1;
+                TimeoutAction::ToDuration(retry_delay(timeout, retry))
+            },
+        )
+        .map_err(|e| anyhow!("failed to insert configure timeout timer: {e}"))
+        .log_and_ignore(loc!());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_until_the_cap() {
+        let timeout = Duration::from_millis(500);
+        // A surface which is never configured is committed again after each
+        // of these delays.
+        let delays: Vec<_> = (0..6).map(|retry| retry_delay(timeout, retry)).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 4000, 4000].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn many_retries_dont_overflow() {
+        let timeout = Duration::from_millis(500);
+        assert_eq!(retry_delay(timeout, u32::MAX), Duration::from_millis(4000));
+    }
+}
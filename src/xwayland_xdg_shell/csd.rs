@@ -0,0 +1,177 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Client-side decorated X11 windows. GTK draws its own title bar and a drop
+/// shadow around it, and advertises the size of the shadow with
+/// _GTK_FRAME_EXTENTS. With DecorationBehavior::Auto, such windows (and ones
+/// asking not to be decorated with _MOTIF_WM_HINTS) get no frame from us, and
+/// we ask the local compositor not to decorate them either.
+///
+/// The shadow is part of the X11 window, so it's kept out of the window
+/// geometry and the sizes the local compositor configures are grown by it. The
+/// local surface still covers the whole X11 window, so input coordinates map
+/// onto the X11 window unchanged, including over the shadow, which GTK uses
+/// for resizing. GTK drops the shadow of maximized, fullscreen and tiled
+/// windows, so it's ignored for those.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::utils::Logical;
+use smithay::utils::Rectangle;
+use smithay::xwayland::X11Surface;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum CsdDetection {
+    /// Treat windows with _GTK_FRAME_EXTENTS or undecorated _MOTIF_WM_HINTS as
+    /// client-side decorated.
+    #[default]
+    GtkFrameExtents,
+    /// Only look at _MOTIF_WM_HINTS.
+    MotifHints,
+}
+
+/// The width of the shadow on each side of a client-side decorated window.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct FrameExtents {
+    pub left: i32,
+    pub right: i32,
+    pub top: i32,
+    pub bottom: i32,
+}
+
+impl FrameExtents {
+    /// Parses a _GTK_FRAME_EXTENTS, which is left, right, top, bottom.
+    fn from_property(values: &[u32]) -> Option<Self> {
+        let [left, right, top, bottom] = values.try_into().ok()?;
+        Some(Self {
+            left: i32::try_from(left).ok()?,
+            right: i32::try_from(right).ok()?,
+            top: i32::try_from(top).ok()?,
+            bottom: i32::try_from(bottom).ok()?,
+        })
+    }
+
+    /// The size of a window whose visible part is `width`x`height`.
+    pub fn grow(&self, (width, height): (i32, i32)) -> (i32, i32) {
+        (
+            width.saturating_add(self.left + self.right),
+            height.saturating_add(self.top + self.bottom),
+        )
+    }
+
+    /// The visible part of a `width`x`height` window, at least one pixel.
+    pub fn visible(&self, (width, height): (i32, i32)) -> Rectangle<i32, Logical> {
+        Rectangle::new(
+            (self.left, self.top).into(),
+            (
+                (width - self.left - self.right).max(1),
+                (height - self.top - self.bottom).max(1),
+            )
+                .into(),
+        )
+    }
+}
+
+/// Reads the _GTK_FRAME_EXTENTS of `window`, if it has one.
+fn fetch_gtk_frame_extents(conn: &X11Connection, window: u32) -> Result<Option<FrameExtents>> {
+    let atom = conn
+        .intern_atom(true, b"_GTK_FRAME_EXTENTS")
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .atom;
+    if atom == u32::from(AtomEnum::NONE) {
+        return Ok(None);
+    }
+    let values: Vec<u32> = conn
+        .get_property(false, window, atom, AtomEnum::CARDINAL, 0, 4)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .value32()
+        .into_iter()
+        .flatten()
+        .collect();
+    Ok(FrameExtents::from_property(&values))
+}
+
+impl WprsState {
+    /// The _GTK_FRAME_EXTENTS of `x11_surface` if it's client-side decorated
+    /// by GTK. It's a round trip to the X server, so only call this for
+    /// windows which are about to get a role.
+    pub(crate) fn gtk_frame_extents(&self, x11_surface: &X11Surface) -> Option<FrameExtents> {
+        if self.compositor_state.csd_detection != CsdDetection::GtkFrameExtents
+            || x11_surface.is_override_redirect()
+        {
+            return None;
+        }
+        let conn = self.compositor_state.x11_conn.as_ref()?;
+        fetch_gtk_frame_extents(conn, x11_surface.window_id())
+            .warn(loc!())
+            .ok()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENTS: FrameExtents = FrameExtents {
+        left: 10,
+        right: 12,
+        top: 4,
+        bottom: 20,
+    };
+
+    #[test]
+    fn property_is_left_right_top_bottom() {
+        assert_eq!(FrameExtents::from_property(&[10, 12, 4, 20]), Some(EXTENTS));
+        assert_eq!(FrameExtents::from_property(&[10, 12, 4]), None);
+        assert_eq!(FrameExtents::from_property(&[]), None);
+    }
+
+    #[test]
+    fn grown_window_has_visible_part_of_original_size() {
+        let size = EXTENTS.grow((800, 600));
+        assert_eq!(size, (822, 624));
+        assert_eq!(
+            EXTENTS.visible(size),
+            Rectangle::new((10, 4).into(), (800, 600).into())
+        );
+    }
+
+    #[test]
+    fn visible_part_is_never_empty() {
+        assert_eq!(
+            EXTENTS.visible((5, 5)),
+            Rectangle::new((10, 4).into(), (1, 1).into())
+        );
+    }
+
+    #[test]
+    fn no_extents_change_nothing() {
+        let extents = FrameExtents::default();
+        assert_eq!(extents.grow((800, 600)), (800, 600));
+        assert_eq!(
+            extents.visible((800, 600)),
+            Rectangle::new((0, 0).into(), (800, 600).into())
+        );
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Cursor theme selection for named cursors. Applications can be given their
+/// own cursor theme, keyed by WM_CLASS; everything else uses the global theme
+/// of the themed pointer. The theme and base size default to XCURSOR_THEME and
+/// XCURSOR_SIZE. Cursors are loaded at the base size times the integer scale
+/// of the output they're shown on, so that they keep their size relative to
+/// windows on HiDPI outputs: the themed pointer scales with the outputs its
+/// cursor surface is on, override cursors with those of the window under the
+/// pointer.
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::env;
+use std::iter;
+
+use smithay_client_toolkit::compositor::Surface;
+use smithay_client_toolkit::reexports::client::protocol::wl_pointer::WlPointer;
+use smithay_client_toolkit::reexports::csd_frame::CursorIcon;
+use smithay_client_toolkit::seat::pointer::ThemeSpec;
+use wayland_cursor::CursorTheme;
+
+use crate::fallible_entry::FallibleEntryExt;
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+
+pub const DEFAULT_CURSOR_THEME: &str = "default";
+pub const DEFAULT_CURSOR_SIZE: u32 = 24;
+
+/// The theme and size to use, from the options if given, otherwise from the
+/// XCURSOR_* variables as read by `var`.
+fn resolve(
+    theme: Option<String>,
+    size: Option<u32>,
+    var: impl Fn(&str) -> Option<String>,
+) -> (String, u32) {
+    let theme = theme
+        .or_else(|| var("XCURSOR_THEME"))
+        .filter(|theme| !theme.is_empty())
+        .unwrap_or_else(|| DEFAULT_CURSOR_THEME.to_string());
+    let size = size
+        .or_else(|| var("XCURSOR_SIZE")?.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_CURSOR_SIZE);
+    (theme, size)
+}
+
+#[derive(Debug)]
+pub struct CursorThemes {
+    theme: String,
+    size: u32,
+    /// WM_CLASS -> cursor theme name.
+    overrides: BTreeMap<String, String>,
+    /// Keyed by theme name and scale.
+    loaded: HashMap<(String, u32), CursorTheme>,
+    surface: Option<Surface>,
+}
+
+impl CursorThemes {
+    pub fn new(
+        theme: Option<String>,
+        size: Option<u32>,
+        overrides: BTreeMap<String, String>,
+    ) -> Self {
+        let (theme, size) = resolve(theme, size, |name| env::var(name).ok());
+        Self {
+            theme,
+            size,
+            overrides,
+            loaded: HashMap::new(),
+            surface: None,
+        }
+    }
+
+    /// The spec for the global theme, used when no override matches.
+    pub(crate) fn theme_spec(&self) -> ThemeSpec<'_> {
+        ThemeSpec::Named {
+            name: &self.theme,
+            size: self.size,
+        }
+    }
+}
+
+impl WprsState {
+    /// Sets `icon` on `pointer` from the cursor theme override for the window
+    /// under the pointer. Returns false if there is no override for that window or the
+    /// override theme doesn't have the icon, in which case the global theme
+    /// should be used.
+    pub(crate) fn set_override_cursor(
+        &mut self,
+        pointer: &WlPointer,
+        icon: CursorIcon,
+    ) -> Result<bool> {
+        let client_state = &mut self.client_state;
+        let cursor_themes = &mut client_state.cursor_themes;
+        let Some(theme_name) = client_state
+            .pointer_window_class
+            .as_ref()
+            .and_then(|class| cursor_themes.overrides.get(class))
+        else {
+            return Ok(false);
+        };
+
+        let scale = client_state.pointer_window_scale.max(1);
+        let theme = cursor_themes
+            .loaded
+            .entry((theme_name.clone(), scale as u32))
+            .or_insert_with_result(|| {
+                CursorTheme::load_from_name(
+                    &client_state.conn,
+                    client_state.shm_state.wl_shm().clone(),
+                    theme_name,
+                    cursor_themes.size * scale as u32,
+                )
+            })
+            .location(loc!())?;
+
+        let Some(cursor_name) = iter::once(icon.name())
+            .chain(icon.alt_names().iter().copied())
+            .find(|name| theme.get_cursor(name).is_some())
+        else {
+            debug!("cursor {icon:?} not found in theme {theme_name:?}");
+            return Ok(false);
+        };
+        let cursor = theme.get_cursor(cursor_name).location(loc!())?;
+
+        let surface = match &mut cursor_themes.surface {
+            Some(surface) => surface,
+            surface @ None => surface.insert(
+                Surface::new(&client_state.compositor_state, &client_state.qh).location(loc!())?,
+            ),
+        };
+
+        let image = &cursor[0];
+        let (w, h) = image.dimensions();
+        let (hx, hy) = image.hotspot();
+        let wl_surface = surface.wl_surface();
+        wl_surface.set_buffer_scale(scale);
+        wl_surface.attach(Some(image), 0, 0);
+        wl_surface.damage_buffer(0, 0, w as i32, h as i32);
+        wl_surface.commit();
+        pointer.set_cursor(
+            client_state.last_enter_serial,
+            Some(wl_surface),
+            hx as i32 / scale,
+            hy as i32 / scale,
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_take_precedence_over_environment() {
+        let var = |name: &str| match name {
+            "XCURSOR_THEME" => Some("Adwaita".to_string()),
+            "XCURSOR_SIZE" => Some("48".to_string()),
+            _ => None,
+        };
+        assert_eq!(resolve(None, None, var), ("Adwaita".to_string(), 48));
+        assert_eq!(
+            resolve(Some("breeze".to_string()), Some(32), var),
+            ("breeze".to_string(), 32)
+        );
+    }
+
+    #[test]
+    fn invalid_environment_falls_back_to_defaults() {
+        let var = |name: &str| match name {
+            "XCURSOR_THEME" => Some(String::new()),
+            "XCURSOR_SIZE" => Some("big".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            resolve(None, None, var),
+            (DEFAULT_CURSOR_THEME.to_string(), DEFAULT_CURSOR_SIZE)
+        );
+        assert_eq!(
+            resolve(None, None, |_| None),
+            (DEFAULT_CURSOR_THEME.to_string(), DEFAULT_CURSOR_SIZE)
+        );
+    }
+}
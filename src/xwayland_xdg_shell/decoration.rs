@@ -28,6 +28,8 @@ use crate::xwayland_xdg_shell::client::Role;
 use crate::xwayland_xdg_shell::client::WprsClientState;
 use crate::xwayland_xdg_shell::client::XWaylandSubSurface;
 use crate::xwayland_xdg_shell::client::XWaylandXdgToplevel;
+use crate::xwayland_xdg_shell::frame_buttons::FrameButtons;
+use crate::xwayland_xdg_shell::frame_buttons::X11Action;
 use crate::xwayland_xdg_shell::xsurface_from_client_surface;
 
 fn parent(surface: &WlSurface) -> Option<&WlSurface> {
@@ -102,6 +104,7 @@ pub trait FramedSurface {
         serial: Serial,
         action: FrameAction,
         position: (f64, f64),
+        frame_buttons: &FrameButtons,
     ) -> Result<()>;
 
     fn frame(&mut self) -> &mut FallbackFrame<WprsState>;
@@ -134,14 +137,14 @@ pub trait FramedSurface {
             // when entering a surface, the current cursor is always undefined
             if new_cursor != cur_cursor || matches!(event.kind, PointerEventKind::Enter { .. }) {
                 client_state.cursor_icon = Some(new_cursor);
-                let _ = client_state
+                if let Some(themed_pointer) = client_state
                     .seat_objects
-                    .last()
-                    .unwrap()
-                    .pointer
-                    .as_ref()
-                    .unwrap()
-                    .set_cursor(conn, new_cursor);
+                    .iter()
+                    .filter_map(|seat_obj| seat_obj.pointer.as_ref())
+                    .find(|themed_pointer| themed_pointer.pointer() == pointer)
+                {
+                    let _ = themed_pointer.set_cursor(conn, new_cursor);
+                }
             }
         }
 
@@ -163,22 +166,34 @@ impl FramedSurface for XWaylandXdgToplevel {
         serial: Serial,
         action: FrameAction,
         _position: (f64, f64),
+        frame_buttons: &FrameButtons,
     ) -> Result<()> {
         let window = &self.local_window;
         let pointer_data = client_pointer.data::<PointerData>().unwrap();
         let client_seat = pointer_data.seat();
         match action {
-            FrameAction::Close => {
-                x11_surface.close().location(loc!())?;
-            },
-            FrameAction::Minimize => {
-                window.set_minimized();
-            },
-            FrameAction::Maximize => {
-                window.set_maximized();
-            },
-            FrameAction::UnMaximize => {
-                window.unset_maximized();
+            FrameAction::Close
+            | FrameAction::Minimize
+            | FrameAction::Maximize
+            | FrameAction::UnMaximize => {
+                match frame_buttons.x11_action(&action, x11_surface.is_maximized()) {
+                    Some(X11Action::DeleteWindow) => {
+                        x11_surface.close().location(loc!())?;
+                    },
+                    Some(X11Action::SetMaximized(true)) => {
+                        window.set_maximized();
+                    },
+                    Some(X11Action::SetMaximized(false)) => {
+                        window.unset_maximized();
+                    },
+                    Some(X11Action::Minimize) => {
+                        window.set_minimized();
+                        x11_surface.set_suspended(true).location(loc!())?;
+                    },
+                    None => {
+                        debug!("ignoring frame action {action:?}");
+                    },
+                }
             },
             FrameAction::ShowMenu(x, y) => {
                 window.show_window_menu(client_seat, serial.into(), (x, y));
@@ -251,8 +266,15 @@ impl FramedSurface for XWaylandXdgToplevel {
                 if let Some(action) = frame.on_click(Duration::ZERO, click, pressed) {
                     debug!("button: {click:?}, kind: {kind:?}, action {action:?}");
 
-                    self.frame_action(x11_surface, pointer, serial.into(), action, (x, y))
-                        .location(loc!())?;
+                    self.frame_action(
+                        x11_surface,
+                        pointer,
+                        serial.into(),
+                        action,
+                        (x, y),
+                        &client_state.frame_buttons,
+                    )
+                    .location(loc!())?;
                 }
             },
             PointerEventKind::Axis { .. } => {},
@@ -270,10 +292,14 @@ impl FramedSurface for XWaylandSubSurface {
         _serial: Serial,
         action: FrameAction,
         position: (f64, f64),
+        frame_buttons: &FrameButtons,
     ) -> Result<()> {
         match action {
+            // Subsurfaces can't be maximized or minimized.
             FrameAction::Close => {
-                x11_surface.close().location(loc!())?;
+                if frame_buttons.x11_action(&action, false) == Some(X11Action::DeleteWindow) {
+                    x11_surface.close().location(loc!())?;
+                }
             },
             FrameAction::Resize(_edge) => {
                 // TODO
@@ -357,8 +383,15 @@ impl FramedSurface for XWaylandSubSurface {
                 if let Some(action) = frame.on_click(Duration::ZERO, click, pressed) {
                     debug!("button: {click:?}, kind: {kind:?}, action {action:?}");
 
-                    self.frame_action(x11_surface, pointer, serial.into(), action, event.position)
-                        .location(loc!())?;
+                    self.frame_action(
+                        x11_surface,
+                        pointer,
+                        serial.into(),
+                        action,
+                        event.position,
+                        &client_state.frame_buttons,
+                    )
+                    .location(loc!())?;
                 } else {
                     self.move_active = false;
                 }
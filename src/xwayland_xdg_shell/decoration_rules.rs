@@ -0,0 +1,132 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Per-application decoration behaviors. Some apps (e.g. IDEs drawing their
+/// own titlebars) look wrong with decorations while others need them, so the
+/// global DecorationBehavior can be overridden by rules matching the WM_CLASS
+/// class or instance of a window against a glob pattern. Rules are tried in
+/// order and the first match wins.
+use crate::xwayland_xdg_shell::compositor::DecorationBehavior;
+
+#[derive(Debug, Clone, Default)]
+pub struct DecorationRules {
+    /// (glob pattern, behavior), in order of precedence.
+    rules: Vec<(String, DecorationBehavior)>,
+}
+
+impl DecorationRules {
+    pub fn new(rules: Vec<(String, DecorationBehavior)>) -> Self {
+        Self { rules }
+    }
+
+    /// The behavior for windows with WM_CLASS `class` and `instance`, or
+    /// `default` if no rule matches.
+    pub fn behavior(
+        &self,
+        class: &str,
+        instance: &str,
+        default: DecorationBehavior,
+    ) -> DecorationBehavior {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| glob_matches(pattern, class) || glob_matches(pattern, instance))
+            .map_or(default, |(_, behavior)| *behavior)
+    }
+}
+
+/// Matches `text` against `pattern`, in which `*` matches any sequence of
+/// characters and `?` matches any single character.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` and the text position it was tried at.
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                // Let the `*` match one more character.
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("XTerm", "XTerm"));
+        assert!(!glob_matches("XTerm", "xterm"));
+        assert!(glob_matches("jetbrains-*", "jetbrains-idea"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*-idea*", "jetbrains-idea-ce"));
+        assert!(glob_matches("?term", "xterm"));
+        assert!(!glob_matches("?term", "term"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = DecorationRules::new(vec![
+            (
+                "jetbrains-*".to_string(),
+                DecorationBehavior::AlwaysDisabled,
+            ),
+            ("*".to_string(), DecorationBehavior::AlwaysEnabled),
+        ]);
+        assert_eq!(
+            rules.behavior("jetbrains-idea", "jetbrains-idea", DecorationBehavior::Auto),
+            DecorationBehavior::AlwaysDisabled
+        );
+        assert_eq!(
+            rules.behavior("Gimp", "gimp", DecorationBehavior::Auto),
+            DecorationBehavior::AlwaysEnabled
+        );
+    }
+
+    #[test]
+    fn matches_instance_or_falls_back() {
+        let rules = DecorationRules::new(vec![(
+            "emacs".to_string(),
+            DecorationBehavior::AlwaysDisabled,
+        )]);
+        assert_eq!(
+            rules.behavior("Emacs", "emacs", DecorationBehavior::Auto),
+            DecorationBehavior::AlwaysDisabled
+        );
+        assert_eq!(
+            rules.behavior("XTerm", "xterm", DecorationBehavior::AlwaysEnabled),
+            DecorationBehavior::AlwaysEnabled
+        );
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Drags started by our clients, replayed against the local compositor. The
+/// local compositor runs the drag: a local drag source offering the client's
+/// mime types and actions is started from the local surface under the pointer,
+/// with the drag icon mirrored by a local surface the way cursors are, and the
+/// events the local drop target causes on it are forwarded to the client's
+/// source.
+///
+/// Our own drag grab stays active meanwhile, since the local compositor takes
+/// the pointer and we never see the button being released. It's ended when
+/// the local drag ends, which makes smithay cancel the client's source unless
+/// one of our surfaces accepted the drop. That is what a target rejecting the
+/// offer and a drop over no surface need; after a finished drop the client
+/// has already destroyed its source in response to dnd_finished.
+use smithay::input::Seat;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::protocol::wl_data_device_manager::DndAction as CompositorDndAction;
+use smithay::reexports::wayland_server::protocol::wl_data_source;
+use smithay::reexports::wayland_server::protocol::wl_data_source::WlDataSource as CompositorWlDataSource;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface as CompositorWlSurface;
+use smithay::utils::SERIAL_COUNTER;
+use smithay::wayland::selection::data_device;
+use smithay::wayland::selection::data_device::ClientDndGrabHandler;
+use smithay::wayland::selection::data_device::ServerDndGrabHandler;
+use smithay_client_toolkit::data_device_manager::data_source::DragSource;
+use smithay_client_toolkit::reexports::client::protocol::wl_data_device_manager::DndAction;
+use smithay_client_toolkit::reexports::client::protocol::wl_data_source::WlDataSource;
+use smithay_client_toolkit::shell::WaylandSurface;
+
+use crate::fallible_entry::FallibleEntryExt;
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::XWaylandSurface;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::seat;
+use crate::xwayland_xdg_shell::xsurface_from_x11_surface;
+
+#[derive(Debug)]
+pub(crate) struct ClientDrag {
+    source: CompositorWlDataSource,
+    local_source: DragSource,
+    seat: Seat<WprsState>,
+}
+
+fn to_local_actions(actions: CompositorDndAction) -> DndAction {
+    DndAction::from_bits_truncate(actions.bits())
+}
+
+fn to_compositor_action(action: DndAction) -> CompositorDndAction {
+    CompositorDndAction::from_bits_truncate(action.bits())
+}
+
+/// Forwards the action the local compositor picked for the drag.
+pub(crate) fn forward_action(source: &CompositorWlDataSource, action: DndAction) {
+    if source.version() >= wl_data_source::EVT_ACTION_SINCE {
+        source.action(to_compositor_action(action));
+    }
+}
+
+/// Forwards the drag being dropped on a local target which accepted it.
+pub(crate) fn forward_drop_performed(source: &CompositorWlDataSource) {
+    if source.version() >= wl_data_source::EVT_DND_DROP_PERFORMED_SINCE {
+        source.dnd_drop_performed();
+    }
+}
+
+impl ClientDndGrabHandler for WprsState {
+    #[instrument(skip(self, seat), level = "debug")]
+    fn started(
+        &mut self,
+        source: Option<CompositorWlDataSource>,
+        icon: Option<CompositorWlSurface>,
+        seat: Seat<Self>,
+    ) {
+        // Drags without a source stay within the client.
+        if let Some(source) = source {
+            self.start_client_drag(source, icon, seat)
+                .log_and_ignore(loc!());
+        }
+    }
+
+    #[instrument(skip(self, _target, _seat), level = "debug")]
+    fn dropped(
+        &mut self,
+        _target: Option<CompositorWlSurface>,
+        validated: bool,
+        _seat: Seat<Self>,
+    ) {
+        // Our grab ended before the local drag did, e.g. because the local
+        // compositor refused to start it. Dropping the local source cancels
+        // the local drag.
+        self.client_state.client_drag = None;
+    }
+}
+
+// Only used for drags started by the compositor, which we don't do.
+impl ServerDndGrabHandler for WprsState {}
+
+impl WprsState {
+    fn start_client_drag(
+        &mut self,
+        source: CompositorWlDataSource,
+        icon: Option<CompositorWlSurface>,
+        seat: Seat<Self>,
+    ) -> Result<()> {
+        let (mime_types, actions) = data_device::with_source_metadata(&source, |metadata| {
+            (metadata.mime_types.clone(), metadata.dnd_action)
+        })
+        .location(loc!())?;
+
+        // The drag starts from the surface holding the implicit grab.
+        let origin = seat
+            .get_pointer()
+            .location(loc!())?
+            .current_focus()
+            .location(loc!())?;
+        let origin = xsurface_from_x11_surface(&mut self.surfaces, &origin)
+            .location(loc!())?
+            .wl_surface()
+            .clone();
+
+        let local_icon = match &icon {
+            Some(icon) => {
+                self.admit_surface(icon).location(loc!())?;
+                let xwayland_surface =
+                    self.surfaces.entry(icon.id()).or_insert_with_result(|| {
+                        XWaylandSurface::new(
+                            icon,
+                            &self.client_state.compositor_state,
+                            &self.client_state.qh,
+                            &mut self.surface_bimap,
+                        )
+                    })?;
+                xwayland_surface.role = Some(Role::DragIcon);
+                Some(xwayland_surface.wl_surface().clone())
+            },
+            None => None,
+        };
+
+        let local_source = self
+            .client_state
+            .data_device_manager_state
+            .create_drag_and_drop_source(
+                &self.client_state.qh,
+                mime_types,
+                to_local_actions(actions),
+            );
+        let seat_obj = seat::seat_object(&self.client_state, &seat).location(loc!())?;
+        local_source.start_drag(
+            &seat_obj.data_device,
+            &origin,
+            local_icon.as_ref(),
+            self.client_state.last_implicit_grab_serial,
+        );
+
+        self.client_state.client_drag = Some(ClientDrag {
+            source,
+            local_source,
+            seat,
+        });
+        Ok(())
+    }
+
+    /// The client's source mirrored by the local `source`, if it's the source
+    /// of a drag started by a client.
+    pub(crate) fn client_drag_source(
+        &self,
+        source: &WlDataSource,
+    ) -> Option<&CompositorWlDataSource> {
+        self.client_state
+            .client_drag
+            .as_ref()
+            .filter(|drag| drag.local_source.inner() == source)
+            .map(|drag| &drag.source)
+    }
+
+    /// Forwards the local drop target being done with the data and ends the
+    /// drag.
+    pub(crate) fn client_drag_finished(&mut self, source: &CompositorWlDataSource) {
+        if source.version() >= wl_data_source::EVT_DND_FINISHED_SINCE {
+            source.dnd_finished();
+        }
+        self.end_client_drag();
+    }
+
+    /// Ends our drag grab after the local drag ended, see the module docs.
+    pub(crate) fn end_client_drag(&mut self) {
+        let Some(drag) = self.client_state.client_drag.take() else {
+            return;
+        };
+        if let Some(pointer) = drag.seat.get_pointer() {
+            let time = self.compositor_state.start_time.elapsed().as_millis() as u32;
+            pointer.unset_grab(self, SERIAL_COUNTER.next_serial(), time);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_convert_between_protocol_sides() {
+        let actions = CompositorDndAction::Copy | CompositorDndAction::Move;
+        assert_eq!(to_local_actions(actions), DndAction::Copy | DndAction::Move);
+        assert_eq!(
+            to_compositor_action(DndAction::Ask),
+            CompositorDndAction::Ask
+        );
+        assert_eq!(
+            to_local_actions(CompositorDndAction::empty()),
+            DndAction::empty()
+        );
+    }
+}
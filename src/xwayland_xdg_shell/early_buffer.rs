@@ -0,0 +1,93 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of buffers which are committed to a surface before it is matched
+/// to an X11 window and assigned a role. Xwayland can commit a window's first
+/// buffer before the window manager has seen the window, and X11 apps which
+/// don't redraw after mapping would stay blank if that buffer were lost.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+/// What to do with a buffer committed before the surface got a role.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum EarlyBufferBehavior {
+    /// Keep the latest buffer and display it as soon as the role is assigned.
+    #[default]
+    Retain,
+    /// Drop the buffer, the surface is displayed at its next commit.
+    Discard,
+}
+
+impl EarlyBufferBehavior {
+    /// Whether to keep a buffer committed to a surface which does or doesn't
+    /// have a role yet.
+    pub fn keep_buffer(self, has_role: bool) -> bool {
+        has_role || self == Self::Retain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay::reexports::wayland_server::Resource;
+
+    use super::*;
+    use crate::xwayland_xdg_shell::compositor::CompositorOptions;
+    use crate::xwayland_xdg_shell::testing;
+    use crate::xwayland_xdg_shell::testing::Harness;
+
+    /// Commits a buffer to a surface before it's matched to its X11 window,
+    /// then maps the window. Returns what's displayed once it has a role.
+    fn display_early_buffer(early_buffer_behavior: EarlyBufferBehavior) -> Option<Vec<u8>> {
+        let mut harness = Harness::new(CompositorOptions {
+            early_buffer_behavior,
+            ..testing::options()
+        });
+        let surface = harness.xwayland.create_surface();
+        let committed: Vec<u8> = (0..8 * 8 * 4).map(|i| i as u8).collect();
+        let buffer = harness.xwayland.create_buffer(8, 8, &committed);
+        harness.xwayland.commit(&surface, Some(&buffer));
+        harness.dispatch();
+        assert_eq!(harness.displayed(&surface), None);
+
+        harness.map_x11_window(&surface, None);
+        // The window has no role until its surface commits again, which
+        // xwayland does without a new buffer.
+        harness.xwayland.commit(&surface, None);
+        harness.dispatch();
+        let xwayland_surface = &harness.state.surfaces[&harness.surface(&surface).id()];
+        assert!(xwayland_surface.role.is_some());
+        harness.displayed(&surface)
+    }
+
+    #[test]
+    fn retain_keeps_buffer_until_role() {
+        let committed: Vec<u8> = (0..8 * 8 * 4).map(|i| i as u8).collect();
+        assert_eq!(
+            display_early_buffer(EarlyBufferBehavior::Retain),
+            Some(committed)
+        );
+    }
+
+    #[test]
+    fn discard_displays_nothing_until_next_buffer() {
+        assert_eq!(display_early_buffer(EarlyBufferBehavior::Discard), None);
+    }
+
+    #[test]
+    fn discard_drops_only_roleless_buffers() {
+        let behavior = EarlyBufferBehavior::Discard;
+        assert!(!behavior.keep_buffer(false));
+        assert!(behavior.keep_buffer(true));
+    }
+}
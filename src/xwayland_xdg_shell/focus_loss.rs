@@ -0,0 +1,234 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of the keyboard focus when the focused X11 window is unmapped or
+/// destroyed. The local compositor moves its focus when the corresponding
+/// local window goes away, but not necessarily to one of our windows (or at
+/// all), and X11 apps which close a dialog expect the focus to return to the
+/// window below it.
+use std::collections::HashSet;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::wayland_server::Resource;
+use smithay::utils::SERIAL_COUNTER;
+use smithay::wayland::selection::data_device;
+use smithay::wayland::selection::primary_selection;
+use smithay::xwayland::X11Surface;
+
+use crate::prelude::*;
+use crate::serialization::wayland::KeyState;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::MODIFIER_KEYCODES;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum FocusLossBehavior {
+    /// Focus the window which was focused before the lost one.
+    #[default]
+    MostRecentlyUsed,
+    /// Focus the window below the lost one in the stacking order.
+    NextInStack,
+    /// Leave the keyboard unfocused until the local compositor focuses one of
+    /// our windows.
+    None,
+}
+
+/// The orders in which windows were focused and raised.
+#[derive(Debug)]
+pub(crate) struct FocusHistory<T> {
+    /// Least recently focused first.
+    focused: Vec<T>,
+    /// Bottom first.
+    stack: Vec<T>,
+}
+
+impl<T: Clone + PartialEq> FocusHistory<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            focused: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn focus(&mut self, window: &T) {
+        self.focused.retain(|w| w != window);
+        self.focused.push(window.clone());
+        // Focusing a window raises it.
+        self.raise(window);
+    }
+
+    pub(crate) fn raise(&mut self, window: &T) {
+        self.stack.retain(|w| w != window);
+        self.stack.push(window.clone());
+    }
+
+    /// Forgets `window` and returns the window which should get its focus.
+    pub(crate) fn remove(&mut self, window: &T, behavior: FocusLossBehavior) -> Option<T> {
+        let below = self
+            .stack
+            .iter()
+            .position(|w| w == window)
+            .and_then(|i| i.checked_sub(1))
+            .map(|i| self.stack[i].clone());
+        self.focused.retain(|w| w != window);
+        self.stack.retain(|w| w != window);
+        match behavior {
+            FocusLossBehavior::MostRecentlyUsed => self.focused.last().cloned(),
+            FocusLossBehavior::NextInStack => below.or_else(|| self.stack.last().cloned()),
+            FocusLossBehavior::None => None,
+        }
+    }
+}
+
+/// Splits `pressed_keys` into the keys to re-press after moving the focus and
+/// the ones to drop. Held modifiers still apply to the new focus, while the
+/// other keys (e.g., the one which closed the window) would start repeating
+/// there.
+fn resync_keys(pressed_keys: &HashSet<u32>) -> (Vec<u32>, Vec<u32>) {
+    let mut keys: Vec<u32> = pressed_keys.iter().copied().collect();
+    keys.sort_unstable();
+    keys.into_iter()
+        .partition(|keycode| MODIFIER_KEYCODES.contains(keycode))
+}
+
+impl WprsState {
+    /// Moves the keyboard focus of each seat away from `window`, which is
+    /// being unmapped, if it has it.
+    pub(crate) fn handle_focus_loss(&mut self, window: &X11Surface) -> Result<()> {
+        let compositor_state = &mut self.compositor_state;
+        let successor = compositor_state
+            .focus_history
+            .remove(window, compositor_state.focus_loss_behavior);
+        let seat_names: Vec<String> = compositor_state.seats.keys().cloned().collect();
+        for seat_name in seat_names {
+            self.move_seat_focus(&seat_name, window, successor.as_ref())
+                .location(loc!())?;
+        }
+        Ok(())
+    }
+
+    fn move_seat_focus(
+        &mut self,
+        seat_name: &str,
+        window: &X11Surface,
+        successor: Option<&X11Surface>,
+    ) -> Result<()> {
+        let seat = self.compositor_state.seat(seat_name).location(loc!())?;
+        let keyboard = seat.keyboard().location(loc!())?;
+        if keyboard.current_focus().as_ref() != Some(window) {
+            return Ok(());
+        }
+
+        // Without this, xwayland still thinks the key that triggered the
+        // window close is still held down and sends key repeat events.
+        keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+        let pressed_keys = self
+            .compositor_state
+            .seat(seat_name)
+            .location(loc!())?
+            .pressed_keys
+            .clone();
+        let (modifiers, _) = resync_keys(&pressed_keys);
+        for keycode in pressed_keys {
+            self.set_key_state(
+                seat_name,
+                keycode,
+                KeyState::Released,
+                SERIAL_COUNTER.next_serial(),
+            )
+            .location(loc!())?;
+        }
+
+        let Some(successor) = successor else {
+            return Ok(());
+        };
+        debug!("moving keyboard focus of seat {seat_name:?} from {window:?} to {successor:?}");
+        let client = successor.wl_surface().and_then(|surface| surface.client());
+        successor.set_activated(true).location(loc!())?;
+        // The modifiers are pressed while the new window has the focus, so
+        // that it sees them as a newly focused wayland app would.
+        keyboard.set_focus(self, Some(successor.clone()), SERIAL_COUNTER.next_serial());
+        for keycode in modifiers {
+            self.set_key_state(
+                seat_name,
+                keycode,
+                KeyState::Pressed,
+                SERIAL_COUNTER.next_serial(),
+            )
+            .location(loc!())?;
+        }
+        self.compositor_state.focus_history.focus(successor);
+        self.install_window_colormap(successor);
+        let seat = &self.compositor_state.seat(seat_name).location(loc!())?.seat;
+        data_device::set_data_device_focus(&self.compositor_state.dh, seat, client.clone());
+        primary_selection::set_primary_focus(&self.compositor_state.dh, seat, client);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> FocusHistory<u32> {
+        let mut history = FocusHistory::new();
+        // 1 is a main window, 2 a second window raised above it without
+        // focusing it, and 3 a dialog of 1.
+        history.focus(&1);
+        history.raise(&2);
+        history.focus(&3);
+        history
+    }
+
+    #[test]
+    fn destroying_focused_window_focuses_most_recently_used() {
+        let mut history = history();
+        assert_eq!(
+            history.remove(&3, FocusLossBehavior::MostRecentlyUsed),
+            Some(1)
+        );
+        assert_eq!(
+            history.remove(&1, FocusLossBehavior::MostRecentlyUsed),
+            None
+        );
+    }
+
+    #[test]
+    fn destroying_focused_window_focuses_next_in_stack() {
+        let mut history = history();
+        assert_eq!(history.remove(&3, FocusLossBehavior::NextInStack), Some(2));
+        // The bottom window passes the focus to the top one.
+        history.raise(&1);
+        assert_eq!(history.remove(&2, FocusLossBehavior::NextInStack), Some(1));
+    }
+
+    #[test]
+    fn destroying_focused_window_without_refocus() {
+        let mut history = history();
+        assert_eq!(history.remove(&3, FocusLossBehavior::None), None);
+        // The window is still forgotten.
+        assert_eq!(
+            history.remove(&2, FocusLossBehavior::MostRecentlyUsed),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn only_modifiers_are_pressed_again() {
+        // KEY_LEFTCTRL and KEY_W.
+        let (pressed, dropped) = resync_keys(&HashSet::from([29, 17]));
+        assert_eq!(pressed, vec![29]);
+        assert_eq!(dropped, vec![17]);
+    }
+}
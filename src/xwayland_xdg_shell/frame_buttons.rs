@@ -0,0 +1,128 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Actions of the close, maximize and minimize buttons of the window frame we
+/// draw for X11 windows. Maximizing is requested from the local compositor and
+/// reaches the X11 window through the following configure, which sets
+/// _NET_WM_STATE_MAXIMIZED_{HORZ,VERT}. Minimizing is never confirmed by the
+/// local compositor, so _NET_WM_STATE_HIDDEN is set right away and cleared
+/// when the window regains the keyboard focus.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay_client_toolkit::reexports::csd_frame::FrameAction;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ButtonAction {
+    /// Ask the window to close with WM_DELETE_WINDOW, or destroy it if it
+    /// doesn't support that.
+    Close,
+    /// Maximize the window, or unmaximize it if it is maximized.
+    ToggleMaximize,
+    /// Minimize the window.
+    Minimize,
+    /// Do nothing.
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub struct FrameButtons {
+    pub close: ButtonAction,
+    pub maximize: ButtonAction,
+    pub minimize: ButtonAction,
+}
+
+impl Default for FrameButtons {
+    fn default() -> Self {
+        Self {
+            close: ButtonAction::Close,
+            maximize: ButtonAction::ToggleMaximize,
+            minimize: ButtonAction::Minimize,
+        }
+    }
+}
+
+/// What a frame button does to the X11 window.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum X11Action {
+    DeleteWindow,
+    SetMaximized(bool),
+    Minimize,
+}
+
+impl FrameButtons {
+    /// The X11 action for the frame `action` on a window which is
+    /// `maximized`, or None if `action` isn't a button or is ignored.
+    pub(crate) fn x11_action(&self, action: &FrameAction, maximized: bool) -> Option<X11Action> {
+        let button_action = match action {
+            FrameAction::Close => self.close,
+            FrameAction::Maximize | FrameAction::UnMaximize => self.maximize,
+            FrameAction::Minimize => self.minimize,
+            _ => return None,
+        };
+        match button_action {
+            ButtonAction::Close => Some(X11Action::DeleteWindow),
+            ButtonAction::ToggleMaximize => Some(X11Action::SetMaximized(!maximized)),
+            ButtonAction::Minimize => Some(X11Action::Minimize),
+            ButtonAction::Ignore => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_route_to_x11_actions() {
+        let buttons = FrameButtons::default();
+        assert_eq!(
+            buttons.x11_action(&FrameAction::Close, false),
+            Some(X11Action::DeleteWindow)
+        );
+        assert_eq!(
+            buttons.x11_action(&FrameAction::Maximize, false),
+            Some(X11Action::SetMaximized(true))
+        );
+        assert_eq!(
+            buttons.x11_action(&FrameAction::UnMaximize, true),
+            Some(X11Action::SetMaximized(false))
+        );
+        assert_eq!(
+            buttons.x11_action(&FrameAction::Minimize, false),
+            Some(X11Action::Minimize)
+        );
+        assert_eq!(buttons.x11_action(&FrameAction::Move, false), None);
+    }
+
+    #[test]
+    fn maximize_toggles_x11_state() {
+        // The frame may not know about maximizes requested by the X11 app.
+        let buttons = FrameButtons::default();
+        assert_eq!(
+            buttons.x11_action(&FrameAction::Maximize, true),
+            Some(X11Action::SetMaximized(false))
+        );
+    }
+
+    #[test]
+    fn buttons_can_be_remapped() {
+        let buttons: FrameButtons =
+            ron::from_str("(close: Ignore, maximize: ToggleMaximize, minimize: Close)").unwrap();
+        assert_eq!(buttons.x11_action(&FrameAction::Close, false), None);
+        assert_eq!(
+            buttons.x11_action(&FrameAction::Minimize, false),
+            Some(X11Action::DeleteWindow)
+        );
+    }
+}
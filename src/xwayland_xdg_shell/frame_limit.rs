@@ -0,0 +1,83 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Limiting of the frames in flight per surface. A frame is in flight from
+/// when it's committed to the local compositor until the local compositor
+/// signals that it was displayed with a frame callback. Once a surface has the
+/// maximum number of frames in flight, new frames aren't sent: the surface's
+/// buffer is still updated, and its damage accumulated, but only the latest
+/// contents are sent once a frame callback arrives. This way, an app which
+/// renders faster than the local compositor displays its frames has the
+/// intermediate frames dropped instead of queueing them up.
+pub const DEFAULT_MAX_FRAMES_IN_FLIGHT: u32 = 2;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct FramesInFlight(u32);
+
+impl FramesInFlight {
+    /// Whether another frame may be sent when at most `max` frames may be in
+    /// flight. 0 means unlimited.
+    pub(crate) fn can_send(self, max: u32) -> bool {
+        max == 0 || self.0 < max
+    }
+
+    pub(crate) fn sent(&mut self) {
+        self.0 = self.0.saturating_add(1);
+    }
+
+    /// A frame callback arrived.
+    pub(crate) fn displayed(&mut self) {
+        self.0 = self.0.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_held_at_limit_until_displayed() {
+        let mut frames_in_flight = FramesInFlight::default();
+        let mut sent = 0;
+        // The app commits 10 frames before the local compositor displays any.
+        for _ in 0..10 {
+            if frames_in_flight.can_send(DEFAULT_MAX_FRAMES_IN_FLIGHT) {
+                frames_in_flight.sent();
+                sent += 1;
+            }
+        }
+        assert_eq!(sent, DEFAULT_MAX_FRAMES_IN_FLIGHT);
+
+        frames_in_flight.displayed();
+        assert!(frames_in_flight.can_send(DEFAULT_MAX_FRAMES_IN_FLIGHT));
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let mut frames_in_flight = FramesInFlight::default();
+        for _ in 0..100 {
+            assert!(frames_in_flight.can_send(0));
+            frames_in_flight.sent();
+        }
+    }
+
+    #[test]
+    fn unrequested_callbacks_dont_underflow() {
+        let mut frames_in_flight = FramesInFlight::default();
+        frames_in_flight.displayed();
+        assert_eq!(frames_in_flight, FramesInFlight::default());
+        frames_in_flight.sent();
+        assert!(!frames_in_flight.can_send(1));
+    }
+}
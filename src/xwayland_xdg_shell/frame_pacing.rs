@@ -0,0 +1,224 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Pacing of the frame callbacks we answer ourselves. Surfaces without an X11
+/// window, cursors, drag icons and held frames aren't sent with a local frame
+/// callback, so their frame callbacks are sent by us. With Refresh, they're
+/// sent at most once per refresh interval of the outputs the surface is on (or
+/// of the fastest output, for surfaces which aren't on any), so that apps
+/// animating them throttle to the display rate instead of rendering as fast as
+/// they can.
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::wayland::compositor;
+use smithay::wayland::compositor::SurfaceAttributes;
+use smithay::wayland::compositor::SurfaceData;
+
+use crate::compositor_utils;
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::compositor::WprsCompositorState;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum FramePacing {
+    /// Send frame callbacks as soon as the surface is committed.
+    Immediate,
+    /// Send frame callbacks at most once per output refresh interval.
+    #[default]
+    Refresh,
+}
+
+/// The refresh interval of a mode refreshing at `refresh` mHz, None if the
+/// refresh rate is unknown.
+fn refresh_interval(refresh: i32) -> Option<Duration> {
+    let refresh = u64::try_from(refresh).ok().filter(|refresh| *refresh > 0)?;
+    Some(Duration::from_nanos(1_000_000_000_000 / refresh))
+}
+
+/// When the frame callbacks of a surface may be sent next.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum NextFrame {
+    Now,
+    After(Duration),
+    /// A timer sending them is already scheduled.
+    Scheduled,
+}
+
+#[derive(Debug, Default)]
+struct PacingState {
+    last_frame: Option<Duration>,
+    scheduled: bool,
+}
+
+impl PacingState {
+    fn next_frame(&mut self, now: Duration, interval: Duration) -> NextFrame {
+        if self.scheduled {
+            return NextFrame::Scheduled;
+        }
+        let delay = self
+            .last_frame
+            .map_or(Duration::ZERO, |last| (last + interval).saturating_sub(now));
+        if delay.is_zero() {
+            self.last_frame = Some(now);
+            NextFrame::Now
+        } else {
+            self.scheduled = true;
+            NextFrame::After(delay)
+        }
+    }
+
+    /// The scheduled frame callbacks were sent at `now`.
+    fn fired(&mut self, now: Duration) {
+        self.scheduled = false;
+        self.last_frame = Some(now);
+    }
+}
+
+impl WprsCompositorState {
+    /// The interval between frame callbacks of a surface on the outputs
+    /// `output_ids`, zero if they should be sent immediately.
+    pub(crate) fn frame_interval(&self, output_ids: &HashSet<u32>) -> Duration {
+        if self.frame_pacing == FramePacing::Immediate {
+            return Duration::ZERO;
+        }
+        let mut outputs: Vec<_> = output_ids
+            .iter()
+            .filter_map(|id| self.outputs.get(id))
+            .collect();
+        if outputs.is_empty() {
+            outputs = self.outputs.values().collect();
+        }
+        outputs
+            .into_iter()
+            .filter_map(|(output, _)| output.current_mode())
+            .filter_map(|mode| refresh_interval(mode.refresh))
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+impl WprsState {
+    /// Sends the frame callbacks of `surface` now or, if the surface's last
+    /// ones were sent less than `interval` ago, once `interval` has passed.
+    pub(crate) fn send_paced_frames(
+        &self,
+        surface: &WlSurface,
+        surface_data: &SurfaceData,
+        surface_attributes: &mut SurfaceAttributes,
+        interval: Duration,
+    ) -> Result<()> {
+        let now = self.compositor_state.start_time.elapsed();
+        surface_data
+            .data_map
+            .insert_if_missing_threadsafe(|| Mutex::new(PacingState::default()));
+        let next_frame = surface_data
+            .data_map
+            .get::<Mutex<PacingState>>()
+            .location(loc!())?
+            .lock()
+            .unwrap()
+            .next_frame(now, interval);
+
+        match next_frame {
+            NextFrame::Now => compositor_utils::send_frames(
+                surface,
+                &surface_data.data_map,
+                surface_attributes,
+                now,
+                Duration::ZERO,
+            )
+            .location(loc!()),
+            NextFrame::Scheduled => Ok(()),
+            NextFrame::After(delay) => {
+                let surface = surface.clone();
+                self.event_loop_handle
+                    .insert_source(Timer::from_duration(delay), move |_, _, state| {
+                        if surface.is_alive() {
+                            send_scheduled_frames(state, &surface).log_and_ignore(loc!());
+                        }
+                        TimeoutAction::Drop
+                    })
+                    .map_err(|e| anyhow!("failed to insert frame pacing timer: {e}"))
+                    .location(loc!())?;
+                Ok(())
+            },
+        }
+    }
+}
+
+fn send_scheduled_frames(state: &WprsState, surface: &WlSurface) -> Result<()> {
+    let now = state.compositor_state.start_time.elapsed();
+    compositor::with_states(surface, |surface_data| {
+        if let Some(pacing_state) = surface_data.data_map.get::<Mutex<PacingState>>() {
+            pacing_state.lock().unwrap().fired(now);
+        }
+        let mut guard = surface_data.cached_state.get::<SurfaceAttributes>();
+        compositor_utils::send_frames(
+            surface,
+            &surface_data.data_map,
+            guard.current(),
+            now,
+            Duration::ZERO,
+        )
+    })
+    .location(loc!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_nanos(16_666_666);
+
+    #[test]
+    fn refresh_interval_of_mode() {
+        assert_eq!(refresh_interval(60_000), Some(FRAME));
+        assert_eq!(refresh_interval(0), None);
+        assert_eq!(refresh_interval(-1), None);
+    }
+
+    #[test]
+    fn frames_are_paced_to_interval() {
+        let mut state = PacingState::default();
+        assert_eq!(state.next_frame(Duration::ZERO, FRAME), NextFrame::Now);
+        let now = Duration::from_millis(5);
+        assert_eq!(state.next_frame(now, FRAME), NextFrame::After(FRAME - now));
+        // Commits until the timer fires don't schedule another one.
+        assert_eq!(
+            state.next_frame(Duration::from_millis(10), FRAME),
+            NextFrame::Scheduled
+        );
+        state.fired(FRAME);
+        assert_eq!(state.next_frame(FRAME * 3, FRAME), NextFrame::Now);
+    }
+
+    #[test]
+    fn zero_interval_is_immediate() {
+        let mut state = PacingState::default();
+        for ms in 0..10 {
+            assert_eq!(
+                state.next_frame(Duration::from_millis(ms), Duration::ZERO),
+                NextFrame::Now
+            );
+        }
+    }
+}
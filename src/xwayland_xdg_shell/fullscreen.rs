@@ -0,0 +1,183 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Fullscreen on the monitor requested by the X11 app. _NET_WM_FULLSCREEN_MONITORS
+/// lists the Xinerama indices of the monitors whose top, bottom, left, and
+/// right edges the fullscreen window should span. xdg_toplevel.set_fullscreen
+/// only takes a single output, so the window goes fullscreen on the monitor of
+/// its top edge, or on the output it's currently on if that monitor isn't
+/// available.
+///
+/// Xwayland creates a RandR monitor for each of our outputs, named after the
+/// output, and Xinerama indices are indices into the RandR monitor list.
+use std::collections::HashSet;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
+use x11rb::protocol::randr::ConnectionExt as RandrConnectionExt;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum FullscreenMonitorBehavior {
+    /// Go fullscreen on the monitor requested with _NET_WM_FULLSCREEN_MONITORS.
+    #[default]
+    Honor,
+    /// Let the local compositor pick the output.
+    Ignore,
+}
+
+/// The monitor to go fullscreen on for a _NET_WM_FULLSCREEN_MONITORS of
+/// `monitors`.
+fn requested_monitor(monitors: &[u32]) -> Option<u32> {
+    match monitors {
+        [top, _bottom, _left, _right] => Some(*top),
+        _ => None,
+    }
+}
+
+/// The first of the requested output and the outputs the window is currently
+/// on which `lookup` finds.
+fn pick_output<T>(
+    requested: Option<u32>,
+    current: &HashSet<u32>,
+    lookup: impl Fn(u32) -> Option<T>,
+) -> Option<T> {
+    let mut current: Vec<_> = current.iter().copied().collect();
+    current.sort_unstable();
+    requested.into_iter().chain(current).find_map(lookup)
+}
+
+/// Reads the name of the monitor `window` asked to go fullscreen on.
+fn fetch_requested_monitor(conn: &X11Connection, window: u32) -> Result<Option<String>> {
+    let root = conn.screen().root;
+
+    let atom = conn
+        .intern_atom(true, b"_NET_WM_FULLSCREEN_MONITORS")
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .atom;
+    if atom == u32::from(AtomEnum::NONE) {
+        return Ok(None);
+    }
+    let monitors: Vec<u32> = conn
+        .get_property(false, window, atom, AtomEnum::CARDINAL, 0, 4)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .value32()
+        .into_iter()
+        .flatten()
+        .collect();
+    let Some(index) = requested_monitor(&monitors) else {
+        return Ok(None);
+    };
+
+    let monitors = conn
+        .randr_get_monitors(root, true)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .monitors;
+    let Some(monitor) = monitors.get(index as usize) else {
+        debug!(
+            "window {window} requested fullscreen on monitor {index}, but there are only {} monitors",
+            monitors.len()
+        );
+        return Ok(None);
+    };
+    let name = conn
+        .get_atom_name(monitor.name)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .name;
+    Ok(Some(String::from_utf8_lossy(&name).into_owned()))
+}
+
+impl WprsState {
+    /// The local output to make `x11_surface` fullscreen on, None to let the
+    /// local compositor pick.
+    pub(crate) fn fullscreen_output(
+        &self,
+        x11_surface: &X11Surface,
+        current_output_ids: &HashSet<u32>,
+    ) -> Option<WlOutput> {
+        if self.compositor_state.fullscreen_monitor_behavior == FullscreenMonitorBehavior::Ignore {
+            return None;
+        }
+
+        let requested = self
+            .compositor_state
+            .x11_conn
+            .as_ref()
+            .and_then(|conn| {
+                fetch_requested_monitor(conn, x11_surface.window_id())
+                    .warn(loc!())
+                    .ok()
+                    .flatten()
+            })
+            .and_then(|monitor_name| {
+                self.compositor_state
+                    .outputs
+                    .iter()
+                    .find(|(_, (output, _))| output.name() == monitor_name)
+                    .map(|(id, _)| *id)
+            });
+
+        let output_state = &self.client_state.output_state;
+        pick_output(requested, current_output_ids, |id| {
+            output_state.outputs().find(|output| {
+                output_state
+                    .info(output)
+                    .is_some_and(|output_info| output_info.id == id)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_monitor_is_requested() {
+        assert_eq!(requested_monitor(&[1, 1, 1, 1]), Some(1));
+        assert_eq!(requested_monitor(&[0, 1, 0, 1]), Some(0));
+        assert_eq!(requested_monitor(&[]), None);
+    }
+
+    #[test]
+    fn requested_output_is_preferred() {
+        let available = [1, 2];
+        let lookup = |id| available.contains(&id).then_some(id);
+        assert_eq!(pick_output(Some(2), &HashSet::from([1]), lookup), Some(2));
+    }
+
+    #[test]
+    fn unavailable_output_falls_back_to_current() {
+        let available = [1, 2];
+        let lookup = |id| available.contains(&id).then_some(id);
+        assert_eq!(pick_output(Some(3), &HashSet::from([1]), lookup), Some(1));
+        assert_eq!(pick_output(None, &HashSet::from([2]), lookup), Some(2));
+        assert_eq!(pick_output(Some(3), &HashSet::new(), lookup), None);
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Forwarding of the local compositor's idle state (ext-idle-notify-v1) to the
+/// X server's screensaver, so that X11 screensavers and apps querying idle time
+/// through the XScreenSaver extension see the idle state of the user actually
+/// sitting in front of the local compositor.
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
+use smithay_client_toolkit::reexports::protocols::ext::idle_notify::v1::client::ext_idle_notification_v1;
+use smithay_client_toolkit::reexports::protocols::ext::idle_notify::v1::client::ext_idle_notification_v1::ExtIdleNotificationV1;
+use smithay_client_toolkit::reexports::protocols::ext::idle_notify::v1::client::ext_idle_notifier_v1::ExtIdleNotifierV1;
+use smithay_client_toolkit::registry::SimpleGlobal;
+use x11rb::connection::Connection as _;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::ScreenSaver;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+fn force_screen_saver(conn: &X11Connection, mode: ScreenSaver) -> Result<()> {
+    conn.force_screen_saver(mode).location(loc!())?;
+    conn.flush().location(loc!())?;
+    Ok(())
+}
+
+impl WprsState {
+    /// Requests idle notifications for the given seat from the local
+    /// compositor. Only one notification is created, for the first seat seen.
+    pub(crate) fn init_idle_notification(&mut self, seat: &WlSeat) {
+        let client_state = &mut self.client_state;
+        if client_state.idle_notification.is_some() || client_state.idle_timeout_ms == 0 {
+            return;
+        }
+        let Some(idle_notifier) = &client_state.idle_notifier else {
+            return;
+        };
+        let Ok(idle_notifier) = idle_notifier.get() else {
+            return;
+        };
+
+        client_state.idle_notification = Some(idle_notifier.get_idle_notification(
+            client_state.idle_timeout_ms,
+            seat,
+            &client_state.qh,
+            (),
+        ));
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    fn set_idle(&mut self, idle: bool) {
+        if self.client_state.idle == idle {
+            return;
+        }
+        self.client_state.idle = idle;
+
+        let mode = if idle {
+            ScreenSaver::ACTIVE
+        } else {
+            ScreenSaver::RESET
+        };
+        if let Some(conn) = &self.compositor_state.x11_conn {
+            force_screen_saver(conn, mode).log_and_ignore(loc!());
+        }
+    }
+
+    /// Resets the X screensaver if the local compositor previously reported the
+    /// user as idle. Called for every input event forwarded to xwayland.
+    pub(crate) fn reset_idle(&mut self) {
+        self.set_idle(false);
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for WprsState {
+    fn event(
+        state: &mut Self,
+        _notification: &ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => state.set_idle(true),
+            ext_idle_notification_v1::Event::Resumed => state.set_idle(false),
+            _ => {},
+        }
+    }
+}
+
+impl AsMut<SimpleGlobal<ExtIdleNotifierV1, 1>> for WprsState {
+    fn as_mut(&mut self) -> &mut SimpleGlobal<ExtIdleNotifierV1, 1> {
+        // This should never panic since if idle_notifier is None then we will
+        // never get any events for it.
+        self.client_state.idle_notifier.as_mut().unwrap()
+    }
+}
+
+smithay_client_toolkit::delegate_simple!(WprsState, ExtIdleNotifierV1, 1);
@@ -0,0 +1,184 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Forwarding of input regions. X11 windows with an input shape, e.g.
+/// click-through overlays and OSDs, get an input region, and pointer and touch
+/// events outside of it go to the window beneath. An unset input region covers
+/// the whole surface, while an empty one covers none of it, so the two must not
+/// be confused. Some apps set an empty input shape by mistake and become
+/// impossible to click, so empty input regions can be ignored instead.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::wayland::compositor::RectangleKind;
+use smithay::wayland::compositor::RegionAttributes;
+use smithay_client_toolkit::compositor::CompositorState;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+
+use crate::prelude::*;
+use crate::serialization::wayland::Region;
+
+/// What to do with surfaces whose input region is empty.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum EmptyInputRegionBehavior {
+    /// Forward the empty input region, input goes to the windows beneath.
+    #[default]
+    ClickThrough,
+    /// Unset the input region, the surface takes input everywhere.
+    Interactive,
+}
+
+/// Whether `input_region` has no area added to it.
+fn is_empty(input_region: &RegionAttributes) -> bool {
+    !input_region
+        .rects
+        .iter()
+        .any(|(kind, rect)| matches!(kind, RectangleKind::Add) && !rect.is_empty())
+}
+
+/// The input region of a local surface `scale` times the size of the app's,
+/// None for the whole surface.
+fn local_input_region(
+    input_region: Option<&RegionAttributes>,
+    behavior: EmptyInputRegionBehavior,
+    scale: u32,
+) -> Option<RegionAttributes> {
+    let input_region = input_region?;
+    if behavior == EmptyInputRegionBehavior::Interactive && is_empty(input_region) {
+        return None;
+    }
+    let scale = scale as i32;
+    Some(RegionAttributes {
+        rects: input_region
+            .rects
+            .iter()
+            .map(|(kind, rect)| (*kind, rect.upscale(scale)))
+            .collect(),
+    })
+}
+
+/// The input region of a local surface.
+#[derive(Debug, Default)]
+pub(crate) struct LocalInputRegion {
+    /// None for the whole surface, which is the initial input region.
+    current: Option<Region>,
+}
+
+impl LocalInputRegion {
+    /// Sets the input region of `surface` to `input_region` scaled by `scale`,
+    /// if it changed. Applied with the next local commit.
+    pub(crate) fn update(
+        &mut self,
+        input_region: Option<&RegionAttributes>,
+        behavior: EmptyInputRegionBehavior,
+        scale: u32,
+        surface: &WlSurface,
+        compositor_state: &CompositorState,
+    ) -> Result<()> {
+        let region = local_input_region(input_region, behavior, scale)
+            .as_ref()
+            .map(Region::from);
+        if self.current == region {
+            return Ok(());
+        }
+        match &region {
+            Some(region) => surface.set_input_region(Some(
+                region
+                    .create_compositor_region(compositor_state)
+                    .location(loc!())?
+                    .wl_region(),
+            )),
+            None => surface.set_input_region(None),
+        }
+        self.current = region;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay::utils::Logical;
+    use smithay::utils::Rectangle;
+
+    use super::*;
+
+    fn region(rects: Vec<(RectangleKind, Rectangle<i32, Logical>)>) -> RegionAttributes {
+        RegionAttributes { rects }
+    }
+
+    #[test]
+    fn empty_input_region_passes_input_through() {
+        let empty = region(Vec::new());
+        let local =
+            local_input_region(Some(&empty), EmptyInputRegionBehavior::ClickThrough, 1).unwrap();
+        // No point of the surface takes input, so it reaches the window
+        // beneath.
+        for point in [(0, 0), (10, 10), (1919, 1079)] {
+            assert!(!local.contains(point));
+        }
+
+        // Regions whose area is all subtracted are just as empty.
+        let subtracted = region(vec![
+            (
+                RectangleKind::Add,
+                Rectangle::new((0, 0).into(), (0, 0).into()),
+            ),
+            (
+                RectangleKind::Subtract,
+                Rectangle::new((0, 0).into(), (100, 100).into()),
+            ),
+        ]);
+        assert!(is_empty(&subtracted));
+        assert!(
+            local_input_region(Some(&subtracted), EmptyInputRegionBehavior::Interactive, 1)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn unset_input_region_takes_all_input() {
+        for behavior in [
+            EmptyInputRegionBehavior::ClickThrough,
+            EmptyInputRegionBehavior::Interactive,
+        ] {
+            assert!(local_input_region(None, behavior, 1).is_none());
+        }
+        assert!(
+            local_input_region(
+                Some(&region(Vec::new())),
+                EmptyInputRegionBehavior::Interactive,
+                1
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn input_region_is_scaled_with_override() {
+        let input_region = region(vec![(
+            RectangleKind::Add,
+            Rectangle::new((10, 10).into(), (20, 20).into()),
+        )]);
+        assert!(!is_empty(&input_region));
+        let local = local_input_region(
+            Some(&input_region),
+            EmptyInputRegionBehavior::ClickThrough,
+            2,
+        )
+        .unwrap();
+        assert!(local.contains((20, 20)));
+        assert!(local.contains((59, 59)));
+        assert!(!local.contains((60, 60)));
+        assert!(!local.contains((15, 15)));
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// X11 auto-repeat controls. Games turn auto-repeat off (XAutoRepeatOff, or
+/// the RepeatKeys control with XkbSetControls) while they're focused so that
+/// held keys only generate a press and a release. Xwayland's own key repeat
+/// honors that, but repeats sent by the local compositor are delivered as a
+/// release followed by a press and would bypass it, so they're dropped for
+/// keys the X server doesn't repeat. The controls are global to the X server,
+/// but apps which change them restore them when they lose focus, so they
+/// effectively apply to the focused window.
+///
+/// The controls are queried on the shared X11 connection on the first repeat
+/// after the keyboard focus changes or a MappingNotify arrives, and cached
+/// until then, so that repeats don't wait on a round trip each. They aren't
+/// queried on the focus change itself because apps change them only once
+/// they've gained the focus.
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::AutoRepeatMode;
+use x11rb::protocol::xproto::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+/// Whether the X server auto-repeats the key with (evdev) `keycode`, given
+/// the global mode and per-key bitmask of GetKeyboardControl. Xkb's
+/// RepeatKeys control is reflected in the global mode.
+fn key_repeats(global_auto_repeat: AutoRepeatMode, auto_repeats: &[u8; 32], keycode: u32) -> bool {
+    if global_auto_repeat == AutoRepeatMode::OFF {
+        return false;
+    }
+    // X11 keycodes are offset by 8 from evdev keycodes.
+    let Some(x11_keycode) = keycode.checked_add(8).filter(|kc| *kc < 256) else {
+        return true;
+    };
+    auto_repeats[(x11_keycode / 8) as usize] & (1 << (x11_keycode % 8)) != 0
+}
+
+/// The auto-repeat controls as of the last GetKeyboardControl.
+#[derive(Debug)]
+pub(crate) struct AutoRepeatControls {
+    global_auto_repeat: AutoRepeatMode,
+    auto_repeats: [u8; 32],
+}
+
+impl AutoRepeatControls {
+    fn query(conn: &X11Connection) -> Result<Self> {
+        let reply = conn
+            .get_keyboard_control()
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        Ok(Self {
+            global_auto_repeat: reply.global_auto_repeat,
+            auto_repeats: reply.auto_repeats,
+        })
+    }
+
+    fn key_repeats(&self, keycode: u32) -> bool {
+        key_repeats(self.global_auto_repeat, &self.auto_repeats, keycode)
+    }
+}
+
+/// Whether a MappingNotify arrived on `conn`. No events are selected on the
+/// shared connection, but MappingNotify is sent to all clients, so this also
+/// keeps it from piling up there.
+fn mapping_changed(conn: &X11Connection) -> Result<bool> {
+    let mut changed = false;
+    while let Some(event) = conn.poll_for_event().location(loc!())? {
+        if let Event::MappingNotify(_) = event {
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+impl WprsState {
+    /// Drops the cached auto-repeat controls, so that they're queried again on
+    /// the next repeat.
+    pub(crate) fn invalidate_auto_repeat_controls(&mut self) {
+        self.compositor_state.auto_repeat_controls = None;
+    }
+
+    /// Whether repeats of `keycode` sent by the local compositor should be
+    /// forwarded to xwayland. Keys are repeated if the controls can't be
+    /// queried, so that text entry keeps working.
+    pub(crate) fn forward_key_repeat(&mut self, keycode: u32) -> bool {
+        let Some(conn) = &self.compositor_state.x11_conn else {
+            return true;
+        };
+        if mapping_changed(conn).warn(loc!()).unwrap_or(true) {
+            self.compositor_state.auto_repeat_controls = None;
+        }
+        if self.compositor_state.auto_repeat_controls.is_none() {
+            self.compositor_state.auto_repeat_controls =
+                AutoRepeatControls::query(conn).warn(loc!()).ok();
+        }
+        self.compositor_state
+            .auto_repeat_controls
+            .as_ref()
+            .is_none_or(|controls| controls.key_repeats(keycode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // KEY_W
+    const W: u32 = 17;
+
+    fn all_repeat() -> [u8; 32] {
+        [0xff; 32]
+    }
+
+    #[test]
+    fn repeat_toggles_with_global_mode() {
+        assert!(key_repeats(AutoRepeatMode::ON, &all_repeat(), W));
+        assert!(!key_repeats(AutoRepeatMode::OFF, &all_repeat(), W));
+    }
+
+    #[test]
+    fn repeat_toggles_per_key() {
+        let mut auto_repeats = all_repeat();
+        // X11 keycode 25.
+        auto_repeats[3] &= !(1 << 1);
+        assert!(!key_repeats(AutoRepeatMode::ON, &auto_repeats, W));
+        // Other keys, e.g. those used for text entry, still repeat.
+        assert!(key_repeats(AutoRepeatMode::ON, &auto_repeats, W + 1));
+
+        auto_repeats[3] |= 1 << 1;
+        assert!(key_repeats(AutoRepeatMode::ON, &auto_repeats, W));
+    }
+
+    #[test]
+    fn out_of_range_keycodes_repeat() {
+        assert!(key_repeats(AutoRepeatMode::ON, &[0; 32], 300));
+    }
+}
@@ -15,7 +15,6 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::sync::Arc;
 
 use bimap::BiMap;
 use calloop::RegistrationToken;
@@ -42,10 +41,8 @@ use smithay_client_toolkit::reexports::client::QueueHandle;
 use smithay_client_toolkit::reexports::client::backend::ObjectId as ClientObjectId;
 use smithay_client_toolkit::reexports::client::globals::GlobalList;
 use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface as ClientWlSurface;
+use smithay_client_toolkit::reexports::csd_frame::CursorIcon;
 use smithay_client_toolkit::shell::WaylandSurface;
-use smithay_client_toolkit::shell::xdg::XdgShell;
-use smithay_client_toolkit::shm::Shm;
-use smithay_client_toolkit::subcompositor::SubcompositorState;
 use tracing::Span;
 
 use crate::args;
@@ -57,10 +54,55 @@ use crate::serialization::geometry::Rectangle;
 use crate::serialization::wayland::KeyState;
 use crate::xwayland_xdg_shell::client::XWaylandSubSurface;
 
+pub mod allowed_actions;
 pub mod client;
 pub mod compositor;
+pub mod configure_timeout;
+pub mod csd;
+pub mod cursor;
 pub mod decoration;
+pub mod decoration_rules;
+pub mod drag;
+pub mod early_buffer;
+pub mod focus_loss;
+pub mod frame_buttons;
+pub mod frame_limit;
+pub mod frame_pacing;
+pub mod fullscreen;
+pub mod idle;
+pub mod input_region;
+pub mod key_repeat;
+pub mod mode_change;
+pub mod move_resize;
+pub mod no_output;
+pub mod opacity;
+pub mod pending_parents;
+pub mod pointer_constraints;
+pub mod pointer_leave;
+pub mod popup_grab;
+pub mod scale_override;
+pub mod scroll;
+pub mod seat;
+pub mod selection_limit;
+pub mod session;
+pub mod snapshot;
+pub mod stacking;
+pub mod startup;
+pub mod surface_limit;
+pub mod sync_request;
+pub mod tablet;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod title;
+pub mod touch;
+pub mod viewport;
+pub mod visual;
+pub mod window_layer;
+pub mod window_state;
 pub mod wmname;
+pub mod x11_connection;
+pub mod xdnd;
+pub mod xresources;
 pub mod xwayland;
 
 use client::Role;
@@ -68,10 +110,27 @@ use client::WprsClientState;
 use client::XWaylandBuffer;
 use client::XWaylandXdgPopup;
 use client::XWaylandXdgToplevel;
+use compositor::CompositorOptions;
 use compositor::DecorationBehavior;
+use compositor::TilingMode;
 use compositor::WprsCompositorState;
 use compositor::X11Parent;
 use compositor::XwaylandOptions;
+use cursor::CursorThemes;
+use frame_buttons::FrameButtons;
+use frame_limit::FramesInFlight;
+use input_region::LocalInputRegion;
+use mode_change::PendingResize;
+use scale_override::ScaleOverride;
+use seat::WprsSeat;
+use session::SessionProperties;
+use session::SessionWindows;
+use stacking::ZOrderedChildren;
+use surface_limit::SurfaceCounts;
+use surface_limit::SurfaceLimit;
+use viewport::LocalViewport;
+use window_layer::LayerPlacement;
+use window_layer::XWaylandLayerSurface;
 
 #[derive(Debug, Default)]
 pub struct XWaylandSurface {
@@ -82,9 +141,20 @@ pub struct XWaylandSurface {
     pub(crate) local_surface: Option<Surface>,
     pub(crate) role: Option<Role>,
     pub(crate) parent: Option<X11Parent>,
-    pub(crate) children: HashSet<CompositorObjectId>,
+    pub(crate) children: ZOrderedChildren<CompositorObjectId>,
     pub(crate) output_ids: HashSet<u32>,
     pub(crate) damage: Option<Vec<Rectangle<i32>>>,
+    /// Hash of the last committed buffer, see XWaylandBuffer::content_hash.
+    pub(crate) last_commit_hash: Option<u64>,
+    pub(crate) frames_in_flight: FramesInFlight,
+    /// Set while frames of the old size are held after an output mode change.
+    pub(crate) pending_resize: Option<PendingResize>,
+    /// Set when the app's buffers are upscaled, see scale_override.
+    pub(crate) scale_override: Option<ScaleOverride>,
+    pub(crate) viewport: LocalViewport,
+    pub(crate) input_region: LocalInputRegion,
+    /// Set for toplevels, see session.
+    pub(crate) session: Option<SessionProperties>,
 }
 
 impl XWaylandSurface {
@@ -110,9 +180,16 @@ impl XWaylandSurface {
             local_surface: Some(local_surface),
             role: None,
             parent: None,
-            children: HashSet::new(),
+            children: ZOrderedChildren::default(),
             output_ids: HashSet::new(),
             damage: None,
+            last_commit_hash: None,
+            frames_in_flight: FramesInFlight::default(),
+            pending_resize: None,
+            scale_override: None,
+            viewport: LocalViewport::default(),
+            input_region: LocalInputRegion::default(),
+            session: None,
         })
     }
 
@@ -130,6 +207,8 @@ impl XWaylandSurface {
 
         surface_bimap.insert(compositor_wl_surface.id(), local_surface.wl_surface().id());
         self.local_surface = Some(local_surface);
+        self.viewport = LocalViewport::default();
+        self.input_region = LocalInputRegion::default();
 
         Ok(())
     }
@@ -138,7 +217,11 @@ impl XWaylandSurface {
         match &self.role {
             Some(Role::XdgToplevel(toplevel)) if !toplevel.configured => false,
             Some(Role::XdgPopup(popup)) if !popup.configured => false,
-            _ => self.x11_surface.is_some() || matches!(self.role, Some(Role::Cursor)),
+            Some(Role::LayerSurface(layer)) if !layer.configured => false,
+            _ => {
+                self.x11_surface.is_some()
+                    || matches!(self.role, Some(Role::Cursor | Role::DragIcon))
+            },
         }
     }
 
@@ -146,6 +229,7 @@ impl XWaylandSurface {
         match &self.role {
             Some(Role::XdgToplevel(toplevel)) if !toplevel.configured => true,
             Some(Role::XdgPopup(popup)) if !popup.configured => true,
+            Some(Role::LayerSurface(layer)) if !layer.configured => true,
             _ => false,
         }
     }
@@ -189,23 +273,37 @@ impl XWaylandSurface {
         }
     }
 
-    #[instrument(skip(xdg_shell_state, qh), level = "debug")]
+    #[instrument(skip(client_state), level = "debug")]
     fn update_x11_surface(
         &mut self,
         x11_surface: X11Surface,
         x11_offset: Point<i32>,
         parent: Option<X11Parent>,
         fallback_parent: &Option<X11Parent>,
-        xdg_shell_state: &XdgShell,
-        shm_state: &Shm,
-        subcompositor_state: Arc<SubcompositorState>,
-        qh: &QueueHandle<WprsState>,
+        layer_placement: Option<LayerPlacement>,
+        client_state: &WprsClientState,
         decoration_behavior: DecorationBehavior,
+        tiling_mode: TilingMode,
     ) -> Result<()> {
         self.x11_surface = Some(x11_surface);
         if self.role.is_some() {
             return Ok(());
         }
+        let xdg_shell_state = &client_state.xdg_shell_state;
+        let shm_state = &client_state.shm_state;
+        let subcompositor_state = client_state.subcompositor_state.clone();
+        let qh = &client_state.qh;
+
+        // Desktops and docks, see window_layer.
+        if parent.is_none()
+            && let Some(layer_placement) = layer_placement
+            && let Some(layer_shell) = &client_state.layer_shell
+        {
+            debug!("creating layer surface for {self:?}");
+            self.parent = None;
+            return XWaylandLayerSurface::set_role(self, layer_placement, layer_shell, qh)
+                .location(loc!());
+        }
 
         let x11_surface = self.get_x11_surface().location(loc!())?;
 
@@ -276,6 +374,7 @@ impl XWaylandSurface {
                     subcompositor_state,
                     qh,
                     decoration_behavior,
+                    tiling_mode,
                 )
                 .location(loc!())?;
             },
@@ -292,6 +391,7 @@ impl XWaylandSurface {
                     subcompositor_state,
                     qh,
                     decoration_behavior,
+                    tiling_mode,
                 )
                 .location(loc!())?;
             },
@@ -342,16 +442,28 @@ impl XWaylandSurface {
 impl WaylandSurface for XWaylandSurface {
     fn wl_surface(&self) -> &ClientWlSurface {
         match &self.role {
-            None | Some(Role::Cursor) => self.local_surface.as_ref().unwrap().wl_surface(),
+            None | Some(Role::Cursor | Role::DragIcon) => {
+                self.local_surface.as_ref().unwrap().wl_surface()
+            },
             Some(Role::XdgToplevel(remote_xdg_toplevel)) => {
                 remote_xdg_toplevel.local_window.wl_surface()
             },
             Some(Role::XdgPopup(remote_xdg_popup)) => remote_xdg_popup.local_popup.wl_surface(),
             Some(Role::SubSurface(remote_subsurface)) => remote_subsurface.wl_surface(),
+            Some(Role::LayerSurface(remote_layer_surface)) => remote_layer_surface.wl_surface(),
         }
     }
 }
 
+pub struct ShellOptions {
+    pub compositor: CompositorOptions,
+    pub frame_buttons: FrameButtons,
+    /// 0 disables idle forwarding, see idle.
+    pub idle_timeout_ms: u32,
+    pub cursor_themes: CursorThemes,
+    pub surface_limit: SurfaceLimit,
+}
+
 #[derive(Debug)]
 pub struct WprsState {
     pub dh: DisplayHandle,
@@ -361,6 +473,11 @@ pub struct WprsState {
     pub compositor_state: WprsCompositorState,
     pub surface_bimap: BiMap<CompositorObjectId, ClientObjectId>,
     pub surfaces: HashMap<CompositorObjectId, XWaylandSurface>,
+    pub surface_limit: SurfaceLimit,
+    /// Mirrors surfaces, see surface_limit.
+    pub surface_counts: SurfaceCounts,
+    /// Mirrors the session properties of surfaces, see session.
+    pub session_windows: SessionWindows,
     pub outputs: HashMap<u32, Output>,
 }
 
@@ -371,7 +488,7 @@ impl WprsState {
         qh: QueueHandle<Self>,
         conn: Connection,
         event_loop_handle: LoopHandle<'static, Self>,
-        decoration_behavior: DecorationBehavior,
+        options: ShellOptions,
         xwayland_options: XwaylandOptions<K, V, I>,
     ) -> Result<Self>
     where
@@ -379,43 +496,96 @@ impl WprsState {
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        let mut registration_tokens = vec![];
+        let mut state = Self::without_xwayland(dh, globals, qh, conn, event_loop_handle, options)
+            .location(loc!())?;
+        compositor::start_xwayland(
+            &state.dh,
+            &state.event_loop_handle,
+            xwayland_options,
+            &mut state.registration_tokens,
+        );
+        Ok(state)
+    }
+
+    /// The state before xwayland is launched, see WprsState::new.
+    pub(crate) fn without_xwayland(
+        dh: DisplayHandle,
+        globals: &GlobalList,
+        qh: QueueHandle<Self>,
+        conn: Connection,
+        event_loop_handle: LoopHandle<'static, Self>,
+        options: ShellOptions,
+    ) -> Result<Self> {
+        wmname::validate_wmname(&options.compositor.wm_name).location(loc!())?;
         Ok(Self {
             dh: dh.clone(),
-            event_loop_handle: event_loop_handle.clone(),
-            client_state: WprsClientState::new(globals, qh, conn).location(loc!())?,
-            compositor_state: WprsCompositorState::new(
-                dh,
-                &event_loop_handle,
-                decoration_behavior,
-                xwayland_options,
-                &mut registration_tokens,
-            ),
+            event_loop_handle,
+            client_state: WprsClientState::new(
+                globals,
+                qh,
+                conn,
+                options.frame_buttons,
+                options.idle_timeout_ms,
+                options.cursor_themes,
+            )
+            .location(loc!())?,
+            compositor_state: WprsCompositorState::new(dh, options.compositor),
             surface_bimap: BiMap::new(),
             surfaces: HashMap::new(),
+            surface_limit: options.surface_limit,
+            surface_counts: SurfaceCounts::new(),
+            session_windows: SessionWindows::new(),
             outputs: HashMap::new(),
-            registration_tokens,
+            registration_tokens: Vec::new(),
         })
     }
 
+    /// Whether `surface_id` is `ancestor_id` or one of its (transitive)
+    /// children.
+    pub(crate) fn is_descendant(
+        &self,
+        surface_id: &CompositorObjectId,
+        ancestor_id: &CompositorObjectId,
+    ) -> bool {
+        surface_id == ancestor_id
+            || self.surfaces.get(ancestor_id).is_some_and(|ancestor| {
+                ancestor
+                    .children
+                    .iter()
+                    .any(|child| self.is_descendant(surface_id, child))
+            })
+    }
+
     #[instrument(skip(self), level = "debug")]
     pub fn remove_surface(&mut self, surface_id: &CompositorObjectId) {
         let children = match self.surfaces.get(surface_id) {
             Some(surface) => surface.children.clone(),
-            None => HashSet::new(),
+            None => ZOrderedChildren::default(),
         };
 
-        for child in children {
-            self.remove_surface(&child);
+        for child in children.iter() {
+            self.remove_surface(child);
         }
 
+        self.surface_counts.remove(surface_id);
+        self.session_windows.remove(surface_id);
+        self.forget_pointer_constraints(surface_id);
+        for wprs_seat in self.compositor_state.seats.values_mut() {
+            if wprs_seat.cursor_surface.destroyed(surface_id) {
+                seat::themed_pointer(&self.client_state, &wprs_seat.seat)
+                    .and_then(|themed_pointer| {
+                        themed_pointer
+                            .set_cursor(&self.client_state.conn, CursorIcon::Default)
+                            .location(loc!())
+                    })
+                    .log_and_ignore(loc!());
+            }
+        }
         if let Some(xwayland_surface) = self.surfaces.remove(surface_id)
             && let Some(parent) = xwayland_surface.parent
         {
             let parent_xwayland_surface = self.surfaces.get_mut(&parent.surface_id).unwrap();
-            parent_xwayland_surface
-                .children
-                .retain(|child_surface_id| child_surface_id != surface_id);
+            parent_xwayland_surface.children.remove(surface_id);
         }
 
         // this MUST come after removing xwayland_surface, because xwayland_surface's role needs
@@ -425,6 +595,24 @@ impl WprsState {
         self.surface_bimap.remove_by_left(surface_id);
     }
 
+    /// Releases all keys held on `seat_name`, e.g. before the keymap changes
+    /// so that clients see the release of the same keysyms they saw pressed
+    /// rather than of whatever the new keymap maps the keys to. The keys are
+    /// forgotten, so their eventual releases are dropped.
+    pub(crate) fn release_pressed_keys(&mut self, seat_name: &str, serial: Serial) -> Result<()> {
+        let pressed_keys = self
+            .compositor_state
+            .seat(seat_name)
+            .location(loc!())?
+            .pressed_keys
+            .clone();
+        for keycode in pressed_keys {
+            self.set_key_state(seat_name, keycode, KeyState::Released, serial)
+                .location(loc!())?;
+        }
+        Ok(())
+    }
+
     #[instrument(
         skip(self, keycode, state),
         fields(keycode = "<redacted>", state = "<redacted>"),
@@ -432,11 +620,30 @@ impl WprsState {
     )]
     pub(crate) fn set_key_state(
         &mut self,
+        seat_name: &str,
         keycode: u32,
         state: KeyState,
         serial: Serial,
     ) -> Result<()> {
-        let keyboard = self.compositor_state.seat.get_keyboard().location(loc!())?;
+        let keyboard = self
+            .compositor_state
+            .seat(seat_name)
+            .and_then(WprsSeat::keyboard)
+            .location(loc!())?;
+        self.reset_idle();
+
+        // Clients already saw the keys which were held when the keymap
+        // changed released, see release_pressed_keys.
+        if state != KeyState::Pressed
+            && !self
+                .compositor_state
+                .seat(seat_name)
+                .location(loc!())?
+                .pressed_keys
+                .contains(&keycode)
+        {
+            return Ok(());
+        }
 
         if args::get_log_priv_data() {
             Span::current().record("keycode", field::debug(&keycode));
@@ -455,11 +662,12 @@ impl WprsState {
             FilterResult::Forward
         }
 
-        // our keycode is getting offset by 8 for reasons
-        // see https://github.com/Smithay/smithay/pull/1536
-        let x11_keycode = (keycode + 8).into();
+        let x11_keycode = compositor_utils::xkb_keycode(keycode);
         let time = self.compositor_state.start_time.elapsed().as_millis() as u32;
         match state {
+            // The key isn't marked as pressed, so its release isn't forwarded
+            // either.
+            KeyState::Pressed if self.escape_pointer_constraint(seat_name, keycode) => {},
             KeyState::Pressed => {
                 keyboard.input::<(), _>(
                     self,
@@ -469,7 +677,11 @@ impl WprsState {
                     time,
                     filter,
                 );
-                self.compositor_state.pressed_keys.insert(keycode);
+                self.compositor_state
+                    .seat_mut(seat_name)
+                    .location(loc!())?
+                    .pressed_keys
+                    .insert(keycode);
             },
             KeyState::Released => {
                 keyboard.input::<(), _>(
@@ -480,8 +692,13 @@ impl WprsState {
                     time,
                     filter,
                 );
-                self.compositor_state.pressed_keys.remove(&keycode);
+                self.compositor_state
+                    .seat_mut(seat_name)
+                    .location(loc!())?
+                    .pressed_keys
+                    .remove(&keycode);
             },
+            KeyState::Repeated if !self.forward_key_repeat(keycode) => {},
             KeyState::Repeated => {
                 // Map repeated to released + pressed
                 // Smithay 0.7 keystates don't support repetition
@@ -547,6 +764,10 @@ impl WprsState {
             &xwayland_surface.output_ids,
             |id| self.outputs.get(id),
         );
+        compositor_utils::send_preferred_buffer_scale(
+            &compositor_surface,
+            self.compositor_state.preferred_buffer_scale(&new_ids),
+        );
 
         xwayland_surface.output_ids = new_ids;
     }
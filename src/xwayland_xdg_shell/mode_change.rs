@@ -0,0 +1,179 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of frames committed during output mode changes. Maximized and
+/// fullscreen windows are resized along with their output, but until the app
+/// has redrawn at the new size it keeps committing frames of the old size,
+/// which the local compositor would show stretched or cropped. These stale
+/// frames can be held back, keeping the last frame on screen until the app
+/// commits one of a different size or a timeout passes.
+use std::time::Duration;
+use std::time::Instant;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay::reexports::wayland_server::backend::ObjectId;
+use smithay::utils::Logical;
+use smithay::utils::Size;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::XWaylandSurface;
+use crate::xwayland_xdg_shell::client::Role;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ModeChangeBehavior {
+    /// Show frames of the old size as they're committed.
+    Present,
+    /// Hold frames of the old size for up to timeout_ms.
+    Hold { timeout_ms: u32 },
+}
+
+/// A resize which a mode change is expected to cause.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct PendingResize {
+    stale_size: Size<i32, Logical>,
+    since: Instant,
+}
+
+impl PendingResize {
+    pub(crate) fn new(stale_size: Size<i32, Logical>, since: Instant) -> Self {
+        Self { stale_size, since }
+    }
+
+    /// Whether a frame of `buffer_size` committed at `now` is stale and should
+    /// be held.
+    pub(crate) fn hold(
+        &self,
+        buffer_size: Size<i32, Logical>,
+        now: Instant,
+        timeout: Duration,
+    ) -> bool {
+        buffer_size == self.stale_size && now.saturating_duration_since(self.since) < timeout
+    }
+}
+
+impl XWaylandSurface {
+    /// Whether the surface's latest frame is stale and should be held. Stops
+    /// holding frames once one isn't.
+    pub(crate) fn hold_stale_frame(&mut self, behavior: ModeChangeBehavior) -> bool {
+        let (Some(pending_resize), Some(buffer), ModeChangeBehavior::Hold { timeout_ms }) =
+            (self.pending_resize, &self.buffer, behavior)
+        else {
+            return false;
+        };
+        let buffer_size = (buffer.metadata.width, buffer.metadata.height).into();
+        let timeout = Duration::from_millis(timeout_ms.into());
+        if pending_resize.hold(buffer_size, Instant::now(), timeout) {
+            return true;
+        }
+        self.pending_resize = None;
+        false
+    }
+}
+
+impl WprsState {
+    /// Starts holding stale frames of the maximized and fullscreen windows on
+    /// the output `output_id`, whose mode just changed.
+    pub(crate) fn start_mode_change(&mut self, output_id: u32) {
+        let ModeChangeBehavior::Hold { timeout_ms } = self.compositor_state.mode_change_behavior
+        else {
+            return;
+        };
+        let timeout = Duration::from_millis(timeout_ms.into());
+        let now = Instant::now();
+
+        for (surface_id, xwayland_surface) in &mut self.surfaces {
+            if !xwayland_surface.output_ids.contains(&output_id)
+                || !matches!(xwayland_surface.role, Some(Role::XdgToplevel(_)))
+                || !xwayland_surface
+                    .x11_surface
+                    .as_ref()
+                    .is_some_and(|x11_surface| {
+                        x11_surface.is_maximized() || x11_surface.is_fullscreen()
+                    })
+            {
+                continue;
+            }
+            let Some(buffer) = &xwayland_surface.buffer else {
+                continue;
+            };
+            let pending_resize =
+                PendingResize::new((buffer.metadata.width, buffer.metadata.height).into(), now);
+            xwayland_surface.pending_resize = Some(pending_resize);
+            arm_timeout(
+                &self.event_loop_handle,
+                surface_id.clone(),
+                pending_resize,
+                timeout,
+            );
+        }
+    }
+}
+
+/// Shows the latest frame if the app hasn't resized by the timeout.
+fn arm_timeout(
+    event_loop_handle: &LoopHandle<'static, WprsState>,
+    surface_id: ObjectId,
+    pending_resize: PendingResize,
+    timeout: Duration,
+) {
+    event_loop_handle
+        .insert_source(Timer::from_duration(timeout), move |_, _, state| {
+            let qh = state.client_state.qh.clone();
+            if let Some(xwayland_surface) = state.surfaces.get_mut(&surface_id)
+                && xwayland_surface.pending_resize == Some(pending_resize)
+            {
+                debug!("{surface_id:?} didn't resize after a mode change, showing its frame");
+                xwayland_surface.pending_resize = None;
+                if xwayland_surface.ready() {
+                    xwayland_surface.commit_buffer(&qh);
+                }
+            }
+            TimeoutAction::Drop
+        })
+        .map_err(|e| anyhow!("failed to insert mode change timer: {e}"))
+        .log_and_ignore(loc!());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_size_is_held_until_resize() {
+        let timeout = Duration::from_millis(500);
+        let mode_change = Instant::now();
+        let pending_resize = PendingResize::new((1920, 1080).into(), mode_change);
+
+        // The app had a commit for the old size pending when the mode changed.
+        let commit = mode_change + Duration::from_millis(10);
+        assert!(pending_resize.hold((1920, 1080).into(), commit, timeout));
+
+        // Then it resizes in response to the configure.
+        let commit = mode_change + Duration::from_millis(30);
+        assert!(!pending_resize.hold((1280, 720).into(), commit, timeout));
+    }
+
+    #[test]
+    fn old_size_is_shown_after_timeout() {
+        let timeout = Duration::from_millis(500);
+        let mode_change = Instant::now();
+        let pending_resize = PendingResize::new((1920, 1080).into(), mode_change);
+        assert!(!pending_resize.hold((1920, 1080).into(), mode_change + timeout, timeout));
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Interactive moves and resizes which X11 apps with client-side decorations
+/// request with _NET_WM_MOVERESIZE while a button is held on their titlebar or
+/// border. They're handed to the local compositor as xdg_toplevel moves and
+/// resizes, during which it has the pointer, so the button's release never
+/// reaches the app: the buttons held on the window are released once the local
+/// compositor gives the pointer back.
+///
+/// Apps abort with _NET_WM_MOVERESIZE_CANCEL, e.g. when they got the button
+/// release before the move started, but smithay's X11Wm drops it, so it's
+/// received on a connection of our own. xdg_toplevel has no request to end a
+/// move or resize, so cancelling releases the held buttons at once and puts the
+/// window back where it was when it started.
+use std::os::fd::AsFd;
+
+use smithay::backend::input::ButtonState;
+use smithay::input::Seat;
+use smithay::input::pointer::ButtonEvent;
+use smithay::reexports::calloop::Interest;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::Mode;
+use smithay::reexports::calloop::PostAction;
+use smithay::reexports::calloop::generic::Generic;
+use smithay::utils::Logical;
+use smithay::utils::Rectangle;
+use smithay::utils::SERIAL_COUNTER;
+use smithay::xwayland::X11Surface;
+use smithay::xwayland::xwm::ResizeEdge as X11ResizeEdge;
+use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_toplevel::ResizeEdge;
+use x11rb::connection::Connection as X11Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::ChangeWindowAttributesAux;
+use x11rb::protocol::xproto::ClientMessageEvent;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::EventMask;
+use x11rb::rust_connection::RustConnection;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::seat;
+use crate::xwayland_xdg_shell::xsurface_from_x11_surface;
+
+/// The _NET_WM_MOVERESIZE direction which cancels a move or resize.
+const NET_WM_MOVERESIZE_CANCEL: u32 = 11;
+
+/// A move or resize the local compositor is doing for an X11 window.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MoveResize {
+    window: u32,
+    /// The seat whose pointer is doing it.
+    seat_name: String,
+    /// The geometry of the window when it started.
+    geometry: Rectangle<i32, Logical>,
+}
+
+/// An X11 connection on which _NET_WM_MOVERESIZE messages are received.
+#[derive(Debug)]
+pub(crate) struct MoveResizeWatcher {
+    conn: RustConnection,
+    atom: Atom,
+}
+
+impl MoveResizeWatcher {
+    pub(crate) fn start(
+        display_number: u32,
+        event_loop_handle: &LoopHandle<'static, WprsState>,
+    ) -> Result<Self> {
+        let (conn, screen_num) =
+            x11rb::connect(Some(&format!(":{display_number}"))).location(loc!())?;
+        let atom = conn
+            .intern_atom(false, b"_NET_WM_MOVERESIZE")
+            .location(loc!())?
+            .reply()
+            .location(loc!())?
+            .atom;
+        // Apps send _NET_WM_MOVERESIZE to the root window for the clients
+        // selecting SubstructureRedirect or SubstructureNotify on it. The
+        // former is the WM's, the latter can be selected by any client.
+        let root = conn.setup().roots[screen_num].root;
+        conn.change_window_attributes(
+            root,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::SUBSTRUCTURE_NOTIFY),
+        )
+        .location(loc!())?
+        .check()
+        .location(loc!())?;
+        let fd = conn
+            .stream()
+            .as_fd()
+            .try_clone_to_owned()
+            .location(loc!())?;
+        event_loop_handle
+            .insert_source(
+                Generic::new(fd, Interest::READ, Mode::Level),
+                |_, _, state| {
+                    state.handle_move_resize_events();
+                    Ok(PostAction::Continue)
+                },
+            )
+            .map_err(|e| anyhow!("failed to insert move/resize watcher: {e}"))
+            .location(loc!())?;
+        Ok(Self { conn, atom })
+    }
+
+    /// The _NET_WM_MOVERESIZE messages received since the last call, in order.
+    fn poll_messages(&self) -> Result<Vec<ClientMessageEvent>> {
+        let mut messages = Vec::new();
+        while let Some(event) = self.conn.poll_for_event().location(loc!())? {
+            match event {
+                Event::ClientMessage(event) if event.type_ == self.atom => messages.push(event),
+                Event::Error(error) => debug!("move/resize watcher error: {error:?}"),
+                _ => {},
+            }
+        }
+        Ok(messages)
+    }
+}
+
+fn local_resize_edge(edge: X11ResizeEdge) -> ResizeEdge {
+    match edge {
+        X11ResizeEdge::Top => ResizeEdge::Top,
+        X11ResizeEdge::Bottom => ResizeEdge::Bottom,
+        X11ResizeEdge::Left => ResizeEdge::Left,
+        X11ResizeEdge::TopLeft => ResizeEdge::TopLeft,
+        X11ResizeEdge::BottomLeft => ResizeEdge::BottomLeft,
+        X11ResizeEdge::Right => ResizeEdge::Right,
+        X11ResizeEdge::TopRight => ResizeEdge::TopRight,
+        X11ResizeEdge::BottomRight => ResizeEdge::BottomRight,
+    }
+}
+
+/// Whether the pointer of `seat` has an implicit grab on `window`, i.e. a
+/// button is held on it.
+fn holds_button_on(seat: &Seat<WprsState>, window: u32) -> bool {
+    seat.get_pointer()
+        .and_then(|pointer| pointer.grab_start_data())
+        .and_then(|start| start.focus)
+        .is_some_and(|(focus, _)| focus.window_id() == window)
+}
+
+impl WprsState {
+    /// Has the local compositor move `window`, or resize it from `edge` if
+    /// that's set, with the pointer button held on it.
+    pub(crate) fn start_move_resize(
+        &mut self,
+        window: &X11Surface,
+        edge: Option<X11ResizeEdge>,
+    ) -> Result<()> {
+        let (seat_name, wprs_seat) = self
+            .compositor_state
+            .seats
+            .iter()
+            .find(|(_, wprs_seat)| holds_button_on(&wprs_seat.seat, window.window_id()))
+            .with_context(loc!(), || {
+                format!("no button is held on window {}", window.window_id())
+            })?;
+        let local_seat = &seat::seat_object(&self.client_state, &wprs_seat.seat)
+            .location(loc!())?
+            .seat;
+        let serial = self
+            .client_state
+            .last_button_press_serial
+            .location(loc!())?;
+        let Some(Role::XdgToplevel(toplevel)) =
+            xsurface_from_x11_surface(&mut self.surfaces, window)
+                .and_then(|xwayland_surface| xwayland_surface.role.as_ref())
+        else {
+            bail!("window {} is not a toplevel", window.window_id());
+        };
+        match edge {
+            Some(edge) => {
+                toplevel
+                    .local_window
+                    .resize(local_seat, serial, local_resize_edge(edge));
+            },
+            None => toplevel.local_window.move_(local_seat, serial),
+        }
+        debug!("started move/resize of window {}", window.window_id());
+        self.compositor_state.move_resize = Some(MoveResize {
+            window: window.window_id(),
+            seat_name: seat_name.clone(),
+            geometry: window.geometry(),
+        });
+        Ok(())
+    }
+
+    /// Releases the buttons held on the window being moved or resized with
+    /// the pointer of `seat_name`, as the local compositor has given the
+    /// pointer back.
+    pub(crate) fn end_move_resize(&mut self, seat_name: &str) {
+        let Some(move_resize) = self
+            .compositor_state
+            .move_resize
+            .take_if(|move_resize| move_resize.seat_name == seat_name)
+        else {
+            return;
+        };
+        debug!("move/resize of window {} ended", move_resize.window);
+        self.release_buttons(&move_resize);
+    }
+
+    /// Ends the move or resize of `window` and puts it back where it was when
+    /// it started.
+    fn cancel_move_resize(&mut self, window: u32) {
+        let Some(move_resize) = self
+            .compositor_state
+            .move_resize
+            .take_if(|move_resize| move_resize.window == window)
+        else {
+            return;
+        };
+        debug!("move/resize of window {window} was cancelled");
+        self.release_buttons(&move_resize);
+        if let Some(x11_surface) = self
+            .surfaces
+            .values()
+            .filter_map(|xwayland_surface| xwayland_surface.x11_surface.as_ref())
+            .find(|x11_surface| x11_surface.window_id() == window)
+            && x11_surface.geometry() != move_resize.geometry
+        {
+            x11_surface
+                .configure(move_resize.geometry)
+                .log_and_ignore(loc!());
+        }
+    }
+
+    /// Releases the buttons held on the window of `move_resize`, ending the
+    /// implicit grab which holding them started.
+    fn release_buttons(&mut self, move_resize: &MoveResize) {
+        let Ok(wprs_seat) = self
+            .compositor_state
+            .seat(&move_resize.seat_name)
+            .warn(loc!())
+        else {
+            return;
+        };
+        let seat = wprs_seat.seat.clone();
+        let Some(pointer) = seat.get_pointer() else {
+            return;
+        };
+        if !holds_button_on(&seat, move_resize.window) {
+            return;
+        }
+        if let Some(start) = pointer.grab_start_data() {
+            pointer.button(
+                self,
+                &ButtonEvent {
+                    time: 0, // unused
+                    button: start.button,
+                    serial: SERIAL_COUNTER.next_serial(),
+                    state: ButtonState::Released,
+                },
+            );
+            pointer.frame(self);
+        }
+        // Other buttons held too, which the app will have to do without.
+        if pointer.is_grabbed() {
+            pointer.unset_grab(self, SERIAL_COUNTER.next_serial(), 0);
+        }
+    }
+
+    /// Handles a _NET_WM_MOVERESIZE message. Only cancels need handling,
+    /// smithay's X11Wm handles the rest, see move_request and resize_request.
+    pub(crate) fn handle_move_resize_message(&mut self, event: &ClientMessageEvent) {
+        if event.format == 32 && event.data.as_data32()[2] == NET_WM_MOVERESIZE_CANCEL {
+            self.cancel_move_resize(event.window);
+        }
+    }
+
+    fn handle_move_resize_events(&mut self) {
+        let Some(watcher) = &self.compositor_state.move_resize_watcher else {
+            return;
+        };
+        let Ok(messages) = watcher.poll_messages().warn(loc!()) else {
+            return;
+        };
+        for message in &messages {
+            self.handle_move_resize_message(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay::input::pointer::MotionEvent;
+    use smithay::reexports::wayland_server::Resource;
+
+    use super::*;
+    use crate::xwayland_xdg_shell::testing;
+    use crate::xwayland_xdg_shell::testing::Harness;
+
+    const BTN_LEFT: u32 = 0x110;
+
+    /// A _NET_WM_MOVERESIZE message from `window`, pressed with the first
+    /// button.
+    fn move_resize_message(window: u32, direction: u32) -> ClientMessageEvent {
+        ClientMessageEvent::new(32, window, 0u32, [10, 10, direction, 1, 1])
+    }
+
+    #[test]
+    fn cancelled_move_leaves_no_grab() {
+        let mut harness = Harness::new(testing::options());
+        let surface = harness.xwayland.create_surface();
+        let window = harness.map_x11_window(&surface, None);
+        let buffer = harness.xwayland.create_buffer(8, 8, &[0; 8 * 8 * 4]);
+        harness.xwayland.commit(&surface, Some(&buffer));
+        harness.dispatch();
+        let x11_surface = harness.state.surfaces[&harness.surface(&surface).id()]
+            .x11_surface
+            .clone()
+            .unwrap();
+
+        // The app's titlebar is pressed.
+        let pointer = harness
+            .state
+            .compositor_state
+            .seat("seat0")
+            .unwrap()
+            .seat
+            .get_pointer()
+            .unwrap();
+        pointer.motion(
+            &mut harness.state,
+            Some((x11_surface.clone(), (0.0, 0.0).into())),
+            &MotionEvent {
+                location: (10.0, 10.0).into(),
+                serial: SERIAL_COUNTER.next_serial(),
+                time: 0,
+            },
+        );
+        pointer.button(
+            &mut harness.state,
+            &ButtonEvent {
+                time: 0,
+                button: BTN_LEFT,
+                serial: SERIAL_COUNTER.next_serial(),
+                state: ButtonState::Pressed,
+            },
+        );
+        harness.state.client_state.last_button_press_serial = Some(1);
+
+        harness.state.start_move_resize(&x11_surface, None).unwrap();
+        harness.dispatch();
+        assert!(harness.moved_or_resized(&surface));
+        assert!(pointer.is_grabbed());
+
+        // Smithay's X11Wm handles the other directions.
+        harness
+            .state
+            .handle_move_resize_message(&move_resize_message(window, 8));
+        assert!(pointer.is_grabbed());
+
+        harness
+            .state
+            .handle_move_resize_message(&move_resize_message(window, NET_WM_MOVERESIZE_CANCEL));
+        assert!(!pointer.is_grabbed());
+        assert_eq!(harness.state.compositor_state.move_resize, None);
+    }
+}
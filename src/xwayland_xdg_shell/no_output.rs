@@ -0,0 +1,106 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of X11 windows committed before the local compositor announced
+/// any output. Windows are placed relative to the X11 screen offset, which is
+/// derived from the first output (see WprsCompositorState::new_output), so
+/// until then there is nowhere to place them. Scales and transforms need no
+/// special handling: surfaces on no output get scale 1 and no transform, and
+/// are updated when they enter the output.
+use std::mem;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::wayland_server::Resource;
+
+use crate::prelude::*;
+use crate::serialization::geometry::Point;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::compositor;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum NoOutputBehavior {
+    /// Hold the windows until the first output appears.
+    #[default]
+    Wait,
+    /// Map the windows right away as if the X11 screen weren't offset, and
+    /// move them into place once the first output appears.
+    AssumeDefaults,
+}
+
+impl NoOutputBehavior {
+    /// The X11 screen offset to map windows with, or None if they have to
+    /// wait for an output.
+    pub(crate) fn x11_offset(self, screen_offset: Option<Point<i32>>) -> Option<Point<i32>> {
+        match (screen_offset, self) {
+            (Some(screen_offset), _) => Some(screen_offset),
+            (None, Self::Wait) => None,
+            (None, Self::AssumeDefaults) => Some((0, 0).into()),
+        }
+    }
+}
+
+impl WprsState {
+    /// Places the windows committed before the first output, which just
+    /// appeared.
+    #[instrument(skip(self), level = "debug")]
+    pub(crate) fn place_windows_awaiting_output(&mut self) {
+        let Some(x11_offset) = self.compositor_state.x11_screen_offset else {
+            return;
+        };
+
+        // Mapped with AssumeDefaults.
+        for xwayland_surface in self.surfaces.values_mut() {
+            if let Some(Role::XdgToplevel(toplevel)) = &mut xwayland_surface.role
+                && let Some(x11_surface) = &xwayland_surface.x11_surface
+            {
+                toplevel.x11_offset = x11_offset;
+                let mut geometry = x11_surface.geometry();
+                geometry.loc = (-x11_offset.x, -x11_offset.y).into();
+                x11_surface.configure(geometry).log_and_ignore(loc!());
+            }
+        }
+
+        // Held with Wait.
+        for surface in mem::take(&mut self.compositor_state.surfaces_awaiting_output) {
+            if surface.is_alive() {
+                debug!("replaying commit of {surface:?} held for an output");
+                compositor::execute_or_defer_commit(self, surface).log_and_ignore(loc!());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_wait_for_first_output() {
+        assert_eq!(NoOutputBehavior::Wait.x11_offset(None), None);
+        assert_eq!(
+            NoOutputBehavior::AssumeDefaults.x11_offset(None),
+            Some((0, 0).into())
+        );
+    }
+
+    #[test]
+    fn known_offset_is_used() {
+        let screen_offset = Some((-1920, -1080).into());
+        for behavior in [NoOutputBehavior::Wait, NoOutputBehavior::AssumeDefaults] {
+            assert_eq!(behavior.x11_offset(screen_offset), screen_offset);
+        }
+    }
+}
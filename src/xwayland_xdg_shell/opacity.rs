@@ -0,0 +1,475 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Forwarding of window opacity (_NET_WM_WINDOW_OPACITY) to the local
+/// compositor with wp-alpha-modifier-v1. Apps fading their windows in or out
+/// change the property many times per second, so changes are only recorded as
+/// they arrive and forwarded at most once per frame. The forwarded opacity can
+/// additionally be interpolated between the values the app set, over the time
+/// between them but never longer than a configured bound, so that coarse fades
+/// look smooth while a jump to a new opacity still lands promptly.
+use std::collections::HashMap;
+use std::os::fd::AsFd;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::Interest;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::Mode;
+use smithay::reexports::calloop::PostAction;
+use smithay::reexports::calloop::generic::Generic;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::reexports::protocols::wp::alpha_modifier::v1::client::wp_alpha_modifier_surface_v1;
+use smithay_client_toolkit::reexports::protocols::wp::alpha_modifier::v1::client::wp_alpha_modifier_surface_v1::WpAlphaModifierSurfaceV1;
+use smithay_client_toolkit::reexports::protocols::wp::alpha_modifier::v1::client::wp_alpha_modifier_v1::WpAlphaModifierV1;
+use smithay_client_toolkit::registry::SimpleGlobal;
+use smithay_client_toolkit::shell::WaylandSurface;
+use x11rb::connection::Connection as X11Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ChangeWindowAttributesAux;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::EventMask;
+use x11rb::protocol::xproto::Property;
+use x11rb::rust_connection::RustConnection;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::Role;
+
+/// Both _NET_WM_WINDOW_OPACITY and wp_alpha_modifier_surface_v1 scale opacity
+/// to the full u32 range.
+pub const OPAQUE: u32 = u32::MAX;
+
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum OpacityInterpolation {
+    /// Forward the latest opacity set by the app once per frame.
+    Disabled,
+    /// Fade between the opacities set by the app over the time between them,
+    /// but at most max_ms.
+    Linear { max_ms: u32 },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct OpacityAnimation {
+    from: u32,
+    to: u32,
+    start: Instant,
+    duration: Duration,
+    /// When the app last changed the opacity.
+    last_change: Instant,
+}
+
+impl OpacityAnimation {
+    fn new(opacity: u32, now: Instant) -> Self {
+        Self {
+            from: opacity,
+            to: opacity,
+            start: now,
+            duration: Duration::ZERO,
+            last_change: now,
+        }
+    }
+
+    /// Animates from the current opacity to `to`.
+    fn retarget(&mut self, to: u32, now: Instant, interpolation: OpacityInterpolation) {
+        let duration = match interpolation {
+            OpacityInterpolation::Disabled => Duration::ZERO,
+            OpacityInterpolation::Linear { max_ms } => now
+                .saturating_duration_since(self.last_change)
+                .min(Duration::from_millis(max_ms.into())),
+        };
+        *self = Self {
+            from: self.value(now),
+            to,
+            start: now,
+            duration,
+            last_change: now,
+        };
+    }
+
+    fn value(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            return self.to;
+        }
+        let t = elapsed.as_secs_f64() / self.duration.as_secs_f64();
+        let (from, to) = (f64::from(self.from), f64::from(self.to));
+        (from + (to - from) * t).round() as u32
+    }
+
+    fn done(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) >= self.duration
+    }
+}
+
+#[derive(Debug)]
+struct WindowOpacity {
+    animation: OpacityAnimation,
+    /// The opacity of the local surface. Local surfaces start out opaque.
+    forwarded: u32,
+    alpha_surface: Option<WpAlphaModifierSurfaceV1>,
+}
+
+/// The opacities of X11 windows, keyed by window id.
+#[derive(Debug)]
+pub(crate) struct WindowOpacities {
+    interpolation: OpacityInterpolation,
+    windows: HashMap<u32, WindowOpacity>,
+    ticker_armed: bool,
+}
+
+impl WindowOpacities {
+    pub(crate) fn new(interpolation: OpacityInterpolation) -> Self {
+        Self {
+            interpolation,
+            windows: HashMap::new(),
+            ticker_armed: false,
+        }
+    }
+
+    /// Records that the app set the opacity of `window`.
+    fn set(&mut self, window: u32, opacity: u32, now: Instant) {
+        match self.windows.get_mut(&window) {
+            Some(window_opacity) => {
+                window_opacity
+                    .animation
+                    .retarget(opacity, now, self.interpolation);
+            },
+            // The initial opacity of a window isn't animated.
+            None => {
+                self.windows.insert(
+                    window,
+                    WindowOpacity {
+                        animation: OpacityAnimation::new(opacity, now),
+                        forwarded: OPAQUE,
+                        alpha_surface: None,
+                    },
+                );
+            },
+        }
+    }
+
+    fn remove(&mut self, window: u32) {
+        if let Some(alpha_surface) = self
+            .windows
+            .remove(&window)
+            .and_then(|window_opacity| window_opacity.alpha_surface)
+        {
+            alpha_surface.destroy();
+        }
+    }
+
+    /// The opacity to forward for `window`, if it changed since it was last
+    /// forwarded.
+    fn pending(&self, window: u32, now: Instant) -> Option<u32> {
+        let window_opacity = self.windows.get(&window)?;
+        let opacity = window_opacity.animation.value(now);
+        (opacity != window_opacity.forwarded).then_some(opacity)
+    }
+
+    fn animating(&self, now: Instant) -> bool {
+        self.windows
+            .values()
+            .any(|window_opacity| !window_opacity.animation.done(now))
+    }
+
+    /// Sets the opacity of `surface`, the local surface of `window`, if it
+    /// changed. The change is applied with the next commit of `surface`.
+    /// Returns whether it changed.
+    pub(crate) fn apply(
+        &mut self,
+        window: u32,
+        surface: &WlSurface,
+        alpha_modifier: Option<&SimpleGlobal<WpAlphaModifierV1, 1>>,
+        qh: &QueueHandle<WprsState>,
+    ) -> bool {
+        let Some(opacity) = self.pending(window, Instant::now()) else {
+            return false;
+        };
+        let Some(alpha_modifier) = alpha_modifier.and_then(|global| global.get().ok()) else {
+            return false;
+        };
+        let window_opacity = self.windows.get_mut(&window).unwrap();
+        window_opacity
+            .alpha_surface
+            .get_or_insert_with(|| alpha_modifier.get_surface(surface, qh, ()))
+            .set_multiplier(opacity);
+        window_opacity.forwarded = opacity;
+        true
+    }
+}
+
+/// An X11 connection on which we're notified of opacity changes.
+#[derive(Debug)]
+pub(crate) struct OpacityWatcher {
+    conn: RustConnection,
+    atom: Atom,
+}
+
+impl OpacityWatcher {
+    pub(crate) fn start(
+        display_number: u32,
+        event_loop_handle: &LoopHandle<'static, WprsState>,
+    ) -> Result<Self> {
+        let (conn, _) = x11rb::connect(Some(&format!(":{display_number}"))).location(loc!())?;
+        let atom = conn
+            .intern_atom(false, b"_NET_WM_WINDOW_OPACITY")
+            .location(loc!())?
+            .reply()
+            .location(loc!())?
+            .atom;
+        let fd = conn
+            .stream()
+            .as_fd()
+            .try_clone_to_owned()
+            .location(loc!())?;
+        event_loop_handle
+            .insert_source(
+                Generic::new(fd, Interest::READ, Mode::Level),
+                |_, _, state| {
+                    state.handle_opacity_events();
+                    Ok(PostAction::Continue)
+                },
+            )
+            .map_err(|e| anyhow!("failed to insert opacity watcher: {e}"))
+            .location(loc!())?;
+        Ok(Self { conn, atom })
+    }
+
+    fn fetch_opacity(&self, window: u32) -> Result<u32> {
+        let reply = self
+            .conn
+            .get_property(false, window, self.atom, AtomEnum::CARDINAL, 0, 1)
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        Ok(reply
+            .value32()
+            .and_then(|mut values| values.next())
+            .unwrap_or(OPAQUE))
+    }
+
+    /// Starts watching `window` and returns its current opacity.
+    fn watch(&self, window: u32) -> Result<u32> {
+        self.conn
+            .change_window_attributes(
+                window,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+            )
+            .location(loc!())?
+            .check()
+            .location(loc!())?;
+        self.fetch_opacity(window)
+    }
+
+    /// The opacity changes received since the last call, in order.
+    fn poll_changes(&self) -> Result<Vec<(u32, u32)>> {
+        let mut changes = Vec::new();
+        while let Some(event) = self.conn.poll_for_event().location(loc!())? {
+            match event {
+                Event::PropertyNotify(event) if event.atom == self.atom => {
+                    let opacity = if event.state == Property::DELETE {
+                        OPAQUE
+                    } else {
+                        self.fetch_opacity(event.window).location(loc!())?
+                    };
+                    changes.push((event.window, opacity));
+                },
+                // Errors for windows which were destroyed before we watched
+                // them.
+                Event::Error(error) => debug!("opacity watcher error: {error:?}"),
+                _ => {},
+            }
+        }
+        Ok(changes)
+    }
+}
+
+impl WprsState {
+    /// Starts forwarding the opacity of `window`.
+    pub(crate) fn watch_window_opacity(&mut self, window: &X11Surface) {
+        let Some(watcher) = &self.compositor_state.opacity_watcher else {
+            return;
+        };
+        let window_id = window.window_id();
+        if let Ok(opacity) = watcher.watch(window_id).warn(loc!()) {
+            self.set_window_opacity(window_id, opacity);
+        }
+        // The round trips may have read events.
+        self.handle_opacity_events();
+    }
+
+    pub(crate) fn forget_window_opacity(&mut self, window: &X11Surface) {
+        self.compositor_state
+            .window_opacities
+            .remove(window.window_id());
+    }
+
+    fn handle_opacity_events(&mut self) {
+        let Some(watcher) = &self.compositor_state.opacity_watcher else {
+            return;
+        };
+        let Ok(changes) = watcher.poll_changes().warn(loc!()) else {
+            return;
+        };
+        for (window, opacity) in changes {
+            self.set_window_opacity(window, opacity);
+        }
+    }
+
+    fn set_window_opacity(&mut self, window: u32, opacity: u32) {
+        debug!("window {window} opacity set to {opacity:#x}");
+        let window_opacities = &mut self.compositor_state.window_opacities;
+        window_opacities.set(window, opacity, Instant::now());
+        if !window_opacities.ticker_armed {
+            window_opacities.ticker_armed = true;
+            self.arm_opacity_ticker();
+        }
+    }
+
+    /// Forwards the opacities of all windows once per frame until no window
+    /// is animating.
+    fn arm_opacity_ticker(&self) {
+        self.event_loop_handle
+            .insert_source(Timer::immediate(), |_, _, state| {
+                state.forward_window_opacities();
+                let window_opacities = &mut state.compositor_state.window_opacities;
+                if window_opacities.animating(Instant::now()) {
+                    TimeoutAction::ToDuration(FRAME_INTERVAL)
+                } else {
+                    window_opacities.ticker_armed = false;
+                    TimeoutAction::Drop
+                }
+            })
+            .map_err(|e| anyhow!("failed to insert opacity timer: {e}"))
+            .log_and_ignore(loc!());
+    }
+
+    fn forward_window_opacities(&mut self) {
+        for xwayland_surface in self.surfaces.values() {
+            let (Some(Role::XdgToplevel(toplevel)), Some(x11_surface)) =
+                (&xwayland_surface.role, &xwayland_surface.x11_surface)
+            else {
+                continue;
+            };
+            let surface = toplevel.wl_surface();
+            if self.compositor_state.window_opacities.apply(
+                x11_surface.window_id(),
+                surface,
+                self.client_state.alpha_modifier.as_ref(),
+                &self.client_state.qh,
+            ) {
+                surface.commit();
+            }
+        }
+    }
+}
+
+impl Dispatch<WpAlphaModifierSurfaceV1, ()> for WprsState {
+    fn event(
+        _state: &mut Self,
+        _alpha_surface: &WpAlphaModifierSurfaceV1,
+        _event: wp_alpha_modifier_surface_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // wp_alpha_modifier_surface_v1 has no events.
+    }
+}
+
+impl AsMut<SimpleGlobal<WpAlphaModifierV1, 1>> for WprsState {
+    fn as_mut(&mut self) -> &mut SimpleGlobal<WpAlphaModifierV1, 1> {
+        // This should never panic since if alpha_modifier is None then we will
+        // never get any events for it.
+        self.client_state.alpha_modifier.as_mut().unwrap()
+    }
+}
+
+smithay_client_toolkit::delegate_simple!(WprsState, WpAlphaModifierV1, 1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF: u32 = OPAQUE / 2;
+
+    #[test]
+    fn initial_opacity_is_not_animated() {
+        let mut opacities = WindowOpacities::new(OpacityInterpolation::Linear { max_ms: 100 });
+        let now = Instant::now();
+        opacities.set(1, HALF, now);
+        assert_eq!(opacities.pending(1, now), Some(HALF));
+        assert!(!opacities.animating(now));
+        assert_eq!(opacities.pending(2, now), None);
+    }
+
+    #[test]
+    fn fade_is_interpolated_between_steps() {
+        let interpolation = OpacityInterpolation::Linear { max_ms: 100 };
+        let start = Instant::now();
+        let mut animation = OpacityAnimation::new(0, start);
+        // A fade in steps every 40ms.
+        let step = Duration::from_millis(40);
+        animation.retarget(HALF, start + step, interpolation);
+        assert_eq!(animation.value(start + step), 0);
+        assert_eq!(animation.value(start + step + step / 2), HALF / 2 + 1);
+        assert_eq!(animation.value(start + 2 * step), HALF);
+
+        // The next step starts from wherever the previous one got to.
+        animation.retarget(OPAQUE, start + step + step / 2, interpolation);
+        assert_eq!(animation.value(start + step + step / 2), HALF / 2 + 1);
+        assert!(animation.done(start + 2 * step + step / 2));
+    }
+
+    #[test]
+    fn jump_is_bounded() {
+        let start = Instant::now();
+        let mut animation = OpacityAnimation::new(OPAQUE, start);
+        let change = start + Duration::from_secs(10);
+        animation.retarget(0, change, OpacityInterpolation::Linear { max_ms: 100 });
+        assert!(!animation.done(change + Duration::from_millis(99)));
+        assert_eq!(animation.value(change + Duration::from_millis(100)), 0);
+
+        animation.retarget(OPAQUE, change, OpacityInterpolation::Disabled);
+        assert_eq!(animation.value(change), OPAQUE);
+    }
+
+    #[test]
+    fn changes_are_coalesced() {
+        let mut opacities = WindowOpacities::new(OpacityInterpolation::Disabled);
+        let now = Instant::now();
+        opacities.set(1, OPAQUE, now);
+        assert_eq!(opacities.pending(1, now), None);
+        // Many changes within a frame are forwarded as the last one.
+        for opacity in [HALF, 0, HALF, 7] {
+            opacities.set(1, opacity, now);
+        }
+        assert_eq!(opacities.pending(1, now), Some(7));
+        opacities.set(1, OPAQUE, now);
+        assert_eq!(opacities.pending(1, now), None);
+    }
+}
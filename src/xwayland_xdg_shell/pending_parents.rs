@@ -0,0 +1,379 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Queue of child surfaces whose commits arrived before their X11 parent was
+/// assigned a role. Children are held here until the parent's role is
+/// assigned and are then released in the order they were queued.
+///
+/// The parent may belong to another X11 client than the child (e.g. a dialog
+/// some helper process opens for an app's window), whose commits arrive in no
+/// particular order relative to the child's, so the parent may not even have
+/// been committed when the child is. Children of a parent which is unmapped
+/// before getting a role are mapped without it, as are children whose parent
+/// still has no role after MAX_RETRIES checks, so that a parent which never
+/// commits (or a WM_TRANSIENT_FOR cycle) doesn't hold them forever.
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+/// What to do with a child surface whose parent doesn't yet have a role.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ParentRaceBehavior {
+    /// Hold the child until the parent's role is assigned, including when the
+    /// parent hasn't been committed yet.
+    #[default]
+    Queue,
+    /// Map the child immediately as if it had no parent.
+    Orphan,
+}
+
+/// The interval at which waiting children check whether they're still waiting.
+pub const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of checks after which a child stops waiting on its parent.
+pub const MAX_RETRIES: u32 = 50;
+
+/// The result of checking on a child, see PendingParents::retry.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Retry {
+    /// The child isn't waiting (anymore).
+    NotWaiting,
+    /// The child is still waiting on its parent.
+    Waiting,
+    /// The child waited too long and was removed from the queue, it should be
+    /// mapped without its parent.
+    GaveUp,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    parent: T,
+    child: T,
+    retries: u32,
+}
+
+/// Maps waiting children to the parent they're waiting on, preserving the order
+/// in which they were queued.
+#[derive(Debug)]
+pub struct PendingParents<T> {
+    queue: Vec<Entry<T>>,
+    /// Children which gave up waiting, and so must not be queued again.
+    abandoned: Vec<T>,
+}
+
+impl<T> Default for PendingParents<T> {
+    fn default() -> Self {
+        Self {
+            queue: Vec::new(),
+            abandoned: Vec::new(),
+        }
+    }
+}
+
+impl<T: Eq> PendingParents<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `child` until `parent` is ready. Re-queueing a child which is
+    /// already waiting keeps its original position. Returns whether the child
+    /// was newly queued.
+    pub fn push(&mut self, parent: T, child: T) -> bool {
+        if self.contains_child(&child) {
+            return false;
+        }
+        self.queue.push(Entry {
+            parent,
+            child,
+            retries: 0,
+        });
+        true
+    }
+
+    pub fn contains_child(&self, child: &T) -> bool {
+        self.queue.iter().any(|entry| entry.child == *child)
+    }
+
+    /// Whether `child` gave up waiting on its parent.
+    pub fn is_abandoned(&self, child: &T) -> bool {
+        self.abandoned.contains(child)
+    }
+
+    /// Checks on `child`, giving up on its parent after MAX_RETRIES checks.
+    pub fn retry(&mut self, child: &T) -> Retry {
+        let Some(pos) = self.queue.iter().position(|entry| entry.child == *child) else {
+            return Retry::NotWaiting;
+        };
+        self.queue[pos].retries += 1;
+        if self.queue[pos].retries < MAX_RETRIES {
+            return Retry::Waiting;
+        }
+        let entry = self.queue.remove(pos);
+        self.abandoned.push(entry.child);
+        Retry::GaveUp
+    }
+
+    /// Removes and returns all children waiting on `parent`, in the order they
+    /// were queued.
+    pub fn take_children(&mut self, parent: &T) -> Vec<T> {
+        let (taken, remaining) = self
+            .queue
+            .drain(..)
+            .partition(|entry| entry.parent == *parent);
+        self.queue = remaining;
+        taken.into_iter().map(|entry| entry.child).collect()
+    }
+
+    /// Forgets about `surface`, whether it is a waiting child or a parent being
+    /// waited on. Returns the children which were waiting on it.
+    pub fn remove(&mut self, surface: &T) -> Vec<T> {
+        self.queue.retain(|entry| entry.child != *surface);
+        self.abandoned.retain(|child| child != surface);
+        self.take_children(surface)
+    }
+
+    /// The number of waiting children.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay::reexports::wayland_server::Resource;
+    use smithay::reexports::wayland_server::backend::ObjectId;
+    use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface as ClientWlSurface;
+
+    use super::*;
+    use crate::xwayland_xdg_shell::compositor;
+    use crate::xwayland_xdg_shell::compositor::CompositorOptions;
+    use crate::xwayland_xdg_shell::early_buffer::EarlyBufferBehavior;
+    use crate::xwayland_xdg_shell::testing;
+    use crate::xwayland_xdg_shell::testing::Harness;
+
+    /// The id of `surface`'s parent, or None if it was mapped without one.
+    /// Panics if `surface` wasn't mapped.
+    fn parent_of(harness: &Harness, surface: &ClientWlSurface) -> Option<ObjectId> {
+        let xwayland_surface = &harness.state.surfaces[&harness.surface(surface).id()];
+        assert!(xwayland_surface.role.is_some());
+        xwayland_surface
+            .parent
+            .as_ref()
+            .map(|parent| parent.surface_id.clone())
+    }
+
+    fn children_of(harness: &Harness, surface: &ClientWlSurface) -> Vec<ObjectId> {
+        let xwayland_surface = &harness.state.surfaces[&harness.surface(surface).id()];
+        xwayland_surface.children.iter().cloned().collect()
+    }
+
+    /// The parent may belong to another client than the child, in which case
+    /// it may not even have been committed when the child is.
+    #[test]
+    fn child_commit_before_parent_commit() {
+        let mut harness = Harness::new(testing::options());
+        let parent = harness.xwayland.create_surface();
+        let child = harness.xwayland.create_surface();
+        let parent_window = harness.map_x11_window(&parent, None);
+        harness.map_x11_window(&child, Some(parent_window));
+
+        harness.xwayland.commit(&child, None);
+        harness.dispatch();
+        let pending_parents = &harness.state.compositor_state.pending_parents;
+        assert!(pending_parents.contains_child(&harness.surface(&child)));
+
+        harness.xwayland.commit(&parent, None);
+        harness.dispatch();
+        assert!(harness.state.compositor_state.pending_parents.is_empty());
+        let parent_id = harness.surface(&parent).id();
+        assert_eq!(parent_of(&harness, &child), Some(parent_id));
+        assert_eq!(
+            children_of(&harness, &parent),
+            vec![harness.surface(&child).id()]
+        );
+    }
+
+    /// A parent which was unmapped (or never mapped) isn't waited on.
+    #[test]
+    fn missing_parent_is_not_waited_on() {
+        let mut harness = Harness::new(testing::options());
+        let child = harness.xwayland.create_surface();
+        harness.map_x11_window(&child, Some(1000));
+
+        harness.xwayland.commit(&child, None);
+        harness.dispatch();
+        assert!(harness.state.compositor_state.pending_parents.is_empty());
+        assert_eq!(parent_of(&harness, &child), None);
+    }
+
+    #[test]
+    fn children_released_in_order() {
+        let mut harness = Harness::new(testing::options());
+        let parent = harness.xwayland.create_surface();
+        let first = harness.xwayland.create_surface();
+        let second = harness.xwayland.create_surface();
+        let parent_window = harness.map_x11_window(&parent, None);
+        harness.map_x11_window(&first, Some(parent_window));
+        harness.map_x11_window(&second, Some(parent_window));
+
+        harness.xwayland.commit(&first, None);
+        harness.xwayland.commit(&second, None);
+        // Committing again keeps the child's position in the queue.
+        harness.xwayland.commit(&first, None);
+        harness.dispatch();
+        assert_eq!(harness.state.compositor_state.pending_parents.len(), 2);
+
+        harness.xwayland.commit(&parent, None);
+        harness.dispatch();
+        assert_eq!(
+            children_of(&harness, &parent),
+            vec![harness.surface(&first).id(), harness.surface(&second).id()]
+        );
+    }
+
+    #[test]
+    fn nested_children_released_transitively() {
+        let mut harness = Harness::new(testing::options());
+        let parent = harness.xwayland.create_surface();
+        let child = harness.xwayland.create_surface();
+        let grandchild = harness.xwayland.create_surface();
+        let parent_window = harness.map_x11_window(&parent, None);
+        let child_window = harness.map_x11_window(&child, Some(parent_window));
+        harness.map_x11_window(&grandchild, Some(child_window));
+
+        harness.xwayland.commit(&grandchild, None);
+        harness.xwayland.commit(&child, None);
+        harness.dispatch();
+        assert_eq!(harness.state.compositor_state.pending_parents.len(), 2);
+
+        harness.xwayland.commit(&parent, None);
+        harness.dispatch();
+        assert!(harness.state.compositor_state.pending_parents.is_empty());
+        let child_id = harness.surface(&child).id();
+        assert_eq!(parent_of(&harness, &grandchild), Some(child_id));
+        let parent_id = harness.surface(&parent).id();
+        assert_eq!(parent_of(&harness, &child), Some(parent_id));
+    }
+
+    /// The app reuses its SHM buffer once it has committed the next one, which
+    /// can happen before the parent gets a role.
+    #[test]
+    fn queued_commit_reads_buffer_at_commit() {
+        for early_buffer_behavior in [EarlyBufferBehavior::Retain, EarlyBufferBehavior::Discard] {
+            let mut harness = Harness::new(CompositorOptions {
+                early_buffer_behavior,
+                ..testing::options()
+            });
+            let parent = harness.xwayland.create_surface();
+            let child = harness.xwayland.create_surface();
+            let parent_window = harness.map_x11_window(&parent, None);
+            harness.map_x11_window(&child, Some(parent_window));
+
+            let committed: Vec<u8> = (0..8 * 8 * 4).map(|i| i as u8).collect();
+            let buffer = harness.xwayland.create_buffer(8, 8, &committed);
+            harness.xwayland.commit(&child, Some(&buffer));
+            harness.dispatch();
+            let pending_parents = &harness.state.compositor_state.pending_parents;
+            assert!(pending_parents.contains_child(&harness.surface(&child)));
+
+            // The app draws its next frame into the buffer while the child is
+            // still queued.
+            buffer.write(&[0xff; 8 * 8 * 4]);
+            harness.xwayland.commit(&parent, None);
+            harness.dispatch();
+            assert!(harness.state.compositor_state.pending_parents.is_empty());
+            assert_eq!(harness.displayed(&child), Some(committed));
+        }
+    }
+
+    #[test]
+    fn child_gives_up_on_parent_without_role() {
+        let mut harness = Harness::new(testing::options());
+        let parent = harness.xwayland.create_surface();
+        let child = harness.xwayland.create_surface();
+        let parent_window = harness.map_x11_window(&parent, None);
+        harness.map_x11_window(&child, Some(parent_window));
+        harness.xwayland.commit(&child, None);
+        harness.dispatch();
+
+        // What the child's retry timer does, without waiting for it.
+        let child_surface = harness.surface(&child);
+        let pending_parents = &mut harness.state.compositor_state.pending_parents;
+        for _ in 1..MAX_RETRIES {
+            assert_eq!(pending_parents.retry(&child_surface), Retry::Waiting);
+        }
+        assert_eq!(pending_parents.retry(&child_surface), Retry::GaveUp);
+        assert!(pending_parents.is_empty());
+        compositor::execute_or_defer_commit(&mut harness.state, child_surface.clone()).unwrap();
+        harness.dispatch();
+
+        assert_eq!(parent_of(&harness, &child), None);
+        // Its next commit doesn't queue it again either.
+        harness.xwayland.commit(&child, None);
+        harness.dispatch();
+        let pending_parents = &mut harness.state.compositor_state.pending_parents;
+        assert_eq!(pending_parents.retry(&child_surface), Retry::NotWaiting);
+    }
+
+    /// A menu's popup checked on while waiting for its parent toplevel to be
+    /// configured and given a role.
+    #[test]
+    fn popup_waits_within_retries() {
+        let (toplevel, popup) = (1, 2);
+        let mut pending = PendingParents::new();
+        assert!(pending.push(toplevel, popup));
+        for _ in 0..MAX_RETRIES / 2 {
+            assert_eq!(pending.retry(&popup), Retry::Waiting);
+        }
+        assert_eq!(pending.take_children(&toplevel), vec![popup]);
+        assert_eq!(pending.retry(&popup), Retry::NotWaiting);
+        assert!(!pending.is_abandoned(&popup));
+    }
+
+    /// Windows in a WM_TRANSIENT_FOR cycle wait on each other until they give
+    /// up.
+    #[test]
+    fn transient_for_cycle_is_bounded() {
+        let mut pending = PendingParents::new();
+        pending.push(2, 1);
+        pending.push(1, 2);
+        let mut retries = 0;
+        while pending.retry(&1) == Retry::Waiting {
+            retries += 1;
+        }
+        assert_eq!(retries, MAX_RETRIES - 1);
+        assert!(pending.is_abandoned(&1));
+        // Mapping 1 without its parent releases 2.
+        assert_eq!(pending.take_children(&1), vec![2]);
+    }
+
+    #[test]
+    fn remove_returns_orphaned_children() {
+        let mut pending = PendingParents::new();
+        pending.push(1, 2);
+        pending.push(2, 3);
+        pending.push(1, 4);
+
+        assert_eq!(pending.remove(&1), vec![2, 4]);
+        assert!(!pending.contains_child(&2));
+        assert!(pending.contains_child(&3));
+        assert_eq!(pending.remove(&3), Vec::<u32>::new());
+        assert!(pending.is_empty());
+    }
+}
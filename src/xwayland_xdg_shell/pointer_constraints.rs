@@ -0,0 +1,584 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Pointer locks and confinements of X11 apps, and their relative pointer
+/// motion. Xwayland locks the pointer for apps which grab it and warp it back
+/// on every motion (the usual way X11 games implement mouse look), warps the
+/// cursor with cursor position hints (XWarpPointer), and confines it for
+/// grabs confined to a window. Each constraint is mirrored by a constraint of
+/// the local pointer while the window has the keyboard focus, and activated
+/// once the local one is. The relative motion of the local pointer is
+/// forwarded meanwhile.
+///
+/// The local constraint is released when the window loses the keyboard focus,
+/// or when the user holds the keys of PointerLockEscape, after which the window
+/// stays unconstrained until it loses the keyboard focus. The local cursor is
+/// then left where the window last warped it, or at its center.
+///
+/// Xwayland constrains the pointer to the whole surface, so regions aren't
+/// mirrored. See client::pointer_constraints for the same between wprsc and
+/// the local compositor.
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::mem;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::input::Seat;
+use smithay::input::pointer::PointerHandle;
+use smithay::input::pointer::RelativeMotionEvent;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::backend::ObjectId as CompositorObjectId;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::Logical;
+use smithay::utils::Point;
+use smithay::utils::Size;
+use smithay::wayland::compositor::RegionAttributes;
+use smithay::wayland::pointer_constraints::PointerConstraintsHandler;
+use smithay::wayland::pointer_constraints::with_pointer_constraint;
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_pointer::WlPointer;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface as ClientWlSurface;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_confined_pointer_v1::ZwpConfinedPointerV1;
+use smithay_client_toolkit::reexports::protocols::wp::pointer_constraints::zv1::client::zwp_locked_pointer_v1::ZwpLockedPointerV1;
+use smithay_client_toolkit::reexports::protocols::wp::relative_pointer::zv1::client::zwp_relative_pointer_v1::ZwpRelativePointerV1;
+use smithay_client_toolkit::seat::pointer_constraints::PointerConstraintsHandler as LocalPointerConstraintsHandler;
+use smithay_client_toolkit::seat::relative_pointer::RelativeMotionEvent as LocalRelativeMotionEvent;
+use smithay_client_toolkit::seat::relative_pointer::RelativePointerHandler;
+use smithay_client_toolkit::shell::WaylandSurface;
+
+use crate::client_utils::LocalPointerConstraint;
+use crate::compositor_utils;
+use crate::prelude::*;
+use crate::serialization::wayland::PointerConstraintKind;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::XWaylandSurface;
+use crate::xwayland_xdg_shell::seat;
+use crate::xwayland_xdg_shell::xsurface_from_x11_surface;
+
+/// Keys which release the pointer from the X11 window constraining it.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub enum PointerLockEscape {
+    /// Only moving the keyboard focus away releases the pointer.
+    Disabled,
+    /// Holding all of these evdev keycodes releases the pointer.
+    Keys(Vec<u32>),
+}
+
+impl Default for PointerLockEscape {
+    /// Left Ctrl + Left Shift + Escape.
+    fn default() -> Self {
+        Self::Keys(vec![29, 42, 1])
+    }
+}
+
+impl PointerLockEscape {
+    /// Whether pressing `keycode` while `pressed_keys` are held completes the
+    /// keys.
+    pub fn completed_by(&self, pressed_keys: &HashSet<u32>, keycode: u32) -> bool {
+        match self {
+            Self::Disabled => false,
+            Self::Keys(keys) => {
+                keys.contains(&keycode)
+                    && keys
+                        .iter()
+                        .all(|key| *key == keycode || pressed_keys.contains(key))
+            },
+        }
+    }
+}
+
+/// A constraint of an X11 window's pointer and the local constraint mirroring
+/// it.
+#[derive(Debug)]
+pub(crate) struct MirroredConstraint {
+    x11_surface: X11Surface,
+    seat: Seat<WprsState>,
+    local: LocalPointerConstraint,
+    /// The last warp of the window, in local surface coordinates.
+    last_hint: Option<(f64, f64)>,
+}
+
+/// A window whose constraint the user escaped, which isn't mirrored again
+/// until it loses the keyboard focus.
+#[derive(Debug)]
+pub(crate) struct EscapedConstraint {
+    x11_surface: X11Surface,
+    seat: Seat<WprsState>,
+}
+
+impl WprsState {
+    /// Mirrors the constraints of the X11 windows with the keyboard focus
+    /// locally, and releases the local constraints of others and of those
+    /// which were destroyed.
+    pub(crate) fn sync_pointer_constraints(&mut self) {
+        let (kept, released): (Vec<_>, Vec<_>) =
+            mem::take(&mut self.client_state.pointer_constraints)
+                .into_iter()
+                .partition(|mirrored| {
+                    pointer_constraint_applies(&mirrored.x11_surface, &mirrored.seat)
+                });
+        self.client_state.pointer_constraints = kept;
+        for mirrored in released {
+            self.release_pointer_constraint(mirrored);
+        }
+        self.client_state
+            .escaped_pointer_constraints
+            .retain(|escaped| pointer_constraint_applies(&escaped.x11_surface, &escaped.seat));
+
+        let seats: Vec<Seat<Self>> = self
+            .compositor_state
+            .seats
+            .values()
+            .map(|wprs_seat| wprs_seat.seat.clone())
+            .collect();
+        for seat in seats {
+            let Some(x11_surface) = seat
+                .get_keyboard()
+                .and_then(|keyboard| keyboard.current_focus())
+            else {
+                continue;
+            };
+            if self
+                .client_state
+                .pointer_constraints
+                .iter()
+                .any(|mirrored| mirrored.x11_surface == x11_surface && mirrored.seat == seat)
+                || self
+                    .client_state
+                    .escaped_pointer_constraints
+                    .iter()
+                    .any(|escaped| escaped.x11_surface == x11_surface && escaped.seat == seat)
+                || !pointer_constraint_applies(&x11_surface, &seat)
+            {
+                continue;
+            }
+            self.mirror_pointer_constraint(x11_surface, seat)
+                .log_and_ignore(loc!());
+        }
+    }
+
+    /// Releases the local constraints of the surface `surface_id`, which is
+    /// being destroyed.
+    pub(crate) fn forget_pointer_constraints(&mut self, surface_id: &CompositorObjectId) {
+        self.client_state.pointer_constraints.retain(|mirrored| {
+            mirrored
+                .x11_surface
+                .wl_surface()
+                .is_some_and(|surface| surface.id() != *surface_id)
+        });
+        self.client_state
+            .escaped_pointer_constraints
+            .retain(|escaped| {
+                escaped
+                    .x11_surface
+                    .wl_surface()
+                    .is_some_and(|surface| surface.id() != *surface_id)
+            });
+    }
+
+    /// Releases the pointer of `seat_name` from the window constraining it if
+    /// pressing `keycode` completes the keys of PointerLockEscape. Returns
+    /// whether it did, in which case the press isn't forwarded.
+    pub(crate) fn escape_pointer_constraint(&mut self, seat_name: &str, keycode: u32) -> bool {
+        let Ok(wprs_seat) = self.compositor_state.seat(seat_name) else {
+            return false;
+        };
+        if !self
+            .compositor_state
+            .pointer_lock_escape
+            .completed_by(&wprs_seat.pressed_keys, keycode)
+        {
+            return false;
+        }
+        let seat = wprs_seat.seat.clone();
+        let (escaped, kept): (Vec<_>, Vec<_>) =
+            mem::take(&mut self.client_state.pointer_constraints)
+                .into_iter()
+                .partition(|mirrored| mirrored.seat == seat);
+        self.client_state.pointer_constraints = kept;
+        if escaped.is_empty() {
+            return false;
+        }
+        for mirrored in escaped {
+            self.client_state
+                .escaped_pointer_constraints
+                .push(EscapedConstraint {
+                    x11_surface: mirrored.x11_surface.clone(),
+                    seat: mirrored.seat.clone(),
+                });
+            self.release_pointer_constraint(mirrored);
+        }
+        true
+    }
+
+    /// Leaves the local cursor at a sensible position and destroys the local
+    /// constraint of `mirrored`, deactivating the window's.
+    fn release_pointer_constraint(&mut self, mirrored: MirroredConstraint) {
+        debug!(
+            "releasing the local pointer of window {}",
+            mirrored.x11_surface.window_id()
+        );
+        // The hint takes effect on the next commit, which must come before
+        // the local constraint is destroyed on drop.
+        if let Some(xwayland_surface) =
+            xsurface_from_x11_surface(&mut self.surfaces, &mirrored.x11_surface)
+        {
+            mirrored.local.set_cursor_position_hint(release_position(
+                mirrored.last_hint,
+                mirrored.x11_surface.geometry().size,
+                f64::from(xwayland_surface.scale()),
+            ));
+            xwayland_surface.wl_surface().commit();
+        }
+        if mirrored.local.active {
+            set_constraint_active(&mirrored.x11_surface, &mirrored.seat, false);
+        }
+    }
+
+    fn mirror_pointer_constraint(
+        &mut self,
+        x11_surface: X11Surface,
+        seat: Seat<Self>,
+    ) -> Result<()> {
+        let surface = x11_surface.wl_surface().location(loc!())?;
+        let pointer = seat.get_pointer().location(loc!())?;
+        let (kind, _) =
+            compositor_utils::pointer_constraint(&surface, &pointer).location(loc!())?;
+        let local_surface = xsurface_from_x11_surface(&mut self.surfaces, &x11_surface)
+            .location(loc!())?
+            .wl_surface()
+            .clone();
+        let local_pointer = seat::themed_pointer(&self.client_state, &seat)
+            .location(loc!())?
+            .pointer()
+            .clone();
+        let local = LocalPointerConstraint::new(
+            &self.client_state.pointer_constraints_state,
+            &self.client_state.relative_pointer_state,
+            &local_surface,
+            &local_pointer,
+            kind,
+            None,
+            &self.client_state.qh,
+        )
+        .context(
+            loc!(),
+            "failed to constrain the local pointer, is zwp_pointer_constraints_v1 available?",
+        )?;
+        debug!(
+            "constraining the local pointer for window {}",
+            x11_surface.window_id()
+        );
+        self.client_state
+            .pointer_constraints
+            .push(MirroredConstraint {
+                x11_surface,
+                seat,
+                local,
+                last_hint: None,
+            });
+        Ok(())
+    }
+
+    /// Records that the local compositor (de)activated the local constraint
+    /// `constraint` and (de)activates the X11 window's constraint.
+    fn set_pointer_constraint_active(&mut self, constraint: &impl Proxy, active: bool) {
+        let Some(mirrored) = self
+            .client_state
+            .pointer_constraints
+            .iter_mut()
+            .find(|mirrored| mirrored.local.is(&constraint.id()))
+        else {
+            return;
+        };
+        mirrored.local.active = active;
+        set_constraint_active(&mirrored.x11_surface, &mirrored.seat, active);
+    }
+}
+
+/// The scale of the local surface of `x11_surface` relative to the X11
+/// window, see scale_override.
+fn local_scale(
+    surfaces: &mut HashMap<CompositorObjectId, XWaylandSurface>,
+    x11_surface: &X11Surface,
+) -> f64 {
+    xsurface_from_x11_surface(surfaces, x11_surface)
+        .map_or(1.0, |xwayland_surface| f64::from(xwayland_surface.scale()))
+}
+
+/// Where the local cursor is left when the window's constraint is released:
+/// where the window last warped it, or the center of the window of size
+/// `size` otherwise, in the coordinates of its local surface of scale `scale`.
+fn release_position(
+    last_hint: Option<(f64, f64)>,
+    size: Size<i32, Logical>,
+    scale: f64,
+) -> (f64, f64) {
+    last_hint.unwrap_or((
+        f64::from(size.w) * scale / 2.0,
+        f64::from(size.h) * scale / 2.0,
+    ))
+}
+
+/// The cursor position hint of the local lock for a warp of the window to
+/// `location`, in the coordinates of its local surface of scale `scale`. None
+/// unless the window's pointer is locked and `location` is in the lock's
+/// `region`.
+fn local_position_hint(
+    kind: PointerConstraintKind,
+    region: Option<&RegionAttributes>,
+    location: Point<f64, Logical>,
+    scale: f64,
+) -> Option<(f64, f64)> {
+    (kind == PointerConstraintKind::Lock
+        && compositor_utils::position_hint_in_region(region, location))
+    .then_some((location.x * scale, location.y * scale))
+}
+
+/// Whether the pointer of `seat` has a constraint on `x11_surface` and the
+/// window has the keyboard focus of `seat`.
+fn pointer_constraint_applies(x11_surface: &X11Surface, seat: &Seat<WprsState>) -> bool {
+    let (Some(surface), Some(pointer), Some(keyboard)) = (
+        x11_surface.wl_surface(),
+        seat.get_pointer(),
+        seat.get_keyboard(),
+    ) else {
+        return false;
+    };
+    keyboard.current_focus().as_ref() == Some(x11_surface)
+        && compositor_utils::pointer_constraint(&surface, &pointer).is_some()
+}
+
+fn set_constraint_active(x11_surface: &X11Surface, seat: &Seat<WprsState>, active: bool) {
+    let (Some(surface), Some(pointer)) = (x11_surface.wl_surface(), seat.get_pointer()) else {
+        return;
+    };
+    with_pointer_constraint(&surface, &pointer, |constraint| match constraint {
+        Some(constraint) if active && !constraint.is_active() => constraint.activate(),
+        Some(constraint) if !active && constraint.is_active() => constraint.deactivate(),
+        _ => {},
+    });
+}
+
+impl PointerConstraintsHandler for WprsState {
+    fn new_constraint(&mut self, _surface: &WlSurface, _pointer: &PointerHandle<Self>) {
+        self.sync_pointer_constraints();
+    }
+
+    fn cursor_position_hint(
+        &mut self,
+        surface: &WlSurface,
+        pointer: &PointerHandle<Self>,
+        location: Point<f64, Logical>,
+    ) {
+        let Some((kind, region)) = compositor_utils::pointer_constraint(surface, pointer) else {
+            return;
+        };
+        let Some(mirrored) = self
+            .client_state
+            .pointer_constraints
+            .iter_mut()
+            .find(|mirrored| mirrored.x11_surface.wl_surface().as_ref() == Some(surface))
+        else {
+            debug!("ignoring warp to {location:?}, the pointer isn't constrained locally");
+            return;
+        };
+        let Some(xwayland_surface) =
+            xsurface_from_x11_surface(&mut self.surfaces, &mirrored.x11_surface)
+        else {
+            return;
+        };
+        let Some(hint) = local_position_hint(
+            kind,
+            region.as_ref(),
+            location,
+            f64::from(xwayland_surface.scale()),
+        ) else {
+            debug!("ignoring warp to {location:?} outside of the locked region");
+            return;
+        };
+        mirrored.local.set_cursor_position_hint(hint);
+        mirrored.last_hint = Some(hint);
+        // The hint takes effect on the next commit, which may not come until
+        // the window draws again.
+        xwayland_surface.wl_surface().commit();
+    }
+}
+
+impl LocalPointerConstraintsHandler for WprsState {
+    fn confined(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        confined_pointer: &ZwpConfinedPointerV1,
+        _surface: &ClientWlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(confined_pointer, true);
+    }
+
+    fn unconfined(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        confined_pointer: &ZwpConfinedPointerV1,
+        _surface: &ClientWlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(confined_pointer, false);
+    }
+
+    fn locked(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        locked_pointer: &ZwpLockedPointerV1,
+        _surface: &ClientWlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(locked_pointer, true);
+    }
+
+    fn unlocked(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        locked_pointer: &ZwpLockedPointerV1,
+        _surface: &ClientWlSurface,
+        _pointer: &WlPointer,
+    ) {
+        self.set_pointer_constraint_active(locked_pointer, false);
+    }
+}
+
+impl RelativePointerHandler for WprsState {
+    fn relative_pointer_motion(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        relative_pointer: &ZwpRelativePointerV1,
+        _pointer: &WlPointer,
+        event: LocalRelativeMotionEvent,
+    ) {
+        let Some((x11_surface, seat)) = self
+            .client_state
+            .pointer_constraints
+            .iter()
+            .find(|mirrored| mirrored.local.has_relative_pointer(relative_pointer))
+            .map(|mirrored| (mirrored.x11_surface.clone(), mirrored.seat.clone()))
+        else {
+            return;
+        };
+        let Some(pointer) = seat.get_pointer() else {
+            return;
+        };
+        let scale = local_scale(&mut self.surfaces, &x11_surface);
+        pointer.relative_motion(
+            self,
+            Some((x11_surface, (0.0, 0.0).into())),
+            &RelativeMotionEvent {
+                delta: (event.delta.0 / scale, event.delta.1 / scale).into(),
+                delta_unaccel: (event.delta_unaccel.0 / scale, event.delta_unaccel.1 / scale)
+                    .into(),
+                utime: event.utime,
+            },
+        );
+        pointer.frame(self);
+    }
+}
+
+smithay::delegate_pointer_constraints!(WprsState);
+smithay::delegate_relative_pointer!(WprsState);
+smithay_client_toolkit::delegate_pointer_constraints!(WprsState);
+smithay_client_toolkit::delegate_relative_pointer!(WprsState);
+
+#[cfg(test)]
+mod tests {
+    use smithay::utils::Rectangle;
+    use smithay::wayland::compositor::RectangleKind;
+
+    use super::*;
+
+    #[test]
+    fn escape_is_completed_by_the_last_key() {
+        let escape = PointerLockEscape::default();
+        let ctrl_shift = HashSet::from([29, 42]);
+        assert!(escape.completed_by(&ctrl_shift, 1));
+        // Escape alone, or another key with Ctrl + Shift.
+        assert!(!escape.completed_by(&HashSet::new(), 1));
+        assert!(!escape.completed_by(&ctrl_shift, 30));
+        // The keys can be pressed in any order.
+        assert!(escape.completed_by(&HashSet::from([1, 29]), 42));
+    }
+
+    #[test]
+    fn disabled_or_empty_escape_is_never_completed() {
+        let pressed_keys = HashSet::from([29, 42]);
+        assert!(!PointerLockEscape::Disabled.completed_by(&pressed_keys, 1));
+        assert!(!PointerLockEscape::Keys(Vec::new()).completed_by(&pressed_keys, 1));
+    }
+
+    #[test]
+    fn warp_within_locked_region_becomes_a_scaled_hint() {
+        let region = RegionAttributes {
+            rects: vec![(
+                RectangleKind::Add,
+                Rectangle::new((0, 40).into(), (800, 560).into()),
+            )],
+        };
+        assert_eq!(
+            local_position_hint(
+                PointerConstraintKind::Lock,
+                Some(&region),
+                (400.0, 300.0).into(),
+                2.0
+            ),
+            Some((800.0, 600.0))
+        );
+        // Above the region, and with the pointer confined rather than locked.
+        assert_eq!(
+            local_position_hint(
+                PointerConstraintKind::Lock,
+                Some(&region),
+                (400.0, 20.0).into(),
+                2.0
+            ),
+            None
+        );
+        assert_eq!(
+            local_position_hint(
+                PointerConstraintKind::Confine,
+                Some(&region),
+                (400.0, 300.0).into(),
+                2.0
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn released_cursor_is_left_at_the_last_warp_or_the_center() {
+        let size = Size::from((800, 600));
+        assert_eq!(release_position(None, size, 1.0), (400.0, 300.0));
+        assert_eq!(release_position(None, size, 2.0), (800.0, 600.0));
+        assert_eq!(
+            release_position(Some((10.0, 20.0)), size, 2.0),
+            (10.0, 20.0)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Handling of the local pointer leaving our surfaces, e.g. for the desktop or
+/// a local window. Until the next enter, the pointer is in none of the X11
+/// windows, whether the surface it left is an X11 window, one of our window
+/// frames or a surface which has been destroyed since. By default the X11
+/// window which had the pointer gets a leave, as it would on a local X server,
+/// but games which scroll while the pointer is at the edge of their window can
+/// keep it instead. Either way, the cursor surface set by the window is no
+/// longer shown.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::input::pointer::MotionEvent;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+
+/// What the X11 window which had the pointer sees when it leaves our surfaces.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum PointerLeaveBehavior {
+    /// Send a leave, the window sees the pointer outside of it.
+    #[default]
+    Leave,
+    /// Keep the pointer in the window, at the position it left it.
+    Retain,
+}
+
+impl PointerLeaveBehavior {
+    /// The pointer focus after the pointer left all of our surfaces while
+    /// `focus` had it.
+    pub fn focus_after_leave<F>(self, focus: Option<F>) -> Option<F> {
+        match self {
+            Self::Leave => None,
+            Self::Retain => focus,
+        }
+    }
+}
+
+impl WprsState {
+    /// Handles the local pointer of `seat_name` leaving our surfaces with
+    /// `serial`.
+    pub(crate) fn handle_pointer_leave(&mut self, seat_name: &str, serial: u32) -> Result<()> {
+        let behavior = self.compositor_state.pointer_leave_behavior;
+        let wprs_seat = self.compositor_state.seat_mut(seat_name).location(loc!())?;
+        // The local compositor shows its own cursor outside of our surfaces,
+        // so the window's cursor surface mustn't be reset over it when it's
+        // destroyed.
+        wprs_seat.cursor_surface.set(None);
+        let serial = wprs_seat.serial_map.insert(serial);
+        let pointer = wprs_seat.seat.get_pointer().location(loc!())?;
+
+        let focus = pointer.current_focus();
+        if focus.is_none() || behavior.focus_after_leave(focus).is_some() {
+            return Ok(());
+        }
+        debug!("pointer of seat {seat_name:?} left all surfaces");
+        let location = pointer.current_location();
+        pointer.motion(
+            self,
+            None,
+            &MotionEvent {
+                location,
+                serial,
+                time: 0, // unused
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaving_all_surfaces_clears_focus() {
+        // The pointer moves from window 1 to window 2, and then off all
+        // surfaces.
+        let mut focus = None;
+        for event in [Some(1), Some(2), None] {
+            focus = match event {
+                Some(window) => Some(window),
+                None => PointerLeaveBehavior::Leave.focus_after_leave(focus),
+            };
+        }
+        assert_eq!(focus, None);
+    }
+
+    #[test]
+    fn retain_keeps_last_focus() {
+        assert_eq!(
+            PointerLeaveBehavior::Retain.focus_after_leave(Some(2)),
+            Some(2)
+        );
+        assert_eq!(
+            PointerLeaveBehavior::Retain.focus_after_leave::<u32>(None),
+            None
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// xdg_popup grabs for X11 menus. X11 menus grab the X pointer and keyboard
+/// themselves, but that grab only covers the X server. Taking an xdg_popup
+/// grab, with the serial of the button press which opened the menu, lets the
+/// local compositor tell us when the user clicks outside of the menu, at which
+/// point we dismiss the X11 menu through the window manager. Override-redirect
+/// menus aren't managed by it, and it has no way to unmap them, so they're left
+/// to their app, which closes them on the next click it gets through its X
+/// pointer grab.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::wayland_server::backend::ObjectId;
+use smithay::xwayland::X11Surface;
+use smithay::xwayland::xwm::WmWindowType;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::compositor::X11Parent;
+use crate::xwayland_xdg_shell::compositor::x11_parent_from_surface;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum PopupGrabBehavior {
+    /// Take an xdg_popup grab for X11 menus and unmap the menu when the local
+    /// compositor breaks the grab (e.g., the user clicked outside the menu).
+    #[default]
+    Dismiss,
+    /// Don't grab; X11 menus stay open until the X11 app closes them.
+    Ignore,
+}
+
+pub(crate) fn is_menu(x11_surface: &X11Surface) -> bool {
+    matches!(
+        x11_surface.window_type(),
+        Some(WmWindowType::Menu | WmWindowType::PopupMenu | WmWindowType::DropdownMenu)
+    )
+}
+
+/// Removes the grabbing popup `popup` and the grabbing popups nested on top of
+/// it from `grab_stack`, and returns them topmost first. Popups which didn't
+/// take a grab aren't X11 menus and are left alone.
+fn take_dismissed<T: PartialEq>(grab_stack: &mut Vec<T>, popup: &T) -> Vec<T> {
+    let Some(pos) = grab_stack.iter().position(|id| id == popup) else {
+        return Vec::new();
+    };
+    let mut dismissed = grab_stack.split_off(pos);
+    dismissed.reverse();
+    dismissed
+}
+
+/// Unmaps the menu of `x11_surface`, which also breaks any X grabs its client
+/// holds on it, which is what actually closes the menu from the X11 app's
+/// point of view.
+fn dismiss_menu(x11_surface: &X11Surface) -> Result<()> {
+    if x11_surface.is_override_redirect() {
+        debug!(
+            "leaving override-redirect menu {} to its app",
+            x11_surface.window_id()
+        );
+        return Ok(());
+    }
+    x11_surface.set_mapped(false).location(loc!())
+}
+
+impl WprsState {
+    /// The parent a new grabbing popup must use: xdg_shell requires grabbing
+    /// popups to be children of the topmost grabbing popup, if any.
+    pub(crate) fn popup_grab_parent(&mut self) -> Option<X11Parent> {
+        let grab_stack = &mut self.client_state.popup_grab_stack;
+        // Popups can be destroyed without us being told that their grab
+        // ended, e.g., when the X11 app unmaps the menu itself.
+        grab_stack.retain(|surface_id| self.surfaces.contains_key(surface_id));
+        let top = grab_stack.last()?;
+        x11_parent_from_surface(top, self.surfaces.get(top)?)
+    }
+
+    /// Dismisses the X11 menu for the grabbing popup whose grab was broken,
+    /// along with any grabbing popups nested on top of it.
+    #[instrument(skip(self), level = "debug")]
+    pub(crate) fn dismiss_popup(&mut self, surface_id: &ObjectId) {
+        let dismissed = take_dismissed(&mut self.client_state.popup_grab_stack, surface_id);
+        for x11_surface in dismissed
+            .iter()
+            .filter_map(|id| self.surfaces.get(id))
+            .filter(|xwls| matches!(xwls.role, Some(Role::XdgPopup(_))))
+            .filter_map(|xwls| xwls.x11_surface.as_ref())
+        {
+            dismiss_menu(x11_surface).log_and_ignore(loc!());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_grabbing_popups_are_dismissed_topmost_first() {
+        let mut grab_stack = vec![1, 2, 3];
+        assert_eq!(take_dismissed(&mut grab_stack, &2), [3, 2]);
+        assert_eq!(grab_stack, [1]);
+    }
+
+    #[test]
+    fn popups_without_a_grab_are_not_dismissed() {
+        let mut grab_stack = vec![1, 2];
+        assert!(take_dismissed(&mut grab_stack, &4).is_empty());
+        assert_eq!(grab_stack, [1, 2]);
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Per-application scale overrides for legacy X11 apps which don't support
+/// HiDPI and would otherwise appear tiny. X11 has no per-window scale to
+/// request, so the app keeps rendering at 1x and its buffers are upscaled
+/// before being committed locally. The local window is then `scale` times the
+/// size of the X11 window, and sizes and positions are converted between the
+/// two wherever they cross over.
+use std::collections::BTreeMap;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use crate::serialization::geometry::Rectangle;
+use crate::serialization::wayland::BufferMetadata;
+use crate::xwayland_xdg_shell::XWaylandSurface;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum UpscaleFilter {
+    /// Repeat each pixel, keeps pixel art and text crisp but blocky.
+    #[default]
+    Nearest,
+    /// Bilinear interpolation, smoother but blurrier.
+    Linear,
+}
+
+/// The override applied to a surface.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ScaleOverride {
+    pub scale: u32,
+    pub filter: UpscaleFilter,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScaleOverrides {
+    /// WM_CLASS -> scale.
+    overrides: BTreeMap<String, u32>,
+    filter: UpscaleFilter,
+}
+
+impl ScaleOverrides {
+    pub fn new(overrides: BTreeMap<String, u32>, filter: UpscaleFilter) -> Self {
+        Self { overrides, filter }
+    }
+
+    /// The override for windows of `class`, if any. Scales of 0 and 1 don't
+    /// override anything.
+    pub fn get(&self, class: &str) -> Option<ScaleOverride> {
+        self.overrides
+            .get(class)
+            .filter(|scale| **scale > 1)
+            .map(|scale| ScaleOverride {
+                scale: *scale,
+                filter: self.filter,
+            })
+    }
+}
+
+impl ScaleOverride {
+    /// The metadata of the upscaled buffer.
+    pub fn scale_metadata(&self, metadata: &BufferMetadata) -> BufferMetadata {
+        let scale = self.scale as i32;
+        BufferMetadata {
+            width: metadata.width * scale,
+            height: metadata.height * scale,
+            stride: metadata.width * scale * BYTES_PER_PIXEL as i32,
+            format: metadata.format,
+        }
+    }
+
+    /// Upscales `src`, with layout `metadata`, into `dst`, with the layout
+    /// returned by scale_metadata.
+    pub fn upscale(&self, src: &[u8], metadata: &BufferMetadata, dst: &mut [u8]) {
+        let scale = self.scale as usize;
+        let src_width = metadata.width as usize;
+        let src_height = metadata.height as usize;
+        let src_stride = metadata.stride as usize;
+        let dst_width = src_width * scale;
+        let dst_stride = dst_width * BYTES_PER_PIXEL;
+        let pixel = |x: usize, y: usize| {
+            let i = y * src_stride + x * BYTES_PER_PIXEL;
+            &src[i..i + BYTES_PER_PIXEL]
+        };
+
+        for (dst_y, dst_row) in dst
+            .chunks_exact_mut(dst_stride)
+            .take(src_height * scale)
+            .enumerate()
+        {
+            for (dst_x, dst_pixel) in dst_row.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+                match self.filter {
+                    UpscaleFilter::Nearest => {
+                        dst_pixel.copy_from_slice(pixel(dst_x / scale, dst_y / scale));
+                    },
+                    UpscaleFilter::Linear => {
+                        // Sample at the center of the destination pixel.
+                        let (x0, x1, fx) = sample_coordinates(dst_x, scale, src_width);
+                        let (y0, y1, fy) = sample_coordinates(dst_y, scale, src_height);
+                        let (p00, p10) = (pixel(x0, y0), pixel(x1, y0));
+                        let (p01, p11) = (pixel(x0, y1), pixel(x1, y1));
+                        // Interpolating each channel separately is correct for
+                        // premultiplied alpha, which ARGB8888 uses.
+                        for (c, out) in dst_pixel.iter_mut().enumerate() {
+                            let top = lerp(p00[c], p10[c], fx);
+                            let bottom = lerp(p01[c], p11[c], fx);
+                            *out = (top + (bottom - top) * fy).round() as u8;
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    /// Converts damage of the source buffer to damage of the upscaled buffer.
+    pub fn scale_damage(&self, damage: &Rectangle<i32>) -> Rectangle<i32> {
+        let scale = self.scale as i32;
+        // Linear filtering blends each source pixel into the destination
+        // pixels around it, up to one source pixel away.
+        let margin = match self.filter {
+            UpscaleFilter::Nearest => 0,
+            UpscaleFilter::Linear => 1,
+        };
+        Rectangle::new(
+            (damage.loc.x - margin) * scale,
+            (damage.loc.y - margin) * scale,
+            (damage.size.w + 2 * margin) * scale,
+            (damage.size.h + 2 * margin) * scale,
+        )
+    }
+}
+
+impl XWaylandSurface {
+    /// The factor from X11 window coordinates to local surface coordinates.
+    pub(crate) fn scale(&self) -> i32 {
+        self.scale_override
+            .map_or(1, |scale_override| scale_override.scale as i32)
+    }
+}
+
+/// The two source pixels around destination pixel `dst` and the weight of the
+/// second one.
+fn sample_coordinates(dst: usize, scale: usize, src_len: usize) -> (usize, usize, f32) {
+    let src = ((dst as f32 + 0.5) / scale as f32 - 0.5).max(0.0);
+    let src0 = (src as usize).min(src_len - 1);
+    let src1 = (src0 + 1).min(src_len - 1);
+    (src0, src1, src - src0 as f32)
+}
+
+fn lerp(a: u8, b: u8, t: f32) -> f32 {
+    f32::from(a) + (f32::from(b) - f32::from(a)) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::wayland::BufferFormat;
+
+    fn metadata(width: i32, height: i32) -> BufferMetadata {
+        BufferMetadata {
+            width,
+            height,
+            stride: width * 4,
+            format: BufferFormat::Argb8888,
+        }
+    }
+
+    fn upscale(filter: UpscaleFilter, src: &[u8], width: i32, height: i32) -> Vec<u8> {
+        let scale_override = ScaleOverride { scale: 2, filter };
+        let metadata = metadata(width, height);
+        let scaled = scale_override.scale_metadata(&metadata);
+        let mut dst = vec![0; (scaled.stride * scaled.height) as usize];
+        scale_override.upscale(src, &metadata, &mut dst);
+        dst
+    }
+
+    #[test]
+    fn overrides_by_class() {
+        let overrides = ScaleOverrides::new(
+            BTreeMap::from([("XTerm".to_string(), 2), ("XClock".to_string(), 1)]),
+            UpscaleFilter::Linear,
+        );
+        assert_eq!(
+            overrides.get("XTerm"),
+            Some(ScaleOverride {
+                scale: 2,
+                filter: UpscaleFilter::Linear,
+            })
+        );
+        assert_eq!(overrides.get("XClock"), None);
+        assert_eq!(overrides.get("Gimp"), None);
+    }
+
+    #[test]
+    fn nearest_repeats_pixels() {
+        let src = [1, 1, 1, 255, 9, 9, 9, 255];
+        let dst = upscale(UpscaleFilter::Nearest, &src, 2, 1);
+        let row = [1, 1, 1, 255, 1, 1, 1, 255, 9, 9, 9, 255, 9, 9, 9, 255];
+        assert_eq!(dst, [row, row].concat());
+    }
+
+    #[test]
+    fn linear_blends_neighbors() {
+        let src = [0, 0, 0, 255, 100, 100, 100, 255];
+        let dst = upscale(UpscaleFilter::Linear, &src, 2, 1);
+        // The edges keep the source pixels, the inner pixels are a quarter of
+        // the way to their other neighbor.
+        let row = [
+            0, 0, 0, 255, 25, 25, 25, 255, 75, 75, 75, 255, 100, 100, 100, 255,
+        ];
+        assert_eq!(dst, [row, row].concat());
+    }
+
+    #[test]
+    fn stride_padding_is_skipped() {
+        let metadata = BufferMetadata {
+            stride: 8,
+            ..metadata(1, 2)
+        };
+        let src = [
+            1, 1, 1, 1, 0xff, 0xff, 0xff, 0xff, 2, 2, 2, 2, 0xff, 0xff, 0xff, 0xff,
+        ];
+        let scale_override = ScaleOverride {
+            scale: 2,
+            filter: UpscaleFilter::Nearest,
+        };
+        let scaled = scale_override.scale_metadata(&metadata);
+        assert_eq!((scaled.width, scaled.height, scaled.stride), (2, 4, 8));
+        let mut dst = vec![0; 32];
+        scale_override.upscale(&src, &metadata, &mut dst);
+        assert_eq!(dst, [[1; 16], [2; 16]].concat());
+    }
+
+    #[test]
+    fn damage_is_scaled() {
+        let damage = Rectangle::new(10, 20, 3, 4);
+        let nearest = ScaleOverride {
+            scale: 2,
+            filter: UpscaleFilter::Nearest,
+        };
+        assert_eq!(nearest.scale_damage(&damage), Rectangle::new(20, 40, 6, 8));
+        let linear = ScaleOverride {
+            filter: UpscaleFilter::Linear,
+            ..nearest
+        };
+        assert_eq!(linear.scale_damage(&damage), Rectangle::new(18, 38, 10, 12));
+    }
+}
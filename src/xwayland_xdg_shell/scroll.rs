@@ -0,0 +1,149 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Translation of local scroll events into scroll events for Xwayland.
+/// Xwayland feeds them to its smooth-scroll valuators, and X11 apps which
+/// implement kinetic scrolling rely on the axis source to tell touchpad
+/// scrolls (which get momentum) from wheel clicks (which don't), and on
+/// axis_stop to know when the fingers were lifted and momentum should start.
+use smithay::backend::input::Axis;
+use smithay::backend::input::AxisSource;
+use smithay::input::pointer::AxisFrame;
+use smithay_client_toolkit::reexports::client::protocol::wl_pointer::AxisSource as WlPointerAxisSource;
+use smithay_client_toolkit::seat::pointer::AxisScroll;
+
+use crate::prelude::*;
+
+fn axis_source(source: WlPointerAxisSource) -> Option<AxisSource> {
+    match source {
+        WlPointerAxisSource::Wheel => Some(AxisSource::Wheel),
+        WlPointerAxisSource::Finger => Some(AxisSource::Finger),
+        WlPointerAxisSource::Continuous => Some(AxisSource::Continuous),
+        WlPointerAxisSource::WheelTilt => Some(AxisSource::WheelTilt),
+        _ => {
+            warn!("got unknown axis source {source:?}");
+            None
+        },
+    }
+}
+
+/// Wheel steps along `axis_scroll` in multiples of 120, preferring the
+/// high-resolution value when the local compositor sends one.
+fn v120(axis_scroll: &AxisScroll) -> i32 {
+    if axis_scroll.value120 != 0 {
+        axis_scroll.value120
+    } else {
+        axis_scroll.discrete * 120
+    }
+}
+
+/// The frame to send to Xwayland for a local scroll event. Local compositors
+/// older than wl_pointer v5 don't send an axis source, in which case none is
+/// sent on either.
+pub(crate) fn axis_frame(
+    time: u32,
+    horizontal: &AxisScroll,
+    vertical: &AxisScroll,
+    source: Option<WlPointerAxisSource>,
+) -> AxisFrame {
+    let mut axis_frame = AxisFrame::new(time)
+        .value(Axis::Horizontal, horizontal.absolute)
+        .value(Axis::Vertical, vertical.absolute)
+        .v120(Axis::Horizontal, v120(horizontal))
+        .v120(Axis::Vertical, v120(vertical));
+
+    if let Some(source) = source.and_then(axis_source) {
+        axis_frame = axis_frame.source(source);
+    }
+    if horizontal.stop {
+        axis_frame = axis_frame.stop(Axis::Horizontal);
+    }
+    if vertical.stop {
+        axis_frame = axis_frame.stop(Axis::Vertical);
+    }
+    axis_frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axis_scroll(absolute: f64, value120: i32, stop: bool) -> AxisScroll {
+        AxisScroll {
+            absolute,
+            discrete: 0,
+            value120,
+            relative_direction: None,
+            stop,
+        }
+    }
+
+    #[test]
+    fn finger_scroll_keeps_source_and_stop() {
+        let frame = axis_frame(
+            1,
+            &AxisScroll::default(),
+            &axis_scroll(7.5, 0, false),
+            Some(WlPointerAxisSource::Finger),
+        );
+        assert_eq!(frame.source, Some(AxisSource::Finger));
+        assert_eq!(frame.axis, (0.0, 7.5));
+        assert_eq!(frame.v120, Some((0, 0)));
+        assert_eq!(frame.stop, (false, false));
+
+        // Lifting the fingers ends the scroll, which starts momentum.
+        let frame = axis_frame(
+            2,
+            &AxisScroll::default(),
+            &axis_scroll(0.0, 0, true),
+            Some(WlPointerAxisSource::Finger),
+        );
+        assert_eq!(frame.source, Some(AxisSource::Finger));
+        assert_eq!(frame.stop, (false, true));
+    }
+
+    #[test]
+    fn wheel_scroll_keeps_source_and_steps() {
+        let frame = axis_frame(
+            1,
+            &AxisScroll::default(),
+            &axis_scroll(15.0, 120, false),
+            Some(WlPointerAxisSource::Wheel),
+        );
+        assert_eq!(frame.source, Some(AxisSource::Wheel));
+        assert_eq!(frame.axis, (0.0, 15.0));
+        assert_eq!(frame.v120, Some((0, 120)));
+    }
+
+    #[test]
+    fn discrete_steps_are_used_without_value120() {
+        let vertical = AxisScroll {
+            discrete: -1,
+            ..axis_scroll(-15.0, 0, false)
+        };
+        let frame = axis_frame(
+            1,
+            &AxisScroll::default(),
+            &vertical,
+            Some(WlPointerAxisSource::Wheel),
+        );
+        assert_eq!(frame.v120, Some((0, -120)));
+    }
+
+    #[test]
+    fn missing_source_is_not_sent() {
+        let frame = axis_frame(1, &AxisScroll::default(), &axis_scroll(1.0, 0, false), None);
+        assert_eq!(frame.source, None);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// The seats offered to Xwayland. Each seat of the local compositor is
+/// mirrored by a seat of the same name, which is created when the local seat
+/// first announces a capability and has its own key state and serials, so that
+/// input on one seat doesn't leak into another.
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+
+use smithay::input::Seat;
+use smithay::input::keyboard::KeyboardHandle;
+use smithay::reexports::wayland_server::backend::ObjectId as CompositorObjectId;
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::protocol::wl_keyboard::WlKeyboard;
+use smithay_client_toolkit::reexports::client::protocol::wl_pointer::WlPointer;
+use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
+use smithay_client_toolkit::seat::keyboard::KeyboardData;
+use smithay_client_toolkit::seat::pointer::PointerData;
+use smithay_client_toolkit::seat::pointer::ThemedPointer;
+
+use crate::client_utils::CursorSurface;
+use crate::client_utils::SeatObject;
+use crate::prelude::*;
+use crate::utils::SerialMap;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::WprsClientState;
+use crate::xwayland_xdg_shell::compositor::WprsCompositorState;
+use crate::xwayland_xdg_shell::touch::TouchPoints;
+
+#[derive(Debug)]
+pub struct WprsSeat {
+    pub seat: Seat<WprsState>,
+    pub(crate) serial_map: SerialMap,
+    pub(crate) pressed_keys: HashSet<u32>,
+    pub(crate) touch_points: TouchPoints<X11Surface>,
+    pub(crate) cursor_surface: CursorSurface<CompositorObjectId>,
+}
+
+impl WprsSeat {
+    pub(crate) fn keyboard(&self) -> Result<KeyboardHandle<WprsState>> {
+        self.seat.get_keyboard().location(loc!())
+    }
+}
+
+/// The local seat `keyboard` belongs to.
+pub(crate) fn keyboard_seat(keyboard: &WlKeyboard) -> Result<&WlSeat> {
+    Ok(keyboard
+        .data::<KeyboardData<WprsState>>()
+        .location(loc!())?
+        .seat())
+}
+
+/// The local seat `pointer` belongs to.
+pub(crate) fn pointer_seat(pointer: &WlPointer) -> Result<&WlSeat> {
+    Ok(pointer.data::<PointerData>().location(loc!())?.seat())
+}
+
+/// The local seat mirrored by `seat`.
+pub(crate) fn seat_object<'a>(
+    client_state: &'a WprsClientState,
+    seat: &Seat<WprsState>,
+) -> Result<&'a SeatObject<ThemedPointer>> {
+    let local_seat = client_state
+        .seat_names
+        .iter()
+        .find(|(_, name)| *name == seat.name())
+        .map(|(id, _)| id)
+        .location(loc!())?;
+    client_state
+        .seat_objects
+        .iter()
+        .find(|seat_obj| &seat_obj.seat.id() == local_seat)
+        .location(loc!())
+}
+
+/// The local pointer of the local seat mirrored by `seat`.
+pub(crate) fn themed_pointer<'a>(
+    client_state: &'a WprsClientState,
+    seat: &Seat<WprsState>,
+) -> Result<&'a ThemedPointer> {
+    seat_object(client_state, seat)?
+        .pointer
+        .as_ref()
+        .location(loc!())
+}
+
+impl WprsCompositorState {
+    pub(crate) fn seat(&self, name: &str) -> Result<&WprsSeat> {
+        self.seats
+            .get(name)
+            .with_context(loc!(), || format!("unknown seat {name:?}"))
+    }
+
+    pub(crate) fn seat_mut(&mut self, name: &str) -> Result<&mut WprsSeat> {
+        self.seats
+            .get_mut(name)
+            .with_context(loc!(), || format!("unknown seat {name:?}"))
+    }
+}
+
+impl WprsState {
+    /// Creates the seat mirroring the local `seat` if it doesn't exist yet.
+    /// The seat is named after the local seat, if that has already announced
+    /// its name.
+    pub(crate) fn add_compositor_seat(&mut self, seat: &WlSeat) -> Result<()> {
+        let client_state = &mut self.client_state;
+        let Entry::Vacant(entry) = client_state.seat_names.entry(seat.id()) else {
+            return Ok(());
+        };
+        let name = client_state
+            .seat_state
+            .info(seat)
+            .and_then(|info| info.name)
+            .unwrap_or_else(|| format!("seat{}", seat.id().protocol_id()));
+        entry.insert(name.clone());
+
+        let compositor_state = &mut self.compositor_state;
+        if let Entry::Vacant(entry) = compositor_state.seats.entry(name) {
+            info!("adding seat {:?}", entry.key());
+            let mut seat = compositor_state
+                .seat_state
+                .new_wl_seat(&compositor_state.dh, entry.key());
+            seat.add_keyboard(Default::default(), 200, 200)
+                .location(loc!())?;
+            seat.add_pointer();
+            entry.insert(WprsSeat {
+                seat,
+                serial_map: SerialMap::new(),
+                pressed_keys: HashSet::new(),
+                touch_points: TouchPoints::default(),
+                cursor_surface: CursorSurface::default(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Offers touch on the seat mirroring the local `seat` while that has the
+    /// touch capability.
+    pub(crate) fn set_compositor_touch(&mut self, seat: &WlSeat, available: bool) -> Result<()> {
+        let seat_name = self.compositor_seat_name(seat)?;
+        let seat = self.compositor_state.seat_mut(&seat_name)?;
+        match (available, seat.seat.get_touch().is_some()) {
+            (true, false) => {
+                seat.seat.add_touch();
+            },
+            (false, true) => {
+                seat.touch_points.cancel();
+                seat.seat.remove_touch();
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// The name of the seat mirroring the local `seat`.
+    pub(crate) fn compositor_seat_name(&self, seat: &WlSeat) -> Result<String> {
+        self.client_state
+            .seat_names
+            .get(&seat.id())
+            .cloned()
+            .with_context(loc!(), || format!("seat {seat:?} has no capabilities yet"))
+    }
+}
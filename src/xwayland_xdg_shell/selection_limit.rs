@@ -0,0 +1,290 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Rate limiting of X11 selection ownership changes. Some misbehaving X11 apps
+/// grab and release the clipboard in a loop, and forwarding every change would
+/// flood the local compositor and wprsd with selections nobody pastes. Changes
+/// beyond the cap are coalesced: only the latest is kept and forwarded once the
+/// rate drops, so the final selection is never lost.
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay::wayland::selection::SelectionTarget;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum SelectionRateLimit {
+    /// Forward every selection change.
+    Unlimited,
+    /// Forward up to max_per_sec selection changes per second and target.
+    Limited { max_per_sec: u32 },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum SelectionChange {
+    /// An X11 client took the selection, offering the mime types.
+    New(Vec<String>),
+    Cleared,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Admission {
+    Forward(SelectionChange),
+    /// Deferred for `delay`. A timer needs to be armed if `arm_timer`, otherwise
+    /// one is already pending.
+    Defer {
+        delay: Duration,
+        arm_timer: bool,
+    },
+}
+
+/// The recent changes of one selection target.
+#[derive(Debug, Default)]
+struct ChangeWindow {
+    /// When the changes in the last WINDOW were forwarded, oldest first.
+    forwarded: VecDeque<Instant>,
+    deferred: Option<SelectionChange>,
+    timer_armed: bool,
+    /// Whether the cap was hit since the window last drained, so that spamming
+    /// is only logged once per burst.
+    spamming: bool,
+}
+
+impl ChangeWindow {
+    fn admit(&mut self, change: SelectionChange, max_per_sec: u32, now: Instant) -> Admission {
+        while self
+            .forwarded
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= WINDOW)
+        {
+            self.forwarded.pop_front();
+        }
+        if self.forwarded.is_empty() {
+            self.spamming = false;
+        }
+
+        // A cap of 0 would never forward anything.
+        if self.forwarded.len() < max_per_sec.max(1) as usize {
+            self.forwarded.push_back(now);
+            // Anything deferred is older than this change.
+            self.deferred = None;
+            return Admission::Forward(change);
+        }
+
+        if !self.spamming {
+            warn!(
+                "an X11 client changed the selection more than {max_per_sec} times per second, forwarding only its latest selection"
+            );
+            self.spamming = true;
+        }
+        self.deferred = Some(change);
+        let oldest = *self.forwarded.front().unwrap();
+        let delay = (oldest + WINDOW).saturating_duration_since(now);
+        let arm_timer = !self.timer_armed;
+        self.timer_armed = true;
+        Admission::Defer { delay, arm_timer }
+    }
+
+    /// Takes the deferred change when its timer fires.
+    fn take_deferred(&mut self) -> Option<SelectionChange> {
+        self.timer_armed = false;
+        self.deferred.take()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SelectionLimiter {
+    limit: SelectionRateLimit,
+    clipboard: ChangeWindow,
+    primary: ChangeWindow,
+}
+
+impl SelectionLimiter {
+    pub(crate) fn new(limit: SelectionRateLimit) -> Self {
+        Self {
+            limit,
+            clipboard: ChangeWindow::default(),
+            primary: ChangeWindow::default(),
+        }
+    }
+
+    fn window(&mut self, selection: SelectionTarget) -> &mut ChangeWindow {
+        match selection {
+            SelectionTarget::Clipboard => &mut self.clipboard,
+            SelectionTarget::Primary => &mut self.primary,
+        }
+    }
+
+    fn admit(
+        &mut self,
+        selection: SelectionTarget,
+        change: SelectionChange,
+        now: Instant,
+    ) -> Admission {
+        match self.limit {
+            SelectionRateLimit::Unlimited => Admission::Forward(change),
+            SelectionRateLimit::Limited { max_per_sec } => {
+                self.window(selection).admit(change, max_per_sec, now)
+            },
+        }
+    }
+}
+
+impl WprsState {
+    /// Forwards `change` to the local selection `selection`, unless X11
+    /// clients are changing it too often.
+    pub(crate) fn limit_selection_change(
+        &mut self,
+        selection: SelectionTarget,
+        change: SelectionChange,
+    ) {
+        match self
+            .compositor_state
+            .selection_limiter
+            .admit(selection, change, Instant::now())
+        {
+            Admission::Forward(change) => self.forward_selection_change(selection, change),
+            Admission::Defer { delay, arm_timer } => {
+                debug!("deferring {selection:?} change by {delay:?}");
+                if arm_timer {
+                    self.arm_deferred_selection_timer(selection, delay);
+                }
+            },
+        }
+    }
+
+    fn forward_selection_change(&mut self, selection: SelectionTarget, change: SelectionChange) {
+        match change {
+            SelectionChange::New(mime_types) => self.set_local_selection(selection, mime_types),
+            SelectionChange::Cleared => self.unset_local_selection(selection),
+        }
+    }
+
+    fn arm_deferred_selection_timer(&self, selection: SelectionTarget, delay: Duration) {
+        self.event_loop_handle
+            .insert_source(Timer::from_duration(delay), move |_, _, state| {
+                if let Some(change) = state
+                    .compositor_state
+                    .selection_limiter
+                    .window(selection)
+                    .take_deferred()
+                {
+                    state.limit_selection_change(selection, change);
+                }
+                TimeoutAction::Drop
+            })
+            .map_err(|e| anyhow!("failed to insert selection timer: {e}"))
+            .log_and_ignore(loc!());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_selection(mime_type: &str) -> SelectionChange {
+        SelectionChange::New(vec![mime_type.to_string()])
+    }
+
+    #[test]
+    fn spam_is_capped_and_latest_change_is_kept() {
+        let mut limiter = SelectionLimiter::new(SelectionRateLimit::Limited { max_per_sec: 10 });
+        let start = Instant::now();
+        let mut forwarded = 0;
+        let mut timers = 0;
+        // An app grabbing and releasing the clipboard every millisecond.
+        for i in 0..500 {
+            let change = if i % 2 == 0 {
+                new_selection(&format!("text/plain;{i}"))
+            } else {
+                SelectionChange::Cleared
+            };
+            let now = start + Duration::from_millis(i);
+            match limiter.admit(SelectionTarget::Clipboard, change, now) {
+                Admission::Forward(_) => forwarded += 1,
+                Admission::Defer { delay, arm_timer } => {
+                    assert!(delay <= WINDOW);
+                    timers += usize::from(arm_timer);
+                },
+            }
+        }
+        assert_eq!(forwarded, 10);
+        assert_eq!(timers, 1);
+
+        // The timer forwards the app's final state.
+        let window = limiter.window(SelectionTarget::Clipboard);
+        assert_eq!(window.take_deferred(), Some(SelectionChange::Cleared));
+        assert_eq!(
+            limiter.admit(
+                SelectionTarget::Clipboard,
+                SelectionChange::Cleared,
+                start + WINDOW,
+            ),
+            Admission::Forward(SelectionChange::Cleared)
+        );
+    }
+
+    #[test]
+    fn rapid_copies_are_forwarded() {
+        let mut limiter = SelectionLimiter::new(SelectionRateLimit::Limited { max_per_sec: 10 });
+        let start = Instant::now();
+        // A user mashing copy for a few seconds.
+        for i in 0..30 {
+            let now = start + Duration::from_millis(i * 120);
+            assert_eq!(
+                limiter.admit(SelectionTarget::Clipboard, new_selection("text/plain"), now),
+                Admission::Forward(new_selection("text/plain"))
+            );
+        }
+    }
+
+    #[test]
+    fn targets_are_limited_separately() {
+        let mut limiter = SelectionLimiter::new(SelectionRateLimit::Limited { max_per_sec: 1 });
+        let now = Instant::now();
+        assert!(matches!(
+            limiter.admit(SelectionTarget::Clipboard, new_selection("a"), now),
+            Admission::Forward(_)
+        ));
+        assert!(matches!(
+            limiter.admit(SelectionTarget::Primary, new_selection("b"), now),
+            Admission::Forward(_)
+        ));
+        assert!(matches!(
+            limiter.admit(SelectionTarget::Clipboard, new_selection("c"), now),
+            Admission::Defer { .. }
+        ));
+    }
+
+    #[test]
+    fn unlimited_forwards_everything() {
+        let mut limiter = SelectionLimiter::new(SelectionRateLimit::Unlimited);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(matches!(
+                limiter.admit(SelectionTarget::Clipboard, SelectionChange::Cleared, now),
+                Admission::Forward(_)
+            ));
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// X11 session management properties. Session-managed apps set SM_CLIENT_ID,
+/// the id the session manager restores them with, and WM_COMMAND, the command
+/// line which restarts them, on their client leader window (WM_CLIENT_LEADER).
+/// Older apps only set WM_COMMAND, on their toplevel window. We don't act as a
+/// session manager, but the properties of each toplevel are recorded when it's
+/// mapped, so that session tooling can restore apps. They can be queried with
+/// the session_windows command on the control socket.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde_derive::Serialize;
+use smithay::reexports::wayland_server::backend::ObjectId as CompositorObjectId;
+use smithay::xwayland::X11Surface;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::rust_connection::RustConnection;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+x11rb::atom_manager! {
+    pub Atoms: AtomsCookie {
+        WM_CLIENT_LEADER,
+        SM_CLIENT_ID,
+        WM_COMMAND,
+    }
+}
+
+/// Properties are short, this is only a bound.
+const MAX_PROPERTY_LEN: u32 = 4096;
+
+/// The session management properties of a window.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
+pub struct SessionProperties {
+    pub sm_client_id: Option<String>,
+    /// Empty if the window has no WM_COMMAND.
+    pub wm_command: Vec<String>,
+}
+
+impl SessionProperties {
+    fn is_empty(&self) -> bool {
+        self.sm_client_id.is_none() && self.wm_command.is_empty()
+    }
+}
+
+/// The arguments of a WM_COMMAND, which are NUL-terminated.
+fn parse_wm_command(value: &[u8]) -> Vec<String> {
+    let value = value.strip_suffix(b"\0").unwrap_or(value);
+    if value.is_empty() {
+        return Vec::new();
+    }
+    value
+        .split(|b| *b == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+fn get_string_property(conn: &RustConnection, window: u32, atom: u32) -> Result<Option<Vec<u8>>> {
+    let reply = conn
+        .get_property(false, window, atom, AtomEnum::STRING, 0, MAX_PROPERTY_LEN)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?;
+    Ok((reply.format == 8).then_some(reply.value))
+}
+
+/// Reads the session management properties of `window`, looking them up on
+/// its client leader first.
+fn fetch_session_properties(conn: &X11Connection, window: u32) -> Result<SessionProperties> {
+    let atoms = Atoms::new(&**conn)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?;
+    let leader = conn
+        .get_property(
+            false,
+            window,
+            atoms.WM_CLIENT_LEADER,
+            AtomEnum::WINDOW,
+            0,
+            1,
+        )
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .value32()
+        .and_then(|mut values| values.next())
+        .filter(|leader| *leader != x11rb::NONE)
+        .unwrap_or(window);
+
+    let sm_client_id = get_string_property(conn, leader, atoms.SM_CLIENT_ID)
+        .location(loc!())?
+        .map(|value| String::from_utf8_lossy(&value).into_owned());
+    let mut wm_command = None;
+    for window in [leader, window] {
+        wm_command = get_string_property(conn, window, atoms.WM_COMMAND).location(loc!())?;
+        if wm_command.is_some() {
+            break;
+        }
+    }
+    Ok(SessionProperties {
+        sm_client_id,
+        wm_command: wm_command
+            .as_deref()
+            .map(parse_wm_command)
+            .unwrap_or_default(),
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SessionWindow {
+    window_id: u32,
+    class: String,
+    instance: String,
+    #[serde(flatten)]
+    properties: SessionProperties,
+}
+
+/// The session management properties of the mapped toplevels. Cloning
+/// shares the underlying map, so the control server can read what the event
+/// loop records.
+#[derive(Debug)]
+pub struct SessionWindows<S = CompositorObjectId>(Arc<Mutex<HashMap<S, SessionWindow>>>);
+
+impl<S> Default for SessionWindows<S> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<S> Clone for SessionWindows<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S: Eq + Hash> SessionWindows<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(
+        &self,
+        surface: S,
+        window_id: u32,
+        class: String,
+        instance: String,
+        properties: SessionProperties,
+    ) {
+        self.0.lock().unwrap().insert(
+            surface,
+            SessionWindow {
+                window_id,
+                class,
+                instance,
+                properties,
+            },
+        );
+    }
+
+    /// Publishes the session management `properties` of the toplevel
+    /// `surface`.
+    pub(crate) fn record(
+        &self,
+        surface: S,
+        x11_surface: &X11Surface,
+        properties: SessionProperties,
+    ) {
+        self.insert(
+            surface,
+            x11_surface.window_id(),
+            x11_surface.class(),
+            x11_surface.instance(),
+            properties,
+        );
+    }
+
+    pub(crate) fn remove(&self, surface: &S) {
+        self.0.lock().unwrap().remove(surface);
+    }
+
+    /// A JSON array of the windows with session management properties,
+    /// ordered by window id.
+    pub fn to_json(&self) -> Result<String> {
+        let mut windows: Vec<SessionWindow> = self.0.lock().unwrap().values().cloned().collect();
+        windows.sort_by_key(|window| window.window_id);
+        serde_json::to_string(&windows).location(loc!())
+    }
+}
+
+impl WprsState {
+    /// The session management properties of `x11_surface`, if it has any.
+    /// They're round trips to the X server, so only call this for windows
+    /// which are about to get a role.
+    pub(crate) fn session_properties(&self, x11_surface: &X11Surface) -> Option<SessionProperties> {
+        if x11_surface.is_override_redirect() {
+            return None;
+        }
+        let conn = self.compositor_state.x11_conn.as_ref()?;
+        fetch_session_properties(conn, x11_surface.window_id())
+            .warn(loc!())
+            .ok()
+            .filter(|properties| !properties.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wm_command_is_split_into_args() {
+        assert_eq!(
+            parse_wm_command(b"xterm\0-geometry\x0080x24\0"),
+            ["xterm", "-geometry", "80x24"]
+        );
+        // Some apps leave out the final NUL.
+        assert_eq!(
+            parse_wm_command(b"xclock\0-digital"),
+            ["xclock", "-digital"]
+        );
+        // Empty arguments are kept.
+        assert_eq!(parse_wm_command(b"app\0\0"), ["app", ""]);
+        assert!(parse_wm_command(b"").is_empty());
+    }
+
+    #[test]
+    fn session_windows_are_listed_by_window_id() {
+        let windows = SessionWindows::new();
+        windows.insert(
+            1,
+            0x600004,
+            "XTerm".to_string(),
+            "xterm".to_string(),
+            SessionProperties {
+                sm_client_id: Some("10d2f0a7c5000162".to_string()),
+                wm_command: vec!["xterm".to_string()],
+            },
+        );
+        windows.insert(
+            2,
+            0x400002,
+            "XClock".to_string(),
+            "xclock".to_string(),
+            SessionProperties {
+                sm_client_id: None,
+                wm_command: vec!["xclock".to_string()],
+            },
+        );
+        assert_eq!(
+            windows.to_json().unwrap(),
+            r#"[{"window_id":4194306,"class":"XClock","instance":"xclock","sm_client_id":null,"wm_command":["xclock"]},{"window_id":6291460,"class":"XTerm","instance":"xterm","sm_client_id":"10d2f0a7c5000162","wm_command":["xterm"]}]"#
+        );
+        windows.remove(&2);
+        windows.remove(&1);
+        assert_eq!(windows.to_json().unwrap(), "[]");
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// A point-in-time dump of the compositor state for attaching to bug reports:
+/// the surface tree, X11 windows which haven't been paired with a surface yet,
+/// outputs, and selection state. Buffer contents are never included, only
+/// their metadata.
+///
+/// Window titles, classes and session properties are private data, they're
+/// only included with --log-priv-data.
+///
+/// Snapshots are taken on the event loop (SIGUSR1 is delivered through a
+/// calloop signal source, not an async signal handler), so taking one never
+/// races with the state being modified.
+use serde_derive::Serialize;
+use smithay::xwayland::X11Surface;
+
+use crate::args;
+use crate::prelude::*;
+use crate::serialization::wayland::DataSource;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::XWaylandSurface;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::session::SessionProperties;
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub x11_display: Option<u32>,
+    pub x11_screen_offset: Option<(i32, i32)>,
+    pub surfaces: Vec<SurfaceSnapshot>,
+    /// X11 windows which haven't been matched to a wl_surface yet.
+    pub unpaired_x11_surfaces: Vec<X11SurfaceSnapshot>,
+    /// Number of child surfaces waiting for their parent to get a role.
+    pub pending_children: usize,
+    pub outputs: Vec<OutputSnapshot>,
+    pub selection: SelectionSnapshot,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SurfaceSnapshot {
+    pub id: String,
+    pub role: Option<&'static str>,
+    /// Only set for toplevels, popups and layer surfaces.
+    pub configured: Option<bool>,
+    pub x11_surface: Option<X11SurfaceSnapshot>,
+    pub parent: Option<String>,
+    pub children: Vec<String>,
+    pub output_ids: Vec<u32>,
+    pub buffer: Option<BufferSnapshot>,
+    pub buffer_attached: bool,
+    pub pending_damage_rects: Option<usize>,
+    /// Only set with --log-priv-data.
+    pub session: Option<SessionProperties>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct X11SurfaceSnapshot {
+    pub window_id: u32,
+    pub title: String,
+    pub class: String,
+    pub window_type: Option<String>,
+    pub transient_for: Option<u32>,
+    pub override_redirect: bool,
+    pub mapped: bool,
+    /// x, y, width, height
+    pub geometry: (i32, i32, i32, i32),
+}
+
+#[derive(Debug, Serialize)]
+pub struct BufferSnapshot {
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub format: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputSnapshot {
+    pub id: u32,
+    pub name: String,
+    pub location: (i32, i32),
+    /// width, height, refresh in mHz
+    pub mode: Option<(i32, i32, i32)>,
+    pub scale: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelectionSnapshot {
+    pub selection_offer: bool,
+    pub selection_source: bool,
+    pub primary_selection_offer: bool,
+    pub primary_selection_source: bool,
+    pub last_focused_window: Option<String>,
+    pub popup_grab_stack: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).location(loc!())
+    }
+}
+
+/// `value`, unless private data mustn't be logged.
+fn redact(value: String) -> String {
+    if args::get_log_priv_data() {
+        value
+    } else {
+        "<redacted>".to_string()
+    }
+}
+
+impl From<&X11Surface> for X11SurfaceSnapshot {
+    fn from(x11_surface: &X11Surface) -> Self {
+        let geometry = x11_surface.geometry();
+        Self {
+            window_id: x11_surface.window_id(),
+            title: redact(x11_surface.title()),
+            class: redact(x11_surface.class()),
+            window_type: x11_surface
+                .window_type()
+                .map(|window_type| format!("{window_type:?}")),
+            transient_for: x11_surface.is_transient_for(),
+            override_redirect: x11_surface.is_override_redirect(),
+            mapped: x11_surface.is_mapped(),
+            geometry: (
+                geometry.loc.x,
+                geometry.loc.y,
+                geometry.size.w,
+                geometry.size.h,
+            ),
+        }
+    }
+}
+
+impl SurfaceSnapshot {
+    fn new(id: String, surface: &XWaylandSurface) -> Self {
+        let (role, configured) = match &surface.role {
+            None => (None, None),
+            Some(Role::Cursor) => (Some("Cursor"), None),
+            Some(Role::DragIcon) => (Some("DragIcon"), None),
+            Some(Role::XdgToplevel(toplevel)) => (Some("XdgToplevel"), Some(toplevel.configured)),
+            Some(Role::XdgPopup(popup)) => (Some("XdgPopup"), Some(popup.configured)),
+            Some(Role::SubSurface(_)) => (Some("SubSurface"), None),
+            Some(Role::LayerSurface(layer_surface)) => {
+                (Some("LayerSurface"), Some(layer_surface.configured))
+            },
+        };
+        let children: Vec<_> = surface.children.iter().map(ToString::to_string).collect();
+        let mut output_ids: Vec<_> = surface.output_ids.iter().copied().collect();
+        output_ids.sort_unstable();
+        Self {
+            id,
+            role,
+            configured,
+            x11_surface: surface.x11_surface.as_ref().map(Into::into),
+            parent: surface
+                .parent
+                .as_ref()
+                .map(|parent| parent.surface_id.to_string()),
+            children,
+            output_ids,
+            buffer: surface.buffer.as_ref().map(|buffer| BufferSnapshot {
+                width: buffer.metadata.width,
+                height: buffer.metadata.height,
+                stride: buffer.metadata.stride,
+                format: format!("{:?}", buffer.metadata.format),
+            }),
+            buffer_attached: surface.buffer_attached,
+            pending_damage_rects: surface.damage.as_ref().map(Vec::len),
+            session: surface
+                .session
+                .clone()
+                .filter(|_| args::get_log_priv_data()),
+        }
+    }
+}
+
+impl WprsState {
+    /// Captures the current compositor state, see [`Snapshot`].
+    pub fn snapshot(&self) -> Snapshot {
+        let mut surfaces: Vec<_> = self
+            .surfaces
+            .iter()
+            .map(|(id, surface)| {
+                (
+                    id.protocol_id(),
+                    SurfaceSnapshot::new(id.to_string(), surface),
+                )
+            })
+            .collect();
+        surfaces.sort_by_key(|(protocol_id, _)| *protocol_id);
+
+        let mut outputs: Vec<_> = self
+            .compositor_state
+            .outputs
+            .iter()
+            .map(|(id, (output, _))| {
+                let location = output.current_location();
+                OutputSnapshot {
+                    id: *id,
+                    name: output.name(),
+                    location: (location.x, location.y),
+                    mode: output
+                        .current_mode()
+                        .map(|mode| (mode.size.w, mode.size.h, mode.refresh)),
+                    scale: output.current_scale().fractional_scale(),
+                }
+            })
+            .collect();
+        outputs.sort_by_key(|output| output.id);
+
+        let client_state = &self.client_state;
+        Snapshot {
+            x11_display: self.compositor_state.x11_display,
+            x11_screen_offset: self
+                .compositor_state
+                .x11_screen_offset
+                .map(|offset| (offset.x, offset.y)),
+            surfaces: surfaces.into_iter().map(|(_, surface)| surface).collect(),
+            unpaired_x11_surfaces: self
+                .compositor_state
+                .x11_surfaces
+                .iter()
+                .map(Into::into)
+                .collect(),
+            pending_children: self.compositor_state.pending_parents.len(),
+            outputs,
+            selection: SelectionSnapshot {
+                selection_offer: client_state
+                    .selection_offers
+                    .get(DataSource::Selection)
+                    .is_some(),
+                selection_source: client_state.selection_source.is_some(),
+                primary_selection_offer: client_state
+                    .selection_offers
+                    .get(DataSource::Primary)
+                    .is_some(),
+                primary_selection_source: client_state.primary_selection_source.is_some(),
+                last_focused_window: client_state
+                    .last_focused_window
+                    .as_ref()
+                    .map(|parent| parent.surface_id.to_string()),
+                popup_grab_stack: client_state
+                    .popup_grab_stack
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            },
+        }
+    }
+}
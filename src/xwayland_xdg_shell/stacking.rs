@@ -0,0 +1,262 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Stacking order of the children of a surface. Children which are mapped as
+/// subsurfaces are stacked locally in the same order, so that overlapping X11
+/// child windows (e.g., overlays) are composited correctly. New subsurfaces
+/// start at the top, as newly mapped X11 windows do, and restack requests from
+/// X11 clients are forwarded with wl_subsurface.place_above/place_below.
+use std::slice;
+
+use smithay::reexports::wayland_server::Resource;
+use smithay::xwayland::X11Surface;
+use smithay::xwayland::xwm::Reorder;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::shell::WaylandSurface;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::Role;
+
+/// Where a child should be stacked relative to its siblings.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Restack<T> {
+    Top,
+    Above(T),
+    Below(T),
+    Bottom,
+}
+
+/// How to place a child locally after restacking it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Placement<T> {
+    Above(T),
+    Below(T),
+}
+
+/// Children in stacking order, bottom first.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ZOrderedChildren<T>(Vec<T>);
+
+impl<T> Default for ZOrderedChildren<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: Clone + PartialEq> ZOrderedChildren<T> {
+    /// Adds `child` at the top, unless it's already a child.
+    pub(crate) fn insert(&mut self, child: T) {
+        if !self.0.contains(&child) {
+            self.0.push(child);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, child: &T) {
+        self.0.retain(|c| c != child);
+    }
+
+    pub(crate) fn iter(&self) -> slice::Iter<'_, T> {
+        self.0.iter()
+    }
+
+    /// Moves `child` as requested and returns how to place it relative to a
+    /// sibling to get the same order. Requests relative to unknown siblings
+    /// are ignored.
+    pub(crate) fn restack(&mut self, child: &T, restack: Restack<T>) -> Option<Placement<T>> {
+        let from = self.0.iter().position(|c| c == child)?;
+        let sibling_position = |children: &[T], sibling: &T| {
+            children
+                .iter()
+                .position(|c| c == sibling)
+                .filter(|_| sibling != child)
+        };
+        let to = match &restack {
+            Restack::Top => self.0.len() - 1,
+            Restack::Bottom => 0,
+            Restack::Above(sibling) => {
+                let position = sibling_position(&self.0, sibling)?;
+                if position < from {
+                    position + 1
+                } else {
+                    position
+                }
+            },
+            Restack::Below(sibling) => {
+                let position = sibling_position(&self.0, sibling)?;
+                if position < from {
+                    position
+                } else {
+                    position - 1
+                }
+            },
+        };
+        let child = self.0.remove(from);
+        self.0.insert(to, child);
+        match (to.checked_sub(1), self.0.get(to + 1)) {
+            (Some(below), _) => Some(Placement::Above(self.0[below].clone())),
+            (None, Some(above)) => Some(Placement::Below(above.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+impl WprsState {
+    /// Restacks the subsurface of `window` among its siblings.
+    pub(crate) fn restack_x11_surface(
+        &mut self,
+        window: &X11Surface,
+        reorder: Reorder,
+    ) -> Result<()> {
+        let Some(surface_id) = window.wl_surface().map(|surface| surface.id()) else {
+            return Ok(());
+        };
+        let find_surface = |sibling| {
+            self.surfaces.iter().find_map(|(id, xwayland_surface)| {
+                xwayland_surface
+                    .x11_surface
+                    .as_ref()
+                    .filter(|x11_surface| x11_surface.window_id() == sibling)
+                    .map(|_| id.clone())
+            })
+        };
+        let restack = match reorder {
+            Reorder::Top => Restack::Top,
+            Reorder::Bottom => Restack::Bottom,
+            Reorder::Above(sibling) => Restack::Above(find_surface(sibling).location(loc!())?),
+            Reorder::Below(sibling) => Restack::Below(find_surface(sibling).location(loc!())?),
+        };
+
+        let xwayland_surface = self.surfaces.get(&surface_id).location(loc!())?;
+        let Some(Role::SubSurface(subsurface)) = &xwayland_surface.role else {
+            // Toplevels and popups are stacked by the local compositor.
+            return Ok(());
+        };
+        let parent_surface = subsurface.parent_surface.clone();
+        let local_subsurface = subsurface.local_subsurface.subsurface.clone();
+        let parent_id = xwayland_surface
+            .parent
+            .as_ref()
+            .location(loc!())?
+            .surface_id
+            .clone();
+
+        let parent = self.surfaces.get_mut(&parent_id).location(loc!())?;
+        let Some(placement) = parent.children.restack(&surface_id, restack) else {
+            return Ok(());
+        };
+        debug!("restacking {surface_id:?}: {placement:?}");
+        let sibling_surface = |sibling_id| -> Result<WlSurface> {
+            match &self.surfaces.get(&sibling_id).location(loc!())?.role {
+                Some(Role::SubSurface(sibling)) => Ok(sibling.wl_surface().clone()),
+                // Siblings which aren't subsurfaces are stacked separately.
+                _ => Ok(parent_surface.clone()),
+            }
+        };
+        match placement {
+            Placement::Above(sibling_id) => {
+                local_subsurface.place_above(&sibling_surface(sibling_id).location(loc!())?);
+            },
+            Placement::Below(sibling_id) => {
+                let sibling = sibling_surface(sibling_id).location(loc!())?;
+                if sibling == parent_surface {
+                    local_subsurface.place_above(&parent_surface);
+                } else {
+                    local_subsurface.place_below(&sibling);
+                }
+            },
+        }
+        // The order is applied with the parent's next commit.
+        parent_surface.commit();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn children(ids: &[u32]) -> ZOrderedChildren<u32> {
+        let mut children = ZOrderedChildren::default();
+        for id in ids {
+            children.insert(*id);
+        }
+        children
+    }
+
+    fn order(children: &ZOrderedChildren<u32>) -> Vec<u32> {
+        children.iter().copied().collect()
+    }
+
+    #[test]
+    fn children_keep_mapping_order() {
+        let mut children = children(&[3, 1, 2]);
+        // Committing a child again doesn't raise it.
+        children.insert(1);
+        assert_eq!(order(&children), vec![3, 1, 2]);
+        children.remove(&1);
+        assert_eq!(order(&children), vec![3, 2]);
+    }
+
+    #[test]
+    fn restack_z_order() {
+        let mut children = children(&[1, 2, 3, 4]);
+
+        assert_eq!(
+            children.restack(&1, Restack::Top),
+            Some(Placement::Above(4))
+        );
+        assert_eq!(order(&children), vec![2, 3, 4, 1]);
+
+        assert_eq!(
+            children.restack(&1, Restack::Bottom),
+            Some(Placement::Below(2))
+        );
+        assert_eq!(order(&children), vec![1, 2, 3, 4]);
+
+        // An overlay moved directly above the content it covers.
+        assert_eq!(
+            children.restack(&4, Restack::Above(2)),
+            Some(Placement::Above(2))
+        );
+        assert_eq!(order(&children), vec![1, 2, 4, 3]);
+
+        assert_eq!(
+            children.restack(&1, Restack::Below(3)),
+            Some(Placement::Above(4))
+        );
+        assert_eq!(order(&children), vec![2, 4, 1, 3]);
+
+        assert_eq!(
+            children.restack(&3, Restack::Below(2)),
+            Some(Placement::Below(2))
+        );
+        assert_eq!(order(&children), vec![3, 2, 4, 1]);
+    }
+
+    #[test]
+    fn restack_relative_to_unknown_sibling_is_ignored() {
+        let mut children = children(&[1, 2]);
+        assert_eq!(children.restack(&1, Restack::Above(5)), None);
+        assert_eq!(children.restack(&1, Restack::Above(1)), None);
+        assert_eq!(children.restack(&5, Restack::Top), None);
+        assert_eq!(order(&children), vec![1, 2]);
+    }
+
+    #[test]
+    fn single_child_has_no_placement() {
+        let mut children = children(&[1]);
+        assert_eq!(children.restack(&1, Restack::Top), None);
+    }
+}
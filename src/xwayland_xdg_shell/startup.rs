@@ -0,0 +1,69 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Startup notification of X11 apps. Launchers pass the activation token they
+/// got from the compositor to X11 apps as DESKTOP_STARTUP_ID, which the apps
+/// set as the _NET_STARTUP_ID of their windows. Activating the local toplevel
+/// with it when the window is mapped uses up the token, which completes the
+/// launcher's startup feedback and lets the compositor focus the new window.
+/// Apps which weren't launched with a startup ID (or don't set it) are mapped
+/// without activation, like before.
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::activation::ActivationState;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+
+fn startup_token(startup_id: Option<String>) -> Option<String> {
+    startup_id.filter(|startup_id| !startup_id.trim().is_empty())
+}
+
+/// Activates `local_surface`, the newly created toplevel for `x11_surface`,
+/// with the startup ID of the X11 window, if any.
+pub(crate) fn complete(
+    activation_state: Option<&ActivationState>,
+    x11_surface: &X11Surface,
+    local_surface: &WlSurface,
+) {
+    let Some(token) = startup_token(x11_surface.startup_id()) else {
+        return;
+    };
+    let Some(activation_state) = activation_state else {
+        debug!("not completing startup of {x11_surface:?}, xdg_activation_v1 is not available");
+        return;
+    };
+    debug!("completing startup of {x11_surface:?}");
+    activation_state.activate::<WprsState>(local_surface, token);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_id_is_used_as_token() {
+        assert_eq!(
+            startup_token(Some("launcher-token-1".to_string())),
+            Some("launcher-token-1".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_startup_id_is_ignored() {
+        assert_eq!(startup_token(None), None);
+        assert_eq!(startup_token(Some(String::new())), None);
+        assert_eq!(startup_token(Some(" ".to_string())), None);
+    }
+}
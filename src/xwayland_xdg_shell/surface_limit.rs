@@ -0,0 +1,191 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Limiting of the number of surfaces tracked per client. Each tracked surface
+/// has a local surface and keeps a copy of its last buffer, so an X11 app which
+/// leaks windows would otherwise grow our memory usage (and the local
+/// compositor's) without bound. Surfaces beyond the limit are refused: they're
+/// never mapped and their commits are logged as errors. The current counts can
+/// be queried with the surface_counts command on the control socket.
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::backend::ObjectId as CompositorObjectId;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+
+use crate::prelude::*;
+use crate::serialization::ClientId;
+use crate::xwayland_xdg_shell::WprsState;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum SurfaceLimit {
+    /// Track every surface.
+    Unlimited,
+    /// Track up to max_per_client surfaces of each client.
+    Limited { max_per_client: usize },
+}
+
+#[derive(Debug)]
+struct Counts<S> {
+    clients: HashMap<S, ClientId>,
+    per_client: HashMap<ClientId, usize>,
+}
+
+impl<S> Default for Counts<S> {
+    fn default() -> Self {
+        Self {
+            clients: HashMap::new(),
+            per_client: HashMap::new(),
+        }
+    }
+}
+
+/// The number of tracked surfaces of each client. Cloning shares the
+/// underlying counts, so the control server can read what the event loop
+/// records.
+#[derive(Debug)]
+pub struct SurfaceCounts<S = CompositorObjectId>(Arc<Mutex<Counts<S>>>);
+
+impl<S> Default for SurfaceCounts<S> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<S> Clone for SurfaceCounts<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S: Clone + Eq + Hash> SurfaceCounts<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts `surface` towards the surfaces of `client`, unless that would
+    /// exceed `limit`. Surfaces which are already counted are always admitted.
+    pub(crate) fn admit(&self, surface: &S, client: ClientId, limit: SurfaceLimit) -> Result<()> {
+        let mut counts = self.0.lock().unwrap();
+        if counts.clients.contains_key(surface) {
+            return Ok(());
+        }
+        let count = counts.per_client.get(&client).copied().unwrap_or_default();
+        if let SurfaceLimit::Limited { max_per_client } = limit
+            && count >= max_per_client
+        {
+            bail!(
+                "client {client:?} already has {count} surfaces, refusing more (see --surface-limit)"
+            );
+        }
+        counts.per_client.insert(client, count + 1);
+        counts.clients.insert(surface.clone(), client);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, surface: &S) {
+        let mut counts = self.0.lock().unwrap();
+        let Some(client) = counts.clients.remove(surface) else {
+            return;
+        };
+        if let Some(count) = counts.per_client.get_mut(&client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_client.remove(&client);
+            }
+        }
+    }
+
+    /// A JSON object mapping client ids to their number of surfaces.
+    pub fn to_json(&self) -> Result<String> {
+        let counts: BTreeMap<u64, usize> = self
+            .0
+            .lock()
+            .unwrap()
+            .per_client
+            .iter()
+            .map(|(client, count)| (client.0, *count))
+            .collect();
+        serde_json::to_string(&counts).location(loc!())
+    }
+}
+
+impl WprsState {
+    /// Counts `surface` towards its client's surfaces, call this before
+    /// tracking a new surface in `surfaces`. Errors if the client is at the
+    /// surface limit.
+    pub(crate) fn admit_surface(&self, surface: &WlSurface) -> Result<()> {
+        let client = surface.client().location(loc!())?;
+        self.surface_counts
+            .admit(&surface.id(), ClientId::new(&client), self.surface_limit)
+            .location(loc!())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: SurfaceLimit = SurfaceLimit::Limited { max_per_client: 2 };
+
+    #[test]
+    fn surfaces_beyond_limit_are_refused() {
+        let counts = SurfaceCounts::new();
+        counts.admit(&1, ClientId(1), LIMIT).unwrap();
+        counts.admit(&2, ClientId(1), LIMIT).unwrap();
+        assert!(counts.admit(&3, ClientId(1), LIMIT).is_err());
+        // Other clients have their own limit.
+        counts.admit(&3, ClientId(2), LIMIT).unwrap();
+        assert_eq!(counts.to_json().unwrap(), r#"{"1":2,"2":1}"#);
+    }
+
+    #[test]
+    fn admitted_surfaces_are_counted_once() {
+        let counts = SurfaceCounts::new();
+        counts.admit(&1, ClientId(1), LIMIT).unwrap();
+        counts.admit(&2, ClientId(1), LIMIT).unwrap();
+        counts.admit(&1, ClientId(1), LIMIT).unwrap();
+        assert_eq!(counts.to_json().unwrap(), r#"{"1":2}"#);
+    }
+
+    #[test]
+    fn removed_surfaces_free_their_slot() {
+        let counts = SurfaceCounts::new();
+        counts.admit(&1, ClientId(1), LIMIT).unwrap();
+        counts.admit(&2, ClientId(1), LIMIT).unwrap();
+        counts.remove(&1);
+        counts.remove(&1);
+        counts.admit(&3, ClientId(1), LIMIT).unwrap();
+        counts.remove(&2);
+        counts.remove(&3);
+        assert_eq!(counts.to_json().unwrap(), "{}");
+    }
+
+    #[test]
+    fn unlimited_admits_everything() {
+        let counts = SurfaceCounts::new();
+        for surface in 0..100 {
+            counts
+                .admit(&surface, ClientId(1), SurfaceLimit::Unlimited)
+                .unwrap();
+        }
+        assert_eq!(counts.to_json().unwrap(), r#"{"1":100}"#);
+    }
+}
@@ -0,0 +1,508 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Support for _NET_WM_SYNC_REQUEST, with which X11 apps tell the WM when
+/// they've finished drawing at a new size. Before resizing a window which
+/// supports it, we send it a sync request, then hold its frames until the app
+/// sets its sync counter to the request's value, so that frames drawn halfway
+/// through a resize aren't forwarded. Apps which advertise the protocol but
+/// don't update the counter have their frames shown after a timeout, and get
+/// no more sync requests until they do update it.
+use std::os::fd::AsFd;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::reexports::calloop::Interest;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::Mode;
+use smithay::reexports::calloop::PostAction;
+use smithay::reexports::calloop::generic::Generic;
+use smithay::reexports::calloop::timer::TimeoutAction;
+use smithay::reexports::calloop::timer::Timer;
+use smithay::reexports::wayland_server::backend::ObjectId;
+use smithay::utils::Logical;
+use smithay::utils::Size;
+use smithay::xwayland::X11Surface;
+use x11rb::connection::Connection as X11Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::sync::Alarm;
+use x11rb::protocol::sync::ChangeAlarmAux;
+use x11rb::protocol::sync::ConnectionExt as SyncConnectionExt;
+use x11rb::protocol::sync::Counter;
+use x11rb::protocol::sync::CreateAlarmAux;
+use x11rb::protocol::sync::Int64;
+use x11rb::protocol::sync::TESTTYPE;
+use x11rb::protocol::sync::VALUETYPE;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ClientMessageEvent;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::EventMask;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as WrapperConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum SyncRequestBehavior {
+    /// Forward frames drawn during resizes as they're committed.
+    Disabled,
+    /// Hold frames until the app has finished drawing at the new size, but
+    /// for at most timeout_ms.
+    Enabled { timeout_ms: u32 },
+}
+
+fn to_int64(value: i64) -> Int64 {
+    Int64 {
+        hi: (value >> 32) as i32,
+        lo: value as u32,
+    }
+}
+
+fn from_int64(value: Int64) -> i64 {
+    (i64::from(value.hi) << 32) | i64::from(value.lo)
+}
+
+/// The sync request state of a window.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+struct SyncState {
+    /// The value of the last sync request.
+    serial: i64,
+    /// Whether frames are held until the counter reaches serial.
+    pending: bool,
+    /// Whether the app didn't update its counter in time.
+    unresponsive: bool,
+}
+
+impl SyncState {
+    fn new(counter_value: i64) -> Self {
+        Self {
+            serial: counter_value,
+            ..Self::default()
+        }
+    }
+
+    /// The value of a new sync request, None if the app is unresponsive.
+    fn request(&mut self) -> Option<i64> {
+        if self.unresponsive {
+            return None;
+        }
+        self.serial += 1;
+        self.pending = true;
+        Some(self.serial)
+    }
+
+    /// The app set its counter to `value`. Returns whether held frames should
+    /// be shown.
+    fn counter_reached(&mut self, value: i64) -> bool {
+        // An app which was late to update its counter still supports the
+        // protocol.
+        self.unresponsive = false;
+        let release = self.pending && value >= self.serial;
+        if release {
+            self.pending = false;
+        }
+        release
+    }
+
+    /// The request `serial` timed out. Returns whether held frames should be
+    /// shown.
+    fn timed_out(&mut self, serial: i64) -> bool {
+        if !self.pending || self.serial != serial {
+            return false;
+        }
+        self.pending = false;
+        self.unresponsive = true;
+        true
+    }
+}
+
+#[derive(Debug)]
+struct SyncConnection {
+    conn: RustConnection,
+    wm_protocols: Atom,
+    sync_request: Atom,
+    sync_request_counter: Atom,
+}
+
+/// An X11 connection on which sync requests are sent and counter alarms are
+/// received.
+#[derive(Debug)]
+pub(crate) struct SyncWatcher(Arc<SyncConnection>);
+
+impl SyncWatcher {
+    pub(crate) fn start(
+        display_number: u32,
+        event_loop_handle: &LoopHandle<'static, WprsState>,
+    ) -> Result<Self> {
+        let (conn, _) = x11rb::connect(Some(&format!(":{display_number}"))).location(loc!())?;
+        conn.sync_initialize(3, 1)
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        let intern = |name: &[u8]| -> Result<Atom> {
+            Ok(conn
+                .intern_atom(false, name)
+                .location(loc!())?
+                .reply()
+                .location(loc!())?
+                .atom)
+        };
+        let wm_protocols = intern(b"WM_PROTOCOLS").location(loc!())?;
+        let sync_request = intern(b"_NET_WM_SYNC_REQUEST").location(loc!())?;
+        let sync_request_counter = intern(b"_NET_WM_SYNC_REQUEST_COUNTER").location(loc!())?;
+        let fd = conn
+            .stream()
+            .as_fd()
+            .try_clone_to_owned()
+            .location(loc!())?;
+        event_loop_handle
+            .insert_source(
+                Generic::new(fd, Interest::READ, Mode::Level),
+                |_, _, state| {
+                    state.handle_sync_events();
+                    Ok(PostAction::Continue)
+                },
+            )
+            .map_err(|e| anyhow!("failed to insert sync watcher: {e}"))
+            .location(loc!())?;
+        Ok(Self(Arc::new(SyncConnection {
+            conn,
+            wm_protocols,
+            sync_request,
+            sync_request_counter,
+        })))
+    }
+
+    /// The sync counter of `window`, if it supports sync requests.
+    fn counter(&self, window: u32) -> Result<Option<Counter>> {
+        let sync = &self.0;
+        let protocols = sync
+            .conn
+            .get_property(false, window, sync.wm_protocols, AtomEnum::ATOM, 0, 32)
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        if !protocols
+            .value32()
+            .is_some_and(|mut atoms| atoms.any(|atom| atom == sync.sync_request))
+        {
+            return Ok(None);
+        }
+        // Apps supporting extended sync set a second counter, which we don't
+        // use.
+        let counter = sync
+            .conn
+            .get_property(
+                false,
+                window,
+                sync.sync_request_counter,
+                AtomEnum::CARDINAL,
+                0,
+                1,
+            )
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        Ok(counter.value32().and_then(|mut values| values.next()))
+    }
+
+    /// The alarms triggered since the last call, with their counter's value.
+    fn poll_alarms(&self) -> Result<Vec<(Alarm, i64)>> {
+        let mut alarms = Vec::new();
+        while let Some(event) = self.0.conn.poll_for_event().location(loc!())? {
+            match event {
+                Event::SyncAlarmNotify(event) => {
+                    alarms.push((event.alarm, from_int64(event.counter_value)));
+                },
+                // Errors for windows which were destroyed in the meantime.
+                Event::Error(error) => debug!("sync watcher error: {error:?}"),
+                _ => {},
+            }
+        }
+        Ok(alarms)
+    }
+}
+
+/// The sync state of a window supporting sync requests, kept in the user data
+/// of its X11Surface.
+#[derive(Debug)]
+struct WindowSync {
+    sync: Arc<SyncConnection>,
+    counter: Counter,
+    alarm: Option<Alarm>,
+    state: SyncState,
+}
+
+impl WindowSync {
+    /// Sends a sync request to `window` and arms the alarm for its counter
+    /// reaching `serial`. Does a round trip, so that the request reaches the
+    /// app before anything sent on other connections afterwards.
+    fn send_request(&mut self, window: u32, serial: i64) -> Result<()> {
+        let sync = &self.sync;
+        let value = to_int64(serial);
+        let event = ClientMessageEvent::new(
+            32,
+            window,
+            sync.wm_protocols,
+            [
+                sync.sync_request,
+                x11rb::CURRENT_TIME,
+                value.lo,
+                value.hi as u32,
+                0,
+            ],
+        );
+        sync.conn
+            .send_event(false, window, EventMask::NO_EVENT, event)
+            .location(loc!())?;
+
+        match self.alarm {
+            Some(alarm) => {
+                sync.conn
+                    .sync_change_alarm(alarm, &ChangeAlarmAux::new().value(value))
+                    .location(loc!())?;
+            },
+            None => {
+                let alarm = sync.conn.generate_id().location(loc!())?;
+                sync.conn
+                    .sync_create_alarm(
+                        alarm,
+                        &CreateAlarmAux::new()
+                            .counter(self.counter)
+                            .value_type(VALUETYPE::ABSOLUTE)
+                            .value(value)
+                            .test_type(TESTTYPE::POSITIVE_COMPARISON)
+                            .delta(to_int64(0))
+                            .events(1),
+                    )
+                    .location(loc!())?;
+                self.alarm = Some(alarm);
+            },
+        }
+        sync.conn.sync().location(loc!())?;
+        Ok(())
+    }
+}
+
+impl Drop for WindowSync {
+    fn drop(&mut self) {
+        if let Some(alarm) = self.alarm {
+            _ = self.sync.conn.sync_destroy_alarm(alarm);
+            _ = self.sync.conn.flush();
+        }
+    }
+}
+
+fn with_window_sync<T>(
+    x11_surface: &X11Surface,
+    f: impl FnOnce(&mut WindowSync) -> T,
+) -> Option<T> {
+    x11_surface
+        .user_data()
+        .get::<Mutex<WindowSync>>()
+        .map(|window_sync| f(&mut window_sync.lock().unwrap()))
+}
+
+/// Sends a sync request to `x11_surface` if it supports them and is about to
+/// be resized to `size`. Call this right before configuring it.
+pub(crate) fn request_sync(x11_surface: &X11Surface, size: Size<i32, Logical>) {
+    if x11_surface.geometry().size == size {
+        return;
+    }
+    with_window_sync(x11_surface, |window_sync| {
+        let Some(serial) = window_sync.state.request() else {
+            return;
+        };
+        if window_sync
+            .send_request(x11_surface.window_id(), serial)
+            .warn(loc!())
+            .is_err()
+        {
+            window_sync.state.pending = false;
+        }
+    });
+}
+
+/// The value of the sync request `x11_surface`'s frames are held for, if any.
+pub(crate) fn pending_sync(x11_surface: &X11Surface) -> Option<i64> {
+    with_window_sync(x11_surface, |window_sync| {
+        window_sync
+            .state
+            .pending
+            .then_some(window_sync.state.serial)
+    })
+    .flatten()
+}
+
+impl WprsState {
+    /// Starts sending sync requests to `window` if it supports them.
+    pub(crate) fn watch_window_sync(&self, window: &X11Surface) {
+        let Some(watcher) = &self.compositor_state.sync_watcher else {
+            return;
+        };
+        if window.user_data().get::<Mutex<WindowSync>>().is_some() {
+            return;
+        }
+        let Ok(Some(counter)) = watcher.counter(window.window_id()).warn(loc!()) else {
+            return;
+        };
+        let Ok(counter_value) = watcher
+            .0
+            .conn
+            .sync_query_counter(counter)
+            .location(loc!())
+            .and_then(|cookie| cookie.reply().location(loc!()))
+            .warn(loc!())
+        else {
+            return;
+        };
+        debug!("window {} supports sync requests", window.window_id());
+        window.user_data().insert_if_missing_threadsafe(|| {
+            Mutex::new(WindowSync {
+                sync: watcher.0.clone(),
+                counter,
+                alarm: None,
+                state: SyncState::new(from_int64(counter_value.counter_value)),
+            })
+        });
+    }
+
+    fn handle_sync_events(&mut self) {
+        let Some(watcher) = &self.compositor_state.sync_watcher else {
+            return;
+        };
+        let Ok(alarms) = watcher.poll_alarms().warn(loc!()) else {
+            return;
+        };
+        let qh = self.client_state.qh.clone();
+        for (alarm, counter_value) in alarms {
+            for xwayland_surface in self.surfaces.values_mut() {
+                let Some(x11_surface) = &xwayland_surface.x11_surface else {
+                    continue;
+                };
+                let Some(release) = with_window_sync(x11_surface, |window_sync| {
+                    (window_sync.alarm == Some(alarm))
+                        .then(|| window_sync.state.counter_reached(counter_value))
+                })
+                .flatten() else {
+                    continue;
+                };
+                if release && xwayland_surface.ready() {
+                    debug!(
+                        "window {} finished drawing at its new size",
+                        x11_surface.window_id()
+                    );
+                    xwayland_surface.commit_buffer(&qh);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Shows the held frame of `surface_id` if the app hasn't finished drawing for
+/// sync request `serial` by the timeout.
+pub(crate) fn arm_timeout(
+    event_loop_handle: &LoopHandle<'static, WprsState>,
+    behavior: SyncRequestBehavior,
+    surface_id: ObjectId,
+    serial: i64,
+) {
+    let SyncRequestBehavior::Enabled { timeout_ms } = behavior else {
+        return;
+    };
+    event_loop_handle
+        .insert_source(
+                Timer::from_duration(Duration::from_millis(timeout_ms.into())),
+                move |_, _, state| {
+                    let qh = state.client_state.qh.clone();
+                    if let Some(xwayland_surface) = state.surfaces.get_mut(&surface_id)
+                        && let Some(x11_surface) = &xwayland_surface.x11_surface
+                        && with_window_sync(x11_surface, |window_sync| {
+                            window_sync.state.timed_out(serial)
+                        })
+                        .unwrap_or(false)
+                    {
+                        warn!(
+                            "window {} didn't update its sync counter in time, not syncing it until it does",
+                            x11_surface.window_id()
+                        );
+                        if xwayland_surface.ready() {
+                            xwayland_surface.commit_buffer(&qh);
+                        }
+                    }
+                    TimeoutAction::Drop
+                },
+            )
+            .map_err(|e| anyhow!("failed to insert sync timer: {e}"))
+            .log_and_ignore(loc!());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn int64_round_trips() {
+        for value in [0, 1, -1, i64::from(u32::MAX) + 1, i64::MAX, i64::MIN] {
+            assert_eq!(from_int64(to_int64(value)), value);
+        }
+    }
+
+    #[test]
+    fn frames_are_held_until_counter_is_reached() {
+        let mut state = SyncState::new(10);
+        let serial = state.request().unwrap();
+        assert_eq!(serial, 11);
+        assert!(state.pending);
+        // The alarm only triggers once the counter reaches the serial, but a
+        // stale value mustn't release the frame either.
+        assert!(!state.counter_reached(10));
+        assert!(state.pending);
+        assert!(state.counter_reached(11));
+        assert!(!state.pending);
+        // Nor does the timeout of a completed request do anything.
+        assert!(!state.timed_out(serial));
+        assert!(!state.unresponsive);
+    }
+
+    #[test]
+    fn only_the_latest_request_is_waited_for() {
+        let mut state = SyncState::default();
+        let first = state.request().unwrap();
+        let second = state.request().unwrap();
+        assert!(!state.timed_out(first));
+        assert!(!state.counter_reached(first));
+        assert!(state.counter_reached(second));
+    }
+
+    #[test]
+    fn unresponsive_apps_stop_being_synced() {
+        let mut state = SyncState::default();
+        let serial = state.request().unwrap();
+        assert!(state.timed_out(serial));
+        assert!(!state.pending);
+        assert_eq!(state.request(), None);
+        assert!(!state.pending);
+
+        // Until they update their counter after all.
+        assert!(!state.counter_reached(serial));
+        assert_eq!(state.request(), Some(serial + 1));
+    }
+}
@@ -0,0 +1,876 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Graphics tablet input. Each local seat gets a tablet seat, and the tablets
+/// and tools it announces are mirrored on the seat offered to Xwayland, so that
+/// X11 apps get the pressure, tilt and buttons of the tool. Tool events come in
+/// frames, which are replayed on the mirrored tool when they end. Tablet pads
+/// aren't forwarded.
+///
+/// A tool which leaves a surface doesn't send the releases of the buttons
+/// which were held on it, or the up of its tip, so the buttons and tip still
+/// held on a surface are released when the tool leaves proximity of it.
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+
+use smithay::backend::input::ButtonState;
+use smithay::backend::input::TabletToolCapabilities;
+use smithay::backend::input::TabletToolDescriptor;
+use smithay::backend::input::TabletToolType;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::SERIAL_COUNTER;
+use smithay::utils::Serial;
+use smithay::wayland::tablet_manager::TabletDescriptor;
+use smithay::wayland::tablet_manager::TabletSeatHandle;
+use smithay::wayland::tablet_manager::TabletSeatHandler;
+use smithay::wayland::tablet_manager::TabletSeatTrait;
+use smithay::wayland::tablet_manager::TabletToolHandle;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::WEnum;
+use smithay_client_toolkit::reexports::client::backend::ObjectId as ClientObjectId;
+use smithay_client_toolkit::reexports::client::event_created_child;
+use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface as ClientWlSurface;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_manager_v2::ZwpTabletManagerV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_group_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_group_v2::ZwpTabletPadGroupV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_ring_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_ring_v2::ZwpTabletPadRingV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_strip_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_strip_v2::ZwpTabletPadStripV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_pad_v2::ZwpTabletPadV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_seat_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_seat_v2::ZwpTabletSeatV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_tool_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_tool_v2::ZwpTabletToolV2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_v2;
+use smithay_client_toolkit::reexports::protocols::wp::tablet::zv2::client::zwp_tablet_v2::ZwpTabletV2;
+use smithay_client_toolkit::registry::SimpleGlobal;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::xsurface_from_client_surface;
+
+/// Pressure, distance and slider positions are sent in 65535ths.
+const AXIS_MAX: f64 = 65535.0;
+
+/// The tip and buttons held on a tool.
+#[derive(Debug, Default)]
+struct HeldButtons {
+    tip: bool,
+    buttons: BTreeSet<u32>,
+}
+
+impl HeldButtons {
+    fn update(&mut self, button: u32, state: ButtonState) {
+        match state {
+            ButtonState::Pressed => self.buttons.insert(button),
+            ButtonState::Released => self.buttons.remove(&button),
+        };
+    }
+
+    /// The events releasing the held tip and buttons, which are forgotten.
+    fn release_all(&mut self) -> Vec<ToolEvent> {
+        let tip = mem::take(&mut self.tip).then_some(ToolEvent::TipUp);
+        tip.into_iter()
+            .chain(
+                mem::take(&mut self.buttons)
+                    .into_iter()
+                    .map(ToolEvent::Release),
+            )
+            .collect()
+    }
+}
+
+/// The events of a tool frame.
+#[derive(Debug, Default)]
+struct ToolFrame {
+    proximity_in: Option<(u32, ZwpTabletV2, ClientWlSurface)>,
+    proximity_out: bool,
+    down: Option<u32>,
+    up: bool,
+    motion: Option<(f64, f64)>,
+    pressure: Option<f64>,
+    distance: Option<f64>,
+    tilt: Option<(f64, f64)>,
+    rotation: Option<f64>,
+    slider: Option<f64>,
+    wheel: Option<(f64, i32)>,
+    buttons: Vec<(u32, u32, ButtonState)>,
+}
+
+/// An event replayed on a mirrored tool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ToolEvent {
+    /// Moves the tool to its position, into proximity of its focus.
+    Motion,
+    Pressure(f64),
+    Distance(f64),
+    Tilt((f64, f64)),
+    Rotation(f64),
+    Slider(f64),
+    Wheel(f64, i32),
+    TipDown(Serial),
+    TipUp,
+    Button(u32, ButtonState, Serial),
+    /// The release of a button which was held when the tool left its focus.
+    Release(u32),
+    ProximityOut,
+}
+
+impl ToolFrame {
+    /// The events replaying the frame, with `down_serial` and `button_serials`
+    /// the serials of its tip down and buttons. `leaves_focus` is whether the
+    /// tool moves away from the surface it was in proximity of, and `moves`
+    /// whether it moves at all. The axes only apply to the surface the tool is
+    /// in proximity of, so they follow the motion, which may bring it into
+    /// proximity of a new one.
+    fn events(
+        &self,
+        down_serial: Option<Serial>,
+        button_serials: &[Serial],
+        leaves_focus: bool,
+        moves: bool,
+        held_buttons: &mut HeldButtons,
+    ) -> Vec<ToolEvent> {
+        let mut events = Vec::new();
+        if leaves_focus {
+            events.extend(held_buttons.release_all());
+        }
+        if moves {
+            events.push(ToolEvent::Motion);
+        }
+        events.extend(
+            [
+                self.pressure.map(ToolEvent::Pressure),
+                self.distance.map(ToolEvent::Distance),
+                self.tilt.map(ToolEvent::Tilt),
+                self.rotation.map(ToolEvent::Rotation),
+                self.slider.map(ToolEvent::Slider),
+                self.wheel
+                    .map(|(degrees, clicks)| ToolEvent::Wheel(degrees, clicks)),
+            ]
+            .into_iter()
+            .flatten(),
+        );
+
+        if let Some(down_serial) = down_serial {
+            events.push(ToolEvent::TipDown(down_serial));
+            held_buttons.tip = true;
+        }
+        if self.up {
+            events.push(ToolEvent::TipUp);
+            held_buttons.tip = false;
+        }
+        for ((_, button, state), serial) in self.buttons.iter().zip(button_serials) {
+            events.push(ToolEvent::Button(*button, *state, *serial));
+            held_buttons.update(*button, *state);
+        }
+
+        if self.proximity_out {
+            events.extend(held_buttons.release_all());
+            events.push(ToolEvent::ProximityOut);
+        }
+        events
+    }
+}
+
+#[derive(Debug)]
+struct LocalTablet {
+    seat: WlSeat,
+    tablet: ZwpTabletV2,
+    desc: TabletDescriptor,
+}
+
+#[derive(Debug)]
+struct LocalTool {
+    seat: WlSeat,
+    tool: ZwpTabletToolV2,
+    desc: TabletToolDescriptor,
+    /// Set once the local compositor has described the tool.
+    handle: Option<TabletToolHandle>,
+    frame: ToolFrame,
+    /// The local tablet and surface the tool is in proximity of.
+    proximity: Option<(ClientObjectId, ClientWlSurface)>,
+    position: (f64, f64),
+    /// The X11 surface the mirrored tool is in proximity of.
+    focus: Option<WlSurface>,
+    held_buttons: HeldButtons,
+}
+
+impl LocalTool {
+    fn new(seat: WlSeat, tool: ZwpTabletToolV2) -> Self {
+        Self {
+            seat,
+            tool,
+            desc: TabletToolDescriptor {
+                tool_type: TabletToolType::Unknown,
+                hardware_serial: 0,
+                hardware_id_wacom: 0,
+                capabilities: TabletToolCapabilities::empty(),
+            },
+            handle: None,
+            frame: ToolFrame::default(),
+            proximity: None,
+            position: (0.0, 0.0),
+            focus: None,
+            held_buttons: HeldButtons::default(),
+        }
+    }
+
+    /// Lifts the tip and releases the buttons held on the surface the
+    /// mirrored tool is in proximity of, before it leaves it.
+    fn release_held_buttons(&mut self, time: u32) {
+        let Some(handle) = &self.handle else {
+            return;
+        };
+        for event in self.held_buttons.release_all() {
+            replay_release(handle, event, time);
+        }
+    }
+}
+
+/// Replays the release `event` of a held tip or button on `handle`.
+fn replay_release(handle: &TabletToolHandle, event: ToolEvent, time: u32) {
+    match event {
+        ToolEvent::TipUp => handle.tip_up(time),
+        ToolEvent::Release(button) => handle.button(
+            button,
+            ButtonState::Released,
+            SERIAL_COUNTER.next_serial(),
+            time,
+        ),
+        _ => unreachable!("{event:?} isn't a release"),
+    }
+}
+
+/// The tablet seats, tablets and tools of the local seats.
+#[derive(Debug, Default)]
+pub(crate) struct LocalTablets {
+    seats: HashMap<ClientObjectId, ZwpTabletSeatV2>,
+    tablets: HashMap<ClientObjectId, LocalTablet>,
+    tools: HashMap<ClientObjectId, LocalTool>,
+}
+
+fn tablet_tool_type(tool_type: WEnum<zwp_tablet_tool_v2::Type>) -> TabletToolType {
+    match tool_type {
+        WEnum::Value(zwp_tablet_tool_v2::Type::Pen) => TabletToolType::Pen,
+        WEnum::Value(zwp_tablet_tool_v2::Type::Eraser) => TabletToolType::Eraser,
+        WEnum::Value(zwp_tablet_tool_v2::Type::Brush) => TabletToolType::Brush,
+        WEnum::Value(zwp_tablet_tool_v2::Type::Pencil) => TabletToolType::Pencil,
+        WEnum::Value(zwp_tablet_tool_v2::Type::Airbrush) => TabletToolType::Airbrush,
+        WEnum::Value(zwp_tablet_tool_v2::Type::Mouse) => TabletToolType::Mouse,
+        WEnum::Value(zwp_tablet_tool_v2::Type::Lens) => TabletToolType::Lens,
+        _ => TabletToolType::Unknown,
+    }
+}
+
+fn tool_capability(capability: WEnum<zwp_tablet_tool_v2::Capability>) -> TabletToolCapabilities {
+    match capability {
+        WEnum::Value(zwp_tablet_tool_v2::Capability::Tilt) => TabletToolCapabilities::TILT,
+        WEnum::Value(zwp_tablet_tool_v2::Capability::Pressure) => TabletToolCapabilities::PRESSURE,
+        WEnum::Value(zwp_tablet_tool_v2::Capability::Distance) => TabletToolCapabilities::DISTANCE,
+        WEnum::Value(zwp_tablet_tool_v2::Capability::Rotation) => TabletToolCapabilities::ROTATION,
+        WEnum::Value(zwp_tablet_tool_v2::Capability::Slider) => TabletToolCapabilities::SLIDER,
+        WEnum::Value(zwp_tablet_tool_v2::Capability::Wheel) => TabletToolCapabilities::WHEEL,
+        _ => TabletToolCapabilities::empty(),
+    }
+}
+
+fn tool_button_state(state: WEnum<zwp_tablet_tool_v2::ButtonState>) -> ButtonState {
+    match state {
+        WEnum::Value(zwp_tablet_tool_v2::ButtonState::Pressed) => ButtonState::Pressed,
+        _ => ButtonState::Released,
+    }
+}
+
+impl WprsState {
+    /// Creates the tablet seat of the local `seat` if the local compositor
+    /// supports tablets.
+    pub(crate) fn add_tablet_seat(&mut self, seat: &WlSeat, qh: &QueueHandle<Self>) {
+        let Some(tablet_manager) = self
+            .client_state
+            .tablet_manager
+            .as_ref()
+            .and_then(|global| global.get().ok())
+        else {
+            return;
+        };
+        self.client_state
+            .tablets
+            .seats
+            .entry(seat.id())
+            .or_insert_with(|| tablet_manager.get_tablet_seat(seat, qh, seat.clone()));
+    }
+
+    /// Destroys the tablet seat of the local `seat` and removes its tablets
+    /// and tools from the mirrored seat.
+    pub(crate) fn remove_tablet_seat(&mut self, seat: &WlSeat) {
+        let tablets = &mut self.client_state.tablets;
+        if let Some(tablet_seat) = tablets.seats.remove(&seat.id()) {
+            tablet_seat.destroy();
+        }
+        let tablet_ids: Vec<_> = tablets
+            .tablets
+            .iter()
+            .filter(|(_, tablet)| &tablet.seat == seat)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let tool_ids: Vec<_> = tablets
+            .tools
+            .iter()
+            .filter(|(_, tool)| &tool.seat == seat)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in tool_ids {
+            self.remove_tablet_tool(&id).log_and_ignore(loc!());
+        }
+        for id in tablet_ids {
+            self.remove_tablet(&id).log_and_ignore(loc!());
+        }
+    }
+
+    fn mirrored_tablet_seat(&self, seat: &WlSeat) -> Result<TabletSeatHandle> {
+        let seat_name = self.compositor_seat_name(seat).location(loc!())?;
+        Ok(self
+            .compositor_state
+            .seat(&seat_name)
+            .location(loc!())?
+            .seat
+            .tablet_seat())
+    }
+
+    fn add_tablet(&mut self, id: &ClientObjectId) -> Result<()> {
+        let tablet = self.client_state.tablets.tablets.get(id).location(loc!())?;
+        debug!("adding tablet {:?}", tablet.desc);
+        self.mirrored_tablet_seat(&tablet.seat)
+            .location(loc!())?
+            .add_tablet::<Self>(&self.compositor_state.dh, &tablet.desc);
+        Ok(())
+    }
+
+    fn remove_tablet(&mut self, id: &ClientObjectId) -> Result<()> {
+        let tablet = self
+            .client_state
+            .tablets
+            .tablets
+            .remove(id)
+            .location(loc!())?;
+        debug!("removing tablet {:?}", tablet.desc);
+        tablet.tablet.destroy();
+        self.mirrored_tablet_seat(&tablet.seat)
+            .location(loc!())?
+            .remove_tablet(&tablet.desc);
+        Ok(())
+    }
+
+    fn add_tablet_tool(&mut self, id: &ClientObjectId) -> Result<()> {
+        let tool = self.client_state.tablets.tools.get(id).location(loc!())?;
+        let desc = tool.desc.clone();
+        debug!("adding tablet tool {desc:?}");
+        let tablet_seat = self.mirrored_tablet_seat(&tool.seat).location(loc!())?;
+        let dh = self.compositor_state.dh.clone();
+        let handle = tablet_seat.add_tool::<Self>(self, &dh, &desc);
+        self.client_state
+            .tablets
+            .tools
+            .get_mut(id)
+            .location(loc!())?
+            .handle = Some(handle);
+        Ok(())
+    }
+
+    fn remove_tablet_tool(&mut self, id: &ClientObjectId) -> Result<()> {
+        let mut tool = self
+            .client_state
+            .tablets
+            .tools
+            .remove(id)
+            .location(loc!())?;
+        debug!("removing tablet tool {:?}", tool.desc);
+        tool.tool.destroy();
+        if tool.focus.is_some() {
+            tool.release_held_buttons(0);
+            if let Some(handle) = &tool.handle {
+                handle.proximity_out(0);
+            }
+        }
+        self.mirrored_tablet_seat(&tool.seat)
+            .location(loc!())?
+            .remove_tool(&tool.desc);
+        Ok(())
+    }
+
+    /// Replays the frame of the tool `id` which just ended on the mirrored
+    /// tool.
+    fn apply_tool_frame(&mut self, id: &ClientObjectId, time: u32) -> Result<()> {
+        let tool = self
+            .client_state
+            .tablets
+            .tools
+            .get_mut(id)
+            .location(loc!())?;
+        let frame = mem::take(&mut tool.frame);
+        let Some(handle) = tool.handle.clone() else {
+            return Ok(());
+        };
+        if let Some((_, tablet, surface)) = &frame.proximity_in {
+            tool.proximity = Some((tablet.id(), surface.clone()));
+        }
+        if let Some(position) = frame.motion {
+            tool.position = position;
+        }
+        let seat = tool.seat.clone();
+        let proximity = tool.proximity.clone();
+        let position = tool.position;
+
+        let seat_name = self.compositor_seat_name(&seat).location(loc!())?;
+        let tablet_seat = self.mirrored_tablet_seat(&seat).location(loc!())?;
+        let tablet = proximity
+            .as_ref()
+            .and_then(|(tablet_id, _)| self.client_state.tablets.tablets.get(tablet_id))
+            .and_then(|tablet| tablet_seat.get_tablet(&tablet.desc));
+        // Tools over our decorations aren't in proximity of an X11 surface.
+        let (focus, scale) = proximity
+            .as_ref()
+            .and_then(|(_, surface)| {
+                xsurface_from_client_surface(&self.surface_bimap, &mut self.surfaces, surface)
+            })
+            .and_then(|xwayland_surface| {
+                let focus = xwayland_surface.x11_surface.as_ref()?.wl_surface()?;
+                Some((Some(focus), f64::from(xwayland_surface.scale())))
+            })
+            .unwrap_or((None, 1.0));
+        let mut serial = |serial: u32| -> Result<Serial> {
+            Ok(self
+                .compositor_state
+                .seat_mut(&seat_name)
+                .location(loc!())?
+                .serial_map
+                .insert(serial))
+        };
+        let proximity_in_serial = match &frame.proximity_in {
+            Some((proximity_serial, _, _)) => serial(*proximity_serial).location(loc!())?,
+            None => SERIAL_COUNTER.next_serial(),
+        };
+        let down_serial = frame.down.map(&mut serial).transpose().location(loc!())?;
+        let button_serials = frame
+            .buttons
+            .iter()
+            .map(|(button_serial, _, _)| serial(*button_serial))
+            .collect::<Result<Vec<_>>>()
+            .location(loc!())?;
+
+        let tool = self
+            .client_state
+            .tablets
+            .tools
+            .get_mut(id)
+            .location(loc!())?;
+        let leaves_focus = tool.focus.is_some() && tool.focus != focus;
+        let moves = tablet.is_some() && (frame.proximity_in.is_some() || frame.motion.is_some());
+        let events = frame.events(
+            down_serial,
+            &button_serials,
+            leaves_focus,
+            moves,
+            &mut tool.held_buttons,
+        );
+        for event in events {
+            match event {
+                ToolEvent::Motion => {
+                    if let Some(tablet) = &tablet {
+                        handle.motion(
+                            (position.0 / scale, position.1 / scale).into(),
+                            focus.clone().map(|focus| (focus, (0.0, 0.0).into())),
+                            tablet,
+                            proximity_in_serial,
+                            time,
+                        );
+                        tool.focus.clone_from(&focus);
+                    }
+                },
+                ToolEvent::Pressure(pressure) => handle.pressure(pressure),
+                ToolEvent::Distance(distance) => handle.distance(distance),
+                ToolEvent::Tilt(tilt) => handle.tilt(tilt),
+                ToolEvent::Rotation(rotation) => handle.rotation(rotation),
+                ToolEvent::Slider(slider) => handle.slider_position(slider),
+                ToolEvent::Wheel(degrees, clicks) => handle.wheel(degrees, clicks),
+                ToolEvent::TipDown(serial) => handle.tip_down(serial, time),
+                ToolEvent::Button(button, state, serial) => {
+                    handle.button(button, state, serial, time);
+                },
+                ToolEvent::TipUp | ToolEvent::Release(_) => replay_release(&handle, event, time),
+                ToolEvent::ProximityOut => {
+                    handle.proximity_out(time);
+                    tool.proximity = None;
+                    tool.focus = None;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TabletSeatHandler for WprsState {}
+
+impl Dispatch<ZwpTabletSeatV2, WlSeat> for WprsState {
+    fn event(
+        state: &mut Self,
+        _tablet_seat: &ZwpTabletSeatV2,
+        event: zwp_tablet_seat_v2::Event,
+        seat: &WlSeat,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let tablets = &mut state.client_state.tablets;
+        match event {
+            zwp_tablet_seat_v2::Event::TabletAdded { id } => {
+                tablets.tablets.insert(
+                    id.id(),
+                    LocalTablet {
+                        seat: seat.clone(),
+                        tablet: id,
+                        desc: TabletDescriptor {
+                            name: String::new(),
+                            usb_id: None,
+                            syspath: None,
+                        },
+                    },
+                );
+            },
+            zwp_tablet_seat_v2::Event::ToolAdded { id } => {
+                tablets
+                    .tools
+                    .insert(id.id(), LocalTool::new(seat.clone(), id));
+            },
+            // Pads are destroyed when they're removed.
+            _ => {},
+        }
+    }
+
+    event_created_child!(WprsState, ZwpTabletSeatV2, [
+        zwp_tablet_seat_v2::EVT_TABLET_ADDED_OPCODE => (ZwpTabletV2, ()),
+        zwp_tablet_seat_v2::EVT_TOOL_ADDED_OPCODE => (ZwpTabletToolV2, ()),
+        zwp_tablet_seat_v2::EVT_PAD_ADDED_OPCODE => (ZwpTabletPadV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletV2, ()> for WprsState {
+    fn event(
+        state: &mut Self,
+        tablet: &ZwpTabletV2,
+        event: zwp_tablet_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let Some(local_tablet) = state.client_state.tablets.tablets.get_mut(&tablet.id()) else {
+            return;
+        };
+        match event {
+            zwp_tablet_v2::Event::Name { name } => local_tablet.desc.name = name,
+            zwp_tablet_v2::Event::Id { vid, pid } => local_tablet.desc.usb_id = Some((vid, pid)),
+            zwp_tablet_v2::Event::Path { path } => {
+                // Tablets may have several paths, e.g. for their pen and
+                // touch devices.
+                local_tablet
+                    .desc
+                    .syspath
+                    .get_or_insert_with(|| PathBuf::from(path));
+            },
+            zwp_tablet_v2::Event::Done => state.add_tablet(&tablet.id()).log_and_ignore(loc!()),
+            zwp_tablet_v2::Event::Removed => {
+                state.remove_tablet(&tablet.id()).log_and_ignore(loc!())
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Dispatch<ZwpTabletToolV2, ()> for WprsState {
+    fn event(
+        state: &mut Self,
+        tool: &ZwpTabletToolV2,
+        event: zwp_tablet_tool_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let Some(local_tool) = state.client_state.tablets.tools.get_mut(&tool.id()) else {
+            return;
+        };
+        let frame = &mut local_tool.frame;
+        match event {
+            zwp_tablet_tool_v2::Event::Type { tool_type } => {
+                local_tool.desc.tool_type = tablet_tool_type(tool_type);
+            },
+            zwp_tablet_tool_v2::Event::HardwareSerial {
+                hardware_serial_hi,
+                hardware_serial_lo,
+            } => {
+                local_tool.desc.hardware_serial =
+                    (u64::from(hardware_serial_hi) << 32) | u64::from(hardware_serial_lo);
+            },
+            zwp_tablet_tool_v2::Event::HardwareIdWacom {
+                hardware_id_hi,
+                hardware_id_lo,
+            } => {
+                local_tool.desc.hardware_id_wacom =
+                    (u64::from(hardware_id_hi) << 32) | u64::from(hardware_id_lo);
+            },
+            zwp_tablet_tool_v2::Event::Capability { capability } => {
+                local_tool.desc.capabilities |= tool_capability(capability);
+            },
+            zwp_tablet_tool_v2::Event::Done => {
+                state.add_tablet_tool(&tool.id()).log_and_ignore(loc!());
+            },
+            zwp_tablet_tool_v2::Event::Removed => {
+                state.remove_tablet_tool(&tool.id()).log_and_ignore(loc!());
+            },
+            zwp_tablet_tool_v2::Event::ProximityIn {
+                serial,
+                tablet,
+                surface,
+            } => {
+                frame.proximity_in = Some((serial, tablet, surface));
+            },
+            zwp_tablet_tool_v2::Event::ProximityOut => frame.proximity_out = true,
+            zwp_tablet_tool_v2::Event::Down { serial } => frame.down = Some(serial),
+            zwp_tablet_tool_v2::Event::Up => frame.up = true,
+            zwp_tablet_tool_v2::Event::Motion { x, y } => frame.motion = Some((x, y)),
+            zwp_tablet_tool_v2::Event::Pressure { pressure } => {
+                frame.pressure = Some(f64::from(pressure) / AXIS_MAX);
+            },
+            zwp_tablet_tool_v2::Event::Distance { distance } => {
+                frame.distance = Some(f64::from(distance) / AXIS_MAX);
+            },
+            zwp_tablet_tool_v2::Event::Tilt { tilt_x, tilt_y } => {
+                frame.tilt = Some((tilt_x, tilt_y));
+            },
+            zwp_tablet_tool_v2::Event::Rotation { degrees } => frame.rotation = Some(degrees),
+            zwp_tablet_tool_v2::Event::Slider { position } => {
+                frame.slider = Some(f64::from(position) / AXIS_MAX);
+            },
+            zwp_tablet_tool_v2::Event::Wheel { degrees, clicks } => {
+                frame.wheel = Some((degrees, clicks));
+            },
+            zwp_tablet_tool_v2::Event::Button {
+                serial,
+                button,
+                state: button_state,
+            } => {
+                frame
+                    .buttons
+                    .push((serial, button, tool_button_state(button_state)));
+            },
+            zwp_tablet_tool_v2::Event::Frame { time } => {
+                state.reset_idle();
+                state
+                    .apply_tool_frame(&tool.id(), time)
+                    .log_and_ignore(loc!());
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Dispatch<ZwpTabletPadV2, ()> for WprsState {
+    fn event(
+        _state: &mut Self,
+        pad: &ZwpTabletPadV2,
+        event: zwp_tablet_pad_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let zwp_tablet_pad_v2::Event::Removed = event {
+            pad.destroy();
+        }
+    }
+
+    event_created_child!(WprsState, ZwpTabletPadV2, [
+        zwp_tablet_pad_v2::EVT_GROUP_OPCODE => (ZwpTabletPadGroupV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletPadGroupV2, ()> for WprsState {
+    fn event(
+        _state: &mut Self,
+        _group: &ZwpTabletPadGroupV2,
+        _event: zwp_tablet_pad_group_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(WprsState, ZwpTabletPadGroupV2, [
+        zwp_tablet_pad_group_v2::EVT_RING_OPCODE => (ZwpTabletPadRingV2, ()),
+        zwp_tablet_pad_group_v2::EVT_STRIP_OPCODE => (ZwpTabletPadStripV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletPadRingV2, ()> for WprsState {
+    fn event(
+        _state: &mut Self,
+        _ring: &ZwpTabletPadRingV2,
+        _event: zwp_tablet_pad_ring_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpTabletPadStripV2, ()> for WprsState {
+    fn event(
+        _state: &mut Self,
+        _strip: &ZwpTabletPadStripV2,
+        _event: zwp_tablet_pad_strip_v2::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl AsMut<SimpleGlobal<ZwpTabletManagerV2, 1>> for WprsState {
+    fn as_mut(&mut self) -> &mut SimpleGlobal<ZwpTabletManagerV2, 1> {
+        // This should never panic since if tablet_manager is None then we will
+        // never get any events for it.
+        self.client_state.tablet_manager.as_mut().unwrap()
+    }
+}
+
+smithay_client_toolkit::delegate_simple!(WprsState, ZwpTabletManagerV2, 1);
+smithay::delegate_tablet_manager!(WprsState);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BTN_STYLUS: u32 = 0x14b;
+    const BTN_STYLUS2: u32 = 0x14c;
+
+    #[test]
+    fn held_buttons_are_released_on_proximity_out() {
+        let mut held_buttons = HeldButtons::default();
+        held_buttons.update(BTN_STYLUS, ButtonState::Pressed);
+        held_buttons.update(BTN_STYLUS2, ButtonState::Pressed);
+        held_buttons.update(BTN_STYLUS, ButtonState::Released);
+        assert_eq!(
+            held_buttons.release_all(),
+            [ToolEvent::Release(BTN_STYLUS2)]
+        );
+        // They're only released once.
+        assert!(held_buttons.release_all().is_empty());
+    }
+
+    #[test]
+    fn tip_and_buttons_are_released_when_leaving_proximity_with_a_button_held() {
+        let mut held_buttons = HeldButtons::default();
+        let press = ToolFrame {
+            down: Some(1),
+            buttons: vec![(2, BTN_STYLUS, ButtonState::Pressed)],
+            ..ToolFrame::default()
+        };
+        assert_eq!(
+            press.events(
+                Some(Serial::from(1)),
+                &[Serial::from(2)],
+                false,
+                false,
+                &mut held_buttons
+            ),
+            [
+                ToolEvent::TipDown(Serial::from(1)),
+                ToolEvent::Button(BTN_STYLUS, ButtonState::Pressed, Serial::from(2)),
+            ]
+        );
+
+        // The tool is lifted away with the button still held.
+        let proximity_out = ToolFrame {
+            proximity_out: true,
+            ..ToolFrame::default()
+        };
+        assert_eq!(
+            proximity_out.events(None, &[], false, false, &mut held_buttons),
+            [
+                ToolEvent::TipUp,
+                ToolEvent::Release(BTN_STYLUS),
+                ToolEvent::ProximityOut,
+            ]
+        );
+        // Nothing is left held for the next surface.
+        assert!(held_buttons.release_all().is_empty());
+    }
+
+    #[test]
+    fn motion_is_replayed_before_the_axes() {
+        let frame = ToolFrame {
+            motion: Some((10.0, 20.0)),
+            pressure: Some(0.5),
+            tilt: Some((5.0, -5.0)),
+            ..ToolFrame::default()
+        };
+        assert_eq!(
+            frame.events(None, &[], false, true, &mut HeldButtons::default()),
+            [
+                ToolEvent::Motion,
+                ToolEvent::Pressure(0.5),
+                ToolEvent::Tilt((5.0, -5.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn held_tip_is_lifted_when_moving_to_another_surface() {
+        let mut held_buttons = HeldButtons {
+            tip: true,
+            ..HeldButtons::default()
+        };
+        let frame = ToolFrame {
+            motion: Some((10.0, 20.0)),
+            ..ToolFrame::default()
+        };
+        assert_eq!(
+            frame.events(None, &[], true, true, &mut held_buttons),
+            [ToolEvent::TipUp, ToolEvent::Motion]
+        );
+    }
+
+    #[test]
+    fn tool_types_and_capabilities_are_mirrored() {
+        assert_eq!(
+            tablet_tool_type(WEnum::Value(zwp_tablet_tool_v2::Type::Eraser)),
+            TabletToolType::Eraser
+        );
+        assert_eq!(
+            tablet_tool_type(WEnum::Unknown(0x999)),
+            TabletToolType::Unknown
+        );
+        assert_eq!(
+            tool_capability(WEnum::Value(zwp_tablet_tool_v2::Capability::Pressure))
+                | tool_capability(WEnum::Value(zwp_tablet_tool_v2::Capability::Tilt)),
+            TabletToolCapabilities::PRESSURE | TabletToolCapabilities::TILT
+        );
+    }
+}
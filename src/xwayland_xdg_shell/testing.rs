@@ -0,0 +1,773 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Runs WprsState between a fake xwayland, which connects to it like xwayland
+/// does, and a fake local compositor, which it displays windows on. There is no
+/// X server: X11 windows are made up, see FakeWindow, so this can only test
+/// what doesn't need a round trip to X11.
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::FileExt;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::sync::mpsc;
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::memfd::MFdFlags;
+use nix::sys::memfd::memfd_create;
+use smithay::backend::renderer::utils::on_commit_buffer_handler;
+use smithay::input::Seat;
+use smithay::input::SeatHandler;
+use smithay::input::SeatState;
+use smithay::reexports::calloop::EventLoop;
+use smithay::reexports::wayland_protocols::xdg::shell::server::xdg_toplevel::ResizeEdge;
+use smithay::reexports::wayland_server::Client;
+use smithay::reexports::wayland_server::Display;
+use smithay::reexports::wayland_server::DisplayHandle;
+use smithay::reexports::wayland_server::Resource;
+use smithay::reexports::wayland_server::backend::ClientData;
+use smithay::reexports::wayland_server::protocol::wl_buffer::WlBuffer;
+use smithay::reexports::wayland_server::protocol::wl_seat::WlSeat;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::Rectangle;
+use smithay::utils::SERIAL_COUNTER;
+use smithay::utils::Serial;
+use smithay::wayland::buffer::BufferHandler;
+use smithay::wayland::compositor;
+use smithay::wayland::compositor::BufferAssignment;
+use smithay::wayland::compositor::CompositorClientState;
+use smithay::wayland::compositor::CompositorHandler;
+use smithay::wayland::compositor::CompositorState;
+use smithay::wayland::compositor::SurfaceAttributes;
+use smithay::wayland::selection::SelectionHandler;
+use smithay::wayland::selection::SelectionSource;
+use smithay::wayland::selection::SelectionTarget;
+use smithay::wayland::selection::data_device::ClientDndGrabHandler;
+use smithay::wayland::selection::data_device::DataDeviceHandler;
+use smithay::wayland::selection::data_device::DataDeviceState;
+use smithay::wayland::selection::data_device::ServerDndGrabHandler;
+use smithay::wayland::selection::data_device::set_data_device_focus;
+use smithay::wayland::selection::data_device::set_data_device_selection;
+use smithay::wayland::selection::primary_selection::PrimarySelectionHandler;
+use smithay::wayland::selection::primary_selection::PrimarySelectionState;
+use smithay::wayland::selection::primary_selection::set_primary_focus;
+use smithay::wayland::selection::primary_selection::set_primary_selection;
+use smithay::wayland::shell::xdg::PopupSurface;
+use smithay::wayland::shell::xdg::PositionerState;
+use smithay::wayland::shell::xdg::ToplevelSurface;
+use smithay::wayland::shell::xdg::XdgShellHandler;
+use smithay::wayland::shell::xdg::XdgShellState;
+use smithay::wayland::shm::ShmHandler;
+use smithay::wayland::shm::ShmState;
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::EventQueue;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::delegate_noop;
+use smithay_client_toolkit::reexports::client::globals::GlobalListContents;
+use smithay_client_toolkit::reexports::client::globals::registry_queue_init;
+use smithay_client_toolkit::reexports::client::protocol::wl_buffer::WlBuffer as ClientWlBuffer;
+use smithay_client_toolkit::reexports::client::protocol::wl_compositor::WlCompositor;
+use smithay_client_toolkit::reexports::client::protocol::wl_registry::WlRegistry;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm::WlShm;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm_pool::WlShmPool;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface as ClientWlSurface;
+use smithay_client_toolkit::shell::WaylandSurface;
+
+use crate::compositor_utils;
+use crate::dmabuf::DmabufBehavior;
+use crate::output_dpi::DEFAULT_DPI;
+use crate::xwayland_xdg_shell::ShellOptions;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::compositor::CompositorOptions;
+use crate::xwayland_xdg_shell::compositor::DecorationBehavior;
+use crate::xwayland_xdg_shell::compositor::MaximizedFrame;
+use crate::xwayland_xdg_shell::compositor::TilingMode;
+use crate::xwayland_xdg_shell::configure_timeout::ConfigureTimeout;
+use crate::xwayland_xdg_shell::csd::CsdDetection;
+use crate::xwayland_xdg_shell::cursor::CursorThemes;
+use crate::xwayland_xdg_shell::decoration_rules::DecorationRules;
+use crate::xwayland_xdg_shell::early_buffer::EarlyBufferBehavior;
+use crate::xwayland_xdg_shell::focus_loss::FocusLossBehavior;
+use crate::xwayland_xdg_shell::frame_buttons::FrameButtons;
+use crate::xwayland_xdg_shell::frame_limit::DEFAULT_MAX_FRAMES_IN_FLIGHT;
+use crate::xwayland_xdg_shell::frame_pacing::FramePacing;
+use crate::xwayland_xdg_shell::fullscreen::FullscreenMonitorBehavior;
+use crate::xwayland_xdg_shell::input_region::EmptyInputRegionBehavior;
+use crate::xwayland_xdg_shell::mode_change::ModeChangeBehavior;
+use crate::xwayland_xdg_shell::no_output::NoOutputBehavior;
+use crate::xwayland_xdg_shell::opacity::OpacityInterpolation;
+use crate::xwayland_xdg_shell::pending_parents::ParentRaceBehavior;
+use crate::xwayland_xdg_shell::pointer_constraints::PointerLockEscape;
+use crate::xwayland_xdg_shell::pointer_leave::PointerLeaveBehavior;
+use crate::xwayland_xdg_shell::popup_grab::PopupGrabBehavior;
+use crate::xwayland_xdg_shell::scale_override::ScaleOverrides;
+use crate::xwayland_xdg_shell::selection_limit::SelectionRateLimit;
+use crate::xwayland_xdg_shell::surface_limit::SurfaceLimit;
+use crate::xwayland_xdg_shell::sync_request::SyncRequestBehavior;
+use crate::xwayland_xdg_shell::title::DEFAULT_TITLE_TEMPLATE;
+use crate::xwayland_xdg_shell::title::TitleSource;
+use crate::xwayland_xdg_shell::window_layer::WindowLayerBehavior;
+use crate::xwayland_xdg_shell::wmname::DEFAULT_WMNAME;
+
+/// Options for which nothing waits on X11 or on outputs, which the local
+/// compositor has none of.
+pub(crate) fn options() -> CompositorOptions {
+    CompositorOptions {
+        decoration_behavior: DecorationBehavior::AlwaysDisabled,
+        decoration_rules: DecorationRules::default(),
+        tiling_mode: TilingMode::default(),
+        maximized_frame: MaximizedFrame::default(),
+        csd_detection: CsdDetection::default(),
+        parent_race_behavior: ParentRaceBehavior::default(),
+        early_buffer_behavior: EarlyBufferBehavior::default(),
+        skip_unchanged_commits: false,
+        popup_grab_behavior: PopupGrabBehavior::default(),
+        window_layer_behavior: WindowLayerBehavior::default(),
+        fullscreen_monitor_behavior: FullscreenMonitorBehavior::default(),
+        title_source: TitleSource::default(),
+        title_template: DEFAULT_TITLE_TEMPLATE.to_string(),
+        wm_name: DEFAULT_WMNAME.to_string(),
+        configure_timeout: ConfigureTimeout::Disabled,
+        max_frames_in_flight: DEFAULT_MAX_FRAMES_IN_FLIGHT,
+        frame_pacing: FramePacing::default(),
+        mode_change_behavior: ModeChangeBehavior::Present,
+        scale_overrides: ScaleOverrides::default(),
+        focus_loss_behavior: FocusLossBehavior::default(),
+        selection_rate_limit: SelectionRateLimit::Unlimited,
+        forward_primary_selection: true,
+        opacity_interpolation: OpacityInterpolation::Disabled,
+        sync_request_behavior: SyncRequestBehavior::Disabled,
+        no_output_behavior: NoOutputBehavior::AssumeDefaults,
+        empty_input_region_behavior: EmptyInputRegionBehavior::default(),
+        pointer_leave_behavior: PointerLeaveBehavior::default(),
+        pointer_lock_escape: PointerLockEscape::default(),
+        default_dpi: DEFAULT_DPI,
+        dmabuf_behavior: DmabufBehavior::default(),
+    }
+}
+
+/// Stands in for what xwayland sets on an X11 window through the X server: the
+/// wl_surface it draws the window to and its WM_TRANSIENT_FOR. Attached to
+/// X11Surfaces made without an X server, and used instead of their own state.
+#[derive(Debug)]
+pub(crate) struct FakeWindow {
+    pub(crate) wl_surface: WlSurface,
+    pub(crate) transient_for: Option<u32>,
+}
+
+impl FakeWindow {
+    pub(crate) fn of(x11_surface: &X11Surface) -> Option<&Self> {
+        x11_surface.user_data().get::<Self>()
+    }
+}
+
+/// The client data of the fake xwayland, which is used instead of
+/// XWaylandClientData as that can only be made by spawning xwayland.
+#[derive(Debug, Default)]
+pub(crate) struct FakeXwaylandData {
+    pub(crate) compositor_state: CompositorClientState,
+}
+
+impl ClientData for FakeXwaylandData {}
+
+struct FakeXwaylandState;
+
+impl Dispatch<WlRegistry, GlobalListContents> for FakeXwaylandState {
+    fn event(
+        _state: &mut Self,
+        _registry: &WlRegistry,
+        _event: <WlRegistry as Proxy>::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(FakeXwaylandState: WlCompositor);
+delegate_noop!(FakeXwaylandState: WlShmPool);
+delegate_noop!(FakeXwaylandState: ignore WlShm);
+delegate_noop!(FakeXwaylandState: ignore ClientWlSurface);
+delegate_noop!(FakeXwaylandState: ignore ClientWlBuffer);
+
+/// A wl_shm buffer of the fake xwayland, in a pool of its own.
+pub(crate) struct FakeBuffer {
+    file: File,
+    pub(crate) wl_buffer: ClientWlBuffer,
+}
+
+impl FakeBuffer {
+    /// Overwrites the contents of the buffer, as an app reusing it would.
+    pub(crate) fn write(&self, contents: &[u8]) {
+        self.file.write_all_at(contents, 0).unwrap();
+    }
+}
+
+/// Draws to wprs the way xwayland does, with plain wl_surfaces and wl_shm
+/// buffers.
+pub(crate) struct FakeXwayland {
+    conn: Connection,
+    queue: EventQueue<FakeXwaylandState>,
+    compositor: WlCompositor,
+    shm: WlShm,
+}
+
+impl FakeXwayland {
+    fn connect(stream: UnixStream) -> Self {
+        let conn = Connection::from_socket(stream).unwrap();
+        let (globals, queue) = registry_queue_init::<FakeXwaylandState>(&conn).unwrap();
+        let qh = queue.handle();
+        Self {
+            compositor: globals.bind(&qh, 1..=6, ()).unwrap(),
+            shm: globals.bind(&qh, 1..=1, ()).unwrap(),
+            conn,
+            queue,
+        }
+    }
+
+    pub(crate) fn create_surface(&self) -> ClientWlSurface {
+        self.compositor.create_surface(&self.queue.handle(), ())
+    }
+
+    /// An argb8888 buffer holding `contents`.
+    pub(crate) fn create_buffer(&self, width: i32, height: i32, contents: &[u8]) -> FakeBuffer {
+        let file = File::from(memfd_create("fake-xwayland", MFdFlags::MFD_CLOEXEC).unwrap());
+        file.set_len(contents.len() as u64).unwrap();
+        let pool = self.shm.create_pool(
+            file.as_fd(),
+            contents.len() as i32,
+            &self.queue.handle(),
+            (),
+        );
+        let wl_buffer = pool.create_buffer(
+            0,
+            width,
+            height,
+            width * 4,
+            wl_shm::Format::Argb8888,
+            &self.queue.handle(),
+            (),
+        );
+        pool.destroy();
+        let buffer = FakeBuffer { file, wl_buffer };
+        buffer.write(contents);
+        buffer
+    }
+
+    /// Commits `surface`, with `buffer` attached if it's set.
+    pub(crate) fn commit(&self, surface: &ClientWlSurface, buffer: Option<&FakeBuffer>) {
+        if let Some(buffer) = buffer {
+            surface.attach(Some(&buffer.wl_buffer), 0, 0);
+            surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+        }
+        surface.commit();
+    }
+
+    fn flush(&mut self) {
+        self.conn.flush().unwrap();
+        if let Some(guard) = self.conn.prepare_read() {
+            // Errors if there is nothing to read.
+            _ = guard.read();
+        }
+        self.queue.dispatch_pending(&mut FakeXwaylandState).unwrap();
+    }
+}
+
+/// Something the test asks the local compositor.
+enum LocalRequest {
+    /// The contents of the buffer last committed to the surface with a
+    /// protocol id, as they were when it was committed.
+    Committed(u32, mpsc::Sender<Option<Vec<u8>>>),
+    /// Gives keyboard focus to wprs's surface with a protocol id.
+    Focus(u32, mpsc::Sender<()>),
+    /// Sets the selection as a local app would, offering it as a mime type
+    /// with some contents.
+    SetSelection(SelectionTarget, String, Vec<u8>, mpsc::Sender<()>),
+    /// The mime types of the selection wprs last set, if it's set.
+    Selection(SelectionTarget, mpsc::Sender<Option<Vec<String>>>),
+    /// Whether a move or resize of the toplevel with a protocol id was
+    /// requested.
+    MovedOrResized(u32, mpsc::Sender<bool>),
+}
+
+/// The compositor wprs displays its windows on. Toplevels are configured when
+/// they're first committed.
+struct LocalCompositor {
+    dh: DisplayHandle,
+    compositor_state: CompositorState,
+    shm_state: ShmState,
+    xdg_shell_state: XdgShellState,
+    seat_state: SeatState<Self>,
+    data_device_state: DataDeviceState,
+    primary_selection_state: PrimarySelectionState,
+    seat: Seat<Self>,
+    toplevels: Vec<ToplevelSurface>,
+    committed: HashMap<u32, Vec<u8>>,
+    /// The protocol ids of the toplevels whose move or resize was requested.
+    moved_or_resized: Vec<u32>,
+    clipboard: Option<Vec<String>>,
+    primary: Option<Vec<String>>,
+}
+
+impl LocalCompositor {
+    fn selection_mut(&mut self, target: SelectionTarget) -> &mut Option<Vec<String>> {
+        match target {
+            SelectionTarget::Clipboard => &mut self.clipboard,
+            SelectionTarget::Primary => &mut self.primary,
+        }
+    }
+
+    fn handle(&mut self, client: &Client, request: LocalRequest) {
+        match request {
+            LocalRequest::Committed(protocol_id, reply) => {
+                reply
+                    .send(self.committed.get(&protocol_id).cloned())
+                    .unwrap();
+            },
+            LocalRequest::Focus(protocol_id, reply) => {
+                let surface = client
+                    .object_from_protocol_id::<WlSurface>(&self.dh, protocol_id)
+                    .unwrap();
+                let keyboard = self.seat.get_keyboard().unwrap();
+                keyboard.set_focus(self, Some(surface), SERIAL_COUNTER.next_serial());
+                reply.send(()).unwrap();
+            },
+            LocalRequest::SetSelection(target, mime_type, contents, reply) => {
+                match target {
+                    SelectionTarget::Clipboard => {
+                        set_data_device_selection(&self.dh, &self.seat, vec![mime_type], contents);
+                    },
+                    SelectionTarget::Primary => {
+                        set_primary_selection(&self.dh, &self.seat, vec![mime_type], contents);
+                    },
+                }
+                reply.send(()).unwrap();
+            },
+            LocalRequest::Selection(target, reply) => {
+                reply.send(self.selection_mut(target).clone()).unwrap();
+            },
+            LocalRequest::MovedOrResized(protocol_id, reply) => {
+                reply
+                    .send(self.moved_or_resized.contains(&protocol_id))
+                    .unwrap();
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct LocalClientData {
+    compositor_state: CompositorClientState,
+}
+
+impl ClientData for LocalClientData {}
+
+impl CompositorHandler for LocalCompositor {
+    fn compositor_state(&mut self) -> &mut CompositorState {
+        &mut self.compositor_state
+    }
+
+    fn client_compositor_state<'a>(&self, client: &'a Client) -> &'a CompositorClientState {
+        &client
+            .get_data::<LocalClientData>()
+            .unwrap()
+            .compositor_state
+    }
+
+    fn commit(&mut self, surface: &WlSurface) {
+        let contents = compositor::with_states(surface, |surface_data| {
+            match &surface_data
+                .cached_state
+                .get::<SurfaceAttributes>()
+                .current()
+                .buffer
+            {
+                Some(BufferAssignment::NewBuffer(buffer)) => {
+                    compositor_utils::with_buffer_contents(buffer, |data, _| {
+                        let mut copy = vec![0; data.len()];
+                        data.copy_to_nonoverlapping(&mut copy);
+                        copy
+                    })
+                    .ok()
+                },
+                _ => None,
+            }
+        });
+        if let Some(contents) = contents {
+            self.committed.insert(surface.id().protocol_id(), contents);
+        }
+        on_commit_buffer_handler::<Self>(surface);
+
+        if let Some(toplevel) = self
+            .toplevels
+            .iter()
+            .find(|toplevel| toplevel.wl_surface() == surface)
+            && !toplevel.is_initial_configure_sent()
+        {
+            toplevel.send_configure();
+        }
+    }
+}
+
+impl BufferHandler for LocalCompositor {
+    fn buffer_destroyed(&mut self, _buffer: &WlBuffer) {}
+}
+
+impl ShmHandler for LocalCompositor {
+    fn shm_state(&self) -> &ShmState {
+        &self.shm_state
+    }
+}
+
+impl XdgShellHandler for LocalCompositor {
+    fn xdg_shell_state(&mut self) -> &mut XdgShellState {
+        &mut self.xdg_shell_state
+    }
+
+    fn new_toplevel(&mut self, surface: ToplevelSurface) {
+        self.toplevels.push(surface);
+    }
+
+    fn new_popup(&mut self, _surface: PopupSurface, _positioner: PositionerState) {}
+
+    fn grab(&mut self, _surface: PopupSurface, _seat: WlSeat, _serial: Serial) {}
+
+    fn move_request(&mut self, surface: ToplevelSurface, _seat: WlSeat, _serial: Serial) {
+        self.moved_or_resized
+            .push(surface.wl_surface().id().protocol_id());
+    }
+
+    fn resize_request(
+        &mut self,
+        surface: ToplevelSurface,
+        _seat: WlSeat,
+        _serial: Serial,
+        _edges: ResizeEdge,
+    ) {
+        self.moved_or_resized
+            .push(surface.wl_surface().id().protocol_id());
+    }
+
+    fn reposition_request(
+        &mut self,
+        _surface: PopupSurface,
+        _positioner: PositionerState,
+        _token: u32,
+    ) {
+    }
+}
+
+impl SeatHandler for LocalCompositor {
+    type KeyboardFocus = WlSurface;
+    type PointerFocus = WlSurface;
+    type TouchFocus = WlSurface;
+
+    fn seat_state(&mut self) -> &mut SeatState<Self> {
+        &mut self.seat_state
+    }
+
+    fn focus_changed(&mut self, seat: &Seat<Self>, focused: Option<&WlSurface>) {
+        let client = focused.and_then(Resource::client);
+        set_data_device_focus(&self.dh, seat, client.clone());
+        set_primary_focus(&self.dh, seat, client);
+    }
+}
+
+impl SelectionHandler for LocalCompositor {
+    /// The contents of a selection set with LocalRequest::SetSelection.
+    type SelectionUserData = Vec<u8>;
+
+    fn new_selection(
+        &mut self,
+        target: SelectionTarget,
+        source: Option<SelectionSource>,
+        _seat: Seat<Self>,
+    ) {
+        *self.selection_mut(target) = source.map(|source| source.mime_types());
+    }
+
+    fn send_selection(
+        &mut self,
+        _target: SelectionTarget,
+        _mime_type: String,
+        fd: OwnedFd,
+        _seat: Seat<Self>,
+        contents: &Vec<u8>,
+    ) {
+        File::from(fd).write_all(contents).unwrap();
+    }
+}
+
+impl DataDeviceHandler for LocalCompositor {
+    fn data_device_state(&self) -> &DataDeviceState {
+        &self.data_device_state
+    }
+}
+
+impl PrimarySelectionHandler for LocalCompositor {
+    fn primary_selection_state(&self) -> &PrimarySelectionState {
+        &self.primary_selection_state
+    }
+}
+
+impl ClientDndGrabHandler for LocalCompositor {}
+
+impl ServerDndGrabHandler for LocalCompositor {}
+
+smithay::delegate_compositor!(LocalCompositor);
+smithay::delegate_shm!(LocalCompositor);
+smithay::delegate_xdg_shell!(LocalCompositor);
+smithay::delegate_seat!(LocalCompositor);
+smithay::delegate_data_device!(LocalCompositor);
+smithay::delegate_primary_selection!(LocalCompositor);
+
+/// Runs the local compositor on `stream` until the returned sender is dropped.
+fn spawn_local_compositor(stream: UnixStream) -> mpsc::Sender<LocalRequest> {
+    let (sender, receiver) = mpsc::channel::<LocalRequest>();
+    thread::spawn(move || {
+        let mut display: Display<LocalCompositor> = Display::new().unwrap();
+        let mut dh = display.handle();
+        let mut seat_state = SeatState::new();
+        let mut seat = seat_state.new_wl_seat(&dh, "seat0");
+        seat.add_keyboard(Default::default(), 200, 25).unwrap();
+        seat.add_pointer();
+        let mut state = LocalCompositor {
+            compositor_state: CompositorState::new::<LocalCompositor>(&dh),
+            shm_state: ShmState::new::<LocalCompositor>(&dh, Vec::new()),
+            xdg_shell_state: XdgShellState::new::<LocalCompositor>(&dh),
+            seat_state,
+            data_device_state: DataDeviceState::new::<LocalCompositor>(&dh),
+            primary_selection_state: PrimarySelectionState::new::<LocalCompositor>(&dh),
+            seat,
+            toplevels: Vec::new(),
+            committed: HashMap::new(),
+            moved_or_resized: Vec::new(),
+            clipboard: None,
+            primary: None,
+            dh: dh.clone(),
+        };
+        let client = dh
+            .insert_client(stream, Arc::new(LocalClientData::default()))
+            .unwrap();
+        loop {
+            display.dispatch_clients(&mut state).unwrap();
+            display.flush_clients().unwrap();
+            match receiver.try_recv() {
+                Ok(request) => state.handle(&client, request),
+                Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(1)),
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+    });
+    sender
+}
+
+/// WprsState connected to a fake xwayland and a fake local compositor.
+pub(crate) struct Harness {
+    pub(crate) state: WprsState,
+    event_loop: EventLoop<'static, WprsState>,
+    display: Display<WprsState>,
+    local_queue: EventQueue<WprsState>,
+    local_compositor: mpsc::Sender<LocalRequest>,
+    xwayland_client: Client,
+    pub(crate) xwayland: FakeXwayland,
+    next_window: u32,
+}
+
+impl Harness {
+    pub(crate) fn new(options: CompositorOptions) -> Self {
+        let (local_stream, local_compositor_stream) = UnixStream::pair().unwrap();
+        let local_compositor = spawn_local_compositor(local_compositor_stream);
+        let conn = Connection::from_socket(local_stream).unwrap();
+        let (globals, local_queue) = registry_queue_init::<WprsState>(&conn).unwrap();
+
+        let event_loop = EventLoop::try_new().unwrap();
+        let mut display = Display::new().unwrap();
+        let mut state = WprsState::without_xwayland(
+            display.handle(),
+            &globals,
+            local_queue.handle(),
+            conn,
+            event_loop.handle(),
+            ShellOptions {
+                compositor: options,
+                frame_buttons: FrameButtons::default(),
+                idle_timeout_ms: 0,
+                cursor_themes: CursorThemes::new(None, None, BTreeMap::new()),
+                surface_limit: SurfaceLimit::Unlimited,
+            },
+        )
+        .unwrap();
+
+        let (xwayland_stream, xwayland_server_stream) = UnixStream::pair().unwrap();
+        let xwayland_client = display
+            .handle()
+            .insert_client(
+                xwayland_server_stream,
+                Arc::new(FakeXwaylandData::default()),
+            )
+            .unwrap();
+        // Binding the globals takes a round trip, which wprs has to answer.
+        let xwayland = thread::spawn(move || FakeXwayland::connect(xwayland_stream));
+        while !xwayland.is_finished() {
+            display.dispatch_clients(&mut state).unwrap();
+            display.flush_clients().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut harness = Self {
+            state,
+            event_loop,
+            display,
+            local_queue,
+            local_compositor,
+            xwayland_client,
+            xwayland: xwayland.join().unwrap(),
+            next_window: 1,
+        };
+        harness.dispatch();
+        harness
+    }
+
+    /// Lets the fake xwayland, wprs and the local compositor handle what was
+    /// sent so far, and what they send in response.
+    pub(crate) fn dispatch(&mut self) {
+        for _ in 0..3 {
+            self.xwayland.flush();
+            self.display.dispatch_clients(&mut self.state).unwrap();
+            self.display.flush_clients().unwrap();
+            self.event_loop
+                .dispatch(Duration::ZERO, &mut self.state)
+                .unwrap();
+            self.local_queue.roundtrip(&mut self.state).unwrap();
+        }
+    }
+
+    /// wprs's side of a surface of the fake xwayland.
+    pub(crate) fn surface(&self, surface: &ClientWlSurface) -> WlSurface {
+        self.xwayland_client
+            .object_from_protocol_id(&self.display.handle(), surface.id().protocol_id())
+            .unwrap()
+    }
+
+    /// Maps an X11 window drawn to `surface`, which is transient for the window
+    /// `transient_for` if that's set. Returns the id of the window.
+    pub(crate) fn map_x11_window(
+        &mut self,
+        surface: &ClientWlSurface,
+        transient_for: Option<u32>,
+    ) -> u32 {
+        // wprs has to have seen the surface.
+        self.dispatch();
+        let window = self.next_window;
+        self.next_window += 1;
+        let x11_surface = X11Surface::new(
+            None,
+            window,
+            false,
+            Weak::new(),
+            // SAFETY: Atoms only has u32 fields, for which zero is valid.
+            // They're only sent to the X server, and there is none.
+            unsafe { std::mem::zeroed() },
+            Rectangle::new((0, 0).into(), (100, 100).into()),
+        );
+        let wl_surface = self.surface(surface);
+        x11_surface.user_data().insert_if_missing(|| FakeWindow {
+            wl_surface,
+            transient_for,
+        });
+        self.state.compositor_state.x11_surfaces.push(x11_surface);
+        window
+    }
+
+    /// Sends `request`, made with where to reply, to the local compositor and
+    /// waits for the reply.
+    fn local_request<T>(&self, request: impl FnOnce(mpsc::Sender<T>) -> LocalRequest) -> T {
+        let (reply, receiver) = mpsc::channel();
+        self.local_compositor.send(request(reply)).unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// The protocol id of the local surface wprs displays `surface` on, if it
+    /// has one yet.
+    fn local_protocol_id(&self, surface: &ClientWlSurface) -> Option<u32> {
+        let xwayland_surface = self
+            .state
+            .surfaces
+            .get(&self.surface(surface).id())
+            .filter(|xwls| xwls.role.is_some() || xwls.local_surface.is_some())?;
+        Some(xwayland_surface.wl_surface().id().protocol_id())
+    }
+
+    /// The contents of the buffer wprs last committed to the local surface it
+    /// displays `surface` on, if it committed one.
+    pub(crate) fn displayed(&self, surface: &ClientWlSurface) -> Option<Vec<u8>> {
+        let protocol_id = self.local_protocol_id(surface)?;
+        self.local_request(|reply| LocalRequest::Committed(protocol_id, reply))
+    }
+
+    /// Whether wprs had the local compositor move or resize the toplevel it
+    /// displays `surface` on.
+    pub(crate) fn moved_or_resized(&self, surface: &ClientWlSurface) -> bool {
+        let Some(protocol_id) = self.local_protocol_id(surface) else {
+            return false;
+        };
+        self.local_request(|reply| LocalRequest::MovedOrResized(protocol_id, reply))
+    }
+
+    /// Gives wprs keyboard focus on the local compositor, which it needs to
+    /// set local selections and is sent them with. The focus is on a surface
+    /// which isn't any window's, so that nothing is focused on X11.
+    pub(crate) fn focus_local(&mut self) {
+        let client_state = &self.state.client_state;
+        let surface = client_state
+            .compositor_state
+            .create_surface(&client_state.qh);
+        self.dispatch();
+        let protocol_id = surface.id().protocol_id();
+        self.local_request(|reply| LocalRequest::Focus(protocol_id, reply));
+        self.dispatch();
+    }
+
+    /// Sets the local `target` selection to `contents` as `mime_type`, as a
+    /// local app would.
+    pub(crate) fn set_local_selection(
+        &mut self,
+        target: SelectionTarget,
+        mime_type: &str,
+        contents: &[u8],
+    ) {
+        self.local_request(|reply| {
+            LocalRequest::SetSelection(target, mime_type.to_owned(), contents.to_vec(), reply)
+        });
+        self.dispatch();
+    }
+
+    /// The mime types wprs offers the local `target` selection as, if it set
+    /// one.
+    pub(crate) fn local_selection(&self, target: SelectionTarget) -> Option<Vec<String>> {
+        self.local_request(|reply| LocalRequest::Selection(target, reply))
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Titles of local windows. The title is read from a configurable X11
+/// property and formatted with a template, in which {title} is replaced by the
+/// title, {class} by the class from WM_CLASS, and {instance} by the instance
+/// name from WM_CLASS. Properties the window doesn't set are replaced by
+/// nothing, along with the text separating them from the neighboring
+/// placeholders, so that "{title} — {class}" becomes just the title for
+/// windows without a class. The app id of local windows is the class from
+/// WM_CLASS. Control characters are removed from both, since host panels may
+/// not cope with them.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::xwayland::X11Surface;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::compositor::WprsCompositorState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+pub const DEFAULT_TITLE_TEMPLATE: &str = "{title}";
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum TitleSource {
+    /// _NET_WM_NAME, or WM_NAME for windows which don't set it.
+    #[default]
+    NetWmName,
+    /// WM_NAME, even for windows which set _NET_WM_NAME.
+    WmName,
+    /// The class from WM_CLASS.
+    Class,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Splits `template` into placeholders and the literal text between them.
+/// Literals are never adjacent.
+fn tokenize(template: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while let Some(offset) = template[i..].find('{') {
+        let start = i + offset;
+        let placeholder = template[start..]
+            .find('}')
+            .map(|len| (&template[start + 1..start + len], start + len + 1))
+            .filter(|(name, _)| matches!(*name, "title" | "class" | "instance"));
+        match placeholder {
+            Some((name, end)) => {
+                if start > literal_start {
+                    tokens.push(Token::Literal(&template[literal_start..start]));
+                }
+                tokens.push(Token::Placeholder(name));
+                literal_start = end;
+                i = end;
+            },
+            // Unknown placeholders are kept verbatim.
+            None => i = start + 1,
+        }
+    }
+    if literal_start < template.len() {
+        tokens.push(Token::Literal(&template[literal_start..]));
+    }
+    tokens
+}
+
+/// Formats `template`, see the module documentation.
+pub(crate) fn format_title(template: &str, title: &str, class: &str, instance: &str) -> String {
+    let tokens = tokenize(template);
+    let value = |name: &str| match name {
+        "title" => title,
+        "class" => class,
+        "instance" => instance,
+        _ => unreachable!("unknown placeholder {name}"),
+    };
+
+    let mut formatted = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Placeholder(name) => formatted.push_str(value(name)),
+            Token::Literal(literal) => {
+                // The neighbors of a literal are placeholders.
+                let previous = i.checked_sub(1).map(|i| &tokens[i]);
+                let next = tokens.get(i + 1);
+                let separates_empty = [previous, next].into_iter().any(|neighbor| {
+                    matches!(neighbor, Some(Token::Placeholder(name)) if value(name).is_empty())
+                });
+                if !(previous.is_some() && next.is_some() && separates_empty) {
+                    formatted.push_str(literal);
+                }
+            },
+        }
+    }
+    formatted.trim().to_string()
+}
+
+/// `s` with line breaks and tabs replaced by spaces and other control
+/// characters removed.
+fn strip_control_characters(s: &str) -> String {
+    s.chars()
+        .filter_map(|c| match c {
+            '\n' | '\r' | '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+fn fetch_wm_name(conn: &X11Connection, window: u32) -> Result<String> {
+    let reply = conn
+        .get_property(
+            false,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::ANY,
+            0,
+            u32::MAX / 4,
+        )
+        .location(loc!())?
+        .reply()
+        .location(loc!())?;
+    Ok(String::from_utf8_lossy(&reply.value).into_owned())
+}
+
+impl WprsCompositorState {
+    /// The title of the local window for `x11_surface`.
+    pub(crate) fn window_title(&self, x11_surface: &X11Surface) -> String {
+        let title = match self.title_source {
+            TitleSource::NetWmName => x11_surface.title(),
+            TitleSource::WmName => self
+                .x11_conn
+                .as_ref()
+                .and_then(|conn| {
+                    fetch_wm_name(conn, x11_surface.window_id())
+                        .warn(loc!())
+                        .ok()
+                })
+                .unwrap_or_default(),
+            TitleSource::Class => x11_surface.class(),
+        };
+        strip_control_characters(&format_title(
+            &self.title_template,
+            &title,
+            &x11_surface.class(),
+            &x11_surface.instance(),
+        ))
+        .trim()
+        .to_string()
+    }
+
+    /// The app id of the local window for `x11_surface`.
+    pub(crate) fn window_app_id(&self, x11_surface: &X11Surface) -> String {
+        strip_control_characters(&x11_surface.class())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_template_is_title() {
+        assert_eq!(
+            format_title(DEFAULT_TITLE_TEMPLATE, "Doc", "Editor", "editor"),
+            "Doc"
+        );
+    }
+
+    #[test]
+    fn all_placeholders_are_replaced() {
+        assert_eq!(
+            format_title("{title} — {class} ({instance})", "Doc", "Editor", "editor"),
+            "Doc — Editor (editor)"
+        );
+    }
+
+    #[test]
+    fn separators_of_missing_properties_are_dropped() {
+        assert_eq!(
+            format_title("{title} — {class}", "Doc", "", "editor"),
+            "Doc"
+        );
+        assert_eq!(
+            format_title("{title} — {class}", "", "Editor", "editor"),
+            "Editor"
+        );
+        assert_eq!(format_title("{title} — {class}", "", "", ""), "");
+    }
+
+    #[test]
+    fn surrounding_text_is_kept() {
+        assert_eq!(
+            format_title("X11: {title}", "Doc", "Editor", "editor"),
+            "X11: Doc"
+        );
+        assert_eq!(format_title("X11: {title}", "", "Editor", "editor"), "X11:");
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        assert_eq!(strip_control_characters("Doc\u{7}\u{1b}[31m"), "Doc[31m");
+        assert_eq!(
+            strip_control_characters("line 1\nline\t2\r"),
+            "line 1 line 2 "
+        );
+        assert_eq!(
+            strip_control_characters("Über — ドキュメント"),
+            "Über — ドキュメント"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        assert_eq!(
+            format_title("{title} {unknown} {", "Doc", "Editor", "editor"),
+            "Doc {unknown} {"
+        );
+    }
+}
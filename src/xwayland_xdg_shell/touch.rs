@@ -0,0 +1,252 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Touch input from the local compositor, forwarded to the X11 window each
+/// touch point went down on. A seat offers touch to Xwayland only while the
+/// local seat it mirrors has the capability. Only touch down events carry a
+/// surface, so the window (and its scale) is remembered per touch point until
+/// the point goes up or the sequence is cancelled, also if the window is
+/// destroyed in the meantime.
+use std::collections::HashMap;
+
+use smithay::input::touch::DownEvent;
+use smithay::input::touch::MotionEvent;
+use smithay::input::touch::UpEvent;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_seat::WlSeat;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::reexports::client::protocol::wl_touch::WlTouch;
+use smithay_client_toolkit::seat::touch::TouchData;
+use smithay_client_toolkit::seat::touch::TouchHandler;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::xsurface_from_client_surface;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TouchPoint<S> {
+    pub(crate) surface: S,
+    pub(crate) scale: f64,
+}
+
+/// The active touch points of a seat, by touch ID.
+#[derive(Debug)]
+pub(crate) struct TouchPoints<S> {
+    points: HashMap<i32, TouchPoint<S>>,
+}
+
+impl<S> Default for TouchPoints<S> {
+    fn default() -> Self {
+        Self {
+            points: HashMap::new(),
+        }
+    }
+}
+
+impl<S> TouchPoints<S> {
+    pub(crate) fn down(&mut self, id: i32, surface: S, scale: f64) {
+        self.points.insert(id, TouchPoint { surface, scale });
+    }
+
+    pub(crate) fn get(&self, id: i32) -> Option<&TouchPoint<S>> {
+        self.points.get(&id)
+    }
+
+    pub(crate) fn up(&mut self, id: i32) -> Option<TouchPoint<S>> {
+        self.points.remove(&id)
+    }
+
+    pub(crate) fn cancel(&mut self) {
+        self.points.clear();
+    }
+}
+
+/// The local seat `touch` belongs to.
+fn touch_seat(touch: &WlTouch) -> Result<&WlSeat> {
+    Ok(touch.data::<TouchData>().location(loc!())?.seat())
+}
+
+impl TouchHandler for WprsState {
+    #[instrument(skip(self, _conn, _qh, touch), level = "debug")]
+    fn down(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        touch: &WlTouch,
+        serial: u32,
+        time: u32,
+        surface: WlSurface,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        self.reset_idle();
+        let seat_name =
+            log_and_return!(touch_seat(touch).and_then(|seat| self.compositor_seat_name(seat)));
+        let Some(xwayland_surface) =
+            xsurface_from_client_surface(&self.surface_bimap, &mut self.surfaces, &surface)
+        else {
+            // Decorations or a window which was destroyed already.
+            return;
+        };
+        let x11_surface = log_and_return!(xwayland_surface.get_x11_surface()).clone();
+        let scale = f64::from(xwayland_surface.scale());
+
+        let seat = log_and_return!(self.compositor_state.seat_mut(&seat_name));
+        let compositor_touch = log_and_return!(seat.seat.get_touch().location(loc!()));
+        seat.touch_points.down(id, x11_surface.clone(), scale);
+        let serial = seat.serial_map.insert(serial);
+        compositor_touch.down(
+            self,
+            Some((x11_surface, (0 as f64, 0 as f64).into())),
+            &DownEvent {
+                slot: Some(id as u32).into(),
+                location: (position.0 / scale, position.1 / scale).into(),
+                serial,
+                time,
+            },
+        );
+        compositor_touch.frame(self);
+    }
+
+    #[instrument(skip(self, _conn, _qh, touch), level = "debug")]
+    fn up(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        touch: &WlTouch,
+        serial: u32,
+        time: u32,
+        id: i32,
+    ) {
+        let seat_name =
+            log_and_return!(touch_seat(touch).and_then(|seat| self.compositor_seat_name(seat)));
+        let seat = log_and_return!(self.compositor_state.seat_mut(&seat_name));
+        let compositor_touch = log_and_return!(seat.seat.get_touch().location(loc!()));
+        if seat.touch_points.up(id).is_none() {
+            return;
+        }
+        // Sent even if the window was destroyed, so that the seat forgets the
+        // touch point; X11Surface ignores it then.
+        let serial = seat.serial_map.insert(serial);
+        compositor_touch.up(
+            self,
+            &UpEvent {
+                slot: Some(id as u32).into(),
+                serial,
+                time,
+            },
+        );
+        compositor_touch.frame(self);
+    }
+
+    #[instrument(skip(self, _conn, _qh, touch), level = "debug")]
+    fn motion(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        touch: &WlTouch,
+        time: u32,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        let seat_name =
+            log_and_return!(touch_seat(touch).and_then(|seat| self.compositor_seat_name(seat)));
+        let seat = log_and_return!(self.compositor_state.seat(&seat_name));
+        let compositor_touch = log_and_return!(seat.seat.get_touch().location(loc!()));
+        let Some(TouchPoint { surface, scale }) = seat.touch_points.get(id).cloned() else {
+            return;
+        };
+        if !surface.alive() {
+            return;
+        }
+        compositor_touch.motion(
+            self,
+            Some((surface, (0 as f64, 0 as f64).into())),
+            &MotionEvent {
+                slot: Some(id as u32).into(),
+                location: (position.0 / scale, position.1 / scale).into(),
+                time,
+            },
+        );
+        compositor_touch.frame(self);
+    }
+
+    fn shape(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _id: i32,
+        _major: f64,
+        _minor: f64,
+    ) {
+    }
+
+    fn orientation(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _id: i32,
+        _orientation: f64,
+    ) {
+    }
+
+    #[instrument(skip_all, level = "debug")]
+    fn cancel(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, touch: &WlTouch) {
+        let seat_name =
+            log_and_return!(touch_seat(touch).and_then(|seat| self.compositor_seat_name(seat)));
+        let seat = log_and_return!(self.compositor_state.seat_mut(&seat_name));
+        let compositor_touch = log_and_return!(seat.seat.get_touch().location(loc!()));
+        seat.touch_points.cancel();
+        compositor_touch.cancel(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simultaneous_touch_points() {
+        let mut points = TouchPoints::default();
+        points.down(0, "a", 1.0);
+        points.down(1, "b", 2.0);
+        assert_eq!(
+            points.get(1),
+            Some(&TouchPoint {
+                surface: "b",
+                scale: 2.0
+            })
+        );
+        assert_eq!(points.up(0).map(|p| p.surface), Some("a"));
+        assert_eq!(points.get(0), None);
+        assert_eq!(points.get(1).map(|p| p.surface), Some("b"));
+        assert_eq!(points.up(1).map(|p| p.surface), Some("b"));
+        assert_eq!(points.get(1), None);
+    }
+
+    #[test]
+    fn unknown_and_cancelled_points_are_ignored() {
+        let mut points = TouchPoints::default();
+        assert_eq!(points.up(3), None);
+        points.down(0, "a", 1.0);
+        points.down(1, "a", 1.0);
+        points.cancel();
+        assert_eq!(points.get(0), None);
+        assert_eq!(points.up(1), None);
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Forwarding of wp_viewport state. Xwayland crops and scales surfaces with a
+/// viewport, e.g. when emulating a mode change for a fullscreen game, and
+/// those surfaces would otherwise be shown at the size of their buffer. The
+/// committed source and destination rectangles are set on the viewport of the
+/// local surface, which applies them with the next local commit. Surfaces with
+/// a scale override have larger local buffers, so the rectangles are scaled
+/// up with them.
+use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewport;
+use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewport::WpViewport;
+use smithay::reexports::wayland_protocols::wp::viewporter::client::wp_viewporter::WpViewporter;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Dispatch;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::registry::SimpleGlobal;
+
+use crate::serialization::geometry::Point;
+use crate::serialization::geometry::Rectangle;
+use crate::serialization::geometry::Size;
+use crate::serialization::wayland::ViewportState;
+use crate::xwayland_xdg_shell::WprsState;
+
+/// Whether `viewport_state` crops or scales its surface.
+pub(crate) fn is_viewported(viewport_state: &ViewportState) -> bool {
+    viewport_state.src.is_some() || viewport_state.dst.is_some()
+}
+
+/// `viewport_state` for a local buffer `scale` times the size of the app's.
+/// Source rectangles may have fractional coordinates, which are kept.
+fn scale_viewport(viewport_state: ViewportState, scale: u32) -> ViewportState {
+    let scale_f = f64::from(scale);
+    let scale_i = scale as i32;
+    ViewportState {
+        src: viewport_state.src.map(|src| Rectangle {
+            loc: Point {
+                x: src.loc.x * scale_f,
+                y: src.loc.y * scale_f,
+            },
+            size: Size {
+                w: src.size.w * scale_f,
+                h: src.size.h * scale_f,
+            },
+        }),
+        dst: viewport_state.dst.map(|dst| Size {
+            w: dst.w * scale_i,
+            h: dst.h * scale_i,
+        }),
+    }
+}
+
+/// The viewport of a local surface.
+#[derive(Debug, Default)]
+pub(crate) struct LocalViewport {
+    viewport: Option<WpViewport>,
+    current: Option<ViewportState>,
+}
+
+impl LocalViewport {
+    /// Sets the source and destination of the viewport of `surface` to
+    /// `viewport_state` scaled by `scale`, if they changed. The viewport is
+    /// only created once the app crops or scales the surface.
+    pub(crate) fn update(
+        &mut self,
+        viewport_state: ViewportState,
+        scale: u32,
+        surface: &WlSurface,
+        wp_viewporter: Option<&SimpleGlobal<WpViewporter, 1>>,
+        qh: &QueueHandle<WprsState>,
+    ) {
+        let viewport_state = scale_viewport(viewport_state, scale);
+        if self.current == Some(viewport_state)
+            || (self.viewport.is_none() && !is_viewported(&viewport_state))
+        {
+            return;
+        }
+        let Some(wp_viewporter) = wp_viewporter.and_then(|global| global.get().ok()) else {
+            return;
+        };
+        let viewport = self
+            .viewport
+            .get_or_insert_with(|| wp_viewporter.get_viewport(surface, qh, ()));
+        // -1 unsets the source or destination.
+        match viewport_state.src {
+            Some(src) => viewport.set_source(src.loc.x, src.loc.y, src.size.w, src.size.h),
+            None => viewport.set_source(-1.0, -1.0, -1.0, -1.0),
+        }
+        match viewport_state.dst {
+            Some(dst) => viewport.set_destination(dst.w, dst.h),
+            None => viewport.set_destination(-1, -1),
+        }
+        self.current = Some(viewport_state);
+    }
+}
+
+impl Drop for LocalViewport {
+    fn drop(&mut self) {
+        if let Some(viewport) = &self.viewport {
+            viewport.destroy();
+        }
+    }
+}
+
+impl Dispatch<WpViewport, ()> for WprsState {
+    fn event(
+        _state: &mut Self,
+        _viewport: &WpViewport,
+        _event: wp_viewport::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // wp_viewport has no events.
+    }
+}
+
+impl AsMut<SimpleGlobal<WpViewporter, 1>> for WprsState {
+    fn as_mut(&mut self) -> &mut SimpleGlobal<WpViewporter, 1> {
+        // This should never panic since if wp_viewporter is None then we will
+        // never get any events for it.
+        self.client_state.wp_viewporter.as_mut().unwrap()
+    }
+}
+
+smithay_client_toolkit::delegate_simple!(WprsState, WpViewporter, 1);
+smithay::delegate_viewporter!(WprsState);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_is_scaled_with_override() {
+        let viewport_state = ViewportState {
+            src: None,
+            dst: Some(Size { w: 1920, h: 1080 }),
+        };
+        assert_eq!(scale_viewport(viewport_state, 1), viewport_state);
+        assert_eq!(
+            scale_viewport(viewport_state, 2).dst,
+            Some(Size { w: 3840, h: 2160 })
+        );
+    }
+
+    #[test]
+    fn subpixel_source_is_kept() {
+        let viewport_state = ViewportState {
+            src: Some(Rectangle {
+                loc: Point { x: 0.5, y: 10.25 },
+                size: Size {
+                    w: 639.5,
+                    h: 479.75,
+                },
+            }),
+            dst: Some(Size { w: 1280, h: 960 }),
+        };
+        assert_eq!(
+            scale_viewport(viewport_state, 2).src,
+            Some(Rectangle {
+                loc: Point { x: 1.0, y: 20.5 },
+                size: Size {
+                    w: 1279.0,
+                    h: 959.5
+                },
+            })
+        );
+        assert!(is_viewported(&viewport_state));
+        assert!(!is_viewported(&ViewportState {
+            src: None,
+            dst: None
+        }));
+    }
+}
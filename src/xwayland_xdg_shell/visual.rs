@@ -0,0 +1,212 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Checking of the visuals of X11 windows. wprs only transmits 24-bit and
+/// 32-bit TrueColor contents (the argb8888 and xrgb8888 shm formats), and
+/// Xwayland converts windows with other visuals (e.g., 8-bit PseudoColor in
+/// very old apps) into those formats when handing them to us. That conversion
+/// doesn't always preserve the colors the app intended, e.g. when it animates
+/// its colormap, so windows with such visuals are logged to make the resulting
+/// rendering issues explainable.
+///
+/// Windows whose sub-windows use other colormaps than their own list those
+/// sub-windows in WM_COLORMAP_WINDOWS and expect the window manager to install
+/// their colormaps while the window is focused. Only the common case of a
+/// single alternate colormap is handled, by installing it whenever the window
+/// gets the keyboard focus. X servers only install one colormap at a time in
+/// practice, so with several alternates only some sub-windows could have the
+/// right colors anyway; those windows are logged instead. The list is read
+/// when the window is mapped, changes made while it's mapped are ignored.
+use smithay::xwayland::X11Surface;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::VisualClass;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+/// Whether windows with a visual of `class` and `depth` are transmitted
+/// as-is.
+fn transmittable(class: VisualClass, depth: u8) -> bool {
+    class == VisualClass::TRUE_COLOR && matches!(depth, 24 | 32)
+}
+
+/// The class and depth of `window`'s visual.
+fn fetch_window_visual(conn: &X11Connection, window: u32) -> Result<(VisualClass, u8)> {
+    let visual_id = conn
+        .get_window_attributes(window)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .visual;
+    conn.setup()
+        .roots
+        .iter()
+        .flat_map(|screen| &screen.allowed_depths)
+        .find_map(|depth| {
+            depth
+                .visuals
+                .iter()
+                .find(|visual| visual.visual_id == visual_id)
+                .map(|visual| (visual.class, depth.depth))
+        })
+        .with_context(loc!(), || {
+            format!("window {window} has unknown visual {visual_id}")
+        })
+}
+
+/// The sub-windows of a window with other colormaps than its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ColormapWindows {
+    None,
+    Single(u32),
+    Multiple(Vec<u32>),
+}
+
+/// Classifies the WM_COLORMAP_WINDOWS `windows` of `window`, which may list
+/// `window` itself to give its own colormap a priority.
+fn classify_colormap_windows(window: u32, windows: &[u32]) -> ColormapWindows {
+    let mut alternates: Vec<u32> = windows.iter().copied().filter(|w| *w != window).collect();
+    alternates.dedup();
+    match alternates.as_slice() {
+        [] => ColormapWindows::None,
+        [alternate] => ColormapWindows::Single(*alternate),
+        _ => ColormapWindows::Multiple(alternates),
+    }
+}
+
+fn fetch_colormap_windows(conn: &X11Connection, window: u32) -> Result<Vec<u32>> {
+    let atom = conn
+        .intern_atom(false, b"WM_COLORMAP_WINDOWS")
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .atom;
+    let reply = conn
+        .get_property(false, window, atom, AtomEnum::WINDOW, 0, u32::MAX / 4)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?;
+    Ok(reply.value32().map(Iterator::collect).unwrap_or_default())
+}
+
+fn install_colormap_of(conn: &X11Connection, window: u32) -> Result<()> {
+    let colormap = conn
+        .get_window_attributes(window)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .colormap;
+    conn.install_colormap(colormap).location(loc!())?;
+    conn.flush().location(loc!())?;
+    Ok(())
+}
+
+impl WprsState {
+    /// Looks up the alternate colormap of `x11_surface`'s window, see the
+    /// module documentation.
+    pub(crate) fn check_colormap_windows(&mut self, x11_surface: &X11Surface) {
+        let Some(conn) = &self.compositor_state.x11_conn else {
+            return;
+        };
+        let window = x11_surface.window_id();
+        let Ok(windows) = fetch_colormap_windows(conn, window).warn(loc!()) else {
+            return;
+        };
+        match classify_colormap_windows(window, &windows) {
+            ColormapWindows::None => {
+                self.compositor_state.colormap_windows.remove(&window);
+            },
+            ColormapWindows::Single(alternate) => {
+                debug!("window {window} uses the colormap of sub-window {alternate}");
+                self.compositor_state
+                    .colormap_windows
+                    .insert(window, alternate);
+            },
+            ColormapWindows::Multiple(alternates) => {
+                warn!(
+                    "window {window} ({:?}) has sub-windows {alternates:?} with different colormaps, which is unsupported, so parts of it may have the wrong colors",
+                    x11_surface.class()
+                );
+                self.compositor_state.colormap_windows.remove(&window);
+            },
+        }
+    }
+
+    /// Installs the alternate colormap of `x11_surface`'s window, which got
+    /// the keyboard focus, if it has one.
+    pub(crate) fn install_window_colormap(&self, x11_surface: &X11Surface) {
+        if let Some(conn) = &self.compositor_state.x11_conn
+            && let Some(alternate) = self
+                .compositor_state
+                .colormap_windows
+                .get(&x11_surface.window_id())
+        {
+            install_colormap_of(conn, *alternate).log_and_ignore(loc!());
+        }
+    }
+
+    /// Logs if `x11_surface`'s window uses a visual which Xwayland has to
+    /// convert before we can transmit it, see the module documentation.
+    pub(crate) fn check_window_visual(&self, x11_surface: &X11Surface) {
+        let Some(conn) = &self.compositor_state.x11_conn else {
+            return;
+        };
+        let window = x11_surface.window_id();
+        let Ok((class, depth)) = fetch_window_visual(conn, window).warn(loc!()) else {
+            return;
+        };
+        if !transmittable(class, depth) {
+            warn!(
+                "window {window} ({:?}) uses a {class:?} visual of depth {depth}, which is only transmitted after conversion to 24-bit TrueColor, so its colors may be off",
+                x11_surface.class()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truecolor_is_transmittable() {
+        assert!(transmittable(VisualClass::TRUE_COLOR, 24));
+        assert!(transmittable(VisualClass::TRUE_COLOR, 32));
+    }
+
+    #[test]
+    fn single_alternate_colormap_is_recognized() {
+        assert_eq!(classify_colormap_windows(1, &[]), ColormapWindows::None);
+        assert_eq!(classify_colormap_windows(1, &[1]), ColormapWindows::None);
+        assert_eq!(
+            classify_colormap_windows(1, &[2, 1]),
+            ColormapWindows::Single(2)
+        );
+        assert_eq!(
+            classify_colormap_windows(1, &[1, 2, 3]),
+            ColormapWindows::Multiple(vec![2, 3])
+        );
+    }
+
+    #[test]
+    fn legacy_visuals_are_not_transmittable() {
+        assert!(!transmittable(VisualClass::PSEUDO_COLOR, 8));
+        assert!(!transmittable(VisualClass::STATIC_GRAY, 1));
+        assert!(!transmittable(VisualClass::DIRECT_COLOR, 24));
+        assert!(!transmittable(VisualClass::TRUE_COLOR, 16));
+    }
+}
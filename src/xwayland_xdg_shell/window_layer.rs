@@ -0,0 +1,393 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Stacking for X11 windows whose EWMH window type implies a layer rather than
+/// a window: desktop windows (wallpapers, desktop icons) are mapped to the
+/// wlr-layer-shell background layer so that they stay below everything, and
+/// docks (panels, taskbars) to the top layer, anchored to the nearest edge of
+/// the screen. All other window types keep their xdg_shell roles.
+///
+/// smithay's WmWindowType doesn't cover desktops or docks (they are reported
+/// as having no type), so _NET_WM_WINDOW_TYPE is read from the X server.
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use smithay::utils::Logical;
+use smithay::utils::Rectangle;
+use smithay::utils::Size;
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::compositor::Surface;
+use smithay_client_toolkit::reexports::client::Connection;
+use smithay_client_toolkit::reexports::client::Proxy;
+use smithay_client_toolkit::reexports::client::QueueHandle;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
+use smithay_client_toolkit::shell::WaylandSurface;
+use smithay_client_toolkit::shell::wlr_layer::Anchor;
+use smithay_client_toolkit::shell::wlr_layer::KeyboardInteractivity;
+use smithay_client_toolkit::shell::wlr_layer::Layer;
+use smithay_client_toolkit::shell::wlr_layer::LayerShell;
+use smithay_client_toolkit::shell::wlr_layer::LayerShellHandler;
+use smithay_client_toolkit::shell::wlr_layer::LayerSurface;
+use smithay_client_toolkit::shell::wlr_layer::LayerSurfaceConfigure;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::XWaylandSurface;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+const NAMESPACE: &str = "wprs";
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum WindowLayerBehavior {
+    /// Map desktop and dock windows to layer surfaces if the local compositor
+    /// supports wlr-layer-shell.
+    #[default]
+    LayerShell,
+    /// Map desktop and dock windows as regular toplevels.
+    Toplevel,
+}
+
+/// The layer for a window with the _NET_WM_WINDOW_TYPE `window_types`, if it
+/// belongs in one. Window types are listed in order of preference, and the
+/// first one we know of is used.
+fn layer_for_window_types<'a>(window_types: impl IntoIterator<Item = &'a str>) -> Option<Layer> {
+    window_types
+        .into_iter()
+        .find_map(|window_type| match window_type {
+            "_NET_WM_WINDOW_TYPE_DESKTOP" => Some(Some(Layer::Background)),
+            "_NET_WM_WINDOW_TYPE_DOCK" => Some(Some(Layer::Top)),
+            "_NET_WM_WINDOW_TYPE_NORMAL"
+            | "_NET_WM_WINDOW_TYPE_DIALOG"
+            | "_NET_WM_WINDOW_TYPE_UTILITY"
+            | "_NET_WM_WINDOW_TYPE_TOOLBAR"
+            | "_NET_WM_WINDOW_TYPE_MENU"
+            | "_NET_WM_WINDOW_TYPE_SPLASH"
+            | "_NET_WM_WINDOW_TYPE_DROPDOWN_MENU"
+            | "_NET_WM_WINDOW_TYPE_POPUP_MENU"
+            | "_NET_WM_WINDOW_TYPE_TOOLTIP"
+            | "_NET_WM_WINDOW_TYPE_NOTIFICATION"
+            | "_NET_WM_WINDOW_TYPE_COMBO"
+            | "_NET_WM_WINDOW_TYPE_DND" => Some(None),
+            // Vendor-specific types, e.g. _KDE_NET_WM_WINDOW_TYPE_OVERRIDE.
+            _ => None,
+        })
+        .flatten()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LayerPlacement {
+    pub layer: Layer,
+    pub anchor: Anchor,
+    /// 0 stretches the surface between the anchored edges.
+    pub size: (u32, u32),
+    pub exclusive_zone: i32,
+}
+
+impl LayerPlacement {
+    /// Where to place a window of `geometry` on a `screen_size` X screen in
+    /// `layer`. Docks are attached to the edge they're closest to and reserve
+    /// their thickness, desktops cover the whole output.
+    fn new(
+        layer: Layer,
+        geometry: Rectangle<i32, Logical>,
+        screen_size: Size<i32, Logical>,
+    ) -> Self {
+        if layer != Layer::Top {
+            return Self {
+                layer,
+                anchor: Anchor::all(),
+                size: (0, 0),
+                exclusive_zone: -1,
+            };
+        }
+
+        let center = geometry.loc + geometry.size.downscale(2).to_point();
+        let (anchor, size, exclusive_zone) = if geometry.size.w >= geometry.size.h {
+            let edge = if center.y < screen_size.h / 2 {
+                Anchor::TOP
+            } else {
+                Anchor::BOTTOM
+            };
+            (
+                edge | Anchor::LEFT | Anchor::RIGHT,
+                (0, geometry.size.h.max(1) as u32),
+                geometry.size.h,
+            )
+        } else {
+            let edge = if center.x < screen_size.w / 2 {
+                Anchor::LEFT
+            } else {
+                Anchor::RIGHT
+            };
+            (
+                edge | Anchor::TOP | Anchor::BOTTOM,
+                (geometry.size.w.max(1) as u32, 0),
+                geometry.size.w,
+            )
+        };
+        Self {
+            layer,
+            anchor,
+            size,
+            exclusive_zone,
+        }
+    }
+}
+
+/// Reads the _NET_WM_WINDOW_TYPE of `window` and the size of the X screen.
+fn fetch_window_types(
+    conn: &X11Connection,
+    window: u32,
+) -> Result<(Vec<String>, Size<i32, Logical>)> {
+    let screen = conn.screen();
+    let screen_size = (
+        i32::from(screen.width_in_pixels),
+        i32::from(screen.height_in_pixels),
+    )
+        .into();
+
+    let window_type_atom = conn
+        .intern_atom(true, b"_NET_WM_WINDOW_TYPE")
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .atom;
+    let window_types = conn
+        .get_property(false, window, window_type_atom, AtomEnum::ATOM, 0, 32)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .value32()
+        .into_iter()
+        .flatten()
+        .map(|atom| {
+            let name = conn
+                .get_atom_name(atom)
+                .location(loc!())?
+                .reply()
+                .location(loc!())?
+                .name;
+            Ok(String::from_utf8_lossy(&name).into_owned())
+        })
+        .collect::<Result<_>>()?;
+    Ok((window_types, screen_size))
+}
+
+impl WprsState {
+    /// Where to place `x11_surface` if it should be mapped to a layer surface
+    /// rather than a toplevel.
+    pub(crate) fn layer_placement(&self, x11_surface: &X11Surface) -> Option<LayerPlacement> {
+        if self.compositor_state.window_layer_behavior != WindowLayerBehavior::LayerShell
+            || self.client_state.layer_shell.is_none()
+            || x11_surface.is_override_redirect()
+        {
+            return None;
+        }
+        let conn = self.compositor_state.x11_conn.as_ref()?;
+        let (window_types, screen_size) = fetch_window_types(conn, x11_surface.window_id())
+            .warn(loc!())
+            .ok()?;
+        let layer = layer_for_window_types(window_types.iter().map(String::as_str))?;
+        Some(LayerPlacement::new(
+            layer,
+            x11_surface.geometry(),
+            screen_size,
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct XWaylandLayerSurface {
+    pub local_layer: LayerSurface,
+    pub configured: bool,
+}
+
+impl XWaylandLayerSurface {
+    pub(crate) fn set_role(
+        surface: &mut XWaylandSurface,
+        placement: LayerPlacement,
+        layer_shell: &LayerShell,
+        qh: &QueueHandle<WprsState>,
+    ) -> Result<()> {
+        let local_surface: Surface = surface.local_surface.take().location(loc!())?;
+        let local_layer = layer_shell.create_layer_surface(
+            qh,
+            local_surface,
+            placement.layer,
+            Some(NAMESPACE),
+            None,
+        );
+        local_layer.set_anchor(placement.anchor);
+        local_layer.set_size(placement.size.0, placement.size.1);
+        local_layer.set_exclusive_zone(placement.exclusive_zone);
+        local_layer.set_keyboard_interactivity(KeyboardInteractivity::OnDemand);
+        local_layer.commit();
+
+        surface.role = Some(Role::LayerSurface(Self {
+            local_layer,
+            configured: false,
+        }));
+        Ok(())
+    }
+}
+
+impl WaylandSurface for XWaylandLayerSurface {
+    fn wl_surface(&self) -> &WlSurface {
+        self.local_layer.wl_surface()
+    }
+}
+
+impl LayerShellHandler for WprsState {
+    fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, layer: &LayerSurface) {
+        let Some(compositor_surface_id) = self.surface_bimap.get_by_right(&layer.wl_surface().id())
+        else {
+            return;
+        };
+        let xwayland_surface = self.surfaces.get(compositor_surface_id).unwrap();
+        let x11_surface = log_and_return!(xwayland_surface.get_x11_surface());
+        x11_surface.close().log_and_ignore(loc!());
+    }
+
+    #[instrument(skip(self, _conn, _qh, _serial), level = "debug")]
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        layer: &LayerSurface,
+        configure: LayerSurfaceConfigure,
+        _serial: u32,
+    ) {
+        let Some(compositor_surface_id) = self.surface_bimap.get_by_right(&layer.wl_surface().id())
+        else {
+            warn!("Received configure for already-destroyed layer surface {layer:?}.");
+            return;
+        };
+
+        let xwayland_surface = self.surfaces.get_mut(compositor_surface_id).unwrap();
+        let x11_surface = log_and_return!(xwayland_surface.get_x11_surface());
+
+        // 0 means that we get to pick, in which case we keep the X11 size.
+        let geo = x11_surface.geometry();
+        let (width, height) = configure.new_size;
+        let size = (
+            if width == 0 { geo.size.w } else { width as i32 },
+            if height == 0 {
+                geo.size.h
+            } else {
+                height as i32
+            },
+        );
+        x11_surface
+            .configure(Rectangle::new(geo.loc, size.into()))
+            .log_and_ignore(loc!());
+
+        let layer_surface = match &mut xwayland_surface.role {
+            Some(Role::LayerSurface(layer_surface)) => layer_surface,
+            _ => unreachable!(
+                "expected role layer_surface, found role {:?}",
+                &xwayland_surface.role
+            ),
+        };
+
+        // See WindowHandler::configure.
+        if layer_surface.configured {
+            return;
+        }
+
+        layer_surface.configured = true;
+
+        xwayland_surface.commit_buffer(&self.client_state.qh);
+    }
+}
+
+smithay_client_toolkit::delegate_layer!(WprsState);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_size() -> Size<i32, Logical> {
+        (1920, 1080).into()
+    }
+
+    #[test]
+    fn desktop_and_dock_get_layers() {
+        assert_eq!(
+            layer_for_window_types(["_NET_WM_WINDOW_TYPE_DESKTOP"]),
+            Some(Layer::Background)
+        );
+        assert_eq!(
+            layer_for_window_types(["_NET_WM_WINDOW_TYPE_DOCK"]),
+            Some(Layer::Top)
+        );
+        assert_eq!(layer_for_window_types(["_NET_WM_WINDOW_TYPE_NORMAL"]), None);
+        assert_eq!(layer_for_window_types([]), None);
+    }
+
+    #[test]
+    fn first_known_window_type_wins() {
+        assert_eq!(
+            layer_for_window_types(["_NET_WM_WINDOW_TYPE_NORMAL", "_NET_WM_WINDOW_TYPE_DOCK"]),
+            None
+        );
+        assert_eq!(
+            layer_for_window_types([
+                "_KDE_NET_WM_WINDOW_TYPE_OVERRIDE",
+                "_NET_WM_WINDOW_TYPE_DESKTOP"
+            ]),
+            Some(Layer::Background)
+        );
+    }
+
+    #[test]
+    fn desktop_covers_output() {
+        let placement = LayerPlacement::new(
+            Layer::Background,
+            Rectangle::new((0, 0).into(), screen_size()),
+            screen_size(),
+        );
+        assert_eq!(placement.anchor, Anchor::all());
+        assert_eq!(placement.size, (0, 0));
+        assert_eq!(placement.exclusive_zone, -1);
+    }
+
+    #[test]
+    fn dock_anchors_to_nearest_edge() {
+        let bottom_panel = LayerPlacement::new(
+            Layer::Top,
+            Rectangle::new((0, 1048).into(), (1920, 32).into()),
+            screen_size(),
+        );
+        assert_eq!(
+            bottom_panel.anchor,
+            Anchor::BOTTOM | Anchor::LEFT | Anchor::RIGHT
+        );
+        assert_eq!(bottom_panel.size, (0, 32));
+        assert_eq!(bottom_panel.exclusive_zone, 32);
+
+        let left_dock = LayerPlacement::new(
+            Layer::Top,
+            Rectangle::new((0, 0).into(), (48, 1080).into()),
+            screen_size(),
+        );
+        assert_eq!(
+            left_dock.anchor,
+            Anchor::LEFT | Anchor::TOP | Anchor::BOTTOM
+        );
+        assert_eq!(left_dock.size, (48, 0));
+        assert_eq!(left_dock.exclusive_zone, 48);
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Maximize, fullscreen and minimize requests of X11 windows. Requests of
+/// windows with a local toplevel are forwarded to it right away, and the local
+/// compositor's configures are reflected back into _NET_WM_STATE (see the
+/// toplevel configure handler). Windows can also ask for a state before they
+/// have a local toplevel, either by setting _NET_WM_STATE before mapping (which
+/// smithay's xwm doesn't read) or with client messages sent before their first
+/// commit. Those requests are queued and applied when the toplevel is created,
+/// before its initial commit, so that the first configure already has them.
+///
+/// Minimized windows are marked with _NET_WM_STATE_HIDDEN. xdg_toplevel has no
+/// event for being restored, so the mark is removed when the local compositor
+/// activates the window again.
+use std::collections::HashSet;
+
+use smithay::xwayland::X11Surface;
+use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
+use smithay_client_toolkit::shell::xdg::window::Window;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+use crate::xwayland_xdg_shell::xsurface_from_x11_surface;
+
+x11rb::atom_manager! {
+    pub Atoms: AtomsCookie {
+        _NET_WM_STATE,
+        _NET_WM_STATE_MAXIMIZED_HORZ,
+        _NET_WM_STATE_MAXIMIZED_VERT,
+        _NET_WM_STATE_FULLSCREEN,
+        _NET_WM_STATE_HIDDEN,
+    }
+}
+
+/// A request to enter (true) or leave (false) a window state.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum StateRequest {
+    Maximize(bool),
+    Fullscreen(bool),
+    Minimize(bool),
+}
+
+impl StateRequest {
+    fn apply(self, window: &Window, fullscreen_output: Option<&WlOutput>) {
+        match self {
+            Self::Maximize(true) => window.set_maximized(),
+            Self::Maximize(false) => window.unset_maximized(),
+            Self::Fullscreen(true) => window.set_fullscreen(fullscreen_output),
+            Self::Fullscreen(false) => window.unset_fullscreen(),
+            Self::Minimize(true) => window.set_minimized(),
+            // xdg_toplevel can't restore minimized windows, only the local
+            // compositor can.
+            Self::Minimize(false) => {},
+        }
+    }
+}
+
+/// The states requested by a window without a local toplevel.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct RequestedState {
+    maximized: bool,
+    fullscreen: bool,
+    minimized: bool,
+}
+
+impl RequestedState {
+    /// The states set in _NET_WM_STATE `states`. Windows are only maximized
+    /// if they're maximized in both directions, as xdg_toplevel can't
+    /// maximize in one.
+    fn from_net_wm_state(states: &[Atom], atoms: &Atoms) -> Self {
+        let set = |atom| states.contains(&atom);
+        Self {
+            maximized: set(atoms._NET_WM_STATE_MAXIMIZED_HORZ)
+                && set(atoms._NET_WM_STATE_MAXIMIZED_VERT),
+            fullscreen: set(atoms._NET_WM_STATE_FULLSCREEN),
+            minimized: set(atoms._NET_WM_STATE_HIDDEN),
+        }
+    }
+
+    fn request(&mut self, request: StateRequest) {
+        match request {
+            StateRequest::Maximize(maximized) => self.maximized = maximized,
+            StateRequest::Fullscreen(fullscreen) => self.fullscreen = fullscreen,
+            StateRequest::Minimize(minimized) => self.minimized = minimized,
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            maximized: self.maximized || other.maximized,
+            fullscreen: self.fullscreen || other.fullscreen,
+            minimized: self.minimized || other.minimized,
+        }
+    }
+
+    fn requests(self) -> impl Iterator<Item = StateRequest> {
+        [
+            self.maximized.then_some(StateRequest::Maximize(true)),
+            self.fullscreen.then_some(StateRequest::Fullscreen(true)),
+            self.minimized.then_some(StateRequest::Minimize(true)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// Queued states of a window which is about to get a local toplevel.
+#[derive(Debug)]
+pub(crate) struct PendingState {
+    state: RequestedState,
+    fullscreen_output: Option<WlOutput>,
+}
+
+impl PendingState {
+    /// Requests the queued states from the local compositor, call this before
+    /// the toplevel's initial commit.
+    pub(crate) fn apply(&self, window: &Window) {
+        debug!("applying queued window state {:?}", self.state);
+        for request in self.state.requests() {
+            request.apply(window, self.fullscreen_output.as_ref());
+        }
+    }
+}
+
+fn fetch_net_wm_state(conn: &X11Connection, window: u32) -> Result<RequestedState> {
+    let atoms = Atoms::new(&**conn)
+        .location(loc!())?
+        .reply()
+        .location(loc!())?;
+    let reply = conn
+        .get_property(
+            false,
+            window,
+            atoms._NET_WM_STATE,
+            AtomEnum::ATOM,
+            0,
+            u32::MAX / 4,
+        )
+        .location(loc!())?
+        .reply()
+        .location(loc!())?;
+    let states: Vec<Atom> = reply.value32().map(Iterator::collect).unwrap_or_default();
+    Ok(RequestedState::from_net_wm_state(&states, &atoms))
+}
+
+impl WprsState {
+    /// Queues the states `x11_surface`'s window set in _NET_WM_STATE before
+    /// it was mapped.
+    pub(crate) fn read_initial_window_state(&mut self, x11_surface: &X11Surface) {
+        let Some(conn) = &self.compositor_state.x11_conn else {
+            return;
+        };
+        let Ok(initial) = fetch_net_wm_state(conn, x11_surface.window_id()).warn(loc!()) else {
+            return;
+        };
+        if initial == RequestedState::default() {
+            return;
+        }
+        let requested = self
+            .compositor_state
+            .requested_window_states
+            .entry(x11_surface.window_id())
+            .or_default();
+        *requested = requested.merge(initial);
+    }
+
+    /// Forwards `request` to the local toplevel of `x11_surface`, or queues
+    /// it if the window doesn't have one yet.
+    pub(crate) fn request_window_state(&mut self, x11_surface: &X11Surface, request: StateRequest) {
+        let fullscreen_output = if request == StateRequest::Fullscreen(true) {
+            let output_ids = xsurface_from_x11_surface(&mut self.surfaces, x11_surface)
+                .map(|xwayland_surface| xwayland_surface.output_ids.clone())
+                .unwrap_or_default();
+            self.fullscreen_output(x11_surface, &output_ids)
+        } else {
+            None
+        };
+
+        match xsurface_from_x11_surface(&mut self.surfaces, x11_surface)
+            .and_then(|xwayland_surface| xwayland_surface.role.as_ref())
+        {
+            Some(Role::XdgToplevel(toplevel)) => {
+                request.apply(&toplevel.local_window, fullscreen_output.as_ref());
+                if request == StateRequest::Minimize(true) {
+                    x11_surface.set_suspended(true).log_and_ignore(loc!());
+                }
+            },
+            Some(_) => warn!("Received {request:?} for non-XdgToplevel surface."),
+            None => {
+                debug!(
+                    "queueing {request:?} of window {} until it has a toplevel",
+                    x11_surface.window_id()
+                );
+                self.compositor_state
+                    .requested_window_states
+                    .entry(x11_surface.window_id())
+                    .or_default()
+                    .request(request);
+            },
+        }
+    }
+
+    /// Takes the states queued for `x11_surface`'s window, which is about to
+    /// get a local toplevel.
+    pub(crate) fn take_pending_window_state(
+        &mut self,
+        x11_surface: &X11Surface,
+    ) -> Option<PendingState> {
+        let state = self
+            .compositor_state
+            .requested_window_states
+            .remove(&x11_surface.window_id())?;
+        let fullscreen_output = if state.fullscreen {
+            self.fullscreen_output(x11_surface, &HashSet::new())
+        } else {
+            None
+        };
+        Some(PendingState {
+            state,
+            fullscreen_output,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATOMS: Atoms = Atoms {
+        _NET_WM_STATE: 1,
+        _NET_WM_STATE_MAXIMIZED_HORZ: 2,
+        _NET_WM_STATE_MAXIMIZED_VERT: 3,
+        _NET_WM_STATE_FULLSCREEN: 4,
+        _NET_WM_STATE_HIDDEN: 5,
+    };
+
+    #[test]
+    fn initial_state_is_read_from_net_wm_state() {
+        assert_eq!(
+            RequestedState::from_net_wm_state(&[4, 99], &ATOMS),
+            RequestedState {
+                fullscreen: true,
+                ..RequestedState::default()
+            }
+        );
+        assert_eq!(
+            RequestedState::from_net_wm_state(&[3, 2, 5], &ATOMS),
+            RequestedState {
+                maximized: true,
+                minimized: true,
+                ..RequestedState::default()
+            }
+        );
+        // Maximizing in one direction isn't supported.
+        assert_eq!(
+            RequestedState::from_net_wm_state(&[2], &ATOMS),
+            RequestedState::default()
+        );
+    }
+
+    #[test]
+    fn fullscreen_before_configure_is_queued() {
+        let mut state = RequestedState::from_net_wm_state(&[2, 3], &ATOMS);
+        state.request(StateRequest::Fullscreen(true));
+        state.request(StateRequest::Maximize(false));
+        assert_eq!(
+            state.requests().collect::<Vec<_>>(),
+            [StateRequest::Fullscreen(true)]
+        );
+
+        state.request(StateRequest::Fullscreen(false));
+        assert_eq!(state.requests().count(), 0);
+    }
+
+    #[test]
+    fn initial_state_is_merged_with_requests() {
+        let mut requested = RequestedState::default();
+        requested.request(StateRequest::Minimize(true));
+        let initial = RequestedState::from_net_wm_state(&[4], &ATOMS);
+        assert_eq!(
+            requested.merge(initial).requests().collect::<Vec<_>>(),
+            [StateRequest::Fullscreen(true), StateRequest::Minimize(true)]
+        );
+    }
+}
@@ -29,6 +29,17 @@ x11rb::atom_manager! {
     }
 }
 
+/// The default WM name. Java's AWT only lays out windows correctly under WMs
+/// it knows to be non-reparenting, LG3D (Project Looking Glass) is one of them.
+pub const DEFAULT_WMNAME: &str = "LG3D";
+
+pub fn validate_wmname(name: &str) -> Result<()> {
+    if name.is_empty() {
+        bail!("the WM name must not be empty");
+    }
+    Ok(())
+}
+
 pub fn set_wmname(dpy_name: Option<&str>, name: &str) -> Result<()> {
     let (conn, screen_num) = x11rb::connect(dpy_name).location(loc!())?;
     let atoms = Atoms::new(&conn)
@@ -58,3 +69,14 @@ pub fn set_wmname(dpy_name: Option<&str>, name: &str) -> Result<()> {
     conn.flush().location(loc!())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_wmname_is_invalid() {
+        assert!(validate_wmname("").is_err());
+        assert!(validate_wmname(DEFAULT_WMNAME).is_ok());
+    }
+}
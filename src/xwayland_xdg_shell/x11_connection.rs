@@ -0,0 +1,54 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// The X11 connection on which the properties of windows are read as they're
+/// mapped, and on which the requests smithay's X11Wm has no API for are made.
+/// It's opened once xwayland is ready and kept for the whole session rather
+/// than connecting for each window. The watchers which need X11 events (see
+/// opacity and sync_request) have their own connections, so that their events
+/// aren't interleaved with the replies read here.
+use std::ops::Deref;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::Screen;
+use x11rb::rust_connection::RustConnection;
+
+use crate::prelude::*;
+
+#[derive(Debug)]
+pub(crate) struct X11Connection {
+    conn: RustConnection,
+    screen_num: usize,
+}
+
+impl X11Connection {
+    pub(crate) fn start(display_number: u32) -> Result<Self> {
+        let (conn, screen_num) =
+            x11rb::connect(Some(&format!(":{display_number}"))).location(loc!())?;
+        Ok(Self { conn, screen_num })
+    }
+
+    /// The screen xwayland's windows are on.
+    pub(crate) fn screen(&self) -> &Screen {
+        &self.conn.setup().roots[self.screen_num]
+    }
+}
+
+impl Deref for X11Connection {
+    type Target = RustConnection;
+
+    fn deref(&self) -> &RustConnection {
+        &self.conn
+    }
+}
@@ -0,0 +1,752 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Drags from local apps over X11 windows. smithay's X11 window manager has no
+/// XDND support, so, as other XWayland window managers do, we act as the XDND
+/// source for the local drag on a connection of our own: the X11 window under
+/// the pointer is sent XdndEnter and XdndPosition, its XdndStatus decides the
+/// mime type and action accepted on the local offer, and the data is served
+/// from the local offer when the X11 window converts XdndSelection after the
+/// drop. Drags from X11 windows to local apps aren't bridged.
+///
+/// XDND actions are atoms, of which the source lists the ones it supports and
+/// the target picks one in XdndStatus. Wayland actions are a bitfield, and the
+/// compositor picks one from the source's and the destination's actions.
+use std::io::Read;
+use std::os::fd::AsFd;
+use std::sync::Arc;
+use std::thread;
+
+use smithay::reexports::calloop::Interest;
+use smithay::reexports::calloop::LoopHandle;
+use smithay::reexports::calloop::Mode;
+use smithay::reexports::calloop::PostAction;
+use smithay::reexports::calloop::generic::Generic;
+use smithay_client_toolkit::data_device_manager::data_offer::DragOffer;
+use smithay_client_toolkit::reexports::client::protocol::wl_data_device::WlDataDevice;
+use smithay_client_toolkit::reexports::client::protocol::wl_data_device_manager::DndAction;
+use x11rb::CURRENT_TIME;
+use x11rb::NONE;
+use x11rb::connection::Connection as _;
+use x11rb::connection::RequestConnection as _;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ClientMessageEvent;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::CreateWindowAux;
+use x11rb::protocol::xproto::EventMask;
+use x11rb::protocol::xproto::PropMode;
+use x11rb::protocol::xproto::SelectionNotifyEvent;
+use x11rb::protocol::xproto::SelectionRequestEvent;
+use x11rb::protocol::xproto::Window;
+use x11rb::protocol::xproto::WindowClass;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::WprsState;
+use crate::xwayland_xdg_shell::xsurface_from_client_surface;
+
+pub const XDND_ACTION_COPY: &str = "XdndActionCopy";
+pub const XDND_ACTION_MOVE: &str = "XdndActionMove";
+pub const XDND_ACTION_LINK: &str = "XdndActionLink";
+pub const XDND_ACTION_ASK: &str = "XdndActionAsk";
+
+/// The wayland actions which X11 clients can perform.
+pub fn x11_actions() -> DndAction {
+    DndAction::Copy | DndAction::Move | DndAction::Ask
+}
+
+/// Maps an XDND action atom name to a wayland action. Wayland has no links,
+/// XdndActionLink becomes a copy, which is what a link looks like to the
+/// source: the destination gets the data and the source keeps it.
+pub fn to_dnd_action(xdnd_action: &str) -> DndAction {
+    match xdnd_action {
+        XDND_ACTION_COPY | XDND_ACTION_LINK => DndAction::Copy,
+        XDND_ACTION_MOVE => DndAction::Move,
+        XDND_ACTION_ASK => DndAction::Ask,
+        _ => DndAction::empty(),
+    }
+}
+
+/// Maps a single wayland action to an XDND action atom name.
+pub fn to_xdnd_action(action: DndAction) -> Option<&'static str> {
+    match action {
+        DndAction::Copy => Some(XDND_ACTION_COPY),
+        DndAction::Move => Some(XDND_ACTION_MOVE),
+        DndAction::Ask => Some(XDND_ACTION_ASK),
+        _ => None,
+    }
+}
+
+/// The XdndActionList for a wayland source supporting `actions`.
+pub fn to_xdnd_action_list(actions: DndAction) -> Vec<&'static str> {
+    [DndAction::Copy, DndAction::Move, DndAction::Ask]
+        .into_iter()
+        .filter(|action| actions.contains(*action))
+        .filter_map(to_xdnd_action)
+        .collect()
+}
+
+/// Picks the action for a drag: `preferred` if both sides support it,
+/// otherwise the first of copy, move and ask which both sides support. Copying
+/// comes first as it's what a plain drop does in X11 apps, and asking last as
+/// it needs the user to pick the action after the drop.
+pub fn choose_action(source: DndAction, destination: DndAction, preferred: DndAction) -> DndAction {
+    let available = source & destination;
+    if [DndAction::Copy, DndAction::Move, DndAction::Ask].contains(&preferred)
+        && available.contains(preferred)
+    {
+        return preferred;
+    }
+    [DndAction::Copy, DndAction::Move, DndAction::Ask]
+        .into_iter()
+        .find(|action| available.contains(*action))
+        .unwrap_or(DndAction::empty())
+}
+
+/// The XDND protocol version we speak.
+const XDND_VERSION: u32 = 5;
+/// The oldest version with XdndActionList and the action of XdndStatus.
+const MIN_XDND_VERSION: u32 = 3;
+
+x11rb::atom_manager! {
+    pub Atoms: AtomsCookie {
+        XdndAware,
+        XdndSelection,
+        XdndEnter,
+        XdndPosition,
+        XdndStatus,
+        XdndLeave,
+        XdndDrop,
+        XdndFinished,
+        XdndTypeList,
+        XdndActionList,
+        XdndActionCopy,
+        XdndActionMove,
+        XdndActionLink,
+        XdndActionAsk,
+        TARGETS,
+    }
+}
+
+/// The data of the XdndEnter sent by `source` for `mime_types`. Only three
+/// types fit in the message, the others are in XdndTypeList.
+fn enter_data(source: Window, version: u32, mime_types: &[Atom]) -> [u32; 5] {
+    let more_types = u32::from(mime_types.len() > 3);
+    let mut data = [source, (version << 24) | more_types, NONE, NONE, NONE];
+    for (slot, atom) in data[2..].iter_mut().zip(mime_types) {
+        *slot = *atom;
+    }
+    data
+}
+
+/// The data of the XdndPosition sent by `source` for the pointer at the root
+/// window coordinates `(x, y)`, requesting `action`.
+fn position_data(source: Window, (x, y): (i32, i32), action: Atom) -> [u32; 5] {
+    let x = x.clamp(0, i32::from(u16::MAX)) as u32;
+    let y = y.clamp(0, i32::from(u16::MAX)) as u32;
+    [source, 0, (x << 16) | y, CURRENT_TIME, action]
+}
+
+/// The action to prefer on the local offer after the target sent an
+/// XdndStatus with `accepted` and `action`, empty if it rejected the drop.
+/// Targets accepting with an action we don't know get the one we'd pick.
+fn status_action(source_actions: DndAction, accepted: bool, action: Option<&str>) -> DndAction {
+    if !accepted {
+        return DndAction::empty();
+    }
+    let preferred = action.map_or(DndAction::empty(), to_dnd_action);
+    choose_action(source_actions, x11_actions(), preferred)
+}
+
+/// A local drag over an X11 window.
+#[derive(Debug)]
+struct XdndDrag {
+    offer: DragOffer,
+    /// The XDND aware X11 window under the pointer, and the version we speak
+    /// with it.
+    target: Option<(Window, u32)>,
+    /// The offered mime types and their atoms.
+    mime_types: Vec<(Atom, String)>,
+    /// The action the target picked in its last XdndStatus, empty until it
+    /// accepts the drop.
+    accepted_action: DndAction,
+    dropped: bool,
+}
+
+/// An X11 connection on which we're the XDND source of local drags.
+#[derive(Debug)]
+pub(crate) struct XdndSource {
+    conn: Arc<RustConnection>,
+    atoms: Atoms,
+    /// Owns XdndSelection and receives the target's replies.
+    window: Window,
+    drag: Option<XdndDrag>,
+}
+
+impl XdndSource {
+    pub(crate) fn start(
+        display_number: u32,
+        event_loop_handle: &LoopHandle<'static, WprsState>,
+    ) -> Result<Self> {
+        let (conn, screen_num) =
+            x11rb::connect(Some(&format!(":{display_number}"))).location(loc!())?;
+        let atoms = Atoms::new(&conn)
+            .location(loc!())?
+            .reply()
+            .location(loc!())?;
+        let root = conn.setup().roots[screen_num].root;
+        let window = conn.generate_id().location(loc!())?;
+        conn.create_window(
+            0,
+            window,
+            root,
+            -1,
+            -1,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            0,
+            &CreateWindowAux::new(),
+        )
+        .location(loc!())?
+        .check()
+        .location(loc!())?;
+        let fd = conn
+            .stream()
+            .as_fd()
+            .try_clone_to_owned()
+            .location(loc!())?;
+        event_loop_handle
+            .insert_source(
+                Generic::new(fd, Interest::READ, Mode::Level),
+                |_, _, state| {
+                    state.handle_xdnd_events();
+                    Ok(PostAction::Continue)
+                },
+            )
+            .map_err(|e| anyhow!("failed to insert xdnd source: {e}"))
+            .location(loc!())?;
+        Ok(Self {
+            conn: Arc::new(conn),
+            atoms,
+            window,
+            drag: None,
+        })
+    }
+
+    fn action_atom(&self, action: DndAction) -> Atom {
+        match action {
+            DndAction::Move => self.atoms.XdndActionMove,
+            DndAction::Ask => self.atoms.XdndActionAsk,
+            _ => self.atoms.XdndActionCopy,
+        }
+    }
+
+    fn action_name(&self, atom: Atom) -> Option<&'static str> {
+        [
+            (self.atoms.XdndActionCopy, XDND_ACTION_COPY),
+            (self.atoms.XdndActionMove, XDND_ACTION_MOVE),
+            (self.atoms.XdndActionLink, XDND_ACTION_LINK),
+            (self.atoms.XdndActionAsk, XDND_ACTION_ASK),
+        ]
+        .into_iter()
+        .find_map(|(action_atom, name)| (action_atom == atom).then_some(name))
+    }
+
+    /// The XDND version `window` supports, if it accepts drops.
+    fn xdnd_version(&self, window: Window) -> Result<Option<u32>> {
+        let version = self
+            .conn
+            .get_property(false, window, self.atoms.XdndAware, AtomEnum::ATOM, 0, 1)
+            .location(loc!())?
+            .reply()
+            .location(loc!())?
+            .value32()
+            .and_then(|mut values| values.next());
+        Ok(version
+            .filter(|version| *version >= MIN_XDND_VERSION)
+            .map(|version| version.min(XDND_VERSION)))
+    }
+
+    fn send(&self, target: Window, message_type: Atom, data: [u32; 5]) -> Result<()> {
+        self.conn
+            .send_event(
+                false,
+                target,
+                EventMask::NO_EVENT,
+                ClientMessageEvent::new(32, target, message_type, data),
+            )
+            .location(loc!())?;
+        self.conn.flush().location(loc!())?;
+        Ok(())
+    }
+
+    /// Offers `offer` to `target`, which supports XDND `version`.
+    fn enter(&mut self, offer: DragOffer, target: Option<(Window, u32)>) -> Result<()> {
+        let mime_types = offer
+            .with_mime_types(<[String]>::to_vec)
+            .into_iter()
+            .map(|mime_type| {
+                let atom = self
+                    .conn
+                    .intern_atom(false, mime_type.as_bytes())
+                    .location(loc!())?
+                    .reply()
+                    .location(loc!())?
+                    .atom;
+                Ok((atom, mime_type))
+            })
+            .collect::<Result<Vec<_>>>()
+            .location(loc!())?;
+        let type_atoms: Vec<Atom> = mime_types.iter().map(|(atom, _)| *atom).collect();
+        let action_atoms: Vec<Atom> = to_xdnd_action_list(offer.source_actions & x11_actions())
+            .into_iter()
+            .map(|name| self.action_atom(to_dnd_action(name)))
+            .collect();
+        self.drag = Some(XdndDrag {
+            offer,
+            target,
+            mime_types,
+            accepted_action: DndAction::empty(),
+            dropped: false,
+        });
+        let Some((target, version)) = target else {
+            return Ok(());
+        };
+
+        self.conn
+            .change_property32(
+                PropMode::REPLACE,
+                self.window,
+                self.atoms.XdndTypeList,
+                AtomEnum::ATOM,
+                &type_atoms,
+            )
+            .location(loc!())?;
+        self.conn
+            .change_property32(
+                PropMode::REPLACE,
+                self.window,
+                self.atoms.XdndActionList,
+                AtomEnum::ATOM,
+                &action_atoms,
+            )
+            .location(loc!())?;
+        self.conn
+            .set_selection_owner(self.window, self.atoms.XdndSelection, CURRENT_TIME)
+            .location(loc!())?;
+        self.send(
+            target,
+            self.atoms.XdndEnter,
+            enter_data(self.window, version, &type_atoms),
+        )
+        .location(loc!())
+    }
+
+    /// Tells the target the pointer is at the root window coordinates
+    /// `position`.
+    fn position(&self, position: (i32, i32)) -> Result<()> {
+        let Some(XdndDrag {
+            offer,
+            target: Some((target, _)),
+            ..
+        }) = &self.drag
+        else {
+            return Ok(());
+        };
+        // The action the local compositor selected, e.g. for the modifiers
+        // held, or the one we'd pick.
+        let action = if offer.selected_action.is_empty() {
+            choose_action(offer.source_actions, x11_actions(), DndAction::empty())
+        } else {
+            offer.selected_action
+        };
+        self.send(
+            *target,
+            self.atoms.XdndPosition,
+            position_data(self.window, position, self.action_atom(action)),
+        )
+        .location(loc!())
+    }
+
+    /// Ends the drag, unless it was dropped on the target, which then still
+    /// has to convert the selection and finish.
+    fn leave(&mut self) -> Result<()> {
+        if self.drag.as_ref().is_some_and(|drag| drag.dropped) {
+            return Ok(());
+        }
+        let Some(drag) = self.drag.take() else {
+            return Ok(());
+        };
+        let Some((target, _)) = drag.target else {
+            return Ok(());
+        };
+        self.send(target, self.atoms.XdndLeave, [self.window, 0, 0, 0, 0])
+            .location(loc!())
+    }
+
+    /// Drops on the target if it accepted the drop, and leaves it otherwise.
+    fn drop_performed(&mut self) -> Result<()> {
+        let Some(drag) = &mut self.drag else {
+            return Ok(());
+        };
+        let Some((target, _)) = drag.target else {
+            return Ok(());
+        };
+        if drag.accepted_action.is_empty() {
+            return self.leave().location(loc!());
+        }
+        drag.dropped = true;
+        self.send(
+            target,
+            self.atoms.XdndDrop,
+            [self.window, 0, CURRENT_TIME, 0, 0],
+        )
+        .location(loc!())
+    }
+
+    /// Applies the XdndStatus of the target to the local offer.
+    fn status(&mut self, data: [u32; 5]) {
+        let action_name = self.action_name(data[4]);
+        let Some(drag) = &mut self.drag else {
+            return;
+        };
+        if drag.target.map(|(target, _)| target) != Some(data[0]) {
+            return;
+        }
+        let accepted = data[1] & 1 != 0;
+        let action = status_action(drag.offer.source_actions, accepted, action_name);
+        if action == drag.accepted_action {
+            return;
+        }
+        debug!("xdnd target accepted {action:?}");
+        drag.accepted_action = action;
+        if action.is_empty() {
+            drag.offer.accept_mime_type(drag.offer.serial, None);
+            drag.offer
+                .set_actions(DndAction::empty(), DndAction::empty());
+        } else {
+            let mime_type = drag
+                .mime_types
+                .first()
+                .map(|(_, mime_type)| mime_type.clone());
+            drag.offer.accept_mime_type(drag.offer.serial, mime_type);
+            drag.offer.set_actions(x11_actions(), action);
+        }
+    }
+
+    /// Finishes the local drag once the target read the data.
+    fn finished(&mut self, data: [u32; 5]) {
+        if !self.drag.as_ref().is_some_and(|drag| {
+            drag.dropped && drag.target.map(|(target, _)| target) == Some(data[0])
+        }) {
+            return;
+        }
+        let drag = self.drag.take().unwrap();
+        drag.offer.finish();
+        drag.offer.destroy();
+    }
+
+    /// Converts XdndSelection for the target. The data is read from the local
+    /// offer on a thread, as the local source may take its time to write it.
+    fn convert_selection(&self, request: &SelectionRequestEvent) -> Result<()> {
+        let property = if request.property == NONE {
+            request.target
+        } else {
+            request.property
+        };
+        let notify = SelectionNotifyEvent {
+            response_type: x11rb::protocol::xproto::SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: request.time,
+            requestor: request.requestor,
+            selection: request.selection,
+            target: request.target,
+            property,
+        };
+        let refuse = SelectionNotifyEvent {
+            property: NONE,
+            ..notify
+        };
+        let Some(drag) = &self.drag else {
+            return send_selection_notify(&self.conn, refuse).location(loc!());
+        };
+
+        if request.target == self.atoms.TARGETS {
+            let mut targets = vec![self.atoms.TARGETS];
+            targets.extend(drag.mime_types.iter().map(|(atom, _)| *atom));
+            self.conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    request.requestor,
+                    property,
+                    AtomEnum::ATOM,
+                    &targets,
+                )
+                .location(loc!())?;
+            return send_selection_notify(&self.conn, notify).location(loc!());
+        }
+        let Some((_, mime_type)) = drag
+            .mime_types
+            .iter()
+            .find(|(atom, _)| *atom == request.target)
+        else {
+            return send_selection_notify(&self.conn, refuse).location(loc!());
+        };
+        let mut read_pipe = drag.offer.receive(mime_type.clone()).location(loc!())?;
+        let conn = self.conn.clone();
+        let mime_type = mime_type.clone();
+        thread::spawn(move || {
+            let mut data = Vec::new();
+            let result = read_pipe
+                .read_to_end(&mut data)
+                .location(loc!())
+                .and_then(|_| {
+                    // INCR transfers aren't supported, the data has to fit in
+                    // a single request.
+                    if data.len() + 64 > conn.maximum_request_bytes() {
+                        bail!("{mime_type} drop of {} bytes is too large", data.len());
+                    }
+                    conn.change_property8(
+                        PropMode::REPLACE,
+                        notify.requestor,
+                        notify.property,
+                        notify.target,
+                        &data,
+                    )
+                    .location(loc!())?;
+                    Ok(notify)
+                });
+            let notify = result.warn(loc!()).unwrap_or(refuse);
+            send_selection_notify(&conn, notify).log_and_ignore(loc!());
+        });
+        Ok(())
+    }
+}
+
+fn send_selection_notify(conn: &RustConnection, notify: SelectionNotifyEvent) -> Result<()> {
+    conn.send_event(false, notify.requestor, EventMask::NO_EVENT, notify)
+        .location(loc!())?;
+    conn.flush().location(loc!())?;
+    Ok(())
+}
+
+impl WprsState {
+    /// The local drag offer of `data_device`, if a drag is over our surfaces.
+    pub(crate) fn drag_offer(&self, data_device: &WlDataDevice) -> Option<DragOffer> {
+        self.client_state
+            .seat_objects
+            .iter()
+            .find(|seat| seat.data_device.inner() == data_device)?
+            .data_device
+            .data()
+            .drag_offer()
+    }
+
+    /// The XDND aware X11 window of the local `offer`'s surface, with the
+    /// version we speak with it, and the root window coordinates of the
+    /// pointer.
+    fn xdnd_target(&mut self, offer: &DragOffer) -> Option<((Window, u32), (i32, i32))> {
+        let source = self.compositor_state.xdnd_source.as_ref()?;
+        let xwayland_surface =
+            xsurface_from_client_surface(&self.surface_bimap, &mut self.surfaces, &offer.surface)?;
+        let x11_surface = xwayland_surface.x11_surface.as_ref()?;
+        let window = x11_surface.window_id();
+        let Some(version) = source.xdnd_version(window).warn(loc!()).ok().flatten() else {
+            debug!("window {window} doesn't accept drops");
+            return None;
+        };
+        let scale = f64::from(xwayland_surface.scale());
+        let location = x11_surface.geometry().loc;
+        let position = (
+            location.x + (offer.x / scale) as i32,
+            location.y + (offer.y / scale) as i32,
+        );
+        Some(((window, version), position))
+    }
+
+    /// A local drag entered one of our surfaces.
+    pub(crate) fn xdnd_enter(&mut self, offer: DragOffer) {
+        let target = self.xdnd_target(&offer);
+        let Some(source) = &mut self.compositor_state.xdnd_source else {
+            return;
+        };
+        source.leave().log_and_ignore(loc!());
+        source
+            .enter(offer, target.map(|(target, _)| target))
+            .log_and_ignore(loc!());
+        if let Some((_, position)) = target {
+            source.position(position).log_and_ignore(loc!());
+        }
+    }
+
+    /// The local drag moved over our surface.
+    pub(crate) fn xdnd_motion(&mut self, offer: &DragOffer) {
+        let Some((_, position)) = self.xdnd_target(offer) else {
+            return;
+        };
+        if let Some(source) = &self.compositor_state.xdnd_source {
+            source.position(position).log_and_ignore(loc!());
+        }
+    }
+
+    pub(crate) fn xdnd_leave(&mut self) {
+        if let Some(source) = &mut self.compositor_state.xdnd_source {
+            source.leave().log_and_ignore(loc!());
+        }
+    }
+
+    pub(crate) fn xdnd_drop(&mut self) {
+        if let Some(source) = &mut self.compositor_state.xdnd_source {
+            source.drop_performed().log_and_ignore(loc!());
+        }
+    }
+
+    fn handle_xdnd_events(&mut self) {
+        let Some(source) = &mut self.compositor_state.xdnd_source else {
+            return;
+        };
+        loop {
+            let event = match source.conn.poll_for_event().warn(loc!()) {
+                Ok(Some(event)) => event,
+                _ => return,
+            };
+            match event {
+                Event::ClientMessage(message) if message.format == 32 => {
+                    let data = message.data.as_data32();
+                    if message.type_ == source.atoms.XdndStatus {
+                        source.status(data);
+                    } else if message.type_ == source.atoms.XdndFinished {
+                        source.finished(data);
+                    }
+                },
+                Event::SelectionRequest(request)
+                    if request.selection == source.atoms.XdndSelection =>
+                {
+                    source.convert_selection(&request).log_and_ignore(loc!());
+                },
+                // Errors for targets which were destroyed during the drag.
+                Event::Error(error) => debug!("xdnd source error: {error:?}"),
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xdnd_actions_map_to_dnd_actions() {
+        assert_eq!(to_dnd_action(XDND_ACTION_COPY), DndAction::Copy);
+        assert_eq!(to_dnd_action(XDND_ACTION_MOVE), DndAction::Move);
+        assert_eq!(to_dnd_action(XDND_ACTION_LINK), DndAction::Copy);
+        assert_eq!(to_dnd_action(XDND_ACTION_ASK), DndAction::Ask);
+        assert_eq!(to_dnd_action("XdndActionPrivate"), DndAction::empty());
+
+        assert_eq!(to_xdnd_action(DndAction::Move), Some(XDND_ACTION_MOVE));
+        assert_eq!(to_xdnd_action(DndAction::Copy | DndAction::Move), None);
+        assert_eq!(
+            to_xdnd_action_list(DndAction::Move | DndAction::Copy),
+            vec![XDND_ACTION_COPY, XDND_ACTION_MOVE]
+        );
+    }
+
+    #[test]
+    fn move_operation() {
+        // A wayland source offering copy and move, dropped on an X11 target
+        // which asks for a move in XdndStatus.
+        let source = DndAction::Copy | DndAction::Move;
+        let preferred = to_dnd_action(XDND_ACTION_MOVE);
+        let action = choose_action(source, x11_actions(), preferred);
+        assert_eq!(action, DndAction::Move);
+        assert_eq!(to_xdnd_action(action), Some(XDND_ACTION_MOVE));
+
+        // A move-only source must not be turned into a copy.
+        let action = choose_action(DndAction::Move, x11_actions(), DndAction::Copy);
+        assert_eq!(action, DndAction::Move);
+    }
+
+    #[test]
+    fn copy_is_preferred_over_move_and_ask() {
+        assert_eq!(
+            choose_action(x11_actions(), x11_actions(), DndAction::empty()),
+            DndAction::Copy
+        );
+        assert_eq!(
+            choose_action(
+                DndAction::Move | DndAction::Ask,
+                x11_actions(),
+                DndAction::empty()
+            ),
+            DndAction::Move
+        );
+    }
+
+    #[test]
+    fn status_of_a_move_is_applied_to_the_offer() {
+        // The X11 target accepts the drop of a copy and move source, and asks
+        // for a move.
+        let source = DndAction::Copy | DndAction::Move;
+        assert_eq!(
+            status_action(source, true, Some(XDND_ACTION_MOVE)),
+            DndAction::Move
+        );
+        // Links are copies.
+        assert_eq!(
+            status_action(source, true, Some(XDND_ACTION_LINK)),
+            DndAction::Copy
+        );
+        // Targets rejecting the drop get no action.
+        assert_eq!(
+            status_action(source, false, Some(XDND_ACTION_MOVE)),
+            DndAction::empty()
+        );
+        // A move-only source isn't turned into a copy by an unknown action.
+        assert_eq!(status_action(DndAction::Move, true, None), DndAction::Move);
+    }
+
+    #[test]
+    fn enter_lists_the_first_three_types() {
+        assert_eq!(enter_data(7, 5, &[10, 11]), [7, 5 << 24, 10, 11, NONE]);
+        // The rest are in XdndTypeList.
+        assert_eq!(
+            enter_data(7, 5, &[10, 11, 12, 13]),
+            [7, (5 << 24) | 1, 10, 11, 12]
+        );
+    }
+
+    #[test]
+    fn position_is_packed_in_root_coordinates() {
+        assert_eq!(
+            position_data(7, (300, 200), 42),
+            [7, 0, (300 << 16) | 200, CURRENT_TIME, 42]
+        );
+        // Positions off the screen are clamped.
+        assert_eq!(position_data(7, (-5, 70000), 42)[2], 0xffff);
+    }
+
+    #[test]
+    fn no_common_action() {
+        assert_eq!(
+            choose_action(DndAction::Move, DndAction::Copy, DndAction::Move),
+            DndAction::empty()
+        );
+    }
+}
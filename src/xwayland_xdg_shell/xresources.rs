@@ -0,0 +1,128 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Xft.dpi in the X resource database (the RESOURCE_MANAGER property of the
+/// root window, as set by xrdb). X11 has no fractional scaling, so toolkits
+/// size their fonts by Xft.dpi instead, which follows the largest (possibly
+/// fractional) output scale. Other resources are kept, as with `xrdb -merge`.
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::PropMode;
+use x11rb::wrapper::ConnectionExt as _;
+
+use crate::output_scale;
+use crate::prelude::*;
+use crate::xwayland_xdg_shell::compositor::WprsCompositorState;
+use crate::xwayland_xdg_shell::x11_connection::X11Connection;
+
+const XFT_DPI: &str = "Xft.dpi";
+
+/// `resources` with `name` set to `value`.
+fn merge_resource(resources: &str, name: &str, value: &str) -> String {
+    let mut merged: String = resources
+        .lines()
+        .filter(|line| {
+            line.split_once(':')
+                .is_none_or(|(line_name, _)| line_name.trim() != name)
+        })
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    merged.push_str(&format!("{name}:\t{value}\n"));
+    merged
+}
+
+fn set_xft_dpi(conn: &X11Connection, dpi: u32) -> Result<()> {
+    let root = conn.screen().root;
+    let resources = conn
+        .get_property(
+            false,
+            root,
+            AtomEnum::RESOURCE_MANAGER,
+            AtomEnum::STRING,
+            0,
+            u32::MAX,
+        )
+        .location(loc!())?
+        .reply()
+        .location(loc!())?
+        .value;
+    let resources = merge_resource(
+        &String::from_utf8_lossy(&resources),
+        XFT_DPI,
+        &dpi.to_string(),
+    );
+    conn.change_property8(
+        PropMode::REPLACE,
+        root,
+        AtomEnum::RESOURCE_MANAGER,
+        AtomEnum::STRING,
+        resources.as_bytes(),
+    )
+    .location(loc!())?;
+    conn.flush().location(loc!())?;
+    Ok(())
+}
+
+impl WprsCompositorState {
+    /// Updates Xft.dpi if the largest output scale changed.
+    #[instrument(skip(self), level = "debug")]
+    pub(crate) fn sync_xft_dpi(&mut self) {
+        let Some(conn) = &self.x11_conn else {
+            return;
+        };
+        let Some(scale_120) = self
+            .outputs
+            .values()
+            .map(|(output, _)| output.current_scale().fractional_scale())
+            .max_by(f64::total_cmp)
+            .map(|scale| (scale * f64::from(output_scale::SCALE_DENOMINATOR)).round() as u32)
+        else {
+            return;
+        };
+        let dpi = output_scale::xft_dpi(self.default_dpi, scale_120);
+        if self.xft_dpi == Some(dpi) {
+            return;
+        }
+        debug!("setting Xft.dpi to {dpi}");
+        set_xft_dpi(conn, dpi).log_and_ignore(loc!());
+        self.xft_dpi = Some(dpi);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resource_is_added() {
+        assert_eq!(merge_resource("", XFT_DPI, "144"), "Xft.dpi:\t144\n");
+        assert_eq!(
+            merge_resource("XTerm*faceSize:\t11\n", XFT_DPI, "144"),
+            "XTerm*faceSize:\t11\nXft.dpi:\t144\n"
+        );
+    }
+
+    #[test]
+    fn resource_is_replaced() {
+        assert_eq!(
+            merge_resource(
+                "Xft.dpi:\t96\nXft.antialias:\t1\nXft.dpi : 120",
+                XFT_DPI,
+                "144"
+            ),
+            "Xft.antialias:\t1\nXft.dpi:\t144\n"
+        );
+    }
+}
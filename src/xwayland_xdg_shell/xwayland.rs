@@ -17,11 +17,13 @@ use std::io;
 use std::os::fd::OwnedFd;
 use std::thread;
 
+use smithay::input::Seat;
 use smithay::reexports::wayland_server::Resource;
 use smithay::utils::Logical;
 use smithay::utils::Rectangle;
-use smithay::utils::SERIAL_COUNTER;
 use smithay::wayland::selection::SelectionTarget;
+use smithay::wayland::selection::data_device;
+use smithay::wayland::selection::primary_selection;
 use smithay::xwayland::X11Surface;
 use smithay::xwayland::X11Wm;
 use smithay::xwayland::XwmHandler;
@@ -33,6 +35,9 @@ use smithay::xwayland::xwm::XwmId;
 use crate::prelude::*;
 use crate::xwayland_xdg_shell::WprsState;
 use crate::xwayland_xdg_shell::client::Role;
+use crate::xwayland_xdg_shell::compositor;
+use crate::xwayland_xdg_shell::selection_limit::SelectionChange;
+use crate::xwayland_xdg_shell::window_state::StateRequest;
 use crate::xwayland_xdg_shell::xsurface_from_x11_surface;
 
 impl XwmHandler for WprsState {
@@ -40,38 +45,58 @@ impl XwmHandler for WprsState {
         self.compositor_state.xwm.as_mut().unwrap()
     }
 
-    fn new_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+    fn new_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.check_window_visual(&window);
+    }
 
-    fn new_override_redirect_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+    fn new_override_redirect_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.check_window_visual(&window);
+    }
 
     fn map_window_request(&mut self, _xwm: XwmId, window: X11Surface) {
         window.set_mapped(true).unwrap();
+        self.update_allowed_actions(&window);
+        self.watch_window_opacity(&window);
+        self.watch_window_sync(&window);
+        self.check_colormap_windows(&window);
+        self.read_initial_window_state(&window);
         self.compositor_state.x11_surfaces.push(window);
     }
 
     fn mapped_override_redirect_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.watch_window_opacity(&window);
         self.compositor_state.x11_surfaces.push(window);
     }
 
     #[instrument(skip(self, _xwm), level = "debug")]
     fn unmapped_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.forget_window_opacity(&window);
+        self.compositor_state
+            .colormap_windows
+            .remove(&window.window_id());
+        self.compositor_state
+            .requested_window_states
+            .remove(&window.window_id());
+        // Children must not wait on a window which was unmapped before it was
+        // committed.
+        self.compositor_state
+            .x11_surfaces
+            .retain(|x11_surface| x11_surface != &window);
         if let Some(wl_surface) = window.wl_surface() {
             // TODO: verify that we don't end up with stale entries
             let surface_id = wl_surface.id();
+            let orphans = self.compositor_state.pending_parents.remove(&wl_surface);
             self.remove_surface(&surface_id);
 
-            // TODO: maybe do this on leave?
-            // Without this, xwayland still thinks the key that triggered the
-            // window close is still held down and sends key repeat events.
-            if let Some(keyboard) = self.compositor_state.seat.get_keyboard()
-                && let Some(focus) = keyboard.current_focus()
-                && focus == window
-            {
-                let serial = SERIAL_COUNTER.next_serial();
-                keyboard.set_focus(self, None, serial);
+            // Children which were waiting on this window will never get their
+            // parent, so map them without one.
+            for orphan in orphans {
+                compositor::execute_or_defer_commit(self, orphan).log_and_ignore(loc!());
             }
         }
 
+        self.handle_focus_loss(&window).log_and_ignore(loc!());
+
         if !window.is_override_redirect() {
             window.set_mapped(false).unwrap();
         }
@@ -89,7 +114,7 @@ impl XwmHandler for WprsState {
         y: Option<i32>,
         w: Option<u32>,
         h: Option<u32>,
-        _reorder: Option<Reorder>,
+        reorder: Option<Reorder>,
     ) {
         let mut geo = window.geometry();
 
@@ -165,6 +190,11 @@ impl XwmHandler for WprsState {
         } else {
             window.configure(geo).unwrap();
         }
+
+        if let Some(reorder) = reorder {
+            self.restack_x11_surface(&window, reorder)
+                .log_and_ignore(loc!());
+        }
     }
 
     fn configure_notify(
@@ -190,66 +220,47 @@ impl XwmHandler for WprsState {
     // will follow up with a configure with the geometry to use, so we don't
     // need to worry about that saving the old geometry and restoring it here.
 
+    //
+    // Requests of windows without a local toplevel yet are queued, see
+    // window_state.
+
     fn maximize_request(&mut self, _xwm: XwmId, window: X11Surface) {
-        if let Some(xwayland_surface) = xsurface_from_x11_surface(&mut self.surfaces, &window) {
-            if let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role {
-                toplevel.local_window.set_maximized();
-            } else {
-                warn!("Received maximize request for non-XdgToplevel surface.");
-            }
-        } else {
-            warn!("Received maximize request for unknown surface.");
-        }
+        self.request_window_state(&window, StateRequest::Maximize(true));
     }
 
     fn unmaximize_request(&mut self, _xwm: XwmId, window: X11Surface) {
-        if let Some(xwayland_surface) = xsurface_from_x11_surface(&mut self.surfaces, &window) {
-            if let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role {
-                toplevel.local_window.unset_maximized();
-            } else {
-                warn!("Received unmaximize request for non-XdgToplevel surface.");
-            }
-        } else {
-            warn!("Received unmaximize request for unknown surface.");
-        }
+        self.request_window_state(&window, StateRequest::Maximize(false));
     }
 
     fn fullscreen_request(&mut self, _xwm: XwmId, window: X11Surface) {
-        if let Some(xwayland_surface) = xsurface_from_x11_surface(&mut self.surfaces, &window) {
-            if let Some(Role::XdgToplevel(toplevel)) = &mut xwayland_surface.role {
-                toplevel.local_window.set_fullscreen(None);
-            } else {
-                warn!("Received fullscreen request for non-XdgToplevel surface.");
-            }
-        } else {
-            warn!("Received fullscreen request for unknown surface.");
-        }
+        self.request_window_state(&window, StateRequest::Fullscreen(true));
     }
 
     fn unfullscreen_request(&mut self, _xwm: XwmId, window: X11Surface) {
-        if let Some(xwayland_surface) = xsurface_from_x11_surface(&mut self.surfaces, &window) {
-            if let Some(Role::XdgToplevel(toplevel)) = &mut xwayland_surface.role {
-                toplevel.local_window.unset_fullscreen();
-            } else {
-                warn!("Received unfullscreen request for non-XdgToplevel surface.");
-            }
-        } else {
-            warn!("Received unfullscreen request for unknown surface.");
-        }
+        self.request_window_state(&window, StateRequest::Fullscreen(false));
+    }
+
+    fn minimize_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.request_window_state(&window, StateRequest::Minimize(true));
+    }
+
+    fn unminimize_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.request_window_state(&window, StateRequest::Minimize(false));
     }
 
     fn resize_request(
         &mut self,
         _xwm: XwmId,
-        _window: X11Surface,
+        window: X11Surface,
         _button: u32,
-        _edges: X11ResizeEdge,
+        edges: X11ResizeEdge,
     ) {
-        // TODO, base on frame_action, but need to get serial from somewhere.
+        self.start_move_resize(&window, Some(edges))
+            .log_and_ignore(loc!());
     }
 
-    fn move_request(&mut self, _xwm: XwmId, _window: X11Surface, _button: u32) {
-        // TODO, base on frame_action, but need to get serial from somewhere.
+    fn move_request(&mut self, _xwm: XwmId, window: X11Surface, _button: u32) {
+        self.start_move_resize(&window, None).log_and_ignore(loc!());
     }
 
     #[instrument(skip(self, _xwm), level = "debug")]
@@ -267,23 +278,72 @@ impl XwmHandler for WprsState {
         mime_type: String,
         fd: OwnedFd,
     ) {
-        let read_pipe = match selection {
-            SelectionTarget::Primary => {
-                let Some(cur_offer) = self.client_state.primary_selection_offer.clone() else {
-                    warn!("primary_selection_offer was empty");
-                    return;
-                };
+        self.send_selection_to_x11(selection, mime_type, fd);
+    }
 
-                cur_offer.receive(mime_type.clone()).ok()
+    #[instrument(skip(self, _xwm), level = "debug")]
+    fn new_selection(&mut self, _xwm: XwmId, selection: SelectionTarget, mime_types: Vec<String>) {
+        self.new_x11_selection(selection, mime_types);
+    }
+
+    #[instrument(skip(self, _xwm), level = "debug")]
+    fn cleared_selection(&mut self, _xwm: XwmId, selection: SelectionTarget) {
+        self.cleared_x11_selection(selection);
+    }
+
+    fn property_notify(&mut self, _xwm: XwmId, window: X11Surface, property: WmWindowProperty) {
+        match property {
+            WmWindowProperty::Title => {
+                if let Some(xwayland_surface) =
+                    xsurface_from_x11_surface(&mut self.surfaces, &window)
+                    && let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role
+                {
+                    toplevel
+                        .local_window
+                        .set_title(self.compositor_state.window_title(&window));
+                }
             },
-            SelectionTarget::Clipboard => {
-                let Some(cur_offer) = self.client_state.selection_offer.clone() else {
-                    warn!("selection_offer was empty");
-                    return;
-                };
-                cur_offer.receive(mime_type.clone()).ok()
+            WmWindowProperty::Class => {
+                if let Some(xwayland_surface) =
+                    xsurface_from_x11_surface(&mut self.surfaces, &window)
+                    && let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role
+                {
+                    toplevel
+                        .local_window
+                        .set_app_id(self.compositor_state.window_app_id(&window));
+                    // The title template may include the class.
+                    toplevel
+                        .local_window
+                        .set_title(self.compositor_state.window_title(&window));
+                }
+            },
+            WmWindowProperty::TransientFor => {
+                compositor::update_x11_parent(self, &window).log_and_ignore(loc!());
+            },
+            WmWindowProperty::NormalHints | WmWindowProperty::WindowType => {
+                self.update_allowed_actions(&window);
             },
+            _ => {},
+        }
+    }
+}
+
+impl WprsState {
+    /// Writes the local `selection` as `mime_type` to `fd`. The data is copied
+    /// as it arrives, so payloads of any size are streamed, and if the local
+    /// owner goes away mid-transfer, `fd` is closed, ending the paste with
+    /// what was received so far.
+    pub(crate) fn send_local_selection(
+        &self,
+        selection: SelectionTarget,
+        mime_type: String,
+        fd: OwnedFd,
+    ) {
+        let Some(cur_offer) = self.client_state.selection_offers.get(selection.into()) else {
+            warn!("no local offer for {selection:?}");
+            return;
         };
+        let read_pipe = cur_offer.receive(mime_type.clone());
 
         if let Some(mut read_pipe) = read_pipe {
             debug!("spawning send_selection thread for mime {mime_type}");
@@ -297,16 +357,167 @@ impl XwmHandler for WprsState {
                 // debug!("read selection: {buf:?}");
                 // f.write_all(&buf);
 
-                let bytes_copied = io::copy(&mut read_pipe, &mut f);
-                debug!("wrote selection: {bytes_copied:?} bytes");
+                match io::copy(&mut read_pipe, &mut f) {
+                    Ok(bytes_copied) => debug!("wrote selection: {bytes_copied} bytes"),
+                    Err(e) => warn!("{mime_type} selection transfer was interrupted: {e}"),
+                }
             });
         }
     }
 
-    #[instrument(skip(self, _xwm), level = "debug")]
-    fn new_selection(
+    /// Has the Wayland client owning `selection` write it as `mime_type`
+    /// directly to `fd`, so payloads of any size are streamed and `fd` is
+    /// closed if the client goes away mid-transfer.
+    fn send_client_selection(
+        &mut self,
+        selection: SelectionTarget,
+        mime_type: String,
+        fd: OwnedFd,
+    ) {
+        let Some(seat) = self
+            .compositor_state
+            .client_selections
+            .get(selection.into())
+        else {
+            return;
+        };
+        let no_selection = match selection {
+            SelectionTarget::Clipboard => {
+                match data_device::request_data_device_client_selection(seat, mime_type, fd) {
+                    Err(data_device::SelectionRequestError::NoSelection) => true,
+                    result => {
+                        result.location(loc!()).log_and_ignore(loc!());
+                        false
+                    },
+                }
+            },
+            SelectionTarget::Primary => {
+                match primary_selection::request_primary_client_selection(seat, mime_type, fd) {
+                    Err(primary_selection::SelectionRequestError::NoSelection) => true,
+                    result => {
+                        result.location(loc!()).log_and_ignore(loc!());
+                        false
+                    },
+                }
+            },
+        };
+        if no_selection {
+            // The owner went away without unsetting the selection.
+            self.set_client_selection(selection, None, seat.clone());
+        }
+    }
+
+    /// Offers the selection of a Wayland client connected directly to us, or
+    /// its removal if `mime_types` is None, to X11.
+    pub(crate) fn set_client_selection(
+        &mut self,
+        target: SelectionTarget,
+        mime_types: Option<Vec<String>>,
+        seat: Seat<Self>,
+    ) {
+        if mime_types.is_some() {
+            self.compositor_state
+                .client_selections
+                .set(target.into(), seat);
+            // Superseded, so the local selection being unset mustn't clear
+            // this one.
+            self.client_state.selection_offers.take(target.into());
+        } else if self
+            .compositor_state
+            .client_selections
+            .take(target.into())
+            .is_none()
+        {
+            return;
+        }
+        if let Some(xwm) = &mut self.compositor_state.xwm {
+            xwm.new_selection(target, mime_types).log_and_ignore(loc!());
+        }
+    }
+
+    /// Forwards the local `target` selection being unset to X11. Selections
+    /// owned by X11 apps are left alone, they're cleared through
+    /// `cleared_selection`.
+    pub(crate) fn clear_local_selection(&mut self, target: SelectionTarget) {
+        if self
+            .client_state
+            .selection_offers
+            .take(target.into())
+            .is_none()
+        {
+            return;
+        }
+        if let Some(xwm) = &mut self.compositor_state.xwm {
+            xwm.new_selection(target, None).log_and_ignore(loc!());
+        }
+    }
+}
+
+impl WprsState {
+    /// Whether changes to the X11 `selection` are forwarded. X11 apps set the
+    /// primary selection whenever text is selected, which some users don't
+    /// expect to reach their local apps.
+    fn forwards_selection(&self, selection: SelectionTarget) -> bool {
+        match selection {
+            SelectionTarget::Clipboard => true,
+            SelectionTarget::Primary => self.compositor_state.forward_primary_selection,
+        }
+    }
+
+    /// Writes `selection` as `mime_type` to `fd` for an X11 app pasting it,
+    /// from whoever owns it outside of X11.
+    pub(crate) fn send_selection_to_x11(
+        &mut self,
+        selection: SelectionTarget,
+        mime_type: String,
+        fd: OwnedFd,
+    ) {
+        if self
+            .compositor_state
+            .client_selections
+            .get(selection.into())
+            .is_some()
+        {
+            self.send_client_selection(selection, mime_type, fd);
+        } else {
+            self.send_local_selection(selection, mime_type, fd);
+        }
+    }
+
+    /// An X11 app took ownership of `selection`, offering it as `mime_types`.
+    pub(crate) fn new_x11_selection(
+        &mut self,
+        selection: SelectionTarget,
+        mime_types: Vec<String>,
+    ) {
+        if !self.forwards_selection(selection) {
+            debug!("not forwarding primary selection");
+            return;
+        }
+        self.compositor_state
+            .client_selections
+            .take(selection.into());
+        // An X11 app may own PRIMARY with nothing highlighted, there is
+        // nothing for local apps to paste then.
+        let change = if selection == SelectionTarget::Primary && mime_types.is_empty() {
+            SelectionChange::Cleared
+        } else {
+            SelectionChange::New(mime_types)
+        };
+        self.limit_selection_change(selection, change);
+    }
+
+    /// The X11 app owning `selection` gave it up.
+    pub(crate) fn cleared_x11_selection(&mut self, selection: SelectionTarget) {
+        if !self.forwards_selection(selection) {
+            return;
+        }
+        self.limit_selection_change(selection, SelectionChange::Cleared);
+    }
+
+    /// Makes an X11 selection the local selection.
+    pub(crate) fn set_local_selection(
         &mut self,
-        _xwm: XwmId,
         selection: SelectionTarget,
         mut mime_types: Vec<String>,
     ) {
@@ -352,29 +563,106 @@ impl XwmHandler for WprsState {
         }
     }
 
-    fn cleared_selection(&mut self, _xwm: XwmId, _selection: SelectionTarget) {
-        // TODO
-    }
-
-    fn property_notify(&mut self, _xwm: XwmId, window: X11Surface, property: WmWindowProperty) {
-        match property {
-            WmWindowProperty::Title => {
-                if let Some(xwayland_surface) =
-                    xsurface_from_x11_surface(&mut self.surfaces, &window)
-                    && let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role
+    /// Unsets the local selection after its X11 selection was cleared.
+    pub(crate) fn unset_local_selection(&mut self, selection: SelectionTarget) {
+        // Dropping the source destroys it, which unsets the local selection
+        // if it's still ours. wprsd isn't notified of destroyed sources
+        // though, so unset the selection explicitly for the clear to be
+        // forwarded.
+        let serial = self.client_state.last_implicit_grab_serial;
+        let seat_obj = self.client_state.seat_objects.last();
+        match selection {
+            SelectionTarget::Clipboard => {
+                if self.client_state.selection_source.take().is_some()
+                    && let Some(seat_obj) = seat_obj
                 {
-                    toplevel.local_window.set_title(window.title());
+                    seat_obj.data_device.unset_selection(serial);
                 }
             },
-            WmWindowProperty::Class => {
-                if let Some(xwayland_surface) =
-                    xsurface_from_x11_surface(&mut self.surfaces, &window)
-                    && let Some(Role::XdgToplevel(toplevel)) = &xwayland_surface.role
+            SelectionTarget::Primary => {
+                if self.client_state.primary_selection_source.take().is_some()
+                    && let Some(primary_selection_device) =
+                        seat_obj.and_then(|seat_obj| seat_obj.primary_selection_device.as_ref())
                 {
-                    toplevel.local_window.set_app_id(window.class());
+                    primary_selection_device.unset_selection(serial);
                 }
             },
-            _ => {},
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+
+    use super::*;
+    use crate::xwayland_xdg_shell::testing;
+    use crate::xwayland_xdg_shell::testing::Harness;
+
+    /// What an X11 app pasting `target` as text gets.
+    fn paste_in_x11(harness: &mut Harness, target: SelectionTarget) -> String {
+        let (mut reader, writer) = io::pipe().unwrap();
+        harness.state.send_selection_to_x11(
+            target,
+            "text/plain;charset=utf-8".to_owned(),
+            OwnedFd::from(writer),
+        );
+        harness.dispatch();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn x11_primary_and_clipboard_are_forwarded_independently() {
+        let mut harness = Harness::new(testing::options());
+        harness.focus_local();
+
+        harness
+            .state
+            .new_x11_selection(SelectionTarget::Clipboard, vec!["text/plain".to_owned()]);
+        harness
+            .state
+            .new_x11_selection(SelectionTarget::Primary, vec!["UTF8_STRING".to_owned()]);
+        harness.dispatch();
+        let marker = "_xwayland_xdg_shell_marker".to_owned();
+        assert_eq!(
+            harness.local_selection(SelectionTarget::Clipboard),
+            Some(vec!["text/plain".to_owned(), marker.clone()])
+        );
+        assert_eq!(
+            harness.local_selection(SelectionTarget::Primary),
+            Some(vec!["UTF8_STRING".to_owned(), marker.clone()])
+        );
+
+        // Deselecting text leaves the clipboard alone.
+        harness
+            .state
+            .new_x11_selection(SelectionTarget::Primary, Vec::new());
+        harness.dispatch();
+        assert_eq!(harness.local_selection(SelectionTarget::Primary), None);
+        assert_eq!(
+            harness.local_selection(SelectionTarget::Clipboard),
+            Some(vec!["text/plain".to_owned(), marker])
+        );
+    }
+
+    #[test]
+    fn x11_primary_and_clipboard_are_served_independently() {
+        let mut harness = Harness::new(testing::options());
+        harness.focus_local();
+
+        let mime_type = "text/plain;charset=utf-8";
+        harness.set_local_selection(SelectionTarget::Primary, mime_type, b"primary contents");
+        harness.set_local_selection(SelectionTarget::Clipboard, mime_type, b"clipboard contents");
+        assert_eq!(
+            paste_in_x11(&mut harness, SelectionTarget::Clipboard),
+            "clipboard contents"
+        );
+        assert_eq!(
+            paste_in_x11(&mut harness, SelectionTarget::Primary),
+            "primary contents"
+        );
+    }
+}